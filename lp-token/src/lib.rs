@@ -0,0 +1,115 @@
+//! NEP-141 token wrapping liquidity shares of a single multiswap pool.
+//! Deployed by the multiswap contract as its sub-account, which is the only one allowed to mint and burn it:
+//! shares are locked in the pool when wrapped and released when unwrapped.
+
+use near_contract_standards::fungible_token::metadata::{
+    FungibleTokenMetadata, FungibleTokenMetadataProvider, FT_METADATA_SPEC,
+};
+use near_contract_standards::fungible_token::FungibleToken;
+use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
+use near_sdk::json_types::{ValidAccountId, U128};
+use near_sdk::{env, near_bindgen, AccountId, PanicOnDefault, PromiseOrValue};
+
+near_sdk::setup_alloc!();
+
+/// Decimals of pool shares.
+const SHARES_DECIMALS: u8 = 24;
+
+#[near_bindgen]
+#[derive(BorshSerialize, BorshDeserialize, PanicOnDefault)]
+pub struct Contract {
+    token: FungibleToken,
+    /// Multiswap contract that mints and burns the token.
+    owner_id: AccountId,
+    /// Pool whose shares the token wraps.
+    pool_id: u64,
+}
+
+#[near_bindgen]
+impl Contract {
+    #[init]
+    pub fn new(owner_id: ValidAccountId, pool_id: u64) -> Self {
+        assert!(!env::state_exists(), "ERR_CONTRACT_IS_INITIALIZED");
+        Self {
+            token: FungibleToken::new(b"t".to_vec()),
+            owner_id: owner_id.into(),
+            pool_id,
+        }
+    }
+
+    /// Mints given amount to the account, which must be registered with `storage_deposit`. Only owner can call it.
+    pub fn mint(&mut self, account_id: ValidAccountId, amount: U128) {
+        self.assert_owner();
+        self.token
+            .internal_deposit(account_id.as_ref(), amount.into());
+    }
+
+    /// Burns given amount of the account. Only owner can call it.
+    pub fn burn(&mut self, account_id: ValidAccountId, amount: U128) {
+        self.assert_owner();
+        self.token
+            .internal_withdraw(account_id.as_ref(), amount.into());
+    }
+
+    fn assert_owner(&self) {
+        assert_eq!(
+            env::predecessor_account_id(),
+            self.owner_id,
+            "ERR_NOT_OWNER"
+        );
+    }
+}
+
+near_contract_standards::impl_fungible_token_core!(Contract, token);
+near_contract_standards::impl_fungible_token_storage!(Contract, token);
+
+#[near_bindgen]
+impl FungibleTokenMetadataProvider for Contract {
+    fn ft_metadata(&self) -> FungibleTokenMetadata {
+        FungibleTokenMetadata {
+            spec: FT_METADATA_SPEC.to_string(),
+            name: format!("Multiswap pool {} shares", self.pool_id),
+            symbol: format!("LP-{}", self.pool_id),
+            icon: None,
+            reference: None,
+            reference_hash: None,
+            decimals: SHARES_DECIMALS,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use near_sdk::test_utils::{accounts, VMContextBuilder};
+    use near_sdk::{env, testing_env, MockedBlockchain};
+
+    use super::*;
+
+    #[test]
+    fn test_mint_and_burn() {
+        let mut context = VMContextBuilder::new();
+        testing_env!(context.build());
+        let mut contract = Contract::new(accounts(0), 1);
+        testing_env!(context
+            .attached_deposit(125 * env::storage_byte_cost())
+            .build());
+        contract.storage_deposit(Some(accounts(1)), None);
+        testing_env!(context.predecessor_account_id(accounts(0)).build());
+        contract.mint(accounts(1), 1_000.into());
+        assert_eq!(contract.ft_balance_of(accounts(1)), 1_000.into());
+        contract.burn(accounts(1), 400.into());
+        assert_eq!(contract.ft_balance_of(accounts(1)), 600.into());
+        assert_eq!(contract.ft_total_supply(), 600.into());
+        assert_eq!(contract.ft_metadata().symbol, "LP-1");
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_NOT_OWNER")]
+    fn test_deny_mint_not_owner() {
+        let mut context = VMContextBuilder::new();
+        testing_env!(context.build());
+        let mut contract = Contract::new(accounts(0), 1);
+        testing_env!(context.predecessor_account_id(accounts(1)).build());
+        contract.mint(accounts(1), 1_000.into());
+    }
+}
@@ -0,0 +1,165 @@
+//! Pure math of the multiswap pools, without dependency on near-sdk, so routers, simulators and fuzzers
+//! running off-chain compute exactly what the contract does. Functions here don't validate their inputs:
+//! the contract checks them and reports its own errors, and callers elsewhere must do the same.
+
+#![no_std]
+
+use core::convert::TryFrom;
+
+/// Divisor of swap fees, e.g. fee of 30 is 0.3%.
+pub const FEE_DIVISOR: u32 = 10_000;
+
+pub use wide::{U256, U512};
+
+/// Integers wider than 128 bits. Code generated by `construct_uint!` trips lints of newer clippy,
+/// and lint attributes on the macro invocations are ignored, so they're allowed for the whole module.
+#[allow(clippy::assign_op_pattern, clippy::manual_div_ceil)]
+mod wide {
+    use uint::construct_uint;
+
+    construct_uint! {
+        /// 256-bit unsigned integer.
+        pub struct U256(4);
+    }
+
+    construct_uint! {
+        /// 512-bit unsigned integer, used for intermediate results of 256-bit multiplication.
+        pub struct U512(8);
+    }
+}
+
+/// Error of converting `U512` that doesn't fit into `U256`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct U256Overflow;
+
+impl From<U256> for U512 {
+    fn from(value: U256) -> Self {
+        let mut words = [0u64; 8];
+        words[..4].copy_from_slice(&value.0);
+        U512(words)
+    }
+}
+
+impl TryFrom<U512> for U256 {
+    type Error = U256Overflow;
+
+    fn try_from(value: U512) -> Result<Self, Self::Error> {
+        if value.0[4..].iter().any(|word| *word != 0) {
+            return Err(U256Overflow);
+        }
+        let mut words = [0u64; 4];
+        words.copy_from_slice(&value.0[..4]);
+        Ok(U256(words))
+    }
+}
+
+#[cfg(feature = "borsh")]
+mod borsh_impl {
+    use borsh::maybestd::io::{Result, Write};
+    use borsh::{BorshDeserialize, BorshSerialize};
+
+    use super::U256;
+
+    impl BorshSerialize for U256 {
+        fn serialize<W: Write>(&self, writer: &mut W) -> Result<()> {
+            self.0.serialize(writer)
+        }
+    }
+
+    impl BorshDeserialize for U256 {
+        fn deserialize(buf: &mut &[u8]) -> Result<Self> {
+            Ok(U256(<[u64; 4]>::deserialize(buf)?))
+        }
+    }
+}
+
+/// Computes `a * b / denominator` rounding down, with full precision of the intermediate product.
+/// Returns None if the result doesn't fit into `U256`.
+pub fn mul_div(a: U256, b: U256, denominator: U256) -> Option<U256> {
+    let result = U512::from(a) * U512::from(b) / U512::from(denominator);
+    U256::try_from(result).ok()
+}
+
+/// Computes `a * b / denominator` rounding up, with full precision of the intermediate product.
+/// Returns None if the result doesn't fit into `U256`.
+pub fn mul_div_round_up(a: U256, b: U256, denominator: U256) -> Option<U256> {
+    let product = U512::from(a) * U512::from(b);
+    let denominator = U512::from(denominator);
+    let mut result = product / denominator;
+    if !(product % denominator).is_zero() {
+        result += U512::one();
+    }
+    U256::try_from(result).ok()
+}
+
+/// Returns amount out of a constant product swap of `amount_in` between reserves `in_balance` and `out_balance`,
+/// charged `fee` over `FEE_DIVISOR` of the amount in. Reserves and amount in must be positive.
+pub fn get_return(in_balance: u128, out_balance: u128, amount_in: u128, fee: u32) -> U256 {
+    let amount_with_fee = U256::from(amount_in) * U256::from(FEE_DIVISOR - fee);
+    amount_with_fee * U256::from(out_balance)
+        / (U256::from(FEE_DIVISOR) * U256::from(in_balance) + amount_with_fee)
+}
+
+/// Returns amount in of a constant product swap returning exactly `amount_out`, rounded up, so that
+/// `get_return` of it is at least `amount_out`. Amount out must be positive and below `out_balance`.
+pub fn get_amount_in(in_balance: u128, out_balance: u128, amount_out: u128, fee: u32) -> U256 {
+    let numerator = U256::from(in_balance) * U256::from(amount_out) * U256::from(FEE_DIVISOR);
+    let denominator =
+        (U256::from(out_balance) - U256::from(amount_out)) * U256::from(FEE_DIVISOR - fee);
+    (numerator + denominator - 1) / denominator
+}
+
+/// Returns number of shares minted for depositing given amounts into a pool with given reserves and share supply:
+/// the share of the pool of the token deposited the least in proportion, rounded down.
+/// Amounts and reserves must be positive and in the same order.
+pub fn shares_for_amounts(balances: &[u128], amounts: &[u128], total_shares: u128) -> U256 {
+    balances
+        .iter()
+        .zip(amounts)
+        .map(|(balance, amount)| U256::from(*amount) * U256::from(total_shares) / *balance)
+        .min()
+        .unwrap_or_else(U256::zero)
+}
+
+/// Returns amount of a reserve that given number of shares is worth, rounded down.
+pub fn amount_for_shares(balance: u128, shares: u128, total_shares: u128) -> U256 {
+    U256::from(balance) * U256::from(shares) / U256::from(total_shares)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_mul_div() {
+        let max = U256::max_value();
+        assert_eq!(mul_div(max, max, max), Some(max));
+        assert_eq!(mul_div(max, U256::from(2), U256::one()), None);
+        assert_eq!(
+            mul_div_round_up(U256::from(10), U256::from(10), U256::from(3)),
+            Some(U256::from(34))
+        );
+        assert_eq!(
+            mul_div_round_up(U256::from(10), U256::from(9), U256::from(3)),
+            Some(U256::from(30))
+        );
+    }
+
+    #[test]
+    fn test_get_return_and_amount_in() {
+        let one = 10u128.pow(24);
+        let amount_out = get_return(10 * one, 10 * one, one, 30).low_u128();
+        assert!(amount_out < 10 * one / 11);
+        let amount_in = get_amount_in(10 * one, 10 * one, amount_out, 30).low_u128();
+        assert!(amount_in <= one);
+        assert!(get_return(10 * one, 10 * one, amount_in, 30).low_u128() >= amount_out);
+    }
+
+    #[test]
+    fn test_share_math() {
+        let shares = shares_for_amounts(&[100, 200], &[10, 30], 1000);
+        assert_eq!(shares, U256::from(100));
+        assert_eq!(amount_for_shares(200, 100, 1000), U256::from(20));
+        assert_eq!(amount_for_shares(199, 100, 1000), U256::from(19));
+    }
+}
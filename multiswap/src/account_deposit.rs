@@ -0,0 +1,220 @@
+//! Internal ledger of tokens deposited to the contract by each account.
+//! Tokens are transferred to the contract once, and then any number of swaps and liquidity operations
+//! run against these balances without further cross-contract calls.
+
+use std::collections::HashMap;
+
+use near_sdk::borsh::maybestd::io;
+use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
+use near_sdk::{env, AccountId, Balance, StorageUsage};
+
+use crate::errors::{require, PoolError, UnwrapOrPanic};
+
+/// Maximum number of distinct tokens an account can register in the deposits.
+pub const MAX_NUMBER_OF_TOKENS: usize = 10;
+const MAX_ACCOUNT_LENGTH: usize = 64;
+/// Upper bound of storage taken by deposits of a single account, which is prepaid on registration.
+pub const BYTES_PER_DEPOSIT_RECORD: usize =
+    MAX_NUMBER_OF_TOKENS * (MAX_ACCOUNT_LENGTH + 16) + 4 + MAX_ACCOUNT_LENGTH;
+
+/// Balances of tokens deposited by a single account, together with its storage balance.
+#[derive(BorshSerialize, Default)]
+pub struct AccountDeposits {
+    /// Balance of each token registered by the account.
+    pub tokens: HashMap<AccountId, Balance>,
+    /// NEAR attached by the account to pay for its storage.
+    pub storage_balance: Balance,
+    /// Bytes taken by the account besides its deposits record, e.g. by its liquidity shares and positions in pools.
+    pub storage_used: StorageUsage,
+}
+
+impl BorshDeserialize for AccountDeposits {
+    /// Records of the first release hold only the balances. Their accounts paid the minimum storage balance
+    /// on registration, and storage taken by their shares wasn't tracked.
+    fn deserialize(buf: &mut &[u8]) -> io::Result<Self> {
+        let tokens = HashMap::deserialize(buf)?;
+        if buf.is_empty() {
+            return Ok(Self {
+                tokens,
+                storage_balance: BYTES_PER_DEPOSIT_RECORD as Balance * env::storage_byte_cost(),
+                storage_used: 0,
+            });
+        }
+        Ok(Self {
+            tokens,
+            storage_balance: Balance::deserialize(buf)?,
+            storage_used: StorageUsage::deserialize(buf)?,
+        })
+    }
+}
+
+impl AccountDeposits {
+    /// Returns NEAR needed to cover storage of the account.
+    pub fn storage_cost(&self) -> Balance {
+        (BYTES_PER_DEPOSIT_RECORD as StorageUsage + self.storage_used) as Balance
+            * env::storage_byte_cost()
+    }
+
+    /// Returns part of the storage balance not needed to cover storage of the account.
+    pub fn storage_available(&self) -> Balance {
+        self.storage_balance.saturating_sub(self.storage_cost())
+    }
+
+    /// Fails if storage balance doesn't cover storage of the account.
+    pub fn assert_storage_usage(&self) {
+        require(
+            self.storage_balance >= self.storage_cost(),
+            PoolError::InsufficientStorage,
+        );
+    }
+
+    /// Returns balance of given token, zero if there is none.
+    pub fn get_balance(&self, token_id: &AccountId) -> Balance {
+        self.tokens.get(token_id).cloned().unwrap_or_default()
+    }
+
+    /// Registers given token with zero balance, if it's not registered yet.
+    /// Fails if account already has maximum number of tokens.
+    pub fn register(&mut self, token_id: &AccountId) {
+        if !self.tokens.contains_key(token_id) {
+            require(
+                self.tokens.len() < MAX_NUMBER_OF_TOKENS,
+                PoolError::TooManyTokens,
+            );
+            self.tokens.insert(token_id.clone(), 0);
+        }
+    }
+
+    /// Unregisters given token. Fails if its balance is not zero.
+    pub fn unregister(&mut self, token_id: &AccountId) {
+        require(
+            self.get_balance(token_id) == 0,
+            PoolError::NonZeroTokenBalance,
+        );
+        self.tokens.remove(token_id);
+    }
+
+    /// Adds given amount of token to the balance.
+    /// Fails if the token is not registered. Zero amount is skipped, so the token doesn't need to be registered.
+    pub fn deposit(&mut self, token_id: &AccountId, amount: Balance) {
+        if amount == 0 {
+            return;
+        }
+        *self
+            .tokens
+            .get_mut(token_id)
+            .unwrap_or_panic(PoolError::TokenNotRegistered) += amount;
+    }
+
+    /// Returns given amount of token to the balance, registering the token if needed even if it exceeds
+    /// maximum number of tokens, so tokens of a failed withdrawal are never lost.
+    pub fn refund(&mut self, token_id: &AccountId, amount: Balance) {
+        *self.tokens.entry(token_id.clone()).or_default() += amount;
+    }
+
+    /// Subtracts given amount of token from the balance. The token stays registered.
+    /// Fails if there is not enough of the token. Zero amount is skipped, so the token doesn't need to be present.
+    pub fn withdraw(&mut self, token_id: &AccountId, amount: Balance) {
+        if amount == 0 {
+            return;
+        }
+        let balance = self
+            .tokens
+            .get_mut(token_id)
+            .unwrap_or_panic(PoolError::MissingToken);
+        require(amount <= *balance, PoolError::NotEnoughToken);
+        *balance -= amount;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use near_sdk::test_utils::VMContextBuilder;
+    use near_sdk::testing_env;
+
+    use super::*;
+
+    #[test]
+    fn test_account_deposits() {
+        let mut deposits = AccountDeposits::default();
+        let token: AccountId = "token".parse().unwrap();
+        deposits.register(&token);
+        deposits.deposit(&token, 100);
+        deposits.deposit(&token, 50);
+        assert_eq!(deposits.get_balance(&token), 150);
+        deposits.withdraw(&token, 150);
+        assert_eq!(deposits.get_balance(&token), 0);
+    }
+
+    #[test]
+    fn test_account_token_registration() {
+        testing_env!(VMContextBuilder::new().build());
+        let mut deposits = AccountDeposits::default();
+        let token: AccountId = "token".parse().unwrap();
+        // Zero amounts don't need registration.
+        deposits.deposit(&token, 0);
+        deposits.withdraw(&token, 0);
+        assert!(deposits.tokens.is_empty());
+        deposits.register(&token);
+        deposits.deposit(&token, 1);
+        deposits.withdraw(&token, 1);
+        assert_eq!(deposits.tokens.get(&token), Some(&0));
+        deposits.unregister(&token);
+        assert!(deposits.tokens.is_empty());
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_TOKEN_NOT_REGISTERED")]
+    fn test_account_deposits_not_registered() {
+        testing_env!(VMContextBuilder::new().build());
+        let mut deposits = AccountDeposits::default();
+        deposits.deposit(&"token".parse().unwrap(), 1);
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_NON_ZERO_TOKEN_BALANCE")]
+    fn test_account_deposits_unregister_with_balance() {
+        testing_env!(VMContextBuilder::new().build());
+        let mut deposits = AccountDeposits::default();
+        let token: AccountId = "token".parse().unwrap();
+        deposits.register(&token);
+        deposits.deposit(&token, 1);
+        deposits.unregister(&token);
+    }
+
+    #[test]
+    fn test_account_storage() {
+        testing_env!(VMContextBuilder::new().build());
+        let record_cost = BYTES_PER_DEPOSIT_RECORD as Balance * env::storage_byte_cost();
+        let mut deposits = AccountDeposits {
+            storage_balance: record_cost + 100 * env::storage_byte_cost(),
+            ..Default::default()
+        };
+        assert_eq!(deposits.storage_available(), 100 * env::storage_byte_cost());
+        deposits.storage_used = 100;
+        assert_eq!(deposits.storage_available(), 0);
+        deposits.assert_storage_usage();
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_INSUFFICIENT_STORAGE")]
+    fn test_account_storage_not_covered() {
+        testing_env!(VMContextBuilder::new().build());
+        let deposits = AccountDeposits {
+            storage_balance: BYTES_PER_DEPOSIT_RECORD as Balance * env::storage_byte_cost(),
+            storage_used: 1,
+            ..Default::default()
+        };
+        deposits.assert_storage_usage();
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_TOO_MANY_TOKENS")]
+    fn test_account_deposits_max_tokens() {
+        testing_env!(VMContextBuilder::new().build());
+        let mut deposits = AccountDeposits::default();
+        for i in 0..=MAX_NUMBER_OF_TOKENS {
+            deposits.register(&format!("token{}", i).parse().unwrap());
+        }
+    }
+}
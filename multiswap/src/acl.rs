@@ -0,0 +1,104 @@
+//! Roles of privileged accounts. Owner (or the DAO, once it's set) holds every role, and grants the others:
+//! guardians pause pools and the exchange, fee setters change fees, and pool managers configure pools
+//! and the token whitelist. Everything else stays with owner.
+
+use crate::errors::{require, PoolError};
+use crate::*;
+
+/// Role of a privileged account.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(crate = "near_sdk::serde")]
+pub enum Role {
+    /// Owner, or the DAO once it's set. Changed with `propose_owner` and `set_dao`.
+    Owner,
+    /// Pauses pools and the exchange.
+    Guardian,
+    /// Changes fees of referrals, protocol, flash loans, limit orders, stop orders and pool creation.
+    FeeSetter,
+    /// Changes the token whitelist and pool creation rules, retires pools and sets their circuit breakers,
+    /// batch auctions and LP tokens.
+    PoolManager,
+}
+
+#[near_bindgen]
+impl Contract {
+    /// Grants given role to the account. Only owner can call it.
+    #[payable]
+    pub fn grant_role(&mut self, role: Role, account_id: AccountId) {
+        assert_one_yocto();
+        self.assert_owner();
+        self.internal_role_members_mut(role).insert(&account_id);
+        log!("Role {:?} granted to {}", role, account_id.as_ref());
+    }
+
+    /// Revokes given role from the account. Only owner can call it.
+    #[payable]
+    pub fn revoke_role(&mut self, role: Role, account_id: AccountId) {
+        assert_one_yocto();
+        self.assert_owner();
+        self.internal_role_members_mut(role).remove(&account_id);
+        log!("Role {:?} revoked from {}", role, account_id.as_ref());
+    }
+
+    /// Returns accounts granted given role. Owner holds every role without being listed, except for `Owner` itself.
+    pub fn get_role_members(&self, role: Role) -> Vec<AccountId> {
+        match self.internal_role_members(role) {
+            Some(members) => members.to_vec(),
+            None => vec![self.internal_admin_id().clone()],
+        }
+    }
+
+    /// Returns whether the account holds given role.
+    pub fn has_role(&self, role: Role, account_id: AccountId) -> bool {
+        self.internal_has_role(role, &account_id)
+    }
+}
+
+impl Contract {
+    /// Returns the account holding `Owner` role: the DAO once it's set, or owner.
+    fn internal_admin_id(&self) -> &AccountId {
+        self.dao_id.as_ref().unwrap_or(&self.owner_id)
+    }
+
+    pub(crate) fn internal_has_role(&self, role: Role, account_id: &AccountId) -> bool {
+        if account_id == self.internal_admin_id() {
+            return true;
+        }
+        match self.internal_role_members(role) {
+            Some(members) => members.contains(account_id),
+            None => false,
+        }
+    }
+
+    fn internal_role_members(&self, role: Role) -> Option<&UnorderedSet<AccountId>> {
+        match role {
+            Role::Owner => None,
+            Role::Guardian => Some(&self.guardians),
+            Role::FeeSetter => Some(&self.fee_setters),
+            Role::PoolManager => Some(&self.pool_managers),
+        }
+    }
+
+    fn internal_role_members_mut(&mut self, role: Role) -> &mut UnorderedSet<AccountId> {
+        match role {
+            Role::Owner => PoolError::OwnerRoleNotGranted.panic(),
+            Role::Guardian => &mut self.guardians,
+            Role::FeeSetter => &mut self.fee_setters,
+            Role::PoolManager => &mut self.pool_managers,
+        }
+    }
+
+    /// Checks that the caller holds given role.
+    pub(crate) fn assert_role(&self, role: Role) {
+        let error = match role {
+            Role::Owner => return self.assert_owner(),
+            Role::Guardian => PoolError::NotGuardian,
+            Role::FeeSetter => PoolError::NotFeeSetter,
+            Role::PoolManager => PoolError::NotPoolManager,
+        };
+        require(
+            self.internal_has_role(role, &env::predecessor_account_id()),
+            error,
+        );
+    }
+}
@@ -0,0 +1,55 @@
+//! Swap outputs delivered to Aurora. `swap_to_aurora` sends the output of the swaps to the Aurora engine
+//! with `ft_transfer_call`, whose `msg` is the EVM recipient as 40 hex digits, so the engine mints the bridged
+//! ERC-20 of the token to that address. EVM users receive proceeds in Aurora in one transaction, without
+//! withdrawing to NEAR and bridging. Tokens the engine doesn't take, e.g. without a deployed ERC-20,
+//! are returned to the deposits of the caller.
+
+use crate::errors::{require, PoolError, UnwrapOrPanic};
+use crate::*;
+
+/// Length of an EVM address in bytes.
+const EVM_ADDRESS_LEN: usize = 20;
+
+#[near_bindgen]
+impl Contract {
+    /// Sets Aurora engine used by `swap_to_aurora`. Only owner can call it.
+    #[payable]
+    pub fn set_aurora_id(&mut self, aurora_id: AccountId) {
+        assert_one_yocto();
+        self.assert_owner();
+        self.aurora_id = Some(aurora_id);
+    }
+
+    /// Returns Aurora engine used by `swap_to_aurora`, if it's set.
+    pub fn get_aurora_id(&self) -> Option<AccountId> {
+        self.aurora_id.clone()
+    }
+
+    /// Executes given swap actions from the deposits of the caller like `swap`, and sends the output of the last action
+    /// to given EVM address in Aurora, hex encoded with or without `0x`. The part of the output the engine doesn't
+    /// take is returned to the deposits of the caller. Requires attached 1 yoctoNEAR.
+    #[payable]
+    pub fn swap_to_aurora(
+        &mut self,
+        actions: Vec<SwapAction>,
+        referral_id: Option<AccountId>,
+        recipient: String,
+    ) -> Promise {
+        assert_one_yocto();
+        self.assert_running();
+        let sender_id = env::predecessor_account_id();
+        let aurora_id = self.aurora_id.clone().unwrap_or_panic(PoolError::NoAurora);
+        let msg = aurora_message(&recipient);
+        self.internal_swap_and_call(&sender_id, actions, referral_id.as_ref(), aurora_id, msg)
+    }
+}
+
+/// Returns `msg` of the transfer to the Aurora engine crediting given EVM address: the address as lowercase hex.
+fn aurora_message(recipient: &str) -> String {
+    let address = recipient.strip_prefix("0x").unwrap_or(recipient);
+    require(
+        address.len() == 2 * EVM_ADDRESS_LEN && address.chars().all(|c| c.is_ascii_hexdigit()),
+        PoolError::InvalidEvmAddress,
+    );
+    address.to_ascii_lowercase()
+}
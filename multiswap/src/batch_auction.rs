@@ -0,0 +1,412 @@
+//! Batch auction mode of a two-token pool, enabled by owner with `set_batch_auction`. The pool then doesn't take
+//! regular swaps: orders submitted in a block are collected and settled together by `finalize_batch_auction`
+//! once the block ends, all of them at a single clearing price. Orders in opposite directions are matched with
+//! each other and only the net amount is swapped through the pool, so ordering within the block can't be exploited.
+
+use crate::errors::{require, PoolError, UnwrapOrPanic};
+use crate::events::emit_swap;
+use crate::twap::spot_price;
+use crate::utils::{PRICE_PRECISION, U256};
+use crate::*;
+
+/// Maximum number of orders in a single batch, so it can be settled within gas limits.
+const MAX_BATCH_ORDERS: usize = 50;
+
+/// Order to swap `amount_in` of token_in for the other token of the pool, at the clearing price of its batch.
+#[derive(BorshSerialize, BorshDeserialize)]
+pub struct BatchOrder {
+    /// Account that submitted the order and receives the output.
+    pub owner_id: AccountId,
+    /// Token sold by the order.
+    pub token_in: AccountId,
+    /// Amount of token in, taken from the deposits when submitting the order.
+    pub amount_in: Balance,
+    /// Minimum amount out, below which the order is refunded instead of filled.
+    pub min_amount_out: Balance,
+    /// Storage taken by the order, released to the owner when it's settled.
+    pub storage_usage: StorageUsage,
+}
+
+/// Orders of a pool in batch auction mode waiting for settlement.
+#[derive(BorshSerialize, BorshDeserialize)]
+pub struct BatchAuction {
+    /// Block in which the orders were submitted.
+    pub block_index: u64,
+    pub orders: Vec<BatchOrder>,
+}
+
+/// Batch order as returned by views.
+#[derive(Debug, Serialize, Deserialize, PartialEq)]
+#[serde(crate = "near_sdk::serde")]
+pub struct BatchOrderInfo {
+    pub owner_id: AccountId,
+    pub token_in: AccountId,
+    pub amount_in: U128,
+    pub min_amount_out: U128,
+}
+
+/// Outcome of settling a batch against the pool.
+struct Clearing {
+    /// Net amount swapped through the pool, from the first token if `first_in`.
+    net_amount_in: Balance,
+    net_amount_out: Balance,
+    first_in: bool,
+    /// Total output shared by the orders selling the first token, and by the ones selling the second one.
+    first_out: Balance,
+    second_out: Balance,
+}
+
+/// Finds the clearing of given total amounts of both tokens sold to the pool. The larger side by value swaps
+/// the largest net amount through the pool at which the other side still gets at least the price of the pool swap,
+/// so both sides trade at the same price up to rounding.
+fn clear(pool: &Pool, first_total: Balance, second_total: Balance) -> Clearing {
+    let tokens = pool.tokens();
+    let first_in = U256::from(first_total) * U256::from(spot_price(pool, &tokens[0], &tokens[1]))
+        >= U256::from(second_total) * U256::from(PRICE_PRECISION);
+    let (token_in, token_out, total_in, total_out) = if first_in {
+        (&tokens[0], &tokens[1], first_total, second_total)
+    } else {
+        (&tokens[1], &tokens[0], second_total, first_total)
+    };
+    let quote = |amount_in: Balance| {
+        if amount_in == 0 {
+            Some(0)
+        } else {
+            pool.try_get_return(token_in, amount_in, token_out)
+        }
+    };
+    // Other side receives `total_in - net_amount_in`, which must be at least its amount at the pool price.
+    let fits = |net_amount_in: Balance| match quote(net_amount_in) {
+        Some(net_amount_out) if net_amount_out > 0 => {
+            U256::from(total_in - net_amount_in) * U256::from(net_amount_out)
+                >= U256::from(total_out) * U256::from(net_amount_in)
+        }
+        _ => net_amount_in == 0,
+    };
+    let (mut low, mut high) = (0, total_in);
+    while low < high {
+        let mid = high - (high - low) / 2;
+        if fits(mid) {
+            low = mid;
+        } else {
+            high = mid - 1;
+        }
+    }
+    let net_amount_out = quote(low).unwrap_or_panic(PoolError::NotEnoughLiquidity);
+    let (first_out, second_out) = if first_in {
+        (total_out + net_amount_out, total_in - low)
+    } else {
+        (total_in - low, total_out + net_amount_out)
+    };
+    Clearing {
+        net_amount_in: low,
+        net_amount_out,
+        first_in,
+        first_out,
+        second_out,
+    }
+}
+
+/// Returns total amount in of the orders selling given token.
+fn side_total(orders: &[BatchOrder], token_in: &AccountId) -> Balance {
+    orders
+        .iter()
+        .filter(|order| &order.token_in == token_in)
+        .map(|order| order.amount_in)
+        .sum()
+}
+
+/// Returns amount out of each order, its pro rata part of the output of its side.
+/// The last order of each side gets the rounding dust.
+fn amounts_out(orders: &[BatchOrder], tokens: &[AccountId], clearing: &Clearing) -> Vec<Balance> {
+    let totals_in = [
+        side_total(orders, &tokens[0]),
+        side_total(orders, &tokens[1]),
+    ];
+    let totals_out = [clearing.first_out, clearing.second_out];
+    let mut left = totals_out;
+    let mut counts = [
+        orders
+            .iter()
+            .filter(|order| order.token_in == tokens[0])
+            .count(),
+        orders
+            .iter()
+            .filter(|order| order.token_in == tokens[1])
+            .count(),
+    ];
+    orders
+        .iter()
+        .map(|order| {
+            let side = if order.token_in == tokens[0] { 0 } else { 1 };
+            counts[side] -= 1;
+            let amount_out = if counts[side] == 0 {
+                left[side]
+            } else {
+                (U256::from(order.amount_in) * U256::from(totals_out[side])
+                    / U256::from(totals_in[side]))
+                .as_u128()
+            };
+            left[side] -= amount_out;
+            amount_out
+        })
+        .collect()
+}
+
+#[near_bindgen]
+impl Contract {
+    /// Enables or disables batch auction mode of the pool. Pending orders are refunded when disabling.
+    /// Only two-token simple, stable swap and weighted pools are supported. Only owner and pool managers can call it.
+    #[payable]
+    pub fn set_batch_auction(&mut self, pool_id: u64, enabled: bool) {
+        assert_one_yocto();
+        self.assert_role(Role::PoolManager);
+        let pool = self.internal_get_pool(pool_id);
+        if !enabled {
+            if let Some(batch) = self.batch_auctions.remove(&pool_id) {
+                self.internal_refund_batch_orders(batch.orders);
+            }
+            return;
+        }
+        require(
+            matches!(
+                pool,
+                Pool::SimplePool(_) | Pool::StableSwapPool(_) | Pool::WeightedPool(_)
+            ),
+            PoolError::UnsupportedPool,
+        );
+        require(pool.tokens().len() == 2, PoolError::WrongTokenCount);
+        if !self.batch_auctions.contains_key(&pool_id) {
+            self.batch_auctions.insert(
+                &pool_id,
+                &BatchAuction {
+                    block_index: 0,
+                    orders: vec![],
+                },
+            );
+        }
+    }
+
+    /// Submits order to swap `amount_in` of token_in from the deposits of the caller in the pool in batch auction mode.
+    /// The order is filled at the clearing price of the current block once it ends, or refunded if it would get
+    /// less than `min_amount_out`. Settles the previous batch first, if it's still pending. The caller pays storage of the order.
+    pub fn submit_batch_order(
+        &mut self,
+        pool_id: u64,
+        token_in: AccountId,
+        amount_in: U128,
+        min_amount_out: U128,
+    ) {
+        require(amount_in.0 > 0, PoolError::AmountZero);
+        self.assert_pool_running(pool_id);
+        let sender_id = env::predecessor_account_id();
+        let pool = self.internal_get_pool(pool_id);
+        require(pool.tokens().contains(&token_in), PoolError::MissingToken);
+        let mut batch = self.internal_get_batch_auction(pool_id);
+        if !batch.orders.is_empty() && batch.block_index < env::block_height() {
+            self.internal_finalize_batch_auction(pool_id, batch);
+            batch = self.internal_get_batch_auction(pool_id);
+        }
+        require(
+            batch.orders.len() < MAX_BATCH_ORDERS,
+            PoolError::BatchAuctionFull,
+        );
+        self.internal_withdraw_tokens(&sender_id, &[token_in.clone()], &[amount_in.0]);
+        let mut order = BatchOrder {
+            owner_id: sender_id.clone(),
+            token_in,
+            amount_in: amount_in.into(),
+            min_amount_out: min_amount_out.into(),
+            storage_usage: 0,
+        };
+        // Order takes exactly its serialized size in the stored batch.
+        order.storage_usage = order.try_to_vec().unwrap().len() as StorageUsage;
+        batch.block_index = env::block_height();
+        batch.orders.push(order);
+        let prev_storage = env::storage_usage();
+        self.batch_auctions.insert(&pool_id, &batch);
+        self.internal_update_storage(&sender_id, prev_storage);
+        self.internal_open_position(&sender_id);
+    }
+
+    /// Settles pending orders of the pool submitted in a block that already ended. Anyone can call it.
+    pub fn finalize_batch_auction(&mut self, pool_id: u64) {
+        self.assert_pool_running(pool_id);
+        self.assert_no_flash_loan(pool_id);
+        let batch = self.internal_get_batch_auction(pool_id);
+        require(
+            batch.block_index < env::block_height(),
+            PoolError::BatchAuctionNotEnded,
+        );
+        self.internal_finalize_batch_auction(pool_id, batch);
+    }
+
+    /// Returns pending orders of the pool, or None if it's not in batch auction mode.
+    pub fn get_batch_orders(&self, pool_id: u64) -> Option<Vec<BatchOrderInfo>> {
+        self.batch_auctions.get(&pool_id).map(|batch| {
+            batch
+                .orders
+                .into_iter()
+                .map(|order| BatchOrderInfo {
+                    owner_id: order.owner_id,
+                    token_in: order.token_in,
+                    amount_in: order.amount_in.into(),
+                    min_amount_out: order.min_amount_out.into(),
+                })
+                .collect()
+        })
+    }
+}
+
+impl Contract {
+    fn internal_get_batch_auction(&self, pool_id: u64) -> BatchAuction {
+        self.batch_auctions
+            .get(&pool_id)
+            .unwrap_or_panic(PoolError::NoBatchAuction)
+    }
+
+    /// Fails if the pool is in batch auction mode, so it can only be swapped in through its batch.
+    pub(crate) fn assert_no_batch_auction(&self, pool_id: u64) {
+        require(
+            !self.batch_auctions.contains_key(&pool_id),
+            PoolError::PoolInBatchAuction,
+        );
+    }
+
+    /// Settles the orders at the clearing price. Orders that would get less than their minimum are refunded,
+    /// and the price is found again without them. If the pool has no liquidity or the net swap trips
+    /// its circuit breaker or takes the reserve below its floor, all orders are refunded.
+    fn internal_finalize_batch_auction(&mut self, pool_id: u64, mut batch: BatchAuction) {
+        let mut pool = self.internal_get_pool(pool_id);
+        let tokens = pool.tokens().to_vec();
+        let mut orders = std::mem::take(&mut batch.orders);
+        self.batch_auctions.insert(&pool_id, &batch);
+        if !pool.has_liquidity() {
+            log!(
+                "Pool {} has no liquidity, refunding its batch orders",
+                pool_id
+            );
+            orders = self.internal_refund_batch_orders(orders);
+        }
+        let (clearing, amounts_out) = loop {
+            if orders.is_empty() {
+                return;
+            }
+            let clearing = clear(
+                &pool,
+                side_total(&orders, &tokens[0]),
+                side_total(&orders, &tokens[1]),
+            );
+            let outputs = amounts_out(&orders, &tokens, &clearing);
+            if orders
+                .iter()
+                .zip(outputs.iter())
+                .all(|(order, amount_out)| *amount_out >= order.min_amount_out)
+            {
+                break (clearing, outputs);
+            }
+            let (filled, refunded): (Vec<_>, Vec<_>) = orders
+                .into_iter()
+                .zip(outputs)
+                .partition(|(order, amount_out)| *amount_out >= order.min_amount_out);
+            orders = filled.into_iter().map(|(order, _)| order).collect();
+            self.internal_refund_batch_orders(
+                refunded.into_iter().map(|(order, _)| order).collect(),
+            );
+        };
+        self.internal_update_twap(pool_id, &pool);
+        self.internal_update_circuit_breaker(pool_id, &pool);
+        let fee = pool.current_fee();
+        let (token_in, token_out) = if clearing.first_in {
+            (&tokens[0], &tokens[1])
+        } else {
+            (&tokens[1], &tokens[0])
+        };
+        if clearing.net_amount_in > 0 {
+            pool.swap(
+                token_in,
+                clearing.net_amount_in,
+                token_out,
+                clearing.net_amount_out,
+            );
+        }
+        if !self.internal_circuit_breaker_allows(pool_id, &pool) {
+            log!(
+                "Batch auction of pool {} tripped the circuit breaker",
+                pool_id
+            );
+            self.internal_refund_batch_orders(orders);
+            return;
+        }
+        if !self.internal_reserve_floor_allows(pool_id, &pool, token_out) {
+            log!(
+                "Batch auction of pool {} went below the reserve floor",
+                pool_id
+            );
+            self.internal_refund_batch_orders(orders);
+            return;
+        }
+        let fee_taken = self.internal_collect_protocol_fee(
+            pool_id,
+            &mut pool,
+            token_in,
+            clearing.net_amount_in,
+            fee,
+        );
+        self.internal_accrue_lp_fees(
+            pool_id,
+            &pool,
+            token_in,
+            clearing.net_amount_in,
+            fee,
+            fee_taken,
+        );
+        if clearing.net_amount_in > 0 {
+            self.internal_record_swap(pool_id, &pool, token_in, clearing.net_amount_in, fee);
+        }
+        self.internal_set_pool(pool_id, pool);
+        log!(
+            "Batch auction of pool {} settled {} orders",
+            pool_id,
+            orders.len()
+        );
+        for (order, amount_out) in orders.iter().zip(amounts_out) {
+            let token_out = if order.token_in == tokens[0] {
+                &tokens[1]
+            } else {
+                &tokens[0]
+            };
+            self.internal_settle_batch_order(order, token_out, amount_out);
+            emit_swap(
+                &order.owner_id,
+                pool_id,
+                &order.token_in,
+                order.amount_in,
+                token_out,
+                amount_out,
+            );
+        }
+    }
+
+    /// Refunds token in of given orders. Returns no orders left.
+    fn internal_refund_batch_orders(&mut self, orders: Vec<BatchOrder>) -> Vec<BatchOrder> {
+        for order in orders.iter() {
+            self.internal_settle_batch_order(order, &order.token_in, order.amount_in);
+        }
+        vec![]
+    }
+
+    /// Credits given amount of token to the owner of the order, and releases storage of the order.
+    fn internal_settle_batch_order(
+        &mut self,
+        order: &BatchOrder,
+        token_id: &AccountId,
+        amount: Balance,
+    ) {
+        let mut deposits = self.internal_get_account(&order.owner_id);
+        deposits.refund(token_id, amount);
+        deposits.storage_used = deposits.storage_used.saturating_sub(order.storage_usage);
+        self.deposited_amounts.insert(&order.owner_id, &deposits);
+        self.internal_close_position(&order.owner_id);
+    }
+}
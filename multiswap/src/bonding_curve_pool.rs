@@ -0,0 +1,436 @@
+use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
+use near_sdk::{env, AccountId, Balance};
+
+use crate::errors::{require, CheckedAsU128, PoolError, UnwrapOrPanic};
+use crate::utils::{mul_div, mul_div_round_up, PRICE_PRECISION, U256};
+
+const FEE_DIVISOR: u32 = 10_000;
+/// Index of the token being sold in the pool's token list.
+const SALE_TOKEN: usize = 0;
+/// Index of the token the sale is paid in.
+const RESERVE_TOKEN: usize = 1;
+
+/// Implementation of bonding curve pool, that sells supply of a new token for a reserve token
+/// at price growing linearly with the amount sold: `price = initial_price + slope * sold / PRICE_PRECISION`.
+/// Buyers can sell the token back along the same curve, so the reserve always covers the value of everything sold.
+/// Once the reserve reaches `graduation_reserve`, trading stops and the reserve together with the sale token
+/// at the final price is moved into a regular pool.
+#[derive(BorshSerialize, BorshDeserialize)]
+pub struct BondingCurvePool {
+    /// Token being sold and token it is paid in.
+    pub token_account_ids: Vec<AccountId>,
+    /// Unsold supply of the sale token and reserve collected for it.
+    pub amounts: Vec<Balance>,
+    /// Fee charged for swap (gets divided by FEE_DIVISOR).
+    pub fee: u32,
+    /// Price of the sale token in the reserve token before anything is sold, with `PRICE_PRECISION` precision.
+    pub initial_price: Balance,
+    /// Price increase for every `PRICE_PRECISION` of the sale token sold, with `PRICE_PRECISION` precision.
+    pub slope: Balance,
+    /// How much of the sale token is currently sold.
+    pub sold: Balance,
+    /// Reserve after reaching which the pool stops trading and can graduate.
+    pub graduation_reserve: Balance,
+    /// Account that supplied the sale token and receives what is left over at graduation.
+    pub creator_id: AccountId,
+    /// Id of the pool the liquidity moved to, `None` before graduation.
+    pub graduated_pool_id: Option<u64>,
+}
+
+impl BondingCurvePool {
+    pub fn new(
+        token_account_ids: Vec<AccountId>,
+        creator_id: AccountId,
+        supply: Balance,
+        initial_price: Balance,
+        slope: Balance,
+        graduation_reserve: Balance,
+        fee: u32,
+    ) -> Self {
+        require(fee < FEE_DIVISOR, PoolError::FeeTooLarge);
+        require(token_account_ids.len() == 2, PoolError::WrongTokenCount);
+        require(supply > 0, PoolError::AmountZero);
+        require(initial_price > 0, PoolError::ZeroPrice);
+        Self {
+            token_account_ids: token_account_ids.clone(),
+            amounts: vec![supply, 0],
+            fee,
+            initial_price,
+            slope,
+            sold: 0,
+            graduation_reserve,
+            creator_id,
+            graduated_pool_id: None,
+        }
+    }
+
+    /// Returns list of tokens in this pool.
+    pub fn tokens(&self) -> &[AccountId] {
+        &self.token_account_ids
+    }
+
+    /// Returns true if the pool doesn't trade anymore, because it reached graduation reserve or already graduated.
+    pub fn is_closed(&self) -> bool {
+        self.graduated_pool_id.is_some() || self.amounts[RESERVE_TOKEN] >= self.graduation_reserve
+    }
+
+    /// Checks that the reserve covers selling everything sold back to the curve.
+    #[cfg(feature = "invariant-checks")]
+    pub fn assert_invariants(&self) {
+        require(
+            self.graduated_pool_id.is_some()
+                || U256::from(self.amounts[RESERVE_TOKEN]) >= self.curve_cost(0, self.sold, false),
+            PoolError::InvariantViolated,
+        );
+    }
+
+    /// Returns price of the sale token in the reserve token when given amount is sold.
+    fn price_at(&self, sold: Balance) -> U256 {
+        U256::from(self.initial_price)
+            + U256::from(self.slope) * U256::from(sold) / U256::from(PRICE_PRECISION)
+    }
+
+    /// Returns reserve needed to move along the curve from `from` to `to` sold, i.e. the area under the price line.
+    fn curve_cost(&self, from: Balance, to: Balance, round_up: bool) -> U256 {
+        let precision = U256::from(PRICE_PRECISION);
+        let prices_sum = U256::from(2) * U256::from(self.initial_price) * precision
+            + U256::from(self.slope) * (U256::from(from) + U256::from(to));
+        let denominator = U256::from(2) * precision * precision;
+        if round_up {
+            mul_div_round_up(U256::from(to - from), prices_sum, denominator)
+        } else {
+            mul_div(U256::from(to - from), prices_sum, denominator)
+        }
+    }
+
+    /// Returns how much of the sale token can be bought for given reserve, rounded down.
+    fn buy_amount(&self, reserve: Balance) -> Balance {
+        let price = self.price_at(self.sold);
+        let precision = U256::from(PRICE_PRECISION);
+        let mut amount = if self.slope == 0 {
+            U256::from(reserve) * precision / price
+        } else {
+            // Solves `slope * x^2 / 2 + price * x = reserve` in fixed point.
+            let slope = U256::from(self.slope);
+            ((price * price + U256::from(2) * slope * U256::from(reserve)).integer_sqrt() - price)
+                * precision
+                / slope
+        }
+        .checked_as_u128();
+        // Correct for rounding of the price, so the buyer never pays less than the curve cost.
+        while amount > 0
+            && self.curve_cost(self.sold, self.sold + amount, true) > U256::from(reserve)
+        {
+            amount -= 1;
+        }
+        amount
+    }
+
+    /// Returns how much of the sale token has to be sold to receive given reserve, rounded up.
+    fn sell_amount(&self, reserve: Balance) -> Balance {
+        let price = self.price_at(self.sold);
+        let precision = U256::from(PRICE_PRECISION);
+        let mut amount = if self.slope == 0 {
+            mul_div_round_up(U256::from(reserve), precision, price)
+        } else {
+            // Solves `price * x - slope * x^2 / 2 = reserve` in fixed point.
+            let slope = U256::from(self.slope);
+            let discriminant = (price * price)
+                .checked_sub(U256::from(2) * slope * U256::from(reserve))
+                .unwrap_or_panic(PoolError::NotEnoughLiquidity);
+            mul_div_round_up(price - discriminant.integer_sqrt(), precision, slope)
+        }
+        .checked_as_u128();
+        require(amount <= self.sold, PoolError::NotEnoughLiquidity);
+        // Correct for rounding of the price, so the seller never receives more than the curve gives.
+        while self.curve_cost(self.sold - amount, self.sold, false) < U256::from(reserve) {
+            amount += 1;
+            require(amount <= self.sold, PoolError::NotEnoughLiquidity);
+        }
+        amount
+    }
+
+    /// Returns token index for given pool.
+    fn token_index(&self, token_id: &AccountId) -> usize {
+        self.token_account_ids
+            .iter()
+            .position(|id| id == token_id)
+            .unwrap_or_panic(PoolError::MissingToken)
+    }
+
+    /// Returns number of tokens in outcome, given amount.
+    /// Tokens are provided as indexes into token list for given pool.
+    fn internal_get_return(
+        &self,
+        token_in: usize,
+        amount_in: Balance,
+        token_out: usize,
+    ) -> Balance {
+        require(token_in != token_out && amount_in > 0, PoolError::Invalid);
+        require(!self.is_closed(), PoolError::PoolGraduated);
+        if token_in == RESERVE_TOKEN {
+            let reserve = (U256::from(amount_in) * U256::from(FEE_DIVISOR - self.fee)
+                / U256::from(FEE_DIVISOR))
+            .checked_as_u128();
+            let amount_out = self.buy_amount(reserve);
+            require(
+                amount_out <= self.amounts[SALE_TOKEN],
+                PoolError::NotEnoughLiquidity,
+            );
+            amount_out
+        } else {
+            require(amount_in <= self.sold, PoolError::NotEnoughLiquidity);
+            (self.curve_cost(self.sold - amount_in, self.sold, false)
+                * U256::from(FEE_DIVISOR - self.fee)
+                / U256::from(FEE_DIVISOR))
+            .checked_as_u128()
+        }
+    }
+
+    /// Returns how much token you will receive if swap `token_amount_in` of `token_in` for `token_out`.
+    pub fn get_return(
+        &self,
+        token_in: &AccountId,
+        amount_in: Balance,
+        token_out: &AccountId,
+    ) -> Balance {
+        self.internal_get_return(
+            self.token_index(token_in),
+            amount_in,
+            self.token_index(token_out),
+        )
+    }
+
+    /// Returns marginal price of `token_in` in `token_out` excluding fee, with `PRICE_PRECISION` precision.
+    pub fn spot_price(&self, token_in: &AccountId, token_out: &AccountId) -> U256 {
+        let in_idx = self.token_index(token_in);
+        require(in_idx != self.token_index(token_out), PoolError::Invalid);
+        let price = self.price_at(self.sold);
+        if in_idx == SALE_TOKEN {
+            price
+        } else {
+            U256::from(PRICE_PRECISION) * U256::from(PRICE_PRECISION) / price
+        }
+    }
+
+    /// Returns amount of token in needed to receive given amount of token out, rounded up.
+    /// Tokens are provided as indexes into token list for given pool.
+    fn internal_get_amount_in(
+        &self,
+        token_in: usize,
+        amount_out: Balance,
+        token_out: usize,
+    ) -> Balance {
+        require(token_in != token_out && amount_out > 0, PoolError::Invalid);
+        require(!self.is_closed(), PoolError::PoolGraduated);
+        if token_out == SALE_TOKEN {
+            require(
+                amount_out <= self.amounts[SALE_TOKEN],
+                PoolError::NotEnoughLiquidity,
+            );
+            mul_div_round_up(
+                self.curve_cost(self.sold, self.sold + amount_out, true),
+                U256::from(FEE_DIVISOR),
+                U256::from(FEE_DIVISOR - self.fee),
+            )
+            .checked_as_u128()
+        } else {
+            let reserve = mul_div_round_up(
+                U256::from(amount_out),
+                U256::from(FEE_DIVISOR),
+                U256::from(FEE_DIVISOR - self.fee),
+            )
+            .checked_as_u128();
+            self.sell_amount(reserve)
+        }
+    }
+
+    /// Returns how much of `token_in` is needed to receive exactly `amount_out` of `token_out`.
+    pub fn get_amount_in(
+        &self,
+        token_in: &AccountId,
+        amount_out: Balance,
+        token_out: &AccountId,
+    ) -> Balance {
+        self.internal_get_amount_in(
+            self.token_index(token_in),
+            amount_out,
+            self.token_index(token_out),
+        )
+    }
+
+    /// Moves the pool along the curve by given swap.
+    fn apply_swap(&mut self, token_in: usize, amount_in: Balance, amount_out: Balance) {
+        if token_in == RESERVE_TOKEN {
+            self.sold += amount_out;
+            self.amounts[SALE_TOKEN] -= amount_out;
+            self.amounts[RESERVE_TOKEN] += amount_in;
+        } else {
+            self.sold -= amount_in;
+            self.amounts[SALE_TOKEN] += amount_in;
+            self.amounts[RESERVE_TOKEN] -= amount_out;
+        }
+    }
+
+    /// Swap `token_amount_in` of `token_in` token into `token_out` and return how much was received.
+    /// Assuming that `token_amount_in` was already received from `sender_id`.
+    pub fn swap(
+        &mut self,
+        token_in: &AccountId,
+        amount_in: Balance,
+        token_out: &AccountId,
+        min_amount_out: Balance,
+    ) -> Balance {
+        let in_idx = self.token_index(token_in);
+        let out_idx = self.token_index(token_out);
+        let amount_out = self.internal_get_return(in_idx, amount_in, out_idx);
+        env::log_str(&format!(
+            "Swapped {} {} for {} {}",
+            amount_in, token_in, amount_out, token_out
+        ));
+        require(amount_out >= min_amount_out, PoolError::MinAmount);
+        self.apply_swap(in_idx, amount_in, amount_out);
+        amount_out
+    }
+
+    /// Swap `token_in` into exactly `amount_out` of `token_out` and return how much of `token_in` it takes.
+    /// Fails if it takes more than `max_amount_in`.
+    pub fn swap_for_exact(
+        &mut self,
+        token_in: &AccountId,
+        max_amount_in: Balance,
+        token_out: &AccountId,
+        amount_out: Balance,
+    ) -> Balance {
+        let in_idx = self.token_index(token_in);
+        let out_idx = self.token_index(token_out);
+        let amount_in = self.internal_get_amount_in(in_idx, amount_out, out_idx);
+        env::log_str(&format!(
+            "Swapped {} {} for {} {}",
+            amount_in, token_in, amount_out, token_out
+        ));
+        require(amount_in <= max_amount_in, PoolError::MaxAmountIn);
+        self.apply_swap(in_idx, amount_in, amount_out);
+        amount_in
+    }
+
+    /// Empties the pool that reached graduation reserve, recording that its liquidity moves to the pool with given id.
+    /// Returns amounts of both tokens for the new pool at the final price of the curve,
+    /// and amounts left over because the unsold supply or the reserve is not enough to match the other at this price.
+    pub fn graduate(&mut self, graduated_pool_id: u64) -> (Vec<Balance>, Vec<Balance>) {
+        require(self.graduated_pool_id.is_none(), PoolError::PoolGraduated);
+        require(
+            self.amounts[RESERVE_TOKEN] >= self.graduation_reserve,
+            PoolError::GraduationNotReached,
+        );
+        let price = self.price_at(self.sold);
+        let precision = U256::from(PRICE_PRECISION);
+        let unsold = self.amounts[SALE_TOKEN];
+        let reserve = self.amounts[RESERVE_TOKEN];
+        let sale_needed = mul_div(U256::from(reserve), precision, price);
+        let liquidity = if sale_needed <= U256::from(unsold) {
+            vec![sale_needed.as_u128(), reserve]
+        } else {
+            vec![
+                unsold,
+                mul_div(U256::from(unsold), price, precision).as_u128(),
+            ]
+        };
+        let leftover = vec![
+            unsold - liquidity[SALE_TOKEN],
+            reserve - liquidity[RESERVE_TOKEN],
+        ];
+        self.amounts = vec![0, 0];
+        self.graduated_pool_id = Some(graduated_pool_id);
+        (liquidity, leftover)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use near_sdk::test_utils::{accounts, VMContextBuilder};
+    use near_sdk::testing_env;
+
+    use super::*;
+
+    #[test]
+    fn test_bonding_curve_pool_swap() {
+        let one_token = 10u128.pow(24);
+        let mut context = VMContextBuilder::new();
+        context.predecessor_account_id(accounts(0));
+        testing_env!(context.build());
+        // Price starts at 0.1 and grows by 0.01 for every token sold.
+        let mut pool = BondingCurvePool::new(
+            vec![accounts(1), accounts(2)],
+            accounts(0),
+            1000 * one_token,
+            PRICE_PRECISION / 10,
+            PRICE_PRECISION / 100,
+            100 * one_token,
+            0,
+        );
+        // Buying 10 tokens costs 10 * (0.1 + 0.2) / 2 = 1.5.
+        assert_eq!(
+            pool.get_amount_in(&accounts(2), 10 * one_token, &accounts(1)),
+            3 * one_token / 2
+        );
+        let amount_out = pool.swap(&accounts(2), 3 * one_token / 2, &accounts(1), 1);
+        assert!(amount_out <= 10 * one_token && amount_out > 10 * one_token - 10);
+        assert_eq!(
+            pool.spot_price(&accounts(1), &accounts(2)),
+            pool.price_at(amount_out)
+        );
+        // Selling everything back returns the reserve, except for rounding in favor of the pool.
+        let amount_back = pool.swap(&accounts(1), amount_out, &accounts(2), 1);
+        assert!(amount_back <= 3 * one_token / 2 && amount_back > 3 * one_token / 2 - 10);
+        assert_eq!(pool.sold, 0);
+    }
+
+    #[test]
+    fn test_bonding_curve_pool_graduate() {
+        let one_token = 10u128.pow(24);
+        let mut context = VMContextBuilder::new();
+        context.predecessor_account_id(accounts(0));
+        testing_env!(context.build());
+        // Fixed price of 0.5 with 1% fee.
+        let mut pool = BondingCurvePool::new(
+            vec![accounts(1), accounts(2)],
+            accounts(0),
+            1000 * one_token,
+            PRICE_PRECISION / 2,
+            0,
+            10 * one_token,
+            100,
+        );
+        let amount_out = pool.swap(&accounts(2), 10 * one_token, &accounts(1), 1);
+        assert_eq!(amount_out, 99 * one_token / 5);
+        assert!(pool.is_closed());
+        let (liquidity, leftover) = pool.graduate(1);
+        assert_eq!(liquidity, vec![20 * one_token, 10 * one_token]);
+        assert_eq!(
+            leftover,
+            vec![1000 * one_token - amount_out - 20 * one_token, 0]
+        );
+        assert_eq!(pool.graduated_pool_id, Some(1));
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_POOL_GRADUATED")]
+    fn test_bonding_curve_pool_closed_after_graduation_reserve() {
+        let one_token = 10u128.pow(24);
+        let mut context = VMContextBuilder::new();
+        context.predecessor_account_id(accounts(0));
+        testing_env!(context.build());
+        let mut pool = BondingCurvePool::new(
+            vec![accounts(1), accounts(2)],
+            accounts(0),
+            1000 * one_token,
+            PRICE_PRECISION,
+            0,
+            10 * one_token,
+            0,
+        );
+        pool.swap(&accounts(2), 10 * one_token, &accounts(1), 1);
+        pool.swap(&accounts(1), one_token, &accounts(2), 1);
+    }
+}
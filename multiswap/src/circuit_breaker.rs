@@ -0,0 +1,120 @@
+//! Circuit breaker of a pool, set by owner with `set_circuit_breaker`. Prices of the pool at the first swap
+//! in a block are kept as the reference, and swaps moving any price further from it within the same block fail.
+//! Liquidity changes out of the pool ratio (imbalanced adds, removals by tokens or into one token) move prices
+//! like swaps, so they are checked the same way.
+//! This limits the damage of oracle manipulation and mistaken trades on thin pools.
+
+use crate::errors::{require, PoolError};
+use crate::twap::spot_price;
+use crate::utils::U256;
+use crate::*;
+
+/// Maximum price movement of a pool within a block.
+#[derive(BorshSerialize, BorshDeserialize)]
+pub struct CircuitBreaker {
+    /// Maximum relative price movement in basis points.
+    pub max_price_move: u32,
+    /// Block of the reference prices.
+    pub block_index: u64,
+    /// For each token, its spot price in the first token of the pool at the first swap in the block.
+    pub prices: Vec<u128>,
+}
+
+impl CircuitBreaker {
+    pub fn new(max_price_move: u32) -> Self {
+        require(max_price_move > 0, PoolError::WrongMaxPriceMove);
+        Self {
+            max_price_move,
+            block_index: 0,
+            prices: vec![],
+        }
+    }
+
+    /// Takes prices of the pool before a swap as the reference, if it's the first swap in given block.
+    /// Returns whether the reference changed.
+    pub fn update(&mut self, pool: &Pool, block_index: u64) -> bool {
+        if self.block_index == block_index && !self.prices.is_empty() {
+            return false;
+        }
+        let tokens = pool.tokens();
+        self.block_index = block_index;
+        self.prices = if pool.has_liquidity() {
+            tokens
+                .iter()
+                .map(|token_id| spot_price(pool, token_id, &tokens[0]))
+                .collect()
+        } else {
+            vec![0; tokens.len()]
+        };
+        true
+    }
+
+    /// Returns whether no price of the pool moved from the reference more than allowed.
+    /// Tokens without a reference price, e.g. when the block started with an empty pool, are skipped.
+    pub fn allows(&self, pool: &Pool) -> bool {
+        let tokens = pool.tokens();
+        tokens
+            .iter()
+            .zip(self.prices.iter())
+            .filter(|(_, reference)| **reference > 0)
+            .all(|(token_id, reference)| {
+                let price = spot_price(pool, token_id, &tokens[0]);
+                let price_move = if price > *reference {
+                    price - reference
+                } else {
+                    reference - price
+                };
+                U256::from(price_move) * U256::from(FEE_DIVISOR)
+                    <= U256::from(*reference) * U256::from(self.max_price_move)
+            })
+    }
+}
+
+#[near_bindgen]
+impl Contract {
+    /// Sets maximum price movement of the pool within a block in basis points, e.g. 500 for 5%,
+    /// or removes the circuit breaker with None. Only owner and pool managers can call it.
+    #[payable]
+    pub fn set_circuit_breaker(&mut self, pool_id: u64, max_price_move: Option<u32>) {
+        assert_one_yocto();
+        self.assert_role(Role::PoolManager);
+        self.internal_get_pool(pool_id);
+        match max_price_move {
+            Some(max_price_move) => {
+                self.circuit_breakers
+                    .insert(&pool_id, &CircuitBreaker::new(max_price_move));
+            }
+            None => {
+                self.circuit_breakers.remove(&pool_id);
+            }
+        }
+    }
+
+    /// Returns maximum price movement of the pool within a block in basis points, or None if it's not limited.
+    pub fn get_circuit_breaker(&self, pool_id: u64) -> Option<u32> {
+        self.circuit_breakers
+            .get(&pool_id)
+            .map(|circuit_breaker| circuit_breaker.max_price_move)
+    }
+}
+
+impl Contract {
+    /// Takes prices of the pool as the reference of its circuit breaker, if it has one and the block changed.
+    /// Must be called before every swap in the pool, and every liquidity change out of its ratio.
+    pub(crate) fn internal_update_circuit_breaker(&mut self, pool_id: u64, pool: &Pool) {
+        if let Some(mut circuit_breaker) = self.circuit_breakers.get(&pool_id) {
+            if circuit_breaker.update(pool, env::block_height()) {
+                self.circuit_breakers.insert(&pool_id, &circuit_breaker);
+            }
+        }
+    }
+
+    /// Returns whether prices of the pool moved within the block no more than its circuit breaker allows.
+    /// Swaps and liquidity changes out of the pool ratio must fail unless it holds after them.
+    pub(crate) fn internal_circuit_breaker_allows(&self, pool_id: u64, pool: &Pool) -> bool {
+        self.circuit_breakers
+            .get(&pool_id)
+            .map(|circuit_breaker| circuit_breaker.allows(pool))
+            .unwrap_or(true)
+    }
+}
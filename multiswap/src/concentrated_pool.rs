@@ -0,0 +1,903 @@
+use std::convert::TryInto;
+
+use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
+use near_sdk::collections::{LookupMap, TreeMap};
+use near_sdk::{env, AccountId, Balance};
+
+use crate::errors::{require, CheckedAsU128, PoolError, UnwrapOrPanic};
+use crate::utils::{mul_div, mul_div_round_up, PRICE_PRECISION, U256};
+
+const FEE_DIVISOR: u32 = 10_000;
+/// Minimum tick, corresponding to price of `1.0001 ^ MIN_TICK`.
+pub const MIN_TICK: i32 = -887_272;
+/// Maximum tick, corresponding to price of `1.0001 ^ MAX_TICK`.
+pub const MAX_TICK: i32 = 887_272;
+/// Maximum tick spacing, limits how coarse the price ranges can be.
+const MAX_TICK_SPACING: u32 = 16_384;
+/// `sqrt(1.0001)` as Q64.96 fixed point number.
+const SQRT_TICK_BASE_X96: u128 = 79_232_123_823_359_799_118_286_999_567;
+
+/// Fixed point 1.0 for square root prices (Q64.96).
+fn q96() -> U256 {
+    U256::one() << 96
+}
+
+/// Fixed point 1.0 for fee growth per unit of liquidity (Q128.128).
+fn q128() -> U256 {
+    U256::one() << 128
+}
+
+/// Returns square root of the price at given tick as Q64.96 number: `sqrt(1.0001 ^ tick) * 2^96`.
+pub fn sqrt_price_at_tick(tick: i32) -> U256 {
+    require(
+        tick >= MIN_TICK && tick <= MAX_TICK,
+        PoolError::TickOutOfBounds,
+    );
+    let mut exponent = (tick as i64).abs() as u64;
+    let mut base = U256::from(SQRT_TICK_BASE_X96);
+    let mut result = q96();
+    while exponent > 0 {
+        if exponent & 1 == 1 {
+            result = mul_div(result, base, q96());
+        }
+        exponent >>= 1;
+        if exponent > 0 {
+            base = mul_div(base, base, q96());
+        }
+    }
+    if tick < 0 {
+        mul_div(q96(), q96(), result)
+    } else {
+        result
+    }
+}
+
+/// Returns largest tick in [low, high] which square root price is not greater than given one.
+fn tick_at_sqrt_price(sqrt_price: U256, low: i32, high: i32) -> i32 {
+    let (mut low, mut high) = (low, high);
+    while low < high {
+        let mid = low + (high - low + 1) / 2;
+        if sqrt_price_at_tick(mid) <= sqrt_price {
+            low = mid;
+        } else {
+            high = mid - 1;
+        }
+    }
+    low
+}
+
+/// Amount of token 0 between two square root prices for given liquidity: `L * (b - a) / (a * b)`.
+fn amount0_delta(sqrt_a: U256, sqrt_b: U256, liquidity: u128, round_up: bool) -> U256 {
+    let numerator = U256::from(liquidity) << 96;
+    let diff = sqrt_b - sqrt_a;
+    if round_up {
+        let value = mul_div_round_up(numerator, diff, sqrt_b);
+        (value + sqrt_a - 1) / sqrt_a
+    } else {
+        mul_div(numerator, diff, sqrt_b) / sqrt_a
+    }
+}
+
+/// Amount of token 1 between two square root prices for given liquidity: `L * (b - a)`.
+fn amount1_delta(sqrt_a: U256, sqrt_b: U256, liquidity: u128, round_up: bool) -> U256 {
+    if round_up {
+        mul_div_round_up(U256::from(liquidity), sqrt_b - sqrt_a, q96())
+    } else {
+        mul_div(U256::from(liquidity), sqrt_b - sqrt_a, q96())
+    }
+}
+
+/// Returns square root price after adding given amount of token 0, rounding up.
+fn next_sqrt_price_from_amount0(sqrt_price: U256, liquidity: u128, amount: u128) -> U256 {
+    let numerator = U256::from(liquidity) << 96;
+    match U256::from(amount).checked_mul(sqrt_price) {
+        Some(product) => mul_div_round_up(numerator, sqrt_price, numerator + product),
+        None => {
+            let denominator = numerator / sqrt_price + U256::from(amount);
+            (numerator + denominator - 1) / denominator
+        }
+    }
+}
+
+/// Returns square root price after adding given amount of token 1, rounding down.
+fn next_sqrt_price_from_amount1(sqrt_price: U256, liquidity: u128, amount: u128) -> U256 {
+    sqrt_price + (U256::from(amount) << 96) / U256::from(liquidity)
+}
+
+/// Returns square root price after removing given amount of token 0, rounding up.
+fn next_sqrt_price_from_amount0_out(sqrt_price: U256, liquidity: u128, amount: u128) -> U256 {
+    let numerator = U256::from(liquidity) << 96;
+    let product = U256::from(amount)
+        .checked_mul(sqrt_price)
+        .unwrap_or_panic(PoolError::NotEnoughLiquidity);
+    require(numerator > product, PoolError::NotEnoughLiquidity);
+    mul_div_round_up(numerator, sqrt_price, numerator - product)
+}
+
+/// Returns square root price after removing given amount of token 1, rounding down.
+fn next_sqrt_price_from_amount1_out(sqrt_price: U256, liquidity: u128, amount: u128) -> U256 {
+    let quotient = ((U256::from(amount) << 96) + U256::from(liquidity) - 1) / U256::from(liquidity);
+    require(sqrt_price > quotient, PoolError::NotEnoughLiquidity);
+    sqrt_price - quotient
+}
+
+/// Returns maximum liquidity that can be provided in range (sqrt_a, sqrt_b) with given amounts at current price.
+fn liquidity_for_amounts(
+    sqrt_price: U256,
+    sqrt_a: U256,
+    sqrt_b: U256,
+    amount0: Balance,
+    amount1: Balance,
+) -> u128 {
+    let liquidity0 = |sqrt_lower: U256| {
+        mul_div(
+            U256::from(amount0),
+            mul_div(sqrt_lower, sqrt_b, q96()),
+            sqrt_b - sqrt_lower,
+        )
+    };
+    let liquidity1 = |sqrt_upper: U256| mul_div(U256::from(amount1), q96(), sqrt_upper - sqrt_a);
+    let liquidity = if sqrt_price <= sqrt_a {
+        liquidity0(sqrt_a)
+    } else if sqrt_price < sqrt_b {
+        std::cmp::min(liquidity0(sqrt_price), liquidity1(sqrt_price))
+    } else {
+        liquidity1(sqrt_b)
+    };
+    require(
+        liquidity <= U256::from(u128::MAX),
+        PoolError::LiquidityOverflow,
+    );
+    liquidity.checked_as_u128()
+}
+
+/// Result of swap computation, that can be applied to the pool.
+struct SwapResult {
+    amount_in: Balance,
+    amount_out: Balance,
+    sqrt_price: U256,
+    tick: i32,
+    liquidity: u128,
+    /// Fee growth of token in after the swap.
+    fee_growth_global: U256,
+    /// Ticks crossed by the swap with fee growth of token in at the moment of crossing.
+    crossed_ticks: Vec<(i32, U256)>,
+}
+
+/// Information stored for each initialized tick.
+#[derive(BorshSerialize, BorshDeserialize)]
+pub struct TickInfo {
+    /// Total liquidity of positions that use this tick as a boundary.
+    pub liquidity_gross: u128,
+    /// Liquidity to add when crossing the tick from left to right.
+    pub liquidity_net: i128,
+    /// Fee growth per unit of liquidity on the other side of this tick from the current tick.
+    pub fee_growth_outside: [U256; 2],
+}
+
+/// Liquidity position of a single provider in a price range.
+#[derive(BorshSerialize, BorshDeserialize)]
+pub struct Position {
+    /// Owner of the position.
+    pub owner_id: AccountId,
+    /// Lower tick of the price range.
+    pub tick_lower: i32,
+    /// Upper tick of the price range.
+    pub tick_upper: i32,
+    /// Liquidity provided in the range.
+    pub liquidity: u128,
+    /// Fee growth inside of the range as of the last update of the position.
+    pub fee_growth_inside_last: [U256; 2],
+    /// Fees collected by the position and not withdrawn yet.
+    pub tokens_owed: [Balance; 2],
+}
+
+/// Implementation of pool with concentrated liquidity, similar to "Uniswap v3".
+/// Liquidity providers open positions in a chosen price range instead of receiving shares,
+/// and earn fees only while the current price is inside of their range.
+/// Price of token 0 in token 1 is `1.0001 ^ tick`, range boundaries must be multiples of `tick_spacing`.
+#[derive(BorshSerialize, BorshDeserialize)]
+pub struct ConcentratedPool {
+    /// List of two tokens in the pool.
+    pub token_account_ids: Vec<AccountId>,
+    /// How much of each token this pool has, including fees not withdrawn by providers.
+    pub amounts: Vec<Balance>,
+    /// Fee charged for swap (gets divided by FEE_DIVISOR).
+    pub fee: u32,
+    /// Distance between ticks that can be used as range boundaries.
+    pub tick_spacing: u32,
+    /// Current square root price as Q64.96 number.
+    pub sqrt_price: U256,
+    /// Current tick.
+    pub tick: i32,
+    /// Liquidity that is currently in range.
+    pub liquidity: u128,
+    /// Fee growth per unit of liquidity over the whole history of the pool as Q128.128 number.
+    pub fee_growth_global: [U256; 2],
+    /// Initialized ticks.
+    pub ticks: TreeMap<i32, TickInfo>,
+    /// Positions by their id.
+    pub positions: LookupMap<u64, Position>,
+    /// Id of the next position.
+    pub next_position_id: u64,
+}
+
+impl ConcentratedPool {
+    pub fn new(
+        id: u32,
+        token_account_ids: Vec<AccountId>,
+        fee: u32,
+        tick_spacing: u32,
+        initial_tick: i32,
+    ) -> Self {
+        require(fee < FEE_DIVISOR, PoolError::FeeTooLarge);
+        require(token_account_ids.len() == 2, PoolError::WrongTokenCount);
+        require(
+            tick_spacing > 0 && tick_spacing <= MAX_TICK_SPACING,
+            PoolError::WrongTickSpacing,
+        );
+        Self {
+            token_account_ids: token_account_ids.clone(),
+            amounts: vec![0u128; 2],
+            fee,
+            tick_spacing,
+            sqrt_price: sqrt_price_at_tick(initial_tick),
+            tick: initial_tick,
+            liquidity: 0,
+            fee_growth_global: [U256::zero(); 2],
+            ticks: TreeMap::new(format!("t{}", id).into_bytes()),
+            positions: LookupMap::new(format!("o{}", id).into_bytes()),
+            next_position_id: 0,
+        }
+    }
+
+    /// Returns list of tokens in this pool.
+    pub fn tokens(&self) -> &[AccountId] {
+        &self.token_account_ids
+    }
+
+    /// Returns position by its id.
+    pub fn get_position(&self, position_id: u64) -> Option<Position> {
+        self.positions.get(&position_id)
+    }
+
+    /// Returns fee growth inside of the given range.
+    fn fee_growth_inside(&self, tick_lower: i32, tick_upper: i32) -> [U256; 2] {
+        let lower = self
+            .ticks
+            .get(&tick_lower)
+            .unwrap_or_panic(PoolError::NoTick);
+        let upper = self
+            .ticks
+            .get(&tick_upper)
+            .unwrap_or_panic(PoolError::NoTick);
+        let mut result = [U256::zero(); 2];
+        for i in 0..2 {
+            let global = self.fee_growth_global[i];
+            let below = if self.tick >= tick_lower {
+                lower.fee_growth_outside[i]
+            } else {
+                global.overflowing_sub(lower.fee_growth_outside[i]).0
+            };
+            let above = if self.tick < tick_upper {
+                upper.fee_growth_outside[i]
+            } else {
+                global.overflowing_sub(upper.fee_growth_outside[i]).0
+            };
+            result[i] = global.overflowing_sub(below).0.overflowing_sub(above).0;
+        }
+        result
+    }
+
+    /// Updates tick with change of liquidity of a position using it as lower or upper boundary.
+    fn update_tick(&mut self, tick: i32, liquidity_delta: i128, is_upper: bool) {
+        let mut info = self.ticks.get(&tick).unwrap_or_else(|| TickInfo {
+            liquidity_gross: 0,
+            liquidity_net: 0,
+            // By convention all fee growth before the tick was initialized happened below it.
+            fee_growth_outside: if tick <= self.tick {
+                self.fee_growth_global
+            } else {
+                [U256::zero(); 2]
+            },
+        });
+        info.liquidity_gross = if liquidity_delta >= 0 {
+            info.liquidity_gross + liquidity_delta as u128
+        } else {
+            info.liquidity_gross - liquidity_delta.unsigned_abs()
+        };
+        info.liquidity_net += if is_upper {
+            -liquidity_delta
+        } else {
+            liquidity_delta
+        };
+        if info.liquidity_gross == 0 {
+            self.ticks.remove(&tick);
+        } else {
+            self.ticks.insert(&tick, &info);
+        }
+    }
+
+    /// Returns fee growth inside of the position's range and fees owed to the position including ones accrued since the last update.
+    fn position_fees(&self, position: &Position) -> ([U256; 2], [Balance; 2]) {
+        let fee_growth_inside = self.fee_growth_inside(position.tick_lower, position.tick_upper);
+        let mut tokens_owed = position.tokens_owed;
+        for i in 0..2 {
+            let growth = fee_growth_inside[i]
+                .overflowing_sub(position.fee_growth_inside_last[i])
+                .0;
+            tokens_owed[i] +=
+                mul_div(U256::from(position.liquidity), growth, q128()).checked_as_u128();
+        }
+        (fee_growth_inside, tokens_owed)
+    }
+
+    /// Returns fees earned by the position and not withdrawn yet.
+    pub fn position_unclaimed_fees(&self, position: &Position) -> [Balance; 2] {
+        self.position_fees(position).1
+    }
+
+    /// Returns amounts of tokens corresponding to given liquidity in given range at current price.
+    fn amounts_for_liquidity(
+        &self,
+        tick_lower: i32,
+        tick_upper: i32,
+        liquidity: u128,
+        round_up: bool,
+    ) -> Vec<Balance> {
+        let sqrt_lower = sqrt_price_at_tick(tick_lower);
+        let sqrt_upper = sqrt_price_at_tick(tick_upper);
+        let (amount0, amount1) = if self.tick < tick_lower {
+            (
+                amount0_delta(sqrt_lower, sqrt_upper, liquidity, round_up),
+                U256::zero(),
+            )
+        } else if self.tick < tick_upper {
+            (
+                amount0_delta(self.sqrt_price, sqrt_upper, liquidity, round_up),
+                amount1_delta(sqrt_lower, self.sqrt_price, liquidity, round_up),
+            )
+        } else {
+            (
+                U256::zero(),
+                amount1_delta(sqrt_lower, sqrt_upper, liquidity, round_up),
+            )
+        };
+        vec![amount0.checked_as_u128(), amount1.checked_as_u128()]
+    }
+
+    /// Opens new position in range [tick_lower, tick_upper) with as much liquidity as given amounts allow.
+    /// Returns id of the position and amounts that were actually used.
+    pub fn add_position(
+        &mut self,
+        sender_id: &AccountId,
+        tick_lower: i32,
+        tick_upper: i32,
+        amounts: Vec<Balance>,
+        min_amounts: Vec<Balance>,
+    ) -> (u64, Vec<Balance>) {
+        let spacing = self.tick_spacing as i32;
+        require(
+            tick_lower < tick_upper
+                && tick_lower >= MIN_TICK
+                && tick_upper <= MAX_TICK
+                && tick_lower % spacing == 0
+                && tick_upper % spacing == 0,
+            PoolError::WrongRange,
+        );
+        require(amounts.len() == 2, PoolError::WrongTokenCount);
+        let liquidity = liquidity_for_amounts(
+            self.sqrt_price,
+            sqrt_price_at_tick(tick_lower),
+            sqrt_price_at_tick(tick_upper),
+            amounts[0],
+            amounts[1],
+        );
+        require(liquidity > 0, PoolError::ZeroLiquidity);
+        let liquidity_delta: i128 = liquidity
+            .try_into()
+            .unwrap_or_panic(PoolError::LiquidityOverflow);
+        self.update_tick(tick_lower, liquidity_delta, false);
+        self.update_tick(tick_upper, liquidity_delta, true);
+        if self.tick >= tick_lower && self.tick < tick_upper {
+            self.liquidity += liquidity;
+        }
+        let used_amounts = self.amounts_for_liquidity(tick_lower, tick_upper, liquidity, true);
+        for i in 0..2 {
+            require(used_amounts[i] <= amounts[i], PoolError::NotEnoughToken);
+            require(used_amounts[i] >= min_amounts[i], PoolError::MinAmount);
+            self.amounts[i] += used_amounts[i];
+        }
+        let position = Position {
+            owner_id: sender_id.clone(),
+            tick_lower,
+            tick_upper,
+            liquidity,
+            fee_growth_inside_last: self.fee_growth_inside(tick_lower, tick_upper),
+            tokens_owed: [0; 2],
+        };
+        let position_id = self.next_position_id;
+        self.positions.insert(&position_id, &position);
+        self.next_position_id += 1;
+        (position_id, used_amounts)
+    }
+
+    /// Removes given liquidity from the position and returns amounts to the parent, together with all the earned fees.
+    /// Removing zero liquidity only collects the fees. Position is deleted when all liquidity is removed.
+    pub fn remove_position(
+        &mut self,
+        sender_id: &AccountId,
+        position_id: u64,
+        liquidity: u128,
+        min_amounts: Vec<Balance>,
+    ) -> Vec<Balance> {
+        let mut position = self
+            .positions
+            .get(&position_id)
+            .unwrap_or_panic(PoolError::NoPosition);
+        require(&position.owner_id == sender_id, PoolError::NotOwner);
+        require(
+            position.liquidity >= liquidity,
+            PoolError::NotEnoughLiquidity,
+        );
+        let (fee_growth_inside, tokens_owed) = self.position_fees(&position);
+        position.fee_growth_inside_last = fee_growth_inside;
+        position.tokens_owed = tokens_owed;
+        let mut result = vec![0; 2];
+        if liquidity > 0 {
+            let liquidity_delta: i128 = liquidity
+                .try_into()
+                .unwrap_or_panic(PoolError::LiquidityOverflow);
+            result = self.amounts_for_liquidity(
+                position.tick_lower,
+                position.tick_upper,
+                liquidity,
+                false,
+            );
+            self.update_tick(position.tick_lower, -liquidity_delta, false);
+            self.update_tick(position.tick_upper, -liquidity_delta, true);
+            if self.tick >= position.tick_lower && self.tick < position.tick_upper {
+                self.liquidity -= liquidity;
+            }
+            position.liquidity -= liquidity;
+        }
+        for i in 0..2 {
+            require(result[i] >= min_amounts[i], PoolError::MinAmount);
+            result[i] += position.tokens_owed[i];
+            self.amounts[i] -= result[i];
+        }
+        position.tokens_owed = [0; 2];
+        if position.liquidity == 0 {
+            self.positions.remove(&position_id);
+        } else {
+            self.positions.insert(&position_id, &position);
+        }
+        result
+    }
+
+    /// Returns token index for given pool.
+    fn token_index(&self, token_id: &AccountId) -> usize {
+        self.token_account_ids
+            .iter()
+            .position(|id| id == token_id)
+            .unwrap_or_panic(PoolError::MissingToken)
+    }
+
+    /// Returns marginal price of `token_in` in `token_out` excluding fee, with `PRICE_PRECISION` precision.
+    pub fn spot_price(&self, token_in: &AccountId, token_out: &AccountId) -> U256 {
+        let in_idx = self.token_index(token_in);
+        let out_idx = self.token_index(token_out);
+        require(in_idx != out_idx, PoolError::SameToken);
+        if in_idx == 0 {
+            mul_div(
+                mul_div(self.sqrt_price, self.sqrt_price, q96()),
+                U256::from(PRICE_PRECISION),
+                q96(),
+            )
+        } else {
+            mul_div(
+                mul_div(q96(), U256::from(PRICE_PRECISION), self.sqrt_price),
+                q96(),
+                self.sqrt_price,
+            )
+        }
+    }
+
+    /// Computes swap from token with index `token_in`, crossing ticks as needed, without changing the state.
+    /// If `exact_in`, `amount` is amount of token in to swap, otherwise it's amount of token out to receive.
+    fn compute_swap(&self, token_in: usize, amount: Balance, exact_in: bool) -> SwapResult {
+        require(amount > 0, PoolError::Invalid);
+        let zero_for_one = token_in == 0;
+        let mut result = SwapResult {
+            amount_in: 0,
+            amount_out: 0,
+            sqrt_price: self.sqrt_price,
+            tick: self.tick,
+            liquidity: self.liquidity,
+            fee_growth_global: self.fee_growth_global[token_in],
+            crossed_ticks: vec![],
+        };
+        let mut amount_in = U256::zero();
+        let mut amount_out = U256::zero();
+        let mut amount_remaining = amount;
+        while amount_remaining > 0 {
+            let next_tick = if zero_for_one {
+                self.ticks.floor_key(&result.tick)
+            } else {
+                self.ticks.higher(&result.tick)
+            };
+            require(
+                next_tick.is_some() || result.liquidity > 0,
+                PoolError::NotEnoughLiquidity,
+            );
+            let target_tick = next_tick.unwrap_or(if zero_for_one { MIN_TICK } else { MAX_TICK });
+            let target_sqrt_price = sqrt_price_at_tick(target_tick);
+            let liquidity = result.liquidity;
+            let sqrt_price = result.sqrt_price;
+
+            let amount_less_fee = (U256::from(amount_remaining)
+                * U256::from(FEE_DIVISOR - self.fee)
+                / U256::from(FEE_DIVISOR))
+            .checked_as_u128();
+            let (next_sqrt_price, reached_target) = if liquidity == 0 {
+                (target_sqrt_price, true)
+            } else if exact_in {
+                let amount_in_to_target = if zero_for_one {
+                    amount0_delta(target_sqrt_price, sqrt_price, liquidity, true)
+                } else {
+                    amount1_delta(sqrt_price, target_sqrt_price, liquidity, true)
+                };
+                if U256::from(amount_less_fee) >= amount_in_to_target {
+                    (target_sqrt_price, true)
+                } else if zero_for_one {
+                    (
+                        next_sqrt_price_from_amount0(sqrt_price, liquidity, amount_less_fee),
+                        false,
+                    )
+                } else {
+                    (
+                        next_sqrt_price_from_amount1(sqrt_price, liquidity, amount_less_fee),
+                        false,
+                    )
+                }
+            } else {
+                let amount_out_to_target = if zero_for_one {
+                    amount1_delta(target_sqrt_price, sqrt_price, liquidity, false)
+                } else {
+                    amount0_delta(sqrt_price, target_sqrt_price, liquidity, false)
+                };
+                if U256::from(amount_remaining) >= amount_out_to_target {
+                    (target_sqrt_price, true)
+                } else if zero_for_one {
+                    (
+                        next_sqrt_price_from_amount1_out(sqrt_price, liquidity, amount_remaining),
+                        false,
+                    )
+                } else {
+                    (
+                        next_sqrt_price_from_amount0_out(sqrt_price, liquidity, amount_remaining),
+                        false,
+                    )
+                }
+            };
+            let (mut step_amount_in, mut step_amount_out) = if liquidity == 0 {
+                (0, 0)
+            } else if zero_for_one {
+                (
+                    amount0_delta(next_sqrt_price, sqrt_price, liquidity, true).checked_as_u128(),
+                    amount1_delta(next_sqrt_price, sqrt_price, liquidity, false).checked_as_u128(),
+                )
+            } else {
+                (
+                    amount1_delta(sqrt_price, next_sqrt_price, liquidity, true).checked_as_u128(),
+                    amount0_delta(sqrt_price, next_sqrt_price, liquidity, false).checked_as_u128(),
+                )
+            };
+            let step_fee = if exact_in && !reached_target {
+                // Price doesn't reach the target, so all the remaining amount is used.
+                step_amount_in = step_amount_in.min(amount_less_fee);
+                amount_remaining - step_amount_in
+            } else {
+                let step_fee = mul_div_round_up(
+                    U256::from(step_amount_in),
+                    U256::from(self.fee),
+                    U256::from(FEE_DIVISOR - self.fee),
+                )
+                .checked_as_u128();
+                if exact_in {
+                    step_fee.min(amount_remaining - step_amount_in)
+                } else {
+                    step_fee
+                }
+            };
+            if exact_in {
+                amount_remaining -= step_amount_in + step_fee;
+                amount_out += U256::from(step_amount_out);
+            } else {
+                if !reached_target {
+                    // Price is rounded in favor of the pool, so it covers at least the remaining amount.
+                    step_amount_out = amount_remaining;
+                }
+                amount_remaining -= step_amount_out.min(amount_remaining);
+                amount_in += U256::from(step_amount_in + step_fee);
+            }
+            if liquidity > 0 {
+                result.fee_growth_global = result
+                    .fee_growth_global
+                    .overflowing_add(mul_div(U256::from(step_fee), q128(), U256::from(liquidity)))
+                    .0;
+            }
+            result.sqrt_price = next_sqrt_price;
+
+            if reached_target {
+                require(next_tick.is_some(), PoolError::NotEnoughLiquidity);
+                let info = self.ticks.get(&target_tick).unwrap();
+                let liquidity_net = if zero_for_one {
+                    -info.liquidity_net
+                } else {
+                    info.liquidity_net
+                };
+                result.liquidity = if liquidity_net >= 0 {
+                    liquidity + liquidity_net as u128
+                } else {
+                    liquidity - liquidity_net.unsigned_abs()
+                };
+                result
+                    .crossed_ticks
+                    .push((target_tick, result.fee_growth_global));
+                result.tick = if zero_for_one {
+                    target_tick - 1
+                } else {
+                    target_tick
+                };
+            } else {
+                let (low, high) = if zero_for_one {
+                    (target_tick, result.tick)
+                } else {
+                    (result.tick, target_tick)
+                };
+                result.tick = tick_at_sqrt_price(result.sqrt_price, low, high);
+            }
+        }
+        if exact_in {
+            result.amount_in = amount;
+            result.amount_out = amount_out.checked_as_u128();
+        } else {
+            result.amount_in = amount_in.checked_as_u128();
+            result.amount_out = amount;
+        }
+        result
+    }
+
+    /// Applies computed swap to the state of the pool.
+    fn apply_swap(&mut self, in_idx: usize, out_idx: usize, result: SwapResult) {
+        for (tick, fee_growth_in) in result.crossed_ticks {
+            let mut info = self.ticks.get(&tick).unwrap();
+            for i in 0..2 {
+                let global = if i == in_idx {
+                    fee_growth_in
+                } else {
+                    self.fee_growth_global[i]
+                };
+                info.fee_growth_outside[i] = global.overflowing_sub(info.fee_growth_outside[i]).0;
+            }
+            self.ticks.insert(&tick, &info);
+        }
+        self.sqrt_price = result.sqrt_price;
+        self.tick = result.tick;
+        self.liquidity = result.liquidity;
+        self.fee_growth_global[in_idx] = result.fee_growth_global;
+        self.amounts[in_idx] += result.amount_in;
+        self.amounts[out_idx] -= result.amount_out;
+    }
+
+    /// Returns how much token you will receive if swap `token_amount_in` of `token_in` for `token_out`.
+    /// Simulates the swap through all the ranges it would cross.
+    pub fn get_return(
+        &self,
+        token_in: &AccountId,
+        amount_in: Balance,
+        token_out: &AccountId,
+    ) -> Balance {
+        let in_idx = self.token_index(token_in);
+        let out_idx = self.token_index(token_out);
+        require(in_idx != out_idx, PoolError::SameToken);
+        self.compute_swap(in_idx, amount_in, true).amount_out
+    }
+
+    /// Returns how much of `token_in` is needed to receive exactly `amount_out` of `token_out`.
+    pub fn get_amount_in(
+        &self,
+        token_in: &AccountId,
+        amount_out: Balance,
+        token_out: &AccountId,
+    ) -> Balance {
+        let in_idx = self.token_index(token_in);
+        let out_idx = self.token_index(token_out);
+        require(in_idx != out_idx, PoolError::SameToken);
+        self.compute_swap(in_idx, amount_out, false).amount_in
+    }
+
+    /// Swap `token_amount_in` of `token_in` token into `token_out` and return how much was received.
+    /// Assuming that `token_amount_in` was already received from `sender_id`.
+    pub fn swap(
+        &mut self,
+        token_in: &AccountId,
+        amount_in: Balance,
+        token_out: &AccountId,
+        min_amount_out: Balance,
+    ) -> Balance {
+        let in_idx = self.token_index(token_in);
+        let out_idx = self.token_index(token_out);
+        require(in_idx != out_idx, PoolError::SameToken);
+        let result = self.compute_swap(in_idx, amount_in, true);
+        let amount_out = result.amount_out;
+        env::log_str(&format!(
+            "Swapped {} {} for {} {}",
+            amount_in, token_in, amount_out, token_out
+        ));
+        require(amount_out >= min_amount_out, PoolError::MinAmount);
+        self.apply_swap(in_idx, out_idx, result);
+        amount_out
+    }
+
+    /// Swap `token_in` into exactly `amount_out` of `token_out` and return how much of `token_in` it takes.
+    /// Fails if it takes more than `max_amount_in`.
+    pub fn swap_for_exact(
+        &mut self,
+        token_in: &AccountId,
+        max_amount_in: Balance,
+        token_out: &AccountId,
+        amount_out: Balance,
+    ) -> Balance {
+        let in_idx = self.token_index(token_in);
+        let out_idx = self.token_index(token_out);
+        require(in_idx != out_idx, PoolError::SameToken);
+        let result = self.compute_swap(in_idx, amount_out, false);
+        let amount_in = result.amount_in;
+        env::log_str(&format!(
+            "Swapped {} {} for {} {}",
+            amount_in, token_in, amount_out, token_out
+        ));
+        require(amount_in <= max_amount_in, PoolError::MaxAmountIn);
+        self.apply_swap(in_idx, out_idx, result);
+        amount_in
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use near_sdk::test_utils::{accounts, VMContextBuilder};
+    use near_sdk::testing_env;
+
+    use super::*;
+
+    #[test]
+    fn test_tick_math() {
+        assert_eq!(sqrt_price_at_tick(0), q96());
+        for tick in [-100_000, -1, 1, 23_027, 500_000].iter() {
+            let sqrt_price = sqrt_price_at_tick(*tick);
+            assert!(sqrt_price_at_tick(*tick - 1) < sqrt_price);
+            assert_eq!(tick_at_sqrt_price(sqrt_price, MIN_TICK, MAX_TICK), *tick);
+            assert_eq!(
+                tick_at_sqrt_price(sqrt_price + 1, MIN_TICK, MAX_TICK),
+                *tick
+            );
+        }
+    }
+
+    #[test]
+    fn test_concentrated_pool_swap() {
+        let one_near = 10u128.pow(24);
+        let mut context = VMContextBuilder::new();
+        context.predecessor_account_id(accounts(0));
+        testing_env!(context.build());
+        let mut pool = ConcentratedPool::new(0, vec![accounts(1), accounts(2)], 30, 10, 0);
+        let (position_id, used) = pool.add_position(
+            &accounts(0),
+            -1000,
+            1000,
+            vec![100 * one_near, 100 * one_near],
+            vec![0, 0],
+        );
+        assert_eq!(used[0], used[1]);
+        assert_eq!(pool.amounts, used);
+        // Price is 1, liquidity concentrated around it gives much less slippage than constant product of same amounts.
+        let amount_out = pool.swap(&accounts(1), one_near, &accounts(2), 1);
+        assert!(amount_out > 99 * one_near / 100 && amount_out < 997 * one_near / 1000);
+        assert!(pool.tick < 0);
+        let position = pool.get_position(position_id).unwrap();
+        let fees = pool.position_unclaimed_fees(&position);
+        assert!(fees[0] > 0 && fees[0] <= 3 * one_near / 1000);
+        assert_eq!(fees[1], 0);
+        let amounts =
+            pool.remove_position(&accounts(0), position_id, position.liquidity, vec![0, 0]);
+        assert!(amounts[0] <= used[0] + one_near && amounts[0] + 2 >= used[0] + one_near);
+        assert!(amounts[1] <= used[1] - amount_out);
+        assert!(pool.get_position(position_id).is_none());
+        assert_eq!(pool.liquidity, 0);
+    }
+
+    #[test]
+    fn test_concentrated_pool_swap_for_exact() {
+        let one_near = 10u128.pow(24);
+        let mut context = VMContextBuilder::new();
+        context.predecessor_account_id(accounts(0));
+        testing_env!(context.build());
+        let mut pool = ConcentratedPool::new(0, vec![accounts(1), accounts(2)], 30, 10, 0);
+        pool.add_position(
+            &accounts(0),
+            -100,
+            100,
+            vec![one_near, one_near],
+            vec![0, 0],
+        );
+        pool.add_position(
+            &accounts(0),
+            -1000,
+            1000,
+            vec![10 * one_near, 10 * one_near],
+            vec![0, 0],
+        );
+        // Large enough to cross the narrow range in both directions.
+        for (token_in, token_out) in [(accounts(1), accounts(2)), (accounts(2), accounts(1))].iter()
+        {
+            let amount_out = 2 * one_near;
+            let amount_in = pool.get_amount_in(token_in, amount_out, token_out);
+            let exact_out = pool.get_return(token_in, amount_in, token_out);
+            assert!(exact_out >= amount_out && exact_out < amount_out + 10);
+            assert_eq!(
+                pool.swap_for_exact(token_in, amount_in, token_out, amount_out),
+                amount_in
+            );
+        }
+    }
+
+    #[test]
+    fn test_swap_crosses_ranges() {
+        let one_near = 10u128.pow(24);
+        let mut context = VMContextBuilder::new();
+        context.predecessor_account_id(accounts(0));
+        testing_env!(context.build());
+        let mut pool = ConcentratedPool::new(0, vec![accounts(1), accounts(2)], 30, 10, 0);
+        pool.add_position(
+            &accounts(0),
+            -100,
+            100,
+            vec![one_near, one_near],
+            vec![0, 0],
+        );
+        // Range fully above the current price holds only token 0.
+        let (_, used) = pool.add_position(
+            &accounts(1),
+            200,
+            400,
+            vec![10 * one_near, 10 * one_near],
+            vec![0, 0],
+        );
+        assert_eq!(used[1], 0);
+        let expected = pool.get_return(&accounts(2), 5 * one_near, &accounts(1));
+        let amount_out = pool.swap(&accounts(2), 5 * one_near, &accounts(1), 1);
+        assert_eq!(amount_out, expected);
+        assert!(pool.tick >= 200 && pool.tick < 400);
+        assert_eq!(
+            pool.liquidity,
+            pool.ticks.get(&200).unwrap().liquidity_net as u128
+        );
+        // Swapping back crosses into the first range again.
+        pool.swap(&accounts(1), amount_out, &accounts(2), 1);
+        assert!(pool.tick < 100);
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_NOT_ENOUGH_LIQUIDITY")]
+    fn test_swap_out_of_liquidity() {
+        let one_near = 10u128.pow(24);
+        let mut context = VMContextBuilder::new();
+        context.predecessor_account_id(accounts(0));
+        testing_env!(context.build());
+        let mut pool = ConcentratedPool::new(0, vec![accounts(1), accounts(2)], 30, 10, 0);
+        pool.add_position(&accounts(0), -10, 10, vec![one_near, one_near], vec![0, 0]);
+        pool.swap(&accounts(1), 10 * one_near, &accounts(2), 1);
+    }
+}
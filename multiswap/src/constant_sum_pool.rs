@@ -0,0 +1,476 @@
+use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
+use near_sdk::collections::LookupMap;
+use near_sdk::{env, AccountId, Balance};
+
+use crate::errors::{require, CheckedAsU128, PoolError, UnwrapOrPanic};
+use crate::utils::{add_to_collection, mul_div_round_up, PRICE_PRECISION, U256};
+
+const FEE_DIVISOR: u32 = 10_000;
+/// Divisor of `min_reserve_bps`.
+const BPS_DIVISOR: u32 = 10_000;
+
+/// Implementation of constant sum pool, where all tokens trade 1:1, i.e. `sum(balance_i) = const`.
+/// Should be used for tokens representing the same asset, e.g. two bridged versions of it, as trades don't suffer slippage.
+/// To bound the exposure of liquidity providers if one of the tokens loses its peg, swaps and withdrawals fail
+/// once balance of any token would drop below `min_reserve_bps` of the total balance.
+/// Liquidity providers receive shares in proportion to the sum of deposited amounts.
+#[derive(BorshSerialize, BorshDeserialize)]
+pub struct ConstantSumPool {
+    /// List of tokens in the pool.
+    pub token_account_ids: Vec<AccountId>,
+    /// How much of each token this pool has.
+    pub amounts: Vec<Balance>,
+    /// Fee charged for swap (gets divided by FEE_DIVISOR).
+    pub fee: u32,
+    /// Minimum share of the total balance, in basis points, that each token must keep after swaps and withdrawals.
+    pub min_reserve_bps: u32,
+    /// Shares of the pool by liquidity providers.
+    pub shares: LookupMap<AccountId, Balance>,
+    /// Total number of shares.
+    pub shares_total_supply: Balance,
+}
+
+impl ConstantSumPool {
+    pub fn new(id: u32, token_account_ids: Vec<AccountId>, fee: u32, min_reserve_bps: u32) -> Self {
+        require(fee < FEE_DIVISOR, PoolError::FeeTooLarge);
+        require(token_account_ids.len() >= 2, PoolError::NotEnoughTokens);
+        require(
+            min_reserve_bps as u64 * token_account_ids.len() as u64 <= BPS_DIVISOR as u64,
+            PoolError::WrongMinReserve,
+        );
+        Self {
+            token_account_ids: token_account_ids.clone(),
+            amounts: vec![0u128; token_account_ids.len()],
+            fee,
+            min_reserve_bps,
+            shares: LookupMap::new(format!("s{}", id).into_bytes()),
+            shares_total_supply: 0,
+        }
+    }
+
+    /// Returns number of shares given account has.
+    pub fn share_balances(&self, account_id: &AccountId) -> Balance {
+        self.shares.get(account_id).unwrap_or_default()
+    }
+
+    /// Returns total number of shares in this pool.
+    pub fn share_total_balance(&self) -> Balance {
+        self.shares_total_supply
+    }
+
+    /// Returns list of tokens in this pool.
+    pub fn tokens(&self) -> &[AccountId] {
+        &self.token_account_ids
+    }
+
+    /// Checks that the pool has nonzero total balance while it has shares.
+    #[cfg(feature = "invariant-checks")]
+    pub fn assert_invariants(&self) {
+        require(
+            self.shares_total_supply == 0 || !total_amount(&self.amounts).is_zero(),
+            PoolError::InvariantViolated,
+        );
+    }
+
+    /// Checks that the swap didn't decrease sum of balances, given balances before the swap.
+    #[cfg(feature = "invariant-checks")]
+    pub fn assert_swap_invariant(&self, prev_amounts: &[Balance]) {
+        require(
+            total_amount(&self.amounts) >= total_amount(prev_amounts),
+            PoolError::InvariantViolated,
+        );
+    }
+
+    /// Checks that every token keeps at least `min_reserve_bps` of the total balance in given balances.
+    fn assert_reserves(&self, amounts: &[Balance]) {
+        let total = total_amount(amounts);
+        for amount in amounts.iter() {
+            require(
+                U256::from(*amount) * U256::from(BPS_DIVISOR)
+                    >= total * U256::from(self.min_reserve_bps),
+                PoolError::ReserveOutOfBounds,
+            );
+        }
+    }
+
+    /// Returns part of given deposit or withdrawal above the pool ratio, which is equivalent to a swap.
+    fn imbalance(&self, amounts: &[Balance]) -> U256 {
+        let total = total_amount(&self.amounts);
+        if total.is_zero() {
+            return U256::zero();
+        }
+        let value = total_amount(amounts);
+        let mut imbalance = U256::zero();
+        for i in 0..amounts.len() {
+            let ideal_amount = value * U256::from(self.amounts[i]) / total;
+            imbalance += U256::from(amounts[i]).saturating_sub(ideal_amount);
+        }
+        imbalance
+    }
+
+    /// Returns swap fee charged on the imbalanced part of given deposit or withdrawal, rounded up.
+    fn imbalance_fee(&self, amounts: &[Balance]) -> U256 {
+        mul_div_round_up(
+            self.imbalance(amounts),
+            U256::from(self.fee),
+            U256::from(FEE_DIVISOR),
+        )
+    }
+
+    /// Returns number of shares minted for adding given amounts of tokens, and amounts of them actually used,
+    /// which are all of them.
+    pub fn predict_add_liquidity(&self, amounts: &[Balance]) -> (Balance, Vec<Balance>) {
+        let n = self.token_account_ids.len();
+        require(amounts.len() == n, PoolError::WrongTokenCount);
+        let value = total_amount(amounts);
+        let shares = if self.shares_total_supply > 0 {
+            (value.saturating_sub(self.imbalance_fee(amounts))
+                * U256::from(self.shares_total_supply)
+                / total_amount(&self.amounts))
+            .checked_as_u128()
+        } else {
+            value.checked_as_u128()
+        };
+        require(shares > 0, PoolError::ZeroShares);
+        let new_amounts: Vec<Balance> = (0..n).map(|i| self.amounts[i] + amounts[i]).collect();
+        self.assert_reserves(&new_amounts);
+        (shares, amounts.to_vec())
+    }
+
+    /// Adds the amounts of tokens to liquidity pool and returns number of shares that this user receives.
+    /// Deposits don't need to be in the pool ratio, but imbalanced part is charged a fee same as swapping it.
+    pub fn add_liquidity(&mut self, sender_id: &AccountId, amounts: &mut Vec<Balance>) -> Balance {
+        let (shares, _) = self.predict_add_liquidity(amounts);
+        for (amount, added) in self.amounts.iter_mut().zip(amounts.iter()) {
+            *amount += added;
+        }
+        self.shares_total_supply += shares;
+        add_to_collection(&mut self.shares, &sender_id, shares);
+        shares
+    }
+
+    /// Transfers given number of shares from one account to another.
+    pub fn share_transfer(
+        &mut self,
+        sender_id: &AccountId,
+        receiver_id: &AccountId,
+        shares: Balance,
+    ) {
+        self.burn_shares(sender_id, shares);
+        self.shares_total_supply += shares;
+        add_to_collection(&mut self.shares, receiver_id, shares);
+    }
+
+    /// Takes given number of shares from the account, leaving them in the total supply without owner.
+    pub fn lock_shares(&mut self, sender_id: &AccountId, shares: Balance) {
+        self.burn_shares(sender_id, shares);
+        self.shares_total_supply += shares;
+    }
+
+    /// Burns given number of shares of the given account.
+    fn burn_shares(&mut self, sender_id: &AccountId, shares: Balance) {
+        let prev_shares_amount = self
+            .shares
+            .get(&sender_id)
+            .unwrap_or_panic(PoolError::NoShares);
+        require(prev_shares_amount >= shares, PoolError::NotEnoughShares);
+        if prev_shares_amount == shares {
+            self.shares.remove(&sender_id);
+        } else {
+            self.shares
+                .insert(&sender_id, &(prev_shares_amount - shares));
+        }
+        self.shares_total_supply -= shares;
+    }
+
+    /// Removes given number of shares from the pool and returns amounts to the parent.
+    pub fn remove_liquidity(
+        &mut self,
+        sender_id: &AccountId,
+        shares: Balance,
+        min_amounts: Vec<Balance>,
+    ) -> Vec<Balance> {
+        let mut result = vec![];
+        for i in 0..self.token_account_ids.len() {
+            let amount = (U256::from(self.amounts[i]) * U256::from(shares)
+                / U256::from(self.shares_total_supply))
+            .checked_as_u128();
+            require(amount >= min_amounts[i], PoolError::MinAmount);
+            self.amounts[i] -= amount;
+            result.push(amount);
+        }
+        self.burn_shares(sender_id, shares);
+        result
+    }
+
+    /// Burns shares needed to withdraw exactly given amounts of tokens and returns number of burnt shares.
+    /// Part of the withdrawal above the pool ratio is charged swap fee. Fails if it needs more than `max_burn_shares`.
+    pub fn remove_liquidity_by_tokens(
+        &mut self,
+        sender_id: &AccountId,
+        amounts: Vec<Balance>,
+        max_burn_shares: Balance,
+    ) -> Balance {
+        let n = self.token_account_ids.len();
+        require(amounts.len() == n, PoolError::WrongTokenCount);
+        let mut new_amounts = self.amounts.clone();
+        for i in 0..n {
+            require(amounts[i] <= new_amounts[i], PoolError::NotEnoughLiquidity);
+            new_amounts[i] -= amounts[i];
+        }
+        self.assert_reserves(&new_amounts);
+        // Round up in favor of the pool.
+        let shares = mul_div_round_up(
+            total_amount(&amounts) + self.imbalance_fee(&amounts),
+            U256::from(self.shares_total_supply),
+            total_amount(&self.amounts),
+        )
+        .checked_as_u128();
+        require(shares <= max_burn_shares, PoolError::MaxBurnShares);
+        self.burn_shares(sender_id, shares);
+        self.amounts = new_amounts;
+        shares
+    }
+
+    /// Burns given number of shares and returns amount of given token to the parent.
+    /// Part of the amount that is converted from the other tokens is charged swap fee.
+    pub fn remove_liquidity_one_token(
+        &mut self,
+        sender_id: &AccountId,
+        shares: Balance,
+        token_out: &AccountId,
+        min_amount_out: Balance,
+    ) -> Balance {
+        require(shares < self.shares_total_supply, PoolError::SharesTooLarge);
+        let out_idx = self.token_index(token_out);
+        let value = (total_amount(&self.amounts) * U256::from(shares)
+            / U256::from(self.shares_total_supply))
+        .checked_as_u128();
+        let mut amounts = vec![0; self.token_account_ids.len()];
+        amounts[out_idx] = value;
+        let amount_out = value - self.imbalance_fee(&amounts).checked_as_u128();
+        require(amount_out >= min_amount_out, PoolError::MinAmount);
+        require(
+            amount_out <= self.amounts[out_idx],
+            PoolError::NotEnoughLiquidity,
+        );
+        self.burn_shares(sender_id, shares);
+        self.amounts[out_idx] -= amount_out;
+        self.assert_reserves(&self.amounts);
+        amount_out
+    }
+
+    /// Returns token index for given pool.
+    fn token_index(&self, token_id: &AccountId) -> usize {
+        self.token_account_ids
+            .iter()
+            .position(|id| id == token_id)
+            .unwrap_or_panic(PoolError::MissingToken)
+    }
+
+    /// Checks that the swap is possible and keeps balances within bounds.
+    fn assert_swap(
+        &self,
+        token_in: usize,
+        amount_in: Balance,
+        token_out: usize,
+        amount_out: Balance,
+    ) {
+        require(
+            amount_out <= self.amounts[token_out],
+            PoolError::NotEnoughLiquidity,
+        );
+        let mut new_amounts = self.amounts.clone();
+        new_amounts[token_in] += amount_in;
+        new_amounts[token_out] -= amount_out;
+        self.assert_reserves(&new_amounts);
+    }
+
+    /// Returns number of tokens in outcome, given amount.
+    /// Tokens are provided as indexes into token list for given pool.
+    fn internal_get_return(
+        &self,
+        token_in: usize,
+        amount_in: Balance,
+        token_out: usize,
+    ) -> Balance {
+        require(token_in != token_out && amount_in > 0, PoolError::Invalid);
+        let amount_out = (U256::from(amount_in) * U256::from(FEE_DIVISOR - self.fee)
+            / U256::from(FEE_DIVISOR))
+        .checked_as_u128();
+        self.assert_swap(token_in, amount_in, token_out, amount_out);
+        amount_out
+    }
+
+    /// Returns how much token you will receive if swap `token_amount_in` of `token_in` for `token_out`.
+    pub fn get_return(
+        &self,
+        token_in: &AccountId,
+        amount_in: Balance,
+        token_out: &AccountId,
+    ) -> Balance {
+        self.internal_get_return(
+            self.token_index(token_in),
+            amount_in,
+            self.token_index(token_out),
+        )
+    }
+
+    /// Returns marginal price of `token_in` in `token_out` excluding fee, with `PRICE_PRECISION` precision.
+    /// All tokens trade 1:1.
+    pub fn spot_price(&self, token_in: &AccountId, token_out: &AccountId) -> U256 {
+        require(
+            self.token_index(token_in) != self.token_index(token_out),
+            PoolError::Invalid,
+        );
+        U256::from(PRICE_PRECISION)
+    }
+
+    /// Returns amount of token in needed to receive given amount of token out, rounded up.
+    /// Tokens are provided as indexes into token list for given pool.
+    fn internal_get_amount_in(
+        &self,
+        token_in: usize,
+        amount_out: Balance,
+        token_out: usize,
+    ) -> Balance {
+        require(token_in != token_out && amount_out > 0, PoolError::Invalid);
+        let amount_in = mul_div_round_up(
+            U256::from(amount_out),
+            U256::from(FEE_DIVISOR),
+            U256::from(FEE_DIVISOR - self.fee),
+        )
+        .checked_as_u128();
+        self.assert_swap(token_in, amount_in, token_out, amount_out);
+        amount_in
+    }
+
+    /// Returns how much of `token_in` is needed to receive exactly `amount_out` of `token_out`.
+    pub fn get_amount_in(
+        &self,
+        token_in: &AccountId,
+        amount_out: Balance,
+        token_out: &AccountId,
+    ) -> Balance {
+        self.internal_get_amount_in(
+            self.token_index(token_in),
+            amount_out,
+            self.token_index(token_out),
+        )
+    }
+
+    /// Swap `token_amount_in` of `token_in` token into `token_out` and return how much was received.
+    /// Assuming that `token_amount_in` was already received from `sender_id`.
+    pub fn swap(
+        &mut self,
+        token_in: &AccountId,
+        amount_in: Balance,
+        token_out: &AccountId,
+        min_amount_out: Balance,
+    ) -> Balance {
+        let in_idx = self.token_index(token_in);
+        let out_idx = self.token_index(token_out);
+        let amount_out = self.internal_get_return(in_idx, amount_in, out_idx);
+        env::log_str(&format!(
+            "Swapped {} {} for {} {}",
+            amount_in, token_in, amount_out, token_out
+        ));
+        require(amount_out >= min_amount_out, PoolError::MinAmount);
+        self.amounts[in_idx] += amount_in;
+        self.amounts[out_idx] -= amount_out;
+        amount_out
+    }
+
+    /// Swap `token_in` into exactly `amount_out` of `token_out` and return how much of `token_in` it takes.
+    /// Fails if it takes more than `max_amount_in`.
+    pub fn swap_for_exact(
+        &mut self,
+        token_in: &AccountId,
+        max_amount_in: Balance,
+        token_out: &AccountId,
+        amount_out: Balance,
+    ) -> Balance {
+        let in_idx = self.token_index(token_in);
+        let out_idx = self.token_index(token_out);
+        let amount_in = self.internal_get_amount_in(in_idx, amount_out, out_idx);
+        env::log_str(&format!(
+            "Swapped {} {} for {} {}",
+            amount_in, token_in, amount_out, token_out
+        ));
+        require(amount_in <= max_amount_in, PoolError::MaxAmountIn);
+        self.amounts[in_idx] += amount_in;
+        self.amounts[out_idx] -= amount_out;
+        amount_in
+    }
+}
+
+/// Returns sum of given amounts.
+fn total_amount(amounts: &[Balance]) -> U256 {
+    amounts
+        .iter()
+        .fold(U256::zero(), |total, amount| total + U256::from(*amount))
+}
+
+#[cfg(test)]
+mod tests {
+    use near_sdk::test_utils::{accounts, VMContextBuilder};
+    use near_sdk::testing_env;
+
+    use super::*;
+
+    #[test]
+    fn test_constant_sum_pool_swap() {
+        let one_token = 10u128.pow(24);
+        let mut context = VMContextBuilder::new();
+        context.predecessor_account_id(accounts(0));
+        testing_env!(context.build());
+        let mut pool = ConstantSumPool::new(0, vec![accounts(1), accounts(2)], 5, 1000);
+        let num_shares =
+            pool.add_liquidity(&accounts(0), &mut vec![100 * one_token, 100 * one_token]);
+        // Large trade has no slippage, only the fee.
+        let amount_out = pool.swap(&accounts(1), 50 * one_token, &accounts(2), 1);
+        assert_eq!(amount_out, 50 * one_token * 9995 / 10000);
+        let amount_in =
+            pool.swap_for_exact(&accounts(2), 50 * one_token, &accounts(1), 10 * one_token);
+        assert_eq!(amount_in, 10 * one_token * 10000 / 9995 + 1);
+        let amounts = pool.remove_liquidity(&accounts(0), num_shares, vec![0, 0]);
+        // Liquidity provider earned fees.
+        assert!(amounts[0] + amounts[1] > 200 * one_token);
+        assert_eq!(pool.share_total_balance(), 0);
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_RESERVE_OUT_OF_BOUNDS")]
+    fn test_constant_sum_pool_reserve_bounds() {
+        let one_token = 10u128.pow(24);
+        let mut context = VMContextBuilder::new();
+        context.predecessor_account_id(accounts(0));
+        testing_env!(context.build());
+        let mut pool = ConstantSumPool::new(0, vec![accounts(1), accounts(2)], 5, 1000);
+        pool.add_liquidity(&accounts(0), &mut vec![100 * one_token, 100 * one_token]);
+        // Balance of token out can't drop below 10% of the total.
+        pool.swap(&accounts(1), 80 * one_token, &accounts(2), 1);
+        pool.swap(&accounts(1), 20 * one_token, &accounts(2), 1);
+    }
+
+    #[test]
+    fn test_constant_sum_pool_imbalanced_liquidity() {
+        let one_token = 10u128.pow(24);
+        let mut context = VMContextBuilder::new();
+        context.predecessor_account_id(accounts(0));
+        testing_env!(context.build());
+        let mut pool = ConstantSumPool::new(0, vec![accounts(1), accounts(2)], 5, 1000);
+        pool.add_liquidity(&accounts(0), &mut vec![100 * one_token, 100 * one_token]);
+        // Depositing one token and withdrawing the other costs about the same as swapping.
+        let shares = pool.add_liquidity(&accounts(1), &mut vec![10 * one_token, 0]);
+        let amount_out = pool.remove_liquidity_one_token(&accounts(1), shares, &accounts(2), 1);
+        assert!(amount_out > 10 * one_token * 9994 / 10000);
+        assert!(amount_out < 10 * one_token * 9996 / 10000);
+        let total_shares = pool.share_total_balance();
+        let total = total_amount(&pool.amounts);
+        let shares =
+            pool.remove_liquidity_by_tokens(&accounts(0), vec![one_token, one_token], u128::MAX);
+        // Burnt shares are worth at least the withdrawn amounts.
+        assert!(U256::from(shares) * total >= U256::from(2 * one_token) * U256::from(total_shares));
+    }
+}
@@ -0,0 +1,119 @@
+//! Dynamic fee, that grows when the pool's price moves a lot and decays back to the base fee once it calms down.
+
+use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
+use near_sdk::{env, Balance};
+
+use crate::errors::{require, PoolError};
+use crate::utils::U256;
+
+const FEE_DIVISOR: u32 = 10_000;
+/// Number of the most recent swaps tracked to estimate volatility.
+const MAX_OBSERVATIONS: usize = 8;
+
+/// Price movement caused by a single swap.
+#[derive(BorshSerialize, BorshDeserialize, Clone)]
+pub struct Observation {
+    /// Block timestamp of the swap in seconds.
+    pub timestamp: u64,
+    /// Relative price movement in basis points.
+    pub price_movement: u32,
+}
+
+/// Configuration and recent observations of the pool's dynamic fee.
+/// Volatility is the sum of price movements of recent swaps, each linearly decaying to zero over `decay_period`.
+/// Every basis point of volatility above `volatility_threshold` adds a basis point to the base fee, up to `max_fee`.
+#[derive(BorshSerialize, BorshDeserialize)]
+pub struct DynamicFee {
+    /// Maximum fee charged for swap (gets divided by FEE_DIVISOR).
+    pub max_fee: u32,
+    /// Volatility in basis points, above which fee starts to increase.
+    pub volatility_threshold: u32,
+    /// Number of seconds after which price movement stops affecting the fee.
+    pub decay_period: u64,
+    /// Price movements of the most recent swaps, oldest first.
+    pub observations: Vec<Observation>,
+}
+
+impl DynamicFee {
+    pub fn new(base_fee: u32, max_fee: u32, volatility_threshold: u32, decay_period: u64) -> Self {
+        require(
+            max_fee >= base_fee && max_fee < FEE_DIVISOR,
+            PoolError::WrongMaxFee,
+        );
+        require(decay_period > 0, PoolError::WrongDecayPeriod);
+        Self {
+            max_fee,
+            volatility_threshold,
+            decay_period,
+            observations: vec![],
+        }
+    }
+
+    /// Returns volatility at given timestamp in basis points.
+    pub fn volatility(&self, timestamp: u64) -> u64 {
+        self.observations
+            .iter()
+            .map(|observation| {
+                let age = timestamp.saturating_sub(observation.timestamp);
+                if age >= self.decay_period {
+                    0
+                } else {
+                    observation.price_movement as u64 * (self.decay_period - age)
+                        / self.decay_period
+                }
+            })
+            .sum()
+    }
+
+    /// Returns fee that should be charged at given timestamp for the pool with given base fee.
+    pub fn fee(&self, base_fee: u32, timestamp: u64) -> u32 {
+        let volatility = self.volatility(timestamp);
+        if volatility <= self.volatility_threshold as u64 {
+            base_fee
+        } else {
+            std::cmp::min(
+                self.max_fee as u64,
+                base_fee as u64 + volatility - self.volatility_threshold as u64,
+            ) as u32
+        }
+    }
+
+    /// Records price movement of the swap at given timestamp, dropping the oldest observation if buffer is full.
+    pub fn record(&mut self, timestamp: u64, price_movement: u32) {
+        if self.observations.len() == MAX_OBSERVATIONS {
+            self.observations.remove(0);
+        }
+        self.observations.push(Observation {
+            timestamp,
+            price_movement,
+        });
+    }
+}
+
+/// Returns current block timestamp in seconds.
+pub fn timestamp_sec() -> u64 {
+    env::block_timestamp() / 1_000_000_000
+}
+
+/// Returns relative movement in basis points of the price of token out in token in,
+/// when balances change from (`in_before`, `out_before`) to (`in_after`, `out_after`).
+pub fn price_movement(
+    in_before: Balance,
+    out_before: Balance,
+    in_after: Balance,
+    out_after: Balance,
+) -> u32 {
+    let before = U256::from(in_before) * U256::from(out_after);
+    let after = U256::from(in_after) * U256::from(out_before);
+    let difference = if after > before {
+        after - before
+    } else {
+        before - after
+    };
+    let movement = difference * U256::from(FEE_DIVISOR) / before;
+    if movement > U256::from(u32::MAX) {
+        u32::MAX
+    } else {
+        movement.as_u32()
+    }
+}
@@ -0,0 +1,508 @@
+//! Errors of the contract with their stable codes.
+//! Every failed call aborts with the code of one of these errors as the panic message.
+
+use std::fmt;
+
+use near_sdk::env;
+
+use crate::utils::U256;
+
+/// Reason why the call failed. Codes returned by `code` are stable and can be matched on by integrators.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PoolError {
+    /// Normalized token amount doesn't fit into `u128`.
+    AmountOverflow,
+    /// Deposit of one of the tokens is zero.
+    AmountZero,
+    /// Batch auction has maximum number of orders.
+    BatchAuctionFull,
+    /// Batch auction can only be settled after its block.
+    BatchAuctionNotEnded,
+    /// Base of the fixed point power is outside of (0, 2).
+    BpowBase,
+    /// Contract is already initialized.
+    ContractIsInitialized,
+    /// Contract has no state to migrate.
+    ContractNotInitialized,
+    /// Exchange is paused.
+    ContractPaused,
+    /// Token has more decimals than the pool normalizes to.
+    DecimalsTooLarge,
+    /// Fixed point division by zero.
+    DivZero,
+    /// Stable swap invariant didn't converge.
+    DNotConverged,
+    /// Protocol fees of the pool are compounded into its vault.
+    FeesCompounded,
+    /// Pool of the same kind with the same fee and parameters already exists for these tokens.
+    FeeTierExists,
+    /// Fee is not less than 100%.
+    FeeTooLarge,
+    /// First action of the batch doesn't specify amount in.
+    FirstSwapMissingAmount,
+    /// Pool already has a flash loan out.
+    FlashLoanInProgress,
+    /// Bonding curve pool has not reached its graduation reserve yet.
+    GraduationNotReached,
+    /// Pools don't have the same tokens.
+    IncompatiblePools,
+    /// Account's storage balance doesn't cover its storage.
+    InsufficientStorage,
+    /// Swap is invalid: same token, zero amount or empty pool.
+    Invalid,
+    /// Account id is not valid.
+    InvalidAccountId,
+    /// Recipient is not a hex encoded EVM address.
+    InvalidEvmAddress,
+    /// Pool invariant doesn't hold after the state change.
+    InvariantViolated,
+    /// Liquidity doesn't fit into signed liquidity delta.
+    LiquidityOverflow,
+    /// Unlock time is in the past or before the unlock time of the current lock.
+    LockTooShort,
+    /// Token wrapping shares of the pool is already deployed.
+    LpTokenExists,
+    /// Swap requires more than maximum amount in.
+    MaxAmountIn,
+    /// Withdrawal requires burning more than maximum shares.
+    MaxBurnShares,
+    /// Amount in is too large relative to the pool balance.
+    MaxInRatio,
+    /// Amount out is too large relative to the pool balance.
+    MaxOutRatio,
+    /// Amount out is less than minimum amount out.
+    MinAmount,
+    /// Deposit mints less than minimum shares.
+    MinShares,
+    /// Token is not in the pool or in the account deposits.
+    MissingToken,
+    /// Unsupported `ft_on_transfer` message.
+    MsgIncorrect,
+    /// Result of `mul_div` doesn't fit into `U256`.
+    MulDivOverflow,
+    /// Base pool of the metapool is itself a metapool.
+    NestedMetapool,
+    /// Account can't be unregistered without `force` while it has deposits.
+    NonZeroDeposits,
+    /// Token can't be unregistered while the account has a balance of it.
+    NonZeroTokenBalance,
+    /// Pool is not a bonding curve pool.
+    NotBondingCurvePool,
+    /// Pool is not a concentrated liquidity pool.
+    NotConcentratedPool,
+    /// Only the DAO can call admin methods once it is set.
+    NotDao,
+    /// Withdrawal is larger than deposited amount.
+    NotEnough,
+    /// Spender is approved for less shares than it transfers.
+    NotEnoughAllowance,
+    /// Account deposit of token in is less than amount in.
+    NotEnoughDeposit,
+    /// Rescued amount is more than the contract holds above pools and deposits.
+    NotEnoughExcess,
+    /// Pool doesn't have enough liquidity for the operation.
+    NotEnoughLiquidity,
+    /// Account doesn't have enough shares.
+    NotEnoughShares,
+    /// Account deposit of the token is not enough for the operation.
+    NotEnoughToken,
+    /// Pool is created with less than two tokens.
+    NotEnoughTokens,
+    /// Only owner and fee setters can change fees.
+    NotFeeSetter,
+    /// Receiver of the flash loan is not approved by owner.
+    NotFlashLoanReceiver,
+    /// Only owner and guardians can pause.
+    NotGuardian,
+    /// Pool is not a metapool.
+    NotMetapool,
+    /// Caller doesn't own the position token.
+    NotNftOwner,
+    /// Caller is not the owner of the contract, of the position, of the bonding curve pool or of the limit order.
+    NotOwner,
+    /// Caller is not the proposed owner.
+    NotPendingOwner,
+    /// Caller is not allowed to create pools.
+    NotPoolCreator,
+    /// Only owner and pool managers can configure pools.
+    NotPoolManager,
+    /// Account is not registered.
+    NotRegistered,
+    /// Pool is not a stable swap pool.
+    NotStableSwapPool,
+    /// Swap of attached NEAR doesn't start from wrapped NEAR.
+    NotWrappedNear,
+    /// Admin action does not exist.
+    NoAdminAction,
+    /// Aurora engine is not set.
+    NoAurora,
+    /// Pool is not in batch auction mode.
+    NoBatchAuction,
+    /// Account has no deposits.
+    NoDeposit,
+    /// Farm doesn't exist.
+    NoFarm,
+    /// Limit order with given id doesn't exist.
+    NoLimitOrder,
+    /// Bonding curve pool sells the supplied token and doesn't take liquidity.
+    NoLiquidityShares,
+    /// Pool doesn't have a token wrapping its shares.
+    NoLpToken,
+    /// Code of the token wrapping pool shares is not set.
+    NoLpTokenCode,
+    /// Position token doesn't exist.
+    NoNft,
+    /// Pool with given id doesn't exist.
+    NoPool,
+    /// Position with given id doesn't exist.
+    NoPosition,
+    /// Token doesn't have a rate provider.
+    NoRateProvider,
+    /// Account has no shares in the pool.
+    NoShares,
+    /// Account has no lock of shares in the pool.
+    NoShareLock,
+    /// No code is staged.
+    NoStagedCode,
+    /// Stop order doesn't exist.
+    NoStopOrder,
+    /// Tick is not initialized.
+    NoTick,
+    /// Account has no deposit of the token.
+    NoToken,
+    /// Treasury is not set.
+    NoTreasury,
+    /// TWAP oracle of the pool is not enabled.
+    NoTwapOracle,
+    /// Pool doesn't have a vault.
+    NoVault,
+    /// Wrapped NEAR contract is not set.
+    NoWrappedNear,
+    /// Account can't be unregistered while it has open orders, farm stakes or vault shares.
+    OpenPositions,
+    /// Migrating state of the first release, which has no owner, requires owner id.
+    OwnerIdRequired,
+    /// Owner role is changed with propose_owner and set_dao, not granted.
+    OwnerRoleNotGranted,
+    /// Bonding curve pool stopped trading after reaching its graduation reserve.
+    PoolGraduated,
+    /// Pool only takes swaps through its batch auction.
+    PoolInBatchAuction,
+    /// Pool still has tokens or liquidity.
+    PoolNotEmpty,
+    /// Pool is paused.
+    PoolPaused,
+    /// Pool is retired and can no longer be used.
+    PoolRetired,
+    /// Spot price of the pool deviates from its TWAP more than allowed.
+    PriceDeviatesFromTwap,
+    /// Swap moves the price of the pool within the block more than its circuit breaker allows.
+    PriceMoveTooLarge,
+    /// Swap or withdrawal takes a reserve of the pool below its floor.
+    ReserveBelowFloor,
+    /// Reserve floor is above `MAX_RESERVE_FLOOR` part of the reserve.
+    ReserveFloorTooHigh,
+    /// Swap or withdrawal would leave a token below the minimum share of the pool's total balance.
+    ReserveOutOfBounds,
+    /// Token in and token out are the same.
+    SameToken,
+    /// First deposit into the pool mints no more shares than the locked minimum liquidity.
+    SharesBelowMinLiquidity,
+    /// Shares are locked until the unlock time of the lock.
+    SharesLocked,
+    /// Withdrawal would burn all shares of the pool.
+    SharesTooLarge,
+    /// Rate of a token with rate provider was never fetched or is older than `MAX_RATE_AGE`.
+    StaleRate,
+    /// Price of the pool is above the trigger price of the stop order.
+    StopPriceNotReached,
+    /// Attached deposit doesn't cover storage of the new pool.
+    StorageDeposit,
+    /// Tick is outside of the supported price range.
+    TickOutOfBounds,
+    /// Change must be proposed as an admin action while the timelock delay is set.
+    TimelockActive,
+    /// Delay of the admin action has not passed yet.
+    TimelockNotEnded,
+    /// Same token is given more than once.
+    TokenDuplicates,
+    /// Pool is created with this contract as one of its tokens.
+    TokenIsContract,
+    /// Token is not registered in the account deposits.
+    TokenNotRegistered,
+    /// Token is not in the whitelist of the contract.
+    TokenNotWhitelisted,
+    /// Farm already has maximum number of reward tokens.
+    TooManyFarmRewards,
+    /// Number of hops of a route is zero or above the limit.
+    TooManyHops,
+    /// Pool is created with too many tokens.
+    TooManyTokens,
+    /// Account has a transfer out in flight, whose failure returns the tokens to its deposits.
+    TransferInFlight,
+    /// TWAP oracle of the pool is already enabled.
+    TwapOracleExists,
+    /// TWAP oracle has no observation old enough for the requested period.
+    TwapPeriodTooLong,
+    /// Value doesn't fit into `u128`.
+    U128Overflow,
+    /// Feature is not supported by this kind of pool.
+    UnsupportedPool,
+    /// Concentrated liquidity pool is managed through positions, not shares.
+    UsePositions,
+    /// Pool already has a vault.
+    VaultExists,
+    /// Token weight is below the minimum share of the total weight.
+    WeightTooSmall,
+    /// Amplification factor is out of bounds.
+    WrongAmp,
+    /// Decay period of dynamic fee is zero.
+    WrongDecayPeriod,
+    /// Number of decimals doesn't match number of tokens.
+    WrongDecimalsCount,
+    /// Farm period must not start in the past and must end after it starts.
+    WrongFarmPeriod,
+    /// Lock of a stake must last 1 to 12 months.
+    WrongLockDuration,
+    /// Maximum dynamic fee is less than base fee or not less than 100%.
+    WrongMaxFee,
+    /// Maximum number of tokens in a pool is out of bounds.
+    WrongMaxNumTokens,
+    /// Maximum price movement of a circuit breaker must be positive.
+    WrongMaxPriceMove,
+    /// Minimum reserve share is too large for the number of tokens.
+    WrongMinReserve,
+    /// Position ticks are out of order, out of bounds or not multiples of tick spacing.
+    WrongRange,
+    /// Number of rate providers doesn't match number of tokens.
+    WrongRateProvidersCount,
+    /// Ratios of a split swap must match its pools and not all be zero.
+    WrongRatios,
+    /// Tick spacing is zero or too large.
+    WrongTickSpacing,
+    /// Number of amounts doesn't match number of tokens.
+    WrongTokenCount,
+    /// Multi-token id is not of the form `:<pool_id>`.
+    WrongTokenId,
+    /// Action without amount in doesn't swap from token out of the previous action.
+    WrongTokenIn,
+    /// Number of weights doesn't match number of tokens.
+    WrongWeightsCount,
+    /// Weight schedule ends before it starts.
+    WrongWeightSchedule,
+    /// Stable swap balance computation didn't converge.
+    YNotConverged,
+    /// Position liquidity is zero.
+    ZeroLiquidity,
+    /// Initial price of bonding curve is zero.
+    ZeroPrice,
+    /// Rate provider returned zero rate.
+    ZeroRate,
+    /// Operation mints zero shares.
+    ZeroShares,
+}
+
+impl PoolError {
+    /// Returns stable code of the error.
+    pub fn code(&self) -> &'static str {
+        match self {
+            PoolError::AmountOverflow => "ERR_AMOUNT_OVERFLOW",
+            PoolError::AmountZero => "ERR_AMOUNT_ZERO",
+            PoolError::BatchAuctionFull => "ERR_BATCH_AUCTION_FULL",
+            PoolError::BatchAuctionNotEnded => "ERR_BATCH_AUCTION_NOT_ENDED",
+            PoolError::BpowBase => "ERR_BPOW_BASE",
+            PoolError::ContractIsInitialized => "ERR_CONTRACT_IS_INITIALIZED",
+            PoolError::ContractNotInitialized => "ERR_CONTRACT_NOT_INITIALIZED",
+            PoolError::ContractPaused => "ERR_CONTRACT_PAUSED",
+            PoolError::DecimalsTooLarge => "ERR_DECIMALS_TOO_LARGE",
+            PoolError::DivZero => "ERR_DIV_ZERO",
+            PoolError::DNotConverged => "ERR_D_NOT_CONVERGED",
+            PoolError::FeesCompounded => "ERR_FEES_COMPOUNDED",
+            PoolError::FeeTierExists => "ERR_FEE_TIER_EXISTS",
+            PoolError::FeeTooLarge => "ERR_FEE_TOO_LARGE",
+            PoolError::FirstSwapMissingAmount => "ERR_FIRST_SWAP_MISSING_AMOUNT",
+            PoolError::FlashLoanInProgress => "ERR_FLASH_LOAN_IN_PROGRESS",
+            PoolError::GraduationNotReached => "ERR_GRADUATION_NOT_REACHED",
+            PoolError::IncompatiblePools => "ERR_INCOMPATIBLE_POOLS",
+            PoolError::InsufficientStorage => "ERR_INSUFFICIENT_STORAGE",
+            PoolError::Invalid => "ERR_INVALID",
+            PoolError::InvalidAccountId => "ERR_INVALID_ACCOUNT_ID",
+            PoolError::InvalidEvmAddress => "ERR_INVALID_EVM_ADDRESS",
+            PoolError::InvariantViolated => "ERR_INVARIANT_VIOLATED",
+            PoolError::LiquidityOverflow => "ERR_LIQUIDITY_OVERFLOW",
+            PoolError::LockTooShort => "ERR_LOCK_TOO_SHORT",
+            PoolError::LpTokenExists => "ERR_LP_TOKEN_EXISTS",
+            PoolError::MaxAmountIn => "ERR_MAX_AMOUNT_IN",
+            PoolError::MaxBurnShares => "ERR_MAX_BURN_SHARES",
+            PoolError::MaxInRatio => "ERR_MAX_IN_RATIO",
+            PoolError::MaxOutRatio => "ERR_MAX_OUT_RATIO",
+            PoolError::MinAmount => "ERR_MIN_AMOUNT",
+            PoolError::MinShares => "ERR_MIN_SHARES",
+            PoolError::MissingToken => "ERR_MISSING_TOKEN",
+            PoolError::MsgIncorrect => "ERR_MSG_INCORRECT",
+            PoolError::MulDivOverflow => "ERR_MUL_DIV_OVERFLOW",
+            PoolError::NestedMetapool => "ERR_NESTED_METAPOOL",
+            PoolError::NonZeroDeposits => "ERR_NON_ZERO_DEPOSITS",
+            PoolError::NonZeroTokenBalance => "ERR_NON_ZERO_TOKEN_BALANCE",
+            PoolError::NotBondingCurvePool => "ERR_NOT_BONDING_CURVE_POOL",
+            PoolError::NotConcentratedPool => "ERR_NOT_CONCENTRATED_POOL",
+            PoolError::NotDao => "ERR_NOT_DAO",
+            PoolError::NotEnough => "ERR_NOT_ENOUGH",
+            PoolError::NotEnoughAllowance => "ERR_NOT_ENOUGH_ALLOWANCE",
+            PoolError::NotEnoughDeposit => "ERR_NOT_ENOUGH_DEPOSIT",
+            PoolError::NotEnoughExcess => "ERR_NOT_ENOUGH_EXCESS",
+            PoolError::NotEnoughLiquidity => "ERR_NOT_ENOUGH_LIQUIDITY",
+            PoolError::NotEnoughShares => "ERR_NOT_ENOUGH_SHARES",
+            PoolError::NotEnoughToken => "ERR_NOT_ENOUGH_TOKEN",
+            PoolError::NotEnoughTokens => "ERR_NOT_ENOUGH_TOKENS",
+            PoolError::NotFeeSetter => "ERR_NOT_FEE_SETTER",
+            PoolError::NotFlashLoanReceiver => "ERR_NOT_FLASH_LOAN_RECEIVER",
+            PoolError::NotGuardian => "ERR_NOT_GUARDIAN",
+            PoolError::NotMetapool => "ERR_NOT_METAPOOL",
+            PoolError::NotNftOwner => "ERR_NOT_NFT_OWNER",
+            PoolError::NotOwner => "ERR_NOT_OWNER",
+            PoolError::NotPendingOwner => "ERR_NOT_PENDING_OWNER",
+            PoolError::NotPoolCreator => "ERR_NOT_POOL_CREATOR",
+            PoolError::NotPoolManager => "ERR_NOT_POOL_MANAGER",
+            PoolError::NotRegistered => "ERR_NOT_REGISTERED",
+            PoolError::NotStableSwapPool => "ERR_NOT_STABLE_SWAP_POOL",
+            PoolError::NotWrappedNear => "ERR_NOT_WNEAR",
+            PoolError::NoAdminAction => "ERR_NO_ADMIN_ACTION",
+            PoolError::NoAurora => "ERR_NO_AURORA",
+            PoolError::NoBatchAuction => "ERR_NO_BATCH_AUCTION",
+            PoolError::NoDeposit => "ERR_NO_DEPOSIT",
+            PoolError::NoFarm => "ERR_NO_FARM",
+            PoolError::NoLimitOrder => "ERR_NO_LIMIT_ORDER",
+            PoolError::NoLiquidityShares => "ERR_NO_LIQUIDITY_SHARES",
+            PoolError::NoLpToken => "ERR_NO_LP_TOKEN",
+            PoolError::NoLpTokenCode => "ERR_NO_LP_TOKEN_CODE",
+            PoolError::NoNft => "ERR_NO_NFT",
+            PoolError::NoPool => "ERR_NO_POOL",
+            PoolError::NoPosition => "ERR_NO_POSITION",
+            PoolError::NoRateProvider => "ERR_NO_RATE_PROVIDER",
+            PoolError::NoShares => "ERR_NO_SHARES",
+            PoolError::NoShareLock => "ERR_NO_SHARE_LOCK",
+            PoolError::NoStagedCode => "ERR_NO_STAGED_CODE",
+            PoolError::NoStopOrder => "ERR_NO_STOP_ORDER",
+            PoolError::NoTick => "ERR_NO_TICK",
+            PoolError::NoToken => "ERR_NO_TOKEN",
+            PoolError::NoTreasury => "ERR_NO_TREASURY",
+            PoolError::NoTwapOracle => "ERR_NO_TWAP_ORACLE",
+            PoolError::NoVault => "ERR_NO_VAULT",
+            PoolError::NoWrappedNear => "ERR_NO_WNEAR",
+            PoolError::OpenPositions => "ERR_OPEN_POSITIONS",
+            PoolError::OwnerIdRequired => "ERR_OWNER_ID_REQUIRED",
+            PoolError::OwnerRoleNotGranted => "ERR_OWNER_ROLE_NOT_GRANTED",
+            PoolError::PoolGraduated => "ERR_POOL_GRADUATED",
+            PoolError::PoolInBatchAuction => "ERR_POOL_IN_BATCH_AUCTION",
+            PoolError::PoolNotEmpty => "ERR_POOL_NOT_EMPTY",
+            PoolError::PoolPaused => "ERR_POOL_PAUSED",
+            PoolError::PoolRetired => "ERR_POOL_RETIRED",
+            PoolError::PriceDeviatesFromTwap => "ERR_PRICE_DEVIATES_FROM_TWAP",
+            PoolError::PriceMoveTooLarge => "ERR_PRICE_MOVE_TOO_LARGE",
+            PoolError::ReserveBelowFloor => "ERR_RESERVE_BELOW_FLOOR",
+            PoolError::ReserveFloorTooHigh => "ERR_RESERVE_FLOOR_TOO_HIGH",
+            PoolError::ReserveOutOfBounds => "ERR_RESERVE_OUT_OF_BOUNDS",
+            PoolError::SameToken => "ERR_SAME_TOKEN",
+            PoolError::SharesBelowMinLiquidity => "ERR_SHARES_BELOW_MIN_LIQUIDITY",
+            PoolError::SharesLocked => "ERR_SHARES_LOCKED",
+            PoolError::SharesTooLarge => "ERR_SHARES_TOO_LARGE",
+            PoolError::StaleRate => "ERR_STALE_RATE",
+            PoolError::StopPriceNotReached => "ERR_STOP_PRICE_NOT_REACHED",
+            PoolError::StorageDeposit => "ERR_STORAGE_DEPOSIT",
+            PoolError::TickOutOfBounds => "ERR_TICK_OUT_OF_BOUNDS",
+            PoolError::TimelockActive => "ERR_TIMELOCK_ACTIVE",
+            PoolError::TimelockNotEnded => "ERR_TIMELOCK_NOT_ENDED",
+            PoolError::TokenDuplicates => "ERR_TOKEN_DUPLICATES",
+            PoolError::TokenIsContract => "ERR_TOKEN_IS_CONTRACT",
+            PoolError::TokenNotRegistered => "ERR_TOKEN_NOT_REGISTERED",
+            PoolError::TokenNotWhitelisted => "ERR_TOKEN_NOT_WHITELISTED",
+            PoolError::TooManyFarmRewards => "ERR_TOO_MANY_FARM_REWARDS",
+            PoolError::TooManyHops => "ERR_TOO_MANY_HOPS",
+            PoolError::TooManyTokens => "ERR_TOO_MANY_TOKENS",
+            PoolError::TransferInFlight => "ERR_TRANSFER_IN_FLIGHT",
+            PoolError::TwapOracleExists => "ERR_TWAP_ORACLE_EXISTS",
+            PoolError::TwapPeriodTooLong => "ERR_TWAP_PERIOD_TOO_LONG",
+            PoolError::U128Overflow => "ERR_U128_OVERFLOW",
+            PoolError::UnsupportedPool => "ERR_UNSUPPORTED_POOL",
+            PoolError::UsePositions => "ERR_USE_POSITIONS",
+            PoolError::VaultExists => "ERR_VAULT_EXISTS",
+            PoolError::WeightTooSmall => "ERR_WEIGHT_TOO_SMALL",
+            PoolError::WrongAmp => "ERR_WRONG_AMP",
+            PoolError::WrongDecayPeriod => "ERR_WRONG_DECAY_PERIOD",
+            PoolError::WrongDecimalsCount => "ERR_WRONG_DECIMALS_COUNT",
+            PoolError::WrongFarmPeriod => "ERR_WRONG_FARM_PERIOD",
+            PoolError::WrongLockDuration => "ERR_WRONG_LOCK_DURATION",
+            PoolError::WrongMaxFee => "ERR_WRONG_MAX_FEE",
+            PoolError::WrongMaxNumTokens => "ERR_WRONG_MAX_NUM_TOKENS",
+            PoolError::WrongMaxPriceMove => "ERR_WRONG_MAX_PRICE_MOVE",
+            PoolError::WrongMinReserve => "ERR_WRONG_MIN_RESERVE",
+            PoolError::WrongRange => "ERR_WRONG_RANGE",
+            PoolError::WrongRateProvidersCount => "ERR_WRONG_RATE_PROVIDERS_COUNT",
+            PoolError::WrongRatios => "ERR_WRONG_RATIOS",
+            PoolError::WrongTickSpacing => "ERR_WRONG_TICK_SPACING",
+            PoolError::WrongTokenCount => "ERR_WRONG_TOKEN_COUNT",
+            PoolError::WrongTokenId => "ERR_WRONG_TOKEN_ID",
+            PoolError::WrongTokenIn => "ERR_WRONG_TOKEN_IN",
+            PoolError::WrongWeightsCount => "ERR_WRONG_WEIGHTS_COUNT",
+            PoolError::WrongWeightSchedule => "ERR_WRONG_WEIGHT_SCHEDULE",
+            PoolError::YNotConverged => "ERR_Y_NOT_CONVERGED",
+            PoolError::ZeroLiquidity => "ERR_ZERO_LIQUIDITY",
+            PoolError::ZeroPrice => "ERR_ZERO_PRICE",
+            PoolError::ZeroRate => "ERR_ZERO_RATE",
+            PoolError::ZeroShares => "ERR_ZERO_SHARES",
+        }
+    }
+
+    /// Aborts execution with the code of the error. Like `require!` of near-sdk, debug builds panic instead,
+    /// since unit tests can't catch a panic raised through the mocked host functions.
+    pub fn panic(self) -> ! {
+        if cfg!(debug_assertions) {
+            panic!("{}", self.code())
+        } else {
+            env::panic_str(self.code())
+        }
+    }
+}
+
+impl fmt::Display for PoolError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.code())
+    }
+}
+
+/// Aborts execution with given error if condition doesn't hold.
+pub(crate) fn require(condition: bool, error: PoolError) {
+    if !condition {
+        error.panic();
+    }
+}
+
+/// Unwraps the value or aborts execution with given error.
+pub(crate) trait UnwrapOrPanic<T> {
+    fn unwrap_or_panic(self, error: PoolError) -> T;
+}
+
+impl<T> UnwrapOrPanic<T> for Option<T> {
+    fn unwrap_or_panic(self, error: PoolError) -> T {
+        self.unwrap_or_else(|| error.panic())
+    }
+}
+
+impl<T, E> UnwrapOrPanic<T> for Result<T, E> {
+    fn unwrap_or_panic(self, error: PoolError) -> T {
+        self.unwrap_or_else(|_| error.panic())
+    }
+}
+
+/// Conversion of `U256` to `u128` that fails with `PoolError::U128Overflow` instead of truncating.
+pub(crate) trait CheckedAsU128 {
+    fn checked_as_u128(self) -> u128;
+}
+
+impl CheckedAsU128 for U256 {
+    fn checked_as_u128(self) -> u128 {
+        require(self <= U256::from(u128::MAX), PoolError::U128Overflow);
+        self.low_u128()
+    }
+}
@@ -0,0 +1,149 @@
+//! Events in the NEP-297 format: `EVENT_JSON:` logs with standard, version, event name and data, so indexers
+//! and explorers can track swaps, liquidity, deposits and withdrawals of the exchange without decoding receipts.
+
+use near_sdk::serde_json;
+
+use crate::*;
+
+/// Name of the standard of the events.
+const EVENT_STANDARD: &str = "multiswap";
+
+/// Version of the standard of the events.
+const EVENT_STANDARD_VERSION: &str = "1.0.0";
+
+/// Event of the exchange, serialized as event name and its data.
+#[derive(Serialize, Debug)]
+#[serde(crate = "near_sdk::serde")]
+#[serde(tag = "event", content = "data", rename_all = "snake_case")]
+pub enum Event<'a> {
+    Swap(Vec<SwapEvent<'a>>),
+    AddLiquidity(Vec<LiquidityEvent<'a>>),
+    RemoveLiquidity(Vec<LiquidityEvent<'a>>),
+    Deposit(Vec<TokenEvent<'a>>),
+    Withdraw(Vec<TokenEvent<'a>>),
+}
+
+/// Swap of an account through a pool.
+#[derive(Serialize, Debug)]
+#[serde(crate = "near_sdk::serde")]
+pub struct SwapEvent<'a> {
+    pub account_id: &'a AccountId,
+    pub pool_id: u64,
+    pub token_in: &'a AccountId,
+    pub amount_in: U128,
+    pub token_out: &'a AccountId,
+    pub amount_out: U128,
+}
+
+/// Liquidity added to or removed from a pool by an account, with minted or burnt shares.
+#[derive(Serialize, Debug)]
+#[serde(crate = "near_sdk::serde")]
+pub struct LiquidityEvent<'a> {
+    pub account_id: &'a AccountId,
+    pub pool_id: u64,
+    pub tokens: &'a [AccountId],
+    pub amounts: Vec<U128>,
+    pub shares: U128,
+}
+
+/// Token deposited into or withdrawn from the deposits of an account.
+#[derive(Serialize, Debug)]
+#[serde(crate = "near_sdk::serde")]
+pub struct TokenEvent<'a> {
+    pub account_id: &'a AccountId,
+    pub token_id: &'a AccountId,
+    pub amount: U128,
+}
+
+/// Event with its standard, as logged.
+#[derive(Serialize)]
+#[serde(crate = "near_sdk::serde")]
+struct EventLog<'a> {
+    standard: &'static str,
+    version: &'static str,
+    #[serde(flatten)]
+    event: &'a Event<'a>,
+}
+
+impl Event<'_> {
+    /// Logs the event with `EVENT_JSON:` prefix.
+    pub fn emit(&self) {
+        let event = EventLog {
+            standard: EVENT_STANDARD,
+            version: EVENT_STANDARD_VERSION,
+            event: self,
+        };
+        log!("EVENT_JSON:{}", serde_json::to_string(&event).unwrap());
+    }
+}
+
+pub(crate) fn emit_swap(
+    account_id: &AccountId,
+    pool_id: u64,
+    token_in: &AccountId,
+    amount_in: Balance,
+    token_out: &AccountId,
+    amount_out: Balance,
+) {
+    Event::Swap(vec![SwapEvent {
+        account_id,
+        pool_id,
+        token_in,
+        amount_in: amount_in.into(),
+        token_out,
+        amount_out: amount_out.into(),
+    }])
+    .emit();
+}
+
+pub(crate) fn emit_add_liquidity(
+    account_id: &AccountId,
+    pool_id: u64,
+    tokens: &[AccountId],
+    amounts: &[Balance],
+    shares: Balance,
+) {
+    Event::AddLiquidity(vec![LiquidityEvent {
+        account_id,
+        pool_id,
+        tokens,
+        amounts: amounts.iter().map(|amount| U128(*amount)).collect(),
+        shares: shares.into(),
+    }])
+    .emit();
+}
+
+pub(crate) fn emit_remove_liquidity(
+    account_id: &AccountId,
+    pool_id: u64,
+    tokens: &[AccountId],
+    amounts: &[Balance],
+    shares: Balance,
+) {
+    Event::RemoveLiquidity(vec![LiquidityEvent {
+        account_id,
+        pool_id,
+        tokens,
+        amounts: amounts.iter().map(|amount| U128(*amount)).collect(),
+        shares: shares.into(),
+    }])
+    .emit();
+}
+
+pub(crate) fn emit_deposit(account_id: &AccountId, token_id: &AccountId, amount: Balance) {
+    Event::Deposit(vec![TokenEvent {
+        account_id,
+        token_id,
+        amount: amount.into(),
+    }])
+    .emit();
+}
+
+pub(crate) fn emit_withdraw(account_id: &AccountId, token_id: &AccountId, amount: Balance) {
+    Event::Withdraw(vec![TokenEvent {
+        account_id,
+        token_id,
+        amount: amount.into(),
+    }])
+    .emit();
+}
@@ -0,0 +1,646 @@
+//! Farms. Anyone can create a farm of a pool, which emits given amount of a reward token linearly
+//! between its start and end time to liquidity providers staking their shares of the pool in it.
+//! Anyone can add more reward tokens to a farm, each emitted over its own period, so e.g. a project and its partner
+//! can incentivize the same pool. Each reward accumulates its emission per staked share, and each stake keeps
+//! a checkpoint of that, settled whenever the farm or the stake changes. Rewards emitted while nothing is staked
+//! go to the first stake after.
+//! Stakers can lock their stake for 1 to 12 months, which boosts its weight in the emissions linearly with
+//! the lock duration, up to 2.5x. Unstaking before the lock ends costs a part of the shares, decreasing linearly
+//! with the time left, which goes to the other locked stakes of the farm by weight. A boost stays until the stake
+//! is touched after its lock ended, anyone can end it with `refresh_stake`.
+//! Rewards of a period that ended while nothing was staked would wait for a stake forever, so the creator of
+//! the farm (or owner) can reclaim them with `reclaim_farm_rewards`.
+
+use crate::dynamic_fee::timestamp_sec;
+use crate::errors::{require, PoolError, UnwrapOrPanic};
+use crate::utils::U256;
+use crate::*;
+
+/// Precision of the rewards per share.
+const REWARD_PER_SHARE_PRECISION: u128 = 1_000_000_000_000_000_000_000_000;
+
+/// Maximum number of reward tokens of a farm.
+const MAX_FARM_REWARDS: usize = 5;
+
+/// Length of a month of a lock in seconds.
+const MONTH: u64 = 30 * 24 * 60 * 60;
+
+/// Maximum duration of a lock in months.
+const MAX_LOCK_MONTHS: u32 = 12;
+
+/// Divisor of the boost of a stake, which is 1x without a lock.
+const BOOST_DIVISOR: u32 = 10_000;
+
+/// Boost added per month of a lock (gets divided by BOOST_DIVISOR).
+const BOOST_PER_MONTH: u32 = 1_250;
+
+/// Part of unstaked shares taken when a whole year of the lock is left (gets divided by FEE_DIVISOR).
+const MAX_EARLY_EXIT_PENALTY: u32 = 5_000;
+
+/// Reward token emitted by a farm.
+#[derive(BorshSerialize, BorshDeserialize)]
+pub struct FarmReward {
+    /// Token given as rewards.
+    pub reward_token: AccountId,
+    /// Amount of reward token emitted over the whole period.
+    pub total_reward: Balance,
+    /// Timestamp in seconds when emission starts.
+    pub start_at: u64,
+    /// Timestamp in seconds when emission ends.
+    pub end_at: u64,
+    /// Rewards per staked share emitted so far, times `REWARD_PER_SHARE_PRECISION`. Wraps around.
+    pub reward_per_share: u128,
+    /// Timestamp in seconds until which rewards were added to `reward_per_share`.
+    pub emitted_at: u64,
+}
+
+/// Farm emitting reward tokens to stakers of shares of a pool.
+#[derive(BorshSerialize, BorshDeserialize)]
+pub struct Farm {
+    /// Pool which shares are staked.
+    pub pool_id: u64,
+    /// Account that created the farm.
+    pub owner_id: AccountId,
+    /// Shares staked in the farm.
+    pub total_staked: Balance,
+    /// Sum of weights of the stakes, which is what rewards are emitted to.
+    pub total_weight: Balance,
+    /// Sum of weights of the locked stakes, which is what early exit penalties go to.
+    pub locked_weight: Balance,
+    /// Penalty shares per locked weight charged so far, times `REWARD_PER_SHARE_PRECISION`. Wraps around.
+    pub penalty_per_weight: u128,
+    /// Reward tokens emitted by the farm, each with its own amount and period.
+    pub rewards: Vec<FarmReward>,
+}
+
+/// Shares of an account staked in a farm.
+#[derive(BorshSerialize, BorshDeserialize)]
+pub struct Stake {
+    /// Staked shares.
+    pub shares: Balance,
+    /// Staked shares times the boost.
+    pub weight: Balance,
+    /// Boost of the lock (gets divided by BOOST_DIVISOR).
+    pub boost: u32,
+    /// Timestamp in seconds when the lock ends, or 0 if the stake is not locked.
+    pub locked_until: u64,
+    /// Penalty shares per locked weight of the farm at the last settlement.
+    pub penalty_per_weight: u128,
+    /// Shares of early exit penalties earned and not claimed yet.
+    pub penalty_shares: Balance,
+    /// Rewards per share of the farm at the last settlement, in the order of its rewards.
+    /// Rewards added to the farm later are missing until then.
+    pub reward_per_share: Vec<u128>,
+    /// Rewards earned and not claimed yet, in the order of the farm's rewards.
+    pub unclaimed: Vec<Balance>,
+}
+
+/// Reward token of a farm as returned by views.
+#[derive(Debug, Serialize, Deserialize, PartialEq)]
+#[serde(crate = "near_sdk::serde")]
+pub struct FarmRewardInfo {
+    /// Token given as rewards.
+    pub reward_token: AccountId,
+    /// Amount of reward token emitted over the whole period.
+    pub total_reward: U128,
+    /// Timestamp in seconds when emission starts.
+    pub start_at: U64,
+    /// Timestamp in seconds when emission ends.
+    pub end_at: U64,
+}
+
+/// Farm as returned by views.
+#[derive(Debug, Serialize, Deserialize, PartialEq)]
+#[serde(crate = "near_sdk::serde")]
+pub struct FarmInfo {
+    /// Id of the farm.
+    pub farm_id: U64,
+    /// Pool which shares are staked.
+    pub pool_id: u64,
+    /// Account that created the farm.
+    pub owner_id: AccountId,
+    /// Shares staked in the farm.
+    pub total_staked: U128,
+    /// Sum of weights of the stakes, which is what rewards are emitted to.
+    pub total_weight: U128,
+    /// Reward tokens emitted by the farm.
+    pub rewards: Vec<FarmRewardInfo>,
+}
+
+/// Stake of an account in a farm as returned by views.
+#[derive(Debug, Serialize, Deserialize, PartialEq)]
+#[serde(crate = "near_sdk::serde")]
+pub struct StakeInfo {
+    /// Staked shares.
+    pub shares: U128,
+    /// Staked shares times the boost of the lock.
+    pub weight: U128,
+    /// Timestamp in seconds when the lock ends, or 0 if the stake is not locked.
+    pub locked_until: U64,
+    /// Shares of early exit penalties earned and not claimed yet.
+    pub penalty_shares: U128,
+}
+
+#[near_bindgen]
+impl Contract {
+    /// Creates farm of the pool, emitting `total_reward` of reward token from the deposits of the caller linearly
+    /// between `start_at` and `end_at` (timestamps in seconds, not in the past). The caller pays storage of the farm.
+    /// Returns id of the farm.
+    pub fn create_farm(
+        &mut self,
+        pool_id: u64,
+        reward_token: AccountId,
+        total_reward: U128,
+        start_at: U64,
+        end_at: U64,
+    ) -> U64 {
+        self.assert_running();
+        require(
+            matches!(
+                self.internal_get_pool(pool_id),
+                Pool::SimplePool(_)
+                    | Pool::StableSwapPool(_)
+                    | Pool::WeightedPool(_)
+                    | Pool::ConstantSumPool(_)
+            ),
+            PoolError::NoLiquidityShares,
+        );
+        let sender_id = env::predecessor_account_id();
+        let reward = self.internal_take_farm_reward(
+            &sender_id,
+            reward_token,
+            total_reward,
+            start_at,
+            end_at,
+        );
+        let farm_id = self.next_farm_id;
+        self.next_farm_id += 1;
+        let prev_storage = env::storage_usage();
+        self.farms.insert(
+            &farm_id,
+            &Farm {
+                pool_id,
+                owner_id: sender_id.clone(),
+                total_staked: 0,
+                total_weight: 0,
+                locked_weight: 0,
+                penalty_per_weight: 0,
+                rewards: vec![reward],
+            },
+        );
+        self.internal_update_storage(&sender_id, prev_storage);
+        log!(
+            "Farm {} of pool {} created by {}",
+            farm_id,
+            pool_id,
+            sender_id
+        );
+        farm_id.into()
+    }
+
+    /// Adds another reward token to the farm, e.g. of a project co-incentivizing the pool, emitting `total_reward`
+    /// from the deposits of the caller linearly between `start_at` and `end_at`, independently of other rewards.
+    /// A farm has up to 5 rewards. Anyone can call it, and pays storage of the reward.
+    pub fn add_farm_reward(
+        &mut self,
+        farm_id: U64,
+        reward_token: AccountId,
+        total_reward: U128,
+        start_at: U64,
+        end_at: U64,
+    ) {
+        self.assert_running();
+        let mut farm = self.internal_get_farm(farm_id.into());
+        require(
+            farm.rewards.len() < MAX_FARM_REWARDS,
+            PoolError::TooManyFarmRewards,
+        );
+        let sender_id = env::predecessor_account_id();
+        let reward = self.internal_take_farm_reward(
+            &sender_id,
+            reward_token,
+            total_reward,
+            start_at,
+            end_at,
+        );
+        log!(
+            "{} added {} of {} to rewards of farm {}",
+            sender_id,
+            total_reward.0,
+            reward.reward_token,
+            farm_id.0
+        );
+        farm.rewards.push(reward);
+        let prev_storage = env::storage_usage();
+        self.farms.insert(&farm_id.into(), &farm);
+        self.internal_update_storage(&sender_id, prev_storage);
+    }
+
+    /// Stakes given number of caller's shares of the pool of the farm. The caller pays storage of the stake.
+    pub fn stake(&mut self, farm_id: U64, shares: U128) {
+        self.assert_running();
+        require(shares.0 > 0, PoolError::AmountZero);
+        let sender_id = env::predecessor_account_id();
+        let (mut farm, mut stake) = self.internal_get_stake(farm_id.into(), &sender_id);
+        let pool_id = farm.pool_id;
+        let mut pool = self.internal_get_pool(pool_id);
+        let prev_storage = env::storage_usage();
+        self.internal_settle_lp_fees(pool_id, &pool, &sender_id);
+        self.internal_settle_lp_fees(pool_id, &pool, &env::current_account_id());
+        pool.share_transfer(&sender_id, &env::current_account_id(), shares.into());
+        self.internal_prune_lp_fees(pool_id, &pool, &sender_id);
+        self.assert_shares_unlocked(pool_id, &pool, &sender_id);
+        self.internal_set_pool(pool_id, pool);
+        // Shares added to a locked stake get its lock and boost.
+        let weight = stake.boosted(shares.0);
+        stake.shares += shares.0;
+        stake.weight += weight;
+        farm.total_staked += shares.0;
+        farm.total_weight += weight;
+        if stake.locked_until > 0 {
+            farm.locked_weight += weight;
+        }
+        self.internal_set_stake(farm_id.into(), farm, &sender_id, stake);
+        self.internal_update_storage(&sender_id, prev_storage);
+    }
+
+    /// Locks caller's whole stake in the farm for given number of months (1 to 12) from now, boosting its weight
+    /// by 1/8 per month. The lock can be renewed, but can't end earlier than the current one.
+    pub fn lock_stake(&mut self, farm_id: U64, months: u32) {
+        require(
+            (1..=MAX_LOCK_MONTHS).contains(&months),
+            PoolError::WrongLockDuration,
+        );
+        let sender_id = env::predecessor_account_id();
+        let (mut farm, mut stake) = self.internal_get_stake(farm_id.into(), &sender_id);
+        require(stake.shares > 0, PoolError::NoShares);
+        let locked_until = timestamp_sec() + months as u64 * MONTH;
+        require(locked_until >= stake.locked_until, PoolError::LockTooShort);
+        farm.total_weight -= stake.weight;
+        if stake.locked_until > 0 {
+            farm.locked_weight -= stake.weight;
+        }
+        stake.boost = BOOST_DIVISOR + months * BOOST_PER_MONTH;
+        stake.weight = stake.boosted(stake.shares);
+        stake.locked_until = locked_until;
+        farm.total_weight += stake.weight;
+        farm.locked_weight += stake.weight;
+        self.internal_set_stake(farm_id.into(), farm, &sender_id, stake);
+        log!(
+            "{} locked stake in farm {} until {}",
+            sender_id,
+            farm_id.0,
+            locked_until
+        );
+    }
+
+    /// Ends boost of the stake of given account in the farm once its lock ended. Anyone can call it.
+    pub fn refresh_stake(&mut self, farm_id: U64, account_id: AccountId) {
+        let (farm, stake) = self.internal_get_stake(farm_id.into(), &account_id);
+        self.internal_set_stake(farm_id.into(), farm, &account_id, stake);
+    }
+
+    /// Unstakes given number of caller's shares from the farm back to the pool. Earned rewards stay to be claimed.
+    /// While the stake is locked, a part of the shares is taken as penalty for the other locked stakes,
+    /// up to a half with a whole year left, unless there are none. Returns number of shares received.
+    pub fn unstake(&mut self, farm_id: U64, shares: U128) -> U128 {
+        let sender_id = env::predecessor_account_id();
+        let (mut farm, mut stake) = self.internal_get_stake(farm_id.into(), &sender_id);
+        require(stake.shares >= shares.0, PoolError::NotEnoughShares);
+        let weight = if shares.0 == stake.shares {
+            stake.weight
+        } else {
+            stake.boosted(shares.0)
+        };
+        stake.shares -= shares.0;
+        stake.weight -= weight;
+        farm.total_staked -= shares.0;
+        farm.total_weight -= weight;
+        let mut penalty = 0;
+        if stake.locked_until > 0 {
+            farm.locked_weight -= weight;
+            penalty = farm.charge_penalty(shares.0, stake.locked_until - timestamp_sec());
+            if stake.shares == 0 {
+                stake.boost = BOOST_DIVISOR;
+                stake.locked_until = 0;
+            }
+        }
+        let pool_id = farm.pool_id;
+        let mut pool = self.internal_get_pool(pool_id);
+        let prev_storage = env::storage_usage();
+        self.internal_settle_lp_fees(pool_id, &pool, &env::current_account_id());
+        self.internal_settle_lp_fees(pool_id, &pool, &sender_id);
+        pool.share_transfer(&env::current_account_id(), &sender_id, shares.0 - penalty);
+        self.internal_prune_lp_fees(pool_id, &pool, &env::current_account_id());
+        self.internal_set_pool(pool_id, pool);
+        self.internal_set_stake(farm_id.into(), farm, &sender_id, stake);
+        self.internal_update_storage(&sender_id, prev_storage);
+        if penalty > 0 {
+            log!(
+                "{} unstaked {} shares from farm {} before the lock ended, {} taken as penalty",
+                sender_id,
+                shares.0,
+                farm_id.0,
+                penalty
+            );
+        }
+        (shares.0 - penalty).into()
+    }
+
+    /// Moves rewards the caller earned in the farm to its deposits, which must have the reward tokens registered,
+    /// and shares of early exit penalties it earned to its shares of the pool.
+    /// Returns claimed amounts, in the order of the farm's rewards.
+    pub fn claim_reward(&mut self, farm_id: U64) -> Vec<U128> {
+        let sender_id = env::predecessor_account_id();
+        let (farm, mut stake) = self.internal_get_stake(farm_id.into(), &sender_id);
+        let amounts = std::mem::replace(&mut stake.unclaimed, vec![0; farm.rewards.len()]);
+        let reward_tokens: Vec<AccountId> = farm
+            .rewards
+            .iter()
+            .map(|reward| reward.reward_token.clone())
+            .collect();
+        let penalty_shares = std::mem::take(&mut stake.penalty_shares);
+        let pool_id = farm.pool_id;
+        let prev_storage = env::storage_usage();
+        if penalty_shares > 0 {
+            let mut pool = self.internal_get_pool(pool_id);
+            self.internal_settle_lp_fees(pool_id, &pool, &env::current_account_id());
+            self.internal_settle_lp_fees(pool_id, &pool, &sender_id);
+            pool.share_transfer(&env::current_account_id(), &sender_id, penalty_shares);
+            self.internal_prune_lp_fees(pool_id, &pool, &env::current_account_id());
+            self.internal_set_pool(pool_id, pool);
+        }
+        self.internal_set_stake(farm_id.into(), farm, &sender_id, stake);
+        self.internal_update_storage(&sender_id, prev_storage);
+        self.internal_deposit_tokens(&sender_id, &reward_tokens, &amounts);
+        amounts.into_iter().map(U128).collect()
+    }
+
+    /// Moves rewards of the farm whose period ended and which weren't emitted to any stake, because nothing is staked
+    /// since, to the deposits of the creator of the farm, which must have the reward tokens registered.
+    /// Only the creator and owner can call it. Returns reclaimed amounts, in the order of the farm's rewards.
+    pub fn reclaim_farm_rewards(&mut self, farm_id: U64) -> Vec<U128> {
+        let mut farm = self.internal_get_farm(farm_id.into());
+        if env::predecessor_account_id() != farm.owner_id {
+            self.assert_owner();
+        }
+        let now = timestamp_sec();
+        let total_weight = farm.total_weight;
+        let amounts: Vec<Balance> = farm
+            .rewards
+            .iter_mut()
+            .map(|reward| {
+                // Rewards left with something staked go to the stakes once they are settled.
+                if total_weight > 0 || now < reward.end_at || reward.emitted_at >= reward.end_at {
+                    return 0;
+                }
+                let amount = reward.emitted(reward.end_at) - reward.emitted(reward.emitted_at);
+                reward.emitted_at = reward.end_at;
+                amount
+            })
+            .collect();
+        let reward_tokens: Vec<AccountId> = farm
+            .rewards
+            .iter()
+            .map(|reward| reward.reward_token.clone())
+            .collect();
+        let owner_id = farm.owner_id.clone();
+        self.farms.insert(&farm_id.into(), &farm);
+        self.internal_deposit_tokens(&owner_id, &reward_tokens, &amounts);
+        log!(
+            "Reclaimed {:?} of rewards of farm {} to {}",
+            amounts,
+            farm_id.0,
+            owner_id
+        );
+        amounts.into_iter().map(U128).collect()
+    }
+
+    /// Returns the farm, if it exists.
+    pub fn get_farm(&self, farm_id: U64) -> Option<FarmInfo> {
+        self.farms
+            .get(&farm_id.into())
+            .map(|farm| FarmInfo::new(farm_id.into(), farm))
+    }
+
+    /// Returns farms from given index up to given limit.
+    pub fn get_farms(&self, from_index: u64, limit: u64) -> Vec<FarmInfo> {
+        let keys = self.farms.keys_as_vector();
+        let values = self.farms.values_as_vector();
+        (from_index..std::cmp::min(from_index + limit, keys.len()))
+            .map(|index| FarmInfo::new(keys.get(index).unwrap(), values.get(index).unwrap()))
+            .collect()
+    }
+
+    /// Returns shares given account staked in the farm.
+    pub fn get_staked(&self, farm_id: U64, account_id: AccountId) -> U128 {
+        self.farm_stakes
+            .get(&(farm_id.into(), account_id))
+            .map(|stake| stake.shares)
+            .unwrap_or_default()
+            .into()
+    }
+
+    /// Returns stake of given account in the farm, if it has one.
+    pub fn get_stake(&self, farm_id: U64, account_id: AccountId) -> Option<StakeInfo> {
+        if !self
+            .farm_stakes
+            .contains_key(&(farm_id.into(), account_id.clone()))
+        {
+            return None;
+        }
+        let (_, stake) = self.internal_get_stake(farm_id.into(), &account_id);
+        Some(StakeInfo {
+            shares: stake.shares.into(),
+            weight: stake.weight.into(),
+            locked_until: stake.locked_until.into(),
+            penalty_shares: stake.penalty_shares.into(),
+        })
+    }
+
+    /// Returns rewards given account earned in the farm and can claim now, in the order of the farm's rewards.
+    pub fn get_unclaimed_rewards(&self, farm_id: U64, account_id: AccountId) -> Vec<U128> {
+        let (_, stake) = self.internal_get_stake(farm_id.into(), &account_id);
+        stake.unclaimed.into_iter().map(U128).collect()
+    }
+}
+
+impl Contract {
+    fn internal_get_farm(&self, farm_id: u64) -> Farm {
+        self.farms.get(&farm_id).unwrap_or_panic(PoolError::NoFarm)
+    }
+
+    /// Takes given amount of reward token from the deposits of the account for a new reward of a farm.
+    fn internal_take_farm_reward(
+        &mut self,
+        account_id: &AccountId,
+        reward_token: AccountId,
+        total_reward: U128,
+        start_at: U64,
+        end_at: U64,
+    ) -> FarmReward {
+        require(total_reward.0 > 0, PoolError::AmountZero);
+        require(
+            start_at.0 >= timestamp_sec() && end_at.0 > start_at.0,
+            PoolError::WrongFarmPeriod,
+        );
+        self.internal_withdraw_tokens(account_id, &[reward_token.clone()], &[total_reward.0]);
+        FarmReward {
+            reward_token,
+            total_reward: total_reward.into(),
+            start_at: start_at.into(),
+            end_at: end_at.into(),
+            reward_per_share: 0,
+            emitted_at: start_at.into(),
+        }
+    }
+
+    /// Returns the farm and stake of the account in it, with rewards emitted until now settled.
+    fn internal_get_stake(&self, farm_id: u64, account_id: &AccountId) -> (Farm, Stake) {
+        let mut farm = self.internal_get_farm(farm_id);
+        let mut stake = self
+            .farm_stakes
+            .get(&(farm_id, account_id.clone()))
+            .unwrap_or(Stake {
+                shares: 0,
+                weight: 0,
+                boost: BOOST_DIVISOR,
+                locked_until: 0,
+                penalty_per_weight: farm.penalty_per_weight,
+                penalty_shares: 0,
+                reward_per_share: vec![],
+                unclaimed: vec![],
+            });
+        // Rewards added after the last settlement start from 0 per share.
+        stake.reward_per_share.resize(farm.rewards.len(), 0);
+        stake.unclaimed.resize(farm.rewards.len(), 0);
+        let rewards = farm.rewards.iter_mut();
+        let checkpoints = stake
+            .reward_per_share
+            .iter_mut()
+            .zip(stake.unclaimed.iter_mut());
+        for (reward, (checkpoint, unclaimed)) in rewards.zip(checkpoints) {
+            reward.emit(timestamp_sec(), farm.total_weight);
+            // Reward per share wraps around, so the difference is right as long as it's below 2^128.
+            let reward_per_share = reward.reward_per_share.wrapping_sub(*checkpoint);
+            *unclaimed += (U256::from(stake.weight) * U256::from(reward_per_share)
+                / U256::from(REWARD_PER_SHARE_PRECISION))
+            .as_u128();
+            *checkpoint = reward.reward_per_share;
+        }
+        if stake.locked_until > 0 {
+            let penalty_per_weight = farm
+                .penalty_per_weight
+                .wrapping_sub(stake.penalty_per_weight);
+            stake.penalty_shares += (U256::from(stake.weight) * U256::from(penalty_per_weight)
+                / U256::from(REWARD_PER_SHARE_PRECISION))
+            .as_u128();
+            if timestamp_sec() >= stake.locked_until {
+                farm.total_weight = farm.total_weight - stake.weight + stake.shares;
+                farm.locked_weight -= stake.weight;
+                stake.weight = stake.shares;
+                stake.boost = BOOST_DIVISOR;
+                stake.locked_until = 0;
+            }
+        }
+        stake.penalty_per_weight = farm.penalty_per_weight;
+        (farm, stake)
+    }
+
+    /// Saves the farm and stake of the account in it, removing the stake once it has no shares and rewards.
+    fn internal_set_stake(
+        &mut self,
+        farm_id: u64,
+        farm: Farm,
+        account_id: &AccountId,
+        stake: Stake,
+    ) {
+        let key = (farm_id, account_id.clone());
+        if stake.shares == 0
+            && stake.penalty_shares == 0
+            && stake.unclaimed.iter().all(|amount| *amount == 0)
+        {
+            if self.farm_stakes.remove(&key).is_some() {
+                self.internal_close_position(account_id);
+            }
+        } else if self.farm_stakes.insert(&key, &stake).is_none() {
+            self.internal_open_position(account_id);
+        }
+        self.farms.insert(&farm_id, &farm);
+    }
+}
+
+impl Farm {
+    /// Charges penalty for unstaking given shares with given seconds of the lock left, adding it to
+    /// the penalty shares per locked weight. Nothing is charged if no other stake is locked. Returns the penalty.
+    fn charge_penalty(&mut self, shares: Balance, time_left: u64) -> Balance {
+        if self.locked_weight == 0 {
+            return 0;
+        }
+        let penalty =
+            (U256::from(shares) * U256::from(MAX_EARLY_EXIT_PENALTY) * U256::from(time_left)
+                / U256::from(FEE_DIVISOR)
+                / U256::from(MAX_LOCK_MONTHS as u64 * MONTH))
+            .as_u128();
+        let penalty_per_weight = U256::from(penalty) * U256::from(REWARD_PER_SHARE_PRECISION)
+            / U256::from(self.locked_weight);
+        self.penalty_per_weight = self
+            .penalty_per_weight
+            .wrapping_add(penalty_per_weight.low_u128());
+        penalty
+    }
+}
+
+impl Stake {
+    /// Returns weight of given shares with the boost of the stake.
+    fn boosted(&self, shares: Balance) -> Balance {
+        (U256::from(shares) * U256::from(self.boost) / U256::from(BOOST_DIVISOR)).as_u128()
+    }
+}
+
+impl FarmReward {
+    /// Returns amount of reward token emitted from the start until given timestamp.
+    fn emitted(&self, timestamp: u64) -> Balance {
+        let elapsed = timestamp.clamp(self.start_at, self.end_at) - self.start_at;
+        (U256::from(self.total_reward) * U256::from(elapsed)
+            / U256::from(self.end_at - self.start_at))
+        .as_u128()
+    }
+
+    /// Adds rewards emitted until given timestamp to the rewards per share of given staked shares.
+    /// Nothing is added while nothing is staked, so those rewards go to the first stake after.
+    fn emit(&mut self, timestamp: u64, total_staked: Balance) {
+        if total_staked == 0 || timestamp <= self.emitted_at {
+            return;
+        }
+        let amount = self.emitted(timestamp) - self.emitted(self.emitted_at);
+        let reward_per_share =
+            U256::from(amount) * U256::from(REWARD_PER_SHARE_PRECISION) / U256::from(total_staked);
+        self.reward_per_share = self
+            .reward_per_share
+            .wrapping_add(reward_per_share.low_u128());
+        self.emitted_at = timestamp;
+    }
+}
+
+impl FarmInfo {
+    fn new(farm_id: u64, farm: Farm) -> Self {
+        Self {
+            farm_id: farm_id.into(),
+            pool_id: farm.pool_id,
+            owner_id: farm.owner_id,
+            total_staked: farm.total_staked.into(),
+            total_weight: farm.total_weight.into(),
+            rewards: farm
+                .rewards
+                .into_iter()
+                .map(|reward| FarmRewardInfo {
+                    reward_token: reward.reward_token,
+                    total_reward: reward.total_reward.into(),
+                    start_at: reward.start_at.into(),
+                    end_at: reward.end_at.into(),
+                })
+                .collect(),
+        }
+    }
+}
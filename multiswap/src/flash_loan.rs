@@ -0,0 +1,377 @@
+//! Flash loans of pool reserves. The loaned tokens are transferred to the receiver, which gets `on_flash_loan` call,
+//! and must deposit the loan plus fee back to its deposits before it returns (e.g. with `ft_transfer_call`).
+//! The repayment is then taken from its deposits, and the fee is added to the pool, so it accrues to liquidity providers.
+//! Balances of the pool are not changed while the loan is out, so its price can't be moved by the loan.
+//! The pool takes no swaps and no liquidity changes meanwhile, so the loaned tokens, which the contract doesn't hold,
+//! can't be swapped or withdrawn out of it, and any unpaid part of the loan is written off the liquidity that lent it.
+//! Flash swaps are flash loans of the output of a swap, which the receiver (`on_flash_swap`) may repay either
+//! with the input of the swap, executed once it's repaid, or like a flash loan.
+//! Cross-contract calls can't be reverted, so only receivers approved by owner can borrow,
+//! and any part of the loan they fail to repay is written off the pool. If resolving the loan fails, e.g. out of gas
+//! swapping the repayment, the last callback takes the repayment like for a flash loan, so the pool is always released.
+
+use crate::errors::{require, PoolError};
+use crate::events::emit_swap;
+use crate::utils::{
+    ext_flash_loan_receiver, ext_fungible_token, GAS_FOR_FLASH_LOAN, GAS_FOR_FT_TRANSFER,
+    GAS_FOR_ON_FLASH_LOAN_RESOLVED, GAS_FOR_ON_FLASH_LOAN_TRANSFER, GAS_FOR_RESOLVE_FLASH_LOAN,
+    GAS_FOR_RESOLVE_FLASH_SWAP, U256,
+};
+use crate::*;
+use near_sdk::serde_json;
+
+/// Flash loan passed between the callbacks.
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(crate = "near_sdk::serde")]
+pub struct FlashLoan {
+    /// Pool lending its reserves.
+    pub pool_id: u64,
+    /// Loaned token.
+    pub token_id: AccountId,
+    /// Loaned amount.
+    pub amount: U128,
+    /// Fee to be paid on top of the loaned amount.
+    pub fee: U128,
+    /// Account receiving the loan.
+    pub receiver_id: AccountId,
+    /// For flash swaps, token the loan can be repaid in by swapping `amount_in` of it into the loaned amount.
+    pub token_in: Option<AccountId>,
+    /// Amount of token in repaying a flash swap, quoted when the swap starts.
+    pub amount_in: U128,
+}
+
+#[near_bindgen]
+impl Contract {
+    /// Lends given amount of token from the reserves of the pool to the receiver, which must be approved by owner
+    /// and registered, and calls `on_flash_loan(sender_id, token_id, amount, fee, msg)` on it.
+    /// The receiver must have the amount plus `flash_loan_fee` in its deposits when the call ends.
+    /// Each pool has at most one loan out at a time.
+    pub fn flash_loan(
+        &mut self,
+        pool_id: u64,
+        token_id: AccountId,
+        amount: U128,
+        receiver_id: AccountId,
+        msg: String,
+    ) -> Promise {
+        self.internal_flash_loan(
+            FlashLoan {
+                pool_id,
+                token_id,
+                amount,
+                fee: U128(0),
+                receiver_id,
+                token_in: None,
+                amount_in: U128(0),
+            },
+            msg,
+        )
+    }
+
+    /// Sends `amount_out` of token_out from the pool to the receiver before it pays for it, and calls
+    /// `on_flash_swap(sender_id, loan, msg)` on it. When the call ends, the receiver must have in its deposits either
+    /// `loan.amount_in` of token_in, which is swapped for at least amount_out with the surplus credited to its deposits,
+    /// or amount_out plus `flash_loan_fee` of token_out. Same rules as for `flash_loan` apply.
+    /// Only pools that can be quoted by `get_best_route` support swapping the repayment.
+    pub fn flash_swap(
+        &mut self,
+        pool_id: u64,
+        token_in: AccountId,
+        token_out: AccountId,
+        amount_out: U128,
+        receiver_id: AccountId,
+        msg: String,
+    ) -> Promise {
+        let amount_in =
+            self.internal_get_pool(pool_id)
+                .get_amount_in(&token_in, amount_out.into(), &token_out);
+        self.internal_flash_loan(
+            FlashLoan {
+                pool_id,
+                token_id: token_out,
+                amount: amount_out,
+                fee: U128(0),
+                receiver_id,
+                token_in: Some(token_in),
+                amount_in: amount_in.into(),
+            },
+            msg,
+        )
+    }
+
+    /// Callback after the transfer of the loan. Calls the receiver if the transfer succeeded,
+    /// otherwise the loan is cancelled.
+    #[private]
+    pub fn on_flash_loan_transfer(&mut self, loan: FlashLoan, sender_id: AccountId, msg: String) {
+        require(env::promise_results_count() == 1, PoolError::Invalid);
+        if let PromiseResult::Successful(_) = env::promise_result(0) {
+            self.internal_sub_token_balance(&loan.token_id, loan.amount.into());
+            let receiver_id = loan.receiver_id.clone();
+            let gas = env::prepaid_gas() - GAS_FOR_ON_FLASH_LOAN_TRANSFER;
+            let resolve_gas = if loan.token_in.is_some() {
+                GAS_FOR_RESOLVE_FLASH_SWAP
+            } else {
+                GAS_FOR_RESOLVE_FLASH_LOAN
+            };
+            let promise = if loan.token_in.is_some() {
+                ext_flash_loan_receiver::ext(receiver_id.clone())
+                    .with_static_gas(gas)
+                    .on_flash_swap(sender_id, loan.clone(), msg)
+            } else {
+                ext_flash_loan_receiver::ext(receiver_id.clone())
+                    .with_static_gas(gas)
+                    .on_flash_loan(sender_id, loan.token_id.clone(), loan.amount, loan.fee, msg)
+            };
+            promise
+                .then(
+                    ext_self::ext(env::current_account_id())
+                        .with_static_gas(resolve_gas)
+                        .resolve_flash_loan(loan.clone()),
+                )
+                .then(
+                    ext_self::ext(env::current_account_id())
+                        .with_static_gas(GAS_FOR_ON_FLASH_LOAN_RESOLVED)
+                        .on_flash_loan_resolved(loan),
+                );
+            return;
+        }
+        log!(
+            "Flash loan of {} {} to {} failed",
+            loan.amount.0,
+            loan.token_id,
+            loan.receiver_id
+        );
+        if let Some(balance) = self.measured_balances.get(&loan.token_id) {
+            self.measured_balances
+                .insert(&loan.token_id, &(balance + loan.amount.0));
+        }
+        self.flash_loan_pools.remove(&loan.pool_id);
+    }
+
+    /// Callback after `on_flash_loan` or `on_flash_swap` of the receiver. A flash swap is repaid by swapping
+    /// its amount in from the deposits of the receiver, if it has enough and the pool still returns the loaned amount.
+    /// Otherwise takes the loan plus fee from the deposits of the receiver, or as much as it has,
+    /// and adds the fee to the pool. Unpaid part of the loan is subtracted from the pool.
+    /// Returns the repaid amount, in token in for swapped repayments.
+    #[private]
+    pub fn resolve_flash_loan(&mut self, loan: FlashLoan) -> U128 {
+        self.flash_loan_pools.remove(&loan.pool_id);
+        if let Some(repaid) = self.internal_repay_flash_swap(&loan) {
+            return repaid.into();
+        }
+        self.internal_resolve_flash_loan(loan).into()
+    }
+
+    /// Callback after `resolve_flash_loan`. Returns its result, or if it failed, releases the pool and takes
+    /// the repayment like for a flash loan, without swapping. Returns the repaid amount.
+    #[private]
+    pub fn on_flash_loan_resolved(&mut self, loan: FlashLoan) -> U128 {
+        require(env::promise_results_count() == 1, PoolError::Invalid);
+        if let PromiseResult::Successful(value) = env::promise_result(0) {
+            if let Ok(repaid) = serde_json::from_slice::<U128>(&value) {
+                return repaid;
+            }
+        }
+        log!(
+            "Resolving flash loan of {} {} to {} failed, repaying it without swap",
+            loan.amount.0,
+            loan.token_id,
+            loan.receiver_id
+        );
+        self.flash_loan_pools.remove(&loan.pool_id);
+        self.internal_resolve_flash_loan(loan).into()
+    }
+
+    /// Approves given contract to receive flash loans. Only owner can call it.
+    #[payable]
+    pub fn add_flash_loan_receiver(&mut self, receiver_id: AccountId) {
+        assert_one_yocto();
+        self.assert_owner();
+        self.flash_loan_receivers.insert(&receiver_id);
+    }
+
+    /// Revokes approval of given contract to receive flash loans. Only owner can call it.
+    #[payable]
+    pub fn remove_flash_loan_receiver(&mut self, receiver_id: AccountId) {
+        assert_one_yocto();
+        self.assert_owner();
+        self.flash_loan_receivers.remove(&receiver_id);
+    }
+
+    /// Sets fee of flash loans, e.g. 9 for 0.09% of the loan. Only owner and fee setters can call it.
+    #[payable]
+    pub fn set_flash_loan_fee(&mut self, flash_loan_fee: u32) {
+        assert_one_yocto();
+        self.assert_role(Role::FeeSetter);
+        self.assert_no_timelock();
+        self.internal_set_flash_loan_fee(flash_loan_fee);
+    }
+
+    /// Returns contracts approved to receive flash loans.
+    pub fn get_flash_loan_receivers(&self) -> Vec<AccountId> {
+        self.flash_loan_receivers.to_vec()
+    }
+
+    /// Returns fee of flash loans, divided by 10000.
+    pub fn get_flash_loan_fee(&self) -> u32 {
+        self.flash_loan_fee
+    }
+}
+
+impl Contract {
+    /// Checks that the pool has no flash loan out.
+    pub(crate) fn assert_no_flash_loan(&self, pool_id: u64) {
+        require(
+            !self.flash_loan_pools.contains(&pool_id),
+            PoolError::FlashLoanInProgress,
+        );
+    }
+
+    pub(crate) fn internal_set_flash_loan_fee(&mut self, flash_loan_fee: u32) {
+        require(flash_loan_fee < FEE_DIVISOR, PoolError::FeeTooLarge);
+        self.flash_loan_fee = flash_loan_fee;
+    }
+
+    /// Checks the loan, charges `flash_loan_fee` on it and transfers it to the receiver.
+    fn internal_flash_loan(&mut self, mut loan: FlashLoan, msg: String) -> Promise {
+        require(
+            self.flash_loan_receivers.contains(&loan.receiver_id),
+            PoolError::NotFlashLoanReceiver,
+        );
+        require(loan.amount.0 > 0, PoolError::AmountZero);
+        self.assert_pool_running(loan.pool_id);
+        self.internal_get_account(&loan.receiver_id);
+        let pool = self.internal_get_pool(loan.pool_id);
+        require(
+            loan.amount.0 <= pool.reserve(&loan.token_id),
+            PoolError::NotEnoughLiquidity,
+        );
+        require(
+            self.flash_loan_pools.insert(&loan.pool_id),
+            PoolError::FlashLoanInProgress,
+        );
+        if let Some(balance) = self.measured_balances.get(&loan.token_id) {
+            self.measured_balances
+                .insert(&loan.token_id, &balance.saturating_sub(loan.amount.0));
+        }
+        let fee = (U256::from(loan.amount.0) * U256::from(self.flash_loan_fee)
+            + U256::from(FEE_DIVISOR - 1))
+            / U256::from(FEE_DIVISOR);
+        loan.fee = U128(fee.as_u128());
+        ext_fungible_token::ext(loan.token_id.clone())
+            .with_attached_deposit(1)
+            .with_static_gas(GAS_FOR_FT_TRANSFER)
+            .ft_transfer(loan.receiver_id.clone(), loan.amount, None)
+            .then(
+                ext_self::ext(env::current_account_id())
+                    .with_static_gas(env::prepaid_gas() - GAS_FOR_FLASH_LOAN)
+                    .on_flash_loan_transfer(loan, env::predecessor_account_id(), msg),
+            )
+    }
+
+    /// Takes the loan plus fee from the deposits of the receiver, or as much as it has, and adds the fee to the pool.
+    /// Unpaid part of the loan is subtracted from the pool. Returns the repaid amount.
+    fn internal_resolve_flash_loan(&mut self, loan: FlashLoan) -> Balance {
+        let FlashLoan {
+            pool_id,
+            token_id,
+            amount,
+            fee,
+            receiver_id,
+            ..
+        } = loan;
+        let mut pool = self.internal_get_pool(pool_id);
+        // Fee is not charged if all liquidity left the pool meanwhile, as there is no one to receive it.
+        let due = if pool.share_total_balance() > 0 {
+            amount.0 + fee.0
+        } else {
+            amount.0
+        };
+        let repaid = match self.deposited_amounts.get(&receiver_id) {
+            Some(mut deposits) => {
+                let repaid = std::cmp::min(due, deposits.get_balance(&token_id));
+                deposits.withdraw(&token_id, repaid);
+                self.deposited_amounts.insert(&receiver_id, &deposits);
+                repaid
+            }
+            None => 0,
+        };
+        if repaid > amount.0 {
+            pool.donate(&token_id, repaid - amount.0);
+        } else if repaid < amount.0 {
+            let loss = std::cmp::min(amount.0 - repaid, pool.reserve(&token_id));
+            log!(
+                "Flash loan of {} {} to {} is not repaid, the pool lost {}",
+                amount.0,
+                token_id,
+                receiver_id,
+                loss
+            );
+            pool.withdraw_fee(&token_id, loss);
+        }
+        self.internal_set_pool(pool_id, pool);
+        repaid
+    }
+
+    /// Repays the flash swap by swapping its amount in from the deposits of the receiver into the loaned token,
+    /// crediting the receiver with whatever the swap returns above the loan. Returns None without changes if the loan
+    /// isn't a flash swap, the receiver doesn't have the amount in, or the swap doesn't cover the loan,
+    /// would trip the circuit breaker of the pool or take its reserve below the floor. Pools in batch auction mode take swaps only through their batch,
+    /// and paused pools take none.
+    fn internal_repay_flash_swap(&mut self, loan: &FlashLoan) -> Option<Balance> {
+        let token_in = loan.token_in.as_ref()?;
+        if self.batch_auctions.contains_key(&loan.pool_id)
+            || self.running_state != RunningState::Running
+            || self.paused_pools.contains(&loan.pool_id)
+        {
+            return None;
+        }
+        let mut deposits = self.deposited_amounts.get(&loan.receiver_id)?;
+        if deposits.get_balance(token_in) < loan.amount_in.0 {
+            return None;
+        }
+        let mut pool = self.internal_get_pool(loan.pool_id);
+        let amount_out = pool.try_get_return(token_in, loan.amount_in.0, &loan.token_id)?;
+        if amount_out < loan.amount.0 {
+            return None;
+        }
+        self.internal_update_twap(loan.pool_id, &pool);
+        self.internal_update_circuit_breaker(loan.pool_id, &pool);
+        let fee = pool.current_fee();
+        let amount_out = pool.swap(token_in, loan.amount_in.0, &loan.token_id, loan.amount.0);
+        if !self.internal_circuit_breaker_allows(loan.pool_id, &pool)
+            || !self.internal_reserve_floor_allows(loan.pool_id, &pool, &loan.token_id)
+        {
+            return None;
+        }
+        let fee_taken = self.internal_collect_protocol_fee(
+            loan.pool_id,
+            &mut pool,
+            token_in,
+            loan.amount_in.0,
+            fee,
+        );
+        self.internal_accrue_lp_fees(
+            loan.pool_id,
+            &pool,
+            token_in,
+            loan.amount_in.0,
+            fee,
+            fee_taken,
+        );
+        self.internal_record_swap(loan.pool_id, &pool, token_in, loan.amount_in.0, fee);
+        deposits.withdraw(token_in, loan.amount_in.0);
+        self.internal_set_pool(loan.pool_id, pool);
+        deposits.refund(&loan.token_id, amount_out - loan.amount.0);
+        self.deposited_amounts.insert(&loan.receiver_id, &deposits);
+        emit_swap(
+            &loan.receiver_id,
+            loan.pool_id,
+            token_in,
+            loan.amount_in.0,
+            &loan.token_id,
+            amount_out,
+        );
+        Some(loan.amount_in.0)
+    }
+}
@@ -0,0 +1,88 @@
+//! Governance by a Sputnik DAO. Once owner sets a DAO contract, admin methods can only be called by the DAO,
+//! through its function call proposals. `get_dao_proposal` builds the proposal for an admin action,
+//! going through `propose_admin_action` when the timelock is set.
+
+use crate::utils::GAS_FOR_DAO_ACTION;
+use crate::*;
+use near_sdk::json_types::Base64VecU8;
+
+/// Function call of a Sputnik DAO proposal (`ActionCall`).
+#[derive(Debug, Serialize, Deserialize, PartialEq)]
+#[serde(crate = "near_sdk::serde")]
+pub struct DaoActionCall {
+    /// Method of this contract to call.
+    pub method_name: String,
+    /// JSON arguments of the method.
+    pub args: Base64VecU8,
+    /// Attached deposit.
+    pub deposit: U128,
+    /// Attached gas.
+    pub gas: U64,
+}
+
+/// Payload of a Sputnik DAO `FunctionCall` proposal, i.e. `{"FunctionCall": <DaoProposal>}`.
+#[derive(Debug, Serialize, Deserialize, PartialEq)]
+#[serde(crate = "near_sdk::serde")]
+pub struct DaoProposal {
+    /// This contract.
+    pub receiver_id: AccountId,
+    /// Calls to make once the proposal is approved.
+    pub actions: Vec<DaoActionCall>,
+}
+
+#[near_bindgen]
+impl Contract {
+    /// Hands admin methods over to given DAO contract, or back to owner with None. Only owner can call it,
+    /// or the DAO once it's set.
+    #[payable]
+    pub fn set_dao(&mut self, dao_id: Option<AccountId>) {
+        assert_one_yocto();
+        self.assert_owner();
+        self.dao_id = dao_id;
+    }
+
+    /// Returns DAO contract calling admin methods, if it's set.
+    pub fn get_dao(&self) -> Option<AccountId> {
+        self.dao_id.clone()
+    }
+
+    /// Returns payload of the DAO proposal executing given admin action: a call of its setter,
+    /// or of `propose_admin_action` while the timelock delay is set, with the 1 yoctoNEAR admin methods require.
+    pub fn get_dao_proposal(&self, action: AdminAction) -> DaoProposal {
+        let (method_name, args) = if self.timelock_delay > 0 {
+            (
+                "propose_admin_action",
+                serde_json::json!({ "action": action }),
+            )
+        } else {
+            let method_name = match action {
+                AdminAction::SetReferralFee { .. } => "set_referral_fee",
+                AdminAction::SetProtocolFee { .. } => "set_protocol_fee",
+                AdminAction::SetFlashLoanFee { .. } => "set_flash_loan_fee",
+                AdminAction::SetLimitOrderFees { .. } => "set_limit_order_fees",
+                AdminAction::SetStopOrderBounty { .. } => "set_stop_order_bounty",
+                AdminAction::AddWhitelistedToken { .. } => "add_whitelisted_token",
+                AdminAction::RemoveWhitelistedToken { .. } => "remove_whitelisted_token",
+                AdminAction::SetTreasury { .. } => "set_treasury",
+                AdminAction::SetVaultProtocolFees { .. } => "set_vault_protocol_fees",
+                AdminAction::SetTimelockDelay { .. } => "set_timelock_delay",
+                AdminAction::SetReserveFloor { .. } => "set_reserve_floor",
+            };
+            // Fields of each action are named as the arguments of its setter.
+            let args = match serde_json::to_value(&action).unwrap() {
+                serde_json::Value::Object(action) => action.into_iter().next().unwrap().1,
+                _ => unreachable!(),
+            };
+            (method_name, args)
+        };
+        DaoProposal {
+            receiver_id: env::current_account_id(),
+            actions: vec![DaoActionCall {
+                method_name: method_name.to_string(),
+                args: args.to_string().into_bytes().into(),
+                deposit: U128(1),
+                gas: U64(GAS_FOR_DAO_ACTION.0),
+            }],
+        }
+    }
+}
@@ -0,0 +1,88 @@
+//! Emergency response. Guardians (see `Role::Guardian`) can pause single pools or the whole exchange to contain
+//! an exploit, but can't change fees, configuration or move funds. Only owner can resume.
+//! Paused pools don't take swaps, liquidity and flash loans, while liquidity can still be removed from them.
+//! Paused exchange also doesn't take deposits, new pools and orders, while withdrawals and cancellations still work,
+//! so users can exit while an exploit is contained.
+
+use crate::errors::{require, PoolError};
+use crate::*;
+
+/// Whether the contract is running or paused as a whole.
+#[derive(
+    BorshSerialize, BorshDeserialize, Serialize, Deserialize, Clone, Copy, Debug, PartialEq,
+)]
+#[serde(crate = "near_sdk::serde")]
+pub enum RunningState {
+    Running,
+    Paused,
+}
+
+#[near_bindgen]
+impl Contract {
+    /// Pauses given pool. Owner and guardians can call it.
+    #[payable]
+    pub fn pause_pool(&mut self, pool_id: u64) {
+        assert_one_yocto();
+        self.assert_role(Role::Guardian);
+        self.internal_get_pool(pool_id);
+        self.paused_pools.insert(&pool_id);
+        log!(
+            "Pool {} paused by {}",
+            pool_id,
+            env::predecessor_account_id()
+        );
+    }
+
+    /// Resumes given pool. Only owner can call it.
+    #[payable]
+    pub fn resume_pool(&mut self, pool_id: u64) {
+        assert_one_yocto();
+        self.assert_owner();
+        self.paused_pools.remove(&pool_id);
+    }
+
+    /// Pauses all pools. Owner and guardians can call it.
+    #[payable]
+    pub fn pause_exchange(&mut self) {
+        assert_one_yocto();
+        self.assert_role(Role::Guardian);
+        self.running_state = RunningState::Paused;
+        log!("Exchange paused by {}", env::predecessor_account_id());
+    }
+
+    /// Resumes the exchange. Pools paused one by one stay paused. Only owner can call it.
+    #[payable]
+    pub fn resume_exchange(&mut self) {
+        assert_one_yocto();
+        self.assert_owner();
+        self.running_state = RunningState::Running;
+    }
+
+    /// Returns pools paused one by one.
+    pub fn get_paused_pools(&self) -> Vec<u64> {
+        self.paused_pools.to_vec()
+    }
+
+    /// Returns whether the contract is running or paused as a whole.
+    pub fn get_running_state(&self) -> RunningState {
+        self.running_state
+    }
+}
+
+impl Contract {
+    /// Fails if the exchange is paused. Must be called at the top of every entry point that takes funds in
+    /// or trades, but not of withdrawals.
+    pub(crate) fn assert_running(&self) {
+        require(
+            self.running_state == RunningState::Running,
+            PoolError::ContractPaused,
+        );
+    }
+
+    /// Fails if the exchange or given pool is paused. Must be called before every swap in the pool
+    /// and before adding liquidity to it.
+    pub(crate) fn assert_pool_running(&self, pool_id: u64) {
+        self.assert_running();
+        require(!self.paused_pools.contains(&pool_id), PoolError::PoolPaused);
+    }
+}
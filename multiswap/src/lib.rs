@@ -1,35 +1,125 @@
-use std::collections::HashMap;
-use std::convert::TryInto;
-
 use near_contract_standards::storage_management::{
     StorageBalance, StorageBalanceBounds, StorageManagement,
 };
 use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
-use near_sdk::collections::{LookupMap, Vector};
-use near_sdk::json_types::{ValidAccountId, U128};
+use near_sdk::collections::{LookupMap, UnorderedMap, UnorderedSet, Vector};
+use near_sdk::json_types::{U128, U64};
 use near_sdk::serde::{Deserialize, Serialize};
 use near_sdk::{
-    assert_one_yocto, env, log, near_bindgen, AccountId, Balance, PanicOnDefault, Promise,
+    assert_one_yocto, env, log, near_bindgen, serde_json, AccountId, Balance, PanicOnDefault,
+    Promise, PromiseResult, StorageUsage,
 };
 
-use crate::pool::Pool;
+use crate::account_deposit::{AccountDeposits, BYTES_PER_DEPOSIT_RECORD};
+pub use crate::acl::Role;
+use crate::batch_auction::BatchAuction;
+pub use crate::batch_auction::BatchOrderInfo;
+use crate::bonding_curve_pool::BondingCurvePool;
+use crate::circuit_breaker::CircuitBreaker;
+use crate::concentrated_pool::ConcentratedPool;
+use crate::constant_sum_pool::ConstantSumPool;
+use crate::dynamic_fee::DynamicFee;
+pub use crate::errors::PoolError;
+use crate::errors::{require, UnwrapOrPanic};
+use crate::events::{emit_add_liquidity, emit_remove_liquidity, emit_swap, emit_withdraw};
+use crate::farm::{Farm, Stake};
+pub use crate::farm::{FarmInfo, FarmRewardInfo, StakeInfo};
+pub use crate::governance::{DaoActionCall, DaoProposal};
+pub use crate::guardian::RunningState;
+use crate::limit_order::LimitOrder;
+pub use crate::limit_order::{LimitOrderFees, LimitOrderInfo};
+use crate::lp_fees::FeeCheckpoint;
+pub use crate::lp_fees::LiquidityProviderInfo;
+pub use crate::migration::StateVersion;
+use crate::migration::CURRENT_STATE_VERSION;
+use crate::pool::{Pool, RetiredPool, VersionedPool};
+use crate::pool_stats::PoolStats;
+pub use crate::pool_stats::PoolStatsInfo;
+use crate::position_nft::PositionNft;
+pub use crate::position_nft::{NftContractMetadata, PositionNftMetadata, PositionNftToken};
+pub use crate::share_lock::ShareLock;
 use crate::simple_pool::SimplePool;
-use crate::utils::{check_token_duplicates, ext_fungible_token, GAS_FOR_FT_TRANSFER};
-pub use crate::views::PoolInfo;
+use crate::stable_swap_pool::StableSwapPool;
+use crate::stop_order::StopOrder;
+pub use crate::stop_order::StopOrderInfo;
+use crate::timelock::PendingAdminAction;
+pub use crate::timelock::{AdminAction, AdminActionInfo};
+pub use crate::token_metadata::TokenMetadata;
+use crate::twap::TwapOracle;
+pub use crate::upgrade::StagedCode;
+use crate::utils::{
+    check_pool_tokens, ext_fungible_token, ext_rate_provider, ext_self, ext_wrap_near,
+    shares_token_id, GAS_FOR_FT_BALANCE_OF, GAS_FOR_FT_METADATA, GAS_FOR_FT_TRANSFER,
+    GAS_FOR_GET_RATE, GAS_FOR_NEAR_DEPOSIT, GAS_FOR_NEAR_WITHDRAW, GAS_FOR_ON_BALANCE,
+    GAS_FOR_ON_EXCESS_TRANSFER, GAS_FOR_ON_MEASURED_BALANCE, GAS_FOR_ON_NEAR_DEPOSIT,
+    GAS_FOR_ON_NEAR_WITHDRAW, GAS_FOR_ON_TOKEN_METADATA, GAS_FOR_ON_TOKEN_RATE,
+    GAS_FOR_ON_TRANSFER_CALL, GAS_FOR_ON_WITHDRAW, GAS_FOR_SEND_EXCESS, GAS_FOR_SWAP_AND_CALL,
+    GAS_FOR_SWAP_NEAR, U256,
+};
+use crate::vault::Vault;
+pub use crate::vault::VaultInfo;
+pub use crate::views::{
+    AddLiquidityReturn, ConfigInfo, PoolInfo, PoolReturn, PositionInfo, RouteInfo, RouteReturn,
+    SwapSimulation,
+};
+use crate::weight_schedule::WeightSchedule;
+use crate::weighted_pool::WeightedPool;
 
+mod account_deposit;
+mod acl;
+mod aurora;
+mod batch_auction;
+mod bonding_curve_pool;
+mod circuit_breaker;
+mod concentrated_pool;
+mod constant_sum_pool;
+mod dynamic_fee;
+mod errors;
+mod events;
+mod farm;
+mod flash_loan;
+mod governance;
+mod guardian;
+mod limit_order;
+mod lp_fees;
+mod lp_token;
+mod migration;
+mod multi_token;
 mod pool;
+mod pool_creation;
+mod pool_stats;
+mod position_nft;
+mod protocol_fee;
+mod reserve_floor;
+mod share_lock;
 mod simple_pool;
+mod stable_swap_pool;
+mod stop_order;
 mod storage_impl;
+mod timelock;
+mod token_metadata;
 mod token_receiver;
+mod twap;
+mod upgrade;
 mod utils;
+mod vault;
 mod views;
+mod weight_schedule;
+mod weighted_math;
+mod weighted_pool;
 
-near_sdk::setup_alloc!();
-
-const MAX_ACCOUNT_LENGTH: u128 = 64;
-const MAX_NUMBER_OF_TOKENS: u128 = 10;
-const BYTES_PER_DEPOSIT_RECORD: u128 =
-    MAX_NUMBER_OF_TOKENS * (MAX_ACCOUNT_LENGTH + 16) + 4 + MAX_ACCOUNT_LENGTH;
+/// Default maximum number of tokens in a single pool.
+const DEFAULT_MAX_NUM_TOKENS: u32 = 10;
+/// Upper bound of configurable maximum number of tokens in a pool, so swaps in the largest pools stay well within gas limits.
+const MAX_NUM_TOKENS_LIMIT: u32 = 20;
+/// Divisor of the referral fee, which is a fraction of the swap fee.
+const REFERRAL_FEE_DIVISOR: u32 = 10_000;
+/// Divisor of the protocol fee, which is a fraction of the swap fee.
+const PROTOCOL_FEE_DIVISOR: u32 = 10_000;
+/// Divisor of swap fees of the pools.
+const FEE_DIVISOR: u32 = 10_000;
+/// Number of parts the amount of a split swap is allocated in when splitting for the best output.
+const SPLIT_STEPS: u128 = 20;
 
 /// Single swap action.
 #[derive(Serialize, Deserialize)]
@@ -38,318 +128,6806 @@ pub struct SwapAction {
     /// Pool which should be used for swapping.
     pub pool_id: u64,
     /// Token to swap from.
-    pub token_in: ValidAccountId,
+    pub token_in: AccountId,
     /// Amount to exchange.
-    /// If amount_in is None, it will take amount_out from previous step.
+    /// If amount_in is None, it will take amount_out from previous step, which must be in token_in.
     /// Will fail if amount_in is None on the first step.
     pub amount_in: Option<U128>,
     /// Token to swap into.
-    pub token_out: ValidAccountId,
+    pub token_out: AccountId,
     /// Required minimum amount of token_out.
     pub min_amount_out: U128,
 }
 
+/// Swap of one input amount split across several pools of the same pair, with the outputs added up.
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct SplitSwapAction {
+    /// Pools to swap in, each of them with both tokens.
+    pub pool_ids: Vec<u64>,
+    /// Token to swap from.
+    pub token_in: AccountId,
+    /// Total amount to exchange.
+    pub amount_in: U128,
+    /// Token to swap into.
+    pub token_out: AccountId,
+    /// Relative parts of amount_in swapped in each pool. If None, amount_in is split for the best total output.
+    pub ratios: Option<Vec<u32>>,
+    /// Required minimum total amount of token_out.
+    pub min_amount_out: U128,
+}
+
+/// Single swap of a route quoted by `get_return_by_route`.
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct RouteHop {
+    /// Pool to swap in.
+    pub pool_id: u64,
+    /// Token to swap from, which must be token_out of the previous hop.
+    pub token_in: AccountId,
+    /// Token to swap into.
+    pub token_out: AccountId,
+}
+
 #[near_bindgen]
 #[derive(BorshSerialize, BorshDeserialize, PanicOnDefault)]
 pub struct Contract {
-    pools: Vector<Pool>,
+    /// Account that can change configuration of the contract.
+    owner_id: AccountId,
+    /// Account proposed by owner to become the new owner once it accepts.
+    pending_owner_id: Option<AccountId>,
+    /// Maximum number of tokens in newly created pools.
+    max_num_tokens: u32,
+    pools: Vector<VersionedPool>,
     /// Balances of deposited tokens for each account.
-    deposited_amounts: LookupMap<AccountId, HashMap<AccountId, Balance>>,
+    deposited_amounts: LookupMap<AccountId, AccountDeposits>,
+    /// Ids of pools over the same sorted set of tokens, i.e. fee tiers of the same market.
+    fee_tiers: LookupMap<Vec<AccountId>, Vec<u64>>,
+    /// Ids of pools of more than two tokens containing each sorted pair of them. Pools of two tokens are in `fee_tiers`.
+    token_pair_pools: LookupMap<Vec<AccountId>, Vec<u64>>,
+    /// Ids of pools containing each token.
+    token_pools: LookupMap<AccountId, Vec<u64>>,
+    /// Wrapped NEAR contract used to swap native NEAR.
+    wnear_id: Option<AccountId>,
+    /// Balances of the contract in tokens that take a cut on transfer, as of the last measurement.
+    /// Deposits of these tokens are credited by the measured change of the balance instead of the transferred amount.
+    measured_balances: LookupMap<AccountId, Balance>,
+    /// Tokens that can be deposited and added to new pools.
+    whitelisted_tokens: UnorderedSet<AccountId>,
+    /// Amount of each token held by pools, deposits and limit orders. Anything the contract holds above it is excess,
+    /// e.g. tokens sent with `ft_transfer` instead of `ft_transfer_call`.
+    token_balances: LookupMap<AccountId, Balance>,
+    /// Pools whose shares can be wrapped into a NEP-141 token deployed at `lp_token_id`.
+    lp_tokens: UnorderedSet<u64>,
+    /// Part of the swap fee paid to the referral of the swap (gets divided by REFERRAL_FEE_DIVISOR).
+    referral_fee: u32,
+    /// Open limit orders by id.
+    limit_orders: UnorderedMap<u64, LimitOrder>,
+    /// Id of the next limit order.
+    next_limit_order_id: u64,
+    /// Fees charged on fills of limit orders.
+    limit_order_fees: LimitOrderFees,
+    /// Fees of limit orders collected in each token for the owner.
+    limit_order_collected_fees: LookupMap<AccountId, Balance>,
+    /// TWAP oracles of the pools that have them enabled.
+    twap_oracles: LookupMap<u64, TwapOracle>,
+    /// Contracts approved by owner to receive flash loans.
+    flash_loan_receivers: UnorderedSet<AccountId>,
+    /// Fee of flash loans (gets divided by FEE_DIVISOR).
+    flash_loan_fee: u32,
+    /// Pools with a flash loan out.
+    flash_loan_pools: UnorderedSet<u64>,
+    /// Circuit breakers of the pools that have them set by owner.
+    circuit_breakers: LookupMap<u64, CircuitBreaker>,
+    /// Pending orders of the pools in batch auction mode.
+    batch_auctions: LookupMap<u64, BatchAuction>,
+    /// Open stop orders by id.
+    stop_orders: UnorderedMap<u64, StopOrder>,
+    /// Id of the next stop order.
+    next_stop_order_id: u64,
+    /// Bounty of newly placed stop orders (gets divided by FEE_DIVISOR).
+    stop_order_bounty: u32,
+    /// Whether anyone can create pools, or only owner and `pool_creators`.
+    permissionless_pool_creation: bool,
+    /// Accounts approved by owner to create pools while creation is allow-listed.
+    pool_creators: UnorderedSet<AccountId>,
+    /// Deposit taken on top of storage from newly created pools.
+    pool_creation_deposit: Balance,
+    /// Creator and deposit of the pools created with one, refunded when the pool is retired.
+    pool_creation_deposits: LookupMap<u64, (AccountId, Balance)>,
+    /// Accounts granted `Role::Guardian`.
+    guardians: UnorderedSet<AccountId>,
+    /// Pools paused by owner or guardians.
+    paused_pools: UnorderedSet<u64>,
+    /// Whether the exchange is running or paused as a whole.
+    running_state: RunningState,
+    /// Part of the swap fee taken by the protocol (gets divided by PROTOCOL_FEE_DIVISOR).
+    protocol_fee: u32,
+    /// Protocol fees accumulated by pool, in the order of its tokens.
+    protocol_fees: LookupMap<u64, Vec<Balance>>,
+    /// Account receiving protocol fees.
+    treasury_id: Option<AccountId>,
+    /// Delay in seconds between proposing and executing admin actions, or 0 if they are not timelocked.
+    timelock_delay: u64,
+    /// Proposed admin actions.
+    admin_actions: UnorderedMap<u64, PendingAdminAction>,
+    /// Id of the next admin action.
+    next_admin_action_id: u64,
+    /// DAO contract that calls admin methods instead of owner, if it's set.
+    dao_id: Option<AccountId>,
+    /// Version of the layout of this state, see `migrate`.
+    state_version: StateVersion,
+    /// Accounts granted `Role::FeeSetter`.
+    fee_setters: UnorderedSet<AccountId>,
+    /// Accounts granted `Role::PoolManager`.
+    pool_managers: UnorderedSet<AccountId>,
+    /// Shares of each pool that owners approved spenders to transfer, by pool, owner and spender.
+    share_allowances: LookupMap<(u64, AccountId, AccountId), Balance>,
+    /// Fees earned by liquidity providers per share of each pool, in the order of its tokens.
+    fee_growth: LookupMap<u64, Vec<u128>>,
+    /// Fees earned by each liquidity provider in each pool, as of the last change of its shares.
+    fee_checkpoints: LookupMap<(u64, AccountId), FeeCheckpoint>,
+    /// Locks of shares by pool and owner.
+    share_locks: LookupMap<(u64, AccountId), ShareLock>,
+    /// Tokens of liquidity positions by id.
+    position_nfts: UnorderedMap<u64, PositionNft>,
+    /// Ids of the tokens of liquidity positions each account owns.
+    position_nft_owners: LookupMap<AccountId, Vec<u64>>,
+    /// Id of the next token of a liquidity position.
+    next_position_nft_id: u64,
+    /// Auto-compounding vaults by pool.
+    vaults: LookupMap<u64, Vault>,
+    /// Vault shares of each pool held by each account.
+    vault_balances: LookupMap<(u64, AccountId), Balance>,
+    /// Farms by id.
+    farms: UnorderedMap<u64, Farm>,
+    /// Shares staked in each farm by each account.
+    farm_stakes: LookupMap<(u64, AccountId), Stake>,
+    /// Id of the next farm.
+    next_farm_id: u64,
+    /// Cumulative statistics of the swaps of each pool.
+    pool_stats: LookupMap<u64, PoolStats>,
+    /// Liquidity providers of each pool, listed when their shares change.
+    liquidity_providers: LookupMap<u64, UnorderedSet<AccountId>>,
+    /// Number of transfers out in flight for each account, whose failure returns the tokens to its deposits.
+    transfers_in_flight: LookupMap<AccountId, u32>,
+    /// Minimum reserves of the pools that have them set by owner, in the order of their tokens.
+    reserve_floors: LookupMap<u64, Vec<Balance>>,
+    /// Aurora engine receiving outputs of `swap_to_aurora`, if it's set.
+    aurora_id: Option<AccountId>,
+    /// Cached symbol and decimals of tokens, fetched when they're whitelisted.
+    token_metadata: LookupMap<AccountId, TokenMetadata>,
+    /// Number of open batch, limit and stop orders, farm stakes and vault balances of each account,
+    /// which credit its deposits once they close.
+    open_positions: LookupMap<AccountId, u32>,
 }
 
 #[near_bindgen]
 impl Contract {
     #[init]
-    pub fn new() -> Self {
-        assert!(!env::state_exists(), "ERR_CONTRACT_IS_INITIALIZED");
-        Self {
-            pools: Vector::new(b"p".to_vec()),
-            deposited_amounts: LookupMap::new(b"d".to_vec()),
-        }
+    pub fn new(owner_id: AccountId) -> Self {
+        require(!env::state_exists(), PoolError::ContractIsInitialized);
+        Self::internal_new(owner_id)
     }
 
-    /// Adds new "Simple Pool" with given tokens and given fee.
-    /// Attached NEAR should be enough to cover the added storage.
+    /// Proposes given account as the new owner, which takes over once it calls `accept_ownership`,
+    /// so ownership can't be lost to a mistyped account. None cancels the proposal. Only owner can call it.
     #[payable]
-    pub fn add_simple_pool(&mut self, tokens: Vec<ValidAccountId>, fee: u32) -> u32 {
-        check_token_duplicates(&tokens);
-        self.internal_add_pool(Pool::SimplePool(SimplePool::new(
-            self.pools.len() as u32,
-            tokens,
-            fee,
-        )))
+    pub fn propose_owner(&mut self, new_owner_id: Option<AccountId>) {
+        assert_one_yocto();
+        self.assert_owner();
+        self.pending_owner_id = new_owner_id;
     }
 
-    /// Swaps given amount_in of token_in into token_out via given pool.
-    /// Should be at least min_amount_out or swap will fail (prevents front running and other slippage issues).
-    pub fn internal_swap(
-        &mut self,
-        sender_id: &AccountId,
-        pool_id: u64,
-        token_in: ValidAccountId,
-        amount_in: U128,
-        token_out: ValidAccountId,
-        min_amount_out: U128,
-    ) -> U128 {
-        let prev_amount_in = self.internal_get_deposit(&sender_id, token_in.as_ref());
-        let prev_amount_out = self.internal_get_deposit(&sender_id, token_out.as_ref());
-        let amount_in: u128 = amount_in.into();
-        assert!(amount_in <= prev_amount_in, "ERR_NOT_ENOUGH_DEPOSIT");
-        let mut pool = self.pools.get(pool_id).expect("ERR_NO_POOL");
-        let amount_out = pool.swap(
-            token_in.as_ref(),
-            amount_in,
-            token_out.as_ref(),
-            min_amount_out.into(),
+    /// Makes the caller owner of the contract. Only the account proposed with `propose_owner` can call it.
+    #[payable]
+    pub fn accept_ownership(&mut self) {
+        assert_one_yocto();
+        let sender_id = env::predecessor_account_id();
+        require(
+            self.pending_owner_id.as_ref() == Some(&sender_id),
+            PoolError::NotPendingOwner,
         );
-        self.internal_deposit(&sender_id, token_in.as_ref(), prev_amount_in - amount_in);
-        self.internal_deposit(&sender_id, token_out.as_ref(), prev_amount_out + amount_out);
-        self.pools.replace(pool_id, &pool);
-        amount_out.into()
+        log!(
+            "Ownership transferred from {} to {}",
+            self.owner_id,
+            sender_id
+        );
+        self.owner_id = sender_id;
+        self.pending_owner_id = None;
     }
 
-    pub fn swap(&mut self, actions: Vec<SwapAction>) -> U128 {
-        let sender_id = env::predecessor_account_id();
-        let mut prev_amount = None;
-        for action in actions {
-            let amount_in = action
-                .amount_in
-                .unwrap_or_else(|| prev_amount.expect("ERR_FIRST_SWAP_MISSING_AMOUNT"));
-            prev_amount = Some(self.internal_swap(
-                &sender_id,
-                action.pool_id,
-                action.token_in,
-                amount_in,
-                action.token_out,
-                action.min_amount_out,
-            ));
-        }
-        prev_amount.unwrap()
+    /// Sets maximum number of tokens in newly created pools. Only owner and pool managers can call it.
+    #[payable]
+    pub fn set_max_num_tokens(&mut self, max_num_tokens: u32) {
+        assert_one_yocto();
+        self.assert_role(Role::PoolManager);
+        require(
+            max_num_tokens >= 2 && max_num_tokens <= MAX_NUM_TOKENS_LIMIT,
+            PoolError::WrongMaxNumTokens,
+        );
+        self.max_num_tokens = max_num_tokens;
     }
 
-    /// Add liquidity from already deposited amounts to given pool.
-    pub fn add_liquidity(&mut self, pool_id: u64, amounts: Vec<U128>) {
-        let sender_id = env::predecessor_account_id();
-        let amounts: Vec<u128> = amounts.into_iter().map(|amount| amount.into()).collect();
-        let mut pool = self.pools.get(pool_id).expect("ERR_NO_POOL");
-        let mut deposits = self.internal_get_deposits(&sender_id);
-        let tokens = pool.tokens();
-        for i in 0..tokens.len() {
-            let amount = *deposits
-                .get(&tokens[i])
-                .expect(&format!("ERR_MISSING_TOKEN:{}", tokens[i]));
-            assert!(
-                amounts[i] <= amount,
-                format!("ERR_NOT_ENOUGH_TOKEN:{}", tokens[i])
+    /// Adds given token to the whitelist, allowing to deposit it and add it to new pools, and fetches its metadata.
+    /// Only owner and pool managers can call it.
+    #[payable]
+    pub fn add_whitelisted_token(&mut self, token_id: AccountId) {
+        assert_one_yocto();
+        self.assert_role(Role::PoolManager);
+        self.assert_no_timelock();
+        self.internal_add_whitelisted_token(&token_id);
+    }
+
+    /// Removes given token from the whitelist. Only owner and pool managers can call it.
+    /// Existing pools and deposits of the token are not affected, but it can't be deposited or added to new pools anymore.
+    #[payable]
+    pub fn remove_whitelisted_token(&mut self, token_id: AccountId) {
+        assert_one_yocto();
+        self.assert_role(Role::PoolManager);
+        self.assert_no_timelock();
+        self.whitelisted_tokens.remove(&token_id);
+    }
+
+    /// Adds excess of the contract's balance in tokens of given pool to the pool, e.g. donations and airdrops
+    /// sent with plain `ft_transfer`. Only owner can call it.
+    /// Balance is read asynchronously, so deposits that arrive at the same time may not be recorded yet
+    /// and be counted as excess; it should be called when the tokens are not being deposited.
+    #[payable]
+    pub fn sync(&mut self, pool_id: u64) {
+        assert_one_yocto();
+        self.assert_owner();
+        for token_id in self.internal_get_pool(pool_id).tokens() {
+            self.internal_query_balance(token_id).then(
+                ext_self::ext(env::current_account_id())
+                    .with_static_gas(GAS_FOR_ON_BALANCE)
+                    .on_sync_balance(pool_id, token_id.clone()),
             );
-            if amounts[i] == amount {
-                deposits.remove(&tokens[i]);
-            } else {
-                deposits.insert(tokens[i].clone(), amount - amounts[i]);
-            }
         }
-        pool.add_liquidity(&sender_id, amounts);
-        self.deposited_amounts.insert(&sender_id, &deposits);
-        self.pools.replace(pool_id, &pool);
     }
 
-    /// Remove liquidity from the pool into general pool of liquidity.
-    pub fn remove_liquidity(&mut self, pool_id: u64, shares: U128, min_amounts: Vec<U128>) {
-        let sender_id = env::predecessor_account_id();
-        let mut pool = self.pools.get(pool_id).expect("ERR_NO_POOL");
-        let amounts = pool.remove_liquidity(
-            &sender_id,
-            shares.into(),
-            min_amounts
-                .into_iter()
-                .map(|amount| amount.into())
-                .collect(),
-        );
-        self.pools.replace(pool_id, &pool);
-        let tokens = pool.tokens();
-        let mut deposits = self.internal_get_deposits(&sender_id);
-        for i in 0..tokens.len() {
-            *deposits.entry(tokens[i].clone()).or_default() += amounts[i];
+    /// Callback from the token with the balance of the contract for `sync`.
+    #[private]
+    pub fn on_sync_balance(
+        &mut self,
+        pool_id: u64,
+        token_id: AccountId,
+        #[callback_unwrap] balance: U128,
+    ) {
+        let excess = balance
+            .0
+            .saturating_sub(self.token_balances.get(&token_id).unwrap_or_default());
+        if excess > 0 {
+            let mut pool = self.internal_get_pool(pool_id);
+            pool.donate(&token_id, excess);
+            self.internal_set_pool(pool_id, pool);
+            self.internal_add_token_balance(&token_id, excess);
+            log!("Synced {} of {} into pool {}", excess, token_id, pool_id);
         }
-        self.deposited_amounts.insert(&sender_id, &deposits);
     }
 
-    /// Withdraws given token from the deposits of given user.
+    /// Sends excess of the contract's balance in tokens of given pool to given receiver, e.g. a treasury.
+    /// Only owner can call it. Like `sync`, it should be called when the tokens are not being deposited.
     #[payable]
-    pub fn withdraw(&mut self, token_id: ValidAccountId, amount: U128) {
+    pub fn skim(&mut self, pool_id: u64, receiver_id: AccountId) {
         assert_one_yocto();
-        let amount: u128 = amount.into();
-        let sender_id = env::predecessor_account_id();
-        let mut deposits = self.deposited_amounts.get(&sender_id).unwrap();
-        let available_amount = deposits
-            .get(token_id.as_ref())
-            .expect("ERR_NO_TOKEN")
-            .clone();
-        assert!(available_amount >= amount, "ERR_NOT_ENOUGH");
-        if available_amount == amount {
-            deposits.remove(token_id.as_ref());
-        } else {
-            deposits.insert(token_id.as_ref().clone(), available_amount - amount);
+        self.assert_owner();
+        for token_id in self.internal_get_pool(pool_id).tokens() {
+            self.internal_query_balance(token_id).then(
+                ext_self::ext(env::current_account_id())
+                    .with_static_gas(GAS_FOR_SEND_EXCESS)
+                    .on_skim_balance(token_id.clone(), receiver_id.clone()),
+            );
         }
-        ext_fungible_token::ft_transfer(
-            sender_id.try_into().unwrap(),
-            amount.into(),
-            None,
-            token_id.as_ref(),
-            1,
-            GAS_FOR_FT_TRANSFER,
-        );
     }
-}
 
-/// Internal methods implementation.
-impl Contract {
-    /// Adds given pool to the list and returns it's id.
-    /// If there is not enough attached balance to cover storage, fails.
-    fn internal_add_pool(&mut self, pool: Pool) -> u32 {
-        let prev_storage = env::storage_usage();
-        let id = self.pools.len() as u32;
-        self.pools.push(&pool);
-        assert!(
-            (env::storage_usage() - prev_storage) as u128 * env::storage_byte_cost()
-                <= env::attached_deposit(),
-            "ERR_STORAGE_DEPOSIT"
+    /// Callback from the token with the balance of the contract for `skim`.
+    #[private]
+    pub fn on_skim_balance(
+        &mut self,
+        token_id: AccountId,
+        receiver_id: AccountId,
+        #[callback_unwrap] balance: U128,
+    ) {
+        let excess = balance
+            .0
+            .saturating_sub(self.token_balances.get(&token_id).unwrap_or_default());
+        if excess > 0 {
+            log!("Skimmed {} of {} to {}", excess, token_id, receiver_id);
+            self.internal_send_excess(&token_id, excess, receiver_id);
+        }
+    }
+
+    /// Sends given amount of token that was sent to the contract directly, and so isn't owned by any
+    /// pool or deposit, to given receiver. Only owner can call it.
+    /// Fails in the callback if the amount is more than the excess of the contract's balance.
+    #[payable]
+    pub fn rescue_token(&mut self, token_id: AccountId, amount: U128, receiver_id: AccountId) {
+        assert_one_yocto();
+        self.assert_owner();
+        self.internal_query_balance(&token_id).then(
+            ext_self::ext(env::current_account_id())
+                .with_static_gas(GAS_FOR_SEND_EXCESS)
+                .on_rescue_balance(token_id, amount, receiver_id),
         );
-        id
     }
 
-    /// Registers account in deposited amounts.
-    /// This should be used when it's known that storage is prepaid.
-    fn internal_register_account(&mut self, account_id: &AccountId) {
-        self.deposited_amounts
-            .insert(&account_id, &HashMap::default());
+    /// Callback from the token with the balance of the contract for `rescue_token`.
+    #[private]
+    pub fn on_rescue_balance(
+        &mut self,
+        token_id: AccountId,
+        amount: U128,
+        receiver_id: AccountId,
+        #[callback_unwrap] balance: U128,
+    ) {
+        let excess = balance
+            .0
+            .saturating_sub(self.token_balances.get(&token_id).unwrap_or_default());
+        require(amount.0 <= excess, PoolError::NotEnoughExcess);
+        log!("Rescued {} of {} to {}", amount.0, token_id, receiver_id);
+        self.internal_send_excess(&token_id, amount.into(), receiver_id);
     }
 
-    /// Record deposit of some number of tokens to this contract.
-    fn internal_deposit(&mut self, sender_id: &AccountId, token_id: &AccountId, amount: Balance) {
-        let mut amounts = self
-            .deposited_amounts
-            .get(sender_id)
-            .expect("ERR_NOT_REGISTERED");
-        assert!(amounts.len() <= 10, "ERR_TOO_MANY_TOKENS");
-        amounts.insert(token_id.clone(), amount);
-        self.deposited_amounts.insert(sender_id, &amounts);
+    /// Callback after sending out excess tokens. Releases the amount reserved while the transfer was in flight.
+    /// If the transfer failed, the tokens become excess again.
+    #[private]
+    pub fn on_excess_transfer(&mut self, token_id: AccountId, amount: U128) {
+        self.internal_sub_token_balance(&token_id, amount.into());
     }
 
-    /// Returns current balances across all tokens for given user.
-    fn internal_get_deposits(&self, sender_id: &AccountId) -> HashMap<AccountId, Balance> {
-        self.deposited_amounts
-            .get(sender_id)
-            .expect("ERR_NO_DEPOSIT")
-            .clone()
+    /// Sets part of the swap fee paid to referrals of swaps, e.g. 2000 for a fifth of it. Only owner and fee setters can call it.
+    #[payable]
+    pub fn set_referral_fee(&mut self, referral_fee: u32) {
+        assert_one_yocto();
+        self.assert_role(Role::FeeSetter);
+        self.assert_no_timelock();
+        self.internal_set_referral_fee(referral_fee);
     }
 
-    /// Returns current balance of given token for given user. If there is nothing recorded, returns 0.
-    fn internal_get_deposit(&self, sender_id: &AccountId, token_id: &AccountId) -> Balance {
-        self.internal_get_deposits(sender_id)
-            .get(token_id)
-            .cloned()
-            .unwrap_or_default()
+    /// Sets wrapped NEAR contract used by `swap_near`. Only owner can call it.
+    /// The contract should be registered with the wrapped NEAR contract.
+    #[payable]
+    pub fn set_wnear_id(&mut self, wnear_id: AccountId) {
+        assert_one_yocto();
+        self.assert_owner();
+        self.wnear_id = Some(wnear_id);
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use near_contract_standards::fungible_token::receiver::FungibleTokenReceiver;
-    use near_sdk::test_utils::{accounts, VMContextBuilder};
-    use near_sdk::{testing_env, MockedBlockchain};
+    /// Turns on measured deposits for given token that takes a cut on transfer. Only owner can call it.
+    /// Measurement starts from the current balance of the contract, once it's returned by the token.
+    #[payable]
+    pub fn add_measured_token(&mut self, token_id: AccountId) {
+        assert_one_yocto();
+        self.assert_owner();
+        ext_fungible_token::ext(token_id.clone())
+            .with_static_gas(GAS_FOR_FT_BALANCE_OF)
+            .ft_balance_of(env::current_account_id())
+            .then(
+                ext_self::ext(env::current_account_id())
+                    .with_static_gas(GAS_FOR_ON_MEASURED_BALANCE)
+                    .on_measured_token_balance(token_id),
+            );
+    }
 
-    use super::*;
+    /// Callback from the token with the initial balance of the contract for measured deposits.
+    #[private]
+    pub fn on_measured_token_balance(
+        &mut self,
+        token_id: AccountId,
+        #[callback_unwrap] balance: U128,
+    ) {
+        self.measured_balances.insert(&token_id, &balance.into());
+    }
 
-    #[test]
-    fn test_basics() {
-        let one_near = 10u128.pow(24);
-        let mut context = VMContextBuilder::new();
-        context.predecessor_account_id(accounts(0));
-        testing_env!(context.build());
-        let mut contract = Contract::new();
+    /// Turns off measured deposits for given token. Only owner can call it.
+    #[payable]
+    pub fn remove_measured_token(&mut self, token_id: AccountId) {
+        assert_one_yocto();
+        self.assert_owner();
+        self.measured_balances.remove(&token_id);
+    }
 
-        // create 1st pool (1, 2) with 0.3% fee.
-        testing_env!(context
-            .predecessor_account_id(accounts(3))
-            .attached_deposit(env::storage_byte_cost() * 300)
-            .build());
-        contract.add_simple_pool(vec![accounts(1), accounts(2)], 30);
+    /// Adds new "Simple Pool" with given tokens and given fee.
+    /// Attached NEAR should be enough to cover the added storage, the rest of it is refunded.
+    #[payable]
+    pub fn add_simple_pool(&mut self, tokens: Vec<AccountId>, fee: u32) -> u32 {
+        check_pool_tokens(&tokens);
+        self.internal_add_pool(Pool::SimplePool(SimplePool::new(
+            self.pools.len() as u32,
+            tokens,
+            fee,
+        )))
+    }
 
-        // add liquidity of (1,2) tokens and create 1st pool.
-        testing_env!(context
-            .predecessor_account_id(accounts(3))
-            .attached_deposit(contract.storage_balance_bounds().min.0)
-            .build());
-        contract.storage_deposit(None, None);
-        testing_env!(context
-            .predecessor_account_id(accounts(1))
-            .attached_deposit(1)
-            .build());
-        contract.ft_on_transfer(accounts(3), (105 * one_near).into(), "".to_string());
-        testing_env!(context.predecessor_account_id(accounts(2)).build());
-        contract.ft_on_transfer(accounts(3), (110 * one_near).into(), "".to_string());
-        testing_env!(context.predecessor_account_id(accounts(3)).build());
-        assert_eq!(
-            contract.get_deposit(accounts(3).as_ref(), accounts(1).as_ref()),
-            (105 * one_near).into()
-        );
-        assert_eq!(
-            contract.get_deposit(accounts(3).as_ref(), accounts(2).as_ref()),
-            (110 * one_near).into()
-        );
-        contract.add_liquidity(0, vec![U128(5 * one_near), U128(10 * one_near)]);
-        assert_eq!(
-            contract.get_pool_total_shares(0),
-            U128(1000000000000000000000000)
-        );
+    /// Adds new "Simple Pool" with given tokens and dynamic fee, that starts at given base `fee`
+    /// and grows up to `max_fee` when recent price movement exceeds `volatility_threshold` basis points.
+    /// Price movement stops affecting the fee after `decay_period` seconds.
+    /// Attached NEAR should be enough to cover the added storage, the rest of it is refunded.
+    #[payable]
+    pub fn add_dynamic_fee_pool(
+        &mut self,
+        tokens: Vec<AccountId>,
+        fee: u32,
+        max_fee: u32,
+        volatility_threshold: u32,
+        decay_period: u64,
+    ) -> u32 {
+        check_pool_tokens(&tokens);
+        let mut pool = SimplePool::new(self.pools.len() as u32, tokens, fee);
+        pool.dynamic_fee = Some(DynamicFee::new(
+            fee,
+            max_fee,
+            volatility_threshold,
+            decay_period,
+        ));
+        self.internal_add_pool(Pool::SimplePool(pool))
+    }
 
-        // Get price from pool #0 1 -> 2 tokens.
-        let amount_out = contract.get_return(0, accounts(1), one_near.into(), accounts(2));
-        assert_eq!(amount_out, 1662497915624478906119726.into());
+    /// Adds new "Stable Swap Pool" with given tokens, their decimals, amplification coefficient and given fee.
+    /// Should be used for tokens that are expected to trade close to 1:1 when adjusted for decimals.
+    /// Attached NEAR should be enough to cover the added storage, the rest of it is refunded.
+    #[payable]
+    pub fn add_stable_swap_pool(
+        &mut self,
+        tokens: Vec<AccountId>,
+        decimals: Vec<u8>,
+        fee: u32,
+        amp_factor: u64,
+    ) -> u32 {
+        let rate_providers = vec![None; tokens.len()];
+        self.add_rated_swap_pool(tokens, decimals, rate_providers, fee, amp_factor)
+    }
 
-        let amount_out = contract.swap(vec![SwapAction {
-            pool_id: 0,
-            token_in: accounts(1),
-            amount_in: Some(one_near.into()),
-            token_out: accounts(2),
-            min_amount_out: U128(1),
-        }]);
-        assert_eq!(amount_out, 1662497915624478906119726.into());
-        assert_eq!(
-            contract.get_deposit(accounts(3).as_ref(), accounts(1).as_ref()),
-            (99 * one_near).into()
-        );
-        assert_eq!(
-            contract.get_deposit(accounts(3).as_ref(), accounts(2).as_ref()),
-            (100 * one_near + amount_out.0).into()
-        );
+    /// Adds new "Stable Swap Pool" where some of the tokens are yield-bearing and trade at the exchange rate
+    /// to the underlying asset given by their rate provider (e.g. stNEAR / NEAR rate from the staking contract).
+    /// Swaps and liquidity changes, except removing liquidity in the pool ratio, fail until rates are fetched via
+    /// `update_token_rates`, and again once they are older than `MAX_RATE_AGE` (an hour).
+    /// Attached NEAR should be enough to cover the added storage, the rest of it is refunded.
+    #[payable]
+    pub fn add_rated_swap_pool(
+        &mut self,
+        tokens: Vec<AccountId>,
+        decimals: Vec<u8>,
+        rate_providers: Vec<Option<AccountId>>,
+        fee: u32,
+        amp_factor: u64,
+    ) -> u32 {
+        check_pool_tokens(&tokens);
+        self.internal_add_pool(Pool::StableSwapPool(StableSwapPool::new(
+            self.pools.len() as u32,
+            tokens,
+            decimals,
+            rate_providers,
+            amp_factor,
+            fee,
+        )))
+    }
 
-        testing_env!(context.predecessor_account_id(accounts(3)).build());
-        contract.remove_liquidity(
-            0,
-            contract.get_pool_shares(0, accounts(3)),
-            vec![1.into(), 2.into()],
+    /// Adds new "Metapool": stable swap pool between given token and shares of the given base stable swap pool,
+    /// e.g. FRAX against shares of the USDC / USDT / DAI pool. Base pool shares are priced at its virtual price.
+    /// Shares are moved between the base pool and the deposits via `deposit_shares` and `withdraw_shares`.
+    /// Attached NEAR should be enough to cover the added storage, the rest of it is refunded.
+    #[payable]
+    pub fn add_metapool(
+        &mut self,
+        token: AccountId,
+        decimals: u8,
+        base_pool_id: u64,
+        fee: u32,
+        amp_factor: u64,
+    ) -> u32 {
+        let virtual_price = match self.internal_get_pool(base_pool_id) {
+            Pool::StableSwapPool(base_pool) => {
+                require(base_pool.base_pool_id.is_none(), PoolError::NestedMetapool);
+                base_pool.virtual_price()
+            }
+            _ => PoolError::NotStableSwapPool.panic(),
+        };
+        let shares_token = shares_token_id(base_pool_id);
+        let tokens = vec![token, shares_token];
+        check_pool_tokens(&tokens);
+        let mut pool = StableSwapPool::new(
+            self.pools.len() as u32,
+            tokens,
+            vec![decimals, 24],
+            vec![None, None],
+            amp_factor,
+            fee,
         );
-        assert_eq!(contract.get_pool_total_shares(0), U128(0));
+        pool.base_pool_id = Some(base_pool_id);
+        pool.set_base_pool_virtual_price(virtual_price);
+        self.internal_add_pool(Pool::StableSwapPool(pool))
+    }
 
-        contract.withdraw(
-            accounts(1),
-            contract.get_deposit(accounts(3).as_ref(), accounts(1).as_ref()),
-        );
+    /// Requests fresh exchange rates from rate providers of all the tokens in the given stable swap pool.
+    /// Rates are cached in the pool when providers respond.
+    pub fn update_token_rates(&mut self, pool_id: u64) {
+        let pool = match self.internal_get_pool(pool_id) {
+            Pool::StableSwapPool(pool) => pool,
+            _ => PoolError::NotStableSwapPool.panic(),
+        };
+        for token_id in pool.tokens() {
+            if let Some(rate_provider) = pool.rate_provider(token_id) {
+                ext_rate_provider::ext(rate_provider.clone())
+                    .with_static_gas(GAS_FOR_GET_RATE)
+                    .get_rate()
+                    .then(
+                        ext_self::ext(env::current_account_id())
+                            .with_static_gas(GAS_FOR_ON_TOKEN_RATE)
+                            .on_token_rate(pool_id, token_id.clone()),
+                    );
+            }
+        }
     }
 
-    /// Should deny creating a pool with duplicate tokens.
-    #[test]
-    #[should_panic(expected = "ERR_TOKEN_DUPLICATES")]
-    fn test_deny_duplicate_tokens_pool() {
-        let mut context = VMContextBuilder::new();
-        testing_env!(context.build());
-        let mut contract = Contract::new();
-        testing_env!(context
-            .predecessor_account_id(accounts(3))
-            .attached_deposit(env::storage_byte_cost() * 300)
-            .build());
-        contract.add_simple_pool(vec![accounts(1), accounts(1)], 30);
+    /// Callback from the rate provider with the new exchange rate of given token.
+    #[private]
+    pub fn on_token_rate(
+        &mut self,
+        pool_id: u64,
+        token_id: AccountId,
+        #[callback_unwrap] rate: U128,
+    ) {
+        let mut pool = self.internal_get_pool(pool_id);
+        match &mut pool {
+            Pool::StableSwapPool(pool) => pool.set_rate(&token_id, rate.into()),
+            _ => PoolError::NotStableSwapPool.panic(),
+        };
+        self.internal_set_pool(pool_id, pool);
+    }
+
+    /// Adds new "Weighted Pool" with given tokens, their relative weights and given fee.
+    /// For example weights `[80, 20]` create 80/20 pool.
+    /// Attached NEAR should be enough to cover the added storage, the rest of it is refunded.
+    #[payable]
+    pub fn add_weighted_pool(
+        &mut self,
+        tokens: Vec<AccountId>,
+        weights: Vec<u32>,
+        fee: u32,
+    ) -> u32 {
+        check_pool_tokens(&tokens);
+        self.internal_add_pool(Pool::WeightedPool(WeightedPool::new(
+            self.pools.len() as u32,
+            tokens,
+            weights,
+            fee,
+        )))
+    }
+
+    /// Adds new "Liquidity Bootstrapping Pool": weighted pool, which weights move linearly
+    /// from `start_weights` to `end_weights` between `start_time` and `end_time` (timestamps in seconds).
+    /// For example weights going from `[95, 5]` to `[50, 50]` over 72 hours let the price of the first token
+    /// fall gradually, for a fair launch without large initial holdings of the second token.
+    /// Attached NEAR should be enough to cover the added storage, the rest of it is refunded.
+    #[payable]
+    pub fn add_liquidity_bootstrapping_pool(
+        &mut self,
+        tokens: Vec<AccountId>,
+        start_weights: Vec<u32>,
+        end_weights: Vec<u32>,
+        start_time: u64,
+        end_time: u64,
+        fee: u32,
+    ) -> u32 {
+        check_pool_tokens(&tokens);
+        let num_tokens = tokens.len();
+        let mut pool = WeightedPool::new(self.pools.len() as u32, tokens, start_weights, fee);
+        pool.weight_schedule = Some(WeightSchedule::new(
+            num_tokens,
+            end_weights,
+            start_time,
+            end_time,
+        ));
+        self.internal_add_pool(Pool::WeightedPool(pool))
+    }
+
+    /// Adds new "Constant Sum Pool" with given tokens and given fee, where all tokens trade 1:1.
+    /// Should be used for tokens representing the same asset, e.g. two bridged versions of it.
+    /// Swaps and withdrawals fail once any token would drop below `min_reserve_bps` basis points of the pool's total balance.
+    /// Attached NEAR should be enough to cover the added storage, the rest of it is refunded.
+    #[payable]
+    pub fn add_constant_sum_pool(
+        &mut self,
+        tokens: Vec<AccountId>,
+        fee: u32,
+        min_reserve_bps: u32,
+    ) -> u32 {
+        check_pool_tokens(&tokens);
+        self.internal_add_pool(Pool::ConstantSumPool(ConstantSumPool::new(
+            self.pools.len() as u32,
+            tokens,
+            fee,
+            min_reserve_bps,
+        )))
+    }
+
+    /// Adds new "Concentrated Pool" with given two tokens and given fee,
+    /// where liquidity is provided in price ranges that are multiples of `tick_spacing` ticks.
+    /// Initial price of the first token in the second one is `1.0001 ^ initial_tick`.
+    /// Attached NEAR should be enough to cover the added storage, the rest of it is refunded.
+    #[payable]
+    pub fn add_concentrated_pool(
+        &mut self,
+        tokens: Vec<AccountId>,
+        fee: u32,
+        tick_spacing: u32,
+        initial_tick: i32,
+    ) -> u32 {
+        check_pool_tokens(&tokens);
+        self.internal_add_pool(Pool::ConcentratedPool(ConcentratedPool::new(
+            self.pools.len() as u32,
+            tokens,
+            fee,
+            tick_spacing,
+            initial_tick,
+        )))
+    }
+
+    /// Adds new "Bonding Curve Pool" that sells `supply` of `sale_token`, taken from the caller's deposits, for `reserve_token`
+    /// at price growing linearly with the amount sold: `initial_price + slope * sold / 10^24`, with 10^24 meaning 1:1.
+    /// Buyers can sell back along the same curve. Once the reserve reaches `graduation_reserve` the pool stops trading,
+    /// and the caller can move its liquidity into a regular pool via `graduate_bonding_curve_pool`.
+    /// Attached NEAR should be enough to cover the added storage, the rest of it is refunded.
+    #[payable]
+    #[allow(clippy::too_many_arguments)]
+    pub fn add_bonding_curve_pool(
+        &mut self,
+        sale_token: AccountId,
+        reserve_token: AccountId,
+        supply: U128,
+        initial_price: U128,
+        slope: U128,
+        graduation_reserve: U128,
+        fee: u32,
+    ) -> u32 {
+        let sender_id = env::predecessor_account_id();
+        let tokens = vec![sale_token, reserve_token];
+        check_pool_tokens(&tokens);
+        self.internal_withdraw_tokens(&sender_id, &[tokens[0].clone()], &[supply.into()]);
+        self.internal_add_pool(Pool::BondingCurvePool(BondingCurvePool::new(
+            tokens,
+            sender_id,
+            supply.into(),
+            initial_price.into(),
+            slope.into(),
+            graduation_reserve.into(),
+            fee,
+        )))
+    }
+
+    /// Moves liquidity of the bonding curve pool that reached its graduation reserve into a new "Simple Pool" with given fee:
+    /// the whole reserve and the sale token matching it at the final price of the curve.
+    /// Shares of the new pool stay locked in this contract, tokens left over go to the creator's deposits.
+    /// Only creator of the bonding curve pool can call it, which doesn't need to be allowed to create pools,
+    /// and pays no pool creation deposit. Returns id of the new pool.
+    /// Attached NEAR should be enough to cover the added storage, the rest of it is refunded.
+    #[payable]
+    pub fn graduate_bonding_curve_pool(&mut self, pool_id: u64, fee: u32) -> u32 {
+        let sender_id = env::predecessor_account_id();
+        self.assert_pool_running(pool_id);
+        self.assert_no_flash_loan(pool_id);
+        let mut pool = self.internal_get_pool(pool_id);
+        let new_pool_id = self.pools.len();
+        let (mut liquidity, leftover) = match &mut pool {
+            Pool::BondingCurvePool(pool) => {
+                require(pool.creator_id == sender_id, PoolError::NotOwner);
+                pool.graduate(new_pool_id)
+            }
+            _ => PoolError::NotBondingCurvePool.panic(),
+        };
+        let tokens = pool.tokens().to_vec();
+        self.internal_set_pool(pool_id, pool);
+        let prev_storage = env::storage_usage();
+        self.internal_insert_pool(Pool::SimplePool(SimplePool::new(
+            new_pool_id as u32,
+            tokens.clone(),
+            fee,
+        )));
+        self.internal_charge_attached_deposit(prev_storage, 0);
+        let mut new_pool = self.internal_get_pool(new_pool_id);
+        self.internal_settle_lp_fees(new_pool_id, &new_pool, &env::current_account_id());
+        new_pool.add_liquidity(&env::current_account_id(), &mut liquidity);
+        self.internal_set_pool(new_pool_id, new_pool);
+        self.internal_deposit_tokens(&sender_id, &tokens, &leftover);
+        new_pool_id as u32
+    }
+
+    /// Retires the pool while it holds no tokens and no liquidity. Pools that had a deposit keep the minimum liquidity
+    /// locked by it, along with its tokens, so they stay. The pool is replaced by a tombstone that
+    /// keeps only its tokens and fee, shown by views with "RETIRED" kind, and its TWAP oracle, circuit breaker,
+    /// reserve floor, fee tier and token indexes, LP fee growth, swap statistics and provider list are removed, so the same
+    /// fee tier can be added again. Pool ids are never reused. Storage of shares and positions is already freed when
+    /// they are burnt. The deposit taken when the pool was created is refunded to its creator along with the storage
+    /// freed by retiring the pool. Only owner and pool managers can call it.
+    #[payable]
+    pub fn retire_pool(&mut self, pool_id: u64) {
+        assert_one_yocto();
+        self.assert_role(Role::PoolManager);
+        let pool: Pool = self
+            .pools
+            .get(pool_id)
+            .unwrap_or_panic(PoolError::NoPool)
+            .into();
+        require(
+            !matches!(pool, Pool::RetiredPool(_)),
+            PoolError::PoolRetired,
+        );
+        require(pool.is_empty(), PoolError::PoolNotEmpty);
+        self.assert_no_flash_loan(pool_id);
+        require(
+            !self.batch_auctions.contains_key(&pool_id),
+            PoolError::PoolInBatchAuction,
+        );
+        let prev_storage = env::storage_usage();
+        let tokens_key = tokens_key(pool.tokens());
+        let mut fee_tiers = self.fee_tiers.get(&tokens_key).unwrap_or_default();
+        fee_tiers.retain(|id| *id != pool_id);
+        if fee_tiers.is_empty() {
+            self.fee_tiers.remove(&tokens_key);
+        } else {
+            self.fee_tiers.insert(&tokens_key, &fee_tiers);
+        }
+        for pair in token_pairs(pool.tokens()) {
+            let mut pool_ids = self.token_pair_pools.get(&pair).unwrap_or_default();
+            pool_ids.retain(|id| *id != pool_id);
+            if pool_ids.is_empty() {
+                self.token_pair_pools.remove(&pair);
+            } else {
+                self.token_pair_pools.insert(&pair, &pool_ids);
+            }
+        }
+        for token_id in pool.tokens() {
+            let mut pool_ids = self.token_pools.get(token_id).unwrap_or_default();
+            pool_ids.retain(|id| *id != pool_id);
+            if pool_ids.is_empty() {
+                self.token_pools.remove(token_id);
+            } else {
+                self.token_pools.insert(token_id, &pool_ids);
+            }
+        }
+        self.twap_oracles.remove(&pool_id);
+        self.circuit_breakers.remove(&pool_id);
+        self.reserve_floors.remove(&pool_id);
+        self.fee_growth.remove(&pool_id);
+        self.pool_stats.remove(&pool_id);
+        if let Some(mut providers) = self.liquidity_providers.remove(&pool_id) {
+            for account_id in providers.to_vec() {
+                self.fee_checkpoints.remove(&(pool_id, account_id));
+            }
+            providers.clear();
+        }
+        self.internal_set_pool(
+            pool_id,
+            Pool::RetiredPool(RetiredPool {
+                token_account_ids: pool.tokens().to_vec(),
+                fee: pool.fee(),
+            }),
+        );
+        self.internal_refund_pool_creation(pool_id, prev_storage);
+        log!("Pool {} retired", pool_id);
+    }
+
+    /// Executes given swap actions from the deposits of the caller. Returns amount of token out of the last action.
+    /// Swaps don't transfer tokens: the output is credited to the deposits, and only leaves the contract through
+    /// `withdraw`, which returns it to the deposits if the transfer fails.
+    /// If `referral_id` is given, it receives `referral_fee` part of the swap fees into its deposits.
+    pub fn swap(&mut self, actions: Vec<SwapAction>, referral_id: Option<AccountId>) -> U128 {
+        self.assert_running();
+        let sender_id = env::predecessor_account_id();
+        self.internal_execute_actions(&sender_id, actions, referral_id.as_ref())
+    }
+
+    /// Executes given independent swaps from the deposits of the caller, each with its own `amount_in`.
+    /// Returns amount of token out of each swap. If any swap fails, the whole call fails and none of them is applied.
+    pub fn batch_swap(
+        &mut self,
+        swaps: Vec<SwapAction>,
+        referral_id: Option<AccountId>,
+    ) -> Vec<U128> {
+        self.assert_running();
+        let sender_id = env::predecessor_account_id();
+        let referral_id = referral_id.as_ref();
+        swaps
+            .into_iter()
+            .map(|swap| self.internal_execute_actions(&sender_id, vec![swap], referral_id))
+            .collect()
+    }
+
+    /// Executes given swap actions from the deposits of the caller like `swap`, and sends the output of the last action
+    /// to `receiver_id` with `ft_transfer_call` and given `msg`, e.g. to deposit it into a lending protocol
+    /// in the same transaction. The receiver sees this contract as the sender, so `msg` must tell it whom to credit.
+    /// The part of the output the receiver doesn't use is returned to the deposits of the caller.
+    /// Requires attached 1 yoctoNEAR.
+    #[payable]
+    pub fn swap_and_call(
+        &mut self,
+        actions: Vec<SwapAction>,
+        referral_id: Option<AccountId>,
+        receiver_id: AccountId,
+        msg: String,
+    ) -> Promise {
+        assert_one_yocto();
+        self.assert_running();
+        let sender_id = env::predecessor_account_id();
+        self.internal_swap_and_call(&sender_id, actions, referral_id.as_ref(), receiver_id, msg)
+    }
+
+    /// Callback after `ft_transfer_call` of `swap_and_call`. Returns the part of the amount the receiver didn't use
+    /// to the deposits of the sender, all of it if the transfer failed. Returns the amount the receiver used.
+    #[private]
+    pub fn on_transfer_call(
+        &mut self,
+        token_id: AccountId,
+        sender_id: AccountId,
+        amount: U128,
+    ) -> U128 {
+        require(env::promise_results_count() == 1, PoolError::Invalid);
+        self.internal_finish_transfer(&sender_id);
+        let used_amount = match env::promise_result(0) {
+            // Tokens have left the contract unless the token says otherwise.
+            PromiseResult::Successful(value) => serde_json::from_slice::<U128>(&value)
+                .map(|used_amount| std::cmp::min(amount.0, used_amount.0))
+                .unwrap_or(amount.0),
+            _ => 0,
+        };
+        self.internal_sub_token_balance(&token_id, used_amount);
+        let unused_amount = amount.0 - used_amount;
+        if unused_amount > 0 {
+            log!(
+                "Returning {} {} not used by the receiver to the deposits of {}",
+                unused_amount,
+                token_id,
+                sender_id
+            );
+            let mut deposits = self.deposited_amounts.get(&sender_id).unwrap_or_default();
+            deposits.refund(&token_id, unused_amount);
+            self.deposited_amounts.insert(&sender_id, &deposits);
+            if let Some(balance) = self.measured_balances.get(&token_id) {
+                self.measured_balances
+                    .insert(&token_id, &(balance + unused_amount));
+            }
+        }
+        used_amount.into()
+    }
+
+    /// Swaps `amount_in` from the deposits of the caller across given pools, in given ratios or split for the best
+    /// total output, so large swaps move the price of each pool less. Only pools quoted by `get_best_route`
+    /// get part of the amount when splitting for the best output. Returns total amount of token out.
+    pub fn split_swap(&mut self, action: SplitSwapAction, referral_id: Option<AccountId>) -> U128 {
+        self.assert_running();
+        let sender_id = env::predecessor_account_id();
+        let token_in: AccountId = action.token_in.clone();
+        let token_out: AccountId = action.token_out.clone();
+        let referral_id = referral_id
+            .as_ref()
+            .filter(|referral_id| *referral_id != &sender_id);
+        let amounts = self.internal_split_amount(&action);
+        self.internal_withdraw_tokens(&sender_id, &[token_in.clone()], &[action.amount_in.into()]);
+        let mut amount_out = 0;
+        for (pool_id, amount_in) in action.pool_ids.iter().zip(amounts) {
+            if amount_in > 0 {
+                amount_out += self.internal_swap(
+                    &sender_id,
+                    *pool_id,
+                    &token_in,
+                    amount_in,
+                    &token_out,
+                    0,
+                    referral_id,
+                );
+            }
+        }
+        require(amount_out >= action.min_amount_out.0, PoolError::MinAmount);
+        self.internal_deposit_tokens(&sender_id, &[token_out], &[amount_out]);
+        amount_out.into()
+    }
+
+    /// Swaps native NEAR along given actions and sends the output to the caller, who must be registered.
+    /// Attached NEAR is wrapped first and then credited as wrapped NEAR, so the first action must swap from it.
+    /// If wrapping fails the NEAR is refunded, and if the swaps fail the wrapped NEAR stays in the deposits.
+    /// With exactly 1 yoctoNEAR attached the swaps run from the deposits right away.
+    /// So either way the call needs a full access key.
+    /// Output in wrapped NEAR is unwrapped and sent as native NEAR, any other token is sent like in `withdraw`.
+    #[payable]
+    pub fn swap_near(
+        &mut self,
+        actions: Vec<SwapAction>,
+        referral_id: Option<AccountId>,
+    ) -> Promise {
+        self.assert_running();
+        let sender_id = env::predecessor_account_id();
+        let wnear_id = self
+            .wnear_id
+            .clone()
+            .unwrap_or_panic(PoolError::NoWrappedNear);
+        let amount = env::attached_deposit();
+        if amount > 1 {
+            require(
+                actions.first().map(|action| &action.token_in) == Some(&wnear_id),
+                PoolError::NotWrappedNear,
+            );
+            self.internal_get_account(&sender_id);
+            self.internal_start_transfer(&sender_id);
+            return ext_wrap_near::ext(wnear_id.clone())
+                .with_attached_deposit(amount)
+                .with_static_gas(GAS_FOR_NEAR_DEPOSIT)
+                .near_deposit()
+                .then(
+                    ext_self::ext(env::current_account_id())
+                        .with_static_gas(env::prepaid_gas() - GAS_FOR_SWAP_NEAR)
+                        .on_near_deposit(sender_id, amount.into(), actions, referral_id),
+                );
+        }
+        assert_one_yocto();
+        self.internal_swap_near(&sender_id, actions, referral_id.as_ref())
+    }
+
+    /// Callback after wrapping NEAR attached to `swap_near`. Credits the wrapped NEAR to the deposits and
+    /// swaps it in a separate call, so it stays in the deposits if the swaps fail. Refunds the NEAR if wrapping failed.
+    #[private]
+    pub fn on_near_deposit(
+        &mut self,
+        sender_id: AccountId,
+        amount: U128,
+        actions: Vec<SwapAction>,
+        referral_id: Option<AccountId>,
+    ) -> Promise {
+        require(env::promise_results_count() == 1, PoolError::Invalid);
+        self.internal_finish_transfer(&sender_id);
+        if !matches!(env::promise_result(0), PromiseResult::Successful(_)) {
+            log!(
+                "Wrapping {} NEAR for {} failed, refunding it",
+                amount.0,
+                sender_id
+            );
+            return Promise::new(sender_id).transfer(amount.into());
+        }
+        let wnear_id = self
+            .wnear_id
+            .clone()
+            .unwrap_or_panic(PoolError::NoWrappedNear);
+        let mut deposits = self.internal_get_account(&sender_id);
+        deposits.refund(&wnear_id, amount.into());
+        self.deposited_amounts.insert(&sender_id, &deposits);
+        self.internal_add_token_balance(&wnear_id, amount.into());
+        ext_self::ext(env::current_account_id())
+            .with_static_gas(env::prepaid_gas() - GAS_FOR_ON_NEAR_DEPOSIT)
+            .swap_near_from_deposits(sender_id, actions, referral_id)
+    }
+
+    /// Swaps wrapped NEAR credited by `on_near_deposit` like `swap_near` without attached NEAR.
+    #[private]
+    pub fn swap_near_from_deposits(
+        &mut self,
+        sender_id: AccountId,
+        actions: Vec<SwapAction>,
+        referral_id: Option<AccountId>,
+    ) -> Promise {
+        self.assert_running();
+        self.internal_swap_near(&sender_id, actions, referral_id.as_ref())
+    }
+
+    /// Callback after unwrapping NEAR for `swap_near`. Sends the NEAR to the user,
+    /// or returns wrapped NEAR to the deposits if unwrapping failed.
+    #[private]
+    pub fn on_near_withdraw(&mut self, sender_id: AccountId, amount: U128) {
+        require(env::promise_results_count() == 1, PoolError::Invalid);
+        self.internal_finish_transfer(&sender_id);
+        let wnear_id = self
+            .wnear_id
+            .clone()
+            .unwrap_or_panic(PoolError::NoWrappedNear);
+        if let PromiseResult::Successful(_) = env::promise_result(0) {
+            self.internal_sub_token_balance(&wnear_id, amount.into());
+            Promise::new(sender_id).transfer(amount.into());
+            return;
+        }
+        log!(
+            "Unwrapping {} NEAR for {} failed, returning it to the deposits",
+            amount.0,
+            sender_id
+        );
+        let mut deposits = self.deposited_amounts.get(&sender_id).unwrap_or_default();
+        deposits.refund(&wnear_id, amount.into());
+        self.deposited_amounts.insert(&sender_id, &deposits);
+    }
+
+    /// Swaps token_in from the deposits for exactly amount_out of token_out via given pool.
+    /// Fails if it needs more than max_amount_in of token_in, unless `allow_partial` is set: then the whole
+    /// max_amount_in is swapped for as much token_out as it gets, and the unfilled rest of amount_out is logged.
+    /// Returns charged amount of token_in.
+    pub fn swap_for_exact(
+        &mut self,
+        pool_id: u64,
+        token_in: AccountId,
+        max_amount_in: U128,
+        token_out: AccountId,
+        amount_out: U128,
+        allow_partial: Option<bool>,
+    ) -> U128 {
+        let sender_id = env::predecessor_account_id();
+        self.assert_pool_running(pool_id);
+        self.assert_no_flash_loan(pool_id);
+        self.assert_no_batch_auction(pool_id);
+        let mut pool = self.internal_get_pool(pool_id);
+        self.internal_update_twap(pool_id, &pool);
+        self.internal_update_circuit_breaker(pool_id, &pool);
+        let fee = pool.current_fee();
+        let partial = allow_partial.unwrap_or(false)
+            && pool.get_return(&token_in, max_amount_in.into(), &token_out) < amount_out.0;
+        let (amount_in, filled_amount_out) = if partial {
+            let filled_amount_out = pool.swap(&token_in, max_amount_in.into(), &token_out, 0);
+            log!(
+                "Swap for {} {} partially filled, {} left",
+                amount_out.0,
+                token_out.as_ref(),
+                amount_out.0 - filled_amount_out
+            );
+            (max_amount_in.into(), filled_amount_out)
+        } else {
+            let amount_in = pool.swap_for_exact(
+                &token_in,
+                max_amount_in.into(),
+                &token_out,
+                amount_out.into(),
+            );
+            (amount_in, amount_out.into())
+        };
+        require(
+            self.internal_circuit_breaker_allows(pool_id, &pool),
+            PoolError::PriceMoveTooLarge,
+        );
+        require(
+            self.internal_reserve_floor_allows(pool_id, &pool, &token_out),
+            PoolError::ReserveBelowFloor,
+        );
+        let fee_taken =
+            self.internal_collect_protocol_fee(pool_id, &mut pool, &token_in, amount_in, fee);
+        self.internal_accrue_lp_fees(pool_id, &pool, &token_in, amount_in, fee, fee_taken);
+        self.internal_record_swap(pool_id, &pool, &token_in, amount_in, fee);
+        self.internal_withdraw_tokens(&sender_id, &[token_in.clone()], &[amount_in]);
+        self.internal_deposit_tokens(&sender_id, &[token_out.clone()], &[filled_amount_out]);
+        self.internal_set_pool(pool_id, pool);
+        emit_swap(
+            &sender_id,
+            pool_id,
+            &token_in,
+            amount_in,
+            &token_out,
+            filled_amount_out,
+        );
+        U128(amount_in)
+    }
+
+    /// Add liquidity from already deposited amounts to given pool.
+    /// Only amounts in the current ratio of the pool are used, the rest stays in the deposits.
+    /// Fails if it would mint less than `min_shares`, e.g. because the ratio was moved right before.
+    /// Returns number of minted shares.
+    pub fn add_liquidity(&mut self, pool_id: u64, amounts: Vec<U128>, min_shares: U128) -> U128 {
+        let sender_id = env::predecessor_account_id();
+        self.assert_pool_running(pool_id);
+        self.assert_no_flash_loan(pool_id);
+        let mut amounts: Vec<u128> = amounts.into_iter().map(|amount| amount.into()).collect();
+        let mut pool = self.internal_get_pool(pool_id);
+        let prev_storage = env::storage_usage();
+        self.internal_settle_lp_fees(pool_id, &pool, &sender_id);
+        let shares = pool.add_liquidity(&sender_id, &mut amounts);
+        require(shares >= min_shares.0, PoolError::MinShares);
+        let tokens = pool.tokens().to_vec();
+        self.internal_set_pool(pool_id, pool);
+        self.internal_update_storage(&sender_id, prev_storage);
+        self.internal_withdraw_tokens(&sender_id, &tokens, &amounts);
+        emit_add_liquidity(&sender_id, pool_id, &tokens, &amounts, shares);
+        shares.into()
+    }
+
+    /// Add liquidity in arbitrary proportion from already deposited amounts to given pool.
+    /// Part of the deposit above the pool ratio is charged swap fee, as if it was swapped into the other tokens.
+    /// Fails if it would mint less than `min_shares`. Returns number of minted shares.
+    pub fn add_liquidity_imbalanced(
+        &mut self,
+        pool_id: u64,
+        amounts: Vec<U128>,
+        min_shares: U128,
+    ) -> U128 {
+        let sender_id = env::predecessor_account_id();
+        self.assert_pool_running(pool_id);
+        self.assert_no_flash_loan(pool_id);
+        let amounts: Vec<u128> = amounts.into_iter().map(|amount| amount.into()).collect();
+        let mut pool = self.internal_get_pool(pool_id);
+        self.internal_withdraw_tokens(&sender_id, pool.tokens(), &amounts);
+        self.internal_update_circuit_breaker(pool_id, &pool);
+        let prev_storage = env::storage_usage();
+        self.internal_settle_lp_fees(pool_id, &pool, &sender_id);
+        let tokens = pool.tokens().to_vec();
+        let shares = pool.add_liquidity_imbalanced(&sender_id, amounts.clone());
+        require(shares >= min_shares.0, PoolError::MinShares);
+        require(
+            self.internal_circuit_breaker_allows(pool_id, &pool),
+            PoolError::PriceMoveTooLarge,
+        );
+        self.internal_set_pool(pool_id, pool);
+        self.internal_update_storage(&sender_id, prev_storage);
+        emit_add_liquidity(&sender_id, pool_id, &tokens, &amounts, shares);
+        shares.into()
+    }
+
+    /// Remove liquidity from the pool into general pool of liquidity.
+    pub fn remove_liquidity(&mut self, pool_id: u64, shares: U128, min_amounts: Vec<U128>) {
+        let sender_id = env::predecessor_account_id();
+        self.internal_remove_liquidity(&sender_id, pool_id, shares, min_amounts);
+    }
+
+    /// Remove liquidity from the pool and send all received tokens to the caller.
+    /// Each token is transferred like in `withdraw`, so any token whose transfer fails is returned to the deposits.
+    #[payable]
+    pub fn remove_liquidity_and_withdraw(
+        &mut self,
+        pool_id: u64,
+        shares: U128,
+        min_amounts: Vec<U128>,
+    ) {
+        assert_one_yocto();
+        let sender_id = env::predecessor_account_id();
+        let (tokens, amounts) =
+            self.internal_remove_liquidity(&sender_id, pool_id, shares, min_amounts);
+        for (token_id, amount) in tokens.iter().zip(amounts) {
+            if amount > 0 {
+                self.internal_send_tokens(&sender_id, token_id, amount);
+            }
+        }
+    }
+
+    /// Remove exactly given amounts of tokens from the pool into general pool of liquidity,
+    /// burning as many shares as needed, but not more than `max_burn_shares`.
+    /// Part of the withdrawal above the pool ratio is charged swap fee. Returns number of burnt shares.
+    pub fn remove_liquidity_by_tokens(
+        &mut self,
+        pool_id: u64,
+        amounts: Vec<U128>,
+        max_burn_shares: U128,
+    ) -> U128 {
+        let sender_id = env::predecessor_account_id();
+        self.assert_no_flash_loan(pool_id);
+        let amounts: Vec<u128> = amounts.into_iter().map(|amount| amount.into()).collect();
+        let mut pool = self.internal_get_pool(pool_id);
+        self.internal_update_circuit_breaker(pool_id, &pool);
+        let prev_storage = env::storage_usage();
+        self.internal_settle_lp_fees(pool_id, &pool, &sender_id);
+        let burnt_shares =
+            pool.remove_liquidity_by_tokens(&sender_id, amounts.clone(), max_burn_shares.into());
+        require(
+            self.internal_circuit_breaker_allows(pool_id, &pool),
+            PoolError::PriceMoveTooLarge,
+        );
+        self.internal_prune_lp_fees(pool_id, &pool, &sender_id);
+        self.assert_shares_unlocked(pool_id, &pool, &sender_id);
+        self.assert_reserve_floor(pool_id, &pool, &amounts);
+        let tokens = pool.tokens().to_vec();
+        self.internal_set_pool(pool_id, pool);
+        self.internal_update_storage(&sender_id, prev_storage);
+        self.internal_deposit_tokens(&sender_id, &tokens, &amounts);
+        emit_remove_liquidity(&sender_id, pool_id, &tokens, &amounts, burnt_shares);
+        burnt_shares.into()
+    }
+
+    /// Remove liquidity from the pool into general pool of liquidity in a single token.
+    /// Part of the amount that is converted from the other tokens is charged swap fee.
+    /// Returns received amount of token_out.
+    pub fn remove_liquidity_one_token(
+        &mut self,
+        pool_id: u64,
+        shares: U128,
+        token_out: AccountId,
+        min_amount_out: U128,
+    ) -> U128 {
+        let sender_id = env::predecessor_account_id();
+        self.assert_no_flash_loan(pool_id);
+        let mut pool = self.internal_get_pool(pool_id);
+        self.internal_update_circuit_breaker(pool_id, &pool);
+        let prev_storage = env::storage_usage();
+        self.internal_settle_lp_fees(pool_id, &pool, &sender_id);
+        let amount_out = pool.remove_liquidity_one_token(
+            &sender_id,
+            shares.into(),
+            &token_out,
+            min_amount_out.into(),
+        );
+        require(
+            self.internal_circuit_breaker_allows(pool_id, &pool),
+            PoolError::PriceMoveTooLarge,
+        );
+        self.internal_prune_lp_fees(pool_id, &pool, &sender_id);
+        self.assert_shares_unlocked(pool_id, &pool, &sender_id);
+        require(
+            self.internal_reserve_floor_allows(pool_id, &pool, &token_out),
+            PoolError::ReserveBelowFloor,
+        );
+        self.internal_set_pool(pool_id, pool);
+        self.internal_update_storage(&sender_id, prev_storage);
+        self.internal_deposit_tokens(&sender_id, &[token_out.clone()], &[amount_out]);
+        emit_remove_liquidity(
+            &sender_id,
+            pool_id,
+            &[token_out],
+            &[amount_out],
+            shares.into(),
+        );
+        amount_out.into()
+    }
+
+    /// Moves liquidity to another pool of the same tokens, e.g. a different fee tier, in one call: burns `shares`
+    /// in `from_pool_id` (failing if it returns less than `min_amounts`, in its token order) and adds the received
+    /// amounts to `to_pool_id` in its current ratio, failing if it mints less than `min_shares`. The part the target
+    /// pool doesn't take stays in the deposits, so the tokens must be registered. Returns number of shares minted
+    /// in the target pool.
+    pub fn migrate_liquidity(
+        &mut self,
+        from_pool_id: u64,
+        to_pool_id: u64,
+        shares: U128,
+        min_amounts: Vec<U128>,
+        min_shares: U128,
+    ) -> U128 {
+        let sender_id = env::predecessor_account_id();
+        self.assert_pool_running(to_pool_id);
+        self.assert_no_flash_loan(to_pool_id);
+        let (tokens, amounts) =
+            self.internal_remove_liquidity(&sender_id, from_pool_id, shares, min_amounts);
+        let mut pool = self.internal_get_pool(to_pool_id);
+        require(
+            pool.tokens().len() == tokens.len(),
+            PoolError::IncompatiblePools,
+        );
+        let mut amounts: Vec<Balance> = pool
+            .tokens()
+            .iter()
+            .map(|token_id| {
+                let index = tokens
+                    .iter()
+                    .position(|id| id == token_id)
+                    .unwrap_or_panic(PoolError::IncompatiblePools);
+                amounts[index]
+            })
+            .collect();
+        let prev_storage = env::storage_usage();
+        self.internal_settle_lp_fees(to_pool_id, &pool, &sender_id);
+        let minted_shares = pool.add_liquidity(&sender_id, &mut amounts);
+        require(minted_shares >= min_shares.0, PoolError::MinShares);
+        let tokens = pool.tokens().to_vec();
+        self.internal_set_pool(to_pool_id, pool);
+        self.internal_update_storage(&sender_id, prev_storage);
+        self.internal_withdraw_tokens(&sender_id, &tokens, &amounts);
+        emit_add_liquidity(&sender_id, to_pool_id, &tokens, &amounts, minted_shares);
+        minted_shares.into()
+    }
+
+    /// Opens liquidity position in the given concentrated pool in the price range [tick_lower, tick_upper) from already deposited amounts.
+    /// Uses as much of given amounts as possible at the current price, the rest stays in the deposits.
+    /// Returns id of the new position.
+    pub fn add_position(
+        &mut self,
+        pool_id: u64,
+        tick_lower: i32,
+        tick_upper: i32,
+        amounts: Vec<U128>,
+        min_amounts: Vec<U128>,
+    ) -> U64 {
+        let sender_id = env::predecessor_account_id();
+        self.assert_pool_running(pool_id);
+        self.assert_no_flash_loan(pool_id);
+        let mut pool = self.internal_get_pool(pool_id);
+        let prev_storage = env::storage_usage();
+        let (position_id, used_amounts) = match &mut pool {
+            Pool::ConcentratedPool(pool) => pool.add_position(
+                &sender_id,
+                tick_lower,
+                tick_upper,
+                amounts.into_iter().map(|amount| amount.into()).collect(),
+                min_amounts
+                    .into_iter()
+                    .map(|amount| amount.into())
+                    .collect(),
+            ),
+            _ => PoolError::NotConcentratedPool.panic(),
+        };
+        let tokens = pool.tokens().to_vec();
+        self.internal_set_pool(pool_id, pool);
+        self.internal_update_storage(&sender_id, prev_storage);
+        self.internal_withdraw_tokens(&sender_id, &tokens, &used_amounts);
+        position_id.into()
+    }
+
+    /// Removes given liquidity from the position in concentrated pool into deposits, together with all the fees earned by the position.
+    /// Removing zero liquidity only collects the fees.
+    pub fn remove_position(
+        &mut self,
+        pool_id: u64,
+        position_id: U64,
+        liquidity: U128,
+        min_amounts: Vec<U128>,
+    ) {
+        let sender_id = env::predecessor_account_id();
+        self.assert_no_flash_loan(pool_id);
+        let mut pool = self.internal_get_pool(pool_id);
+        let prev_storage = env::storage_usage();
+        let amounts = match &mut pool {
+            Pool::ConcentratedPool(pool) => pool.remove_position(
+                &sender_id,
+                position_id.into(),
+                liquidity.into(),
+                min_amounts
+                    .into_iter()
+                    .map(|amount| amount.into())
+                    .collect(),
+            ),
+            _ => PoolError::NotConcentratedPool.panic(),
+        };
+        let tokens = pool.tokens().to_vec();
+        self.internal_set_pool(pool_id, pool);
+        self.internal_update_storage(&sender_id, prev_storage);
+        self.internal_deposit_tokens(&sender_id, &tokens, &amounts);
+    }
+
+    /// Moves given number of caller's shares of the stable swap pool into the deposits,
+    /// where they can be used as a token of the metapools built on top of this pool.
+    pub fn deposit_shares(&mut self, pool_id: u64, shares: U128) {
+        self.assert_running();
+        let sender_id = env::predecessor_account_id();
+        let mut pool = self.internal_get_pool(pool_id);
+        let prev_storage = env::storage_usage();
+        self.internal_settle_lp_fees(pool_id, &pool, &sender_id);
+        self.internal_settle_lp_fees(pool_id, &pool, &env::current_account_id());
+        match &mut pool {
+            Pool::StableSwapPool(pool) => {
+                pool.share_transfer(&sender_id, &env::current_account_id(), shares.into())
+            }
+            _ => PoolError::NotStableSwapPool.panic(),
+        };
+        self.internal_prune_lp_fees(pool_id, &pool, &sender_id);
+        self.assert_shares_unlocked(pool_id, &pool, &sender_id);
+        self.internal_set_pool(pool_id, pool);
+        self.internal_update_storage(&sender_id, prev_storage);
+        self.internal_deposit_tokens(&sender_id, &[shares_token_id(pool_id)], &[shares.into()]);
+    }
+
+    /// Moves given number of shares of the stable swap pool from the deposits back to the caller's shares in the pool.
+    pub fn withdraw_shares(&mut self, pool_id: u64, shares: U128) {
+        let sender_id = env::predecessor_account_id();
+        self.internal_withdraw_tokens(&sender_id, &[shares_token_id(pool_id)], &[shares.into()]);
+        let mut pool = self.internal_get_pool(pool_id);
+        let prev_storage = env::storage_usage();
+        self.internal_settle_lp_fees(pool_id, &pool, &env::current_account_id());
+        self.internal_settle_lp_fees(pool_id, &pool, &sender_id);
+        match &mut pool {
+            Pool::StableSwapPool(pool) => {
+                pool.share_transfer(&env::current_account_id(), &sender_id, shares.into())
+            }
+            _ => PoolError::NotStableSwapPool.panic(),
+        };
+        self.internal_prune_lp_fees(pool_id, &pool, &env::current_account_id());
+        self.internal_set_pool(pool_id, pool);
+        self.internal_update_storage(&sender_id, prev_storage);
+    }
+
+    /// Registers given tokens in the deposits of the caller, so they can be deposited or received from swaps and pools.
+    /// Up to 10 tokens can be registered, their storage is prepaid by `storage_deposit`.
+    pub fn register_tokens(&mut self, token_ids: Vec<AccountId>) {
+        let sender_id = env::predecessor_account_id();
+        let mut deposits = self.internal_get_account(&sender_id);
+        for token_id in token_ids {
+            deposits.register(&token_id);
+        }
+        self.deposited_amounts.insert(&sender_id, &deposits);
+    }
+
+    /// Unregisters given tokens from the deposits of the caller. Fails if any of them has non zero balance.
+    pub fn unregister_tokens(&mut self, token_ids: Vec<AccountId>) {
+        let sender_id = env::predecessor_account_id();
+        let mut deposits = self.internal_get_account(&sender_id);
+        for token_id in token_ids {
+            deposits.unregister(&token_id);
+        }
+        self.deposited_amounts.insert(&sender_id, &deposits);
+    }
+
+    /// Withdraws given token from the deposits of given user.
+    /// If the transfer fails, e.g. because the user is not registered with the token, the amount is returned to the deposits.
+    #[payable]
+    pub fn withdraw(&mut self, token_id: AccountId, amount: U128) -> Promise {
+        assert_one_yocto();
+        let sender_id = env::predecessor_account_id();
+        self.internal_send_tokens(&sender_id, &token_id, amount.into())
+    }
+
+    /// Withdraws whole balances of given tokens from the deposits of the caller in one transaction.
+    /// Each token is transferred like in `withdraw`, so any token whose transfer fails is returned to the deposits.
+    #[payable]
+    pub fn withdraw_all(&mut self, token_ids: Vec<AccountId>) {
+        assert_one_yocto();
+        let sender_id = env::predecessor_account_id();
+        let deposits = self.internal_get_account(&sender_id);
+        for token_id in token_ids {
+            let amount = deposits.get_balance(&token_id);
+            if amount > 0 {
+                self.internal_send_tokens(&sender_id, &token_id, amount);
+            }
+        }
+    }
+
+    /// Callback after the token transfer of the withdrawal. Returns the amount to the deposits if the transfer failed.
+    #[private]
+    pub fn on_withdraw(&mut self, token_id: AccountId, sender_id: AccountId, amount: U128) {
+        require(env::promise_results_count() == 1, PoolError::Invalid);
+        self.internal_finish_transfer(&sender_id);
+        if let PromiseResult::Successful(_) = env::promise_result(0) {
+            self.internal_sub_token_balance(&token_id, amount.into());
+            emit_withdraw(&sender_id, &token_id, amount.into());
+            return;
+        }
+        log!(
+            "Withdrawal of {} {} to {} failed, returning it to the deposits",
+            amount.0,
+            token_id,
+            sender_id
+        );
+        let mut deposits = self.deposited_amounts.get(&sender_id).unwrap_or_default();
+        deposits.refund(&token_id, amount.into());
+        self.deposited_amounts.insert(&sender_id, &deposits);
+        if let Some(balance) = self.measured_balances.get(&token_id) {
+            self.measured_balances
+                .insert(&token_id, &(balance + amount.0));
+        }
+    }
+}
+
+/// Returns key of the fee tiers index for given tokens.
+fn tokens_key(tokens: &[AccountId]) -> Vec<AccountId> {
+    let mut tokens = tokens.to_vec();
+    tokens.sort();
+    tokens
+}
+
+/// Returns keys of the token pairs index for all pairs of given tokens, none if there are only two of them.
+fn token_pairs(tokens: &[AccountId]) -> Vec<Vec<AccountId>> {
+    let mut pairs = vec![];
+    if tokens.len() <= 2 {
+        return pairs;
+    }
+    for (index, token_a) in tokens.iter().enumerate() {
+        for token_b in &tokens[index + 1..] {
+            pairs.push(tokens_key(&[token_a.clone(), token_b.clone()]));
+        }
+    }
+    pairs
+}
+
+/// Internal methods implementation.
+impl Contract {
+    /// Returns empty state owned by given account.
+    pub(crate) fn internal_new(owner_id: AccountId) -> Self {
+        Self {
+            owner_id,
+            pending_owner_id: None,
+            max_num_tokens: DEFAULT_MAX_NUM_TOKENS,
+            pools: Vector::new(b"p".to_vec()),
+            deposited_amounts: LookupMap::new(b"d".to_vec()),
+            fee_tiers: LookupMap::new(b"f".to_vec()),
+            token_pair_pools: LookupMap::new(b"i".to_vec()),
+            token_pools: LookupMap::new(b"j".to_vec()),
+            wnear_id: None,
+            measured_balances: LookupMap::new(b"m".to_vec()),
+            whitelisted_tokens: UnorderedSet::new(b"w".to_vec()),
+            token_balances: LookupMap::new(b"t".to_vec()),
+            lp_tokens: UnorderedSet::new(b"l".to_vec()),
+            referral_fee: 0,
+            limit_orders: UnorderedMap::new(b"o".to_vec()),
+            next_limit_order_id: 0,
+            limit_order_fees: LimitOrderFees {
+                maker_fee: 0,
+                taker_fee: 0,
+            },
+            limit_order_collected_fees: LookupMap::new(b"c".to_vec()),
+            twap_oracles: LookupMap::new(b"a".to_vec()),
+            flash_loan_receivers: UnorderedSet::new(b"r".to_vec()),
+            flash_loan_fee: 0,
+            flash_loan_pools: UnorderedSet::new(b"b".to_vec()),
+            circuit_breakers: LookupMap::new(b"k".to_vec()),
+            batch_auctions: LookupMap::new(b"e".to_vec()),
+            stop_orders: UnorderedMap::new(b"s".to_vec()),
+            next_stop_order_id: 0,
+            stop_order_bounty: 0,
+            permissionless_pool_creation: true,
+            pool_creators: UnorderedSet::new(b"g".to_vec()),
+            pool_creation_deposit: 0,
+            pool_creation_deposits: LookupMap::new(b"h".to_vec()),
+            guardians: UnorderedSet::new(b"n".to_vec()),
+            paused_pools: UnorderedSet::new(b"q".to_vec()),
+            running_state: RunningState::Running,
+            protocol_fee: 0,
+            protocol_fees: LookupMap::new(b"u".to_vec()),
+            treasury_id: None,
+            timelock_delay: 0,
+            admin_actions: UnorderedMap::new(b"v".to_vec()),
+            next_admin_action_id: 0,
+            dao_id: None,
+            state_version: CURRENT_STATE_VERSION,
+            fee_setters: UnorderedSet::new(b"x".to_vec()),
+            pool_managers: UnorderedSet::new(b"y".to_vec()),
+            share_allowances: LookupMap::new(b"z".to_vec()),
+            fee_growth: LookupMap::new(b"F".to_vec()),
+            fee_checkpoints: LookupMap::new(b"E".to_vec()),
+            share_locks: LookupMap::new(b"L".to_vec()),
+            position_nfts: UnorderedMap::new(b"N".to_vec()),
+            position_nft_owners: LookupMap::new(b"O".to_vec()),
+            next_position_nft_id: 0,
+            vaults: LookupMap::new(b"V".to_vec()),
+            vault_balances: LookupMap::new(b"W".to_vec()),
+            farms: UnorderedMap::new(b"M".to_vec()),
+            farm_stakes: LookupMap::new(b"S".to_vec()),
+            next_farm_id: 0,
+            pool_stats: LookupMap::new(b"P".to_vec()),
+            liquidity_providers: LookupMap::new(b"K".to_vec()),
+            transfers_in_flight: LookupMap::new(b"T".to_vec()),
+            reserve_floors: LookupMap::new(b"R".to_vec()),
+            aurora_id: None,
+            token_metadata: LookupMap::new(b"D".to_vec()),
+            open_positions: LookupMap::new(b"A".to_vec()),
+        }
+    }
+
+    /// Adds given pool to the list and returns it's id.
+    /// There can be several pools of the same kind over the same tokens, but with different fees.
+    /// If there is not enough attached balance to cover storage, fails.
+    fn internal_add_pool(&mut self, pool: Pool) -> u32 {
+        self.assert_running();
+        require(
+            pool.tokens().len() <= self.max_num_tokens as usize,
+            PoolError::TooManyTokens,
+        );
+        for token_id in pool.tokens() {
+            self.assert_whitelisted(token_id);
+        }
+        let prev_storage = env::storage_usage();
+        let id = self.internal_insert_pool(pool);
+        let pool_creation_deposit = self.internal_add_pool_creation(id as u64);
+        self.internal_charge_attached_deposit(prev_storage, pool_creation_deposit);
+        id
+    }
+
+    /// Stores given pool under the next id and adds it to the fee tier and token indexes. Returns its id.
+    fn internal_insert_pool(&mut self, pool: Pool) -> u32 {
+        let id = self.pools.len() as u32;
+        let tokens_key = tokens_key(pool.tokens());
+        for pool_id in self.fee_tiers.get(&tokens_key).unwrap_or_default() {
+            let other_pool: Pool = self
+                .pools
+                .get(pool_id)
+                .unwrap_or_panic(PoolError::NoPool)
+                .into();
+            require(!other_pool.same_parameters(&pool), PoolError::FeeTierExists);
+        }
+        self.internal_index_pool(id as u64, pool.tokens());
+        self.pools.push(&pool.into());
+        id
+    }
+
+    /// Adds pool with given id and tokens to the fee tier and token indexes.
+    pub(crate) fn internal_index_pool(&mut self, pool_id: u64, tokens: &[AccountId]) {
+        let tokens_key = tokens_key(tokens);
+        let mut fee_tiers = self.fee_tiers.get(&tokens_key).unwrap_or_default();
+        fee_tiers.push(pool_id);
+        self.fee_tiers.insert(&tokens_key, &fee_tiers);
+        for pair in token_pairs(tokens) {
+            let mut pool_ids = self.token_pair_pools.get(&pair).unwrap_or_default();
+            pool_ids.push(pool_id);
+            self.token_pair_pools.insert(&pair, &pool_ids);
+        }
+        for token_id in tokens {
+            let mut pool_ids = self.token_pools.get(token_id).unwrap_or_default();
+            pool_ids.push(pool_id);
+            self.token_pools.insert(token_id, &pool_ids);
+        }
+    }
+
+    /// Stores given pool under given id in the current version.
+    fn internal_set_pool(&mut self, pool_id: u64, pool: Pool) {
+        self.pools.replace(pool_id, &pool.into());
+    }
+
+    /// Returns pool with given id. Metapools get the current virtual price of their base pool.
+    fn internal_get_pool(&self, pool_id: u64) -> Pool {
+        let mut pool: Pool = self
+            .pools
+            .get(pool_id)
+            .unwrap_or_panic(PoolError::NoPool)
+            .into();
+        if let Pool::StableSwapPool(metapool) = &mut pool {
+            if let Some(base_pool_id) = metapool.base_pool_id {
+                match self.pools.get(base_pool_id).map(Pool::from) {
+                    Some(Pool::StableSwapPool(base_pool)) => {
+                        metapool.set_base_pool_virtual_price(base_pool.virtual_price())
+                    }
+                    _ => PoolError::NotStableSwapPool.panic(),
+                }
+            }
+        }
+        pool
+    }
+
+    /// Fails if given token is not whitelisted.
+    /// Sub-accounts of this contract, i.e. pool shares in the deposits, are always allowed.
+    fn assert_whitelisted(&self, token_id: &AccountId) {
+        require(
+            self.whitelisted_tokens.contains(token_id)
+                || token_id
+                    .as_str()
+                    .ends_with(&format!(".{}", env::current_account_id())),
+            PoolError::TokenNotWhitelisted,
+        );
+    }
+
+    /// Checks that admin methods are called by owner, or by the DAO once it's set.
+    fn assert_owner(&self) {
+        match &self.dao_id {
+            Some(dao_id) => require(&env::predecessor_account_id() == dao_id, PoolError::NotDao),
+            None => require(
+                env::predecessor_account_id() == self.owner_id,
+                PoolError::NotOwner,
+            ),
+        }
+    }
+
+    /// Registers account in deposited amounts with given storage balance.
+    /// This should be used when it's known that storage is prepaid.
+    fn internal_register_account(&mut self, account_id: &AccountId, storage_balance: Balance) {
+        self.deposited_amounts.insert(
+            account_id,
+            &AccountDeposits {
+                storage_balance,
+                ..Default::default()
+            },
+        );
+    }
+
+    /// Charges the attached deposit for storage taken since `prev_storage` plus given extra amount,
+    /// and refunds the rest of it to the caller. Fails if the attached deposit doesn't cover them.
+    fn internal_charge_attached_deposit(&self, prev_storage: StorageUsage, extra: Balance) {
+        let cost = (env::storage_usage().saturating_sub(prev_storage)) as Balance
+            * env::storage_byte_cost()
+            + extra;
+        let attached_deposit = env::attached_deposit();
+        require(cost <= attached_deposit, PoolError::StorageDeposit);
+        if attached_deposit > cost {
+            Promise::new(env::predecessor_account_id()).transfer(attached_deposit - cost);
+        }
+    }
+
+    /// Charges given account for storage taken since `prev_storage`, or releases storage freed since then.
+    /// Fails if storage balance of the account doesn't cover its storage.
+    fn internal_update_storage(&mut self, account_id: &AccountId, prev_storage: StorageUsage) {
+        let mut deposits = self.internal_get_account(account_id);
+        let storage = env::storage_usage();
+        if storage > prev_storage {
+            deposits.storage_used += storage - prev_storage;
+            deposits.assert_storage_usage();
+        } else {
+            deposits.storage_used = deposits.storage_used.saturating_sub(prev_storage - storage);
+        }
+        self.deposited_amounts.insert(account_id, &deposits);
+    }
+
+    /// Transfers given number of shares of the pool back to the account in a callback.
+    /// Storage of the shares is added to the account's usage without checking its storage balance,
+    /// as the callbacks must return the shares either way.
+    fn internal_return_shares(
+        &mut self,
+        pool_id: u64,
+        sender_id: &AccountId,
+        account_id: &AccountId,
+        amount: Balance,
+    ) {
+        let mut pool = self.internal_get_pool(pool_id);
+        let prev_storage = env::storage_usage();
+        self.internal_settle_lp_fees(pool_id, &pool, sender_id);
+        self.internal_settle_lp_fees(pool_id, &pool, account_id);
+        pool.share_transfer(sender_id, account_id, amount);
+        self.internal_prune_lp_fees(pool_id, &pool, sender_id);
+        self.internal_set_pool(pool_id, pool);
+        if let Some(mut deposits) = self.deposited_amounts.get(account_id) {
+            deposits.storage_used += env::storage_usage().saturating_sub(prev_storage);
+            self.deposited_amounts.insert(account_id, &deposits);
+        }
+    }
+
+    /// Removes given shares of the user from the pool into the deposits. Returns tokens of the pool and received amounts.
+    fn internal_remove_liquidity(
+        &mut self,
+        sender_id: &AccountId,
+        pool_id: u64,
+        shares: U128,
+        min_amounts: Vec<U128>,
+    ) -> (Vec<AccountId>, Vec<Balance>) {
+        self.assert_no_flash_loan(pool_id);
+        let mut pool = self.internal_get_pool(pool_id);
+        let prev_storage = env::storage_usage();
+        self.internal_settle_lp_fees(pool_id, &pool, sender_id);
+        let amounts = pool.remove_liquidity(
+            sender_id,
+            shares.into(),
+            min_amounts
+                .into_iter()
+                .map(|amount| amount.into())
+                .collect(),
+        );
+        self.internal_prune_lp_fees(pool_id, &pool, sender_id);
+        self.assert_shares_unlocked(pool_id, &pool, sender_id);
+        let tokens = pool.tokens().to_vec();
+        self.internal_set_pool(pool_id, pool);
+        self.internal_update_storage(sender_id, prev_storage);
+        self.internal_deposit_tokens(sender_id, &tokens, &amounts);
+        emit_remove_liquidity(sender_id, pool_id, &tokens, &amounts, shares.into());
+        (tokens, amounts)
+    }
+
+    /// Subtracts given amount of token from the deposits of given user and transfers it to the user.
+    /// Callback `on_withdraw` returns the amount to the deposits if the transfer fails.
+    fn internal_send_tokens(
+        &mut self,
+        sender_id: &AccountId,
+        token_id: &AccountId,
+        amount: Balance,
+    ) -> Promise {
+        let mut deposits = self.internal_get_account(sender_id);
+        require(
+            deposits.get_balance(token_id) >= amount,
+            PoolError::NotEnough,
+        );
+        deposits.withdraw(token_id, amount);
+        self.deposited_amounts.insert(sender_id, &deposits);
+        self.internal_transfer_tokens(sender_id, token_id, amount)
+    }
+
+    /// Transfers given amount of token, that is already taken out of the deposits, to given user.
+    /// Callback `on_withdraw` credits the amount to the deposits of the user if the transfer fails.
+    fn internal_transfer_tokens(
+        &mut self,
+        sender_id: &AccountId,
+        token_id: &AccountId,
+        amount: Balance,
+    ) -> Promise {
+        if let Some(balance) = self.measured_balances.get(token_id) {
+            self.measured_balances
+                .insert(token_id, &balance.saturating_sub(amount));
+        }
+        self.internal_start_transfer(sender_id);
+        ext_fungible_token::ext(token_id.clone())
+            .with_attached_deposit(1)
+            .with_static_gas(GAS_FOR_FT_TRANSFER)
+            .ft_transfer(sender_id.clone(), amount.into(), None)
+            .then(
+                ext_self::ext(env::current_account_id())
+                    .with_static_gas(GAS_FOR_ON_WITHDRAW)
+                    .on_withdraw(token_id.clone(), sender_id.clone(), amount.into()),
+            )
+    }
+
+    /// Records a transfer out to, or wrapping of NEAR for, given account whose callback may credit its deposits,
+    /// so the account can't be closed until the callback runs.
+    fn internal_start_transfer(&mut self, account_id: &AccountId) {
+        let count = self.transfers_in_flight.get(account_id).unwrap_or_default();
+        self.transfers_in_flight.insert(account_id, &(count + 1));
+    }
+
+    /// Records that the callback of a transfer out to given account ran.
+    fn internal_finish_transfer(&mut self, account_id: &AccountId) {
+        match self.transfers_in_flight.get(account_id) {
+            Some(count) if count > 1 => {
+                self.transfers_in_flight.insert(account_id, &(count - 1));
+            }
+            Some(_) => {
+                self.transfers_in_flight.remove(account_id);
+            }
+            None => {}
+        }
+    }
+
+    /// Records an order, farm stake or vault balance of given account that credits its deposits once it closes,
+    /// so the account can't be closed until then.
+    pub(crate) fn internal_open_position(&mut self, account_id: &AccountId) {
+        let count = self.open_positions.get(account_id).unwrap_or_default();
+        self.open_positions.insert(account_id, &(count + 1));
+    }
+
+    /// Records that an order, farm stake or vault balance of given account closed.
+    pub(crate) fn internal_close_position(&mut self, account_id: &AccountId) {
+        match self.open_positions.get(account_id) {
+            Some(count) if count > 1 => {
+                self.open_positions.insert(account_id, &(count - 1));
+            }
+            Some(_) => {
+                self.open_positions.remove(account_id);
+            }
+            None => {}
+        }
+    }
+
+    /// Records that given amount of token came into pools and deposits.
+    fn internal_add_token_balance(&mut self, token_id: &AccountId, amount: Balance) {
+        let balance = self.token_balances.get(token_id).unwrap_or_default();
+        self.token_balances.insert(token_id, &(balance + amount));
+    }
+
+    /// Records that given amount of token left pools and deposits.
+    /// Outgoing transfers are recorded once they succeed, so tokens in flight are never counted as excess.
+    fn internal_sub_token_balance(&mut self, token_id: &AccountId, amount: Balance) {
+        let balance = self.token_balances.get(token_id).unwrap_or_default();
+        self.token_balances
+            .insert(token_id, &balance.saturating_sub(amount));
+    }
+
+    /// Executes given swap actions from the deposits of the sender and sends the output to the sender,
+    /// unwrapping wrapped NEAR into native NEAR. Used by `swap_near`.
+    fn internal_swap_near(
+        &mut self,
+        sender_id: &AccountId,
+        actions: Vec<SwapAction>,
+        referral_id: Option<&AccountId>,
+    ) -> Promise {
+        let wnear_id = self
+            .wnear_id
+            .clone()
+            .unwrap_or_panic(PoolError::NoWrappedNear);
+        let token_out: AccountId = actions
+            .last()
+            .unwrap_or_panic(PoolError::Invalid)
+            .token_out
+            .clone();
+        let amount_out = self
+            .internal_execute_actions(sender_id, actions, referral_id)
+            .into();
+        if token_out == wnear_id {
+            self.internal_withdraw_tokens(sender_id, &[wnear_id.clone()], &[amount_out]);
+            self.internal_start_transfer(sender_id);
+            ext_wrap_near::ext(wnear_id.clone())
+                .with_attached_deposit(1)
+                .with_static_gas(GAS_FOR_NEAR_WITHDRAW)
+                .near_withdraw(amount_out.into())
+                .then(
+                    ext_self::ext(env::current_account_id())
+                        .with_static_gas(GAS_FOR_ON_NEAR_WITHDRAW)
+                        .on_near_withdraw(sender_id.clone(), amount_out.into()),
+                )
+        } else {
+            self.internal_send_tokens(sender_id, &token_out, amount_out)
+        }
+    }
+
+    /// Executes given swap actions from the deposits of the sender and sends the output of the last action
+    /// to `receiver_id` with `ft_transfer_call` and given `msg`, returning what the receiver doesn't use to the deposits.
+    pub(crate) fn internal_swap_and_call(
+        &mut self,
+        sender_id: &AccountId,
+        actions: Vec<SwapAction>,
+        referral_id: Option<&AccountId>,
+        receiver_id: AccountId,
+        msg: String,
+    ) -> Promise {
+        let token_out: AccountId = actions
+            .last()
+            .unwrap_or_panic(PoolError::Invalid)
+            .token_out
+            .clone();
+        let amount_out = self.internal_execute_actions(sender_id, actions, referral_id);
+        self.internal_withdraw_tokens(sender_id, &[token_out.clone()], &[amount_out.into()]);
+        if let Some(balance) = self.measured_balances.get(&token_out) {
+            self.measured_balances
+                .insert(&token_out, &balance.saturating_sub(amount_out.0));
+        }
+        self.internal_start_transfer(sender_id);
+        ext_fungible_token::ext(token_out.clone())
+            .with_attached_deposit(1)
+            .with_static_gas(env::prepaid_gas() - GAS_FOR_SWAP_AND_CALL)
+            .ft_transfer_call(receiver_id, amount_out, None, msg)
+            .then(
+                ext_self::ext(env::current_account_id())
+                    .with_static_gas(GAS_FOR_ON_TRANSFER_CALL)
+                    .on_transfer_call(token_out, sender_id.clone(), amount_out),
+            )
+    }
+
+    /// Adds given token to the whitelist and fetches its metadata, unless it's whitelisted already.
+    fn internal_add_whitelisted_token(&mut self, token_id: &AccountId) {
+        if self.whitelisted_tokens.insert(token_id) {
+            self.internal_query_token_metadata(token_id);
+        }
+    }
+
+    /// Sends given amount of excess token to given receiver. The amount is counted as owned by the contract
+    /// until the transfer completes, so concurrent `skim` and `rescue_token` can't send it out again.
+    fn internal_send_excess(
+        &mut self,
+        token_id: &AccountId,
+        amount: Balance,
+        receiver_id: AccountId,
+    ) {
+        self.internal_add_token_balance(token_id, amount);
+        ext_fungible_token::ext(token_id.clone())
+            .with_attached_deposit(1)
+            .with_static_gas(GAS_FOR_FT_TRANSFER)
+            .ft_transfer(receiver_id, amount.into(), None)
+            .then(
+                ext_self::ext(env::current_account_id())
+                    .with_static_gas(GAS_FOR_ON_EXCESS_TRANSFER)
+                    .on_excess_transfer(token_id.clone(), amount.into()),
+            );
+    }
+
+    /// Requests balance of this contract in given token.
+    fn internal_query_balance(&self, token_id: &AccountId) -> Promise {
+        ext_fungible_token::ext(token_id.clone())
+            .with_static_gas(GAS_FOR_FT_BALANCE_OF)
+            .ft_balance_of(env::current_account_id())
+    }
+
+    /// Returns amount in of the split swap for each of its pools. Without ratios, the amount is allocated
+    /// in `SPLIT_STEPS` parts, each to the pool whose output it increases the most.
+    fn internal_split_amount(&self, action: &SplitSwapAction) -> Vec<Balance> {
+        let amount_in: Balance = action.amount_in.into();
+        require(amount_in > 0, PoolError::AmountZero);
+        require(!action.pool_ids.is_empty(), PoolError::NoPool);
+        if let Some(ratios) = &action.ratios {
+            require(
+                ratios.len() == action.pool_ids.len(),
+                PoolError::WrongRatios,
+            );
+            let total: u128 = ratios.iter().map(|ratio| *ratio as u128).sum();
+            require(total > 0, PoolError::WrongRatios);
+            let mut amounts: Vec<Balance> = ratios
+                .iter()
+                .map(|ratio| {
+                    (U256::from(amount_in) * U256::from(*ratio) / U256::from(total)).as_u128()
+                })
+                .collect();
+            // Rounding dust goes to the pool with the largest part.
+            let largest = (0..amounts.len()).max_by_key(|i| amounts[*i]).unwrap();
+            amounts[largest] += amount_in - amounts.iter().sum::<Balance>();
+            return amounts;
+        }
+        let pools: Vec<Pool> = action
+            .pool_ids
+            .iter()
+            .map(|pool_id| self.internal_get_pool(*pool_id))
+            .collect();
+        let get_return = |pool: &Pool, amount: Balance| {
+            if amount == 0 {
+                Some(0)
+            } else {
+                pool.try_get_return(&action.token_in, amount, &action.token_out)
+            }
+        };
+        let mut amounts = vec![0; pools.len()];
+        let step = amount_in / SPLIT_STEPS;
+        for i in 0..SPLIT_STEPS {
+            let part = if i == SPLIT_STEPS - 1 {
+                amount_in - step * (SPLIT_STEPS - 1)
+            } else {
+                step
+            };
+            if part == 0 {
+                continue;
+            }
+            let best = pools
+                .iter()
+                .enumerate()
+                .filter_map(|(index, pool)| {
+                    let prev = get_return(pool, amounts[index])?;
+                    let next = get_return(pool, amounts[index] + part)?;
+                    Some((index, next.saturating_sub(prev)))
+                })
+                .max_by_key(|(_, gain)| *gain)
+                .unwrap_or_panic(PoolError::NotEnoughLiquidity);
+            amounts[best.0] += part;
+        }
+        amounts
+    }
+
+    /// Executes given swap actions one after another from the deposits of given user.
+    /// Returns amount of token out of the last action.
+    /// Referral fee is not paid if the referral is the user.
+    fn internal_execute_actions(
+        &mut self,
+        sender_id: &AccountId,
+        actions: Vec<SwapAction>,
+        referral_id: Option<&AccountId>,
+    ) -> U128 {
+        let mut deposits = self.internal_get_account(sender_id);
+        let referral_id = referral_id.filter(|referral_id| *referral_id != sender_id);
+        let amount_out =
+            self.internal_swap_deposits(sender_id, &mut deposits, actions, referral_id);
+        self.deposited_amounts.insert(sender_id, &deposits);
+        amount_out.into()
+    }
+
+    /// Executes given swap actions one after another from given deposits and returns amount of token out of the last action.
+    /// Action without `amount_in` swaps the whole output of the previous one, which goes straight into the next pool,
+    /// so routes like A -> B -> C only need A and C registered in the deposits.
+    fn internal_swap_deposits(
+        &mut self,
+        sender_id: &AccountId,
+        deposits: &mut AccountDeposits,
+        actions: Vec<SwapAction>,
+        referral_id: Option<&AccountId>,
+    ) -> Balance {
+        // Output of the previous action, not credited to the deposits while the next action swaps it.
+        let mut prev_output: Option<(AccountId, Balance)> = None;
+        for action in actions {
+            let token_in: AccountId = action.token_in;
+            let amount_in = match action.amount_in {
+                Some(amount_in) => {
+                    if let Some((token_id, amount)) = prev_output.take() {
+                        deposits.deposit(&token_id, amount);
+                    }
+                    let amount_in: Balance = amount_in.into();
+                    require(
+                        amount_in <= deposits.get_balance(&token_in),
+                        PoolError::NotEnoughDeposit,
+                    );
+                    deposits.withdraw(&token_in, amount_in);
+                    amount_in
+                }
+                None => {
+                    let (token_id, amount) =
+                        prev_output.unwrap_or_panic(PoolError::FirstSwapMissingAmount);
+                    require(token_id == token_in, PoolError::WrongTokenIn);
+                    amount
+                }
+            };
+            let token_out: AccountId = action.token_out;
+            let amount_out = self.internal_swap(
+                sender_id,
+                action.pool_id,
+                &token_in,
+                amount_in,
+                &token_out,
+                action.min_amount_out.into(),
+                referral_id,
+            );
+            prev_output = Some((token_out, amount_out));
+        }
+        let (token_id, amount) = prev_output.unwrap_or_panic(PoolError::Invalid);
+        deposits.deposit(&token_id, amount);
+        amount
+    }
+
+    /// Swaps given amount_in of token_in into token_out via given pool and returns the amount out.
+    /// Should be at least min_amount_out or swap will fail (prevents front running and other slippage issues).
+    #[allow(clippy::too_many_arguments)]
+    fn internal_swap(
+        &mut self,
+        sender_id: &AccountId,
+        pool_id: u64,
+        token_in: &AccountId,
+        amount_in: Balance,
+        token_out: &AccountId,
+        min_amount_out: Balance,
+        referral_id: Option<&AccountId>,
+    ) -> Balance {
+        self.assert_pool_running(pool_id);
+        self.assert_no_flash_loan(pool_id);
+        self.assert_no_batch_auction(pool_id);
+        let mut pool = self.internal_get_pool(pool_id);
+        self.internal_update_twap(pool_id, &pool);
+        self.internal_update_circuit_breaker(pool_id, &pool);
+        let fee = pool.current_fee();
+        let amount_out = pool.swap(token_in, amount_in, token_out, min_amount_out);
+        require(
+            self.internal_circuit_breaker_allows(pool_id, &pool),
+            PoolError::PriceMoveTooLarge,
+        );
+        require(
+            self.internal_reserve_floor_allows(pool_id, &pool, token_out),
+            PoolError::ReserveBelowFloor,
+        );
+        let mut fee_taken = 0;
+        if let Some(referral_id) = referral_id {
+            fee_taken +=
+                self.internal_pay_referral(&mut pool, referral_id, token_in, amount_in, fee);
+        }
+        fee_taken +=
+            self.internal_collect_protocol_fee(pool_id, &mut pool, token_in, amount_in, fee);
+        self.internal_accrue_lp_fees(pool_id, &pool, token_in, amount_in, fee, fee_taken);
+        self.internal_record_swap(pool_id, &pool, token_in, amount_in, fee);
+        self.internal_set_pool(pool_id, pool);
+        emit_swap(
+            sender_id, pool_id, token_in, amount_in, token_out, amount_out,
+        );
+        amount_out
+    }
+
+    /// Moves `referral_fee` part of the fee of a swap of given amount in, charged at given fee rate,
+    /// from the pool to the deposits of the referral. The fee stays in the pool if the referral doesn't have
+    /// the token registered, or if the pool doesn't keep the fees in its balances. Returns the paid amount.
+    fn internal_pay_referral(
+        &mut self,
+        pool: &mut Pool,
+        referral_id: &AccountId,
+        token_id: &AccountId,
+        amount_in: Balance,
+        fee: u32,
+    ) -> Balance {
+        if let Pool::ConcentratedPool(_) | Pool::BondingCurvePool(_) = pool {
+            return 0;
+        }
+        let amount = (U256::from(amount_in) * U256::from(fee) * U256::from(self.referral_fee)
+            / U256::from(FEE_DIVISOR)
+            / U256::from(REFERRAL_FEE_DIVISOR))
+        .as_u128();
+        if amount == 0 {
+            return 0;
+        }
+        match self.deposited_amounts.get(referral_id) {
+            Some(mut deposits) if deposits.tokens.contains_key(token_id) => {
+                pool.withdraw_fee(token_id, amount);
+                deposits.deposit(token_id, amount);
+                self.deposited_amounts.insert(referral_id, &deposits);
+                amount
+            }
+            _ => {
+                log!(
+                    "Referral {} doesn't have {} registered, the referral fee stays in the pool",
+                    referral_id,
+                    token_id
+                );
+                0
+            }
+        }
+    }
+
+    /// Subtracts given amounts of tokens from the deposits of given user.
+    /// Fails if user doesn't have enough of any of the tokens.
+    /// Zero amounts are skipped, so user doesn't need to have these tokens.
+    fn internal_withdraw_tokens(
+        &mut self,
+        sender_id: &AccountId,
+        tokens: &[AccountId],
+        amounts: &[Balance],
+    ) {
+        let mut deposits = self.internal_get_account(sender_id);
+        for (token_id, amount) in tokens.iter().zip(amounts.iter()) {
+            deposits.withdraw(token_id, *amount);
+        }
+        self.deposited_amounts.insert(sender_id, &deposits);
+    }
+
+    fn internal_set_referral_fee(&mut self, referral_fee: u32) {
+        require(
+            referral_fee + self.protocol_fee <= REFERRAL_FEE_DIVISOR,
+            PoolError::FeeTooLarge,
+        );
+        self.referral_fee = referral_fee;
+    }
+
+    /// Adds given amounts of tokens to the deposits of given user.
+    fn internal_deposit_tokens(
+        &mut self,
+        sender_id: &AccountId,
+        tokens: &[AccountId],
+        amounts: &[Balance],
+    ) {
+        let mut deposits = self.internal_get_account(sender_id);
+        for (token_id, amount) in tokens.iter().zip(amounts.iter()) {
+            deposits.deposit(token_id, *amount);
+        }
+        self.deposited_amounts.insert(sender_id, &deposits);
+    }
+
+    /// Returns deposits of given user. Fails if the user is not registered.
+    fn internal_get_account(&self, sender_id: &AccountId) -> AccountDeposits {
+        self.deposited_amounts
+            .get(sender_id)
+            .unwrap_or_panic(PoolError::NoDeposit)
+    }
+
+    /// Returns current balance of given token for given user. If there is nothing recorded, returns 0.
+    fn internal_get_deposit(&self, sender_id: &AccountId, token_id: &AccountId) -> Balance {
+        self.internal_get_account(sender_id).get_balance(token_id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use near_contract_standards::fungible_token::receiver::FungibleTokenReceiver;
+    use near_sdk::test_utils::{accounts, get_logs, VMContextBuilder};
+    use near_sdk::{
+        serde_json, testing_env, Gas, PromiseOrValue, RuntimeFeesConfig, VMConfig, VMContext,
+    };
+
+    use super::*;
+    use crate::flash_loan::FlashLoan;
+    use crate::migration::{ContractV0, ContractV1, PoolV0};
+    use crate::pool::MIN_LIQUIDITY;
+    use crate::simple_pool::SimplePoolV0;
+    use crate::stop_order::STOP_ORDER_TWAP_PERIOD;
+    use crate::utils::{lp_token_id, PRICE_PRECISION, U256};
+
+    /// Sets up the environment of a callback receiving given result of the promise it's chained to.
+    fn testing_env_with_promise_results(context: VMContext, promise_result: PromiseResult) {
+        testing_env!(
+            context,
+            VMConfig::test(),
+            RuntimeFeesConfig::test(),
+            Default::default(),
+            vec![promise_result]
+        );
+    }
+
+    /// Creates contract owned by `accounts(0)` with given tokens whitelisted.
+    fn setup_contract(context: &mut VMContextBuilder, tokens: Vec<AccountId>) -> Contract {
+        testing_env!(context
+            .predecessor_account_id(accounts(0))
+            .attached_deposit(1)
+            .build());
+        let mut contract = Contract::new(accounts(0));
+        for token_id in tokens {
+            // Each whitelisting attaches gas to a metadata query, so every token gets fresh prepaid gas.
+            testing_env!(context.build());
+            contract.add_whitelisted_token(token_id);
+        }
+        contract
+    }
+
+    #[test]
+    fn test_basics() {
+        let one_near = 10u128.pow(24);
+        let mut context = VMContextBuilder::new();
+        let mut contract = setup_contract(&mut context, vec![accounts(1), accounts(2)]);
+
+        // create 1st pool (1, 2) with 0.3% fee.
+        testing_env!(context
+            .predecessor_account_id(accounts(3))
+            .attached_deposit(env::storage_byte_cost() * 500)
+            .build());
+        contract.add_simple_pool(vec![accounts(1), accounts(2)], 30);
+
+        // add liquidity of (1,2) tokens and create 1st pool.
+        testing_env!(context
+            .predecessor_account_id(accounts(3))
+            .attached_deposit(
+                contract.storage_balance_bounds().min.0 + env::storage_byte_cost() * 1000
+            )
+            .build());
+        contract.storage_deposit(None, None);
+        contract.register_tokens(vec![accounts(1), accounts(2), accounts(4)]);
+        testing_env!(context
+            .predecessor_account_id(accounts(1))
+            .attached_deposit(1)
+            .build());
+        contract.ft_on_transfer(accounts(3), (105 * one_near).into(), "".to_string());
+        testing_env!(context.predecessor_account_id(accounts(2)).build());
+        contract.ft_on_transfer(accounts(3), (110 * one_near).into(), "".to_string());
+        testing_env!(context.predecessor_account_id(accounts(3)).build());
+        assert_eq!(
+            contract.get_deposit(&accounts(3), &accounts(1)),
+            (105 * one_near).into()
+        );
+        assert_eq!(
+            contract.get_deposit(&accounts(3), &accounts(2)),
+            (110 * one_near).into()
+        );
+        contract.add_liquidity(0, vec![U128(5 * one_near), U128(10 * one_near)], U128(0));
+        assert_eq!(
+            contract.get_pool_total_shares(0),
+            U128(1000000000000000000000000)
+        );
+
+        // Get price from pool #0 1 -> 2 tokens.
+        let amount_out = contract.get_return(0, accounts(1), one_near.into(), accounts(2));
+        assert_eq!(amount_out, 1662497915624478906119726.into());
+        let amount_out = contract.swap(
+            vec![SwapAction {
+                pool_id: 0,
+                token_in: accounts(1),
+                amount_in: Some(one_near.into()),
+                token_out: accounts(2),
+                min_amount_out: U128(1),
+            }],
+            None,
+        );
+        assert_eq!(amount_out, 1662497915624478906119726.into());
+        assert_eq!(
+            contract.get_deposit(&accounts(3), &accounts(1)),
+            (99 * one_near).into()
+        );
+        assert_eq!(
+            contract.get_deposit(&accounts(3), &accounts(2)),
+            (100 * one_near + amount_out.0).into()
+        );
+
+        testing_env!(context.predecessor_account_id(accounts(3)).build());
+        contract.remove_liquidity(
+            0,
+            contract.get_pool_shares(0, accounts(3)),
+            vec![1.into(), 2.into()],
+        );
+        assert_eq!(contract.get_pool_total_shares(0), U128(MIN_LIQUIDITY));
+
+        contract.withdraw(
+            accounts(1),
+            contract.get_deposit(&accounts(3), &accounts(1)),
+        );
+    }
+
+    #[test]
+    fn test_shares_sum() {
+        let mut context = VMContextBuilder::new();
+        let contract = setup_route(&mut context);
+        for pool_id in 0..2 {
+            contract
+                .internal_get_pool(pool_id)
+                .assert_shares_sum(&[accounts(3)]);
+        }
+    }
+
+    #[test]
+    fn test_get_returns() {
+        let one_near = 10u128.pow(24);
+        let mut context = VMContextBuilder::new();
+        let contract = setup_route(&mut context);
+        assert_eq!(
+            contract.get_returns(
+                0,
+                accounts(1),
+                vec![(one_near / 2).into(), one_near.into()],
+                accounts(2)
+            ),
+            vec![
+                contract.get_return(0, accounts(1), (one_near / 2).into(), accounts(2)),
+                contract.get_return(0, accounts(1), one_near.into(), accounts(2))
+            ]
+        );
+    }
+
+    #[test]
+    fn test_spot_price() {
+        let mut context = VMContextBuilder::new();
+        let contract = setup_route(&mut context);
+        assert_eq!(
+            contract
+                .get_spot_price(0, accounts(1), accounts(2), false)
+                .0,
+            PRICE_PRECISION
+        );
+        assert_eq!(
+            contract.get_spot_price(0, accounts(1), accounts(2), true).0,
+            PRICE_PRECISION * 9970 / 10000
+        );
+    }
+
+    #[test]
+    fn test_price_impact() {
+        let one_near = 10u128.pow(24);
+        let mut context = VMContextBuilder::new();
+        let contract = setup_route(&mut context);
+        // Spot price is 1, execution price is ~0.906.
+        assert_eq!(
+            contract.get_price_impact(0, accounts(1), one_near.into(), accounts(2)),
+            933
+        );
+    }
+
+    #[test]
+    fn test_transfer_deposit_and_execute() {
+        let one_near = 10u128.pow(24);
+        let mut context = VMContextBuilder::new();
+        let mut contract =
+            setup_contract(&mut context, vec![accounts(1), accounts(2), accounts(4)]);
+        testing_env!(context
+            .predecessor_account_id(accounts(3))
+            .attached_deposit(env::storage_byte_cost() * 500)
+            .build());
+        contract.add_simple_pool(vec![accounts(1), accounts(2)], 30);
+        testing_env!(context
+            .attached_deposit(
+                contract.storage_balance_bounds().min.0 + env::storage_byte_cost() * 1000
+            )
+            .build());
+        contract.storage_deposit(None, None);
+        contract.register_tokens(vec![accounts(1), accounts(2), accounts(4)]);
+        testing_env!(context
+            .predecessor_account_id(accounts(1))
+            .attached_deposit(1)
+            .build());
+        // Deposits of the same token add up.
+        contract.ft_on_transfer(accounts(3), (5 * one_near).into(), "".to_string());
+        contract.ft_on_transfer(accounts(3), (5 * one_near).into(), "".to_string());
+        testing_env!(context.predecessor_account_id(accounts(2)).build());
+        contract.ft_on_transfer(accounts(3), (10 * one_near).into(), "".to_string());
+        testing_env!(context.predecessor_account_id(accounts(3)).build());
+        contract.add_liquidity(0, vec![U128(10 * one_near), U128(10 * one_near)], U128(0));
+        assert_eq!(contract.get_deposit(&accounts(3), &accounts(1)), U128(0));
+
+        // Swaps part of the transferred tokens and returns the rest.
+        let expected_amount_out = contract.get_return(0, accounts(1), one_near.into(), accounts(2));
+        testing_env!(context.predecessor_account_id(accounts(1)).build());
+        let msg = format!(
+            "{{\"actions\": [{{\"pool_id\": 0, \"token_in\": \"{}\", \"amount_in\": \"{}\", \"token_out\": \"{}\", \"min_amount_out\": \"1\"}}]}}",
+            accounts(1).as_ref(),
+            one_near,
+            accounts(2).as_ref()
+        );
+        let unused_amount = match contract.ft_on_transfer(accounts(3), (3 * one_near).into(), msg) {
+            PromiseOrValue::Value(amount) => amount,
+            _ => panic!("expected value"),
+        };
+        assert_eq!(unused_amount, U128(2 * one_near));
+        assert_eq!(contract.get_deposit(&accounts(3), &accounts(1)), U128(0));
+        assert_eq!(
+            contract.get_deposit(&accounts(3), &accounts(2)),
+            expected_amount_out
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_TRANSFER_IN_FLIGHT")]
+    fn test_deny_unregister_with_transfer_in_flight() {
+        let mut context = VMContextBuilder::new();
+        let mut contract = setup_contract(&mut context, vec![accounts(1)]);
+        testing_env!(context
+            .predecessor_account_id(accounts(3))
+            .attached_deposit(contract.storage_balance_bounds().min.0)
+            .build());
+        contract.storage_deposit(None, None);
+        contract.register_tokens(vec![accounts(1)]);
+        testing_env!(context.predecessor_account_id(accounts(1)).build());
+        contract.ft_on_transfer(accounts(3), U128(100), "".to_string());
+        testing_env!(context
+            .predecessor_account_id(accounts(3))
+            .attached_deposit(1)
+            .build());
+        contract.withdraw(accounts(1), U128(100));
+        // The account could be closed after the callback, but not before it may return the tokens.
+        contract.storage_unregister(None);
+    }
+
+    #[test]
+    fn test_withdraw_refund_on_failed_transfer() {
+        let mut context = VMContextBuilder::new();
+        let mut contract =
+            setup_contract(&mut context, vec![accounts(1), accounts(2), accounts(4)]);
+        testing_env!(context
+            .predecessor_account_id(accounts(3))
+            .attached_deposit(
+                contract.storage_balance_bounds().min.0 + env::storage_byte_cost() * 1000
+            )
+            .build());
+        contract.storage_deposit(None, None);
+        contract.register_tokens(vec![accounts(1), accounts(2), accounts(4)]);
+        testing_env!(context.predecessor_account_id(accounts(1)).build());
+        contract.ft_on_transfer(accounts(3), U128(100), "".to_string());
+        testing_env!(context
+            .predecessor_account_id(accounts(3))
+            .attached_deposit(1)
+            .build());
+        contract.withdraw(accounts(1), U128(100));
+        assert_eq!(contract.get_deposit(&accounts(3), &accounts(1)), U128(0));
+
+        // Failed transfer returns tokens to the deposits.
+        context
+            .predecessor_account_id(accounts(0))
+            .attached_deposit(0);
+        testing_env_with_promise_results(context.build(), PromiseResult::Failed);
+        contract.on_withdraw(accounts(1), accounts(3), U128(100));
+        assert_eq!(contract.get_deposit(&accounts(3), &accounts(1)), U128(100));
+
+        // Successful transfer leaves deposits as is.
+        testing_env!(context
+            .predecessor_account_id(accounts(3))
+            .attached_deposit(1)
+            .build());
+        contract.withdraw(accounts(1), U128(40));
+        context
+            .predecessor_account_id(accounts(0))
+            .attached_deposit(0);
+        testing_env_with_promise_results(context.build(), PromiseResult::Successful(vec![]));
+        contract.on_withdraw(accounts(1), accounts(3), U128(40));
+        assert_eq!(contract.get_deposit(&accounts(3), &accounts(1)), U128(60));
+    }
+
+    #[test]
+    fn test_remove_liquidity_and_withdraw() {
+        let one_near = 10u128.pow(24);
+        let mut context = VMContextBuilder::new();
+        let mut contract =
+            setup_contract(&mut context, vec![accounts(1), accounts(2), accounts(4)]);
+        testing_env!(context
+            .predecessor_account_id(accounts(3))
+            .attached_deposit(env::storage_byte_cost() * 500)
+            .build());
+        contract.add_simple_pool(vec![accounts(1), accounts(2)], 30);
+        testing_env!(context
+            .attached_deposit(
+                contract.storage_balance_bounds().min.0 + env::storage_byte_cost() * 1000
+            )
+            .build());
+        contract.storage_deposit(None, None);
+        contract.register_tokens(vec![accounts(1), accounts(2), accounts(4)]);
+        testing_env!(context
+            .predecessor_account_id(accounts(1))
+            .attached_deposit(1)
+            .build());
+        contract.ft_on_transfer(accounts(3), one_near.into(), "".to_string());
+        testing_env!(context.predecessor_account_id(accounts(2)).build());
+        contract.ft_on_transfer(accounts(3), one_near.into(), "".to_string());
+        testing_env!(context.predecessor_account_id(accounts(3)).build());
+        contract.add_liquidity(0, vec![U128(one_near), U128(one_near)], U128(0));
+        let shares = contract.get_pool_shares(0, accounts(3));
+        contract.remove_liquidity_and_withdraw(0, shares, vec![U128(1), U128(1)]);
+        assert!(contract
+            .get_deposits(&accounts(3))
+            .values()
+            .all(|amount| amount.0 == 0));
+
+        // Only the token whose transfer failed is returned to the deposits.
+        context
+            .predecessor_account_id(accounts(0))
+            .attached_deposit(0);
+        testing_env_with_promise_results(context.build(), PromiseResult::Failed);
+        contract.on_withdraw(accounts(2), accounts(3), U128(one_near));
+        testing_env_with_promise_results(context.build(), PromiseResult::Successful(vec![]));
+        contract.on_withdraw(accounts(1), accounts(3), U128(one_near));
+        assert_eq!(contract.get_deposit(&accounts(3), &accounts(1)), U128(0));
+        assert_eq!(
+            contract.get_deposit(&accounts(3), &accounts(2)),
+            U128(one_near)
+        );
+    }
+
+    #[test]
+    fn test_share_transfer() {
+        let mut context = VMContextBuilder::new();
+        let mut contract = setup_route(&mut context);
+        testing_env!(context
+            .predecessor_account_id(accounts(3))
+            .attached_deposit(1)
+            .build());
+        let shares = contract.get_pool_shares(0, accounts(3)).0;
+        contract.share_transfer(0, accounts(5), U128(shares / 4));
+        assert_eq!(
+            contract.get_pool_shares(0, accounts(3)),
+            U128(shares - shares / 4)
+        );
+        assert_eq!(contract.get_pool_shares(0, accounts(5)), U128(shares / 4));
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_NO_DEPOSIT")]
+    fn test_deny_share_transfer_to_unregistered() {
+        let mut context = VMContextBuilder::new();
+        let mut contract = setup_route(&mut context);
+        testing_env!(context
+            .predecessor_account_id(accounts(3))
+            .attached_deposit(1)
+            .build());
+        contract.share_transfer(0, accounts(4), U128(1000));
+    }
+
+    #[test]
+    fn test_mft_transfer() {
+        let one_near = 10u128.pow(24);
+        let mut context = VMContextBuilder::new();
+        let mut contract =
+            setup_contract(&mut context, vec![accounts(1), accounts(2), accounts(4)]);
+        testing_env!(context
+            .predecessor_account_id(accounts(3))
+            .attached_deposit(env::storage_byte_cost() * 500)
+            .build());
+        contract.add_simple_pool(vec![accounts(1), accounts(2)], 30);
+        testing_env!(context
+            .attached_deposit(
+                contract.storage_balance_bounds().min.0 + env::storage_byte_cost() * 1000
+            )
+            .build());
+        contract.storage_deposit(None, None);
+        contract.register_tokens(vec![accounts(1), accounts(2)]);
+        testing_env!(context.predecessor_account_id(accounts(5)).build());
+        contract.storage_deposit(None, None);
+        contract.register_tokens(vec![accounts(1), accounts(2)]);
+        testing_env!(context
+            .predecessor_account_id(accounts(1))
+            .attached_deposit(1)
+            .build());
+        contract.ft_on_transfer(accounts(3), one_near.into(), "".to_string());
+        testing_env!(context.predecessor_account_id(accounts(2)).build());
+        contract.ft_on_transfer(accounts(3), one_near.into(), "".to_string());
+        testing_env!(context.predecessor_account_id(accounts(3)).build());
+        contract.add_liquidity(0, vec![U128(one_near), U128(one_near)], U128(0));
+        let shares = contract.mft_balance_of(":0".to_string(), accounts(3));
+        assert_eq!(shares, contract.get_pool_shares(0, accounts(3)));
+        assert_eq!(
+            contract.mft_total_supply(":0".to_string()).0,
+            shares.0 + MIN_LIQUIDITY
+        );
+
+        contract.mft_transfer(":0".to_string(), accounts(5), U128(shares.0 / 2), None);
+        assert_eq!(
+            contract.mft_balance_of(":0".to_string(), accounts(3)).0,
+            shares.0 - shares.0 / 2
+        );
+        assert_eq!(
+            contract.mft_balance_of(":0".to_string(), accounts(5)).0,
+            shares.0 / 2
+        );
+        assert_eq!(
+            contract.mft_total_supply(":0".to_string()).0,
+            shares.0 + MIN_LIQUIDITY
+        );
+
+        // The receiver can remove liquidity with the received shares.
+        testing_env!(context.predecessor_account_id(accounts(5)).build());
+        contract.remove_liquidity(0, U128(shares.0 / 2), vec![U128(1), U128(1)]);
+        assert!(contract.get_deposit(&accounts(5), &accounts(1)).0 > 0);
+    }
+
+    #[test]
+    fn test_mft_transfer_call() {
+        let one_near = 10u128.pow(24);
+        let mut context = VMContextBuilder::new();
+        let mut contract =
+            setup_contract(&mut context, vec![accounts(1), accounts(2), accounts(4)]);
+        testing_env!(context
+            .predecessor_account_id(accounts(3))
+            .attached_deposit(env::storage_byte_cost() * 500)
+            .build());
+        contract.add_simple_pool(vec![accounts(1), accounts(2)], 30);
+        testing_env!(context
+            .attached_deposit(
+                contract.storage_balance_bounds().min.0 + env::storage_byte_cost() * 1000
+            )
+            .build());
+        contract.storage_deposit(None, None);
+        contract.register_tokens(vec![accounts(1), accounts(2)]);
+        testing_env!(context.predecessor_account_id(accounts(5)).build());
+        contract.storage_deposit(None, None);
+        testing_env!(context
+            .predecessor_account_id(accounts(1))
+            .attached_deposit(1)
+            .build());
+        contract.ft_on_transfer(accounts(3), one_near.into(), "".to_string());
+        testing_env!(context.predecessor_account_id(accounts(2)).build());
+        contract.ft_on_transfer(accounts(3), one_near.into(), "".to_string());
+        testing_env!(context.predecessor_account_id(accounts(3)).build());
+        contract.add_liquidity(0, vec![U128(one_near), U128(one_near)], U128(0));
+        let shares = contract.get_pool_shares(0, accounts(3)).0;
+
+        // Receiver returns a quarter of the shares as unused.
+        contract.mft_transfer_call(
+            ":0".to_string(),
+            accounts(5),
+            U128(shares / 2),
+            None,
+            "".to_string(),
+        );
+        assert_eq!(contract.get_pool_shares(0, accounts(5)).0, shares / 2);
+        context
+            .predecessor_account_id(accounts(0))
+            .attached_deposit(0);
+        testing_env_with_promise_results(
+            context.build(),
+            PromiseResult::Successful(serde_json::to_vec(&U128(shares / 4)).unwrap()),
+        );
+        let kept = contract
+            .mft_resolve_transfer(":0".to_string(), accounts(3), accounts(5), U128(shares / 2))
+            .0;
+        assert_eq!(kept, shares / 2 - shares / 4);
+        assert_eq!(contract.get_pool_shares(0, accounts(5)).0, kept);
+        assert_eq!(contract.get_pool_shares(0, accounts(3)).0, shares - kept);
+
+        // Failed receiver returns everything, but not more than it still has.
+        testing_env_with_promise_results(context.build(), PromiseResult::Failed);
+        let used = contract.mft_resolve_transfer(
+            ":0".to_string(),
+            accounts(3),
+            accounts(5),
+            U128(shares / 2),
+        );
+        assert_eq!(used.0, shares / 2 - kept);
+        assert_eq!(contract.get_pool_shares(0, accounts(5)).0, 0);
+        assert_eq!(contract.get_pool_shares(0, accounts(3)).0, shares);
+    }
+
+    #[test]
+    fn test_mft_transfer_from() {
+        let mut context = VMContextBuilder::new();
+        let mut contract = setup_route(&mut context);
+        let shares = contract.get_pool_shares(0, accounts(3)).0;
+        testing_env!(context
+            .predecessor_account_id(accounts(3))
+            .attached_deposit(1)
+            .build());
+        contract.mft_approve(":0".to_string(), accounts(5), U128(shares / 2));
+        assert_eq!(
+            contract
+                .mft_allowance(":0".to_string(), accounts(3), accounts(5))
+                .0,
+            shares / 2
+        );
+
+        // Spender pulls a part of the allowance to itself, then the rest.
+        testing_env!(context.predecessor_account_id(accounts(5)).build());
+        contract.mft_transfer_from(
+            ":0".to_string(),
+            accounts(3),
+            accounts(5),
+            U128(shares / 4),
+            None,
+        );
+        assert_eq!(
+            contract
+                .mft_allowance(":0".to_string(), accounts(3), accounts(5))
+                .0,
+            shares / 2 - shares / 4
+        );
+        contract.mft_transfer_from(
+            ":0".to_string(),
+            accounts(3),
+            accounts(5),
+            U128(shares / 2 - shares / 4),
+            None,
+        );
+        assert_eq!(contract.get_pool_shares(0, accounts(5)).0, shares / 2);
+        assert_eq!(
+            contract.get_pool_shares(0, accounts(3)).0,
+            shares - shares / 2
+        );
+        assert_eq!(
+            contract
+                .mft_allowance(":0".to_string(), accounts(3), accounts(5))
+                .0,
+            0
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_NOT_ENOUGH_ALLOWANCE")]
+    fn test_deny_mft_transfer_from_above_allowance() {
+        let mut context = VMContextBuilder::new();
+        let mut contract = setup_route(&mut context);
+        testing_env!(context
+            .predecessor_account_id(accounts(3))
+            .attached_deposit(1)
+            .build());
+        contract.mft_approve(":0".to_string(), accounts(5), U128(10));
+        testing_env!(context.predecessor_account_id(accounts(5)).build());
+        contract.mft_transfer_from(":0".to_string(), accounts(3), accounts(5), U128(11), None);
+    }
+
+    #[test]
+    fn test_predict_liquidity() {
+        let one_near = 10u128.pow(24);
+        let mut context = VMContextBuilder::new();
+        let mut contract = setup_route(&mut context);
+        let half = contract.get_pool_total_shares(0).0 / 2;
+        assert_eq!(
+            contract.predict_remove_liquidity(0, U128(half)),
+            vec![U128(5 * one_near), U128(5 * one_near)]
+        );
+        contract.remove_liquidity(0, U128(half), vec![U128(1), U128(1)]);
+        assert_eq!(
+            contract.get_deposit(&accounts(3), &accounts(1)),
+            U128(5 * one_near)
+        );
+
+        // Only the part of the deposit in the pool ratio is taken.
+        let prediction =
+            contract.predict_add_liquidity(0, vec![U128(one_near), U128(2 * one_near)]);
+        assert_eq!(prediction.amounts, vec![U128(one_near), U128(one_near)]);
+        let prev_shares = contract.get_pool_shares(0, accounts(3)).0;
+        contract.add_liquidity(
+            0,
+            vec![U128(one_near), U128(2 * one_near)],
+            prediction.shares,
+        );
+        assert_eq!(
+            contract.get_pool_shares(0, accounts(3)).0 - prev_shares,
+            prediction.shares.0
+        );
+        assert_eq!(
+            contract.get_deposit(&accounts(3), &accounts(2)),
+            U128(4 * one_near)
+        );
+    }
+
+    #[test]
+    fn test_pool_stats() {
+        let one_near = 10u128.pow(24);
+        let mut context = VMContextBuilder::new();
+        let mut contract = setup_route(&mut context);
+        assert_eq!(
+            contract.get_pool_stats(0),
+            PoolStatsInfo {
+                volumes: vec![U128(0), U128(0)],
+                fees: vec![U128(0), U128(0)],
+                num_swaps: U64(0),
+            }
+        );
+        let route = || {
+            vec![
+                SwapAction {
+                    pool_id: 0,
+                    token_in: accounts(1),
+                    amount_in: Some(U128(one_near / 2)),
+                    token_out: accounts(2),
+                    min_amount_out: U128(1),
+                },
+                SwapAction {
+                    pool_id: 1,
+                    token_in: accounts(2),
+                    amount_in: None,
+                    token_out: accounts(4),
+                    min_amount_out: U128(1),
+                },
+            ]
+        };
+        testing_env!(context.predecessor_account_id(accounts(5)).build());
+        contract.swap(route(), None);
+        contract.swap(route(), None);
+        assert_eq!(
+            contract.get_pool_stats(0),
+            PoolStatsInfo {
+                volumes: vec![U128(one_near), U128(0)],
+                fees: vec![U128(one_near / 2 * 30 / 10_000 * 2), U128(0)],
+                num_swaps: U64(2),
+            }
+        );
+        let stats = contract.get_pool_stats(1);
+        assert_eq!(stats.num_swaps, U64(2));
+        assert_eq!(stats.volumes[1], U128(0));
+        assert_eq!(stats.fees[1], U128(0));
+    }
+
+    #[test]
+    fn test_pool_volume_24h() {
+        let one_near = 10u128.pow(24);
+        let one_hour = 3600 * 10u64.pow(9);
+        let mut context = VMContextBuilder::new();
+        let mut contract = setup_route(&mut context);
+        let mut swap_at = |contract: &mut Contract, timestamp: u64| {
+            testing_env!(context
+                .predecessor_account_id(accounts(5))
+                .block_timestamp(timestamp)
+                .build());
+            contract.swap(
+                vec![
+                    SwapAction {
+                        pool_id: 0,
+                        token_in: accounts(1),
+                        amount_in: Some(U128(one_near / 4)),
+                        token_out: accounts(2),
+                        min_amount_out: U128(1),
+                    },
+                    SwapAction {
+                        pool_id: 1,
+                        token_in: accounts(2),
+                        amount_in: None,
+                        token_out: accounts(4),
+                        min_amount_out: U128(1),
+                    },
+                ],
+                None,
+            );
+        };
+        swap_at(&mut contract, 1000 * one_hour);
+        swap_at(&mut contract, 1012 * one_hour);
+        let volume = contract.get_pool_volume_24h(0);
+        assert_eq!(volume.volumes, vec![U128(one_near / 2), U128(0)]);
+        assert_eq!(volume.num_swaps, U64(2));
+
+        // A day later the first swap drops out, its bucket taken by the third one.
+        swap_at(&mut contract, 1024 * one_hour);
+        let volume = contract.get_pool_volume_24h(0);
+        assert_eq!(volume.volumes, vec![U128(one_near / 2), U128(0)]);
+        assert_eq!(
+            volume.fees,
+            vec![U128(one_near / 4 * 30 / 10_000 * 2), U128(0)]
+        );
+        testing_env!(context.block_timestamp(1100 * one_hour).build());
+        assert_eq!(contract.get_pool_volume_24h(0).num_swaps, U64(0));
+        assert_eq!(contract.get_pool_stats(0).num_swaps, U64(3));
+    }
+
+    #[test]
+    fn test_fees_earned() {
+        let one_near = 10u128.pow(24);
+        let fee = one_near / 2 * 30 / 10_000;
+        let mut context = VMContextBuilder::new();
+        let mut contract = setup_route(&mut context);
+        let route = || {
+            vec![
+                SwapAction {
+                    pool_id: 0,
+                    token_in: accounts(1),
+                    amount_in: Some(U128(one_near / 2)),
+                    token_out: accounts(2),
+                    min_amount_out: U128(1),
+                },
+                SwapAction {
+                    pool_id: 1,
+                    token_in: accounts(2),
+                    amount_in: None,
+                    token_out: accounts(4),
+                    min_amount_out: U128(1),
+                },
+            ]
+        };
+        // Minimum liquidity locked in the pool takes its part of the fees too.
+        let shares = contract.get_pool_shares(0, accounts(3)).0;
+        let total_shares = contract.get_pool_total_shares(0).0;
+        let fee_of = |shares: Balance| {
+            (U256::from(fee) * U256::from(shares) / U256::from(total_shares)).as_u128()
+        };
+        testing_env!(context.predecessor_account_id(accounts(5)).build());
+        contract.swap(route(), None);
+        assert_eq!(
+            contract.get_fees_earned(0, accounts(3)),
+            vec![U128(fee_of(shares)), U128(0)]
+        );
+
+        // Fees earned before a transfer stay with the sender, later fees are split by shares.
+        testing_env!(context
+            .predecessor_account_id(accounts(3))
+            .attached_deposit(1)
+            .build());
+        contract.mft_transfer(":0".to_string(), accounts(5), U128(shares / 2), None);
+        testing_env!(context.predecessor_account_id(accounts(5)).build());
+        contract.swap(route(), None);
+        assert_eq!(
+            contract.get_fees_earned(0, accounts(3)),
+            vec![U128(fee_of(shares) + fee_of(shares - shares / 2)), U128(0)]
+        );
+        assert_eq!(
+            contract.get_fees_earned(0, accounts(5)),
+            vec![U128(fee_of(shares / 2)), U128(0)]
+        );
+    }
+
+    #[test]
+    fn test_liquidity_providers() {
+        let mut context = VMContextBuilder::new();
+        let mut contract = setup_route(&mut context);
+        let shares = contract.get_pool_shares(0, accounts(3)).0;
+        assert_eq!(
+            contract.get_liquidity_providers(0, 0, 10),
+            vec![LiquidityProviderInfo {
+                account_id: accounts(3),
+                shares: U128(shares),
+            }]
+        );
+        testing_env!(context
+            .predecessor_account_id(accounts(3))
+            .attached_deposit(1)
+            .build());
+        contract.mft_transfer(":0".to_string(), accounts(5), U128(shares / 4), None);
+        assert_eq!(
+            contract.get_liquidity_providers(0, 1, 10),
+            vec![LiquidityProviderInfo {
+                account_id: accounts(5),
+                shares: U128(shares / 4),
+            }]
+        );
+
+        // Providers are unlisted once they have no shares left.
+        testing_env!(context.predecessor_account_id(accounts(5)).build());
+        contract.mft_transfer(":0".to_string(), accounts(3), U128(shares / 4), None);
+        assert_eq!(
+            contract.get_liquidity_providers(0, 0, 10),
+            vec![LiquidityProviderInfo {
+                account_id: accounts(3),
+                shares: U128(shares),
+            }]
+        );
+        assert_eq!(contract.get_liquidity_providers(1, 1, 10), vec![]);
+    }
+
+    #[test]
+    fn test_lock_shares() {
+        let one_sec = 10u64.pow(9);
+        let mut context = VMContextBuilder::new();
+        let mut contract = setup_route(&mut context);
+        testing_env!(context
+            .predecessor_account_id(accounts(3))
+            .block_timestamp(1000 * one_sec)
+            .build());
+        let shares = contract.get_pool_shares(0, accounts(3)).0;
+        contract.lock_shares(0, U128(shares / 2), U64(2000));
+        assert_eq!(
+            contract.get_share_lock(0, accounts(3)),
+            Some(ShareLock {
+                shares: U128(shares / 2),
+                unlock_at: U64(2000),
+            })
+        );
+
+        // Shares above the lock can be removed, the rest once the lock ends.
+        contract.remove_liquidity(0, U128(shares - shares / 2), vec![U128(1), U128(1)]);
+        testing_env!(context.block_timestamp(2000 * one_sec).build());
+        contract.remove_liquidity(0, U128(shares / 2), vec![U128(1), U128(1)]);
+        contract.unlock_shares(0);
+        assert_eq!(contract.get_share_lock(0, accounts(3)), None);
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_SHARES_LOCKED")]
+    fn test_deny_transfer_locked_shares() {
+        let one_sec = 10u64.pow(9);
+        let mut context = VMContextBuilder::new();
+        let mut contract = setup_route(&mut context);
+        testing_env!(context
+            .predecessor_account_id(accounts(3))
+            .block_timestamp(1000 * one_sec)
+            .attached_deposit(1)
+            .build());
+        let shares = contract.get_pool_shares(0, accounts(3)).0;
+        contract.lock_shares(0, U128(shares / 2), U64(2000));
+        contract.mft_transfer(":0".to_string(), accounts(5), U128(shares / 2 + 1), None);
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_LOCK_TOO_SHORT")]
+    fn test_deny_shorten_share_lock() {
+        let one_sec = 10u64.pow(9);
+        let mut context = VMContextBuilder::new();
+        let mut contract = setup_route(&mut context);
+        testing_env!(context
+            .predecessor_account_id(accounts(3))
+            .block_timestamp(1000 * one_sec)
+            .build());
+        contract.lock_shares(0, U128(1), U64(2000));
+        contract.lock_shares(0, U128(1), U64(1500));
+    }
+
+    #[test]
+    fn test_position_nft() {
+        let one_near = 10u128.pow(24);
+        let mut context = VMContextBuilder::new();
+        let mut contract = setup_route(&mut context);
+        let shares = contract.get_pool_shares(0, accounts(3)).0;
+        let half = contract.get_pool_total_shares(0).0 / 2;
+        let token_id = contract.mint_position_nft(0, U128(half));
+        assert_eq!(contract.get_pool_shares(0, accounts(3)).0, shares - half);
+        let token = contract.nft_token(token_id.clone()).unwrap();
+        assert_eq!(token.owner_id, accounts(3));
+        assert_eq!(token.pool_id, 0);
+        assert_eq!(token.shares, U128(half));
+        assert_eq!(
+            token.entry_amounts,
+            vec![U128(5 * one_near), U128(5 * one_near)]
+        );
+        assert_eq!(contract.nft_supply_for_owner(accounts(3)), U128(1));
+
+        // New owner burns the token and gets the shares.
+        testing_env!(context.attached_deposit(1).build());
+        contract.nft_transfer(accounts(5), token_id.clone(), None, None);
+        assert_eq!(
+            contract.nft_tokens_for_owner(accounts(5), None, None),
+            vec![contract.nft_token(token_id.clone()).unwrap()]
+        );
+        assert_eq!(contract.nft_supply_for_owner(accounts(3)), U128(0));
+        testing_env!(context
+            .predecessor_account_id(accounts(5))
+            .attached_deposit(0)
+            .build());
+        contract.burn_position_nft(token_id.clone());
+        assert_eq!(contract.get_pool_shares(0, accounts(5)).0, half);
+        assert_eq!(contract.nft_token(token_id), None);
+        assert_eq!(contract.nft_total_supply(), U128(0));
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_NOT_NFT_OWNER")]
+    fn test_deny_burn_position_nft_not_owner() {
+        let mut context = VMContextBuilder::new();
+        let mut contract = setup_route(&mut context);
+        let token_id = contract.mint_position_nft(0, U128(1));
+        testing_env!(context.predecessor_account_id(accounts(5)).build());
+        contract.burn_position_nft(token_id);
+    }
+
+    /// Creates vault of pool 0 of `setup_route` at second 1000, and moves half of the shares of accounts(3) into it.
+    fn setup_vault(context: &mut VMContextBuilder) -> Contract {
+        let mut contract = setup_route(context);
+        testing_env!(context
+            .block_timestamp(1000 * 10u64.pow(9))
+            .attached_deposit(env::storage_byte_cost() * 12000)
+            .build());
+        contract.create_vault(0);
+        let shares = contract.get_pool_shares(0, accounts(3)).0;
+        // The first deposit locks the minimum liquidity of the vault.
+        assert_eq!(
+            contract.vault_deposit(0, U128(shares / 2)),
+            U128(shares / 2 - MIN_LIQUIDITY)
+        );
+        contract
+    }
+
+    #[test]
+    fn test_vault() {
+        let one_near = 10u128.pow(24);
+        let mut context = VMContextBuilder::new();
+        let mut contract = setup_vault(&mut context);
+        let shares = contract.get_pool_shares(0, accounts(3)).0 * 2;
+        let vault_shares = shares / 2 - MIN_LIQUIDITY;
+        assert_eq!(
+            contract.get_vault_balance(0, accounts(3)),
+            U128(vault_shares)
+        );
+
+        // Rewards are added as liquidity once the TWAP period passed, so the same vault shares are worth
+        // more shares of the pool.
+        testing_env!(context
+            .predecessor_account_id(accounts(5))
+            .block_timestamp(1300 * 10u64.pow(9))
+            .attached_deposit(0)
+            .build());
+        contract.add_vault_rewards(0, accounts(1), U128(one_near));
+        assert_eq!(
+            contract.get_vault(0).unwrap().rewards,
+            vec![U128(one_near), U128(0)]
+        );
+        let compounded = contract.compound_vault(0, U128(1)).0;
+        assert!(compounded > 0);
+        let vault = contract.get_vault(0).unwrap();
+        assert_eq!(vault.shares.0, shares / 2 + compounded);
+        assert_eq!(vault.rewards, vec![U128(0), U128(0)]);
+        assert_eq!(contract.compound_vault(0, U128(0)), U128(0));
+
+        testing_env!(context.predecessor_account_id(accounts(3)).build());
+        let withdrawn = (U256::from(vault_shares) * U256::from(shares / 2 + compounded)
+            / U256::from(shares / 2))
+        .as_u128();
+        assert_eq!(
+            contract.vault_withdraw(0, U128(vault_shares)),
+            U128(withdrawn)
+        );
+        assert_eq!(
+            contract.get_pool_shares(0, accounts(3)).0,
+            shares / 2 + withdrawn
+        );
+        assert_eq!(contract.get_vault_balance(0, accounts(3)), U128(0));
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_PRICE_DEVIATES_FROM_TWAP")]
+    fn test_deny_compound_vault_off_twap() {
+        let one_near = 10u128.pow(24);
+        let mut context = VMContextBuilder::new();
+        let mut contract = setup_vault(&mut context);
+        testing_env!(context
+            .predecessor_account_id(accounts(5))
+            .block_timestamp(1300 * 10u64.pow(9))
+            .attached_deposit(0)
+            .build());
+        contract.register_tokens(vec![accounts(2)]);
+        contract.add_vault_rewards(0, accounts(1), U128(one_near / 2));
+        // The caller moves the price right before compounding.
+        swap_token_1(&mut contract, one_near / 2);
+        contract.compound_vault(0, U128(0));
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_MIN_SHARES")]
+    fn test_deny_compound_vault_below_min_shares() {
+        let one_near = 10u128.pow(24);
+        let mut context = VMContextBuilder::new();
+        let mut contract = setup_vault(&mut context);
+        testing_env!(context
+            .predecessor_account_id(accounts(5))
+            .block_timestamp(1300 * 10u64.pow(9))
+            .attached_deposit(0)
+            .build());
+        contract.add_vault_rewards(0, accounts(1), U128(one_near));
+        contract.compound_vault(0, U128(one_near));
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_FEES_COMPOUNDED")]
+    fn test_deny_claim_protocol_fees_compounded_into_vault() {
+        let mut context = VMContextBuilder::new();
+        let mut contract = setup_route(&mut context);
+        testing_env!(context
+            .attached_deposit(env::storage_byte_cost() * 12000)
+            .build());
+        contract.create_vault(0);
+        testing_env!(context
+            .predecessor_account_id(accounts(0))
+            .attached_deposit(1)
+            .build());
+        contract.set_treasury(accounts(0));
+        contract.set_vault_protocol_fees(0, true);
+        contract.claim_protocol_fees(0);
+    }
+
+    #[test]
+    fn test_farm() {
+        let one_near = 10u128.pow(24);
+        let one_sec = 10u64.pow(9);
+        let mut context = VMContextBuilder::new();
+        let mut contract = setup_route(&mut context);
+        testing_env!(context
+            .predecessor_account_id(accounts(5))
+            .block_timestamp(1000 * one_sec)
+            .attached_deposit(0)
+            .build());
+        let farm_id = contract.create_farm(0, accounts(1), U128(one_near), U64(1000), U64(1100));
+        assert_eq!(contract.get_deposit(&accounts(5), &accounts(1)), U128(0));
+        testing_env!(context.predecessor_account_id(accounts(3)).build());
+        let shares = contract.get_pool_shares(0, accounts(3)).0;
+        let half = contract.get_pool_total_shares(0).0 / 2;
+        contract.stake(farm_id, U128(half));
+        assert_eq!(contract.get_staked(farm_id, accounts(3)), U128(half));
+        assert_eq!(contract.get_farm(farm_id).unwrap().total_staked, U128(half));
+
+        // Rewards are emitted linearly over the period.
+        testing_env!(context.block_timestamp(1050 * one_sec).build());
+        assert_eq!(
+            contract.get_unclaimed_rewards(farm_id, accounts(3)),
+            vec![U128(one_near / 2)]
+        );
+        testing_env!(context.block_timestamp(1200 * one_sec).build());
+        assert_eq!(contract.claim_reward(farm_id), vec![U128(one_near)]);
+        assert_eq!(
+            contract.get_deposit(&accounts(3), &accounts(1)),
+            U128(one_near)
+        );
+        assert_eq!(contract.claim_reward(farm_id), vec![U128(0)]);
+
+        contract.unstake(farm_id, U128(half));
+        assert_eq!(contract.get_pool_shares(0, accounts(3)).0, shares);
+        assert_eq!(contract.get_staked(farm_id, accounts(3)), U128(0));
+    }
+
+    /// Creates farm of pool 0 of `setup_route` emitting 1 NEAR of token 1 of accounts(5) from second 1000 to 1100,
+    /// with half of the shares of the pool staked by accounts(3) in its first half.
+    fn setup_ended_farm(context: &mut VMContextBuilder) -> (Contract, U64) {
+        let one_near = 10u128.pow(24);
+        let one_sec = 10u64.pow(9);
+        let mut contract = setup_route(context);
+        testing_env!(context
+            .predecessor_account_id(accounts(5))
+            .block_timestamp(1000 * one_sec)
+            .attached_deposit(0)
+            .build());
+        let farm_id = contract.create_farm(0, accounts(1), U128(one_near), U64(1000), U64(1100));
+        testing_env!(context.predecessor_account_id(accounts(3)).build());
+        let half = contract.get_pool_total_shares(0).0 / 2;
+        contract.stake(farm_id, U128(half));
+        testing_env!(context.block_timestamp(1050 * one_sec).build());
+        contract.unstake(farm_id, U128(half));
+        testing_env!(context.block_timestamp(1200 * one_sec).build());
+        (contract, farm_id)
+    }
+
+    #[test]
+    fn test_reclaim_farm_rewards() {
+        let one_near = 10u128.pow(24);
+        let mut context = VMContextBuilder::new();
+        let (mut contract, farm_id) = setup_ended_farm(&mut context);
+        assert_eq!(contract.claim_reward(farm_id), vec![U128(one_near / 2)]);
+
+        // Rewards of the second half weren't emitted to anyone.
+        testing_env!(context.predecessor_account_id(accounts(5)).build());
+        assert_eq!(
+            contract.reclaim_farm_rewards(farm_id),
+            vec![U128(one_near / 2)]
+        );
+        assert_eq!(
+            contract.get_deposit(&accounts(5), &accounts(1)),
+            U128(one_near / 2)
+        );
+        assert_eq!(contract.reclaim_farm_rewards(farm_id), vec![U128(0)]);
+        // A later stake gets nothing of the reclaimed rewards.
+        testing_env!(context.predecessor_account_id(accounts(3)).build());
+        contract.stake(farm_id, U128(1000));
+        assert_eq!(
+            contract.get_unclaimed_rewards(farm_id, accounts(3)),
+            vec![U128(0)]
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_NOT_OWNER")]
+    fn test_deny_reclaim_farm_rewards_by_other() {
+        let mut context = VMContextBuilder::new();
+        let (mut contract, farm_id) = setup_ended_farm(&mut context);
+        contract.reclaim_farm_rewards(farm_id);
+    }
+
+    #[test]
+    fn test_farm_multiple_rewards() {
+        let one_near = 10u128.pow(24);
+        let one_sec = 10u64.pow(9);
+        let mut context = VMContextBuilder::new();
+        let mut contract = setup_route(&mut context);
+        testing_env!(context
+            .predecessor_account_id(accounts(4))
+            .block_timestamp(1000 * one_sec)
+            .attached_deposit(1)
+            .build());
+        contract.ft_on_transfer(accounts(5), (2 * one_near).into(), "".to_string());
+        testing_env!(context
+            .predecessor_account_id(accounts(5))
+            .attached_deposit(0)
+            .build());
+        let farm_id = contract.create_farm(0, accounts(1), U128(one_near), U64(1000), U64(1100));
+        testing_env!(context.predecessor_account_id(accounts(3)).build());
+        let half = contract.get_pool_total_shares(0).0 / 2;
+        contract.stake(farm_id, U128(half));
+
+        // Partner's reward has its own period, and goes to stakes from before it was added too.
+        testing_env!(context.predecessor_account_id(accounts(5)).build());
+        contract.add_farm_reward(
+            farm_id,
+            accounts(4),
+            U128(2 * one_near),
+            U64(1050),
+            U64(1250),
+        );
+        assert_eq!(
+            contract.get_farm(farm_id).unwrap().rewards[1],
+            FarmRewardInfo {
+                reward_token: accounts(4),
+                total_reward: U128(2 * one_near),
+                start_at: U64(1050),
+                end_at: U64(1250),
+            }
+        );
+        testing_env!(context.block_timestamp(1100 * one_sec).build());
+        assert_eq!(
+            contract.get_unclaimed_rewards(farm_id, accounts(3)),
+            vec![U128(one_near), U128(one_near / 2)]
+        );
+        testing_env!(context
+            .predecessor_account_id(accounts(3))
+            .block_timestamp(1300 * one_sec)
+            .build());
+        assert_eq!(
+            contract.claim_reward(farm_id),
+            vec![U128(one_near), U128(2 * one_near)]
+        );
+        assert_eq!(
+            contract.get_deposit(&accounts(3), &accounts(4)),
+            U128(2 * one_near)
+        );
+    }
+
+    /// Creates farm of pool 0 emitting 1 NEAR of token 1 between 1000 and 1100 seconds, where account 3 stakes half
+    /// of its shares locked for given months, and account 5 stakes a quarter of them, locked if given.
+    fn setup_locked_farm(
+        context: &mut VMContextBuilder,
+        months: u32,
+        other_months: Option<u32>,
+    ) -> (Contract, U64) {
+        let one_near = 10u128.pow(24);
+        let one_sec = 10u64.pow(9);
+        let mut contract = setup_route(context);
+        testing_env!(context
+            .predecessor_account_id(accounts(5))
+            .block_timestamp(1000 * one_sec)
+            .attached_deposit(0)
+            .build());
+        let farm_id = contract.create_farm(0, accounts(1), U128(one_near), U64(1000), U64(1100));
+        let supply = contract.get_pool_total_shares(0).0;
+        testing_env!(context
+            .predecessor_account_id(accounts(3))
+            .attached_deposit(1)
+            .build());
+        contract.mft_transfer(":0".to_string(), accounts(5), U128(supply / 4), None);
+        contract.stake(farm_id, U128(supply / 2));
+        contract.lock_stake(farm_id, months);
+        testing_env!(context.predecessor_account_id(accounts(5)).build());
+        contract.stake(farm_id, U128(supply / 4));
+        if let Some(months) = other_months {
+            contract.lock_stake(farm_id, months);
+        }
+        (contract, farm_id)
+    }
+
+    #[test]
+    fn test_farm_lock_boost() {
+        let one_near = 10u128.pow(24);
+        let one_sec = 10u64.pow(9);
+        let mut context = VMContextBuilder::new();
+        let (mut contract, farm_id) = setup_locked_farm(&mut context, 4, None);
+        let supply = contract.get_pool_total_shares(0).0;
+        // 4 months boost account 3 by 1.5x.
+        assert_eq!(
+            contract.get_stake(farm_id, accounts(3)).unwrap().weight,
+            U128(supply / 4 * 3)
+        );
+        testing_env!(context.block_timestamp(1100 * one_sec).build());
+        assert_eq!(
+            contract.get_unclaimed_rewards(farm_id, accounts(3)),
+            vec![U128(one_near / 4 * 3)]
+        );
+        assert_eq!(
+            contract.get_unclaimed_rewards(farm_id, accounts(5)),
+            vec![U128(one_near / 4)]
+        );
+
+        // Boost ends with the lock.
+        testing_env!(context
+            .block_timestamp((1100 + 4 * 30 * 24 * 60 * 60) * one_sec)
+            .build());
+        contract.refresh_stake(farm_id, accounts(3));
+        assert_eq!(
+            contract.get_stake(farm_id, accounts(3)).unwrap(),
+            StakeInfo {
+                shares: U128(supply / 2),
+                weight: U128(supply / 2),
+                locked_until: U64(0),
+                penalty_shares: U128(0),
+            }
+        );
+        assert_eq!(
+            contract.get_farm(farm_id).unwrap().total_weight,
+            U128(supply / 4 * 3)
+        );
+    }
+
+    #[test]
+    fn test_farm_early_exit_penalty() {
+        let mut context = VMContextBuilder::new();
+        let (mut contract, farm_id) = setup_locked_farm(&mut context, 12, Some(12));
+        let supply = contract.get_pool_total_shares(0).0;
+        // Half of the shares is taken with the whole year left, and goes to the other locked stake.
+        assert_eq!(
+            contract.unstake(farm_id, U128(supply / 4)),
+            U128(supply / 8)
+        );
+        assert_eq!(contract.get_pool_shares(0, accounts(5)), U128(supply / 8));
+        assert_eq!(
+            contract
+                .get_stake(farm_id, accounts(3))
+                .unwrap()
+                .penalty_shares,
+            U128(supply / 8)
+        );
+        testing_env!(context.predecessor_account_id(accounts(3)).build());
+        contract.claim_reward(farm_id);
+        assert_eq!(
+            contract.get_pool_shares(0, accounts(3)),
+            U128(supply / 4 + supply / 8 - MIN_LIQUIDITY)
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_WRONG_LOCK_DURATION")]
+    fn test_deny_lock_stake_over_year() {
+        let mut context = VMContextBuilder::new();
+        setup_locked_farm(&mut context, 13, None);
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_TOO_MANY_FARM_REWARDS")]
+    fn test_deny_too_many_farm_rewards() {
+        let one_sec = 10u64.pow(9);
+        let mut context = VMContextBuilder::new();
+        let mut contract = setup_route(&mut context);
+        testing_env!(context
+            .predecessor_account_id(accounts(5))
+            .block_timestamp(1000 * one_sec)
+            .build());
+        let farm_id = contract.create_farm(0, accounts(1), U128(1), U64(1000), U64(1100));
+        for _ in 0..5 {
+            contract.add_farm_reward(farm_id, accounts(1), U128(1), U64(1000), U64(1100));
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_WRONG_FARM_PERIOD")]
+    fn test_deny_farm_starting_in_past() {
+        let one_near = 10u128.pow(24);
+        let one_sec = 10u64.pow(9);
+        let mut context = VMContextBuilder::new();
+        let mut contract = setup_route(&mut context);
+        testing_env!(context
+            .predecessor_account_id(accounts(5))
+            .block_timestamp(1000 * one_sec)
+            .build());
+        contract.create_farm(0, accounts(1), U128(one_near), U64(900), U64(1100));
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_MIN_SHARES")]
+    fn test_deny_add_liquidity_below_min_shares() {
+        let one_near = 10u128.pow(24);
+        let mut context = VMContextBuilder::new();
+        let mut contract = setup_route(&mut context);
+        let shares = contract.get_pool_shares(0, accounts(3)).0;
+        contract.remove_liquidity(0, U128(shares / 2), vec![U128(1), U128(1)]);
+        let amounts = vec![U128(one_near), U128(one_near)];
+        let prediction = contract.predict_add_liquidity(0, amounts.clone());
+
+        // The ratio of the pool moves before the deposit.
+        contract.swap(
+            vec![SwapAction {
+                pool_id: 0,
+                token_in: accounts(2),
+                amount_in: Some(U128(one_near)),
+                token_out: accounts(1),
+                min_amount_out: U128(1),
+            }],
+            None,
+        );
+        contract.add_liquidity(0, amounts, prediction.shares);
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_NO_DEPOSIT")]
+    fn test_deny_mft_transfer_to_not_registered() {
+        let mut context = VMContextBuilder::new();
+        let mut contract = setup_contract(&mut context, vec![accounts(1), accounts(2)]);
+        testing_env!(context
+            .predecessor_account_id(accounts(3))
+            .attached_deposit(env::storage_byte_cost() * 500)
+            .build());
+        contract.add_simple_pool(vec![accounts(1), accounts(2)], 30);
+        testing_env!(context.attached_deposit(1).build());
+        contract.mft_transfer(":0".to_string(), accounts(5), U128(1), None);
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_WRONG_TOKEN_ID")]
+    fn test_deny_mft_wrong_token_id() {
+        let mut context = VMContextBuilder::new();
+        let contract = setup_contract(&mut context, vec![]);
+        contract.mft_total_supply("0".to_string());
+    }
+
+    #[test]
+    fn test_wrap_shares() {
+        let one_near = 10u128.pow(24);
+        let mut context = VMContextBuilder::new();
+        let mut contract =
+            setup_contract(&mut context, vec![accounts(1), accounts(2), accounts(4)]);
+        testing_env!(context
+            .predecessor_account_id(accounts(3))
+            .attached_deposit(env::storage_byte_cost() * 500)
+            .build());
+        contract.add_simple_pool(vec![accounts(1), accounts(2)], 30);
+        testing_env!(context
+            .attached_deposit(
+                contract.storage_balance_bounds().min.0 + env::storage_byte_cost() * 1000
+            )
+            .build());
+        contract.storage_deposit(None, None);
+        contract.register_tokens(vec![accounts(1), accounts(2)]);
+        testing_env!(context
+            .predecessor_account_id(accounts(1))
+            .attached_deposit(1)
+            .build());
+        contract.ft_on_transfer(accounts(3), one_near.into(), "".to_string());
+        testing_env!(context.predecessor_account_id(accounts(2)).build());
+        contract.ft_on_transfer(accounts(3), one_near.into(), "".to_string());
+        testing_env!(context.predecessor_account_id(accounts(3)).build());
+        contract.add_liquidity(0, vec![U128(one_near), U128(one_near)], U128(0));
+        let shares = contract.get_pool_shares(0, accounts(3));
+
+        testing_env!(context
+            .predecessor_account_id(accounts(0))
+            .attached_deposit(1)
+            .build());
+        contract.set_lp_token_code(vec![0; 100]);
+        contract.deploy_lp_token(0);
+        assert_eq!(contract.get_lp_token(0), Some(lp_token_id(0)));
+
+        // Shares are held by the contract while wrapped, and returned if minting fails.
+        let contract_id: AccountId = env::current_account_id();
+        testing_env!(context
+            .predecessor_account_id(accounts(3))
+            .attached_deposit(1)
+            .build());
+        contract.wrap_shares(0, shares);
+        assert_eq!(contract.get_pool_shares(0, accounts(3)).0, 0);
+        assert_eq!(contract.get_pool_shares(0, contract_id.clone()), shares);
+        context
+            .predecessor_account_id(contract_id.clone())
+            .attached_deposit(0);
+        testing_env_with_promise_results(context.build(), PromiseResult::Failed);
+        contract.on_lp_token_mint(0, accounts(3), shares);
+        assert_eq!(contract.get_pool_shares(0, accounts(3)), shares);
+
+        // Shares are returned only after the wrapped ones are burnt.
+        testing_env!(context
+            .predecessor_account_id(accounts(3))
+            .attached_deposit(1)
+            .build());
+        contract.wrap_shares(0, shares);
+        context
+            .predecessor_account_id(contract_id.clone())
+            .attached_deposit(0);
+        testing_env_with_promise_results(context.build(), PromiseResult::Successful(vec![]));
+        contract.on_lp_token_mint(0, accounts(3), shares);
+        contract.on_lp_token_burn(0, accounts(3), U128(shares.0 / 2));
+        assert_eq!(contract.get_pool_shares(0, accounts(3)).0, shares.0 / 2);
+        testing_env_with_promise_results(context.build(), PromiseResult::Failed);
+        contract.on_lp_token_burn(0, accounts(3), U128(shares.0 / 2));
+        assert_eq!(contract.get_pool_shares(0, accounts(3)).0, shares.0 / 2);
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_NO_LP_TOKEN")]
+    fn test_deny_wrap_shares_without_lp_token() {
+        let mut context = VMContextBuilder::new();
+        let mut contract = setup_contract(&mut context, vec![accounts(1), accounts(2)]);
+        testing_env!(context
+            .predecessor_account_id(accounts(3))
+            .attached_deposit(env::storage_byte_cost() * 500)
+            .build());
+        contract.add_simple_pool(vec![accounts(1), accounts(2)], 30);
+        testing_env!(context.attached_deposit(1).build());
+        contract.wrap_shares(0, U128(1));
+    }
+
+    /// Creates pools (1, 2) and (2, 4) with liquidity of account 3, and account 5 with 1 NEAR of token 1 deposited.
+    fn setup_route(context: &mut VMContextBuilder) -> Contract {
+        let one_near = 10u128.pow(24);
+        let mut contract = setup_contract(context, vec![accounts(1), accounts(2), accounts(4)]);
+        testing_env!(context
+            .predecessor_account_id(accounts(3))
+            .attached_deposit(env::storage_byte_cost() * 600)
+            .build());
+        contract.add_simple_pool(vec![accounts(1), accounts(2)], 30);
+        contract.add_simple_pool(vec![accounts(2), accounts(4)], 30);
+        testing_env!(context
+            .attached_deposit(
+                contract.storage_balance_bounds().min.0 + env::storage_byte_cost() * 2000
+            )
+            .build());
+        contract.storage_deposit(None, None);
+        contract.register_tokens(vec![accounts(1), accounts(2), accounts(4)]);
+        testing_env!(context.predecessor_account_id(accounts(5)).build());
+        contract.storage_deposit(None, None);
+        contract.register_tokens(vec![accounts(1), accounts(4)]);
+        testing_env!(context
+            .predecessor_account_id(accounts(1))
+            .attached_deposit(1)
+            .build());
+        contract.ft_on_transfer(accounts(3), (10 * one_near).into(), "".to_string());
+        contract.ft_on_transfer(accounts(5), one_near.into(), "".to_string());
+        testing_env!(context.predecessor_account_id(accounts(2)).build());
+        contract.ft_on_transfer(accounts(3), (20 * one_near).into(), "".to_string());
+        testing_env!(context.predecessor_account_id(accounts(4)).build());
+        contract.ft_on_transfer(accounts(3), (10 * one_near).into(), "".to_string());
+        testing_env!(context.predecessor_account_id(accounts(3)).build());
+        contract.add_liquidity(0, vec![U128(10 * one_near), U128(10 * one_near)], U128(0));
+        contract.add_liquidity(1, vec![U128(10 * one_near), U128(10 * one_near)], U128(0));
+        contract
+    }
+
+    #[test]
+    fn test_multi_hop_swap() {
+        let one_near = 10u128.pow(24);
+        let mut context = VMContextBuilder::new();
+        let mut contract = setup_route(&mut context);
+        let amount_mid = contract.get_return(0, accounts(1), one_near.into(), accounts(2));
+        let expected_out = contract.get_return(1, accounts(2), amount_mid, accounts(4));
+
+        // Intermediate token doesn't need to be registered.
+        testing_env!(context.predecessor_account_id(accounts(5)).build());
+        let amount_out = contract.swap(
+            vec![
+                SwapAction {
+                    pool_id: 0,
+                    token_in: accounts(1),
+                    amount_in: Some(one_near.into()),
+                    token_out: accounts(2),
+                    min_amount_out: U128(1),
+                },
+                SwapAction {
+                    pool_id: 1,
+                    token_in: accounts(2),
+                    amount_in: None,
+                    token_out: accounts(4),
+                    min_amount_out: expected_out,
+                },
+            ],
+            None,
+        );
+        assert_eq!(amount_out, expected_out);
+        assert_eq!(contract.get_deposit(&accounts(5), &accounts(1)), U128(0));
+        assert_eq!(
+            contract.get_deposit(&accounts(5), &accounts(4)),
+            expected_out
+        );
+    }
+
+    #[test]
+    fn test_events() {
+        let one_near = 10u128.pow(24);
+        let mut context = VMContextBuilder::new();
+        let mut contract = setup_route(&mut context);
+        testing_env!(context.predecessor_account_id(accounts(1)).build());
+        contract.ft_on_transfer(accounts(5), one_near.into(), "".to_string());
+        assert_eq!(
+            get_logs(),
+            vec![format!(
+                "EVENT_JSON:{{\"standard\":\"multiswap\",\"version\":\"1.0.0\",\"event\":\"deposit\",\
+                 \"data\":[{{\"account_id\":\"fargo\",\"token_id\":\"bob\",\"amount\":\"{}\"}}]}}",
+                one_near
+            )]
+        );
+
+        // Each hop of a route is a swap of the sender.
+        testing_env!(context.predecessor_account_id(accounts(5)).build());
+        let amount_out = contract.swap(
+            vec![
+                SwapAction {
+                    pool_id: 0,
+                    token_in: accounts(1),
+                    amount_in: Some(one_near.into()),
+                    token_out: accounts(2),
+                    min_amount_out: U128(1),
+                },
+                SwapAction {
+                    pool_id: 1,
+                    token_in: accounts(2),
+                    amount_in: None,
+                    token_out: accounts(4),
+                    min_amount_out: U128(1),
+                },
+            ],
+            None,
+        );
+        let events: Vec<serde_json::Value> = get_logs()
+            .iter()
+            .filter_map(|log| log.strip_prefix("EVENT_JSON:"))
+            .map(|event| serde_json::from_str(event).unwrap())
+            .collect();
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[1]["event"], "swap");
+        assert_eq!(events[1]["data"][0]["account_id"], "fargo");
+        assert_eq!(events[1]["data"][0]["pool_id"], 1);
+        assert_eq!(events[1]["data"][0]["token_in"], "charlie");
+        assert_eq!(
+            events[1]["data"][0]["amount_in"],
+            events[0]["data"][0]["amount_out"]
+        );
+        assert_eq!(events[1]["data"][0]["amount_out"], amount_out.0.to_string());
+    }
+
+    #[test]
+    fn test_swap_for_exact_partial() {
+        let one_near = 10u128.pow(24);
+        let mut context = VMContextBuilder::new();
+        let mut contract = setup_route(&mut context);
+        testing_env!(context.predecessor_account_id(accounts(5)).build());
+        contract.register_tokens(vec![accounts(2)]);
+        let amount_in = contract.get_amount_in(0, accounts(1), U128(one_near / 4), accounts(2));
+        let charged = contract.swap_for_exact(
+            0,
+            accounts(1),
+            U128(one_near / 2),
+            accounts(2),
+            U128(one_near / 4),
+            None,
+        );
+        assert_eq!(charged, amount_in);
+
+        // Remaining deposit is not enough for the whole amount out, so all of it is swapped.
+        let expected_out =
+            contract.get_return(0, accounts(1), U128(one_near - amount_in.0), accounts(2));
+        let charged = contract.swap_for_exact(
+            0,
+            accounts(1),
+            U128(one_near - amount_in.0),
+            accounts(2),
+            U128(one_near),
+            Some(true),
+        );
+        assert_eq!(charged, U128(one_near - amount_in.0));
+        assert_eq!(contract.get_deposit(&accounts(5), &accounts(1)), U128(0));
+        assert_eq!(
+            contract.get_deposit(&accounts(5), &accounts(2)),
+            U128(one_near / 4 + expected_out.0)
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_MAX_AMOUNT_IN")]
+    fn test_deny_swap_for_exact_over_max_amount_in() {
+        let one_near = 10u128.pow(24);
+        let mut context = VMContextBuilder::new();
+        let mut contract = setup_route(&mut context);
+        testing_env!(context.predecessor_account_id(accounts(5)).build());
+        contract.register_tokens(vec![accounts(2)]);
+        contract.swap_for_exact(
+            0,
+            accounts(1),
+            U128(one_near),
+            accounts(2),
+            U128(one_near),
+            Some(false),
+        );
+    }
+
+    /// Sets 0.1% stop order bounty, and places stop order of accounts(5) in `setup_route` to sell half NEAR
+    /// of token 1 for token 2 in pool 0 once the price falls by 10%.
+    fn setup_stop_order(context: &mut VMContextBuilder) -> (Contract, U64) {
+        let one_near = 10u128.pow(24);
+        let mut contract = setup_route(context);
+        testing_env!(context
+            .predecessor_account_id(accounts(0))
+            .block_timestamp(1000 * 10u64.pow(9))
+            .attached_deposit(env::storage_byte_cost() * 10000)
+            .build());
+        contract.enable_twap(0);
+        testing_env!(context.attached_deposit(1).build());
+        contract.set_stop_order_bounty(10);
+        testing_env!(context.predecessor_account_id(accounts(5)).build());
+        contract.register_tokens(vec![accounts(2)]);
+        let order_id = contract.add_stop_order(
+            0,
+            accounts(1),
+            U128(one_near / 2),
+            accounts(2),
+            U128(PRICE_PRECISION * 9 / 10),
+            U128(1),
+        );
+        (contract, order_id)
+    }
+
+    /// Lets accounts(3) sell 2 NEAR of token 1 in pool 0 of `setup_stop_order`, at given second.
+    fn sell_token_1(context: &mut VMContextBuilder, contract: &mut Contract, timestamp: u64) {
+        let one_near = 10u128.pow(24);
+        testing_env!(context
+            .predecessor_account_id(accounts(1))
+            .block_timestamp(timestamp * 10u64.pow(9))
+            .attached_deposit(1)
+            .build());
+        contract.ft_on_transfer(accounts(3), (2 * one_near).into(), "".to_string());
+        testing_env!(context
+            .predecessor_account_id(accounts(3))
+            .attached_deposit(0)
+            .build());
+        contract.swap(
+            vec![SwapAction {
+                pool_id: 0,
+                token_in: accounts(1),
+                amount_in: Some(U128(2 * one_near)),
+                token_out: accounts(2),
+                min_amount_out: U128(1),
+            }],
+            None,
+        );
+    }
+
+    #[test]
+    fn test_stop_order() {
+        let one_near = 10u128.pow(24);
+        let mut context = VMContextBuilder::new();
+        let (mut contract, order_id) = setup_stop_order(&mut context);
+        assert_eq!(
+            contract.get_deposit(&accounts(5), &accounts(1)),
+            U128(one_near / 2)
+        );
+        assert_eq!(contract.get_stop_orders(0, 10).len(), 1);
+
+        // accounts(3) sells token 1, and executes the order once the average price reached the trigger.
+        sell_token_1(&mut context, &mut contract, 1000);
+        testing_env!(context
+            .block_timestamp(1000 * 10u64.pow(9) + STOP_ORDER_TWAP_PERIOD * 10u64.pow(9))
+            .build());
+        let bounty = one_near / 2 / 1000;
+        let expected_out =
+            contract.get_return(0, accounts(1), U128(one_near / 2 - bounty), accounts(2));
+        assert_eq!(contract.execute_stop_order(order_id), expected_out);
+        assert_eq!(contract.get_stop_order(order_id), None);
+        assert_eq!(
+            contract.get_deposit(&accounts(5), &accounts(2)),
+            expected_out
+        );
+        assert_eq!(
+            contract.get_deposit(&accounts(3), &accounts(1)),
+            U128(bounty)
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_STOP_PRICE_NOT_REACHED")]
+    fn test_deny_execute_stop_order_above_trigger() {
+        let mut context = VMContextBuilder::new();
+        let (mut contract, order_id) = setup_stop_order(&mut context);
+        // Spot price falls below the trigger within the block, but the average price doesn't.
+        sell_token_1(&mut context, &mut contract, 2000);
+        contract.execute_stop_order(order_id);
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_NO_TWAP_ORACLE")]
+    fn test_deny_stop_order_without_twap() {
+        let one_near = 10u128.pow(24);
+        let mut context = VMContextBuilder::new();
+        let mut contract = setup_route(&mut context);
+        testing_env!(context.predecessor_account_id(accounts(5)).build());
+        contract.register_tokens(vec![accounts(2)]);
+        contract.add_stop_order(
+            0,
+            accounts(1),
+            U128(one_near / 2),
+            accounts(2),
+            U128(PRICE_PRECISION * 9 / 10),
+            U128(1),
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_OPEN_POSITIONS")]
+    fn test_deny_unregister_with_stop_order() {
+        let mut context = VMContextBuilder::new();
+        let (mut contract, _) = setup_stop_order(&mut context);
+        testing_env!(context.attached_deposit(1).build());
+        contract.storage_unregister(Some(true));
+    }
+
+    /// Puts pool 0 of `setup_route` into batch auction mode in block 1, and gives accounts(3) 1 NEAR of token 2.
+    fn setup_batch_auction(context: &mut VMContextBuilder) -> Contract {
+        let one_near = 10u128.pow(24);
+        let mut contract = setup_route(context);
+        testing_env!(context
+            .predecessor_account_id(accounts(2))
+            .attached_deposit(1)
+            .block_index(1)
+            .build());
+        contract.ft_on_transfer(accounts(3), one_near.into(), "".to_string());
+        testing_env!(context
+            .predecessor_account_id(accounts(0))
+            .attached_deposit(1)
+            .build());
+        contract.set_batch_auction(0, true);
+        testing_env!(context.predecessor_account_id(accounts(5)).build());
+        contract.register_tokens(vec![accounts(2)]);
+        contract
+    }
+
+    #[test]
+    fn test_batch_auction() {
+        let one_near = 10u128.pow(24);
+        let mut context = VMContextBuilder::new();
+        let mut contract = setup_batch_auction(&mut context);
+        contract.submit_batch_order(0, accounts(1), U128(one_near), U128(1));
+        testing_env!(context.predecessor_account_id(accounts(3)).build());
+        contract.submit_batch_order(0, accounts(2), U128(one_near / 2), U128(1));
+        assert_eq!(contract.get_batch_orders(0).unwrap().len(), 2);
+
+        testing_env!(context.block_index(2).build());
+        contract.finalize_batch_auction(0);
+        assert_eq!(contract.get_batch_orders(0), Some(vec![]));
+        assert!(!contract.open_positions.contains_key(&accounts(5)));
+        let out_5 = contract.get_deposit(&accounts(5), &accounts(2)).0;
+        let out_3 = contract.get_deposit(&accounts(3), &accounts(1)).0;
+        // Both sides trade at the same price.
+        let price_5 = out_5 * 10u128.pow(6) / one_near;
+        let price_3 = (one_near / 2) * 10u128.pow(6) / out_3;
+        assert!(price_5 <= price_3 && price_3 - price_5 <= 1);
+        // Only the net amount went through the pool.
+        let pool = contract.get_pool(0);
+        assert_eq!(pool.amounts[0], U128(11 * one_near - out_3));
+        assert_eq!(pool.amounts[1], U128(10 * one_near + one_near / 2 - out_5));
+
+        // Order asking for too much is refunded when the next batch starts.
+        testing_env!(context.predecessor_account_id(accounts(3)).build());
+        contract.submit_batch_order(0, accounts(1), U128(out_3), U128(out_3));
+        testing_env!(context.block_index(3).build());
+        contract.submit_batch_order(0, accounts(2), U128(one_near / 2), U128(1));
+        assert_eq!(
+            contract.get_deposit(&accounts(3), &accounts(1)),
+            U128(out_3)
+        );
+        assert_eq!(contract.get_batch_orders(0).unwrap().len(), 1);
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_POOL_IN_BATCH_AUCTION")]
+    fn test_deny_swap_in_batch_auction_pool() {
+        let one_near = 10u128.pow(24);
+        let mut context = VMContextBuilder::new();
+        let mut contract = setup_batch_auction(&mut context);
+        swap_token_1(&mut contract, one_near / 5);
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_BATCH_AUCTION_NOT_ENDED")]
+    fn test_deny_finalize_batch_auction_in_same_block() {
+        let one_near = 10u128.pow(24);
+        let mut context = VMContextBuilder::new();
+        let mut contract = setup_batch_auction(&mut context);
+        contract.submit_batch_order(0, accounts(1), U128(one_near), U128(1));
+        contract.finalize_batch_auction(0);
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_OPEN_POSITIONS")]
+    fn test_deny_unregister_with_batch_order() {
+        let one_near = 10u128.pow(24);
+        let mut context = VMContextBuilder::new();
+        let mut contract = setup_batch_auction(&mut context);
+        contract.submit_batch_order(0, accounts(1), U128(one_near), U128(1));
+        testing_env!(context.attached_deposit(1).build());
+        // Settling the order would credit a closed account.
+        contract.storage_unregister(None);
+    }
+
+    /// Adds pool 2 of tokens 1 and 2 with 0.05% fee and 5 NEAR of each to `setup_route`.
+    fn setup_split_swap(context: &mut VMContextBuilder) -> Contract {
+        let one_near = 10u128.pow(24);
+        let mut contract = setup_route(context);
+        testing_env!(context
+            .predecessor_account_id(accounts(1))
+            .attached_deposit(1)
+            .build());
+        contract.ft_on_transfer(accounts(3), (5 * one_near).into(), "".to_string());
+        testing_env!(context.predecessor_account_id(accounts(2)).build());
+        contract.ft_on_transfer(accounts(3), (5 * one_near).into(), "".to_string());
+        testing_env!(context
+            .predecessor_account_id(accounts(3))
+            .attached_deposit(env::storage_byte_cost() * 500)
+            .build());
+        contract.add_simple_pool(vec![accounts(1), accounts(2)], 5);
+        testing_env!(context.attached_deposit(0).build());
+        contract.add_liquidity(2, vec![U128(5 * one_near), U128(5 * one_near)], U128(0));
+        testing_env!(context.predecessor_account_id(accounts(5)).build());
+        contract.register_tokens(vec![accounts(2)]);
+        contract
+    }
+
+    fn split_swap_action(amount_in: Balance, ratios: Option<Vec<u32>>) -> SplitSwapAction {
+        SplitSwapAction {
+            pool_ids: vec![0, 2],
+            token_in: accounts(1),
+            amount_in: amount_in.into(),
+            token_out: accounts(2),
+            ratios,
+            min_amount_out: U128(1),
+        }
+    }
+
+    #[test]
+    fn test_split_swap() {
+        let one_near = 10u128.pow(24);
+        let mut context = VMContextBuilder::new();
+        let mut contract = setup_split_swap(&mut context);
+        let expected_out = contract
+            .get_return(0, accounts(1), U128(one_near / 4), accounts(2))
+            .0
+            + contract
+                .get_return(2, accounts(1), U128(one_near / 4), accounts(2))
+                .0;
+        let amount_out =
+            contract.split_swap(split_swap_action(one_near / 2, Some(vec![1, 1])), None);
+        assert_eq!(amount_out, U128(expected_out));
+        assert_eq!(contract.get_deposit(&accounts(5), &accounts(2)), amount_out);
+        assert_eq!(
+            contract.get_deposit(&accounts(5), &accounts(1)),
+            U128(one_near / 2)
+        );
+    }
+
+    #[test]
+    fn test_split_swap_best_output() {
+        let one_near = 10u128.pow(24);
+        let mut context = VMContextBuilder::new();
+        let mut contract = setup_split_swap(&mut context);
+        let single_out = std::cmp::max(
+            contract
+                .get_return(0, accounts(1), U128(one_near), accounts(2))
+                .0,
+            contract
+                .get_return(2, accounts(1), U128(one_near), accounts(2))
+                .0,
+        );
+        let amount_out = contract.split_swap(split_swap_action(one_near, None), None);
+        assert!(amount_out.0 > single_out);
+        // Both pools got part of the amount.
+        assert!(contract.get_pool(0).amounts[0].0 > 10 * one_near);
+        assert!(contract.get_pool(2).amounts[0].0 > 5 * one_near);
+        assert_eq!(
+            contract.get_pool(0).amounts[0].0 + contract.get_pool(2).amounts[0].0,
+            16 * one_near
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_WRONG_RATIOS")]
+    fn test_deny_split_swap_wrong_ratios() {
+        let one_near = 10u128.pow(24);
+        let mut context = VMContextBuilder::new();
+        let mut contract = setup_split_swap(&mut context);
+        contract.split_swap(split_swap_action(one_near, Some(vec![1])), None);
+    }
+
+    #[test]
+    fn test_swap_and_call() {
+        let one_near = 10u128.pow(24);
+        let mut context = VMContextBuilder::new();
+        let mut contract = setup_route(&mut context);
+        let amount_mid = contract.get_return(0, accounts(1), U128(one_near / 2), accounts(2));
+        let amount_out = contract
+            .get_return(1, accounts(2), amount_mid, accounts(4))
+            .0;
+
+        testing_env!(context
+            .predecessor_account_id(accounts(5))
+            .attached_deposit(1)
+            .prepaid_gas(Gas(300 * 10u64.pow(12)))
+            .build());
+        contract.swap_and_call(
+            vec![
+                SwapAction {
+                    pool_id: 0,
+                    token_in: accounts(1),
+                    amount_in: Some(U128(one_near / 2)),
+                    token_out: accounts(2),
+                    min_amount_out: U128(1),
+                },
+                SwapAction {
+                    pool_id: 1,
+                    token_in: accounts(2),
+                    amount_in: None,
+                    token_out: accounts(4),
+                    min_amount_out: U128(1),
+                },
+            ],
+            None,
+            accounts(3),
+            "deposit".to_string(),
+        );
+        assert_eq!(contract.get_deposit(&accounts(5), &accounts(4)), U128(0));
+
+        // Receiver uses only a third of the output, the rest is returned to the deposits.
+        context.predecessor_account_id(env::current_account_id());
+        testing_env_with_promise_results(
+            context.build(),
+            PromiseResult::Successful(serde_json::to_vec(&U128(amount_out / 3)).unwrap()),
+        );
+        let used_amount = contract.on_transfer_call(accounts(4), accounts(5), U128(amount_out));
+        assert_eq!(used_amount, U128(amount_out / 3));
+        assert_eq!(
+            contract.get_deposit(&accounts(5), &accounts(4)),
+            U128(amount_out - amount_out / 3)
+        );
+
+        // Failed transfer returns everything.
+        testing_env_with_promise_results(context.build(), PromiseResult::Failed);
+        let used_amount = contract.on_transfer_call(accounts(4), accounts(5), U128(amount_out));
+        assert_eq!(used_amount, U128(0));
+        assert_eq!(
+            contract.get_deposit(&accounts(5), &accounts(4)),
+            U128(2 * amount_out - amount_out / 3)
+        );
+    }
+
+    /// Returns swap actions of half of NEAR of token 1 to token 4 along the route of `setup_route`.
+    fn route_actions(one_near: Balance) -> Vec<SwapAction> {
+        vec![
+            SwapAction {
+                pool_id: 0,
+                token_in: accounts(1),
+                amount_in: Some(U128(one_near / 2)),
+                token_out: accounts(2),
+                min_amount_out: U128(1),
+            },
+            SwapAction {
+                pool_id: 1,
+                token_in: accounts(2),
+                amount_in: None,
+                token_out: accounts(4),
+                min_amount_out: U128(1),
+            },
+        ]
+    }
+
+    #[test]
+    fn test_swap_to_aurora() {
+        let one_near = 10u128.pow(24);
+        let mut context = VMContextBuilder::new();
+        let mut contract = setup_route(&mut context);
+        testing_env!(context
+            .predecessor_account_id(accounts(0))
+            .attached_deposit(1)
+            .build());
+        contract.set_aurora_id("aurora".parse().unwrap());
+        assert_eq!(contract.get_aurora_id(), Some("aurora".parse().unwrap()));
+        assert_eq!(
+            contract.get_config().aurora_id,
+            Some("aurora".parse().unwrap())
+        );
+        let amount_mid = contract.get_return(0, accounts(1), U128(one_near / 2), accounts(2));
+        let amount_out = contract
+            .get_return(1, accounts(2), amount_mid, accounts(4))
+            .0;
+
+        testing_env!(context
+            .predecessor_account_id(accounts(5))
+            .attached_deposit(1)
+            .prepaid_gas(Gas(300 * 10u64.pow(12)))
+            .build());
+        contract.swap_to_aurora(
+            route_actions(one_near),
+            None,
+            "0x5A2F6B7f4c2f1f4d6c5E1d9b0D3F1a8b2c3d4e5F".to_string(),
+        );
+        assert_eq!(
+            contract.get_deposit(&accounts(5), &accounts(1)),
+            U128(one_near / 2)
+        );
+        assert_eq!(contract.get_deposit(&accounts(5), &accounts(4)), U128(0));
+
+        // Tokens the engine doesn't take are returned to the deposits.
+        context.predecessor_account_id(env::current_account_id());
+        testing_env_with_promise_results(context.build(), PromiseResult::Failed);
+        contract.on_transfer_call(accounts(4), accounts(5), U128(amount_out));
+        assert_eq!(
+            contract.get_deposit(&accounts(5), &accounts(4)),
+            U128(amount_out)
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_INVALID_EVM_ADDRESS")]
+    fn test_deny_swap_to_invalid_aurora_address() {
+        let one_near = 10u128.pow(24);
+        let mut context = VMContextBuilder::new();
+        let mut contract = setup_route(&mut context);
+        testing_env!(context
+            .predecessor_account_id(accounts(0))
+            .attached_deposit(1)
+            .build());
+        contract.set_aurora_id("aurora".parse().unwrap());
+        testing_env!(context.predecessor_account_id(accounts(5)).build());
+        contract.swap_to_aurora(route_actions(one_near), None, "0x5a2f6b7f".to_string());
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_NO_AURORA")]
+    fn test_deny_swap_to_aurora_without_engine() {
+        let one_near = 10u128.pow(24);
+        let mut context = VMContextBuilder::new();
+        let mut contract = setup_route(&mut context);
+        testing_env!(context
+            .predecessor_account_id(accounts(5))
+            .attached_deposit(1)
+            .build());
+        contract.swap_to_aurora(
+            route_actions(one_near),
+            None,
+            "5a2f6b7f4c2f1f4d6c5e1d9b0d3f1a8b2c3d4e5f".to_string(),
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_WRONG_TOKEN_IN")]
+    fn test_deny_multi_hop_swap_from_other_token() {
+        let one_near = 10u128.pow(24);
+        let mut context = VMContextBuilder::new();
+        let mut contract = setup_route(&mut context);
+        testing_env!(context.predecessor_account_id(accounts(5)).build());
+        contract.swap(
+            vec![
+                SwapAction {
+                    pool_id: 0,
+                    token_in: accounts(1),
+                    amount_in: Some(one_near.into()),
+                    token_out: accounts(2),
+                    min_amount_out: U128(1),
+                },
+                SwapAction {
+                    pool_id: 1,
+                    token_in: accounts(4),
+                    amount_in: None,
+                    token_out: accounts(2),
+                    min_amount_out: U128(1),
+                },
+            ],
+            None,
+        );
+    }
+
+    #[test]
+    fn test_get_best_route() {
+        let one_near = 10u128.pow(24);
+        let mut context = VMContextBuilder::new();
+        let contract = setup_route(&mut context);
+        let amount_mid = contract.get_return(0, accounts(1), one_near.into(), accounts(2));
+        let expected_out = contract.get_return(1, accounts(2), amount_mid, accounts(4));
+        assert_eq!(
+            contract.get_best_route(accounts(1), one_near.into(), accounts(4), 2),
+            Some(RouteInfo {
+                pool_ids: vec![0, 1],
+                tokens: vec![accounts(1), accounts(2), accounts(4)],
+                amount_out: expected_out,
+            })
+        );
+        assert_eq!(
+            contract.get_best_route(accounts(1), one_near.into(), accounts(4), 1),
+            None
+        );
+    }
+
+    #[test]
+    fn test_get_return_by_route() {
+        let one_near = 10u128.pow(24);
+        let mut context = VMContextBuilder::new();
+        let contract = setup_route(&mut context);
+        let amount_mid = contract.get_return(0, accounts(1), one_near.into(), accounts(2));
+        let expected_out = contract.get_return(1, accounts(2), amount_mid, accounts(4));
+        assert_eq!(
+            contract.get_return_by_route(
+                vec![
+                    RouteHop {
+                        pool_id: 0,
+                        token_in: accounts(1),
+                        token_out: accounts(2),
+                    },
+                    RouteHop {
+                        pool_id: 1,
+                        token_in: accounts(2),
+                        token_out: accounts(4),
+                    },
+                ],
+                one_near.into(),
+            ),
+            RouteReturn {
+                amount_out: expected_out,
+                hops: vec![
+                    PoolReturn {
+                        pool_id: 0,
+                        amount_out: amount_mid,
+                    },
+                    PoolReturn {
+                        pool_id: 1,
+                        amount_out: expected_out,
+                    },
+                ],
+            }
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_WRONG_TOKEN_IN")]
+    fn test_deny_get_return_by_broken_route() {
+        let mut context = VMContextBuilder::new();
+        let contract = setup_route(&mut context);
+        contract.get_return_by_route(
+            vec![
+                RouteHop {
+                    pool_id: 0,
+                    token_in: accounts(1),
+                    token_out: accounts(2),
+                },
+                RouteHop {
+                    pool_id: 1,
+                    token_in: accounts(4),
+                    token_out: accounts(2),
+                },
+            ],
+            10u128.pow(24).into(),
+        );
+    }
+
+    #[test]
+    fn test_batch_swap() {
+        let one_near = 10u128.pow(24);
+        let mut context = VMContextBuilder::new();
+        let mut contract = setup_route(&mut context);
+        let expected_out_0 = contract.get_return(0, accounts(1), one_near.into(), accounts(2));
+        let expected_out_1 = contract.get_return(1, accounts(4), one_near.into(), accounts(2));
+        testing_env!(context
+            .predecessor_account_id(accounts(4))
+            .attached_deposit(1)
+            .build());
+        contract.ft_on_transfer(accounts(5), one_near.into(), "".to_string());
+        testing_env!(context.predecessor_account_id(accounts(5)).build());
+        contract.register_tokens(vec![accounts(2)]);
+        let amounts_out = contract.batch_swap(
+            vec![
+                SwapAction {
+                    pool_id: 0,
+                    token_in: accounts(1),
+                    amount_in: Some(one_near.into()),
+                    token_out: accounts(2),
+                    min_amount_out: expected_out_0,
+                },
+                SwapAction {
+                    pool_id: 1,
+                    token_in: accounts(4),
+                    amount_in: Some(one_near.into()),
+                    token_out: accounts(2),
+                    min_amount_out: expected_out_1,
+                },
+            ],
+            None,
+        );
+        assert_eq!(amounts_out, vec![expected_out_0, expected_out_1]);
+        assert_eq!(
+            contract.get_deposit(&accounts(5), &accounts(2)).0,
+            expected_out_0.0 + expected_out_1.0
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_FIRST_SWAP_MISSING_AMOUNT")]
+    fn test_deny_batch_swap_without_amount() {
+        let mut context = VMContextBuilder::new();
+        let mut contract = setup_route(&mut context);
+        testing_env!(context.predecessor_account_id(accounts(5)).build());
+        // Swaps in the batch are not chained, so each needs its own amount.
+        contract.batch_swap(
+            vec![SwapAction {
+                pool_id: 0,
+                token_in: accounts(1),
+                amount_in: None,
+                token_out: accounts(2),
+                min_amount_out: U128(1),
+            }],
+            None,
+        );
+    }
+
+    #[test]
+    fn test_referral_fee() {
+        let one_near = 10u128.pow(24);
+        let mut context = VMContextBuilder::new();
+        let mut contract = setup_route(&mut context);
+        testing_env!(context.predecessor_account_id(accounts(0)).build());
+        contract.set_referral_fee(2000);
+        testing_env!(context.predecessor_account_id(accounts(5)).build());
+        contract.register_tokens(vec![accounts(2)]);
+        let action = SwapAction {
+            pool_id: 0,
+            token_in: accounts(1),
+            amount_in: Some((one_near / 2).into()),
+            token_out: accounts(2),
+            min_amount_out: U128(1),
+        };
+        let referral_amount = one_near / 2 * 30 / 10_000 * 2000 / 10_000;
+        let pool_amount = contract.get_pool(0).amounts[0].0;
+        contract.swap(vec![action], Some(accounts(3)));
+        assert_eq!(
+            contract.get_deposit(&accounts(3), &accounts(1)),
+            U128(referral_amount)
+        );
+        assert_eq!(
+            contract.get_pool(0).amounts[0].0,
+            pool_amount + one_near / 2 - referral_amount
+        );
+
+        // Fee stays in the pool if the referral is not registered.
+        let pool_amount = contract.get_pool(0).amounts[0].0;
+        contract.swap(
+            vec![SwapAction {
+                pool_id: 0,
+                token_in: accounts(1),
+                amount_in: Some((one_near / 2).into()),
+                token_out: accounts(2),
+                min_amount_out: U128(1),
+            }],
+            Some(accounts(0)),
+        );
+        assert_eq!(
+            contract.get_pool(0).amounts[0].0,
+            pool_amount + one_near / 2
+        );
+    }
+
+    #[test]
+    fn test_transfer_and_swap_with_withdraw() {
+        let one_near = 10u128.pow(24);
+        let mut context = VMContextBuilder::new();
+        let mut contract = setup_route(&mut context);
+        let amount_mid = contract.get_return(0, accounts(1), one_near.into(), accounts(2));
+        let expected_out = contract.get_return(1, accounts(2), amount_mid, accounts(4));
+        let balance_out = contract.get_token_balance(accounts(4)).0;
+
+        // Sender is not registered, the output and the unused part are sent back right away.
+        testing_env!(context.predecessor_account_id(accounts(1)).build());
+        let msg = serde_json::json!({
+            "actions": [
+                {
+                    "pool_id": 0,
+                    "token_in": accounts(1),
+                    "amount_in": U128(one_near),
+                    "token_out": accounts(2),
+                    "min_amount_out": U128(1),
+                },
+                {
+                    "pool_id": 1,
+                    "token_in": accounts(2),
+                    "token_out": accounts(4),
+                    "min_amount_out": expected_out,
+                },
+            ],
+            "withdraw": true,
+        })
+        .to_string();
+        match contract.ft_on_transfer(accounts(0), (2 * one_near).into(), msg) {
+            PromiseOrValue::Value(unused_amount) => assert_eq!(unused_amount.0, one_near),
+            _ => panic!("expected value"),
+        }
+        assert!(contract.storage_balance_of(accounts(0)).is_none());
+
+        // Output is accounted until its transfer succeeds.
+        assert_eq!(contract.get_token_balance(accounts(4)).0, balance_out);
+        context.predecessor_account_id(env::current_account_id());
+        testing_env_with_promise_results(context.build(), PromiseResult::Successful(vec![]));
+        contract.on_withdraw(accounts(4), accounts(0), expected_out);
+        assert_eq!(
+            contract.get_token_balance(accounts(4)).0,
+            balance_out - expected_out.0
+        );
+    }
+
+    #[test]
+    fn test_swap_near() {
+        let one_near = 10u128.pow(24);
+        let mut context = VMContextBuilder::new();
+        let mut contract =
+            setup_contract(&mut context, vec![accounts(1), accounts(2), accounts(4)]);
+        contract.set_wnear_id(accounts(1));
+        testing_env!(context
+            .predecessor_account_id(accounts(3))
+            .attached_deposit(env::storage_byte_cost() * 500)
+            .build());
+        contract.add_simple_pool(vec![accounts(1), accounts(2)], 30);
+        testing_env!(context
+            .attached_deposit(
+                contract.storage_balance_bounds().min.0 + env::storage_byte_cost() * 1000
+            )
+            .build());
+        contract.storage_deposit(None, None);
+        contract.register_tokens(vec![accounts(1), accounts(2), accounts(4)]);
+        testing_env!(context
+            .predecessor_account_id(accounts(1))
+            .attached_deposit(1)
+            .build());
+        contract.ft_on_transfer(accounts(3), (10 * one_near).into(), "".to_string());
+        testing_env!(context.predecessor_account_id(accounts(2)).build());
+        contract.ft_on_transfer(accounts(3), (10 * one_near).into(), "".to_string());
+        testing_env!(context.predecessor_account_id(accounts(3)).build());
+        contract.add_liquidity(0, vec![U128(10 * one_near), U128(10 * one_near)], U128(0));
+        let action = |token_in: AccountId, token_out: AccountId| SwapAction {
+            pool_id: 0,
+            token_in,
+            amount_in: Some(one_near.into()),
+            token_out,
+            min_amount_out: U128(1),
+        };
+
+        // Attached NEAR is credited only once it's wrapped, and refunded if wrapping fails.
+        let amount_out = contract.get_return(0, accounts(1), one_near.into(), accounts(2));
+        testing_env!(context.attached_deposit(one_near).build());
+        contract.swap_near(vec![action(accounts(1), accounts(2))], None);
+        assert_eq!(
+            contract.get_pool(0).amounts,
+            vec![U128(10 * one_near), U128(10 * one_near)]
+        );
+        assert!(contract.transfers_in_flight.contains_key(&accounts(3)));
+        context
+            .predecessor_account_id(accounts(0))
+            .attached_deposit(0);
+        testing_env_with_promise_results(context.build(), PromiseResult::Failed);
+        contract.on_near_deposit(
+            accounts(3),
+            one_near.into(),
+            vec![action(accounts(1), accounts(2))],
+            None,
+        );
+        assert!(!contract.transfers_in_flight.contains_key(&accounts(3)));
+        assert_eq!(contract.get_deposit(&accounts(3), &accounts(1)), U128(0));
+
+        // Wrapped NEAR is swapped and the output is sent out, nothing stays in the deposits.
+        testing_env_with_promise_results(context.build(), PromiseResult::Successful(vec![]));
+        contract.on_near_deposit(
+            accounts(3),
+            one_near.into(),
+            vec![action(accounts(1), accounts(2))],
+            None,
+        );
+        assert_eq!(
+            contract.get_deposit(&accounts(3), &accounts(1)),
+            U128(one_near)
+        );
+        testing_env!(context.build());
+        contract.swap_near_from_deposits(accounts(3), vec![action(accounts(1), accounts(2))], None);
+        assert!(contract
+            .get_deposits(&accounts(3))
+            .values()
+            .all(|amount| amount.0 == 0));
+        assert_eq!(
+            contract.get_pool(0).amounts,
+            vec![U128(11 * one_near), U128(10 * one_near - amount_out.0)]
+        );
+
+        // Swap into wrapped NEAR returns it to the deposits if unwrapping fails.
+        testing_env!(context.predecessor_account_id(accounts(2)).build());
+        contract.ft_on_transfer(accounts(3), one_near.into(), "".to_string());
+        testing_env!(context
+            .predecessor_account_id(accounts(3))
+            .attached_deposit(1)
+            .build());
+        let amount_out = contract.get_return(0, accounts(2), one_near.into(), accounts(1));
+        contract.swap_near(vec![action(accounts(2), accounts(1))], None);
+        assert!(contract
+            .get_deposits(&accounts(3))
+            .values()
+            .all(|amount| amount.0 == 0));
+        context.predecessor_account_id(accounts(0));
+        testing_env_with_promise_results(context.build(), PromiseResult::Failed);
+        contract.on_near_withdraw(accounts(3), amount_out);
+        assert_eq!(contract.get_deposit(&accounts(3), &accounts(1)), amount_out);
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_NOT_WNEAR")]
+    fn test_deny_swap_near_from_other_token() {
+        let mut context = VMContextBuilder::new();
+        let mut contract =
+            setup_contract(&mut context, vec![accounts(1), accounts(2), accounts(4)]);
+        contract.set_wnear_id(accounts(1));
+        testing_env!(context
+            .predecessor_account_id(accounts(3))
+            .attached_deposit(10)
+            .build());
+        contract.swap_near(
+            vec![SwapAction {
+                pool_id: 0,
+                token_in: accounts(2),
+                amount_in: Some(U128(10)),
+                token_out: accounts(1),
+                min_amount_out: U128(1),
+            }],
+            None,
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "Requires attached deposit of exactly 1 yoctoNEAR")]
+    fn test_deny_swap_near_from_deposits_without_yocto() {
+        let mut context = VMContextBuilder::new();
+        let mut contract =
+            setup_contract(&mut context, vec![accounts(1), accounts(2), accounts(4)]);
+        contract.set_wnear_id(accounts(1));
+        testing_env!(context
+            .predecessor_account_id(accounts(3))
+            .attached_deposit(0)
+            .build());
+        contract.swap_near(
+            vec![SwapAction {
+                pool_id: 0,
+                token_in: accounts(2),
+                amount_in: Some(U128(10)),
+                token_out: accounts(1),
+                min_amount_out: U128(1),
+            }],
+            None,
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_NO_DEPOSIT")]
+    fn test_deny_swap_near_unregistered() {
+        let mut context = VMContextBuilder::new();
+        let mut contract =
+            setup_contract(&mut context, vec![accounts(1), accounts(2), accounts(4)]);
+        contract.set_wnear_id(accounts(1));
+        testing_env!(context
+            .predecessor_account_id(accounts(3))
+            .attached_deposit(10)
+            .build());
+        contract.swap_near(
+            vec![SwapAction {
+                pool_id: 0,
+                token_in: accounts(1),
+                amount_in: Some(U128(10)),
+                token_out: accounts(2),
+                min_amount_out: U128(1),
+            }],
+            None,
+        );
+    }
+
+    #[test]
+    fn test_measured_deposit() {
+        let mut context = VMContextBuilder::new();
+        let mut contract =
+            setup_contract(&mut context, vec![accounts(1), accounts(2), accounts(4)]);
+        contract.add_measured_token(accounts(1));
+        contract.on_measured_token_balance(accounts(1), U128(10));
+        testing_env!(context
+            .predecessor_account_id(accounts(3))
+            .attached_deposit(contract.storage_balance_bounds().min.0)
+            .build());
+        contract.storage_deposit(None, None);
+        contract.register_tokens(vec![accounts(1), accounts(2), accounts(4)]);
+
+        // Token takes 5% on transfer, only the received amount is credited.
+        testing_env!(context.predecessor_account_id(accounts(1)).build());
+        match contract.ft_on_transfer(accounts(3), U128(100), "".to_string()) {
+            PromiseOrValue::Promise(_) => {}
+            _ => panic!("expected promise"),
+        }
+        testing_env!(context.predecessor_account_id(accounts(0)).build());
+        contract.on_measured_deposit(accounts(3), accounts(1), U128(100), U128(105));
+        assert_eq!(contract.get_deposit(&accounts(3), &accounts(1)), U128(95));
+
+        // Concurrent deposits are credited no more than the contract received in total.
+        contract.on_measured_deposit(accounts(3), accounts(1), U128(100), U128(295));
+        contract.on_measured_deposit(accounts(3), accounts(1), U128(100), U128(295));
+        assert_eq!(contract.get_deposit(&accounts(3), &accounts(1)), U128(285));
+        assert_eq!(contract.get_measured_balance(accounts(1)), Some(U128(295)));
+
+        // Withdrawals are subtracted from the measured balance.
+        testing_env!(context
+            .predecessor_account_id(accounts(3))
+            .attached_deposit(1)
+            .build());
+        contract.withdraw(accounts(1), U128(85));
+        assert_eq!(contract.get_measured_balance(accounts(1)), Some(U128(210)));
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_TOKEN_NOT_WHITELISTED")]
+    fn test_deny_pool_with_not_whitelisted_token() {
+        let mut context = VMContextBuilder::new();
+        let mut contract = setup_contract(&mut context, vec![accounts(1)]);
+        testing_env!(context
+            .predecessor_account_id(accounts(3))
+            .attached_deposit(env::storage_byte_cost() * 500)
+            .build());
+        contract.add_simple_pool(vec![accounts(1), accounts(2)], 30);
+    }
+
+    #[test]
+    fn test_token_metadata() {
+        let mut context = VMContextBuilder::new();
+        let mut contract = setup_contract(&mut context, vec![accounts(1), accounts(2)]);
+        testing_env!(context
+            .predecessor_account_id(accounts(3))
+            .attached_deposit(env::storage_byte_cost() * 500)
+            .build());
+        contract.add_simple_pool(vec![accounts(1), accounts(2)], 30);
+        assert_eq!(contract.get_token_metadata(accounts(1)), None);
+        assert_eq!(contract.get_pool(0).token_metadata, vec![None, None]);
+
+        context.predecessor_account_id(env::current_account_id());
+        testing_env_with_promise_results(
+            context.build(),
+            PromiseResult::Successful(
+                br#"{"spec":"ft-1.0.0","name":"Bob","symbol":"BOB","decimals":18}"#.to_vec(),
+            ),
+        );
+        contract.on_token_metadata(accounts(1));
+        let metadata = TokenMetadata {
+            symbol: "BOB".to_string(),
+            decimals: 18,
+        };
+        assert_eq!(
+            contract.get_token_metadata(accounts(1)),
+            Some(metadata.clone())
+        );
+        assert_eq!(
+            contract.get_pool(0).token_metadata,
+            vec![Some(metadata.clone()), None]
+        );
+
+        // Failed refresh keeps the cached metadata.
+        testing_env_with_promise_results(context.build(), PromiseResult::Failed);
+        contract.on_token_metadata(accounts(1));
+        assert_eq!(contract.get_token_metadata(accounts(1)), Some(metadata));
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_TOKEN_NOT_WHITELISTED")]
+    fn test_deny_refresh_metadata_of_not_whitelisted_token() {
+        let mut context = VMContextBuilder::new();
+        let mut contract = setup_contract(&mut context, vec![accounts(1)]);
+        contract.refresh_token_metadata(accounts(2));
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_TOKEN_NOT_WHITELISTED")]
+    fn test_deny_deposit_of_removed_token() {
+        let mut context = VMContextBuilder::new();
+        let mut contract = setup_contract(&mut context, vec![accounts(1), accounts(2)]);
+        contract.remove_whitelisted_token(accounts(2));
+        assert_eq!(contract.get_whitelisted_tokens(), vec![accounts(1)]);
+        testing_env!(context
+            .predecessor_account_id(accounts(3))
+            .attached_deposit(contract.storage_balance_bounds().min.0)
+            .build());
+        contract.storage_deposit(None, None);
+        contract.register_tokens(vec![accounts(1), accounts(2), accounts(4)]);
+        testing_env!(context.predecessor_account_id(accounts(2)).build());
+        contract.ft_on_transfer(accounts(3), U128(100), "".to_string());
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_TOKEN_NOT_REGISTERED")]
+    fn test_deny_deposit_of_not_registered_token() {
+        let mut context = VMContextBuilder::new();
+        let mut contract = setup_contract(&mut context, vec![accounts(1), accounts(2)]);
+        testing_env!(context
+            .predecessor_account_id(accounts(3))
+            .attached_deposit(contract.storage_balance_bounds().min.0)
+            .build());
+        contract.storage_deposit(None, None);
+        contract.register_tokens(vec![accounts(1)]);
+        testing_env!(context.predecessor_account_id(accounts(2)).build());
+        contract.ft_on_transfer(accounts(3), U128(100), "".to_string());
+    }
+
+    #[test]
+    fn test_sync_and_skim() {
+        let one_near = 10u128.pow(24);
+        let mut context = VMContextBuilder::new();
+        let mut contract = setup_contract(&mut context, vec![accounts(1), accounts(2)]);
+        testing_env!(context
+            .predecessor_account_id(accounts(3))
+            .attached_deposit(env::storage_byte_cost() * 500)
+            .build());
+        contract.add_simple_pool(vec![accounts(1), accounts(2)], 30);
+        testing_env!(context
+            .attached_deposit(
+                contract.storage_balance_bounds().min.0 + env::storage_byte_cost() * 1000
+            )
+            .build());
+        contract.storage_deposit(None, None);
+        contract.register_tokens(vec![accounts(1), accounts(2)]);
+        testing_env!(context
+            .predecessor_account_id(accounts(1))
+            .attached_deposit(1)
+            .build());
+        contract.ft_on_transfer(accounts(3), (10 * one_near).into(), "".to_string());
+        testing_env!(context.predecessor_account_id(accounts(2)).build());
+        contract.ft_on_transfer(accounts(3), (10 * one_near).into(), "".to_string());
+        testing_env!(context.predecessor_account_id(accounts(3)).build());
+        contract.add_liquidity(0, vec![U128(10 * one_near), U128(10 * one_near)], U128(0));
+        assert_eq!(contract.get_token_balance(accounts(1)), U128(10 * one_near));
+
+        // Tokens sent directly to the contract are synced into the pool.
+        testing_env!(context.predecessor_account_id(accounts(0)).build());
+        contract.sync(0);
+        contract.on_sync_balance(0, accounts(1), U128(11 * one_near));
+        contract.on_sync_balance(0, accounts(2), U128(10 * one_near));
+        assert_eq!(
+            contract.get_pool(0).amounts,
+            vec![U128(11 * one_near), U128(10 * one_near)]
+        );
+        assert_eq!(contract.get_token_balance(accounts(1)), U128(11 * one_near));
+
+        // Skimming sends the excess out and doesn't touch accounted balances.
+        contract.skim(0, accounts(0));
+        contract.on_skim_balance(accounts(2), accounts(0), U128(12 * one_near));
+        assert_eq!(contract.get_token_balance(accounts(2)), U128(12 * one_near));
+        contract.on_excess_transfer(accounts(2), U128(2 * one_near));
+        assert_eq!(contract.get_token_balance(accounts(2)), U128(10 * one_near));
+        assert_eq!(
+            contract.get_pool(0).amounts,
+            vec![U128(11 * one_near), U128(10 * one_near)]
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_NOT_ENOUGH_EXCESS")]
+    fn test_deny_rescue_of_deposited_tokens() {
+        let mut context = VMContextBuilder::new();
+        let mut contract = setup_contract(&mut context, vec![accounts(1)]);
+        testing_env!(context
+            .predecessor_account_id(accounts(3))
+            .attached_deposit(contract.storage_balance_bounds().min.0)
+            .build());
+        contract.storage_deposit(None, None);
+        contract.register_tokens(vec![accounts(1)]);
+        testing_env!(context.predecessor_account_id(accounts(1)).build());
+        contract.ft_on_transfer(accounts(3), U128(100), "".to_string());
+        testing_env!(context
+            .predecessor_account_id(accounts(0))
+            .attached_deposit(1)
+            .build());
+        contract.rescue_token(accounts(1), U128(20), accounts(0));
+        // 10 tokens were sent directly, the rest belongs to the deposits.
+        contract.on_rescue_balance(accounts(1), U128(10), accounts(0), U128(110));
+        // Concurrent rescue can't take the same tokens while the first transfer is in flight.
+        contract.on_rescue_balance(accounts(1), U128(1), accounts(0), U128(110));
+    }
+
+    #[test]
+    fn test_get_deposits() {
+        let one_near = 10u128.pow(24);
+        let mut context = VMContextBuilder::new();
+        let contract = setup_route(&mut context);
+        assert_eq!(
+            contract.get_deposits(&accounts(5)),
+            vec![(accounts(1), U128(one_near)), (accounts(4), U128(0))]
+                .into_iter()
+                .collect()
+        );
+        assert!(contract.get_deposits(&accounts(0)).is_empty());
+    }
+
+    #[test]
+    fn test_withdraw_all() {
+        let mut context = VMContextBuilder::new();
+        let mut contract = setup_contract(&mut context, vec![accounts(1), accounts(2)]);
+        testing_env!(context
+            .predecessor_account_id(accounts(3))
+            .attached_deposit(contract.storage_balance_bounds().min.0)
+            .build());
+        contract.storage_deposit(None, None);
+        contract.register_tokens(vec![accounts(1), accounts(2)]);
+        testing_env!(context.predecessor_account_id(accounts(1)).build());
+        contract.ft_on_transfer(accounts(3), U128(100), "".to_string());
+        testing_env!(context.predecessor_account_id(accounts(2)).build());
+        contract.ft_on_transfer(accounts(3), U128(50), "".to_string());
+        testing_env!(context
+            .predecessor_account_id(accounts(3))
+            .attached_deposit(1)
+            .build());
+        contract.withdraw_all(vec![accounts(1), accounts(2)]);
+        assert!(contract
+            .get_deposits(&accounts(3))
+            .values()
+            .all(|amount| amount.0 == 0));
+
+        // Failed transfer of one token returns only that token.
+        context
+            .predecessor_account_id(accounts(0))
+            .attached_deposit(0);
+        testing_env_with_promise_results(context.build(), PromiseResult::Failed);
+        contract.on_withdraw(accounts(2), accounts(3), U128(50));
+        assert_eq!(contract.get_deposit(&accounts(3), &accounts(2)), U128(50));
+        assert_eq!(contract.get_deposit(&accounts(3), &accounts(1)), U128(0));
+    }
+
+    #[test]
+    fn test_storage_management() {
+        let one_near = 10u128.pow(24);
+        let mut context = VMContextBuilder::new();
+        let mut contract =
+            setup_contract(&mut context, vec![accounts(1), accounts(2), accounts(4)]);
+        testing_env!(context
+            .predecessor_account_id(accounts(3))
+            .attached_deposit(env::storage_byte_cost() * 500)
+            .build());
+        contract.add_simple_pool(vec![accounts(1), accounts(2)], 30);
+        let min_balance = contract.storage_balance_bounds().min.0;
+        let extra = env::storage_byte_cost() * 1000;
+        testing_env!(context.attached_deposit(min_balance + extra).build());
+        let balance = contract.storage_deposit(None, Some(true));
+        assert_eq!(balance.total, U128(min_balance));
+        assert_eq!(balance.available, U128(0));
+        let balance = contract.storage_deposit(None, None);
+        assert_eq!(balance.total, U128(2 * min_balance + extra));
+        assert_eq!(balance.available, U128(min_balance + extra));
+        contract.register_tokens(vec![accounts(1), accounts(2)]);
+
+        // Liquidity shares take storage from the available balance.
+        testing_env!(context
+            .predecessor_account_id(accounts(1))
+            .attached_deposit(1)
+            .build());
+        contract.ft_on_transfer(accounts(3), one_near.into(), "".to_string());
+        testing_env!(context.predecessor_account_id(accounts(2)).build());
+        contract.ft_on_transfer(accounts(3), one_near.into(), "".to_string());
+        testing_env!(context.predecessor_account_id(accounts(3)).build());
+        contract.add_liquidity(0, vec![U128(one_near), U128(one_near)], U128(0));
+        let available = contract
+            .storage_balance_of(accounts(3))
+            .unwrap()
+            .available
+            .0;
+        assert!(available < min_balance + extra);
+        let balance = contract.storage_withdraw(None);
+        assert_eq!(balance.total, U128(2 * min_balance + extra - available));
+        assert_eq!(balance.available, U128(0));
+
+        // Removing liquidity releases the storage.
+        let shares = contract.get_pool_shares(0, accounts(3));
+        contract.remove_liquidity(0, shares, vec![U128(1), U128(1)]);
+        assert_eq!(
+            contract.storage_balance_of(accounts(3)).unwrap().available,
+            U128(min_balance + extra - available)
+        );
+        assert!(contract.storage_unregister(Some(true)));
+        assert!(contract.storage_balance_of(accounts(3)).is_none());
+        assert!(!contract.storage_unregister(None));
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_NON_ZERO_DEPOSITS")]
+    fn test_deny_unregister_with_deposits() {
+        let mut context = VMContextBuilder::new();
+        let mut contract =
+            setup_contract(&mut context, vec![accounts(1), accounts(2), accounts(4)]);
+        testing_env!(context
+            .predecessor_account_id(accounts(3))
+            .attached_deposit(contract.storage_balance_bounds().min.0)
+            .build());
+        contract.storage_deposit(None, None);
+        contract.register_tokens(vec![accounts(1), accounts(2), accounts(4)]);
+        testing_env!(context.predecessor_account_id(accounts(1)).build());
+        contract.ft_on_transfer(accounts(3), U128(100), "".to_string());
+        testing_env!(context
+            .predecessor_account_id(accounts(3))
+            .attached_deposit(1)
+            .build());
+        contract.storage_unregister(None);
+    }
+
+    #[test]
+    fn test_retire_pool() {
+        let mut context = VMContextBuilder::new();
+        let mut contract = setup_route(&mut context);
+        testing_env!(context
+            .predecessor_account_id(accounts(3))
+            .attached_deposit(env::storage_byte_cost() * 500)
+            .build());
+        contract.add_simple_pool(vec![accounts(1), accounts(2)], 5);
+        testing_env!(context
+            .predecessor_account_id(accounts(0))
+            .attached_deposit(1)
+            .build());
+        let prev_storage = env::storage_usage();
+        contract.retire_pool(2);
+        assert!(env::storage_usage() < prev_storage);
+        let pool = contract.get_pool(2);
+        assert_eq!(pool.pool_kind, "RETIRED");
+        assert_eq!(pool.amounts, vec![U128(0), U128(0)]);
+        assert_eq!(
+            contract.get_fee_tiers(vec![accounts(1), accounts(2)]),
+            vec![0]
+        );
+        assert_eq!(contract.get_number_of_pools(), 3);
+
+        // The fee tier can be added again, under a new id.
+        testing_env!(context
+            .predecessor_account_id(accounts(3))
+            .attached_deposit(env::storage_byte_cost() * 500)
+            .build());
+        assert_eq!(
+            contract.add_simple_pool(vec![accounts(1), accounts(2)], 5),
+            3
+        );
+        assert_eq!(
+            contract.get_fee_tiers(vec![accounts(1), accounts(2)]),
+            vec![0, 3]
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_POOL_NOT_EMPTY")]
+    fn test_deny_retire_pool_with_locked_liquidity() {
+        let mut context = VMContextBuilder::new();
+        let mut contract = setup_route(&mut context);
+        testing_env!(context
+            .predecessor_account_id(accounts(3))
+            .attached_deposit(0)
+            .build());
+        let shares = contract.get_pool_shares(0, accounts(3));
+        contract.remove_liquidity(0, shares, vec![U128(1), U128(1)]);
+        // The locked minimum liquidity and its tokens stay in the pool.
+        assert_eq!(contract.get_pool_total_shares(0), U128(MIN_LIQUIDITY));
+        testing_env!(context
+            .predecessor_account_id(accounts(0))
+            .attached_deposit(1)
+            .build());
+        contract.retire_pool(0);
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_SHARES_BELOW_MIN_LIQUIDITY")]
+    fn test_deny_first_deposit_below_min_liquidity() {
+        let mut context = VMContextBuilder::new();
+        let mut contract = setup_contract(&mut context, vec![accounts(1), accounts(2)]);
+        testing_env!(context
+            .predecessor_account_id(accounts(3))
+            .attached_deposit(env::storage_byte_cost() * 500)
+            .build());
+        contract.add_stable_swap_pool(vec![accounts(1), accounts(2)], vec![24, 24], 25, 100);
+        testing_env!(context
+            .attached_deposit(
+                contract.storage_balance_bounds().min.0 + env::storage_byte_cost() * 1000
+            )
+            .build());
+        contract.storage_deposit(None, None);
+        contract.register_tokens(vec![accounts(1), accounts(2)]);
+        for token_id in [accounts(1), accounts(2)] {
+            testing_env!(context
+                .predecessor_account_id(token_id)
+                .attached_deposit(1)
+                .build());
+            contract.ft_on_transfer(accounts(3), U128(100), "".to_string());
+        }
+        testing_env!(context.predecessor_account_id(accounts(3)).build());
+        // Stable swap pools mint as many shares as the deposit is worth, fewer than the locked minimum here.
+        contract.add_liquidity(0, vec![U128(100), U128(100)], U128(0));
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_POOL_NOT_EMPTY")]
+    fn test_deny_retire_pool_with_liquidity() {
+        let mut context = VMContextBuilder::new();
+        let mut contract = setup_route(&mut context);
+        testing_env!(context.predecessor_account_id(accounts(0)).build());
+        contract.retire_pool(1);
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_POOL_RETIRED")]
+    fn test_deny_add_liquidity_to_retired_pool() {
+        let mut context = VMContextBuilder::new();
+        let mut contract = setup_contract(&mut context, vec![accounts(1), accounts(2)]);
+        testing_env!(context
+            .predecessor_account_id(accounts(3))
+            .attached_deposit(env::storage_byte_cost() * 500)
+            .build());
+        contract.add_simple_pool(vec![accounts(1), accounts(2)], 30);
+        testing_env!(context
+            .predecessor_account_id(accounts(0))
+            .attached_deposit(1)
+            .build());
+        contract.retire_pool(0);
+        testing_env!(context
+            .predecessor_account_id(accounts(3))
+            .attached_deposit(1)
+            .build());
+        contract.add_liquidity(0, vec![U128(1), U128(1)], U128(0));
+    }
+
+    #[test]
+    fn test_pools_by_tokens() {
+        let mut context = VMContextBuilder::new();
+        let mut contract = setup_route(&mut context);
+        testing_env!(context
+            .predecessor_account_id(accounts(3))
+            .attached_deposit(env::storage_byte_cost() * 1000)
+            .build());
+        contract.add_simple_pool(vec![accounts(4), accounts(2), accounts(1)], 30);
+        assert_eq!(
+            contract.get_pools_by_tokens(accounts(2), accounts(1)),
+            vec![0, 2]
+        );
+        assert_eq!(
+            contract.get_pools_by_tokens(accounts(2), accounts(4)),
+            vec![1, 2]
+        );
+        assert_eq!(
+            contract.get_pools_by_tokens(accounts(1), accounts(4)),
+            vec![2]
+        );
+        testing_env!(context
+            .predecessor_account_id(accounts(0))
+            .attached_deposit(1)
+            .build());
+        contract.retire_pool(2);
+        assert!(contract
+            .get_pools_by_tokens(accounts(1), accounts(4))
+            .is_empty());
+        assert_eq!(
+            contract.get_pools_by_tokens(accounts(1), accounts(2)),
+            vec![0]
+        );
+    }
+
+    #[test]
+    fn test_pools_with_token() {
+        let mut context = VMContextBuilder::new();
+        let mut contract = setup_route(&mut context);
+        testing_env!(context
+            .predecessor_account_id(accounts(3))
+            .attached_deposit(env::storage_byte_cost() * 1000)
+            .build());
+        contract.add_simple_pool(vec![accounts(4), accounts(2)], 5);
+        assert_eq!(
+            contract.get_pools_with_token(accounts(2), 0, 10),
+            vec![0, 1, 2]
+        );
+        assert_eq!(contract.get_pools_with_token(accounts(2), 1, 1), vec![1]);
+        assert_eq!(
+            contract.get_pools_with_token(accounts(4), 0, 10),
+            vec![1, 2]
+        );
+        assert!(contract.get_pools_with_token(accounts(5), 0, 10).is_empty());
+        testing_env!(context
+            .predecessor_account_id(accounts(0))
+            .attached_deposit(1)
+            .build());
+        contract.retire_pool(2);
+        assert_eq!(contract.get_pools_with_token(accounts(4), 0, 10), vec![1]);
+    }
+
+    #[test]
+    fn test_pool_creation_deposit() {
+        let one_near = 10u128.pow(24);
+        let mut context = VMContextBuilder::new();
+        let mut contract = setup_contract(&mut context, vec![accounts(1), accounts(2)]);
+        contract.set_pool_creation_deposit(U128(one_near));
+        testing_env!(context
+            .predecessor_account_id(accounts(3))
+            .attached_deposit(env::storage_byte_cost() * 500 + one_near)
+            .build());
+        contract.add_simple_pool(vec![accounts(1), accounts(2)], 30);
+        testing_env!(context
+            .predecessor_account_id(accounts(0))
+            .attached_deposit(1)
+            .build());
+        contract.retire_pool(0);
+        assert_eq!(contract.get_pool(0).pool_kind, "RETIRED");
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_STORAGE_DEPOSIT")]
+    fn test_deny_pool_without_creation_deposit() {
+        let one_near = 10u128.pow(24);
+        let mut context = VMContextBuilder::new();
+        let mut contract = setup_contract(&mut context, vec![accounts(1), accounts(2)]);
+        contract.set_pool_creation_deposit(U128(one_near));
+        testing_env!(context
+            .predecessor_account_id(accounts(3))
+            .attached_deposit(env::storage_byte_cost() * 500)
+            .build());
+        contract.add_simple_pool(vec![accounts(1), accounts(2)], 30);
+    }
+
+    #[test]
+    fn test_allow_listed_pool_creation() {
+        let mut context = VMContextBuilder::new();
+        let mut contract = setup_contract(&mut context, vec![accounts(1), accounts(2)]);
+        contract.set_permissionless_pool_creation(false);
+        contract.add_pool_creator(accounts(3));
+        assert!(!contract.is_permissionless_pool_creation());
+        assert_eq!(contract.get_pool_creators(), vec![accounts(3)]);
+        testing_env!(context
+            .predecessor_account_id(accounts(3))
+            .attached_deposit(env::storage_byte_cost() * 500)
+            .build());
+        assert_eq!(
+            contract.add_simple_pool(vec![accounts(1), accounts(2)], 30),
+            0
+        );
+    }
+
+    #[test]
+    fn test_allow_listed_pool_creation_by_dao() {
+        let mut context = VMContextBuilder::new();
+        let mut contract = setup_contract(&mut context, vec![accounts(1), accounts(2)]);
+        contract.set_permissionless_pool_creation(false);
+        contract.set_dao(Some(accounts(5)));
+        testing_env!(context
+            .predecessor_account_id(accounts(5))
+            .attached_deposit(env::storage_byte_cost() * 500)
+            .build());
+        assert_eq!(
+            contract.add_simple_pool(vec![accounts(1), accounts(2)], 30),
+            0
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_NOT_POOL_CREATOR")]
+    fn test_deny_pool_by_not_approved_creator() {
+        let mut context = VMContextBuilder::new();
+        let mut contract = setup_contract(&mut context, vec![accounts(1), accounts(2)]);
+        contract.set_permissionless_pool_creation(false);
+        testing_env!(context
+            .predecessor_account_id(accounts(3))
+            .attached_deposit(env::storage_byte_cost() * 500)
+            .build());
+        contract.add_simple_pool(vec![accounts(1), accounts(2)], 30);
+    }
+
+    #[test]
+    fn test_migrate_liquidity() {
+        let one_near = 10u128.pow(24);
+        let mut context = VMContextBuilder::new();
+        let mut contract = setup_route(&mut context);
+        testing_env!(context
+            .predecessor_account_id(accounts(1))
+            .attached_deposit(1)
+            .build());
+        contract.ft_on_transfer(accounts(3), one_near.into(), "".to_string());
+        testing_env!(context
+            .predecessor_account_id(accounts(3))
+            .attached_deposit(env::storage_byte_cost() * 500)
+            .build());
+        swap_token_1(&mut contract, one_near);
+        // Same tokens in the other order, with a lower fee.
+        contract.add_simple_pool(vec![accounts(2), accounts(1)], 5);
+        testing_env!(context.attached_deposit(0).build());
+        let amounts = contract.get_pool(0).amounts;
+        let shares = contract.get_pool_shares(0, accounts(3));
+        let minted_shares =
+            contract.migrate_liquidity(0, 2, shares, vec![U128(1), U128(1)], U128(1));
+        assert_eq!(contract.get_pool_shares(0, accounts(3)), U128(0));
+        assert_eq!(contract.get_pool_shares(2, accounts(3)), minted_shares);
+        // Tokens of the minimum liquidity locked in the old pool stay there.
+        let locked_amounts = contract.get_pool(0).amounts;
+        assert_eq!(
+            contract.get_pool(2).amounts,
+            vec![
+                U128(amounts[1].0 - locked_amounts[1].0),
+                U128(amounts[0].0 - locked_amounts[0].0)
+            ]
+        );
+        assert_eq!(contract.get_deposit(&accounts(3), &accounts(1)), U128(0));
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_INCOMPATIBLE_POOLS")]
+    fn test_deny_migrate_liquidity_to_other_tokens() {
+        let mut context = VMContextBuilder::new();
+        let mut contract = setup_route(&mut context);
+        testing_env!(context
+            .predecessor_account_id(accounts(3))
+            .attached_deposit(0)
+            .build());
+        let shares = contract.get_pool_shares(0, accounts(3));
+        contract.migrate_liquidity(0, 1, shares, vec![U128(1), U128(1)], U128(0));
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_MIN_SHARES")]
+    fn test_deny_migrate_liquidity_below_min_shares() {
+        let mut context = VMContextBuilder::new();
+        let mut contract = setup_route(&mut context);
+        testing_env!(context
+            .predecessor_account_id(accounts(3))
+            .attached_deposit(env::storage_byte_cost() * 500)
+            .build());
+        contract.add_simple_pool(vec![accounts(1), accounts(2)], 5);
+        testing_env!(context.attached_deposit(0).build());
+        let shares = contract.get_pool_shares(0, accounts(3));
+        contract.migrate_liquidity(0, 2, shares, vec![U128(1), U128(1)], U128(u128::MAX));
+    }
+
+    #[test]
+    fn test_fee_tiers() {
+        let one_near = 10u128.pow(24);
+        let mut context = VMContextBuilder::new();
+        let mut contract =
+            setup_contract(&mut context, vec![accounts(1), accounts(2), accounts(4)]);
+        testing_env!(context
+            .predecessor_account_id(accounts(3))
+            .attached_deposit(env::storage_byte_cost() * 500)
+            .build());
+        for fee in [5, 30, 100].iter() {
+            contract.add_simple_pool(vec![accounts(1), accounts(2)], *fee);
+        }
+        assert_eq!(
+            contract.get_fee_tiers(vec![accounts(2), accounts(1)]),
+            vec![0, 1, 2]
+        );
+        testing_env!(context
+            .attached_deposit(
+                contract.storage_balance_bounds().min.0 + env::storage_byte_cost() * 1500
+            )
+            .build());
+        contract.storage_deposit(None, None);
+        contract.register_tokens(vec![accounts(1), accounts(2), accounts(4)]);
+        testing_env!(context
+            .predecessor_account_id(accounts(1))
+            .attached_deposit(1)
+            .build());
+        contract.ft_on_transfer(accounts(3), (20 * one_near).into(), "".to_string());
+        testing_env!(context.predecessor_account_id(accounts(2)).build());
+        contract.ft_on_transfer(accounts(3), (30 * one_near).into(), "".to_string());
+        testing_env!(context.predecessor_account_id(accounts(3)).build());
+        // Lowest fee tier has much less liquidity, so for large trade it gives worse price.
+        contract.add_liquidity(0, vec![U128(one_near), U128(one_near)], U128(0));
+        contract.add_liquidity(1, vec![U128(10 * one_near), U128(10 * one_near)], U128(0));
+        contract.add_liquidity(2, vec![U128(5 * one_near), U128(5 * one_near)], U128(0));
+        let best = contract
+            .get_best_fee_tier(accounts(1), one_near.into(), accounts(2))
+            .unwrap();
+        assert_eq!(best.pool_id, 1);
+        assert_eq!(
+            best.amount_out,
+            contract.get_return(1, accounts(1), one_near.into(), accounts(2))
+        );
+        let best = contract
+            .get_best_fee_tier(accounts(1), (one_near / 1000).into(), accounts(2))
+            .unwrap();
+        assert_eq!(best.pool_id, 0);
+    }
+
+    /// Should deny creating a pool of the same kind with the same tokens and fee.
+    #[test]
+    #[should_panic(expected = "ERR_FEE_TIER_EXISTS")]
+    fn test_deny_duplicate_fee_tier() {
+        let mut context = VMContextBuilder::new();
+        let mut contract =
+            setup_contract(&mut context, vec![accounts(1), accounts(2), accounts(4)]);
+        testing_env!(context
+            .predecessor_account_id(accounts(3))
+            .attached_deposit(env::storage_byte_cost() * 500)
+            .build());
+        contract.add_simple_pool(vec![accounts(1), accounts(2)], 30);
+        contract.add_simple_pool(vec![accounts(2), accounts(1)], 30);
+    }
+
+    /// Pools of the same kind and fee over the same tokens can differ in other parameters.
+    #[test]
+    fn test_fee_tiers_with_different_parameters() {
+        let mut context = VMContextBuilder::new();
+        let mut contract =
+            setup_contract(&mut context, vec![accounts(1), accounts(2), accounts(4)]);
+        testing_env!(context
+            .predecessor_account_id(accounts(3))
+            .attached_deposit(env::storage_byte_cost() * 1000)
+            .build());
+        contract.add_weighted_pool(vec![accounts(1), accounts(2)], vec![80, 20], 30);
+        contract.add_weighted_pool(vec![accounts(1), accounts(2)], vec![50, 50], 30);
+        contract.add_stable_swap_pool(vec![accounts(1), accounts(2)], vec![24, 24], 30, 100);
+        contract.add_stable_swap_pool(vec![accounts(1), accounts(2)], vec![24, 24], 30, 200);
+        assert_eq!(
+            contract.get_fee_tiers(vec![accounts(1), accounts(2)]),
+            vec![0, 1, 2, 3]
+        );
+    }
+
+    /// Should deny creating a weighted pool with the same weights of the tokens listed in another order.
+    #[test]
+    #[should_panic(expected = "ERR_FEE_TIER_EXISTS")]
+    fn test_deny_duplicate_weighted_pool() {
+        let mut context = VMContextBuilder::new();
+        let mut contract =
+            setup_contract(&mut context, vec![accounts(1), accounts(2), accounts(4)]);
+        testing_env!(context
+            .predecessor_account_id(accounts(3))
+            .attached_deposit(env::storage_byte_cost() * 1000)
+            .build());
+        contract.add_weighted_pool(vec![accounts(1), accounts(2)], vec![80, 20], 30);
+        contract.add_weighted_pool(vec![accounts(2), accounts(1)], vec![20, 80], 30);
+    }
+
+    /// Should deny creating a pool with duplicate tokens.
+    #[test]
+    #[should_panic(expected = "ERR_TOKEN_DUPLICATES")]
+    fn test_deny_duplicate_tokens_pool() {
+        let mut context = VMContextBuilder::new();
+        let mut contract =
+            setup_contract(&mut context, vec![accounts(1), accounts(2), accounts(4)]);
+        testing_env!(context
+            .predecessor_account_id(accounts(3))
+            .attached_deposit(env::storage_byte_cost() * 500)
+            .build());
+        contract.add_simple_pool(vec![accounts(1), accounts(1)], 30);
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_TOKEN_IS_CONTRACT")]
+    fn test_deny_contract_token_pool() {
+        let mut context = VMContextBuilder::new();
+        let mut contract = setup_contract(&mut context, vec![accounts(1)]);
+        testing_env!(context
+            .predecessor_account_id(accounts(3))
+            .attached_deposit(env::storage_byte_cost() * 500)
+            .build());
+        contract.add_simple_pool(vec![accounts(1), env::current_account_id()], 30);
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_NOT_ENOUGH_TOKENS")]
+    fn test_deny_pool_without_tokens() {
+        let mut context = VMContextBuilder::new();
+        let mut contract = setup_contract(&mut context, vec![]);
+        testing_env!(context
+            .predecessor_account_id(accounts(3))
+            .attached_deposit(env::storage_byte_cost() * 500)
+            .build());
+        contract.add_weighted_pool(vec![], vec![], 30);
+    }
+
+    #[test]
+    fn test_metapool() {
+        let one_near = 10u128.pow(24);
+        let mut context = VMContextBuilder::new();
+        let mut contract =
+            setup_contract(&mut context, vec![accounts(1), accounts(2), accounts(4)]);
+        testing_env!(context
+            .predecessor_account_id(accounts(3))
+            .attached_deposit(env::storage_byte_cost() * 500)
+            .build());
+        contract.add_stable_swap_pool(vec![accounts(1), accounts(2)], vec![24, 24], 25, 100);
+        contract.add_metapool(accounts(4), 24, 0, 25, 100);
+        let shares_token: AccountId = shares_token_id(0);
+        testing_env!(context
+            .attached_deposit(
+                contract.storage_balance_bounds().min.0 + env::storage_byte_cost() * 1500
+            )
+            .build());
+        contract.storage_deposit(None, None);
+        contract.register_tokens(vec![
+            accounts(1),
+            accounts(2),
+            accounts(4),
+            shares_token.clone(),
+        ]);
+        testing_env!(context
+            .predecessor_account_id(accounts(1))
+            .attached_deposit(1)
+            .build());
+        contract.ft_on_transfer(accounts(3), (200 * one_near).into(), "".to_string());
+        testing_env!(context.predecessor_account_id(accounts(2)).build());
+        contract.ft_on_transfer(accounts(3), (200 * one_near).into(), "".to_string());
+        testing_env!(context.predecessor_account_id(accounts(4)).build());
+        contract.ft_on_transfer(accounts(3), (100 * one_near).into(), "".to_string());
+        testing_env!(context.predecessor_account_id(accounts(3)).build());
+        contract.add_liquidity(0, vec![U128(100 * one_near), U128(100 * one_near)], U128(0));
+        assert_eq!(contract.get_virtual_price(0).0, PRICE_PRECISION);
+
+        // Swaps in the base pool collect fees, so its shares become worth more than 1.
+        contract.swap(
+            vec![
+                SwapAction {
+                    pool_id: 0,
+                    token_in: accounts(1),
+                    amount_in: Some((50 * one_near).into()),
+                    token_out: accounts(2),
+                    min_amount_out: U128(1),
+                },
+                SwapAction {
+                    pool_id: 0,
+                    token_in: accounts(2),
+                    amount_in: None,
+                    token_out: accounts(1),
+                    min_amount_out: U128(1),
+                },
+            ],
+            None,
+        );
+        let virtual_price = contract.get_virtual_price(0).0;
+        assert!(virtual_price > PRICE_PRECISION);
+
+        contract.deposit_shares(0, U128(100 * one_near));
+        assert_eq!(
+            contract.get_pool_shares(0, accounts(3)).0,
+            100 * one_near - MIN_LIQUIDITY
+        );
+        contract.add_liquidity(1, vec![U128(100 * one_near), U128(100 * one_near)], U128(0));
+        // Base pool shares are worth more than the paired token, so swapping one for another gives less shares.
+        let amount_out = contract.get_return(1, accounts(4), one_near.into(), shares_token.clone());
+        let fair_amount_out = (U256::from(one_near) * U256::from(PRICE_PRECISION)
+            / U256::from(virtual_price))
+        .as_u128();
+        assert!(amount_out.0 < fair_amount_out && amount_out.0 > fair_amount_out * 99 / 100);
+
+        contract.remove_liquidity(
+            1,
+            contract.get_pool_shares(1, accounts(3)),
+            vec![1.into(), 1.into()],
+        );
+        let shares = contract.get_deposit(&accounts(3), &shares_token);
+        contract.withdraw_shares(0, shares);
+        // Base pool shares backing the minimum liquidity locked in the metapool stay there.
+        let metapool = contract.get_pool(1);
+        let index = metapool
+            .token_account_ids
+            .iter()
+            .position(|token_id| token_id == &shares_token)
+            .unwrap();
+        assert_eq!(
+            contract.get_pool_shares(0, accounts(3)).0,
+            contract.get_pool_total_shares(0).0 - MIN_LIQUIDITY - metapool.amounts[index].0
+        );
+    }
+
+    #[test]
+    fn test_bonding_curve_pool_graduation() {
+        let one_near = 10u128.pow(24);
+        let mut context = VMContextBuilder::new();
+        let mut contract =
+            setup_contract(&mut context, vec![accounts(1), accounts(2), accounts(4)]);
+        for account_id in [accounts(3), accounts(4)].iter() {
+            testing_env!(context
+                .predecessor_account_id(account_id.clone())
+                .attached_deposit(contract.storage_balance_bounds().min.0)
+                .build());
+            contract.storage_deposit(None, None);
+            contract.register_tokens(vec![accounts(1), accounts(2), accounts(4)]);
+        }
+        testing_env!(context
+            .predecessor_account_id(accounts(1))
+            .attached_deposit(1)
+            .build());
+        contract.ft_on_transfer(accounts(3), (1000 * one_near).into(), "".to_string());
+        testing_env!(context.predecessor_account_id(accounts(2)).build());
+        contract.ft_on_transfer(accounts(4), (20 * one_near).into(), "".to_string());
+
+        testing_env!(context
+            .predecessor_account_id(accounts(0))
+            .attached_deposit(1)
+            .build());
+        contract.set_permissionless_pool_creation(false);
+        contract.add_pool_creator(accounts(3));
+
+        // Sale of token 1 for token 2 at fixed price 0.5, graduating at 10 of token 2.
+        testing_env!(context
+            .predecessor_account_id(accounts(3))
+            .attached_deposit(env::storage_byte_cost() * 500)
+            .build());
+        let pool_id = contract.add_bonding_curve_pool(
+            accounts(1),
+            accounts(2),
+            (1000 * one_near).into(),
+            (PRICE_PRECISION / 2).into(),
+            U128(0),
+            (10 * one_near).into(),
+            0,
+        ) as u64;
+        assert_eq!(contract.get_deposit(&accounts(3), &accounts(1)), U128(0));
+        testing_env!(context.predecessor_account_id(accounts(4)).build());
+        let amount_out = contract.swap(
+            vec![SwapAction {
+                pool_id,
+                token_in: accounts(2),
+                amount_in: Some((10 * one_near).into()),
+                token_out: accounts(1),
+                min_amount_out: U128(1),
+            }],
+            None,
+        );
+        assert_eq!(amount_out.0, 20 * one_near);
+
+        // Graduation neither needs approval to create pools nor pays the pool creation deposit.
+        testing_env!(context
+            .predecessor_account_id(accounts(0))
+            .attached_deposit(1)
+            .build());
+        contract.remove_pool_creator(accounts(3));
+        contract.set_pool_creation_deposit(U128(one_near));
+        testing_env!(context
+            .predecessor_account_id(accounts(3))
+            .attached_deposit(env::storage_byte_cost() * 500)
+            .build());
+        let new_pool_id = contract.graduate_bonding_curve_pool(pool_id, 30) as u64;
+        let new_pool = contract.get_pool(new_pool_id);
+        assert_eq!(new_pool.pool_kind, "SIMPLE_POOL");
+        assert_eq!(
+            new_pool.amounts,
+            vec![U128(20 * one_near), U128(10 * one_near)]
+        );
+        assert_eq!(
+            contract
+                .get_pool_shares(new_pool_id, env::current_account_id())
+                .0,
+            contract.get_pool_total_shares(new_pool_id).0 - MIN_LIQUIDITY
+        );
+        assert_eq!(
+            contract.get_deposit(&accounts(3), &accounts(1)),
+            (960 * one_near).into()
+        );
+    }
+
+    /// Returns given number of distinct token accounts.
+    fn tokens(num_tokens: usize) -> Vec<AccountId> {
+        (0..num_tokens)
+            .map(|i| format!("token{}", i).parse().unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn test_max_num_tokens() {
+        let mut context = VMContextBuilder::new();
+        let mut contract = setup_contract(&mut context, tokens(12));
+        testing_env!(context
+            .predecessor_account_id(accounts(3))
+            .attached_deposit(env::storage_byte_cost() * 20000)
+            .build());
+        contract.add_simple_pool(tokens(10), 30);
+        testing_env!(context
+            .predecessor_account_id(accounts(0))
+            .attached_deposit(1)
+            .build());
+        contract.set_max_num_tokens(12);
+        assert_eq!(contract.get_max_num_tokens(), 12);
+        testing_env!(context
+            .predecessor_account_id(accounts(3))
+            .attached_deposit(env::storage_byte_cost() * 20000)
+            .build());
+        contract.add_simple_pool(tokens(12), 30);
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_TOO_MANY_TOKENS")]
+    fn test_deny_too_many_tokens() {
+        let mut context = VMContextBuilder::new();
+        let mut contract = setup_contract(&mut context, tokens(11));
+        testing_env!(context
+            .predecessor_account_id(accounts(3))
+            .attached_deposit(env::storage_byte_cost() * 2000)
+            .build());
+        contract.add_simple_pool(tokens(11), 30);
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_NOT_POOL_MANAGER")]
+    fn test_deny_set_max_num_tokens_not_owner() {
+        let mut context = VMContextBuilder::new();
+        let mut contract =
+            setup_contract(&mut context, vec![accounts(1), accounts(2), accounts(4)]);
+        testing_env!(context.predecessor_account_id(accounts(3)).build());
+        contract.set_max_num_tokens(12);
+    }
+
+    #[test]
+    #[should_panic(expected = "Requires attached deposit of exactly 1 yoctoNEAR")]
+    fn test_deny_admin_call_without_yocto() {
+        let mut context = VMContextBuilder::new();
+        let mut contract = setup_contract(&mut context, vec![accounts(1), accounts(2)]);
+        testing_env!(context.attached_deposit(0).build());
+        contract.set_max_num_tokens(12);
+    }
+
+    #[test]
+    fn test_ownership_transfer() {
+        let mut context = VMContextBuilder::new();
+        let mut contract = setup_contract(&mut context, vec![]);
+        contract.propose_owner(Some(accounts(1)));
+        assert_eq!(contract.get_owner(), accounts(0));
+        assert_eq!(contract.get_pending_owner(), Some(accounts(1)));
+        testing_env!(context.predecessor_account_id(accounts(1)).build());
+        contract.accept_ownership();
+        assert_eq!(contract.get_owner(), accounts(1));
+        assert_eq!(contract.get_pending_owner(), None);
+        contract.set_max_num_tokens(12);
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_NOT_PENDING_OWNER")]
+    fn test_deny_accept_ownership_not_proposed() {
+        let mut context = VMContextBuilder::new();
+        let mut contract = setup_contract(&mut context, vec![]);
+        contract.propose_owner(Some(accounts(1)));
+        testing_env!(context.predecessor_account_id(accounts(2)).build());
+        contract.accept_ownership();
+    }
+
+    #[test]
+    fn test_guardian_pause() {
+        let one_near = 10u128.pow(24);
+        let mut context = VMContextBuilder::new();
+        let mut contract = setup_route(&mut context);
+        testing_env!(context.predecessor_account_id(accounts(0)).build());
+        contract.grant_role(Role::Guardian, accounts(2));
+        assert_eq!(contract.get_role_members(Role::Guardian), vec![accounts(2)]);
+        testing_env!(context.predecessor_account_id(accounts(2)).build());
+        contract.pause_pool(0);
+        contract.pause_exchange();
+        assert_eq!(contract.get_paused_pools(), vec![0]);
+        assert_eq!(contract.get_running_state(), RunningState::Paused);
+
+        // Liquidity can still be removed from paused pools.
+        testing_env!(context.predecessor_account_id(accounts(3)).build());
+        let shares = contract.get_pool_shares(0, accounts(3));
+        contract.remove_liquidity(0, U128(shares.0 / 2), vec![U128(1), U128(1)]);
+
+        testing_env!(context.predecessor_account_id(accounts(0)).build());
+        contract.resume_exchange();
+        contract.resume_pool(0);
+        assert_eq!(contract.get_running_state(), RunningState::Running);
+        testing_env!(context.predecessor_account_id(accounts(3)).build());
+        contract.swap(
+            vec![SwapAction {
+                pool_id: 0,
+                token_in: accounts(1),
+                amount_in: Some(one_near.into()),
+                token_out: accounts(2),
+                min_amount_out: U128(1),
+            }],
+            None,
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_POOL_PAUSED")]
+    fn test_deny_swap_in_paused_pool() {
+        let one_near = 10u128.pow(24);
+        let mut context = VMContextBuilder::new();
+        let mut contract = setup_route(&mut context);
+        testing_env!(context.predecessor_account_id(accounts(0)).build());
+        contract.pause_pool(0);
+        testing_env!(context.predecessor_account_id(accounts(5)).build());
+        contract.swap(
+            vec![SwapAction {
+                pool_id: 0,
+                token_in: accounts(1),
+                amount_in: Some(one_near.into()),
+                token_out: accounts(2),
+                min_amount_out: U128(1),
+            }],
+            None,
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_NOT_GUARDIAN")]
+    fn test_deny_pause_not_guardian() {
+        let mut context = VMContextBuilder::new();
+        let mut contract = setup_route(&mut context);
+        testing_env!(context.predecessor_account_id(accounts(5)).build());
+        contract.pause_exchange();
+    }
+
+    #[test]
+    fn test_protocol_fee() {
+        let one_near = 10u128.pow(24);
+        let mut context = VMContextBuilder::new();
+        let mut contract = setup_route(&mut context);
+        testing_env!(context.predecessor_account_id(accounts(0)).build());
+        contract.set_protocol_fee(5000);
+        testing_env!(context.predecessor_account_id(accounts(5)).build());
+        let amount_mid = contract.get_return(0, accounts(1), one_near.into(), accounts(2));
+        contract.swap(
+            vec![
+                SwapAction {
+                    pool_id: 0,
+                    token_in: accounts(1),
+                    amount_in: Some(one_near.into()),
+                    token_out: accounts(2),
+                    min_amount_out: U128(1),
+                },
+                SwapAction {
+                    pool_id: 1,
+                    token_in: accounts(2),
+                    amount_in: None,
+                    token_out: accounts(4),
+                    min_amount_out: U128(1),
+                },
+            ],
+            None,
+        );
+        // Half of the 0.3% fee goes to the protocol, the rest stays in the pool.
+        let protocol_fee = one_near * 15 / 10000;
+        assert_eq!(
+            contract.get_protocol_fees(0),
+            vec![U128(protocol_fee), U128(0)]
+        );
+        assert_eq!(
+            contract.get_pool(0).amounts[0],
+            U128(11 * one_near - protocol_fee)
+        );
+        assert_eq!(
+            contract.get_protocol_fees(1),
+            vec![U128(amount_mid.0 * 15 / 10000), U128(0)]
+        );
+
+        testing_env!(context.predecessor_account_id(accounts(0)).build());
+        contract.set_treasury(accounts(3));
+        testing_env!(context.predecessor_account_id(accounts(5)).build());
+        contract.claim_protocol_fees(0);
+        assert_eq!(
+            contract.get_deposit(&accounts(3), &accounts(1)),
+            U128(protocol_fee)
+        );
+        assert_eq!(contract.get_protocol_fees(0), vec![U128(0), U128(0)]);
+    }
+
+    #[test]
+    fn test_simulate_swap() {
+        let one_near = 10u128.pow(24);
+        let mut context = VMContextBuilder::new();
+        let mut contract = setup_route(&mut context);
+        testing_env!(context.predecessor_account_id(accounts(0)).build());
+        contract.set_protocol_fee(5000);
+        let simulation = contract.simulate_swap(0, accounts(1), one_near.into(), accounts(2));
+        assert_eq!(
+            simulation.amount_out,
+            contract.get_return(0, accounts(1), one_near.into(), accounts(2))
+        );
+        assert_eq!(simulation.fee, U128(one_near * 30 / 10000));
+        assert_eq!(simulation.protocol_fee, U128(one_near * 15 / 10000));
+        assert_eq!(
+            simulation.price_impact,
+            contract.get_price_impact(0, accounts(1), one_near.into(), accounts(2))
+        );
+
+        // The pool ends up as simulated.
+        testing_env!(context.predecessor_account_id(accounts(5)).build());
+        contract.swap(
+            vec![
+                SwapAction {
+                    pool_id: 0,
+                    token_in: accounts(1),
+                    amount_in: Some(one_near.into()),
+                    token_out: accounts(2),
+                    min_amount_out: U128(1),
+                },
+                SwapAction {
+                    pool_id: 1,
+                    token_in: accounts(2),
+                    amount_in: None,
+                    token_out: accounts(4),
+                    min_amount_out: U128(1),
+                },
+            ],
+            None,
+        );
+        assert_eq!(simulation.amounts, contract.get_pool(0).amounts);
+        assert_eq!(
+            simulation.spot_price,
+            contract.get_spot_price(0, accounts(1), accounts(2), false)
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_FEE_TOO_LARGE")]
+    fn test_deny_protocol_and_referral_fee_above_fee() {
+        let mut context = VMContextBuilder::new();
+        let mut contract = setup_contract(&mut context, vec![]);
+        contract.set_referral_fee(5000);
+        contract.set_protocol_fee(6000);
+    }
+
+    #[test]
+    fn test_timelocked_admin_action() {
+        let one_sec = 10u64.pow(9);
+        let mut context = VMContextBuilder::new();
+        let mut contract = setup_contract(&mut context, vec![]);
+        testing_env!(context.block_timestamp(1000 * one_sec).build());
+        contract.set_timelock_delay(100);
+        let action = AdminAction::SetReferralFee { referral_fee: 2000 };
+        let action_id = contract.propose_admin_action(action.clone());
+        assert_eq!(
+            contract.get_admin_actions(0, 10),
+            vec![AdminActionInfo {
+                action_id,
+                action,
+                executable_at: U64(1100),
+            }]
+        );
+        testing_env!(context.block_timestamp(1100 * one_sec).build());
+        contract.execute_admin_action(action_id);
+        assert_eq!(contract.get_referral_fee(), 2000);
+        assert!(contract.get_admin_actions(0, 10).is_empty());
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_TIMELOCK_NOT_ENDED")]
+    fn test_deny_admin_action_before_delay() {
+        let one_sec = 10u64.pow(9);
+        let mut context = VMContextBuilder::new();
+        let mut contract = setup_contract(&mut context, vec![]);
+        testing_env!(context.block_timestamp(1000 * one_sec).build());
+        contract.set_timelock_delay(100);
+        let action_id = contract.propose_admin_action(AdminAction::AddWhitelistedToken {
+            token_id: accounts(1),
+        });
+        testing_env!(context.block_timestamp(1099 * one_sec).build());
+        contract.execute_admin_action(action_id);
+    }
+
+    #[test]
+    fn test_migrate() {
+        let mut context = VMContextBuilder::new();
+        let mut contract = setup_contract(&mut context, vec![accounts(1)]);
+        contract.set_referral_fee(2000);
+        contract.set_timelock_delay(100);
+        let action = AdminAction::SetTimelockDelay {
+            timelock_delay: 200,
+        };
+        contract.propose_admin_action(action.clone());
+        env::state_write(&contract);
+        let contract = Contract::migrate(None);
+        assert_eq!(contract.get_state_version(), StateVersion::V2);
+        assert_eq!(contract.get_referral_fee(), 2000);
+
+        // Pending admin actions proposed under V1 keep their variant, the 9th of `AdminAction` there.
+        assert_eq!(action.try_to_vec().unwrap()[0], 8);
+
+        // State of V1 is a prefix of the current one, fields added since start empty.
+        let state = contract.try_to_vec().unwrap();
+        let mut contract_v1 = ContractV1::deserialize(&mut state.as_slice()).unwrap();
+        contract_v1.state_version = StateVersion::V1;
+        env::storage_write(b"STATE", &contract_v1.try_to_vec().unwrap());
+        let contract = Contract::migrate(None);
+        assert_eq!(contract.get_state_version(), StateVersion::V2);
+        assert_eq!(contract.get_referral_fee(), 2000);
+        assert_eq!(contract.get_whitelisted_tokens(), vec![accounts(1)]);
+        assert_eq!(contract.get_admin_actions(0, 10)[0].action, action);
+        assert_eq!(contract.next_farm_id, 0);
+    }
+
+    #[test]
+    fn test_migrate_first_release() {
+        let one_near = 10u128.pow(24);
+        let mut context = VMContextBuilder::new();
+        testing_env!(context.predecessor_account_id(accounts(0)).build());
+        let mut shares = LookupMap::new(b"s0".to_vec());
+        shares.insert(&accounts(3), &one_near);
+        let mut pools = Vector::new(b"p".to_vec());
+        pools.push(&PoolV0::SimplePool(SimplePoolV0 {
+            token_account_ids: vec![accounts(1), accounts(2)],
+            amounts: vec![10 * one_near, 20 * one_near],
+            fee: 30,
+            shares,
+            shares_total_supply: one_near,
+        }));
+        let mut deposited_amounts = LookupMap::new(b"d".to_vec());
+        let mut deposits = HashMap::new();
+        deposits.insert(accounts(1), one_near);
+        deposited_amounts.insert(&accounts(3), &deposits);
+        let state = ContractV0 {
+            pools,
+            deposited_amounts,
+        };
+        env::storage_write(b"STATE", &state.try_to_vec().unwrap());
+
+        // Pools are rewritten and indexed, and their tokens whitelisted.
+        let contract = Contract::migrate(Some(accounts(0)));
+        assert_eq!(contract.get_state_version(), StateVersion::V2);
+        assert_eq!(contract.get_owner(), accounts(0));
+        let pool = contract.get_pool(0);
+        assert_eq!(pool.amounts, vec![U128(10 * one_near), U128(20 * one_near)]);
+        assert_eq!(pool.shares_total_supply, U128(one_near));
+        assert_eq!(contract.get_pool_shares(0, accounts(3)), U128(one_near));
+        assert_eq!(
+            contract.get_fee_tiers(vec![accounts(1), accounts(2)]),
+            vec![0]
+        );
+        assert_eq!(
+            contract.get_whitelisted_tokens(),
+            vec![accounts(1), accounts(2)]
+        );
+
+        // Deposits are read in place, with the minimum storage balance paid on registration.
+        assert_eq!(
+            contract.get_deposit(&accounts(3), &accounts(1)),
+            U128(one_near)
+        );
+        assert_eq!(
+            contract.storage_balance_of(accounts(3)).unwrap().total,
+            contract.storage_balance_bounds().min
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_OWNER_ID_REQUIRED")]
+    fn test_deny_migrate_first_release_without_owner() {
+        let mut context = VMContextBuilder::new();
+        testing_env!(context.predecessor_account_id(accounts(0)).build());
+        let state = ContractV0 {
+            pools: Vector::new(b"p".to_vec()),
+            deposited_amounts: LookupMap::new(b"d".to_vec()),
+        };
+        env::storage_write(b"STATE", &state.try_to_vec().unwrap());
+        Contract::migrate(None);
+    }
+
+    #[test]
+    fn test_stage_code() {
+        let one_sec = 10u64.pow(9);
+        let mut context = VMContextBuilder::new();
+        let mut contract = setup_contract(&mut context, vec![]);
+        testing_env!(context.block_timestamp(1000 * one_sec).build());
+        contract.set_timelock_delay(100);
+        contract.stage_code(vec![1; 100]);
+        assert_eq!(
+            contract.get_staged_code(),
+            Some(StagedCode {
+                code_hash: env::sha256(&[1; 100]).into(),
+                deployable_at: U64(1100),
+            })
+        );
+        testing_env!(context.block_timestamp(1100 * one_sec).build());
+        contract.deploy_staged_code();
+        assert_eq!(contract.get_staged_code(), None);
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_TIMELOCK_NOT_ENDED")]
+    fn test_deny_deploy_staged_code_before_delay() {
+        let mut context = VMContextBuilder::new();
+        let mut contract = setup_contract(&mut context, vec![]);
+        contract.set_timelock_delay(100);
+        contract.stage_code(vec![1; 100]);
+        contract.deploy_staged_code();
+    }
+
+    #[test]
+    fn test_config() {
+        let mut context = VMContextBuilder::new();
+        let mut contract = setup_contract(&mut context, vec![]);
+        contract.set_protocol_fee(1667);
+        contract.set_permissionless_pool_creation(false);
+        contract.pause_exchange();
+        let config = contract.get_config();
+        assert_eq!(config.owner_id, accounts(0));
+        assert_eq!(config.fee_divisor, FEE_DIVISOR);
+        assert_eq!(config.protocol_fee, 1667);
+        assert!(!config.permissionless_pool_creation);
+        assert_eq!(config.running_state, RunningState::Paused);
+        assert_eq!(config.state_version, StateVersion::V2);
+    }
+
+    #[test]
+    fn test_roles() {
+        let mut context = VMContextBuilder::new();
+        let mut contract = setup_contract(&mut context, vec![]);
+        contract.grant_role(Role::FeeSetter, accounts(1));
+        contract.grant_role(Role::PoolManager, accounts(2));
+        assert!(contract.has_role(Role::FeeSetter, accounts(1)));
+        assert!(!contract.has_role(Role::FeeSetter, accounts(2)));
+        assert!(contract.has_role(Role::PoolManager, accounts(0)));
+        assert_eq!(contract.get_role_members(Role::Owner), vec![accounts(0)]);
+        testing_env!(context.predecessor_account_id(accounts(1)).build());
+        contract.set_referral_fee(2000);
+        testing_env!(context.predecessor_account_id(accounts(2)).build());
+        contract.add_whitelisted_token(accounts(3));
+        assert_eq!(contract.get_referral_fee(), 2000);
+        assert_eq!(contract.get_whitelisted_tokens(), vec![accounts(3)]);
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_NOT_FEE_SETTER")]
+    fn test_deny_fee_change_after_role_revoked() {
+        let mut context = VMContextBuilder::new();
+        let mut contract = setup_contract(&mut context, vec![]);
+        contract.grant_role(Role::FeeSetter, accounts(1));
+        contract.revoke_role(Role::FeeSetter, accounts(1));
+        testing_env!(context.predecessor_account_id(accounts(1)).build());
+        contract.set_referral_fee(2000);
+    }
+
+    #[test]
+    fn test_dao_governance() {
+        let mut context = VMContextBuilder::new();
+        let mut contract = setup_contract(&mut context, vec![]);
+        contract.set_dao(Some(accounts(1)));
+        assert_eq!(contract.get_dao(), Some(accounts(1)));
+        let proposal =
+            contract.get_dao_proposal(AdminAction::SetReferralFee { referral_fee: 2000 });
+        assert_eq!(proposal.actions[0].method_name, "set_referral_fee");
+        assert_eq!(
+            proposal.actions[0].args.0,
+            br#"{"referral_fee":2000}"#.to_vec()
+        );
+        assert_eq!(proposal.actions[0].deposit, U128(1));
+
+        testing_env!(context.predecessor_account_id(accounts(1)).build());
+        contract.set_referral_fee(2000);
+        assert_eq!(contract.get_referral_fee(), 2000);
+        contract.set_timelock_delay(100);
+        let proposal = contract.get_dao_proposal(AdminAction::SetReferralFee { referral_fee: 0 });
+        assert_eq!(proposal.actions[0].method_name, "propose_admin_action");
+        assert_eq!(
+            proposal.actions[0].args.0,
+            br#"{"action":{"SetReferralFee":{"referral_fee":0}}}"#.to_vec()
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_NOT_DAO")]
+    fn test_deny_owner_with_dao() {
+        let mut context = VMContextBuilder::new();
+        let mut contract = setup_contract(&mut context, vec![]);
+        contract.set_dao(Some(accounts(1)));
+        contract.set_treasury(accounts(2));
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_TIMELOCK_ACTIVE")]
+    fn test_deny_direct_change_with_timelock() {
+        let mut context = VMContextBuilder::new();
+        let mut contract = setup_contract(&mut context, vec![]);
+        contract.set_timelock_delay(100);
+        contract.set_flash_loan_fee(9);
+    }
+
+    #[test]
+    fn test_withdraw_while_paused() {
+        let one_near = 10u128.pow(24);
+        let mut context = VMContextBuilder::new();
+        let mut contract = setup_route(&mut context);
+        testing_env!(context.predecessor_account_id(accounts(0)).build());
+        contract.pause_exchange();
+        testing_env!(context
+            .predecessor_account_id(accounts(5))
+            .attached_deposit(1)
+            .build());
+        contract.withdraw(accounts(1), U128(one_near));
+        assert_eq!(contract.get_deposit(&accounts(5), &accounts(1)), U128(0));
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_CONTRACT_PAUSED")]
+    fn test_deny_deposit_while_paused() {
+        let one_near = 10u128.pow(24);
+        let mut context = VMContextBuilder::new();
+        let mut contract = setup_route(&mut context);
+        testing_env!(context.predecessor_account_id(accounts(0)).build());
+        contract.pause_exchange();
+        testing_env!(context.predecessor_account_id(accounts(1)).build());
+        contract.ft_on_transfer(accounts(5), one_near.into(), "".to_string());
+    }
+
+    #[test]
+    fn test_limit_order_fill() {
+        let one_near = 10u128.pow(24);
+        let mut context = VMContextBuilder::new();
+        let mut contract = setup_route(&mut context);
+        testing_env!(context.predecessor_account_id(accounts(0)).build());
+        contract.set_limit_order_fees(LimitOrderFees {
+            maker_fee: 10,
+            taker_fee: 20,
+        });
+        testing_env!(context.predecessor_account_id(accounts(5)).build());
+        let order_id = contract.add_limit_order(
+            accounts(1),
+            one_near.into(),
+            accounts(4),
+            U128(2 * one_near),
+        );
+        assert_eq!(contract.get_deposit(&accounts(5), &accounts(1)), U128(0));
+
+        testing_env!(context.predecessor_account_id(accounts(4)).build());
+        contract.ft_on_transfer(accounts(3), U128(2 * one_near), "".to_string());
+
+        // Partial fill keeps the price of the order.
+        testing_env!(context.predecessor_account_id(accounts(3)).build());
+        let amount_in = contract.fill_limit_order(order_id, one_near.into());
+        assert_eq!(amount_in, U128(one_near / 2 - one_near / 1000));
+        assert_eq!(
+            contract.get_limit_order(order_id),
+            Some(LimitOrderInfo {
+                order_id,
+                owner_id: accounts(5),
+                token_in: accounts(1),
+                amount_in: U128(one_near / 2),
+                token_out: accounts(4),
+                amount_out: one_near.into(),
+            })
+        );
+        assert_eq!(contract.get_limit_orders(0, 10).len(), 1);
+
+        contract.fill_limit_order(order_id, one_near.into());
+        assert_eq!(contract.get_limit_order(order_id), None);
+        assert_eq!(
+            contract.get_deposit(&accounts(5), &accounts(4)),
+            U128(2 * one_near - 2 * one_near / 1000)
+        );
+        assert_eq!(
+            contract.get_limit_order_collected_fees(accounts(1)),
+            U128(2 * one_near / 1000)
+        );
+        assert_eq!(
+            contract.get_limit_order_collected_fees(accounts(4)),
+            U128(2 * one_near / 1000)
+        );
+    }
+
+    #[test]
+    fn test_execute_limit_order() {
+        let one_near = 10u128.pow(24);
+        let mut context = VMContextBuilder::new();
+        let mut contract = setup_route(&mut context);
+        testing_env!(context.predecessor_account_id(accounts(5)).build());
+        contract.register_tokens(vec![accounts(2)]);
+        let order_id = contract.add_limit_order(
+            accounts(1),
+            one_near.into(),
+            accounts(2),
+            U128(one_near / 2),
+        );
+        let amount_out = contract.get_return(0, accounts(1), one_near.into(), accounts(2));
+
+        testing_env!(context.predecessor_account_id(accounts(3)).build());
+        let prev_deposit = contract.get_deposit(&accounts(3), &accounts(2));
+        let surplus = contract.execute_limit_order(order_id, 0);
+        assert_eq!(surplus, U128(amount_out.0 - one_near / 2));
+        assert_eq!(
+            contract.get_deposit(&accounts(3), &accounts(2)),
+            U128(prev_deposit.0 + surplus.0)
+        );
+        assert_eq!(
+            contract.get_deposit(&accounts(5), &accounts(2)),
+            U128(one_near / 2)
+        );
+        assert_eq!(contract.get_limit_order(order_id), None);
+    }
+
+    #[test]
+    fn test_cancel_limit_order() {
+        let one_near = 10u128.pow(24);
+        let mut context = VMContextBuilder::new();
+        let mut contract = setup_route(&mut context);
+        testing_env!(context.predecessor_account_id(accounts(5)).build());
+        let order_id =
+            contract.add_limit_order(accounts(1), one_near.into(), accounts(4), one_near.into());
+        contract.cancel_limit_order(order_id);
+        assert_eq!(contract.get_limit_order(order_id), None);
+        assert_eq!(
+            contract.get_deposit(&accounts(5), &accounts(1)),
+            U128(one_near)
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_NOT_OWNER")]
+    fn test_deny_cancel_limit_order_not_owner() {
+        let one_near = 10u128.pow(24);
+        let mut context = VMContextBuilder::new();
+        let mut contract = setup_route(&mut context);
+        testing_env!(context.predecessor_account_id(accounts(5)).build());
+        let order_id =
+            contract.add_limit_order(accounts(1), one_near.into(), accounts(4), one_near.into());
+        testing_env!(context.predecessor_account_id(accounts(3)).build());
+        contract.cancel_limit_order(order_id);
+    }
+
+    #[test]
+    fn test_twap() {
+        let one_near = 10u128.pow(24);
+        let one_sec = 10u64.pow(9);
+        let mut context = VMContextBuilder::new();
+        let mut contract = setup_route(&mut context);
+        testing_env!(context
+            .block_timestamp(1000 * one_sec)
+            .attached_deposit(env::storage_byte_cost() * 10000)
+            .build());
+        contract.enable_twap(0);
+
+        testing_env!(context
+            .predecessor_account_id(accounts(5))
+            .block_timestamp(1100 * one_sec)
+            .attached_deposit(0)
+            .build());
+        contract.register_tokens(vec![accounts(2)]);
+        contract.swap(
+            vec![SwapAction {
+                pool_id: 0,
+                token_in: accounts(1),
+                amount_in: Some(one_near.into()),
+                token_out: accounts(2),
+                min_amount_out: U128(1),
+            }],
+            None,
+        );
+        let price = contract.get_spot_price(0, accounts(2), accounts(1), false);
+
+        testing_env!(context.block_timestamp(1200 * one_sec).build());
+        assert_eq!(
+            contract.get_twap(0, accounts(2), accounts(1), 200),
+            U128((PRICE_PRECISION + price.0) / 2)
+        );
+        // Newest observation at least 50 seconds old is from the swap.
+        assert_eq!(contract.get_twap(0, accounts(2), accounts(1), 50), price);
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_TWAP_PERIOD_TOO_LONG")]
+    fn test_deny_twap_period_too_long() {
+        let one_sec = 10u64.pow(9);
+        let mut context = VMContextBuilder::new();
+        let mut contract = setup_route(&mut context);
+        testing_env!(context
+            .block_timestamp(1000 * one_sec)
+            .attached_deposit(env::storage_byte_cost() * 10000)
+            .build());
+        contract.enable_twap(0);
+        testing_env!(context.block_timestamp(1200 * one_sec).build());
+        contract.get_twap(0, accounts(1), accounts(2), 300);
+    }
+
+    /// Limits price movement of pool 0 of `setup_route` to 5% per block, and lets accounts(5) swap token 1 in it.
+    fn setup_circuit_breaker(context: &mut VMContextBuilder) -> Contract {
+        let mut contract = setup_route(context);
+        testing_env!(context
+            .predecessor_account_id(accounts(0))
+            .attached_deposit(1)
+            .block_index(1)
+            .build());
+        contract.set_circuit_breaker(0, Some(500));
+        assert_eq!(contract.get_circuit_breaker(0), Some(500));
+        testing_env!(context.predecessor_account_id(accounts(5)).build());
+        contract.register_tokens(vec![accounts(2)]);
+        contract
+    }
+
+    /// Swaps given amount of token 1 from the deposits of accounts(5) in pool 0.
+    fn swap_token_1(contract: &mut Contract, amount_in: Balance) {
+        contract.swap(
+            vec![SwapAction {
+                pool_id: 0,
+                token_in: accounts(1),
+                amount_in: Some(amount_in.into()),
+                token_out: accounts(2),
+                min_amount_out: U128(1),
+            }],
+            None,
+        );
+    }
+
+    #[test]
+    fn test_circuit_breaker() {
+        let one_near = 10u128.pow(24);
+        let mut context = VMContextBuilder::new();
+        let mut contract = setup_circuit_breaker(&mut context);
+        // Each swap moves the price by about 4%, so they fit only into separate blocks.
+        swap_token_1(&mut contract, one_near / 5);
+        testing_env!(context.block_index(2).build());
+        swap_token_1(&mut contract, one_near / 5);
+
+        testing_env!(context.predecessor_account_id(accounts(0)).build());
+        contract.set_circuit_breaker(0, None);
+        assert_eq!(contract.get_circuit_breaker(0), None);
+        testing_env!(context.predecessor_account_id(accounts(5)).build());
+        swap_token_1(&mut contract, one_near / 5);
+        assert_eq!(
+            contract.get_deposit(&accounts(5), &accounts(1)),
+            U128(one_near * 2 / 5)
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_PRICE_MOVE_TOO_LARGE")]
+    fn test_deny_swap_tripping_circuit_breaker() {
+        let one_near = 10u128.pow(24);
+        let mut context = VMContextBuilder::new();
+        let mut contract = setup_circuit_breaker(&mut context);
+        swap_token_1(&mut contract, one_near / 5);
+        swap_token_1(&mut contract, one_near / 5);
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_PRICE_MOVE_TOO_LARGE")]
+    fn test_deny_remove_liquidity_one_token_tripping_circuit_breaker() {
+        let mut context = VMContextBuilder::new();
+        let mut contract = setup_circuit_breaker(&mut context);
+        testing_env!(context.predecessor_account_id(accounts(3)).build());
+        let shares = contract.get_pool_shares(0, accounts(3));
+        contract.remove_liquidity_one_token(0, U128(shares.0 / 10), accounts(1), U128(0));
+    }
+
+    /// Keeps at least 5 NEAR of token 2 in pool 0 of `setup_route`, and lets accounts(5) swap token 1 in it.
+    fn setup_reserve_floor(context: &mut VMContextBuilder) -> Contract {
+        let one_near = 10u128.pow(24);
+        let mut contract = setup_route(context);
+        testing_env!(context
+            .predecessor_account_id(accounts(0))
+            .attached_deposit(1)
+            .build());
+        contract.set_reserve_floor(0, Some(vec![U128(0), U128(5 * one_near)]));
+        assert_eq!(
+            contract.get_reserve_floor(0),
+            Some(vec![U128(0), U128(5 * one_near)])
+        );
+        testing_env!(context.predecessor_account_id(accounts(5)).build());
+        contract.register_tokens(vec![accounts(2)]);
+        contract
+    }
+
+    #[test]
+    fn test_reserve_floor() {
+        let one_near = 10u128.pow(24);
+        let mut context = VMContextBuilder::new();
+        let mut contract = setup_reserve_floor(&mut context);
+        swap_token_1(&mut contract, one_near / 5);
+
+        // Proportional withdrawals leave the price as is, so they can go below the floor.
+        testing_env!(context
+            .predecessor_account_id(accounts(3))
+            .attached_deposit(0)
+            .build());
+        let shares = contract.get_pool_shares(0, accounts(3)).0;
+        contract.remove_liquidity(0, U128(shares / 2), vec![U128(1), U128(1)]);
+        assert!(contract.get_pool(0).amounts[1].0 < 5 * one_near);
+
+        testing_env!(context
+            .predecessor_account_id(accounts(0))
+            .attached_deposit(1)
+            .build());
+        contract.set_reserve_floor(0, None);
+        assert_eq!(contract.get_reserve_floor(0), None);
+        testing_env!(context.predecessor_account_id(accounts(5)).build());
+        swap_token_1(&mut contract, one_near / 5);
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_RESERVE_FLOOR_TOO_HIGH")]
+    fn test_deny_reserve_floor_above_max() {
+        let one_near = 10u128.pow(24);
+        let mut context = VMContextBuilder::new();
+        let mut contract = setup_route(&mut context);
+        testing_env!(context
+            .predecessor_account_id(accounts(0))
+            .attached_deposit(1)
+            .build());
+        contract.set_reserve_floor(0, Some(vec![U128(0), U128(5 * one_near + 1)]));
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_TIMELOCK_ACTIVE")]
+    fn test_deny_reserve_floor_with_timelock() {
+        let mut context = VMContextBuilder::new();
+        let mut contract = setup_route(&mut context);
+        testing_env!(context
+            .predecessor_account_id(accounts(0))
+            .attached_deposit(1)
+            .build());
+        contract.set_timelock_delay(100);
+        contract.set_reserve_floor(0, Some(vec![U128(0), U128(1)]));
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_RESERVE_BELOW_FLOOR")]
+    fn test_deny_swap_below_reserve_floor() {
+        let one_near = 10u128.pow(24);
+        let mut context = VMContextBuilder::new();
+        let mut contract = setup_reserve_floor(&mut context);
+        testing_env!(context.predecessor_account_id(accounts(3)).build());
+        let shares = contract.get_pool_shares(0, accounts(3)).0;
+        contract.remove_liquidity(0, U128(shares / 2), vec![U128(1), U128(1)]);
+        testing_env!(context.predecessor_account_id(accounts(5)).build());
+        swap_token_1(&mut contract, one_near / 5);
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_RESERVE_BELOW_FLOOR")]
+    fn test_deny_remove_liquidity_below_reserve_floor() {
+        let one_near = 10u128.pow(24);
+        let mut context = VMContextBuilder::new();
+        let mut contract = setup_reserve_floor(&mut context);
+        testing_env!(context.predecessor_account_id(accounts(3)).build());
+        let shares = contract.get_pool_shares(0, accounts(3));
+        contract.remove_liquidity_by_tokens(0, vec![U128(0), U128(6 * one_near)], shares);
+    }
+
+    /// Approves accounts(5) of `setup_route` to receive flash loans with 0.1% fee, and lends it given amount of token 1.
+    fn setup_flash_loan(context: &mut VMContextBuilder, amount: Balance) -> Contract {
+        let mut contract = setup_route(context);
+        testing_env!(context
+            .predecessor_account_id(accounts(0))
+            .attached_deposit(1)
+            .prepaid_gas(Gas(300 * 10u64.pow(12)))
+            .build());
+        contract.add_flash_loan_receiver(accounts(5));
+        contract.set_flash_loan_fee(10);
+        testing_env!(context.predecessor_account_id(accounts(5)).build());
+        contract.flash_loan(0, accounts(1), amount.into(), accounts(5), "".to_string());
+        context.predecessor_account_id(env::current_account_id());
+        testing_env_with_promise_results(context.build(), PromiseResult::Successful(vec![]));
+        contract.on_flash_loan_transfer(
+            FlashLoan {
+                pool_id: 0,
+                token_id: accounts(1),
+                amount: amount.into(),
+                fee: U128(amount / 1000),
+                receiver_id: accounts(5),
+                token_in: None,
+                amount_in: U128(0),
+            },
+            accounts(5),
+            "".to_string(),
+        );
+        contract
+    }
+
+    #[test]
+    fn test_flash_loan() {
+        let one_near = 10u128.pow(24);
+        let mut context = VMContextBuilder::new();
+        let mut contract = setup_flash_loan(&mut context, one_near);
+
+        // Receiver deposits the loan plus fee back.
+        testing_env!(context.predecessor_account_id(accounts(1)).build());
+        contract.ft_on_transfer(
+            accounts(5),
+            U128(one_near + one_near / 1000),
+            "".to_string(),
+        );
+        context.predecessor_account_id(env::current_account_id());
+        testing_env_with_promise_results(context.build(), PromiseResult::Successful(vec![]));
+        let repaid = contract.resolve_flash_loan(FlashLoan {
+            pool_id: 0,
+            token_id: accounts(1),
+            amount: one_near.into(),
+            fee: U128(one_near / 1000),
+            receiver_id: accounts(5),
+            token_in: None,
+            amount_in: U128(0),
+        });
+        assert_eq!(repaid, U128(one_near + one_near / 1000));
+        assert_eq!(
+            contract.get_deposit(&accounts(5), &accounts(1)),
+            U128(one_near)
+        );
+        assert_eq!(
+            contract.get_pool(0).amounts[0],
+            U128(10 * one_near + one_near / 1000)
+        );
+    }
+
+    #[test]
+    fn test_flash_loan_not_repaid() {
+        let one_near = 10u128.pow(24);
+        let mut context = VMContextBuilder::new();
+        let mut contract = setup_flash_loan(&mut context, 2 * one_near);
+        // Only the deposit of the receiver is taken, the rest is lost by the pool.
+        testing_env_with_promise_results(context.build(), PromiseResult::Successful(vec![]));
+        let repaid = contract.resolve_flash_loan(FlashLoan {
+            pool_id: 0,
+            token_id: accounts(1),
+            amount: U128(2 * one_near),
+            fee: U128(2 * one_near / 1000),
+            receiver_id: accounts(5),
+            token_in: None,
+            amount_in: U128(0),
+        });
+        assert_eq!(repaid, U128(one_near));
+        assert_eq!(contract.get_deposit(&accounts(5), &accounts(1)), U128(0));
+        assert_eq!(contract.get_pool(0).amounts[0], U128(9 * one_near));
+    }
+
+    #[test]
+    fn test_flash_loan_resolve_failed() {
+        let one_near = 10u128.pow(24);
+        let mut context = VMContextBuilder::new();
+        let mut contract = setup_flash_loan(&mut context, 2 * one_near);
+        // Failed resolve still takes the repayment and releases the pool.
+        testing_env_with_promise_results(context.build(), PromiseResult::Failed);
+        let repaid = contract.on_flash_loan_resolved(FlashLoan {
+            pool_id: 0,
+            token_id: accounts(1),
+            amount: U128(2 * one_near),
+            fee: U128(2 * one_near / 1000),
+            receiver_id: accounts(5),
+            token_in: None,
+            amount_in: U128(0),
+        });
+        assert_eq!(repaid, U128(one_near));
+        assert!(!contract.flash_loan_pools.contains(&0));
+        assert_eq!(contract.get_pool(0).amounts[0], U128(9 * one_near));
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_FLASH_LOAN_IN_PROGRESS")]
+    fn test_deny_remove_liquidity_during_flash_loan() {
+        let one_near = 10u128.pow(24);
+        let mut context = VMContextBuilder::new();
+        let mut contract = setup_flash_loan(&mut context, one_near);
+        testing_env!(context
+            .predecessor_account_id(accounts(3))
+            .attached_deposit(1)
+            .build());
+        contract.remove_liquidity(0, U128(one_near), vec![U128(1), U128(1)]);
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_FLASH_LOAN_IN_PROGRESS")]
+    fn test_deny_swap_during_flash_loan() {
+        let one_near = 10u128.pow(24);
+        let mut context = VMContextBuilder::new();
+        let mut contract = setup_flash_loan(&mut context, one_near);
+        testing_env!(context.predecessor_account_id(accounts(5)).build());
+        swap_token_1(&mut contract, one_near / 10);
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_FLASH_LOAN_IN_PROGRESS")]
+    fn test_deny_add_liquidity_during_flash_loan() {
+        let one_near = 10u128.pow(24);
+        let mut context = VMContextBuilder::new();
+        let mut contract = setup_flash_loan(&mut context, one_near);
+        testing_env!(context.predecessor_account_id(accounts(3)).build());
+        contract.add_liquidity(0, vec![U128(one_near), U128(one_near)], U128(0));
+    }
+
+    /// Approves accounts(5) of `setup_route` to receive flash loans with 0.1% fee, and flash swaps it given amount
+    /// of token 2 for token 1 from pool 0. Returns the loan.
+    fn setup_flash_swap(
+        context: &mut VMContextBuilder,
+        contract: &mut Contract,
+        amount_out: Balance,
+    ) -> FlashLoan {
+        testing_env!(context
+            .predecessor_account_id(accounts(0))
+            .attached_deposit(1)
+            .prepaid_gas(Gas(300 * 10u64.pow(12)))
+            .build());
+        contract.add_flash_loan_receiver(accounts(5));
+        contract.set_flash_loan_fee(10);
+        testing_env!(context.predecessor_account_id(accounts(5)).build());
+        contract.register_tokens(vec![accounts(2)]);
+        contract.flash_swap(
+            0,
+            accounts(1),
+            accounts(2),
+            amount_out.into(),
+            accounts(5),
+            "".to_string(),
+        );
+        let loan = FlashLoan {
+            pool_id: 0,
+            token_id: accounts(2),
+            amount: amount_out.into(),
+            fee: U128(amount_out / 1000),
+            receiver_id: accounts(5),
+            token_in: Some(accounts(1)),
+            amount_in: contract
+                .internal_get_pool(0)
+                .get_amount_in(&accounts(1), amount_out, &accounts(2))
+                .into(),
+        };
+        context.predecessor_account_id(env::current_account_id());
+        testing_env_with_promise_results(context.build(), PromiseResult::Successful(vec![]));
+        contract.on_flash_loan_transfer(loan.clone(), accounts(5), "".to_string());
+        loan
+    }
+
+    #[test]
+    fn test_flash_swap() {
+        let one_near = 10u128.pow(24);
+        let mut context = VMContextBuilder::new();
+        let mut contract = setup_route(&mut context);
+        let loan = setup_flash_swap(&mut context, &mut contract, one_near / 2);
+        assert!(loan.amount_in.0 > one_near / 2 && loan.amount_in.0 < one_near);
+
+        // Amount in is swapped from the deposits of the receiver, which keeps the loan.
+        testing_env_with_promise_results(context.build(), PromiseResult::Successful(vec![]));
+        let repaid = contract.resolve_flash_loan(loan.clone());
+        assert_eq!(repaid, loan.amount_in);
+        assert_eq!(
+            contract.get_deposit(&accounts(5), &accounts(1)),
+            U128(one_near - loan.amount_in.0)
+        );
+        let pool = contract.get_pool(0);
+        assert_eq!(pool.amounts[0], U128(10 * one_near + loan.amount_in.0));
+        let surplus = contract.get_deposit(&accounts(5), &accounts(2));
+        assert_eq!(pool.amounts[1].0 + surplus.0, 10 * one_near - one_near / 2);
+    }
+
+    #[test]
+    fn test_flash_swap_repaid_as_loan() {
+        let one_near = 10u128.pow(24);
+        let mut context = VMContextBuilder::new();
+        let mut contract = setup_route(&mut context);
+        // Amount in is more than the receiver has, so it repays the loan plus fee in token out.
+        let loan = setup_flash_swap(&mut context, &mut contract, one_near);
+        assert!(loan.amount_in.0 > one_near);
+        testing_env!(context.predecessor_account_id(accounts(2)).build());
+        contract.ft_on_transfer(
+            accounts(5),
+            U128(one_near + one_near / 1000),
+            "".to_string(),
+        );
+        context.predecessor_account_id(env::current_account_id());
+        testing_env_with_promise_results(context.build(), PromiseResult::Successful(vec![]));
+        let repaid = contract.resolve_flash_loan(loan);
+        assert_eq!(repaid, U128(one_near + one_near / 1000));
+        assert_eq!(
+            contract.get_deposit(&accounts(5), &accounts(1)),
+            U128(one_near)
+        );
+        assert_eq!(
+            contract.get_pool(0).amounts,
+            vec![U128(10 * one_near), U128(10 * one_near + one_near / 1000)]
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_NOT_FLASH_LOAN_RECEIVER")]
+    fn test_deny_flash_loan_not_approved() {
+        let mut context = VMContextBuilder::new();
+        let mut contract = setup_route(&mut context);
+        testing_env!(context
+            .predecessor_account_id(accounts(5))
+            .attached_deposit(0)
+            .build());
+        contract.flash_loan(0, accounts(1), U128(1), accounts(5), "".to_string());
     }
 }
@@ -0,0 +1,298 @@
+//! Resting limit orders. A maker locks token in from the deposits, asking for at least given amount of token out.
+//! Orders are filled either directly by a taker paying token out from the deposits (also partially, at the order price),
+//! or by anyone against a pool once its price reaches the order, who keeps whatever the swap returns above the ask.
+//! Maker and taker fees are charged on what each side receives, and collected for the owner.
+
+use crate::errors::{require, PoolError, UnwrapOrPanic};
+use crate::utils::U256;
+use crate::*;
+
+/// Order to sell remaining `amount_in` of token in for at least `amount_out` of token out.
+/// Partial fills reduce both amounts, keeping the price of the order.
+#[derive(BorshSerialize, BorshDeserialize)]
+pub struct LimitOrder {
+    /// Account that placed the order and receives the token out.
+    pub owner_id: AccountId,
+    /// Token sold by the order.
+    pub token_in: AccountId,
+    /// Remaining amount of token in.
+    pub amount_in: Balance,
+    /// Token bought by the order.
+    pub token_out: AccountId,
+    /// Amount of token out asked for the remaining amount of token in.
+    pub amount_out: Balance,
+}
+
+/// Limit order as returned by views.
+#[derive(Debug, Serialize, Deserialize, PartialEq)]
+#[serde(crate = "near_sdk::serde")]
+pub struct LimitOrderInfo {
+    /// Id of the order.
+    pub order_id: U64,
+    /// Account that placed the order.
+    pub owner_id: AccountId,
+    /// Token sold by the order.
+    pub token_in: AccountId,
+    /// Remaining amount of token in.
+    pub amount_in: U128,
+    /// Token bought by the order.
+    pub token_out: AccountId,
+    /// Amount of token out asked for the remaining amount of token in.
+    pub amount_out: U128,
+}
+
+/// Fees of limit orders, in 1/10000 of the amount each side receives.
+#[derive(
+    BorshSerialize, BorshDeserialize, Serialize, Deserialize, Clone, Copy, Debug, PartialEq,
+)]
+#[serde(crate = "near_sdk::serde")]
+pub struct LimitOrderFees {
+    /// Fee on token out received by the maker.
+    pub maker_fee: u32,
+    /// Fee on token in received by the taker of a direct fill.
+    pub taker_fee: u32,
+}
+
+/// Returns given fee rate of the amount.
+fn fee_amount(amount: Balance, fee: u32) -> Balance {
+    (U256::from(amount) * U256::from(fee) / U256::from(FEE_DIVISOR)).as_u128()
+}
+
+#[near_bindgen]
+impl Contract {
+    /// Places order to sell `amount_in` of token_in from the deposits of the caller for at least `amount_out` of token_out.
+    /// The caller must have token_out registered, and pays storage of the order. Returns id of the order.
+    pub fn add_limit_order(
+        &mut self,
+        token_in: AccountId,
+        amount_in: U128,
+        token_out: AccountId,
+        amount_out: U128,
+    ) -> U64 {
+        self.assert_running();
+        require(token_in != token_out, PoolError::SameToken);
+        require(amount_in.0 > 0 && amount_out.0 > 0, PoolError::AmountZero);
+        let sender_id = env::predecessor_account_id();
+        let mut deposits = self.internal_get_account(&sender_id);
+        require(
+            deposits.tokens.contains_key(&token_out),
+            PoolError::TokenNotRegistered,
+        );
+        deposits.withdraw(&token_in, amount_in.into());
+        self.deposited_amounts.insert(&sender_id, &deposits);
+        let order_id = self.next_limit_order_id;
+        self.next_limit_order_id += 1;
+        let prev_storage = env::storage_usage();
+        self.limit_orders.insert(
+            &order_id,
+            &LimitOrder {
+                owner_id: sender_id.clone(),
+                token_in,
+                amount_in: amount_in.into(),
+                token_out,
+                amount_out: amount_out.into(),
+            },
+        );
+        self.internal_update_storage(&sender_id, prev_storage);
+        self.internal_open_position(&sender_id);
+        log!("Limit order {} placed by {}", order_id, sender_id);
+        order_id.into()
+    }
+
+    /// Cancels order of the caller, returning its remaining token in to the deposits.
+    pub fn cancel_limit_order(&mut self, order_id: U64) {
+        let sender_id = env::predecessor_account_id();
+        let order = self.internal_get_limit_order(order_id.into());
+        require(order.owner_id == sender_id, PoolError::NotOwner);
+        self.internal_remove_limit_order(order_id.into(), &order);
+        self.internal_refund_maker(&order.owner_id, &order.token_in, order.amount_in);
+        log!("Limit order {} cancelled", order_id.0);
+    }
+
+    /// Fills the order directly: the caller pays `amount` of its token out from the deposits, and receives
+    /// the matching part of its token in at the order price, less the taker fee. Returns amount of token in received.
+    pub fn fill_limit_order(&mut self, order_id: U64, amount: U128) -> U128 {
+        self.assert_running();
+        let sender_id = env::predecessor_account_id();
+        let mut order = self.internal_get_limit_order(order_id.into());
+        let amount: Balance = amount.into();
+        require(amount <= order.amount_out, PoolError::NotEnough);
+        let amount_in = (U256::from(amount) * U256::from(order.amount_in)
+            / U256::from(order.amount_out))
+        .as_u128();
+        require(amount_in > 0, PoolError::AmountZero);
+        self.internal_withdraw_tokens(&sender_id, &[order.token_out.clone()], &[amount]);
+        let taker_fee = fee_amount(amount_in, self.limit_order_fees.taker_fee);
+        self.internal_deposit_tokens(
+            &sender_id,
+            &[order.token_in.clone()],
+            &[amount_in - taker_fee],
+        );
+        self.internal_collect_limit_order_fee(&order.token_in, taker_fee);
+        order.amount_in -= amount_in;
+        order.amount_out -= amount;
+        self.internal_pay_maker(order_id.into(), order, amount);
+        U128(amount_in - taker_fee)
+    }
+
+    /// Fills the whole order by swapping its remaining token in through given pool. Fails unless the swap
+    /// returns at least the amount of token out asked by the order. The caller receives the surplus into the deposits,
+    /// and must have token out registered if there is any. Returns the surplus.
+    pub fn execute_limit_order(&mut self, order_id: U64, pool_id: u64) -> U128 {
+        let sender_id = env::predecessor_account_id();
+        let order = self.internal_get_limit_order(order_id.into());
+        let amount_out = self.internal_swap(
+            &order.owner_id,
+            pool_id,
+            &order.token_in,
+            order.amount_in,
+            &order.token_out,
+            order.amount_out,
+            None,
+        );
+        let surplus = amount_out - order.amount_out;
+        self.internal_deposit_tokens(&sender_id, &[order.token_out.clone()], &[surplus]);
+        let amount = order.amount_out;
+        self.internal_pay_maker(
+            order_id.into(),
+            LimitOrder {
+                amount_in: 0,
+                amount_out: 0,
+                ..order
+            },
+            amount,
+        );
+        surplus.into()
+    }
+
+    /// Sets fees of limit orders, in 1/10000 of the amount each side receives. Only owner and fee setters can call it.
+    #[payable]
+    pub fn set_limit_order_fees(&mut self, fees: LimitOrderFees) {
+        assert_one_yocto();
+        self.assert_role(Role::FeeSetter);
+        self.assert_no_timelock();
+        self.internal_set_limit_order_fees(fees);
+    }
+
+    /// Moves fees of limit orders collected in given token to the deposits of the owner,
+    /// who must have the token registered. Only owner can call it.
+    #[payable]
+    pub fn withdraw_limit_order_fees(&mut self, token_id: AccountId) -> U128 {
+        assert_one_yocto();
+        self.assert_owner();
+        let amount = self
+            .limit_order_collected_fees
+            .remove(&token_id)
+            .unwrap_or_default();
+        let owner_id = self.owner_id.clone();
+        self.internal_deposit_tokens(&owner_id, &[token_id], &[amount]);
+        amount.into()
+    }
+
+    /// Returns fees of limit orders.
+    pub fn get_limit_order_fees(&self) -> LimitOrderFees {
+        self.limit_order_fees
+    }
+
+    /// Returns fees of limit orders collected in given token and not withdrawn by the owner yet.
+    pub fn get_limit_order_collected_fees(&self, token_id: AccountId) -> U128 {
+        self.limit_order_collected_fees
+            .get(&token_id)
+            .unwrap_or_default()
+            .into()
+    }
+
+    /// Returns given limit order, or None if it doesn't exist, e.g. because it was filled or cancelled.
+    pub fn get_limit_order(&self, order_id: U64) -> Option<LimitOrderInfo> {
+        self.limit_orders
+            .get(&order_id.into())
+            .map(|order| LimitOrderInfo::new(order_id.into(), order))
+    }
+
+    /// Returns open limit orders, in no particular order, e.g. to find the ones to fill.
+    pub fn get_limit_orders(&self, from_index: u64, limit: u64) -> Vec<LimitOrderInfo> {
+        let keys = self.limit_orders.keys_as_vector();
+        let values = self.limit_orders.values_as_vector();
+        (from_index..std::cmp::min(from_index + limit, keys.len()))
+            .map(|index| LimitOrderInfo::new(keys.get(index).unwrap(), values.get(index).unwrap()))
+            .collect()
+    }
+}
+
+impl LimitOrderInfo {
+    fn new(order_id: u64, order: LimitOrder) -> Self {
+        Self {
+            order_id: order_id.into(),
+            owner_id: order.owner_id,
+            token_in: order.token_in,
+            amount_in: order.amount_in.into(),
+            token_out: order.token_out,
+            amount_out: order.amount_out.into(),
+        }
+    }
+}
+
+impl Contract {
+    pub(crate) fn internal_set_limit_order_fees(&mut self, fees: LimitOrderFees) {
+        require(
+            fees.maker_fee < FEE_DIVISOR && fees.taker_fee < FEE_DIVISOR,
+            PoolError::FeeTooLarge,
+        );
+        self.limit_order_fees = fees;
+    }
+
+    fn internal_get_limit_order(&self, order_id: u64) -> LimitOrder {
+        self.limit_orders
+            .get(&order_id)
+            .unwrap_or_panic(PoolError::NoLimitOrder)
+    }
+
+    /// Removes the order, releasing its storage to the owner.
+    fn internal_remove_limit_order(&mut self, order_id: u64, order: &LimitOrder) {
+        let prev_storage = env::storage_usage();
+        self.limit_orders.remove(&order_id);
+        self.internal_update_storage(&order.owner_id, prev_storage);
+        self.internal_close_position(&order.owner_id);
+    }
+
+    /// Credits the maker of the order with given amount of token out less the maker fee, and saves the order
+    /// after the fill, removing it once nothing is left to fill.
+    fn internal_pay_maker(&mut self, order_id: u64, order: LimitOrder, amount: Balance) {
+        let maker_fee = fee_amount(amount, self.limit_order_fees.maker_fee);
+        self.internal_collect_limit_order_fee(&order.token_out, maker_fee);
+        self.internal_refund_maker(&order.owner_id, &order.token_out, amount - maker_fee);
+        if order.amount_out == 0 {
+            self.internal_remove_limit_order(order_id, &order);
+            // Rounding of partial fills may leave dust of token in.
+            self.internal_refund_maker(&order.owner_id, &order.token_in, order.amount_in);
+            log!("Limit order {} filled", order_id);
+        } else {
+            self.limit_orders.insert(&order_id, &order);
+            log!("Limit order {} partially filled", order_id);
+        }
+    }
+
+    /// Adds given amount of token to the deposits of the maker, registering the token if needed,
+    /// so the maker can't block fills and cancellation by unregistering it.
+    fn internal_refund_maker(
+        &mut self,
+        owner_id: &AccountId,
+        token_id: &AccountId,
+        amount: Balance,
+    ) {
+        let mut deposits = self.internal_get_account(owner_id);
+        deposits.refund(token_id, amount);
+        self.deposited_amounts.insert(owner_id, &deposits);
+    }
+
+    fn internal_collect_limit_order_fee(&mut self, token_id: &AccountId, amount: Balance) {
+        if amount > 0 {
+            let collected = self
+                .limit_order_collected_fees
+                .get(token_id)
+                .unwrap_or_default();
+            self.limit_order_collected_fees
+                .insert(token_id, &(collected + amount));
+        }
+    }
+}
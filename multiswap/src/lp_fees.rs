@@ -0,0 +1,186 @@
+//! Swap fees earned by liquidity providers. Each pool accumulates the fees its liquidity providers get
+//! (what's left of the swap fee after referral and protocol fees) per share, and each provider keeps a checkpoint
+//! of that growth, settled whenever its shares change and dropped once it has no shares left.
+//! Fees stay in the pool like before, this only reports them, separately from price movements of its tokens.
+//! Providers are also listed per pool along with their checkpoints, so they can be enumerated, e.g. for airdrops.
+//! Providers whose shares didn't change since the list was introduced aren't in it.
+
+use crate::utils::U256;
+use crate::*;
+
+/// Precision of the fee growth per share.
+const FEE_GROWTH_PRECISION: u128 = 1_000_000_000_000_000_000_000_000;
+
+/// Fees earned by a liquidity provider in a pool, as of the last change of its shares.
+#[derive(BorshSerialize, BorshDeserialize)]
+pub struct FeeCheckpoint {
+    /// Fee growth per share of the pool at the last change, in the order of its tokens.
+    pub fee_growth: Vec<u128>,
+    /// Fees earned until the last change, in the order of the pool's tokens.
+    pub earned: Vec<Balance>,
+}
+
+/// Liquidity provider of a pool, returned by `get_liquidity_providers`.
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+#[serde(crate = "near_sdk::serde")]
+pub struct LiquidityProviderInfo {
+    pub account_id: AccountId,
+    pub shares: U128,
+}
+
+#[near_bindgen]
+impl Contract {
+    /// Returns swap fees given account earned with its shares of the pool since it got them,
+    /// in the order of the pool's tokens. Fees are forgotten once the account has no shares left.
+    pub fn get_fees_earned(&self, pool_id: u64, account_id: AccountId) -> Vec<U128> {
+        let pool = self.internal_get_pool(pool_id);
+        self.internal_fees_earned(pool_id, &pool, &account_id)
+            .earned
+            .into_iter()
+            .map(U128)
+            .collect()
+    }
+
+    /// Returns list of liquidity providers of the pool of given length from given start index, with their shares.
+    /// Shares held for position tokens, vaults and farms are listed under this contract.
+    pub fn get_liquidity_providers(
+        &self,
+        pool_id: u64,
+        from_index: u64,
+        limit: u64,
+    ) -> Vec<LiquidityProviderInfo> {
+        let pool = self.internal_get_pool(pool_id);
+        let providers = match self.liquidity_providers.get(&pool_id) {
+            Some(providers) => providers,
+            None => return vec![],
+        };
+        let accounts = providers.as_vector();
+        (from_index..std::cmp::min(from_index + limit, accounts.len()))
+            .map(|index| {
+                let account_id = accounts.get(index).unwrap();
+                LiquidityProviderInfo {
+                    shares: pool.share_balances(&account_id).into(),
+                    account_id,
+                }
+            })
+            .collect()
+    }
+}
+
+impl Contract {
+    /// Returns fee growth per share of the pool, which only pools with shares have.
+    fn internal_fee_growth(&self, pool_id: u64, pool: &Pool) -> Option<Vec<u128>> {
+        match pool {
+            Pool::ConcentratedPool(_) | Pool::BondingCurvePool(_) | Pool::RetiredPool(_) => None,
+            _ => Some(
+                self.fee_growth
+                    .get(&pool_id)
+                    .unwrap_or_else(|| vec![0; pool.tokens().len()]),
+            ),
+        }
+    }
+
+    /// Returns checkpoint of the account brought up to the current fee growth of the pool.
+    fn internal_fees_earned(
+        &self,
+        pool_id: u64,
+        pool: &Pool,
+        account_id: &AccountId,
+    ) -> FeeCheckpoint {
+        let fee_growth = self
+            .internal_fee_growth(pool_id, pool)
+            .unwrap_or_else(|| vec![0; pool.tokens().len()]);
+        let mut checkpoint = self
+            .fee_checkpoints
+            .get(&(pool_id, account_id.clone()))
+            .unwrap_or_else(|| FeeCheckpoint {
+                fee_growth: fee_growth.clone(),
+                earned: vec![0; fee_growth.len()],
+            });
+        let shares = pool.share_balances(account_id);
+        let earned = checkpoint.earned.iter_mut();
+        for ((earned, prev_growth), growth) in earned.zip(&checkpoint.fee_growth).zip(&fee_growth) {
+            // Growth wraps around, so the difference is right as long as it's below 2^128.
+            let growth = growth.wrapping_sub(*prev_growth);
+            *earned += (U256::from(shares) * U256::from(growth) / U256::from(FEE_GROWTH_PRECISION))
+                .as_u128();
+        }
+        checkpoint.fee_growth = fee_growth;
+        checkpoint
+    }
+
+    /// Settles fees earned by the account with its current shares of the pool, and lists it as a provider of the pool.
+    /// Must be called before its shares change, within the storage accounting of the change.
+    pub(crate) fn internal_settle_lp_fees(
+        &mut self,
+        pool_id: u64,
+        pool: &Pool,
+        account_id: &AccountId,
+    ) {
+        if self.internal_fee_growth(pool_id, pool).is_none() {
+            return;
+        }
+        let checkpoint = self.internal_fees_earned(pool_id, pool, account_id);
+        self.fee_checkpoints
+            .insert(&(pool_id, account_id.clone()), &checkpoint);
+        let mut providers = self
+            .liquidity_providers
+            .get(&pool_id)
+            .unwrap_or_else(|| UnorderedSet::new(format!("J{}", pool_id).into_bytes()));
+        if providers.insert(account_id) {
+            self.liquidity_providers.insert(&pool_id, &providers);
+        }
+    }
+
+    /// Removes checkpoint and listing of the account once it has no shares of the pool left, releasing their storage.
+    /// Must be called after its shares decreased, within the storage accounting of the change.
+    pub(crate) fn internal_prune_lp_fees(
+        &mut self,
+        pool_id: u64,
+        pool: &Pool,
+        account_id: &AccountId,
+    ) {
+        if pool.share_balances(account_id) == 0 {
+            self.fee_checkpoints.remove(&(pool_id, account_id.clone()));
+            if let Some(mut providers) = self.liquidity_providers.get(&pool_id) {
+                if !providers.remove(account_id) {
+                    return;
+                }
+                if providers.is_empty() {
+                    self.liquidity_providers.remove(&pool_id);
+                } else {
+                    self.liquidity_providers.insert(&pool_id, &providers);
+                }
+            }
+        }
+    }
+
+    /// Adds what's left for liquidity providers of the fee of a swap of given amount in, charged at given fee rate,
+    /// after `fee_taken` went to the referral and the protocol, to the fee growth per share of the pool.
+    pub(crate) fn internal_accrue_lp_fees(
+        &mut self,
+        pool_id: u64,
+        pool: &Pool,
+        token_id: &AccountId,
+        amount_in: Balance,
+        fee: u32,
+        fee_taken: Balance,
+    ) {
+        let mut fee_growth = match self.internal_fee_growth(pool_id, pool) {
+            Some(fee_growth) => fee_growth,
+            None => return,
+        };
+        let total_shares = pool.share_total_balance();
+        let amount = (U256::from(amount_in) * U256::from(fee) / U256::from(FEE_DIVISOR))
+            .as_u128()
+            .saturating_sub(fee_taken);
+        if amount == 0 || total_shares == 0 {
+            return;
+        }
+        let index = pool.tokens().iter().position(|id| id == token_id).unwrap();
+        let growth =
+            U256::from(amount) * U256::from(FEE_GROWTH_PRECISION) / U256::from(total_shares);
+        fee_growth[index] = fee_growth[index].wrapping_add(growth.low_u128());
+        self.fee_growth.insert(&pool_id, &fee_growth);
+    }
+}
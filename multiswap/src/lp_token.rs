@@ -0,0 +1,147 @@
+//! Wrapping of pool shares into NEP-141 tokens (see the `lp-token` contract), so they can be used by protocols
+//! that only understand fungible tokens, e.g. as collateral in lending.
+//! Wrapped shares are held by this contract, next to the shares moved into the deposits with `deposit_shares`.
+
+use near_sdk::serde_json::json;
+
+use crate::errors::{require, PoolError, UnwrapOrPanic};
+use crate::utils::{
+    ext_lp_token, lp_token_id, GAS_FOR_LP_TOKEN_BURN, GAS_FOR_LP_TOKEN_MINT, GAS_FOR_LP_TOKEN_NEW,
+    GAS_FOR_ON_LP_TOKEN_BURN, GAS_FOR_ON_LP_TOKEN_DEPLOY, GAS_FOR_ON_LP_TOKEN_MINT,
+};
+use crate::*;
+
+/// Storage key of the code deployed by `deploy_lp_token`.
+const LP_TOKEN_CODE_KEY: &[u8] = b"lp_token_code";
+
+#[near_bindgen]
+impl Contract {
+    /// Sets code of the token wrapping pool shares, i.e. the compiled `lp-token` contract. Only owner can call it.
+    #[payable]
+    pub fn set_lp_token_code(&mut self, #[serializer(borsh)] code: Vec<u8>) {
+        assert_one_yocto();
+        self.assert_owner();
+        env::storage_write(LP_TOKEN_CODE_KEY, &code);
+    }
+
+    /// Deploys token wrapping shares of given pool at `lp_token_id(pool_id)`, with this contract allowed to mint and burn it.
+    /// Attached NEAR is transferred to the token account and must cover storage of its code. Only owner and pool managers can call it.
+    #[payable]
+    pub fn deploy_lp_token(&mut self, pool_id: u64) -> Promise {
+        self.assert_role(Role::PoolManager);
+        match self.internal_get_pool(pool_id) {
+            Pool::ConcentratedPool(_) => PoolError::UsePositions.panic(),
+            Pool::BondingCurvePool(_) => PoolError::NoLiquidityShares.panic(),
+            _ => {}
+        }
+        let code = env::storage_read(LP_TOKEN_CODE_KEY).unwrap_or_panic(PoolError::NoLpTokenCode);
+        require(self.lp_tokens.insert(&pool_id), PoolError::LpTokenExists);
+        Promise::new(lp_token_id(pool_id))
+            .create_account()
+            .transfer(env::attached_deposit())
+            .deploy_contract(code)
+            .function_call(
+                "new".to_string(),
+                json!({ "owner_id": env::current_account_id(), "pool_id": pool_id })
+                    .to_string()
+                    .into_bytes(),
+                0,
+                GAS_FOR_LP_TOKEN_NEW,
+            )
+            .then(
+                ext_self::ext(env::current_account_id())
+                    .with_static_gas(GAS_FOR_ON_LP_TOKEN_DEPLOY)
+                    .on_lp_token_deploy(pool_id),
+            )
+    }
+
+    /// Callback after deploying the token. Forgets the token if the deployment failed, so it can be retried.
+    #[private]
+    pub fn on_lp_token_deploy(&mut self, pool_id: u64) {
+        require(env::promise_results_count() == 1, PoolError::Invalid);
+        if let PromiseResult::Successful(_) = env::promise_result(0) {
+            return;
+        }
+        log!("Deployment of the token of pool {} failed", pool_id);
+        self.lp_tokens.remove(&pool_id);
+    }
+
+    /// Wraps given number of caller's shares of the pool into its token, minted to the caller.
+    /// The caller must be registered in the token with `storage_deposit`, otherwise the shares are returned.
+    /// Requires attached 1 yoctoNEAR.
+    #[payable]
+    pub fn wrap_shares(&mut self, pool_id: u64, amount: U128) -> Promise {
+        assert_one_yocto();
+        require(self.lp_tokens.contains(&pool_id), PoolError::NoLpToken);
+        let sender_id = env::predecessor_account_id();
+        let mut pool = self.internal_get_pool(pool_id);
+        let prev_storage = env::storage_usage();
+        self.internal_settle_lp_fees(pool_id, &pool, &sender_id);
+        self.internal_settle_lp_fees(pool_id, &pool, &env::current_account_id());
+        pool.share_transfer(&sender_id, &env::current_account_id(), amount.into());
+        self.internal_prune_lp_fees(pool_id, &pool, &sender_id);
+        self.assert_shares_unlocked(pool_id, &pool, &sender_id);
+        self.internal_set_pool(pool_id, pool);
+        self.internal_update_storage(&sender_id, prev_storage);
+        ext_lp_token::ext(lp_token_id(pool_id))
+            .with_static_gas(GAS_FOR_LP_TOKEN_MINT)
+            .mint(sender_id.clone(), amount)
+            .then(
+                ext_self::ext(env::current_account_id())
+                    .with_static_gas(GAS_FOR_ON_LP_TOKEN_MINT)
+                    .on_lp_token_mint(pool_id, sender_id, amount),
+            )
+    }
+
+    /// Callback after minting wrapped shares. Returns the shares to the sender if minting failed.
+    #[private]
+    pub fn on_lp_token_mint(&mut self, pool_id: u64, sender_id: AccountId, amount: U128) {
+        require(env::promise_results_count() == 1, PoolError::Invalid);
+        if let PromiseResult::Successful(_) = env::promise_result(0) {
+            return;
+        }
+        log!(
+            "Minting {} wrapped shares of pool {} to {} failed, returning the shares",
+            amount.0,
+            pool_id,
+            sender_id
+        );
+        self.internal_return_shares(
+            pool_id,
+            &env::current_account_id(),
+            &sender_id,
+            amount.into(),
+        );
+    }
+
+    /// Burns given amount of caller's token of the pool and returns the wrapped shares to the caller.
+    /// Requires attached 1 yoctoNEAR.
+    #[payable]
+    pub fn unwrap_shares(&mut self, pool_id: u64, amount: U128) -> Promise {
+        assert_one_yocto();
+        require(self.lp_tokens.contains(&pool_id), PoolError::NoLpToken);
+        let sender_id = env::predecessor_account_id();
+        ext_lp_token::ext(lp_token_id(pool_id))
+            .with_static_gas(GAS_FOR_LP_TOKEN_BURN)
+            .burn(sender_id.clone(), amount)
+            .then(
+                ext_self::ext(env::current_account_id())
+                    .with_static_gas(GAS_FOR_ON_LP_TOKEN_BURN)
+                    .on_lp_token_burn(pool_id, sender_id, amount),
+            )
+    }
+
+    /// Callback after burning wrapped shares. Returns the shares to the sender if the burn succeeded.
+    #[private]
+    pub fn on_lp_token_burn(&mut self, pool_id: u64, sender_id: AccountId, amount: U128) {
+        require(env::promise_results_count() == 1, PoolError::Invalid);
+        if let PromiseResult::Successful(_) = env::promise_result(0) {
+            self.internal_return_shares(
+                pool_id,
+                &env::current_account_id(),
+                &sender_id,
+                amount.into(),
+            );
+        }
+    }
+}
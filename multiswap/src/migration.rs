@@ -0,0 +1,223 @@
+//! Upgrades in place. The contract state records the version of its layout, and `migrate`, called right after
+//! deploying new code, reads the state written by the previous code and rewrites it in the current layout.
+//! Every change of the layout adds a version: the previous `Contract` is kept here as the struct of its version,
+//! and `migrate` converts it into the current one. The state is recognized by the layout it deserializes into
+//! completely, which also covers the state of the first release, written before it recorded a version.
+//! Records inside collections can't be rewritten at once: pools convert when loaded, see `VersionedPool`,
+//! and deposits of the first release when they're read, see `AccountDeposits`.
+
+use std::collections::HashMap;
+
+use crate::errors::{PoolError, UnwrapOrPanic};
+use crate::simple_pool::SimplePoolV0;
+use crate::utils::{ext_self, GAS_FOR_ON_BALANCE};
+use crate::*;
+
+/// Storage key of the contract state.
+const STATE_KEY: &[u8] = b"STATE";
+
+/// Version of the layout of the contract state.
+#[derive(
+    BorshSerialize, BorshDeserialize, Serialize, Deserialize, Clone, Copy, Debug, PartialEq,
+)]
+#[serde(crate = "near_sdk::serde")]
+pub enum StateVersion {
+    V1,
+    V2,
+}
+
+/// Version of the layout written by this code.
+pub const CURRENT_STATE_VERSION: StateVersion = StateVersion::V2;
+
+/// Layout of `Pool` in the first release, stored without `VersionedPool`.
+#[derive(BorshSerialize, BorshDeserialize)]
+pub enum PoolV0 {
+    SimplePool(SimplePoolV0),
+}
+
+/// Layout of `Contract` in the first release, before it recorded a version.
+#[derive(BorshSerialize, BorshDeserialize)]
+pub struct ContractV0 {
+    pub pools: Vector<PoolV0>,
+    pub deposited_amounts: LookupMap<AccountId, HashMap<AccountId, Balance>>,
+}
+
+/// Layout of `Contract` in `StateVersion::V1`, before roles, LP fees, position tokens, vaults, farms,
+/// pool statistics, reserve floors, Aurora outputs, token metadata and tracking of transfers and positions.
+#[derive(BorshSerialize, BorshDeserialize)]
+pub struct ContractV1 {
+    pub owner_id: AccountId,
+    pub pending_owner_id: Option<AccountId>,
+    pub max_num_tokens: u32,
+    pub pools: Vector<VersionedPool>,
+    pub deposited_amounts: LookupMap<AccountId, AccountDeposits>,
+    pub fee_tiers: LookupMap<Vec<AccountId>, Vec<u64>>,
+    pub token_pair_pools: LookupMap<Vec<AccountId>, Vec<u64>>,
+    pub token_pools: LookupMap<AccountId, Vec<u64>>,
+    pub wnear_id: Option<AccountId>,
+    pub measured_balances: LookupMap<AccountId, Balance>,
+    pub whitelisted_tokens: UnorderedSet<AccountId>,
+    pub token_balances: LookupMap<AccountId, Balance>,
+    pub lp_tokens: UnorderedSet<u64>,
+    pub referral_fee: u32,
+    pub limit_orders: UnorderedMap<u64, LimitOrder>,
+    pub next_limit_order_id: u64,
+    pub limit_order_fees: LimitOrderFees,
+    pub limit_order_collected_fees: LookupMap<AccountId, Balance>,
+    pub twap_oracles: LookupMap<u64, TwapOracle>,
+    pub flash_loan_receivers: UnorderedSet<AccountId>,
+    pub flash_loan_fee: u32,
+    pub flash_loan_pools: UnorderedSet<u64>,
+    pub circuit_breakers: LookupMap<u64, CircuitBreaker>,
+    pub batch_auctions: LookupMap<u64, BatchAuction>,
+    pub stop_orders: UnorderedMap<u64, StopOrder>,
+    pub next_stop_order_id: u64,
+    pub stop_order_bounty: u32,
+    pub permissionless_pool_creation: bool,
+    pub pool_creators: UnorderedSet<AccountId>,
+    pub pool_creation_deposit: Balance,
+    pub pool_creation_deposits: LookupMap<u64, (AccountId, Balance)>,
+    pub guardians: UnorderedSet<AccountId>,
+    pub paused_pools: UnorderedSet<u64>,
+    pub running_state: RunningState,
+    pub protocol_fee: u32,
+    pub protocol_fees: LookupMap<u64, Vec<Balance>>,
+    pub treasury_id: Option<AccountId>,
+    pub timelock_delay: u64,
+    pub admin_actions: UnorderedMap<u64, PendingAdminAction>,
+    pub next_admin_action_id: u64,
+    pub dao_id: Option<AccountId>,
+    pub state_version: StateVersion,
+}
+
+impl From<ContractV1> for Contract {
+    /// Fields added since start empty, as they are in a new contract.
+    fn from(contract: ContractV1) -> Self {
+        let new = Contract::internal_new(contract.owner_id.clone());
+        Contract {
+            owner_id: contract.owner_id,
+            pending_owner_id: contract.pending_owner_id,
+            max_num_tokens: contract.max_num_tokens,
+            pools: contract.pools,
+            deposited_amounts: contract.deposited_amounts,
+            fee_tiers: contract.fee_tiers,
+            token_pair_pools: contract.token_pair_pools,
+            token_pools: contract.token_pools,
+            wnear_id: contract.wnear_id,
+            measured_balances: contract.measured_balances,
+            whitelisted_tokens: contract.whitelisted_tokens,
+            token_balances: contract.token_balances,
+            lp_tokens: contract.lp_tokens,
+            referral_fee: contract.referral_fee,
+            limit_orders: contract.limit_orders,
+            next_limit_order_id: contract.next_limit_order_id,
+            limit_order_fees: contract.limit_order_fees,
+            limit_order_collected_fees: contract.limit_order_collected_fees,
+            twap_oracles: contract.twap_oracles,
+            flash_loan_receivers: contract.flash_loan_receivers,
+            flash_loan_fee: contract.flash_loan_fee,
+            flash_loan_pools: contract.flash_loan_pools,
+            circuit_breakers: contract.circuit_breakers,
+            batch_auctions: contract.batch_auctions,
+            stop_orders: contract.stop_orders,
+            next_stop_order_id: contract.next_stop_order_id,
+            stop_order_bounty: contract.stop_order_bounty,
+            permissionless_pool_creation: contract.permissionless_pool_creation,
+            pool_creators: contract.pool_creators,
+            pool_creation_deposit: contract.pool_creation_deposit,
+            pool_creation_deposits: contract.pool_creation_deposits,
+            guardians: contract.guardians,
+            paused_pools: contract.paused_pools,
+            running_state: contract.running_state,
+            protocol_fee: contract.protocol_fee,
+            protocol_fees: contract.protocol_fees,
+            treasury_id: contract.treasury_id,
+            timelock_delay: contract.timelock_delay,
+            admin_actions: contract.admin_actions,
+            next_admin_action_id: contract.next_admin_action_id,
+            dao_id: contract.dao_id,
+            ..new
+        }
+    }
+}
+
+#[near_bindgen]
+impl Contract {
+    /// Reads the state written by the previous code and rewrites it in the current layout.
+    /// Must be called by the contract itself, in the same transaction as the deployment of the new code.
+    /// State of the first release has no owner, so migrating it requires `owner_id`, which is ignored otherwise.
+    #[private]
+    #[init(ignore_state)]
+    pub fn migrate(owner_id: Option<AccountId>) -> Self {
+        let state = env::storage_read(STATE_KEY).unwrap_or_panic(PoolError::ContractNotInitialized);
+        let contract = if let Ok(contract) = Contract::try_from_slice(&state) {
+            contract
+        } else if let Ok(contract) = ContractV1::try_from_slice(&state) {
+            log!(
+                "Migrated state from {:?} to {:?}",
+                contract.state_version,
+                CURRENT_STATE_VERSION
+            );
+            contract.into()
+        } else {
+            let contract = ContractV0::try_from_slice(&state)
+                .unwrap_or_panic(PoolError::ContractNotInitialized);
+            let owner_id = owner_id.unwrap_or_panic(PoolError::OwnerIdRequired);
+            log!(
+                "Migrated state of the first release to {:?}",
+                CURRENT_STATE_VERSION
+            );
+            Contract::internal_migrate_v0(contract, owner_id)
+        };
+        Contract {
+            state_version: CURRENT_STATE_VERSION,
+            ..contract
+        }
+    }
+
+    /// Returns version of the layout of the contract state.
+    pub fn get_state_version(&self) -> StateVersion {
+        self.state_version
+    }
+
+    /// Callback from a token with the balance of the contract when migrating the state of the first release,
+    /// which didn't record what pools and deposits hold. The balance is added to what was recorded since,
+    /// so deposits and withdrawals in the meantime may overstate it, but never count deposits as excess.
+    #[private]
+    pub fn on_migrated_token_balance(
+        &mut self,
+        token_id: AccountId,
+        #[callback_unwrap] balance: U128,
+    ) {
+        self.internal_add_token_balance(&token_id, balance.into());
+    }
+}
+
+impl Contract {
+    /// Converts state of the first release, rewriting its pools in the current layout and indexing them.
+    /// Their tokens are whitelisted, and their balances held by the contract are queried to be recorded.
+    fn internal_migrate_v0(state: ContractV0, owner_id: AccountId) -> Self {
+        let mut contract = Contract::internal_new(owner_id);
+        let mut token_ids: Vec<AccountId> = vec![];
+        for pool_id in 0..state.pools.len() {
+            let PoolV0::SimplePool(pool) = state.pools.get(pool_id).unwrap();
+            let pool = Pool::SimplePool(pool.into());
+            for token_id in pool.tokens() {
+                if !token_ids.contains(token_id) {
+                    token_ids.push(token_id.clone());
+                }
+            }
+            contract.internal_index_pool(pool_id, pool.tokens());
+            contract.pools.push(&pool.into());
+        }
+        for token_id in token_ids {
+            contract.internal_add_whitelisted_token(&token_id);
+            contract.internal_query_balance(&token_id).then(
+                ext_self::ext(env::current_account_id())
+                    .with_static_gas(GAS_FOR_ON_BALANCE)
+                    .on_migrated_token_balance(token_id),
+            );
+        }
+        contract
+    }
+}
@@ -0,0 +1,226 @@
+//! Multi-token (MFT) interface over liquidity shares of the pools, so other contracts (e.g. farms)
+//! can hold and move them. Shares of pool `N` have token id `:N`. Owners of shares can also approve a spender
+//! (e.g. a vault) to pull given number of them with `mft_transfer_from`.
+
+use near_sdk::{serde_json, PromiseOrValue};
+
+use crate::errors::{require, PoolError, UnwrapOrPanic};
+use crate::utils::{ext_mft_receiver, GAS_FOR_MFT_RESOLVE_TRANSFER, GAS_FOR_MFT_TRANSFER_CALL};
+use crate::*;
+
+/// Returns id of the pool, which shares have given multi-token id.
+fn parse_pool_id(token_id: &str) -> u64 {
+    require(token_id.starts_with(':'), PoolError::WrongTokenId);
+    token_id[1..]
+        .parse()
+        .ok()
+        .unwrap_or_panic(PoolError::WrongTokenId)
+}
+
+#[near_bindgen]
+impl Contract {
+    /// Transfers given number of caller's shares of the pool to the receiver, which must be registered.
+    /// Storage taken by the receiver's shares is paid by the caller. Requires attached 1 yoctoNEAR.
+    #[payable]
+    pub fn mft_transfer(
+        &mut self,
+        token_id: String,
+        receiver_id: AccountId,
+        amount: U128,
+        memo: Option<String>,
+    ) {
+        assert_one_yocto();
+        self.internal_mft_transfer(
+            &token_id,
+            &env::predecessor_account_id(),
+            &receiver_id,
+            amount.into(),
+            memo,
+        );
+    }
+
+    /// Transfers given number of caller's shares of the pool to the receiver, e.g. another account of the caller
+    /// or a buyer of the position, like `mft_transfer` of token `:pool_id`. The receiver must be registered, and
+    /// storage taken by its shares is paid by the caller. Requires attached 1 yoctoNEAR.
+    #[payable]
+    pub fn share_transfer(&mut self, pool_id: u64, receiver_id: AccountId, shares: U128) {
+        assert_one_yocto();
+        self.internal_mft_transfer(
+            &format!(":{}", pool_id),
+            &env::predecessor_account_id(),
+            &receiver_id,
+            shares.into(),
+            None,
+        );
+    }
+
+    /// Transfers shares like `mft_transfer` and calls `mft_on_transfer` on the receiver with given `msg`.
+    /// The receiver returns the number of shares it didn't use, which are transferred back to the caller
+    /// in `mft_resolve_transfer`. Requires attached 1 yoctoNEAR.
+    #[payable]
+    pub fn mft_transfer_call(
+        &mut self,
+        token_id: String,
+        receiver_id: AccountId,
+        amount: U128,
+        memo: Option<String>,
+        msg: String,
+    ) -> PromiseOrValue<U128> {
+        assert_one_yocto();
+        let sender_id = env::predecessor_account_id();
+        self.internal_mft_transfer(&token_id, &sender_id, &receiver_id, amount.into(), memo);
+        ext_mft_receiver::ext(receiver_id.clone())
+            .with_static_gas(env::prepaid_gas() - GAS_FOR_MFT_TRANSFER_CALL)
+            .mft_on_transfer(token_id.clone(), sender_id.clone(), amount, msg)
+            .then(
+                ext_self::ext(env::current_account_id())
+                    .with_static_gas(GAS_FOR_MFT_RESOLVE_TRANSFER)
+                    .mft_resolve_transfer(token_id, sender_id, receiver_id, amount),
+            )
+            .into()
+    }
+
+    /// Callback after `mft_on_transfer` of the receiver. Transfers unused shares back to the sender,
+    /// all of them if the receiver failed, but not more than the receiver still has.
+    /// Returns the number of shares the receiver kept.
+    #[private]
+    pub fn mft_resolve_transfer(
+        &mut self,
+        token_id: String,
+        sender_id: AccountId,
+        receiver_id: AccountId,
+        amount: U128,
+    ) -> U128 {
+        require(env::promise_results_count() == 1, PoolError::Invalid);
+        let pool_id = parse_pool_id(&token_id);
+        let unused_amount = match env::promise_result(0) {
+            PromiseResult::Successful(value) => serde_json::from_slice::<U128>(&value)
+                .map(|unused_amount| std::cmp::min(amount.0, unused_amount.0))
+                .unwrap_or(amount.0),
+            _ => amount.0,
+        };
+        let refund_amount = std::cmp::min(
+            unused_amount,
+            self.internal_get_pool(pool_id).share_balances(&receiver_id),
+        );
+        if refund_amount > 0 {
+            log!(
+                "Returning {} shares of pool {} from {} to {}",
+                refund_amount,
+                pool_id,
+                receiver_id,
+                sender_id
+            );
+            self.internal_return_shares(pool_id, &receiver_id, &sender_id, refund_amount);
+        }
+        U128(amount.0 - refund_amount)
+    }
+
+    /// Approves the spender to transfer given number of caller's shares of the pool with `mft_transfer_from`,
+    /// replacing the previous allowance; 0 revokes it. Storage of the allowance is paid by the caller.
+    /// Requires attached 1 yoctoNEAR.
+    #[payable]
+    pub fn mft_approve(&mut self, token_id: String, spender_id: AccountId, amount: U128) {
+        assert_one_yocto();
+        let pool_id = parse_pool_id(&token_id);
+        let owner_id = env::predecessor_account_id();
+        let key = (pool_id, owner_id.clone(), spender_id);
+        let prev_storage = env::storage_usage();
+        if amount.0 > 0 {
+            self.share_allowances.insert(&key, &amount.0);
+        } else {
+            self.share_allowances.remove(&key);
+        }
+        self.internal_update_storage(&owner_id, prev_storage);
+        log!(
+            "{} approved {} to transfer {} shares of pool {}",
+            owner_id,
+            key.2,
+            amount.0,
+            pool_id
+        );
+    }
+
+    /// Transfers given number of the owner's shares of the pool to the receiver, which must be registered,
+    /// spending the caller's allowance. Storage taken by the receiver's shares is paid by the owner.
+    /// Requires attached 1 yoctoNEAR.
+    #[payable]
+    pub fn mft_transfer_from(
+        &mut self,
+        token_id: String,
+        owner_id: AccountId,
+        receiver_id: AccountId,
+        amount: U128,
+        memo: Option<String>,
+    ) {
+        assert_one_yocto();
+        let pool_id = parse_pool_id(&token_id);
+        let key = (pool_id, owner_id, env::predecessor_account_id());
+        let allowance = self.share_allowances.get(&key).unwrap_or(0);
+        require(allowance >= amount.0, PoolError::NotEnoughAllowance);
+        let prev_storage = env::storage_usage();
+        if allowance > amount.0 {
+            self.share_allowances.insert(&key, &(allowance - amount.0));
+        } else {
+            self.share_allowances.remove(&key);
+        }
+        self.internal_update_storage(&key.1, prev_storage);
+        self.internal_mft_transfer(&token_id, &key.1, &receiver_id, amount.into(), memo);
+    }
+
+    /// Returns number of the owner's shares of the pool the spender can still transfer.
+    pub fn mft_allowance(
+        &self,
+        token_id: String,
+        owner_id: AccountId,
+        spender_id: AccountId,
+    ) -> U128 {
+        let key = (parse_pool_id(&token_id), owner_id, spender_id);
+        U128(self.share_allowances.get(&key).unwrap_or(0))
+    }
+
+    /// Returns number of shares of the pool given account has.
+    pub fn mft_balance_of(&self, token_id: String, account_id: AccountId) -> U128 {
+        self.get_pool_shares(parse_pool_id(&token_id), account_id)
+    }
+
+    /// Returns total number of shares of the pool.
+    pub fn mft_total_supply(&self, token_id: String) -> U128 {
+        self.get_pool_total_shares(parse_pool_id(&token_id))
+    }
+}
+
+impl Contract {
+    /// Transfers shares of the pool with given multi-token id to the receiver, which must be registered.
+    /// Storage taken by the receiver's shares is paid by the sender.
+    fn internal_mft_transfer(
+        &mut self,
+        token_id: &str,
+        sender_id: &AccountId,
+        receiver_id: &AccountId,
+        amount: Balance,
+        memo: Option<String>,
+    ) {
+        let pool_id = parse_pool_id(token_id);
+        self.internal_get_account(receiver_id);
+        let mut pool = self.internal_get_pool(pool_id);
+        let prev_storage = env::storage_usage();
+        self.internal_settle_lp_fees(pool_id, &pool, sender_id);
+        self.internal_settle_lp_fees(pool_id, &pool, receiver_id);
+        pool.share_transfer(sender_id, receiver_id, amount);
+        self.internal_prune_lp_fees(pool_id, &pool, sender_id);
+        self.assert_shares_unlocked(pool_id, &pool, sender_id);
+        self.internal_set_pool(pool_id, pool);
+        self.internal_update_storage(sender_id, prev_storage);
+        log!(
+            "Transferred {} shares of pool {} from {} to {}",
+            amount,
+            pool_id,
+            sender_id,
+            receiver_id
+        );
+        if let Some(memo) = memo {
+            log!("Memo: {}", memo);
+        }
+    }
+}
@@ -1,13 +1,108 @@
 use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
 use near_sdk::{AccountId, Balance};
 
+use crate::errors::{require, CheckedAsU128, PoolError, UnwrapOrPanic};
+use crate::utils::U256;
+
+use crate::bonding_curve_pool::BondingCurvePool;
+use crate::concentrated_pool::ConcentratedPool;
+use crate::constant_sum_pool::ConstantSumPool;
 use crate::simple_pool::SimplePool;
+use crate::stable_swap_pool::{StableSwapPool, StableSwapPoolV1};
+use crate::weighted_pool::WeightedPool;
+
+/// Number of shares locked without owner out of the first deposit into a pool, so its share supply never drops
+/// back to zero. Otherwise the first depositor could remove all but one share and donate tokens to the pool,
+/// inflating the price of a share so much that later deposits get rounded down to nothing.
+pub const MIN_LIQUIDITY: Balance = 1_000;
+
+/// Tombstone of a pool retired with `retire_pool`, keeping only what views show about it.
+#[derive(BorshSerialize, BorshDeserialize)]
+pub struct RetiredPool {
+    /// List of tokens the pool had.
+    pub token_account_ids: Vec<AccountId>,
+    /// Fee the pool charged for swap.
+    pub fee: u32,
+}
 
 /// Generic Pool, providing wrapper around different implementations of swap pools.
 /// Allows to add new types of pools just by adding extra item in the enum without needing to migrate the storage.
 #[derive(BorshSerialize, BorshDeserialize)]
 pub enum Pool {
     SimplePool(SimplePool),
+    StableSwapPool(StableSwapPool),
+    WeightedPool(WeightedPool),
+    ConcentratedPool(ConcentratedPool),
+    ConstantSumPool(ConstantSumPool),
+    BondingCurvePool(BondingCurvePool),
+    RetiredPool(RetiredPool),
+}
+
+/// Pool as stored in the contract state. Once a pool struct changes its fields, `Pool` with the old struct is kept
+/// here as a previous version, and converted to the current one when loaded. So pools stored before an upgrade
+/// keep deserializing without migrating the whole state, and are rewritten in the new layout once they change.
+#[derive(BorshSerialize, BorshDeserialize)]
+pub enum VersionedPool {
+    V1(PoolV1),
+    Current(Pool),
+}
+
+/// `Pool` before stable swap pools recorded when their rates were fetched.
+#[derive(BorshSerialize, BorshDeserialize)]
+#[allow(clippy::enum_variant_names)]
+pub enum PoolV1 {
+    SimplePool(SimplePool),
+    StableSwapPool(StableSwapPoolV1),
+    WeightedPool(WeightedPool),
+    ConcentratedPool(ConcentratedPool),
+    ConstantSumPool(ConstantSumPool),
+    BondingCurvePool(BondingCurvePool),
+    RetiredPool(RetiredPool),
+}
+
+impl From<PoolV1> for Pool {
+    fn from(pool: PoolV1) -> Self {
+        match pool {
+            PoolV1::SimplePool(pool) => Pool::SimplePool(pool),
+            PoolV1::StableSwapPool(pool) => Pool::StableSwapPool(pool.into()),
+            PoolV1::WeightedPool(pool) => Pool::WeightedPool(pool),
+            PoolV1::ConcentratedPool(pool) => Pool::ConcentratedPool(pool),
+            PoolV1::ConstantSumPool(pool) => Pool::ConstantSumPool(pool),
+            PoolV1::BondingCurvePool(pool) => Pool::BondingCurvePool(pool),
+            PoolV1::RetiredPool(pool) => Pool::RetiredPool(pool),
+        }
+    }
+}
+
+impl From<VersionedPool> for Pool {
+    fn from(pool: VersionedPool) -> Self {
+        match pool {
+            VersionedPool::V1(pool) => pool.into(),
+            VersionedPool::Current(pool) => pool,
+        }
+    }
+}
+
+impl From<Pool> for VersionedPool {
+    fn from(pool: Pool) -> Self {
+        VersionedPool::Current(pool)
+    }
+}
+
+/// Returns true if every token has the same value in both pools, given tokens of each pool and their values
+/// in the same order. Both pools have the same tokens, possibly in a different order.
+fn same_per_token<T: PartialEq>(
+    tokens: &[AccountId],
+    values: &[T],
+    other_tokens: &[AccountId],
+    other_values: &[T],
+) -> bool {
+    tokens.iter().zip(values).all(|(token_id, value)| {
+        other_tokens
+            .iter()
+            .zip(other_values)
+            .any(|(other_id, other_value)| other_id == token_id && other_value == value)
+    })
 }
 
 impl Pool {
@@ -15,21 +110,197 @@ impl Pool {
     pub fn kind(&self) -> String {
         match self {
             Pool::SimplePool(_) => "SIMPLE_POOL".to_string(),
+            Pool::StableSwapPool(_) => "STABLE_SWAP".to_string(),
+            Pool::WeightedPool(_) => "WEIGHTED_POOL".to_string(),
+            Pool::ConcentratedPool(_) => "CONCENTRATED_POOL".to_string(),
+            Pool::ConstantSumPool(_) => "CONSTANT_SUM".to_string(),
+            Pool::BondingCurvePool(_) => "BONDING_CURVE".to_string(),
+            Pool::RetiredPool(_) => "RETIRED".to_string(),
+        }
+    }
+
+    /// Returns base fee charged for swap in the underlying pool.
+    pub fn fee(&self) -> u32 {
+        match self {
+            Pool::SimplePool(pool) => pool.fee,
+            Pool::StableSwapPool(pool) => pool.fee,
+            Pool::WeightedPool(pool) => pool.fee,
+            Pool::ConcentratedPool(pool) => pool.fee,
+            Pool::ConstantSumPool(pool) => pool.fee,
+            Pool::BondingCurvePool(pool) => pool.fee,
+            Pool::RetiredPool(pool) => pool.fee,
         }
     }
 
+    /// Returns fee currently charged for swap, which for dynamic fee pools can be higher than base fee.
+    pub fn current_fee(&self) -> u32 {
+        match self {
+            Pool::SimplePool(pool) => pool.current_fee(),
+            _ => self.fee(),
+        }
+    }
+
+    /// Returns true if the other pool over the same tokens has the same kind, fee and parameters of its kind,
+    /// i.e. it would be a duplicate of this one. Weighted pools with changing weights are never duplicates.
+    pub fn same_parameters(&self, other: &Pool) -> bool {
+        let tokens = self.tokens();
+        let other_tokens = other.tokens();
+        if self.fee() != other.fee() {
+            return false;
+        }
+        match (self, other) {
+            (Pool::SimplePool(pool), Pool::SimplePool(other)) => {
+                match (&pool.dynamic_fee, &other.dynamic_fee) {
+                    (None, None) => true,
+                    (Some(fee), Some(other_fee)) => {
+                        fee.max_fee == other_fee.max_fee
+                            && fee.volatility_threshold == other_fee.volatility_threshold
+                            && fee.decay_period == other_fee.decay_period
+                    }
+                    _ => false,
+                }
+            }
+            (Pool::StableSwapPool(pool), Pool::StableSwapPool(other)) => {
+                pool.amp_factor == other.amp_factor
+                    && pool.base_pool_id == other.base_pool_id
+                    && same_per_token(
+                        tokens,
+                        &pool.token_decimals,
+                        other_tokens,
+                        &other.token_decimals,
+                    )
+                    && same_per_token(
+                        tokens,
+                        &pool.rate_providers,
+                        other_tokens,
+                        &other.rate_providers,
+                    )
+            }
+            (Pool::WeightedPool(pool), Pool::WeightedPool(other)) => {
+                pool.weight_schedule.is_none()
+                    && other.weight_schedule.is_none()
+                    && same_per_token(tokens, &pool.weights, other_tokens, &other.weights)
+            }
+            (Pool::ConcentratedPool(pool), Pool::ConcentratedPool(other)) => {
+                pool.tick_spacing == other.tick_spacing
+            }
+            (Pool::ConstantSumPool(pool), Pool::ConstantSumPool(other)) => {
+                pool.min_reserve_bps == other.min_reserve_bps
+            }
+            (Pool::BondingCurvePool(pool), Pool::BondingCurvePool(other)) => {
+                pool.initial_price == other.initial_price
+                    && pool.slope == other.slope
+                    && pool.graduation_reserve == other.graduation_reserve
+            }
+            _ => false,
+        }
+    }
+
+    /// Returns true if the underlying pool has any liquidity to swap against.
+    pub fn has_liquidity(&self) -> bool {
+        match self {
+            Pool::ConcentratedPool(pool) => pool.amounts.iter().any(|amount| *amount > 0),
+            Pool::BondingCurvePool(pool) => !pool.is_closed(),
+            _ => self.share_total_balance() > 0,
+        }
+    }
+
+    /// Returns true if the underlying pool holds no tokens and no liquidity, so it can be retired.
+    /// Concentrated pools must also have no initialized ticks, i.e. no positions with liquidity.
+    /// Pools keep the locked `MIN_LIQUIDITY` shares and the tokens backing them, so once funded they never are.
+    pub fn is_empty(&self) -> bool {
+        let no_ticks = match self {
+            Pool::ConcentratedPool(pool) => pool.ticks.len() == 0,
+            _ => true,
+        };
+        no_ticks
+            && self.share_total_balance() == 0
+            && self.amounts().iter().all(|amount| *amount == 0)
+    }
+
     /// Returns which tokens are in the underlying pool.
     pub fn tokens(&self) -> &[AccountId] {
         match self {
             Pool::SimplePool(pool) => pool.tokens(),
+            Pool::StableSwapPool(pool) => pool.tokens(),
+            Pool::WeightedPool(pool) => pool.tokens(),
+            Pool::ConcentratedPool(pool) => pool.tokens(),
+            Pool::ConstantSumPool(pool) => pool.tokens(),
+            Pool::BondingCurvePool(pool) => pool.tokens(),
+            Pool::RetiredPool(pool) => &pool.token_account_ids,
         }
     }
 
     /// Adds liquidity into underlying pool.
-    pub fn add_liquidity(&mut self, sender_id: &AccountId, amounts: Vec<Balance>) -> Balance {
-        match self {
+    /// Updates amounts to amounts actually used.
+    /// First deposit into the pool gets `MIN_LIQUIDITY` shares less, which stay locked.
+    pub fn add_liquidity(&mut self, sender_id: &AccountId, amounts: &mut Vec<Balance>) -> Balance {
+        let first_deposit = self.share_total_balance() == 0;
+        let shares = match self {
             Pool::SimplePool(pool) => pool.add_liquidity(sender_id, amounts),
+            Pool::StableSwapPool(pool) => pool.add_liquidity(sender_id, amounts),
+            Pool::WeightedPool(pool) => pool.add_liquidity(sender_id, amounts),
+            Pool::ConcentratedPool(_) => PoolError::UsePositions.panic(),
+            Pool::ConstantSumPool(pool) => pool.add_liquidity(sender_id, amounts),
+            Pool::BondingCurvePool(_) => PoolError::NoLiquidityShares.panic(),
+            Pool::RetiredPool(_) => PoolError::PoolRetired.panic(),
+        };
+        let shares = if first_deposit {
+            self.lock_min_liquidity(sender_id, shares)
+        } else {
+            shares
+        };
+        #[cfg(feature = "invariant-checks")]
+        self.assert_invariants();
+        shares
+    }
+
+    /// Adds liquidity in arbitrary proportion into underlying pool, charging fee on the imbalanced part.
+    /// Stable swap and constant sum pools always accept imbalanced deposits.
+    /// First deposit into the pool gets `MIN_LIQUIDITY` shares less, which stay locked.
+    pub fn add_liquidity_imbalanced(
+        &mut self,
+        sender_id: &AccountId,
+        amounts: Vec<Balance>,
+    ) -> Balance {
+        let first_deposit = self.share_total_balance() == 0;
+        let shares = match self {
+            Pool::SimplePool(pool) => pool.add_liquidity_imbalanced(sender_id, amounts),
+            Pool::StableSwapPool(pool) => {
+                let mut amounts = amounts;
+                pool.add_liquidity(sender_id, &mut amounts)
+            }
+            Pool::WeightedPool(pool) => pool.add_liquidity_imbalanced(sender_id, amounts),
+            Pool::ConcentratedPool(_) => PoolError::UsePositions.panic(),
+            Pool::ConstantSumPool(pool) => {
+                let mut amounts = amounts;
+                pool.add_liquidity(sender_id, &mut amounts)
+            }
+            Pool::BondingCurvePool(_) => PoolError::NoLiquidityShares.panic(),
+            Pool::RetiredPool(_) => PoolError::PoolRetired.panic(),
+        };
+        let shares = if first_deposit {
+            self.lock_min_liquidity(sender_id, shares)
+        } else {
+            shares
+        };
+        #[cfg(feature = "invariant-checks")]
+        self.assert_invariants();
+        shares
+    }
+
+    /// Locks `MIN_LIQUIDITY` of the shares minted to the account by the first deposit into the underlying pool.
+    /// Returns number of shares left to the account.
+    fn lock_min_liquidity(&mut self, sender_id: &AccountId, shares: Balance) -> Balance {
+        require(shares > MIN_LIQUIDITY, PoolError::SharesBelowMinLiquidity);
+        match self {
+            Pool::SimplePool(pool) => pool.lock_shares(sender_id, MIN_LIQUIDITY),
+            Pool::StableSwapPool(pool) => pool.lock_shares(sender_id, MIN_LIQUIDITY),
+            Pool::WeightedPool(pool) => pool.lock_shares(sender_id, MIN_LIQUIDITY),
+            Pool::ConstantSumPool(pool) => pool.lock_shares(sender_id, MIN_LIQUIDITY),
+            _ => PoolError::NoLiquidityShares.panic(),
         }
+        shares - MIN_LIQUIDITY
     }
 
     /// Removes liquidity from underlying pool.
@@ -39,11 +310,123 @@ impl Pool {
         shares: Balance,
         min_amounts: Vec<Balance>,
     ) -> Vec<Balance> {
-        match self {
+        let amounts = match self {
             Pool::SimplePool(pool) => pool.remove_liquidity(sender_id, shares, min_amounts),
+            Pool::StableSwapPool(pool) => pool.remove_liquidity(sender_id, shares, min_amounts),
+            Pool::WeightedPool(pool) => pool.remove_liquidity(sender_id, shares, min_amounts),
+            Pool::ConcentratedPool(_) => PoolError::UsePositions.panic(),
+            Pool::ConstantSumPool(pool) => pool.remove_liquidity(sender_id, shares, min_amounts),
+            Pool::BondingCurvePool(_) => PoolError::NoLiquidityShares.panic(),
+            Pool::RetiredPool(_) => PoolError::PoolRetired.panic(),
+        };
+        #[cfg(feature = "invariant-checks")]
+        self.assert_invariants();
+        amounts
+    }
+
+    /// Returns number of shares minted for adding given amounts of tokens into underlying pool like `add_liquidity`,
+    /// and amounts of them actually used.
+    pub fn predict_add_liquidity(&self, amounts: &[Balance]) -> (Balance, Vec<Balance>) {
+        let (shares, used_amounts) = match self {
+            Pool::SimplePool(pool) => pool.predict_add_liquidity(amounts),
+            Pool::StableSwapPool(pool) => pool.predict_add_liquidity(amounts),
+            Pool::WeightedPool(pool) => pool.predict_add_liquidity(amounts),
+            Pool::ConcentratedPool(_) => PoolError::UsePositions.panic(),
+            Pool::ConstantSumPool(pool) => pool.predict_add_liquidity(amounts),
+            Pool::BondingCurvePool(_) => PoolError::NoLiquidityShares.panic(),
+            Pool::RetiredPool(_) => PoolError::PoolRetired.panic(),
+        };
+        if self.share_total_balance() == 0 {
+            require(shares > MIN_LIQUIDITY, PoolError::SharesBelowMinLiquidity);
+            (shares - MIN_LIQUIDITY, used_amounts)
+        } else {
+            (shares, used_amounts)
         }
     }
 
+    /// Returns amounts of tokens received for removing given number of shares from underlying pool
+    /// like `remove_liquidity`, which takes them in proportion of the balances.
+    pub fn predict_remove_liquidity(&self, shares: Balance) -> Vec<Balance> {
+        match self {
+            Pool::ConcentratedPool(_) => PoolError::UsePositions.panic(),
+            Pool::BondingCurvePool(_) => PoolError::NoLiquidityShares.panic(),
+            Pool::RetiredPool(_) => PoolError::PoolRetired.panic(),
+            _ => {}
+        }
+        let total_shares = self.share_total_balance();
+        require(shares <= total_shares, PoolError::NotEnoughShares);
+        self.amounts()
+            .iter()
+            .map(|amount| {
+                if shares == 0 {
+                    0
+                } else {
+                    (U256::from(*amount) * U256::from(shares) / U256::from(total_shares))
+                        .checked_as_u128()
+                }
+            })
+            .collect()
+    }
+
+    /// Removes exactly given amounts from underlying pool and returns number of burnt shares.
+    pub fn remove_liquidity_by_tokens(
+        &mut self,
+        sender_id: &AccountId,
+        amounts: Vec<Balance>,
+        max_burn_shares: Balance,
+    ) -> Balance {
+        let burn_shares = match self {
+            Pool::SimplePool(pool) => {
+                pool.remove_liquidity_by_tokens(sender_id, amounts, max_burn_shares)
+            }
+            Pool::StableSwapPool(pool) => {
+                pool.remove_liquidity_by_tokens(sender_id, amounts, max_burn_shares)
+            }
+            Pool::WeightedPool(pool) => {
+                pool.remove_liquidity_by_tokens(sender_id, amounts, max_burn_shares)
+            }
+            Pool::ConcentratedPool(_) => PoolError::UsePositions.panic(),
+            Pool::ConstantSumPool(pool) => {
+                pool.remove_liquidity_by_tokens(sender_id, amounts, max_burn_shares)
+            }
+            Pool::BondingCurvePool(_) => PoolError::NoLiquidityShares.panic(),
+            Pool::RetiredPool(_) => PoolError::PoolRetired.panic(),
+        };
+        #[cfg(feature = "invariant-checks")]
+        self.assert_invariants();
+        burn_shares
+    }
+
+    /// Removes liquidity from underlying pool in a single token.
+    pub fn remove_liquidity_one_token(
+        &mut self,
+        sender_id: &AccountId,
+        shares: Balance,
+        token_out: &AccountId,
+        min_amount_out: Balance,
+    ) -> Balance {
+        let amount_out = match self {
+            Pool::SimplePool(pool) => {
+                pool.remove_liquidity_one_token(sender_id, shares, token_out, min_amount_out)
+            }
+            Pool::StableSwapPool(pool) => {
+                pool.remove_liquidity_one_token(sender_id, shares, token_out, min_amount_out)
+            }
+            Pool::WeightedPool(pool) => {
+                pool.remove_liquidity_one_token(sender_id, shares, token_out, min_amount_out)
+            }
+            Pool::ConcentratedPool(_) => PoolError::UsePositions.panic(),
+            Pool::ConstantSumPool(pool) => {
+                pool.remove_liquidity_one_token(sender_id, shares, token_out, min_amount_out)
+            }
+            Pool::BondingCurvePool(_) => PoolError::NoLiquidityShares.panic(),
+            Pool::RetiredPool(_) => PoolError::PoolRetired.panic(),
+        };
+        #[cfg(feature = "invariant-checks")]
+        self.assert_invariants();
+        amount_out
+    }
+
     /// Returns how many tokens will one receive swapping given amount of token_in for token_out.
     pub fn get_return(
         &self,
@@ -53,7 +436,33 @@ impl Pool {
     ) -> Balance {
         match self {
             Pool::SimplePool(pool) => pool.get_return(token_in, amount_in, token_out),
+            Pool::StableSwapPool(pool) => pool.get_return(token_in, amount_in, token_out),
+            Pool::WeightedPool(pool) => pool.get_return(token_in, amount_in, token_out),
+            Pool::ConcentratedPool(pool) => pool.get_return(token_in, amount_in, token_out),
+            Pool::ConstantSumPool(pool) => pool.get_return(token_in, amount_in, token_out),
+            Pool::BondingCurvePool(pool) => pool.get_return(token_in, amount_in, token_out),
+            Pool::RetiredPool(_) => PoolError::PoolRetired.panic(),
+        }
+    }
+
+    /// Returns how many tokens will one receive swapping given amount of token_in for token_out,
+    /// or None if the pool can't quote the swap without failing, e.g. because it has no liquidity.
+    /// Concentrated, constant sum and bonding curve pools are never quoted.
+    pub fn try_get_return(
+        &self,
+        token_in: &AccountId,
+        amount_in: Balance,
+        token_out: &AccountId,
+    ) -> Option<Balance> {
+        if amount_in == 0 || token_in == token_out || !self.has_liquidity() {
+            return None;
         }
+        match self {
+            Pool::SimplePool(_) | Pool::StableSwapPool(_) => {}
+            Pool::WeightedPool(pool) if amount_in <= pool.max_amount_in(token_in) => {}
+            _ => return None,
+        }
+        Some(self.get_return(token_in, amount_in, token_out))
     }
 
     /// Swaps given number of token_in for token_out and returns received amount.
@@ -64,20 +473,262 @@ impl Pool {
         token_out: &AccountId,
         min_amount_out: Balance,
     ) -> Balance {
-        match self {
+        #[cfg(feature = "invariant-checks")]
+        let prev_amounts = self.amounts().to_vec();
+        let amount_out = match self {
             Pool::SimplePool(pool) => pool.swap(token_in, amount_in, token_out, min_amount_out),
+            Pool::StableSwapPool(pool) => pool.swap(token_in, amount_in, token_out, min_amount_out),
+            Pool::WeightedPool(pool) => pool.swap(token_in, amount_in, token_out, min_amount_out),
+            Pool::ConcentratedPool(pool) => {
+                pool.swap(token_in, amount_in, token_out, min_amount_out)
+            }
+            Pool::ConstantSumPool(pool) => {
+                pool.swap(token_in, amount_in, token_out, min_amount_out)
+            }
+            Pool::BondingCurvePool(pool) => {
+                pool.swap(token_in, amount_in, token_out, min_amount_out)
+            }
+            Pool::RetiredPool(_) => PoolError::PoolRetired.panic(),
+        };
+        #[cfg(feature = "invariant-checks")]
+        self.assert_swap_invariant(&prev_amounts);
+        amount_out
+    }
+
+    /// Returns marginal price of token_in in token_out excluding fee, with `PRICE_PRECISION` precision.
+    pub fn spot_price(&self, token_in: &AccountId, token_out: &AccountId) -> U256 {
+        match self {
+            Pool::SimplePool(pool) => pool.spot_price(token_in, token_out),
+            Pool::StableSwapPool(pool) => pool.spot_price(token_in, token_out),
+            Pool::WeightedPool(pool) => pool.spot_price(token_in, token_out),
+            Pool::ConcentratedPool(pool) => pool.spot_price(token_in, token_out),
+            Pool::ConstantSumPool(pool) => pool.spot_price(token_in, token_out),
+            Pool::BondingCurvePool(pool) => pool.spot_price(token_in, token_out),
+            Pool::RetiredPool(_) => PoolError::PoolRetired.panic(),
+        }
+    }
+
+    /// Returns how many of token_in one needs to receive exactly given amount of token_out.
+    pub fn get_amount_in(
+        &self,
+        token_in: &AccountId,
+        amount_out: Balance,
+        token_out: &AccountId,
+    ) -> Balance {
+        match self {
+            Pool::SimplePool(pool) => pool.get_amount_in(token_in, amount_out, token_out),
+            Pool::StableSwapPool(pool) => pool.get_amount_in(token_in, amount_out, token_out),
+            Pool::WeightedPool(pool) => pool.get_amount_in(token_in, amount_out, token_out),
+            Pool::ConcentratedPool(pool) => pool.get_amount_in(token_in, amount_out, token_out),
+            Pool::ConstantSumPool(pool) => pool.get_amount_in(token_in, amount_out, token_out),
+            Pool::BondingCurvePool(pool) => pool.get_amount_in(token_in, amount_out, token_out),
+            Pool::RetiredPool(_) => PoolError::PoolRetired.panic(),
         }
     }
 
+    /// Swaps token_in for exactly given amount of token_out and returns charged amount of token_in.
+    pub fn swap_for_exact(
+        &mut self,
+        token_in: &AccountId,
+        max_amount_in: Balance,
+        token_out: &AccountId,
+        amount_out: Balance,
+    ) -> Balance {
+        #[cfg(feature = "invariant-checks")]
+        let prev_amounts = self.amounts().to_vec();
+        let amount_in = match self {
+            Pool::SimplePool(pool) => {
+                pool.swap_for_exact(token_in, max_amount_in, token_out, amount_out)
+            }
+            Pool::StableSwapPool(pool) => {
+                pool.swap_for_exact(token_in, max_amount_in, token_out, amount_out)
+            }
+            Pool::WeightedPool(pool) => {
+                pool.swap_for_exact(token_in, max_amount_in, token_out, amount_out)
+            }
+            Pool::ConcentratedPool(pool) => {
+                pool.swap_for_exact(token_in, max_amount_in, token_out, amount_out)
+            }
+            Pool::ConstantSumPool(pool) => {
+                pool.swap_for_exact(token_in, max_amount_in, token_out, amount_out)
+            }
+            Pool::BondingCurvePool(pool) => {
+                pool.swap_for_exact(token_in, max_amount_in, token_out, amount_out)
+            }
+            Pool::RetiredPool(_) => PoolError::PoolRetired.panic(),
+        };
+        #[cfg(feature = "invariant-checks")]
+        self.assert_swap_invariant(&prev_amounts);
+        amount_in
+    }
+
+    /// Returns balance of given token in the underlying pool.
+    pub fn reserve(&self, token_id: &AccountId) -> Balance {
+        let (tokens, amounts) = match self {
+            Pool::SimplePool(pool) => (&pool.token_account_ids, &pool.amounts),
+            Pool::StableSwapPool(pool) => (&pool.token_account_ids, &pool.amounts),
+            Pool::WeightedPool(pool) => (&pool.token_account_ids, &pool.amounts),
+            Pool::ConcentratedPool(_) => PoolError::UsePositions.panic(),
+            Pool::ConstantSumPool(pool) => (&pool.token_account_ids, &pool.amounts),
+            Pool::BondingCurvePool(_) => PoolError::NoLiquidityShares.panic(),
+            Pool::RetiredPool(_) => PoolError::PoolRetired.panic(),
+        };
+        let index = tokens
+            .iter()
+            .position(|token| token == token_id)
+            .unwrap_or_panic(PoolError::MissingToken);
+        amounts[index]
+    }
+
+    /// Adds given amount of token to the balance of the underlying pool without minting shares,
+    /// so it's shared by all liquidity providers. Pool must have liquidity.
+    pub fn donate(&mut self, token_id: &AccountId, amount: Balance) {
+        require(
+            self.share_total_balance() > 0,
+            PoolError::NotEnoughLiquidity,
+        );
+        let (tokens, amounts) = match self {
+            Pool::SimplePool(pool) => (&pool.token_account_ids, &mut pool.amounts),
+            Pool::StableSwapPool(pool) => (&pool.token_account_ids, &mut pool.amounts),
+            Pool::WeightedPool(pool) => (&pool.token_account_ids, &mut pool.amounts),
+            Pool::ConcentratedPool(_) => PoolError::UsePositions.panic(),
+            Pool::ConstantSumPool(pool) => (&pool.token_account_ids, &mut pool.amounts),
+            Pool::BondingCurvePool(_) => PoolError::NoLiquidityShares.panic(),
+            Pool::RetiredPool(_) => PoolError::PoolRetired.panic(),
+        };
+        let index = tokens
+            .iter()
+            .position(|token| token == token_id)
+            .unwrap_or_panic(PoolError::MissingToken);
+        amounts[index] += amount;
+        #[cfg(feature = "invariant-checks")]
+        self.assert_invariants();
+    }
+
+    /// Subtracts given amount of token from the balance of the underlying pool, e.g. swap fee paid
+    /// to the referral of the swap, or a flash loan that wasn't repaid.
+    pub fn withdraw_fee(&mut self, token_id: &AccountId, amount: Balance) {
+        let (tokens, amounts) = match self {
+            Pool::SimplePool(pool) => (&pool.token_account_ids, &mut pool.amounts),
+            Pool::StableSwapPool(pool) => (&pool.token_account_ids, &mut pool.amounts),
+            Pool::WeightedPool(pool) => (&pool.token_account_ids, &mut pool.amounts),
+            Pool::ConcentratedPool(_) => PoolError::UsePositions.panic(),
+            Pool::ConstantSumPool(pool) => (&pool.token_account_ids, &mut pool.amounts),
+            Pool::BondingCurvePool(_) => PoolError::NoLiquidityShares.panic(),
+            Pool::RetiredPool(_) => PoolError::PoolRetired.panic(),
+        };
+        let index = tokens
+            .iter()
+            .position(|token| token == token_id)
+            .unwrap_or_panic(PoolError::MissingToken);
+        require(amounts[index] >= amount, PoolError::NotEnoughLiquidity);
+        amounts[index] -= amount;
+        #[cfg(feature = "invariant-checks")]
+        self.assert_invariants();
+    }
+
+    /// Moves given number of shares of the underlying pool from one account to another.
+    pub fn share_transfer(
+        &mut self,
+        sender_id: &AccountId,
+        receiver_id: &AccountId,
+        shares: Balance,
+    ) {
+        match self {
+            Pool::SimplePool(pool) => pool.share_transfer(sender_id, receiver_id, shares),
+            Pool::StableSwapPool(pool) => pool.share_transfer(sender_id, receiver_id, shares),
+            Pool::WeightedPool(pool) => pool.share_transfer(sender_id, receiver_id, shares),
+            Pool::ConcentratedPool(_) => PoolError::UsePositions.panic(),
+            Pool::ConstantSumPool(pool) => pool.share_transfer(sender_id, receiver_id, shares),
+            Pool::BondingCurvePool(_) => PoolError::NoLiquidityShares.panic(),
+            Pool::RetiredPool(_) => PoolError::PoolRetired.panic(),
+        }
+    }
+
+    /// Returns total number of shares in the underlying pool.
+    /// Concentrated pools track liquidity per position and bonding curve pools don't take liquidity, so they don't have shares.
     pub fn share_total_balance(&self) -> Balance {
         match self {
             Pool::SimplePool(pool) => pool.share_total_balance(),
+            Pool::StableSwapPool(pool) => pool.share_total_balance(),
+            Pool::WeightedPool(pool) => pool.share_total_balance(),
+            Pool::ConcentratedPool(_) => 0,
+            Pool::ConstantSumPool(pool) => pool.share_total_balance(),
+            Pool::BondingCurvePool(_) | Pool::RetiredPool(_) => 0,
         }
     }
 
+    /// Returns number of shares given account has in the underlying pool.
     pub fn share_balances(&self, account_id: &AccountId) -> Balance {
         match self {
             Pool::SimplePool(pool) => pool.share_balances(account_id),
+            Pool::StableSwapPool(pool) => pool.share_balances(account_id),
+            Pool::WeightedPool(pool) => pool.share_balances(account_id),
+            Pool::ConcentratedPool(_) => 0,
+            Pool::ConstantSumPool(pool) => pool.share_balances(account_id),
+            Pool::BondingCurvePool(_) | Pool::RetiredPool(_) => 0,
+        }
+    }
+
+    /// Returns balances of all tokens in the underlying pool.
+    pub fn amounts(&self) -> &[Balance] {
+        match self {
+            Pool::SimplePool(pool) => &pool.amounts,
+            Pool::StableSwapPool(pool) => &pool.amounts,
+            Pool::WeightedPool(pool) => &pool.amounts,
+            Pool::ConcentratedPool(pool) => &pool.amounts,
+            Pool::ConstantSumPool(pool) => &pool.amounts,
+            Pool::BondingCurvePool(pool) => &pool.amounts,
+            Pool::RetiredPool(_) => &[],
         }
     }
+
+    /// Checks invariants of the underlying pool that must hold after every state change.
+    /// Concentrated pools track liquidity per position and aren't checked.
+    #[cfg(feature = "invariant-checks")]
+    pub fn assert_invariants(&self) {
+        match self {
+            Pool::SimplePool(pool) => pool.assert_invariants(),
+            Pool::StableSwapPool(pool) => pool.assert_invariants(),
+            Pool::WeightedPool(pool) => pool.assert_invariants(),
+            Pool::ConcentratedPool(_) => {}
+            Pool::ConstantSumPool(pool) => pool.assert_invariants(),
+            Pool::BondingCurvePool(pool) => pool.assert_invariants(),
+            Pool::RetiredPool(_) => {}
+        }
+    }
+
+    /// Checks invariants after the swap, including that pool invariant didn't decrease from given balances before the swap.
+    /// Bonding curve pools are fully covered by the reserve check of `assert_invariants`.
+    #[cfg(feature = "invariant-checks")]
+    fn assert_swap_invariant(&self, prev_amounts: &[Balance]) {
+        self.assert_invariants();
+        match self {
+            Pool::SimplePool(pool) => pool.assert_swap_invariant(prev_amounts),
+            Pool::StableSwapPool(pool) => pool.assert_swap_invariant(prev_amounts),
+            Pool::WeightedPool(pool) => pool.assert_swap_invariant(prev_amounts),
+            Pool::ConcentratedPool(_) => {}
+            Pool::ConstantSumPool(pool) => pool.assert_swap_invariant(prev_amounts),
+            Pool::BondingCurvePool(_) | Pool::RetiredPool(_) => {}
+        }
+    }
+
+    /// Checks that shares of given accounts and the locked minimum liquidity sum up to total supply of the underlying pool.
+    #[cfg(test)]
+    pub fn assert_shares_sum(&self, account_ids: &[AccountId]) {
+        let shares: Balance = account_ids
+            .iter()
+            .map(|account_id| self.share_balances(account_id))
+            .sum();
+        let locked = if self.share_total_balance() > 0 {
+            MIN_LIQUIDITY
+        } else {
+            0
+        };
+        assert_eq!(
+            shares + locked,
+            self.share_total_balance(),
+            "ERR_INVARIANT_VIOLATED"
+        );
+    }
 }
@@ -0,0 +1,102 @@
+//! Who can create pools and what it costs. By default anyone can, and owner can switch to allow-listed creation,
+//! where only owner (the DAO once it's set), pool managers and creators approved by them can. Besides storage, creating a pool takes `pool_creation_deposit`
+//! set by owner, which discourages spam and is refunded to the creator when the pool is retired, together with
+//! the storage retiring frees.
+
+use crate::errors::{require, PoolError};
+use crate::*;
+
+#[near_bindgen]
+impl Contract {
+    /// Switches between permissionless pool creation and creation only by owner, pool managers and approved creators.
+    /// Only owner and pool managers can call it.
+    #[payable]
+    pub fn set_permissionless_pool_creation(&mut self, permissionless: bool) {
+        assert_one_yocto();
+        self.assert_role(Role::PoolManager);
+        self.permissionless_pool_creation = permissionless;
+    }
+
+    /// Approves given account to create pools while creation is allow-listed. Only owner and pool managers can call it.
+    #[payable]
+    pub fn add_pool_creator(&mut self, account_id: AccountId) {
+        assert_one_yocto();
+        self.assert_role(Role::PoolManager);
+        self.pool_creators.insert(&account_id);
+    }
+
+    /// Revokes approval of given account to create pools. Only owner and pool managers can call it.
+    #[payable]
+    pub fn remove_pool_creator(&mut self, account_id: AccountId) {
+        assert_one_yocto();
+        self.assert_role(Role::PoolManager);
+        self.pool_creators.remove(&account_id);
+    }
+
+    /// Sets deposit taken on top of storage from pools created from now on. Only owner and fee setters can call it.
+    #[payable]
+    pub fn set_pool_creation_deposit(&mut self, pool_creation_deposit: U128) {
+        assert_one_yocto();
+        self.assert_role(Role::FeeSetter);
+        self.pool_creation_deposit = pool_creation_deposit.into();
+    }
+
+    /// Returns whether anyone can create pools.
+    pub fn is_permissionless_pool_creation(&self) -> bool {
+        self.permissionless_pool_creation
+    }
+
+    /// Returns accounts approved to create pools while creation is allow-listed.
+    pub fn get_pool_creators(&self) -> Vec<AccountId> {
+        self.pool_creators.to_vec()
+    }
+
+    /// Returns deposit taken on top of storage from newly created pools.
+    pub fn get_pool_creation_deposit(&self) -> U128 {
+        self.pool_creation_deposit.into()
+    }
+}
+
+impl Contract {
+    /// Checks that the caller can create pools, and records its deposit for the new pool, if there is any.
+    /// Returns the deposit, which the attached NEAR must cover on top of storage.
+    pub(crate) fn internal_add_pool_creation(&mut self, pool_id: u64) -> Balance {
+        let creator_id = env::predecessor_account_id();
+        require(
+            self.permissionless_pool_creation
+                || self.internal_has_role(Role::PoolManager, &creator_id)
+                || self.pool_creators.contains(&creator_id),
+            PoolError::NotPoolCreator,
+        );
+        if self.pool_creation_deposit > 0 {
+            self.pool_creation_deposits
+                .insert(&pool_id, &(creator_id, self.pool_creation_deposit));
+        }
+        self.pool_creation_deposit
+    }
+
+    /// Refunds deposit of the retired pool and storage freed since `prev_storage` to its creator.
+    /// Freed storage of pools created without a deposit, whose creator isn't recorded, goes to the caller.
+    pub(crate) fn internal_refund_pool_creation(
+        &mut self,
+        pool_id: u64,
+        prev_storage: StorageUsage,
+    ) {
+        let (receiver_id, deposit) = self
+            .pool_creation_deposits
+            .remove(&pool_id)
+            .unwrap_or_else(|| (env::predecessor_account_id(), 0));
+        let refund = deposit
+            + prev_storage.saturating_sub(env::storage_usage()) as Balance
+                * env::storage_byte_cost();
+        if refund > 0 {
+            log!(
+                "Refunding {} of pool {} to {}",
+                refund,
+                pool_id,
+                receiver_id
+            );
+            Promise::new(receiver_id).transfer(refund);
+        }
+    }
+}
@@ -0,0 +1,158 @@
+//! Statistics of the swaps of each pool: amounts swapped in and swap fees charged in each token, cumulative and
+//! in hourly buckets over the last day, so dashboards can show volume and APR of the pools straight from the contract,
+//! without an indexer. Buckets are kept in a ring of `NUM_BUCKETS`, where each hour overwrites the one a day before.
+
+use crate::dynamic_fee::timestamp_sec;
+use crate::utils::U256;
+use crate::*;
+
+/// Length of a bucket in seconds.
+const HOUR: u64 = 60 * 60;
+/// Number of hourly buckets, so the volume of the last 24 hours is available.
+const NUM_BUCKETS: u64 = 24;
+
+/// Statistics of swaps over some time.
+#[derive(BorshSerialize, BorshDeserialize)]
+pub struct SwapStats {
+    /// Amounts swapped into the pool, in the order of its tokens.
+    pub volumes: Vec<Balance>,
+    /// Swap fees charged, including the referral and protocol parts, in the order of the pool's tokens.
+    pub fees: Vec<Balance>,
+    /// Number of swaps.
+    pub num_swaps: u64,
+}
+
+/// Statistics of swaps within an hour.
+#[derive(BorshSerialize, BorshDeserialize)]
+pub struct HourlyStats {
+    /// Number of the hour since the Unix epoch.
+    pub hour: u64,
+    pub stats: SwapStats,
+}
+
+/// Statistics of the swaps of a pool.
+#[derive(BorshSerialize, BorshDeserialize)]
+pub struct PoolStats {
+    /// Statistics since they were introduced.
+    pub total: SwapStats,
+    /// Statistics of the hours with swaps, at index `hour % NUM_BUCKETS`. Grows up to `NUM_BUCKETS`.
+    pub hourly: Vec<HourlyStats>,
+}
+
+/// Statistics of the swaps of a pool, returned by `get_pool_stats` and `get_pool_volume_24h`.
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+#[serde(crate = "near_sdk::serde")]
+pub struct PoolStatsInfo {
+    pub volumes: Vec<U128>,
+    pub fees: Vec<U128>,
+    pub num_swaps: U64,
+}
+
+impl SwapStats {
+    fn new(num_tokens: usize) -> Self {
+        Self {
+            volumes: vec![0; num_tokens],
+            fees: vec![0; num_tokens],
+            num_swaps: 0,
+        }
+    }
+
+    /// Adds a swap of given amount of the token at given index, charged given fee.
+    fn record(&mut self, index: usize, amount_in: Balance, fee_amount: Balance) {
+        self.volumes[index] += amount_in;
+        self.fees[index] += fee_amount;
+        self.num_swaps += 1;
+    }
+
+    /// Adds statistics of other swaps.
+    fn add(&mut self, other: &SwapStats) {
+        for (volume, other) in self.volumes.iter_mut().zip(&other.volumes) {
+            *volume += other;
+        }
+        for (fee, other) in self.fees.iter_mut().zip(&other.fees) {
+            *fee += other;
+        }
+        self.num_swaps += other.num_swaps;
+    }
+}
+
+impl From<SwapStats> for PoolStatsInfo {
+    fn from(stats: SwapStats) -> Self {
+        Self {
+            volumes: stats.volumes.into_iter().map(U128).collect(),
+            fees: stats.fees.into_iter().map(U128).collect(),
+            num_swaps: stats.num_swaps.into(),
+        }
+    }
+}
+
+#[near_bindgen]
+impl Contract {
+    /// Returns cumulative amounts swapped into the pool and swap fees it charged, in the order of its tokens,
+    /// and the number of its swaps since the statistics were introduced.
+    pub fn get_pool_stats(&self, pool_id: u64) -> PoolStatsInfo {
+        let pool = self.internal_get_pool(pool_id);
+        self.pool_stats
+            .get(&pool_id)
+            .map(|stats| stats.total)
+            .unwrap_or_else(|| SwapStats::new(pool.tokens().len()))
+            .into()
+    }
+
+    /// Returns amounts swapped into the pool and swap fees it charged over the last 24 hours, in the order of its
+    /// tokens, and the number of its swaps. Counted in whole hours: from the start of the hour 23 hours ago until now.
+    pub fn get_pool_volume_24h(&self, pool_id: u64) -> PoolStatsInfo {
+        let pool = self.internal_get_pool(pool_id);
+        let mut volume = SwapStats::new(pool.tokens().len());
+        let hour = timestamp_sec() / HOUR;
+        if let Some(stats) = self.pool_stats.get(&pool_id) {
+            for bucket in stats.hourly.iter() {
+                if bucket.hour + NUM_BUCKETS > hour {
+                    volume.add(&bucket.stats);
+                }
+            }
+        }
+        volume.into()
+    }
+}
+
+impl Contract {
+    /// Adds a swap of given amount in, charged at given fee rate, to the statistics of the pool.
+    pub(crate) fn internal_record_swap(
+        &mut self,
+        pool_id: u64,
+        pool: &Pool,
+        token_in: &AccountId,
+        amount_in: Balance,
+        fee: u32,
+    ) {
+        let num_tokens = pool.tokens().len();
+        let mut stats = self.pool_stats.get(&pool_id).unwrap_or_else(|| PoolStats {
+            total: SwapStats::new(num_tokens),
+            hourly: vec![],
+        });
+        let index = pool.tokens().iter().position(|id| id == token_in).unwrap();
+        let fee_amount =
+            (U256::from(amount_in) * U256::from(fee) / U256::from(FEE_DIVISOR)).as_u128();
+        stats.total.record(index, amount_in, fee_amount);
+        let hour = timestamp_sec() / HOUR;
+        let bucket_index = (hour % NUM_BUCKETS) as usize;
+        while stats.hourly.len() <= bucket_index {
+            // Placeholders of hours without swaps are empty.
+            stats.hourly.push(HourlyStats {
+                hour: 0,
+                stats: SwapStats::new(num_tokens),
+            });
+        }
+        let bucket = &mut stats.hourly[bucket_index];
+        if bucket.hour != hour {
+            // The bucket holds the hour a day before or earlier, or is a placeholder.
+            *bucket = HourlyStats {
+                hour,
+                stats: SwapStats::new(num_tokens),
+            };
+        }
+        bucket.stats.record(index, amount_in, fee_amount);
+        self.pool_stats.insert(&pool_id, &stats);
+    }
+}
@@ -0,0 +1,363 @@
+//! Non-fungible (NEP-171) tokens of liquidity positions, so a position can be shown in wallets and transferred
+//! or sold as a whole. Minting moves given shares of a pool from the caller to the contract, where they are held
+//! for the token, and burning moves them back to the owner of the token, who can then remove the liquidity.
+//! Tokens have ids `0`, `1`, ..., and implement enumeration (NEP-181) and metadata (NEP-177) views.
+
+use near_sdk::{serde_json, PromiseOrValue};
+
+use crate::dynamic_fee::timestamp_sec;
+use crate::errors::{require, PoolError, UnwrapOrPanic};
+use crate::utils::{ext_nft_receiver, GAS_FOR_NFT_RESOLVE_TRANSFER, GAS_FOR_NFT_TRANSFER_CALL};
+use crate::*;
+
+/// Shares of a pool held for a token.
+#[derive(BorshSerialize, BorshDeserialize)]
+pub struct PositionNft {
+    /// Owner of the token.
+    pub owner_id: AccountId,
+    /// Pool of the shares.
+    pub pool_id: u64,
+    /// Number of shares.
+    pub shares: Balance,
+    /// Timestamp in seconds when the token was minted.
+    pub minted_at: u64,
+    /// Amounts of tokens the shares could be removed for when the token was minted, in the order of the pool's tokens.
+    pub entry_amounts: Vec<Balance>,
+}
+
+/// Metadata of the contract's tokens (NEP-177).
+#[derive(Debug, Serialize, Deserialize, PartialEq)]
+#[serde(crate = "near_sdk::serde")]
+pub struct NftContractMetadata {
+    pub spec: String,
+    pub name: String,
+    pub symbol: String,
+}
+
+/// Metadata of a token (NEP-177), shown by wallets.
+#[derive(Debug, Serialize, Deserialize, PartialEq)]
+#[serde(crate = "near_sdk::serde")]
+pub struct PositionNftMetadata {
+    pub title: String,
+    pub description: String,
+    /// Timestamp in milliseconds when the token was minted.
+    pub issued_at: String,
+}
+
+/// Token of a liquidity position as returned by views.
+#[derive(Debug, Serialize, Deserialize, PartialEq)]
+#[serde(crate = "near_sdk::serde")]
+pub struct PositionNftToken {
+    pub token_id: String,
+    pub owner_id: AccountId,
+    pub metadata: PositionNftMetadata,
+    /// Pool of the shares.
+    pub pool_id: u64,
+    /// Number of shares.
+    pub shares: U128,
+    /// Timestamp in seconds when the token was minted.
+    pub minted_at: U64,
+    /// Amounts of tokens the shares could be removed for when the token was minted, in the order of the pool's tokens.
+    pub entry_amounts: Vec<U128>,
+}
+
+/// Returns id of the position of given token id.
+fn parse_position_id(token_id: &str) -> u64 {
+    token_id
+        .parse()
+        .ok()
+        .unwrap_or_panic(PoolError::WrongTokenId)
+}
+
+#[near_bindgen]
+impl Contract {
+    /// Mints a token holding given number of caller's shares of the pool, owned by the caller.
+    /// Storage of the token is paid by the caller. Returns id of the token.
+    pub fn mint_position_nft(&mut self, pool_id: u64, shares: U128) -> String {
+        let sender_id = env::predecessor_account_id();
+        let mut pool = self.internal_get_pool(pool_id);
+        let entry_amounts = pool.predict_remove_liquidity(shares.into());
+        let prev_storage = env::storage_usage();
+        self.internal_settle_lp_fees(pool_id, &pool, &sender_id);
+        self.internal_settle_lp_fees(pool_id, &pool, &env::current_account_id());
+        pool.share_transfer(&sender_id, &env::current_account_id(), shares.into());
+        self.internal_prune_lp_fees(pool_id, &pool, &sender_id);
+        self.assert_shares_unlocked(pool_id, &pool, &sender_id);
+        self.internal_set_pool(pool_id, pool);
+        let position_id = self.next_position_nft_id;
+        self.next_position_nft_id += 1;
+        self.position_nfts.insert(
+            &position_id,
+            &PositionNft {
+                owner_id: sender_id.clone(),
+                pool_id,
+                shares: shares.into(),
+                minted_at: timestamp_sec(),
+                entry_amounts,
+            },
+        );
+        self.internal_add_position_nft(&sender_id, position_id);
+        self.internal_update_storage(&sender_id, prev_storage);
+        log!(
+            "Minted token {} of {} shares of pool {} to {}",
+            position_id,
+            shares.0,
+            pool_id,
+            sender_id
+        );
+        position_id.to_string()
+    }
+
+    /// Burns caller's token and moves its shares back to the caller.
+    pub fn burn_position_nft(&mut self, token_id: String) {
+        let sender_id = env::predecessor_account_id();
+        let position_id = parse_position_id(&token_id);
+        let nft = self.internal_get_position_nft(position_id);
+        require(nft.owner_id == sender_id, PoolError::NotNftOwner);
+        let mut pool = self.internal_get_pool(nft.pool_id);
+        let prev_storage = env::storage_usage();
+        self.internal_settle_lp_fees(nft.pool_id, &pool, &env::current_account_id());
+        self.internal_settle_lp_fees(nft.pool_id, &pool, &sender_id);
+        pool.share_transfer(&env::current_account_id(), &sender_id, nft.shares);
+        self.internal_prune_lp_fees(nft.pool_id, &pool, &env::current_account_id());
+        self.internal_set_pool(nft.pool_id, pool);
+        self.position_nfts.remove(&position_id);
+        self.internal_remove_position_nft(&sender_id, position_id);
+        self.internal_update_storage(&sender_id, prev_storage);
+        log!(
+            "Burnt token {} of {} shares of pool {}",
+            position_id,
+            nft.shares,
+            nft.pool_id
+        );
+    }
+
+    /// Transfers caller's token to the receiver, which must be registered. Storage taken by the receiver's token
+    /// is paid by the caller. Approvals aren't supported, so `approval_id` is ignored. Requires attached 1 yoctoNEAR.
+    #[payable]
+    pub fn nft_transfer(
+        &mut self,
+        receiver_id: AccountId,
+        token_id: String,
+        approval_id: Option<u64>,
+        memo: Option<String>,
+    ) {
+        assert_one_yocto();
+        let _ = approval_id;
+        self.internal_nft_transfer(
+            &env::predecessor_account_id(),
+            &receiver_id,
+            &token_id,
+            memo,
+        );
+    }
+
+    /// Transfers the token like `nft_transfer` and calls `nft_on_transfer` on the receiver with given `msg`.
+    /// If the receiver returns true or fails, the token is transferred back to the caller in `nft_resolve_transfer`.
+    /// Requires attached 1 yoctoNEAR.
+    #[payable]
+    pub fn nft_transfer_call(
+        &mut self,
+        receiver_id: AccountId,
+        token_id: String,
+        approval_id: Option<u64>,
+        memo: Option<String>,
+        msg: String,
+    ) -> PromiseOrValue<bool> {
+        assert_one_yocto();
+        let _ = approval_id;
+        let sender_id = env::predecessor_account_id();
+        self.internal_nft_transfer(&sender_id, &receiver_id, &token_id, memo);
+        ext_nft_receiver::ext(receiver_id.clone())
+            .with_static_gas(env::prepaid_gas() - GAS_FOR_NFT_TRANSFER_CALL)
+            .nft_on_transfer(sender_id.clone(), sender_id.clone(), token_id.clone(), msg)
+            .then(
+                ext_self::ext(env::current_account_id())
+                    .with_static_gas(GAS_FOR_NFT_RESOLVE_TRANSFER)
+                    .nft_resolve_transfer(sender_id, receiver_id, token_id),
+            )
+            .into()
+    }
+
+    /// Callback after `nft_on_transfer` of the receiver. Transfers the token back to the previous owner
+    /// if the receiver returned true or failed, unless the receiver doesn't own it anymore.
+    /// Returns whether the receiver kept the token.
+    #[private]
+    pub fn nft_resolve_transfer(
+        &mut self,
+        owner_id: AccountId,
+        receiver_id: AccountId,
+        token_id: String,
+    ) -> bool {
+        require(env::promise_results_count() == 1, PoolError::Invalid);
+        let must_return = match env::promise_result(0) {
+            PromiseResult::Successful(value) => {
+                serde_json::from_slice::<bool>(&value).unwrap_or(true)
+            }
+            _ => true,
+        };
+        if !must_return {
+            return true;
+        }
+        let position_id = parse_position_id(&token_id);
+        match self.position_nfts.get(&position_id) {
+            Some(mut nft) if nft.owner_id == receiver_id => {
+                log!(
+                    "Returning token {} from {} to {}",
+                    token_id,
+                    receiver_id,
+                    owner_id
+                );
+                let prev_storage = env::storage_usage();
+                self.internal_remove_position_nft(&receiver_id, position_id);
+                self.internal_add_position_nft(&owner_id, position_id);
+                nft.owner_id = owner_id.clone();
+                self.position_nfts.insert(&position_id, &nft);
+                if let Some(mut deposits) = self.deposited_amounts.get(&owner_id) {
+                    deposits.storage_used += env::storage_usage().saturating_sub(prev_storage);
+                    self.deposited_amounts.insert(&owner_id, &deposits);
+                }
+                false
+            }
+            _ => true,
+        }
+    }
+
+    /// Returns given token, if it exists.
+    pub fn nft_token(&self, token_id: String) -> Option<PositionNftToken> {
+        let position_id = parse_position_id(&token_id);
+        self.position_nfts
+            .get(&position_id)
+            .map(|nft| position_nft_token(position_id, nft))
+    }
+
+    /// Returns number of existing tokens.
+    pub fn nft_total_supply(&self) -> U128 {
+        U128(self.position_nfts.len() as u128)
+    }
+
+    /// Returns existing tokens.
+    pub fn nft_tokens(
+        &self,
+        from_index: Option<U128>,
+        limit: Option<u64>,
+    ) -> Vec<PositionNftToken> {
+        let from_index = from_index.map(|index| index.0 as u64).unwrap_or(0);
+        let keys = self.position_nfts.keys_as_vector();
+        let values = self.position_nfts.values_as_vector();
+        let limit = limit.unwrap_or_else(|| keys.len());
+        (from_index..std::cmp::min(from_index.saturating_add(limit), keys.len()))
+            .map(|index| position_nft_token(keys.get(index).unwrap(), values.get(index).unwrap()))
+            .collect()
+    }
+
+    /// Returns number of tokens given account owns.
+    pub fn nft_supply_for_owner(&self, account_id: AccountId) -> U128 {
+        U128(
+            self.position_nft_owners
+                .get(&account_id)
+                .unwrap_or_default()
+                .len() as u128,
+        )
+    }
+
+    /// Returns tokens given account owns.
+    pub fn nft_tokens_for_owner(
+        &self,
+        account_id: AccountId,
+        from_index: Option<U128>,
+        limit: Option<u64>,
+    ) -> Vec<PositionNftToken> {
+        let from_index = from_index.map(|index| index.0 as usize).unwrap_or(0);
+        self.position_nft_owners
+            .get(&account_id)
+            .unwrap_or_default()
+            .into_iter()
+            .skip(from_index)
+            .take(limit.map(|limit| limit as usize).unwrap_or(usize::MAX))
+            .map(|position_id| {
+                position_nft_token(position_id, self.internal_get_position_nft(position_id))
+            })
+            .collect()
+    }
+
+    /// Returns metadata of the tokens.
+    pub fn nft_metadata(&self) -> NftContractMetadata {
+        NftContractMetadata {
+            spec: "nft-1.0.0".to_string(),
+            name: "Multiswap liquidity positions".to_string(),
+            symbol: "MSLP".to_string(),
+        }
+    }
+}
+
+/// Returns the token as shown by views.
+fn position_nft_token(position_id: u64, nft: PositionNft) -> PositionNftToken {
+    PositionNftToken {
+        token_id: position_id.to_string(),
+        owner_id: nft.owner_id,
+        metadata: PositionNftMetadata {
+            title: format!("Pool {} liquidity position", nft.pool_id),
+            description: format!("{} shares of pool {}", nft.shares, nft.pool_id),
+            issued_at: (nft.minted_at * 1000).to_string(),
+        },
+        pool_id: nft.pool_id,
+        shares: nft.shares.into(),
+        minted_at: nft.minted_at.into(),
+        entry_amounts: nft.entry_amounts.into_iter().map(U128).collect(),
+    }
+}
+
+impl Contract {
+    fn internal_get_position_nft(&self, position_id: u64) -> PositionNft {
+        self.position_nfts
+            .get(&position_id)
+            .unwrap_or_panic(PoolError::NoNft)
+    }
+
+    fn internal_add_position_nft(&mut self, account_id: &AccountId, position_id: u64) {
+        let mut position_ids = self.position_nft_owners.get(account_id).unwrap_or_default();
+        position_ids.push(position_id);
+        self.position_nft_owners.insert(account_id, &position_ids);
+    }
+
+    fn internal_remove_position_nft(&mut self, account_id: &AccountId, position_id: u64) {
+        let mut position_ids = self.position_nft_owners.get(account_id).unwrap_or_default();
+        position_ids.retain(|id| *id != position_id);
+        if position_ids.is_empty() {
+            self.position_nft_owners.remove(account_id);
+        } else {
+            self.position_nft_owners.insert(account_id, &position_ids);
+        }
+    }
+
+    /// Transfers the token from its owner to the receiver, which must be registered.
+    /// Storage taken by the receiver's token is paid by the sender.
+    fn internal_nft_transfer(
+        &mut self,
+        sender_id: &AccountId,
+        receiver_id: &AccountId,
+        token_id: &str,
+        memo: Option<String>,
+    ) {
+        let position_id = parse_position_id(token_id);
+        let mut nft = self.internal_get_position_nft(position_id);
+        require(&nft.owner_id == sender_id, PoolError::NotNftOwner);
+        self.internal_get_account(receiver_id);
+        let prev_storage = env::storage_usage();
+        self.internal_remove_position_nft(sender_id, position_id);
+        self.internal_add_position_nft(receiver_id, position_id);
+        nft.owner_id = receiver_id.clone();
+        self.position_nfts.insert(&position_id, &nft);
+        self.internal_update_storage(sender_id, prev_storage);
+        log!(
+            "Transferred token {} from {} to {}",
+            position_id,
+            sender_id,
+            receiver_id
+        );
+        if let Some(memo) = memo {
+            log!("Memo: {}", memo);
+        }
+    }
+}
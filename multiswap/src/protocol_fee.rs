@@ -0,0 +1,128 @@
+//! Protocol fee. Owner can direct part of the swap fee of every pool to the protocol instead of liquidity providers.
+//! The protocol part is taken out of the pool right after each swap, and accumulated per pool until anyone claims it
+//! into the deposits of the treasury set by owner.
+//! Like referral fees, concentrated and bonding curve pools don't pay it, as they don't keep the fees in their balances.
+
+use crate::errors::{require, PoolError, UnwrapOrPanic};
+use crate::utils::U256;
+use crate::*;
+
+#[near_bindgen]
+impl Contract {
+    /// Sets part of the swap fee taken by the protocol, e.g. 1667 for a sixth of it. Together with `referral_fee`
+    /// it can't exceed the whole fee. Only owner and fee setters can call it.
+    #[payable]
+    pub fn set_protocol_fee(&mut self, protocol_fee: u32) {
+        assert_one_yocto();
+        self.assert_role(Role::FeeSetter);
+        self.assert_no_timelock();
+        self.internal_set_protocol_fee(protocol_fee);
+    }
+
+    /// Sets account receiving protocol fees. Only owner can call it.
+    #[payable]
+    pub fn set_treasury(&mut self, treasury_id: AccountId) {
+        assert_one_yocto();
+        self.assert_owner();
+        self.assert_no_timelock();
+        self.treasury_id = Some(treasury_id);
+    }
+
+    /// Moves protocol fees accumulated by the pool to the deposits of the treasury, which must have
+    /// the tokens of the pool registered. Anyone can call it. Returns claimed amounts, in the order of the pool tokens.
+    /// Fails if the fees are compounded into the vault of the pool, see `set_vault_protocol_fees`.
+    pub fn claim_protocol_fees(&mut self, pool_id: u64) -> Vec<U128> {
+        require(
+            !matches!(self.vaults.get(&pool_id), Some(vault) if vault.compounds_protocol_fees),
+            PoolError::FeesCompounded,
+        );
+        let treasury_id = self
+            .treasury_id
+            .clone()
+            .unwrap_or_panic(PoolError::NoTreasury);
+        let tokens = self.internal_get_pool(pool_id).tokens().to_vec();
+        let fees = self.protocol_fees.remove(&pool_id).unwrap_or_default();
+        if !fees.is_empty() {
+            self.internal_deposit_tokens(&treasury_id, &tokens, &fees);
+            log!(
+                "Claimed protocol fees of pool {} to {}",
+                pool_id,
+                treasury_id
+            );
+        }
+        fees.into_iter().map(|amount| amount.into()).collect()
+    }
+
+    /// Returns part of the swap fee taken by the protocol, divided by 10000.
+    pub fn get_protocol_fee(&self) -> u32 {
+        self.protocol_fee
+    }
+
+    /// Returns account receiving protocol fees, if it's set.
+    pub fn get_treasury(&self) -> Option<AccountId> {
+        self.treasury_id.clone()
+    }
+
+    /// Returns protocol fees accumulated by the pool and not claimed yet, in the order of its tokens.
+    pub fn get_protocol_fees(&self, pool_id: u64) -> Vec<U128> {
+        let num_tokens = self.internal_get_pool(pool_id).tokens().len();
+        self.protocol_fees
+            .get(&pool_id)
+            .unwrap_or_else(|| vec![0; num_tokens])
+            .into_iter()
+            .map(|amount| amount.into())
+            .collect()
+    }
+}
+
+impl Contract {
+    pub(crate) fn internal_set_protocol_fee(&mut self, protocol_fee: u32) {
+        require(
+            protocol_fee + self.referral_fee <= PROTOCOL_FEE_DIVISOR,
+            PoolError::FeeTooLarge,
+        );
+        self.protocol_fee = protocol_fee;
+    }
+
+    /// Returns `protocol_fee` part of the fee of a swap of given amount in, charged at given fee rate.
+    /// Concentrated and bonding curve pools don't pay it.
+    pub(crate) fn internal_protocol_fee_amount(
+        &self,
+        pool: &Pool,
+        amount_in: Balance,
+        fee: u32,
+    ) -> Balance {
+        if let Pool::ConcentratedPool(_) | Pool::BondingCurvePool(_) = pool {
+            return 0;
+        }
+        (U256::from(amount_in) * U256::from(fee) * U256::from(self.protocol_fee)
+            / U256::from(FEE_DIVISOR)
+            / U256::from(PROTOCOL_FEE_DIVISOR))
+        .as_u128()
+    }
+
+    /// Moves `protocol_fee` part of the fee of a swap of given amount in, charged at given fee rate,
+    /// from the pool to its accumulated protocol fees.
+    pub(crate) fn internal_collect_protocol_fee(
+        &mut self,
+        pool_id: u64,
+        pool: &mut Pool,
+        token_id: &AccountId,
+        amount_in: Balance,
+        fee: u32,
+    ) -> Balance {
+        let amount = self.internal_protocol_fee_amount(pool, amount_in, fee);
+        if amount == 0 {
+            return 0;
+        }
+        pool.withdraw_fee(token_id, amount);
+        let index = pool.tokens().iter().position(|id| id == token_id).unwrap();
+        let mut fees = self
+            .protocol_fees
+            .get(&pool_id)
+            .unwrap_or_else(|| vec![0; pool.tokens().len()]);
+        fees[index] += amount;
+        self.protocol_fees.insert(&pool_id, &fees);
+        amount
+    }
+}
@@ -0,0 +1,88 @@
+//! Reserve floor of a pool, set by owner with `set_reserve_floor`. Swaps and unbalanced withdrawals of liquidity
+//! fail if they would take any reserve of the pool below its floor, so the pool can't be drained to a state
+//! where returns can't be computed or prices explode. Withdrawals that leave the price as is, proportional ones
+//! and of positions, aren't limited, so liquidity providers can always exit. A floor may take at most
+//! `MAX_RESERVE_FLOOR` of the reserve when it's set, and reserves can drop below it by such withdrawals,
+//! after which only further decreases by swaps are blocked.
+
+use crate::errors::{require, PoolError};
+use crate::utils::U256;
+use crate::*;
+
+/// Maximum floor of a reserve, as part of the reserve when the floor is set (gets divided by FEE_DIVISOR).
+const MAX_RESERVE_FLOOR: u32 = 5_000;
+
+#[near_bindgen]
+impl Contract {
+    /// Sets minimum reserves of the pool, in the order of its tokens, or removes them with None.
+    /// Each floor may be at most `MAX_RESERVE_FLOOR` of the current reserve. Only owner and pool managers can call it.
+    #[payable]
+    pub fn set_reserve_floor(&mut self, pool_id: u64, floors: Option<Vec<U128>>) {
+        assert_one_yocto();
+        self.assert_role(Role::PoolManager);
+        self.assert_no_timelock();
+        self.internal_set_reserve_floor(pool_id, floors);
+    }
+
+    /// Returns minimum reserves of the pool, in the order of its tokens, or None if they're not limited.
+    pub fn get_reserve_floor(&self, pool_id: u64) -> Option<Vec<U128>> {
+        self.reserve_floors
+            .get(&pool_id)
+            .map(|floors| floors.into_iter().map(U128).collect())
+    }
+}
+
+impl Contract {
+    pub(crate) fn internal_set_reserve_floor(&mut self, pool_id: u64, floors: Option<Vec<U128>>) {
+        let pool = self.internal_get_pool(pool_id);
+        match floors {
+            Some(floors) => {
+                require(
+                    floors.len() == pool.tokens().len(),
+                    PoolError::WrongTokenCount,
+                );
+                let floors: Vec<Balance> = floors.into_iter().map(|floor| floor.0).collect();
+                for (floor, amount) in floors.iter().zip(pool.amounts()) {
+                    require(
+                        U256::from(*floor) * U256::from(FEE_DIVISOR)
+                            <= U256::from(*amount) * U256::from(MAX_RESERVE_FLOOR),
+                        PoolError::ReserveFloorTooHigh,
+                    );
+                }
+                self.reserve_floors.insert(&pool_id, &floors);
+            }
+            None => {
+                self.reserve_floors.remove(&pool_id);
+            }
+        }
+    }
+
+    /// Returns whether reserve of given token in the pool is at least its floor, if the pool has one.
+    /// Swaps must fail unless it holds for their token out after them.
+    pub(crate) fn internal_reserve_floor_allows(
+        &self,
+        pool_id: u64,
+        pool: &Pool,
+        token_id: &AccountId,
+    ) -> bool {
+        match self.reserve_floors.get(&pool_id) {
+            Some(floors) => {
+                let index = pool.tokens().iter().position(|id| id == token_id).unwrap();
+                pool.amounts()[index] >= floors[index]
+            }
+            None => true,
+        }
+    }
+
+    /// Checks that withdrawal of given amounts of the pool tokens left every reserve it reduced at least at its floor.
+    pub(crate) fn assert_reserve_floor(&self, pool_id: u64, pool: &Pool, amounts: &[Balance]) {
+        for (token_id, amount) in pool.tokens().iter().zip(amounts) {
+            if *amount > 0 {
+                require(
+                    self.internal_reserve_floor_allows(pool_id, pool, token_id),
+                    PoolError::ReserveBelowFloor,
+                );
+            }
+        }
+    }
+}
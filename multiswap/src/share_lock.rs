@@ -0,0 +1,90 @@
+//! Locked liquidity. Liquidity providers (e.g. a project team) can lock their shares of a pool until given time,
+//! so anyone can check on-chain that the liquidity can't be removed before. Locked shares can't be removed,
+//! transferred, wrapped or deposited until the lock ends, and the lock can only be extended.
+
+use crate::dynamic_fee::timestamp_sec;
+use crate::errors::{require, PoolError, UnwrapOrPanic};
+use crate::*;
+
+/// Shares of a pool locked by their owner.
+#[derive(BorshSerialize, BorshDeserialize, Serialize, Deserialize, Debug, PartialEq)]
+#[serde(crate = "near_sdk::serde")]
+pub struct ShareLock {
+    /// Number of locked shares.
+    pub shares: U128,
+    /// Timestamp in seconds from which the shares can be moved.
+    pub unlock_at: U64,
+}
+
+#[near_bindgen]
+impl Contract {
+    /// Locks given number of caller's shares of the pool until given timestamp in seconds, on top of shares
+    /// locked before. The unlock time of existing lock can only be extended. Storage of the lock is paid by the caller.
+    pub fn lock_shares(&mut self, pool_id: u64, shares: U128, until: U64) {
+        let sender_id = env::predecessor_account_id();
+        let pool = self.internal_get_pool(pool_id);
+        let key = (pool_id, sender_id.clone());
+        let (locked_shares, unlock_at) = match self.share_locks.get(&key) {
+            Some(lock) => (lock.shares.0, lock.unlock_at.0),
+            None => (0, 0),
+        };
+        require(
+            until.0 > timestamp_sec() && until.0 >= unlock_at,
+            PoolError::LockTooShort,
+        );
+        let locked_shares = locked_shares + shares.0;
+        require(
+            pool.share_balances(&sender_id) >= locked_shares,
+            PoolError::NotEnoughShares,
+        );
+        let prev_storage = env::storage_usage();
+        self.share_locks.insert(
+            &key,
+            &ShareLock {
+                shares: locked_shares.into(),
+                unlock_at: until,
+            },
+        );
+        self.internal_update_storage(&sender_id, prev_storage);
+        log!(
+            "{} locked {} shares of pool {} until {}",
+            sender_id,
+            locked_shares,
+            pool_id,
+            until.0
+        );
+    }
+
+    /// Removes caller's lock of shares of the pool once it ended, releasing its storage.
+    pub fn unlock_shares(&mut self, pool_id: u64) {
+        let sender_id = env::predecessor_account_id();
+        let key = (pool_id, sender_id.clone());
+        let lock = self
+            .share_locks
+            .get(&key)
+            .unwrap_or_panic(PoolError::NoShareLock);
+        require(timestamp_sec() >= lock.unlock_at.0, PoolError::SharesLocked);
+        let prev_storage = env::storage_usage();
+        self.share_locks.remove(&key);
+        self.internal_update_storage(&sender_id, prev_storage);
+    }
+
+    /// Returns shares of the pool given account locked and until when, if it has a lock.
+    pub fn get_share_lock(&self, pool_id: u64, account_id: AccountId) -> Option<ShareLock> {
+        self.share_locks.get(&(pool_id, account_id))
+    }
+}
+
+impl Contract {
+    /// Fails if the account has less shares of the pool than it locked, while the lock lasts.
+    /// Must be called after its shares decreased.
+    pub(crate) fn assert_shares_unlocked(&self, pool_id: u64, pool: &Pool, account_id: &AccountId) {
+        if let Some(lock) = self.share_locks.get(&(pool_id, account_id.clone())) {
+            require(
+                timestamp_sec() >= lock.unlock_at.0
+                    || pool.share_balances(account_id) >= lock.shares.0,
+                PoolError::SharesLocked,
+            );
+        }
+    }
+}
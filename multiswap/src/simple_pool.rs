@@ -1,18 +1,20 @@
-use std::cmp::min;
-
 use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
 use near_sdk::collections::LookupMap;
-use near_sdk::json_types::ValidAccountId;
 use near_sdk::{env, AccountId, Balance};
 
-use crate::utils::{add_to_collection, U256};
+use crate::dynamic_fee::{price_movement, timestamp_sec, DynamicFee};
+use crate::errors::{require, CheckedAsU128, PoolError, UnwrapOrPanic};
+use crate::utils::{add_to_collection, mul_div_round_up, PRICE_PRECISION, U256};
+use crate::weighted_math::{
+    calc_shares_in_given_exact_tokens_out, calc_shares_out_given_exact_tokens_in,
+    calc_single_out_given_shares_in, ONE,
+};
+use multiswap_math::{amount_for_shares, shares_for_amounts, FEE_DIVISOR};
 
-const FEE_DIVISOR: u32 = 10_000;
-const MAX_NUM_TOKENS: usize = 10;
 const INIT_SHARES_SUPPLY: u128 = 1_000_000_000_000_000_000_000_000;
 
 /// Implementation of simple pool, that maintains constant product between balances of all the tokens.
-/// Similar to "Uniswap", but allows up to MAX_NUM_TOKENS of tokens.
+/// Similar to "Uniswap", but allows more than two tokens, up to the contract's `max_num_tokens`.
 /// Liquidity providers when depositing receive shares, that can be later burnt to withdraw pool's tokens in proportion.
 #[derive(BorshSerialize, BorshDeserialize)]
 pub struct SimplePool {
@@ -26,21 +28,43 @@ pub struct SimplePool {
     pub shares: LookupMap<AccountId, Balance>,
     /// Total number of shares.
     pub shares_total_supply: Balance,
+    /// If set, fee charged for swap grows with recent volatility.
+    pub dynamic_fee: Option<DynamicFee>,
+}
+
+/// Layout of `SimplePool` in the first release, before dynamic fees.
+#[derive(BorshSerialize, BorshDeserialize)]
+pub struct SimplePoolV0 {
+    pub token_account_ids: Vec<AccountId>,
+    pub amounts: Vec<Balance>,
+    pub fee: u32,
+    pub shares: LookupMap<AccountId, Balance>,
+    pub shares_total_supply: Balance,
+}
+
+impl From<SimplePoolV0> for SimplePool {
+    fn from(pool: SimplePoolV0) -> Self {
+        Self {
+            token_account_ids: pool.token_account_ids,
+            amounts: pool.amounts,
+            fee: pool.fee,
+            shares: pool.shares,
+            shares_total_supply: pool.shares_total_supply,
+            dynamic_fee: None,
+        }
+    }
 }
 
 impl SimplePool {
-    pub fn new(id: u32, token_account_ids: Vec<ValidAccountId>, fee: u32) -> Self {
-        assert!(fee < FEE_DIVISOR, "ERR_FEE_TOO_LARGE");
-        assert!(
-            token_account_ids.len() < MAX_NUM_TOKENS,
-            "ERR_TOO_MANY_TOKENS"
-        );
+    pub fn new(id: u32, token_account_ids: Vec<AccountId>, fee: u32) -> Self {
+        require(fee < FEE_DIVISOR, PoolError::FeeTooLarge);
         Self {
-            token_account_ids: token_account_ids.iter().map(|a| a.clone().into()).collect(),
+            token_account_ids: token_account_ids.clone(),
             amounts: vec![0u128; token_account_ids.len()],
             fee,
             shares: LookupMap::new(format!("s{}", id).into_bytes()),
             shares_total_supply: 0,
+            dynamic_fee: None,
             // liquidity_amounts: LookupMap::new(format!("l{}", id).into_bytes()),
         }
     }
@@ -60,39 +84,136 @@ impl SimplePool {
         &self.token_account_ids
     }
 
-    /// Adds the amounts of tokens to liquidity pool and returns number of shares that this user receives.
-    pub fn add_liquidity(&mut self, sender_id: &AccountId, amounts: Vec<Balance>) -> Balance {
-        assert_eq!(
-            amounts.len(),
-            self.token_account_ids.len(),
-            "ERR_WRONG_TOKEN_COUNT"
+    /// Checks that every token has nonzero reserve while the pool has shares.
+    #[cfg(feature = "invariant-checks")]
+    pub fn assert_invariants(&self) {
+        require(
+            self.shares_total_supply == 0 || self.amounts.iter().all(|amount| *amount > 0),
+            PoolError::InvariantViolated,
         );
-        let shares = if self.shares_total_supply > 0 {
-            let mut fair_supply = U256::max_value();
-            for i in 0..self.token_account_ids.len() {
-                assert!(amounts[i] > 0, "ERR_AMOUNT_ZERO");
-                fair_supply = min(
-                    fair_supply,
-                    U256::from(amounts[i]) * U256::from(self.shares_total_supply) / self.amounts[i],
-                );
-            }
-            for i in 0..self.token_account_ids.len() {
-                let amount = U256::from(self.amounts[i]) * fair_supply
-                    / U256::from(self.shares_total_supply);
-                self.amounts[i] += amount.as_u128();
+    }
+
+    /// Checks that the swap didn't decrease product of balances, given balances before the swap.
+    #[cfg(feature = "invariant-checks")]
+    pub fn assert_swap_invariant(&self, prev_amounts: &[Balance]) {
+        let mut prev_product = U256::one();
+        let mut product = U256::one();
+        for i in 0..self.amounts.len() {
+            if self.amounts[i] != prev_amounts[i] {
+                prev_product *= U256::from(prev_amounts[i]);
+                product *= U256::from(self.amounts[i]);
             }
-            fair_supply.as_u128()
+        }
+        require(product >= prev_product, PoolError::InvariantViolated);
+    }
+
+    /// Returns number of shares minted for adding given amounts of tokens, and amounts of them actually used.
+    pub fn predict_add_liquidity(&self, amounts: &[Balance]) -> (Balance, Vec<Balance>) {
+        require(
+            amounts.len() == self.token_account_ids.len(),
+            PoolError::WrongTokenCount,
+        );
+        if self.shares_total_supply > 0 {
+            require(
+                amounts.iter().all(|amount| *amount > 0),
+                PoolError::AmountZero,
+            );
+            let fair_supply = shares_for_amounts(&self.amounts, amounts, self.shares_total_supply);
+            // Round amounts taken up and shares minted down in favor of the pool.
+            let used_amounts = self
+                .amounts
+                .iter()
+                .map(|amount| {
+                    mul_div_round_up(
+                        U256::from(*amount),
+                        fair_supply,
+                        U256::from(self.shares_total_supply),
+                    )
+                    .checked_as_u128()
+                })
+                .collect();
+            (fair_supply.checked_as_u128(), used_amounts)
         } else {
-            for i in 0..self.token_account_ids.len() {
-                self.amounts[i] += amounts[i];
-            }
-            INIT_SHARES_SUPPLY
-        };
+            (INIT_SHARES_SUPPLY, amounts.to_vec())
+        }
+    }
+
+    /// Adds the amounts of tokens to liquidity pool and returns number of shares that this user receives.
+    /// Updates amounts to amounts actually used, the rest stays with the user.
+    pub fn add_liquidity(&mut self, sender_id: &AccountId, amounts: &mut Vec<Balance>) -> Balance {
+        let (shares, used_amounts) = self.predict_add_liquidity(amounts);
+        for i in 0..self.token_account_ids.len() {
+            self.amounts[i] += used_amounts[i];
+        }
+        *amounts = used_amounts;
+        self.shares_total_supply += shares;
+        add_to_collection(&mut self.shares, &sender_id, shares);
+        shares
+    }
+
+    /// Adds the amounts of tokens in arbitrary proportion to liquidity pool and returns number of shares that this user receives.
+    /// Part of the deposit above the pool ratio is charged swap fee, as if it was swapped into the other tokens.
+    pub fn add_liquidity_imbalanced(
+        &mut self,
+        sender_id: &AccountId,
+        amounts: Vec<Balance>,
+    ) -> Balance {
+        let n = self.token_account_ids.len();
+        require(amounts.len() == n, PoolError::WrongTokenCount);
+        if self.shares_total_supply == 0 {
+            let mut amounts = amounts;
+            return self.add_liquidity(sender_id, &mut amounts);
+        }
+        let shares = calc_shares_out_given_exact_tokens_in(
+            &self.amounts,
+            &vec![U256::from(ONE) / U256::from(n); n],
+            &amounts,
+            self.shares_total_supply,
+            U256::from(self.current_fee()) * U256::from(ONE) / U256::from(FEE_DIVISOR),
+        );
+        require(shares > 0, PoolError::ZeroShares);
+        for i in 0..n {
+            self.amounts[i] += amounts[i];
+        }
         self.shares_total_supply += shares;
         add_to_collection(&mut self.shares, &sender_id, shares);
         shares
     }
 
+    /// Transfers given number of shares from one account to another.
+    pub fn share_transfer(
+        &mut self,
+        sender_id: &AccountId,
+        receiver_id: &AccountId,
+        shares: Balance,
+    ) {
+        self.burn_shares(sender_id, shares);
+        self.shares_total_supply += shares;
+        add_to_collection(&mut self.shares, receiver_id, shares);
+    }
+
+    /// Takes given number of shares from the account, leaving them in the total supply without owner.
+    pub fn lock_shares(&mut self, sender_id: &AccountId, shares: Balance) {
+        self.burn_shares(sender_id, shares);
+        self.shares_total_supply += shares;
+    }
+
+    /// Burns given number of shares of the given account.
+    fn burn_shares(&mut self, sender_id: &AccountId, shares: Balance) {
+        let prev_shares_amount = self
+            .shares
+            .get(&sender_id)
+            .unwrap_or_panic(PoolError::NoShares);
+        require(prev_shares_amount >= shares, PoolError::NotEnoughShares);
+        if prev_shares_amount == shares {
+            self.shares.remove(&sender_id);
+        } else {
+            self.shares
+                .insert(&sender_id, &(prev_shares_amount - shares));
+        }
+        self.shares_total_supply -= shares;
+    }
+
     /// Removes given number of shares from the pool and returns amounts to the parent.
     pub fn remove_liquidity(
         &mut self,
@@ -100,33 +221,81 @@ impl SimplePool {
         shares: Balance,
         min_amounts: Vec<Balance>,
     ) -> Vec<Balance> {
-        let prev_shares_amount = self.shares.get(&sender_id).expect("ERR_NO_SHARES");
-        assert!(prev_shares_amount >= shares, "ERR_NOT_ENOUGH_SHARES");
         let mut result = vec![];
         for i in 0..self.token_account_ids.len() {
-            let amount = (U256::from(self.amounts[i]) * U256::from(shares)
-                / U256::from(self.shares_total_supply))
-            .as_u128();
-            assert!(amount >= min_amounts[i], "ERR_MIN_AMOUNT");
+            let amount = amount_for_shares(self.amounts[i], shares, self.shares_total_supply)
+                .checked_as_u128();
+            require(amount >= min_amounts[i], PoolError::MinAmount);
             self.amounts[i] -= amount;
             result.push(amount);
         }
-        if prev_shares_amount == shares {
-            self.shares.remove(&sender_id);
-        } else {
-            self.shares
-                .insert(&sender_id, &(prev_shares_amount - shares));
-        }
-        self.shares_total_supply -= shares;
+        self.burn_shares(sender_id, shares);
         result
     }
 
+    /// Returns fee currently charged for swap, which with dynamic fee can be higher than base fee.
+    pub fn current_fee(&self) -> u32 {
+        match &self.dynamic_fee {
+            Some(dynamic_fee) => dynamic_fee.fee(self.fee, timestamp_sec()),
+            None => self.fee,
+        }
+    }
+
+    /// Burns shares needed to withdraw exactly given amounts of tokens and returns number of burnt shares.
+    /// Part of the withdrawal above the pool ratio is charged swap fee. Fails if it needs more than `max_burn_shares`.
+    pub fn remove_liquidity_by_tokens(
+        &mut self,
+        sender_id: &AccountId,
+        amounts: Vec<Balance>,
+        max_burn_shares: Balance,
+    ) -> Balance {
+        let n = self.token_account_ids.len();
+        require(amounts.len() == n, PoolError::WrongTokenCount);
+        let shares = calc_shares_in_given_exact_tokens_out(
+            &self.amounts,
+            &vec![U256::from(ONE) / U256::from(n); n],
+            &amounts,
+            self.shares_total_supply,
+            U256::from(self.current_fee()) * U256::from(ONE) / U256::from(FEE_DIVISOR),
+        );
+        require(shares <= max_burn_shares, PoolError::MaxBurnShares);
+        self.burn_shares(sender_id, shares);
+        for i in 0..n {
+            self.amounts[i] -= amounts[i];
+        }
+        shares
+    }
+
+    /// Burns given number of shares and returns amount of given token to the parent.
+    /// Part of the amount that is converted from the other tokens is charged swap fee.
+    pub fn remove_liquidity_one_token(
+        &mut self,
+        sender_id: &AccountId,
+        shares: Balance,
+        token_out: &AccountId,
+        min_amount_out: Balance,
+    ) -> Balance {
+        require(shares < self.shares_total_supply, PoolError::SharesTooLarge);
+        let out_idx = self.token_index(token_out);
+        let amount_out = calc_single_out_given_shares_in(
+            self.amounts[out_idx],
+            U256::from(ONE) / U256::from(self.token_account_ids.len()),
+            self.shares_total_supply,
+            shares,
+            U256::from(self.current_fee()) * U256::from(ONE) / U256::from(FEE_DIVISOR),
+        );
+        require(amount_out >= min_amount_out, PoolError::MinAmount);
+        self.burn_shares(sender_id, shares);
+        self.amounts[out_idx] -= amount_out;
+        amount_out
+    }
+
     /// Returns token index for given pool.
     fn token_index(&self, token_id: &AccountId) -> usize {
         self.token_account_ids
             .iter()
             .position(|id| id == token_id)
-            .expect("ERR_MISSING_TOKEN")
+            .unwrap_or_panic(PoolError::MissingToken)
     }
 
     /// Returns number of tokens in outcome, given amount.
@@ -137,18 +306,14 @@ impl SimplePool {
         amount_in: Balance,
         token_out: usize,
     ) -> Balance {
-        let in_balance = U256::from(self.amounts[token_in]);
-        let out_balance = U256::from(self.amounts[token_out]);
-        assert!(
-            in_balance > U256::zero()
-                && out_balance > U256::zero()
-                && token_in != token_out
-                && amount_in > 0,
-            "ERR_INVALID"
+        let in_balance = self.amounts[token_in];
+        let out_balance = self.amounts[token_out];
+        require(
+            in_balance > 0 && out_balance > 0 && token_in != token_out && amount_in > 0,
+            PoolError::Invalid,
         );
-        let amount_with_fee = U256::from(amount_in) * U256::from(FEE_DIVISOR - self.fee);
-        (amount_with_fee * out_balance / (U256::from(FEE_DIVISOR) * in_balance + amount_with_fee))
-            .as_u128()
+        multiswap_math::get_return(in_balance, out_balance, amount_in, self.current_fee())
+            .checked_as_u128()
     }
 
     /// Returns how much token you will receive if swap `token_amount_in` of `token_in` for `token_out`.
@@ -165,6 +330,51 @@ impl SimplePool {
         )
     }
 
+    /// Returns marginal price of `token_in` in `token_out` excluding fee, with `PRICE_PRECISION` precision.
+    pub fn spot_price(&self, token_in: &AccountId, token_out: &AccountId) -> U256 {
+        let in_idx = self.token_index(token_in);
+        let out_idx = self.token_index(token_out);
+        require(
+            self.amounts[in_idx] > 0 && in_idx != out_idx,
+            PoolError::Invalid,
+        );
+        U256::from(self.amounts[out_idx]) * U256::from(PRICE_PRECISION)
+            / U256::from(self.amounts[in_idx])
+    }
+
+    /// Returns amount of token in needed to receive given amount of token out, rounded up.
+    /// Tokens are provided as indexes into token list for given pool.
+    fn internal_get_amount_in(
+        &self,
+        token_in: usize,
+        amount_out: Balance,
+        token_out: usize,
+    ) -> Balance {
+        let in_balance = self.amounts[token_in];
+        let out_balance = self.amounts[token_out];
+        require(
+            in_balance > 0 && token_in != token_out && amount_out > 0,
+            PoolError::Invalid,
+        );
+        require(amount_out < out_balance, PoolError::NotEnoughLiquidity);
+        multiswap_math::get_amount_in(in_balance, out_balance, amount_out, self.current_fee())
+            .checked_as_u128()
+    }
+
+    /// Returns how much of `token_in` is needed to receive exactly `amount_out` of `token_out`.
+    pub fn get_amount_in(
+        &self,
+        token_in: &AccountId,
+        amount_out: Balance,
+        token_out: &AccountId,
+    ) -> Balance {
+        self.internal_get_amount_in(
+            self.token_index(token_in),
+            amount_out,
+            self.token_index(token_out),
+        )
+    }
+
     /// Swap `token_amount_in` of `token_in` token into `token_out` and return how much was received.
     /// Assuming that `token_amount_in` was already received from `sender_id`.
     pub fn swap(
@@ -177,26 +387,67 @@ impl SimplePool {
         let in_idx = self.token_index(token_in);
         let out_idx = self.token_index(token_out);
         let amount_out = self.internal_get_return(in_idx, amount_in, out_idx);
-        env::log(
-            format!(
-                "Swapped {} {} for {} {}",
-                amount_in, token_in, amount_out, token_out
-            )
-            .as_bytes(),
-        );
-        assert!(amount_out >= min_amount_out, "ERR_MIN_AMOUNT");
+        env::log_str(&format!(
+            "Swapped {} {} for {} {}",
+            amount_in, token_in, amount_out, token_out
+        ));
+        require(amount_out >= min_amount_out, PoolError::MinAmount);
+        self.apply_swap(in_idx, amount_in, out_idx, amount_out);
+        amount_out
+    }
 
+    /// Swap `token_in` into exactly `amount_out` of `token_out` and return how much of `token_in` it takes.
+    /// Fails if it takes more than `max_amount_in`.
+    pub fn swap_for_exact(
+        &mut self,
+        token_in: &AccountId,
+        max_amount_in: Balance,
+        token_out: &AccountId,
+        amount_out: Balance,
+    ) -> Balance {
+        let in_idx = self.token_index(token_in);
+        let out_idx = self.token_index(token_out);
+        let amount_in = self.internal_get_amount_in(in_idx, amount_out, out_idx);
+        env::log_str(&format!(
+            "Swapped {} {} for {} {}",
+            amount_in, token_in, amount_out, token_out
+        ));
+        require(amount_in <= max_amount_in, PoolError::MaxAmountIn);
+        self.apply_swap(in_idx, amount_in, out_idx, amount_out);
+        amount_in
+    }
+
+    /// Updates balances with the swap and records its price movement for dynamic fee.
+    fn apply_swap(
+        &mut self,
+        in_idx: usize,
+        amount_in: Balance,
+        out_idx: usize,
+        amount_out: Balance,
+    ) {
+        let prev_amount_in = self.amounts[in_idx];
+        let prev_amount_out = self.amounts[out_idx];
         self.amounts[in_idx] += amount_in;
         self.amounts[out_idx] -= amount_out;
 
-        amount_out
+        if let Some(dynamic_fee) = self.dynamic_fee.as_mut() {
+            dynamic_fee.record(
+                timestamp_sec(),
+                price_movement(
+                    prev_amount_in,
+                    prev_amount_out,
+                    self.amounts[in_idx],
+                    self.amounts[out_idx],
+                ),
+            );
+        }
     }
 }
 
 #[cfg(test)]
 mod tests {
     use near_sdk::test_utils::{accounts, VMContextBuilder};
-    use near_sdk::{testing_env, MockedBlockchain};
+    use near_sdk::testing_env;
 
     use super::*;
 
@@ -207,9 +458,149 @@ mod tests {
         context.predecessor_account_id(accounts(0));
         testing_env!(context.build());
         let mut pool = SimplePool::new(0, vec![accounts(1), accounts(2)], 30);
-        let num_shares =
-            pool.add_liquidity(accounts(0).as_ref(), vec![5 * one_near, 10 * one_near]);
-        pool.swap(accounts(1).as_ref(), one_near, accounts(2).as_ref(), 1);
-        pool.remove_liquidity(accounts(0).as_ref(), num_shares, vec![1, 1]);
+        let num_shares = pool.add_liquidity(&accounts(0), &mut vec![5 * one_near, 10 * one_near]);
+        pool.swap(&accounts(1), one_near, &accounts(2), 1);
+        pool.remove_liquidity(&accounts(0), num_shares, vec![1, 1]);
+    }
+
+    #[test]
+    fn test_pool_no_dust_from_small_deposits() {
+        let mut context = VMContextBuilder::new();
+        context.predecessor_account_id(accounts(0));
+        testing_env!(context.build());
+        let mut pool = SimplePool::new(0, vec![accounts(1), accounts(2)], 30);
+        pool.add_liquidity(&accounts(0), &mut vec![1000, 3001]);
+        // Many tiny deposits must not mint shares worth more than what was actually taken.
+        let mut deposited = vec![0, 0];
+        for _ in 0..100 {
+            let mut amounts = vec![1, 3];
+            pool.add_liquidity(&accounts(1), &mut amounts);
+            deposited[0] += amounts[0];
+            deposited[1] += amounts[1];
+        }
+        let shares = pool.share_balances(&accounts(1));
+        let withdrawn = pool.remove_liquidity(&accounts(1), shares, vec![0, 0]);
+        assert!(withdrawn[0] <= deposited[0] && withdrawn[1] <= deposited[1]);
+    }
+
+    #[cfg(feature = "invariant-checks")]
+    #[test]
+    #[should_panic(expected = "ERR_INVARIANT_VIOLATED")]
+    fn test_pool_swap_invariant() {
+        let one_near = 10u128.pow(24);
+        let mut context = VMContextBuilder::new();
+        context.predecessor_account_id(accounts(0));
+        testing_env!(context.build());
+        let mut pool = SimplePool::new(0, vec![accounts(1), accounts(2)], 30);
+        pool.add_liquidity(&accounts(0), &mut vec![5 * one_near, 10 * one_near]);
+        let prev_amounts = pool.amounts.clone();
+        pool.swap(&accounts(1), one_near, &accounts(2), 1);
+        pool.assert_swap_invariant(&prev_amounts);
+        // Swapping at spot price without slippage decreases the invariant.
+        let prev_amounts = pool.amounts.clone();
+        pool.amounts[0] += one_near;
+        pool.amounts[1] -= prev_amounts[1] / (prev_amounts[0] / one_near);
+        pool.assert_swap_invariant(&prev_amounts);
+    }
+
+    #[test]
+    fn test_pool_add_liquidity_imbalanced() {
+        let one_near = 10u128.pow(24);
+        let mut context = VMContextBuilder::new();
+        context.predecessor_account_id(accounts(0));
+        testing_env!(context.build());
+        let mut pool = SimplePool::new(0, vec![accounts(1), accounts(2)], 30);
+        pool.add_liquidity(&accounts(0), &mut vec![10 * one_near, 10 * one_near]);
+        // Proportional deposit gets the same shares as regular add liquidity.
+        let shares = pool.add_liquidity_imbalanced(&accounts(1), vec![one_near, one_near]);
+        // Within precision of power approximation.
+        assert!(shares > 99_999 * 10u128.pow(18) && shares < 100_001 * 10u128.pow(18));
+        // Single sided deposit withdrawn right away gives back less than deposited.
+        let shares = pool.add_liquidity_imbalanced(&accounts(2), vec![one_near, 0]);
+        let amounts = pool.remove_liquidity(&accounts(2), shares, vec![0, 0]);
+        let amount_out = pool.swap(&accounts(2), amounts[1], &accounts(1), 1);
+        assert!(amounts[0] + amount_out < one_near);
+        assert!(amounts[0] + amount_out > 99 * one_near / 100);
+    }
+
+    #[test]
+    fn test_pool_remove_liquidity_one_token() {
+        let one_near = 10u128.pow(24);
+        let mut context = VMContextBuilder::new();
+        context.predecessor_account_id(accounts(0));
+        testing_env!(context.build());
+        let mut pool = SimplePool::new(0, vec![accounts(1), accounts(2)], 30);
+        pool.add_liquidity(&accounts(0), &mut vec![10 * one_near, 10 * one_near]);
+        let shares = pool.add_liquidity(&accounts(1), &mut vec![one_near, one_near]);
+        // Same as removing liquidity proportionally and swapping 1 token 2 into token 1.
+        let amount_out = pool.remove_liquidity_one_token(&accounts(1), shares, &accounts(1), 1);
+        assert!(amount_out > 1_905 * one_near / 1000 && amount_out < 1_907 * one_near / 1000);
+        assert_eq!(pool.share_balances(&accounts(1)), 0);
+        assert_eq!(
+            pool.amounts,
+            vec![11 * one_near - amount_out, 11 * one_near]
+        );
+    }
+
+    #[test]
+    fn test_pool_remove_liquidity_by_tokens() {
+        let one_near = 10u128.pow(24);
+        let mut context = VMContextBuilder::new();
+        context.predecessor_account_id(accounts(0));
+        testing_env!(context.build());
+        let mut pool = SimplePool::new(0, vec![accounts(1), accounts(2)], 30);
+        pool.add_liquidity(&accounts(0), &mut vec![10 * one_near, 10 * one_near]);
+        let shares = pool.add_liquidity(&accounts(1), &mut vec![one_near, one_near]);
+        // Withdrawing 0.5 of token 1 is 1 - sqrt(10.5 / 11) of the pool, plus the fee.
+        let burnt_shares =
+            pool.remove_liquidity_by_tokens(&accounts(1), vec![one_near / 2, 0], shares);
+        assert!(burnt_shares > 2_529 * 10u128.pow(19) && burnt_shares < 2_540 * 10u128.pow(19));
+        assert_eq!(pool.share_balances(&accounts(1)), shares - burnt_shares);
+        assert_eq!(pool.amounts, vec![21 * one_near / 2, 11 * one_near]);
+    }
+
+    #[test]
+    fn test_pool_swap_for_exact() {
+        let one_near = 10u128.pow(24);
+        let mut context = VMContextBuilder::new();
+        context.predecessor_account_id(accounts(0));
+        testing_env!(context.build());
+        let mut pool = SimplePool::new(0, vec![accounts(1), accounts(2)], 30);
+        pool.add_liquidity(&accounts(0), &mut vec![5 * one_near, 10 * one_near]);
+        let amount_out = 1662497915624478906119726;
+        let amount_in = pool.get_amount_in(&accounts(1), amount_out, &accounts(2));
+        // Inverse of the swap with exact amount in, rounded up.
+        assert!(amount_in <= one_near && amount_in + 10 > one_near);
+        assert!(pool.get_return(&accounts(1), amount_in, &accounts(2)) >= amount_out);
+        assert_eq!(
+            pool.swap_for_exact(&accounts(1), one_near, &accounts(2), amount_out),
+            amount_in
+        );
+        assert_eq!(
+            pool.amounts,
+            vec![5 * one_near + amount_in, 10 * one_near - amount_out]
+        );
+    }
+
+    #[test]
+    fn test_pool_dynamic_fee() {
+        let one_near = 10u128.pow(24);
+        let mut context = VMContextBuilder::new();
+        context.predecessor_account_id(accounts(0));
+        testing_env!(context.build());
+        let mut pool = SimplePool::new(0, vec![accounts(1), accounts(2)], 30);
+        pool.dynamic_fee = Some(DynamicFee::new(30, 100, 200, 60));
+        pool.add_liquidity(&accounts(0), &mut vec![100 * one_near, 100 * one_near]);
+        // Small swap doesn't move price above the threshold.
+        pool.swap(&accounts(1), one_near, &accounts(2), 1);
+        assert_eq!(pool.current_fee(), 30);
+        // Large swap moves price by more than 10%, so fee goes to the max.
+        pool.swap(&accounts(1), 10 * one_near, &accounts(2), 1);
+        assert_eq!(pool.current_fee(), 100);
+        // Fee decays back to the base fee.
+        testing_env!(context.block_timestamp(54 * 10u64.pow(9)).build());
+        assert!(pool.current_fee() < 100 && pool.current_fee() > 30);
+        testing_env!(context.block_timestamp(60 * 10u64.pow(9)).build());
+        assert_eq!(pool.current_fee(), 30);
     }
 }
@@ -0,0 +1,991 @@
+use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
+use near_sdk::collections::LookupMap;
+use near_sdk::{env, AccountId, Balance};
+
+use crate::dynamic_fee::timestamp_sec;
+use crate::errors::{require, CheckedAsU128, PoolError, UnwrapOrPanic};
+use crate::utils::{add_to_collection, mul_div, mul_div_round_up, PRICE_PRECISION, U256};
+
+const FEE_DIVISOR: u32 = 10_000;
+/// All amounts are normalized to this number of decimals before running the invariant math.
+const TARGET_DECIMALS: u8 = 24;
+/// Precision of token rates, i.e. rate of `RATE_PRECISION` means 1:1 to the underlying asset.
+pub const RATE_PRECISION: u128 = 1_000_000_000_000_000_000_000_000;
+/// Maximum age of a rate from a rate provider in seconds. Swaps and liquidity changes that depend on rates
+/// fail once a rate is older, until it's refreshed with `update_token_rates`.
+pub const MAX_RATE_AGE: u64 = 60 * 60;
+const MIN_AMP: u64 = 1;
+const MAX_AMP: u64 = 1_000_000;
+/// Maximum number of Newton iterations when solving the invariant.
+const MAX_ITERATIONS: usize = 256;
+
+/// Returns true if two consecutive approximations differ by at most 1.
+fn is_converged(value: U256, prev_value: U256) -> bool {
+    if value > prev_value {
+        value - prev_value <= U256::one()
+    } else {
+        prev_value - value <= U256::one()
+    }
+}
+
+/// Computes StableSwap invariant `D` for given balances and amplification coefficient.
+/// Solves `A * n^n * sum(x_i) + D = A * D * n^n + D^(n + 1) / (n^n * prod(x_i))` by Newton's method.
+fn compute_d(amounts: &[Balance], amp: u64) -> U256 {
+    let n = U256::from(amounts.len());
+    let sum = amounts
+        .iter()
+        .fold(U256::zero(), |acc, amount| acc + U256::from(*amount));
+    if sum.is_zero() {
+        return U256::zero();
+    }
+    let ann = U256::from(amp) * n;
+    let mut d = sum;
+    for _ in 0..MAX_ITERATIONS {
+        let mut d_prod = d;
+        for amount in amounts {
+            d_prod = d_prod * d / (U256::from(*amount) * n);
+        }
+        let d_prev = d;
+        d = (ann * sum + d_prod * n) * d / ((ann - 1) * d + (n + 1) * d_prod);
+        if is_converged(d, d_prev) {
+            return d;
+        }
+    }
+    PoolError::DNotConverged.panic()
+}
+
+/// Computes new balance of token `token_out` that keeps invariant `d`,
+/// given that balance of `token_in` becomes `new_amount_in` and rest of balances are unchanged.
+fn compute_y(
+    amounts: &[Balance],
+    token_in: usize,
+    new_amount_in: Balance,
+    token_out: usize,
+    d: U256,
+    amp: u64,
+) -> U256 {
+    let n = U256::from(amounts.len());
+    let ann = U256::from(amp) * n;
+    let mut c = d;
+    let mut sum = U256::zero();
+    for (i, amount) in amounts.iter().enumerate() {
+        let x = if i == token_in {
+            U256::from(new_amount_in)
+        } else if i != token_out {
+            U256::from(*amount)
+        } else {
+            continue;
+        };
+        sum += x;
+        c = c * d / (x * n);
+    }
+    c = c * d / (ann * n);
+    let b = sum + d / ann;
+    let mut y = d;
+    for _ in 0..MAX_ITERATIONS {
+        let y_prev = y;
+        y = (y * y + c) / (U256::from(2) * y + b - d);
+        if is_converged(y, y_prev) {
+            return y;
+        }
+    }
+    PoolError::YNotConverged.panic()
+}
+
+/// Implementation of stable swap pool, that maintains Curve-style StableSwap invariant between balances of all the tokens.
+/// Designed for pools of pegged assets (e.g. different USD stable coins), where it provides much lower slippage than constant product.
+/// Amounts of tokens with different decimals are normalized to `TARGET_DECIMALS` before running the invariant math,
+/// so for example 6 decimals USDC trades 1:1 with 18 decimals DAI.
+/// Yield-bearing tokens (e.g. stNEAR) can have a rate provider, in which case their amounts are
+/// also multiplied by the cached exchange rate to the underlying asset, refreshed from the provider.
+/// Swaps and liquidity changes, except removing liquidity in the pool ratio, fail while such rate is stale.
+/// Metapool pairs a token with shares of another stable swap pool (the base pool) as its last token,
+/// priced at the base pool's virtual price that is refreshed before every use of the metapool.
+/// Amplification coefficient controls how flat the curve is around the balanced point: the higher, the closer to constant sum.
+#[derive(BorshSerialize, BorshDeserialize)]
+pub struct StableSwapPool {
+    /// List of tokens in the pool.
+    pub token_account_ids: Vec<AccountId>,
+    /// Decimals of each token.
+    pub token_decimals: Vec<u8>,
+    /// Contracts providing exchange rate to the underlying asset for each token, if any.
+    pub rate_providers: Vec<Option<AccountId>>,
+    /// Cached exchange rates of each token to the underlying asset (gets divided by RATE_PRECISION).
+    pub rates: Vec<Balance>,
+    /// When each rate was last received from its rate provider, in seconds, 0 if never.
+    pub rates_updated_at: Vec<u64>,
+    /// How much of each token this pool has.
+    pub amounts: Vec<Balance>,
+    /// Fee charged for swap (gets divided by FEE_DIVISOR).
+    pub fee: u32,
+    /// Amplification coefficient.
+    pub amp_factor: u64,
+    /// Shares of the pool by liquidity providers.
+    pub shares: LookupMap<AccountId, Balance>,
+    /// Total number of shares.
+    pub shares_total_supply: Balance,
+    /// Id of the base pool, which shares are the last token of this pool, `None` if this is not a metapool.
+    pub base_pool_id: Option<u64>,
+}
+
+/// Layout of `StableSwapPool` before rates recorded when they were fetched.
+#[derive(BorshSerialize, BorshDeserialize)]
+pub struct StableSwapPoolV1 {
+    pub token_account_ids: Vec<AccountId>,
+    pub token_decimals: Vec<u8>,
+    pub rate_providers: Vec<Option<AccountId>>,
+    pub rates: Vec<Balance>,
+    pub amounts: Vec<Balance>,
+    pub fee: u32,
+    pub amp_factor: u64,
+    pub shares: LookupMap<AccountId, Balance>,
+    pub shares_total_supply: Balance,
+    pub base_pool_id: Option<u64>,
+}
+
+impl From<StableSwapPoolV1> for StableSwapPool {
+    /// Rates of the previous layout count as never fetched, so they are refreshed before the pool is used.
+    fn from(pool: StableSwapPoolV1) -> Self {
+        Self {
+            rates_updated_at: vec![0; pool.token_account_ids.len()],
+            token_account_ids: pool.token_account_ids,
+            token_decimals: pool.token_decimals,
+            rate_providers: pool.rate_providers,
+            rates: pool.rates,
+            amounts: pool.amounts,
+            fee: pool.fee,
+            amp_factor: pool.amp_factor,
+            shares: pool.shares,
+            shares_total_supply: pool.shares_total_supply,
+            base_pool_id: pool.base_pool_id,
+        }
+    }
+}
+
+impl StableSwapPool {
+    pub fn new(
+        id: u32,
+        token_account_ids: Vec<AccountId>,
+        token_decimals: Vec<u8>,
+        rate_providers: Vec<Option<AccountId>>,
+        amp_factor: u64,
+        fee: u32,
+    ) -> Self {
+        require(fee < FEE_DIVISOR, PoolError::FeeTooLarge);
+        require(
+            amp_factor >= MIN_AMP && amp_factor <= MAX_AMP,
+            PoolError::WrongAmp,
+        );
+        require(token_account_ids.len() >= 2, PoolError::NotEnoughTokens);
+        require(
+            token_decimals.len() == token_account_ids.len(),
+            PoolError::WrongDecimalsCount,
+        );
+        require(
+            token_decimals.iter().all(|d| *d <= TARGET_DECIMALS),
+            PoolError::DecimalsTooLarge,
+        );
+        require(
+            rate_providers.len() == token_account_ids.len(),
+            PoolError::WrongRateProvidersCount,
+        );
+        Self {
+            token_account_ids: token_account_ids.clone(),
+            token_decimals,
+            rate_providers,
+            rates: vec![RATE_PRECISION; token_account_ids.len()],
+            rates_updated_at: vec![0; token_account_ids.len()],
+            amounts: vec![0u128; token_account_ids.len()],
+            fee,
+            amp_factor,
+            shares: LookupMap::new(format!("s{}", id).into_bytes()),
+            shares_total_supply: 0,
+            base_pool_id: None,
+        }
+    }
+
+    /// Returns multiplier that normalizes amounts of given token to `TARGET_DECIMALS`.
+    fn decimals_multiplier(&self, token_index: usize) -> u128 {
+        10u128.pow((TARGET_DECIMALS - self.token_decimals[token_index]) as u32)
+    }
+
+    /// Returns given amounts of pool tokens normalized to `TARGET_DECIMALS`.
+    fn normalize(&self, amounts: &[Balance]) -> Vec<Balance> {
+        amounts
+            .iter()
+            .enumerate()
+            .map(|(i, amount)| self.normalize_amount(i, *amount))
+            .collect()
+    }
+
+    /// Returns given amount of token with given index normalized to `TARGET_DECIMALS` and converted to the underlying asset.
+    fn normalize_amount(&self, token_index: usize, amount: Balance) -> Balance {
+        let amount = U256::from(amount)
+            * U256::from(self.decimals_multiplier(token_index))
+            * U256::from(self.rates[token_index])
+            / U256::from(RATE_PRECISION);
+        require(amount <= U256::from(u128::MAX), PoolError::AmountOverflow);
+        amount.checked_as_u128()
+    }
+
+    /// Converts normalized amount back into amount of token with given index, rounding down.
+    fn denormalize_amount(&self, token_index: usize, amount: U256) -> Balance {
+        (amount * U256::from(RATE_PRECISION)
+            / U256::from(self.rates[token_index])
+            / U256::from(self.decimals_multiplier(token_index)))
+        .checked_as_u128()
+    }
+
+    /// Converts normalized amount back into amount of token with given index, rounding up.
+    fn denormalize_amount_round_up(&self, token_index: usize, amount: U256) -> Balance {
+        let denominator =
+            U256::from(self.rates[token_index]) * U256::from(self.decimals_multiplier(token_index));
+        ((amount * U256::from(RATE_PRECISION) + denominator - 1) / denominator).checked_as_u128()
+    }
+
+    /// Returns invariant for given amounts of pool tokens.
+    fn invariant(&self, amounts: &[Balance]) -> U256 {
+        compute_d(&self.normalize(amounts), self.amp_factor)
+    }
+
+    /// Returns number of shares given account has.
+    pub fn share_balances(&self, account_id: &AccountId) -> Balance {
+        self.shares.get(account_id).unwrap_or_default()
+    }
+
+    /// Returns total number of shares in this pool.
+    pub fn share_total_balance(&self) -> Balance {
+        self.shares_total_supply
+    }
+
+    /// Returns list of tokens in this pool.
+    pub fn tokens(&self) -> &[AccountId] {
+        &self.token_account_ids
+    }
+
+    /// Checks that every token has nonzero reserve while the pool has shares.
+    #[cfg(feature = "invariant-checks")]
+    pub fn assert_invariants(&self) {
+        require(
+            self.shares_total_supply == 0 || self.amounts.iter().all(|amount| *amount > 0),
+            PoolError::InvariantViolated,
+        );
+    }
+
+    /// Checks that the swap didn't decrease the invariant, given balances before the swap.
+    /// Allows for rounding of the iterative computation of D.
+    #[cfg(feature = "invariant-checks")]
+    pub fn assert_swap_invariant(&self, prev_amounts: &[Balance]) {
+        require(
+            self.invariant(&self.amounts) + 1 >= self.invariant(prev_amounts),
+            PoolError::InvariantViolated,
+        );
+    }
+
+    /// Returns rate provider of given token, if it has one.
+    pub fn rate_provider(&self, token_id: &AccountId) -> Option<AccountId> {
+        self.rate_providers[self.token_index(token_id)].clone()
+    }
+
+    /// Updates cached rate of given token, received from its rate provider.
+    pub fn set_rate(&mut self, token_id: &AccountId, rate: Balance) {
+        let token_index = self.token_index(token_id);
+        require(
+            self.rate_providers[token_index].is_some(),
+            PoolError::NoRateProvider,
+        );
+        require(rate > 0, PoolError::ZeroRate);
+        self.rates[token_index] = rate;
+        self.rates_updated_at[token_index] = timestamp_sec();
+    }
+
+    /// Checks that rates of all tokens with rate provider were fetched within `MAX_RATE_AGE`.
+    fn assert_fresh_rates(&self) {
+        let now = timestamp_sec();
+        for (provider, updated_at) in self.rate_providers.iter().zip(&self.rates_updated_at) {
+            require(
+                provider.is_none() || (*updated_at > 0 && now - updated_at <= MAX_RATE_AGE),
+                PoolError::StaleRate,
+            );
+        }
+    }
+
+    /// Returns value of a single share in the underlying asset (gets divided by RATE_PRECISION).
+    /// Grows over time as the pool collects fees. Returns 1:1 if the pool has no shares yet.
+    pub fn virtual_price(&self) -> Balance {
+        if self.shares_total_supply == 0 {
+            return RATE_PRECISION;
+        }
+        (self.invariant(&self.amounts) * U256::from(RATE_PRECISION)
+            / U256::from(self.shares_total_supply))
+        .checked_as_u128()
+    }
+
+    /// Updates rate of the base pool shares in the metapool to the given virtual price of the base pool.
+    pub fn set_base_pool_virtual_price(&mut self, virtual_price: Balance) {
+        require(self.base_pool_id.is_some(), PoolError::NotMetapool);
+        require(virtual_price > 0, PoolError::ZeroRate);
+        let base_index = self.token_account_ids.len() - 1;
+        self.rates[base_index] = virtual_price;
+    }
+
+    /// Transfers given number of shares from one account to another.
+    pub fn share_transfer(
+        &mut self,
+        sender_id: &AccountId,
+        receiver_id: &AccountId,
+        shares: Balance,
+    ) {
+        self.burn_shares(sender_id, shares);
+        self.shares_total_supply += shares;
+        add_to_collection(&mut self.shares, receiver_id, shares);
+    }
+
+    /// Takes given number of shares from the account, leaving them in the total supply without owner.
+    pub fn lock_shares(&mut self, sender_id: &AccountId, shares: Balance) {
+        self.burn_shares(sender_id, shares);
+        self.shares_total_supply += shares;
+    }
+
+    /// Returns new amounts of pool tokens reduced by the fee on their difference from the current amounts scaled
+    /// from invariant `d0` to `d1`, as proportion of the swap fee that would be paid to rebalance it.
+    fn charge_imbalance_fee(&self, new_amounts: &[Balance], d0: U256, d1: U256) -> Vec<Balance> {
+        let n = self.token_account_ids.len();
+        let fee = U256::from(self.fee) * U256::from(n) / U256::from(4 * (n - 1));
+        let mut amounts_after_fee = new_amounts.to_vec();
+        for i in 0..n {
+            let ideal_amount = (d1 * U256::from(self.amounts[i]) / d0).checked_as_u128();
+            let difference = if ideal_amount > new_amounts[i] {
+                ideal_amount - new_amounts[i]
+            } else {
+                new_amounts[i] - ideal_amount
+            };
+            // Round fee up in favor of the pool.
+            amounts_after_fee[i] -=
+                mul_div_round_up(fee, U256::from(difference), U256::from(FEE_DIVISOR))
+                    .checked_as_u128();
+        }
+        amounts_after_fee
+    }
+
+    /// Returns number of shares minted for adding given amounts of tokens, and amounts of them actually used,
+    /// which are all of them.
+    pub fn predict_add_liquidity(&self, amounts: &[Balance]) -> (Balance, Vec<Balance>) {
+        let n = self.token_account_ids.len();
+        require(amounts.len() == n, PoolError::WrongTokenCount);
+        let d0 = self.invariant(&self.amounts);
+        let mut new_amounts = self.amounts.clone();
+        for i in 0..n {
+            if self.shares_total_supply == 0 {
+                require(amounts[i] > 0, PoolError::AmountZero);
+            }
+            new_amounts[i] += amounts[i];
+        }
+        let d1 = self.invariant(&new_amounts);
+        require(d1 > d0, PoolError::ZeroShares);
+        let shares = if self.shares_total_supply > 0 {
+            let amounts_after_fee = self.charge_imbalance_fee(&new_amounts, d0, d1);
+            let d2 = self.invariant(&amounts_after_fee);
+            (U256::from(self.shares_total_supply) * (d2 - d0) / d0).checked_as_u128()
+        } else {
+            d1.checked_as_u128()
+        };
+        require(shares > 0, PoolError::ZeroShares);
+        (shares, amounts.to_vec())
+    }
+
+    /// Adds the amounts of tokens to liquidity pool and returns number of shares that this user receives.
+    /// Deposits don't need to be in the pool ratio, but imbalanced part is charged a fee same as swapping it.
+    pub fn add_liquidity(&mut self, sender_id: &AccountId, amounts: &mut Vec<Balance>) -> Balance {
+        self.assert_fresh_rates();
+        let (shares, _) = self.predict_add_liquidity(amounts);
+        for (amount, added) in self.amounts.iter_mut().zip(amounts.iter()) {
+            *amount += added;
+        }
+        self.shares_total_supply += shares;
+        add_to_collection(&mut self.shares, &sender_id, shares);
+        shares
+    }
+
+    /// Burns given number of shares of the given account.
+    fn burn_shares(&mut self, sender_id: &AccountId, shares: Balance) {
+        let prev_shares_amount = self
+            .shares
+            .get(&sender_id)
+            .unwrap_or_panic(PoolError::NoShares);
+        require(prev_shares_amount >= shares, PoolError::NotEnoughShares);
+        if prev_shares_amount == shares {
+            self.shares.remove(&sender_id);
+        } else {
+            self.shares
+                .insert(&sender_id, &(prev_shares_amount - shares));
+        }
+        self.shares_total_supply -= shares;
+    }
+
+    /// Removes given number of shares from the pool and returns amounts to the parent.
+    pub fn remove_liquidity(
+        &mut self,
+        sender_id: &AccountId,
+        shares: Balance,
+        min_amounts: Vec<Balance>,
+    ) -> Vec<Balance> {
+        let mut result = vec![];
+        for i in 0..self.token_account_ids.len() {
+            let amount = (U256::from(self.amounts[i]) * U256::from(shares)
+                / U256::from(self.shares_total_supply))
+            .checked_as_u128();
+            require(amount >= min_amounts[i], PoolError::MinAmount);
+            self.amounts[i] -= amount;
+            result.push(amount);
+        }
+        self.burn_shares(sender_id, shares);
+        result
+    }
+
+    /// Burns shares needed to withdraw exactly given amounts of tokens and returns number of burnt shares.
+    /// Withdrawal is charged the same fee on imbalance as adding liquidity. Fails if it needs more than `max_burn_shares`.
+    pub fn remove_liquidity_by_tokens(
+        &mut self,
+        sender_id: &AccountId,
+        amounts: Vec<Balance>,
+        max_burn_shares: Balance,
+    ) -> Balance {
+        self.assert_fresh_rates();
+        let n = self.token_account_ids.len();
+        require(amounts.len() == n, PoolError::WrongTokenCount);
+        let mut new_amounts = self.amounts.clone();
+        for i in 0..n {
+            require(amounts[i] < new_amounts[i], PoolError::NotEnoughLiquidity);
+            new_amounts[i] -= amounts[i];
+        }
+        let d0 = self.invariant(&self.amounts);
+        let d1 = self.invariant(&new_amounts);
+        let amounts_after_fee = self.charge_imbalance_fee(&new_amounts, d0, d1);
+        let d2 = self.invariant(&amounts_after_fee);
+        // Add one to round in favor of the pool.
+        let shares = (U256::from(self.shares_total_supply) * (d0 - d2) / d0).checked_as_u128() + 1;
+        require(shares <= max_burn_shares, PoolError::MaxBurnShares);
+        self.burn_shares(sender_id, shares);
+        self.amounts = new_amounts;
+        shares
+    }
+
+    /// Burns given number of shares and returns amount of given token to the parent.
+    /// Withdrawal is charged the same fee on imbalance as adding liquidity.
+    pub fn remove_liquidity_one_token(
+        &mut self,
+        sender_id: &AccountId,
+        shares: Balance,
+        token_out: &AccountId,
+        min_amount_out: Balance,
+    ) -> Balance {
+        self.assert_fresh_rates();
+        require(shares < self.shares_total_supply, PoolError::SharesTooLarge);
+        let n = self.token_account_ids.len();
+        let out_idx = self.token_index(token_out);
+        // Any other token, which balance stays the same while solving for balance of token out.
+        let other_idx = (out_idx + 1) % n;
+        let amounts = self.normalize(&self.amounts);
+        let d0 = compute_d(&amounts, self.amp_factor);
+        let d1 = d0 - d0 * U256::from(shares) / U256::from(self.shares_total_supply);
+        let new_y = compute_y(
+            &amounts,
+            other_idx,
+            amounts[other_idx],
+            out_idx,
+            d1,
+            self.amp_factor,
+        );
+        let fee = U256::from(self.fee) * U256::from(n) / U256::from(4 * (n - 1));
+        let mut amounts_reduced = amounts.clone();
+        for i in 0..n {
+            let ideal_amount = U256::from(amounts[i]) * d1 / d0;
+            let difference = if i == out_idx {
+                ideal_amount.saturating_sub(new_y)
+            } else {
+                U256::from(amounts[i]) - ideal_amount
+            };
+            amounts_reduced[i] -= (fee * difference / U256::from(FEE_DIVISOR)).checked_as_u128();
+        }
+        let y = compute_y(
+            &amounts_reduced,
+            other_idx,
+            amounts_reduced[other_idx],
+            out_idx,
+            d1,
+            self.amp_factor,
+        );
+        // Subtract one to round in favor of the pool.
+        let amount_out = self.denormalize_amount(
+            out_idx,
+            U256::from(amounts_reduced[out_idx])
+                .checked_sub(y + 1)
+                .unwrap_or_default(),
+        );
+        require(amount_out >= min_amount_out, PoolError::MinAmount);
+        self.burn_shares(sender_id, shares);
+        self.amounts[out_idx] -= amount_out;
+        amount_out
+    }
+
+    /// Returns token index for given pool.
+    fn token_index(&self, token_id: &AccountId) -> usize {
+        self.token_account_ids
+            .iter()
+            .position(|id| id == token_id)
+            .unwrap_or_panic(PoolError::MissingToken)
+    }
+
+    /// Returns number of tokens in outcome, given amount.
+    /// Tokens are provided as indexes into token list for given pool.
+    fn internal_get_return(
+        &self,
+        token_in: usize,
+        amount_in: Balance,
+        token_out: usize,
+    ) -> Balance {
+        require(
+            self.shares_total_supply > 0 && token_in != token_out && amount_in > 0,
+            PoolError::Invalid,
+        );
+        let amounts = self.normalize(&self.amounts);
+        let d = compute_d(&amounts, self.amp_factor);
+        let y = compute_y(
+            &amounts,
+            token_in,
+            amounts[token_in] + self.normalize_amount(token_in, amount_in),
+            token_out,
+            d,
+            self.amp_factor,
+        );
+        // Subtract one to round in favor of the pool.
+        let amount_out = self.denormalize_amount(
+            token_out,
+            U256::from(amounts[token_out])
+                .checked_sub(y + 1)
+                .unwrap_or_default(),
+        );
+        (U256::from(amount_out) * U256::from(FEE_DIVISOR - self.fee) / U256::from(FEE_DIVISOR))
+            .checked_as_u128()
+    }
+
+    /// Returns how much token you will receive if swap `token_amount_in` of `token_in` for `token_out`.
+    pub fn get_return(
+        &self,
+        token_in: &AccountId,
+        amount_in: Balance,
+        token_out: &AccountId,
+    ) -> Balance {
+        self.internal_get_return(
+            self.token_index(token_in),
+            amount_in,
+            self.token_index(token_out),
+        )
+    }
+
+    /// Returns marginal price of `token_in` in `token_out` excluding fee, with `PRICE_PRECISION` precision.
+    /// Ratio of partial derivatives of the invariant: `(Ann + c / x_in) / (Ann + c / x_out)`, where `c = D^(n+1) / (n^n * prod(x_i))`.
+    pub fn spot_price(&self, token_in: &AccountId, token_out: &AccountId) -> U256 {
+        let in_idx = self.token_index(token_in);
+        let out_idx = self.token_index(token_out);
+        require(
+            self.shares_total_supply > 0 && in_idx != out_idx,
+            PoolError::Invalid,
+        );
+        let amounts = self.normalize(&self.amounts);
+        let n = U256::from(amounts.len());
+        let ann = U256::from(self.amp_factor) * n;
+        let d = compute_d(&amounts, self.amp_factor);
+        let mut c = d;
+        for amount in amounts.iter() {
+            c = c * d / (U256::from(*amount) * n);
+        }
+        let x_in = U256::from(amounts[in_idx]);
+        let x_out = U256::from(amounts[out_idx]);
+        let price = mul_div(
+            mul_div(x_out, ann * x_in + c, x_in),
+            U256::from(PRICE_PRECISION),
+            ann * x_out + c,
+        );
+        // Convert from normalized amounts back to the token amounts.
+        mul_div(
+            price,
+            U256::from(self.decimals_multiplier(in_idx)) * U256::from(self.rates[in_idx]),
+            U256::from(self.decimals_multiplier(out_idx)) * U256::from(self.rates[out_idx]),
+        )
+    }
+
+    /// Returns amount of token in needed to receive given amount of token out, rounded up.
+    /// Tokens are provided as indexes into token list for given pool.
+    fn internal_get_amount_in(
+        &self,
+        token_in: usize,
+        amount_out: Balance,
+        token_out: usize,
+    ) -> Balance {
+        require(
+            self.shares_total_supply > 0 && token_in != token_out && amount_out > 0,
+            PoolError::Invalid,
+        );
+        let amount_out_before_fee = ((U256::from(amount_out) * U256::from(FEE_DIVISOR)
+            + U256::from(FEE_DIVISOR - self.fee - 1))
+            / U256::from(FEE_DIVISOR - self.fee))
+        .checked_as_u128();
+        require(
+            amount_out_before_fee < self.amounts[token_out],
+            PoolError::NotEnoughLiquidity,
+        );
+        let amounts = self.normalize(&self.amounts);
+        let d = compute_d(&amounts, self.amp_factor);
+        // Add one to round in favor of the pool.
+        let new_amount_out =
+            amounts[token_out] - self.normalize_amount(token_out, amount_out_before_fee) - 1;
+        let x = compute_y(
+            &amounts,
+            token_out,
+            new_amount_out,
+            token_in,
+            d,
+            self.amp_factor,
+        );
+        self.denormalize_amount_round_up(token_in, x + 1 - U256::from(amounts[token_in]))
+    }
+
+    /// Returns how much of `token_in` is needed to receive exactly `amount_out` of `token_out`.
+    pub fn get_amount_in(
+        &self,
+        token_in: &AccountId,
+        amount_out: Balance,
+        token_out: &AccountId,
+    ) -> Balance {
+        self.internal_get_amount_in(
+            self.token_index(token_in),
+            amount_out,
+            self.token_index(token_out),
+        )
+    }
+
+    /// Swap `token_amount_in` of `token_in` token into `token_out` and return how much was received.
+    /// Assuming that `token_amount_in` was already received from `sender_id`.
+    pub fn swap(
+        &mut self,
+        token_in: &AccountId,
+        amount_in: Balance,
+        token_out: &AccountId,
+        min_amount_out: Balance,
+    ) -> Balance {
+        self.assert_fresh_rates();
+        let in_idx = self.token_index(token_in);
+        let out_idx = self.token_index(token_out);
+        let amount_out = self.internal_get_return(in_idx, amount_in, out_idx);
+        env::log_str(&format!(
+            "Swapped {} {} for {} {}",
+            amount_in, token_in, amount_out, token_out
+        ));
+        require(amount_out >= min_amount_out, PoolError::MinAmount);
+
+        self.amounts[in_idx] += amount_in;
+        self.amounts[out_idx] -= amount_out;
+
+        amount_out
+    }
+
+    /// Swap `token_in` into exactly `amount_out` of `token_out` and return how much of `token_in` it takes.
+    /// Fails if it takes more than `max_amount_in`.
+    pub fn swap_for_exact(
+        &mut self,
+        token_in: &AccountId,
+        max_amount_in: Balance,
+        token_out: &AccountId,
+        amount_out: Balance,
+    ) -> Balance {
+        self.assert_fresh_rates();
+        let in_idx = self.token_index(token_in);
+        let out_idx = self.token_index(token_out);
+        let amount_in = self.internal_get_amount_in(in_idx, amount_out, out_idx);
+        env::log_str(&format!(
+            "Swapped {} {} for {} {}",
+            amount_in, token_in, amount_out, token_out
+        ));
+        require(amount_in <= max_amount_in, PoolError::MaxAmountIn);
+
+        self.amounts[in_idx] += amount_in;
+        self.amounts[out_idx] -= amount_out;
+
+        amount_in
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use near_sdk::test_utils::{accounts, VMContextBuilder};
+    use near_sdk::testing_env;
+
+    use super::*;
+
+    #[test]
+    fn test_stable_pool_swap() {
+        let one_token = 10u128.pow(24);
+        let mut context = VMContextBuilder::new();
+        context.predecessor_account_id(accounts(0));
+        testing_env!(context.build());
+        let mut pool = StableSwapPool::new(
+            0,
+            vec![accounts(1), accounts(2)],
+            vec![24, 24],
+            vec![None, None],
+            100,
+            5,
+        );
+        let num_shares =
+            pool.add_liquidity(&accounts(0), &mut vec![1000 * one_token, 1000 * one_token]);
+        assert_eq!(num_shares, 2000 * one_token);
+        let amount_out = pool.swap(&accounts(1), 10 * one_token, &accounts(2), 1);
+        // Close to 1:1 minus 0.05% fee, much better than constant product would give.
+        assert!(amount_out > 9_990 * one_token / 1000);
+        assert!(amount_out < 10 * one_token);
+        let amounts = pool.remove_liquidity(&accounts(0), num_shares, vec![1, 1]);
+        assert_eq!(
+            amounts,
+            vec![1010 * one_token, 1000 * one_token - amount_out]
+        );
+        assert_eq!(pool.share_total_balance(), 0);
+    }
+
+    #[test]
+    fn test_stable_pool_imbalanced_deposit() {
+        let one_token = 10u128.pow(24);
+        let mut context = VMContextBuilder::new();
+        context.predecessor_account_id(accounts(0));
+        testing_env!(context.build());
+        let mut pool = StableSwapPool::new(
+            0,
+            vec![accounts(1), accounts(2)],
+            vec![24, 24],
+            vec![None, None],
+            100,
+            30,
+        );
+        pool.add_liquidity(&accounts(0), &mut vec![1000 * one_token, 1000 * one_token]);
+        let balanced = pool.add_liquidity(&accounts(1), &mut vec![one_token, one_token]);
+        let imbalanced = pool.add_liquidity(&accounts(2), &mut vec![2 * one_token, 1]);
+        assert!(imbalanced < balanced);
+    }
+
+    #[test]
+    fn test_stable_pool_remove_liquidity_one_token() {
+        let one_token = 10u128.pow(24);
+        let mut context = VMContextBuilder::new();
+        context.predecessor_account_id(accounts(0));
+        testing_env!(context.build());
+        let mut pool = StableSwapPool::new(
+            0,
+            vec![accounts(1), accounts(2)],
+            vec![24, 24],
+            vec![None, None],
+            100,
+            5,
+        );
+        pool.add_liquidity(&accounts(0), &mut vec![1000 * one_token, 1000 * one_token]);
+        let shares = pool.add_liquidity(&accounts(1), &mut vec![10 * one_token, 10 * one_token]);
+        let amount_out = pool.remove_liquidity_one_token(&accounts(1), shares, &accounts(1), 1);
+        assert!(amount_out > 19_990 * one_token / 1000 && amount_out < 20 * one_token);
+        assert_eq!(pool.share_balances(&accounts(1)), 0);
+    }
+
+    #[test]
+    fn test_stable_pool_remove_liquidity_by_tokens() {
+        let one_token = 10u128.pow(24);
+        let mut context = VMContextBuilder::new();
+        context.predecessor_account_id(accounts(0));
+        testing_env!(context.build());
+        let mut pool = StableSwapPool::new(
+            0,
+            vec![accounts(1), accounts(2)],
+            vec![24, 24],
+            vec![None, None],
+            100,
+            5,
+        );
+        pool.add_liquidity(&accounts(0), &mut vec![1000 * one_token, 1000 * one_token]);
+        let shares = pool.add_liquidity(&accounts(1), &mut vec![10 * one_token, 10 * one_token]);
+        let balanced = pool.remove_liquidity_by_tokens(
+            &accounts(1),
+            vec![5 * one_token, 5 * one_token],
+            shares,
+        );
+        assert!(balanced >= shares / 2 && balanced <= shares / 2 + 2);
+        let imbalanced =
+            pool.remove_liquidity_by_tokens(&accounts(1), vec![5 * one_token, 0], shares);
+        assert!(imbalanced > balanced / 2);
+    }
+
+    #[test]
+    fn test_stable_pool_swap_for_exact() {
+        let mut context = VMContextBuilder::new();
+        context.predecessor_account_id(accounts(0));
+        testing_env!(context.build());
+        let mut pool = StableSwapPool::new(
+            0,
+            vec![accounts(1), accounts(2)],
+            vec![6, 18],
+            vec![None, None],
+            100,
+            5,
+        );
+        pool.add_liquidity(
+            &accounts(0),
+            &mut vec![1_000 * 10u128.pow(6), 1_000 * 10u128.pow(18)],
+        );
+        let amount_out = 10u128.pow(18);
+        let amount_in = pool.get_amount_in(&accounts(1), amount_out, &accounts(2));
+        assert!(amount_in > 10u128.pow(6) && amount_in < 1_001 * 10u128.pow(3));
+        assert!(pool.get_return(&accounts(1), amount_in, &accounts(2)) >= amount_out);
+        assert_eq!(
+            pool.swap_for_exact(&accounts(1), amount_in, &accounts(2), amount_out),
+            amount_in
+        );
+    }
+
+    #[test]
+    fn test_stable_pool_no_dust_from_small_deposits() {
+        let mut context = VMContextBuilder::new();
+        context.predecessor_account_id(accounts(0));
+        testing_env!(context.build());
+        let mut pool = StableSwapPool::new(
+            0,
+            vec![accounts(1), accounts(2)],
+            vec![6, 6],
+            vec![None, None],
+            100,
+            5,
+        );
+        pool.add_liquidity(&accounts(0), &mut vec![1000, 3001]);
+        // Many tiny imbalanced deposits withdrawn at once give back no more than deposited.
+        for _ in 0..100 {
+            pool.add_liquidity(&accounts(1), &mut vec![1, 3]);
+            pool.add_liquidity(&accounts(1), &mut vec![7, 0]);
+        }
+        let shares = pool.share_balances(&accounts(1));
+        let withdrawn = pool.remove_liquidity(&accounts(1), shares, vec![0, 0]);
+        assert!(withdrawn[0] + withdrawn[1] <= 1100);
+    }
+
+    #[test]
+    fn test_stable_pool_spot_price() {
+        let one_token = 10u128.pow(24);
+        let mut context = VMContextBuilder::new();
+        context.predecessor_account_id(accounts(0));
+        testing_env!(context.build());
+        let mut pool = StableSwapPool::new(
+            0,
+            vec![accounts(1), accounts(2)],
+            vec![24, 6],
+            vec![None, None],
+            100,
+            5,
+        );
+        pool.add_liquidity(
+            &accounts(0),
+            &mut vec![1000 * one_token, 1000 * 10u128.pow(6)],
+        );
+        assert_eq!(
+            pool.spot_price(&accounts(1), &accounts(2)),
+            U256::from(10u128.pow(6))
+        );
+        // Marginal price matches price of a small swap.
+        pool.swap(&accounts(1), 100 * one_token, &accounts(2), 1);
+        let spot_price = pool.spot_price(&accounts(1), &accounts(2));
+        let amount_out = pool.get_return(&accounts(1), one_token, &accounts(2));
+        assert!(spot_price < U256::from(10u128.pow(6)));
+        assert!(U256::from(amount_out) < spot_price);
+        assert!(U256::from(amount_out + 1000) > spot_price);
+    }
+
+    #[test]
+    fn test_stable_pool_different_decimals() {
+        let mut context = VMContextBuilder::new();
+        context.predecessor_account_id(accounts(0));
+        testing_env!(context.build());
+        let mut pool = StableSwapPool::new(
+            0,
+            vec![accounts(1), accounts(2)],
+            vec![6, 18],
+            vec![None, None],
+            100,
+            5,
+        );
+        pool.add_liquidity(
+            &accounts(0),
+            &mut vec![1_000 * 10u128.pow(6), 1_000 * 10u128.pow(18)],
+        );
+        let amount_out = pool.swap(&accounts(1), 10u128.pow(6), &accounts(2), 1);
+        assert!(amount_out > 999 * 10u128.pow(15) && amount_out < 10u128.pow(18));
+        let amount_out = pool.swap(&accounts(2), 10u128.pow(18), &accounts(1), 1);
+        assert!(amount_out > 999 * 10u128.pow(3) && amount_out < 10u128.pow(6));
+    }
+
+    #[test]
+    fn test_stable_pool_token_rate() {
+        let one_token = 10u128.pow(24);
+        let mut context = VMContextBuilder::new();
+        context.predecessor_account_id(accounts(0));
+        context.block_timestamp(1_000 * 10u64.pow(9));
+        testing_env!(context.build());
+        let mut pool = StableSwapPool::new(
+            0,
+            vec![accounts(1), accounts(2)],
+            vec![24, 24],
+            vec![Some(accounts(3)), None],
+            100,
+            5,
+        );
+        // 1 token 1 is worth 1.1 of token 2.
+        pool.set_rate(&accounts(1), 11 * RATE_PRECISION / 10);
+        pool.add_liquidity(&accounts(0), &mut vec![1000 * one_token, 1100 * one_token]);
+        let amount_out = pool.swap(&accounts(1), one_token, &accounts(2), 1);
+        assert!(amount_out > 1_099 * one_token / 1000 && amount_out < 11 * one_token / 10);
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_STALE_RATE")]
+    fn test_deny_swap_with_never_fetched_rate() {
+        let one_token = 10u128.pow(24);
+        let mut context = VMContextBuilder::new();
+        context.predecessor_account_id(accounts(0));
+        testing_env!(context.build());
+        let mut pool = StableSwapPool::new(
+            0,
+            vec![accounts(1), accounts(2)],
+            vec![24, 24],
+            vec![Some(accounts(3)), None],
+            100,
+            5,
+        );
+        pool.add_liquidity(&accounts(0), &mut vec![one_token, one_token]);
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_STALE_RATE")]
+    fn test_deny_swap_with_stale_rate() {
+        let one_token = 10u128.pow(24);
+        let mut context = VMContextBuilder::new();
+        context.predecessor_account_id(accounts(0));
+        context.block_timestamp(1_000 * 10u64.pow(9));
+        testing_env!(context.build());
+        let mut pool = StableSwapPool::new(
+            0,
+            vec![accounts(1), accounts(2)],
+            vec![24, 24],
+            vec![Some(accounts(3)), None],
+            100,
+            5,
+        );
+        pool.set_rate(&accounts(1), RATE_PRECISION);
+        pool.add_liquidity(&accounts(0), &mut vec![1000 * one_token, 1000 * one_token]);
+        context.block_timestamp((1_001 + MAX_RATE_AGE) * 10u64.pow(9));
+        testing_env!(context.build());
+        pool.swap(&accounts(1), one_token, &accounts(2), 1);
+    }
+}
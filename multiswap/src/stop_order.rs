@@ -0,0 +1,232 @@
+//! Stop-loss orders. An owner locks token in from the deposits, to be sold in given pool once its price
+//! drops to the trigger price. Anyone can execute a triggered order, and receives a bounty from its token in
+//! (`stop_order_bounty`, set by owner and fixed when the order is placed). The trigger is checked against
+//! the TWAP of the pool over `STOP_ORDER_TWAP_PERIOD`, so keepers can't trigger orders by pushing the spot price
+//! down within a block, and minimum amount out of the order protects the swap itself.
+
+use crate::errors::{require, PoolError, UnwrapOrPanic};
+use crate::utils::U256;
+use crate::*;
+
+/// Seconds over which the average price of the pool must reach the trigger price.
+pub const STOP_ORDER_TWAP_PERIOD: u64 = 5 * 60;
+
+/// Order to sell `amount_in` of token in for token out in the pool once the price falls to the trigger price.
+#[derive(BorshSerialize, BorshDeserialize)]
+pub struct StopOrder {
+    /// Account that placed the order and receives the token out.
+    pub owner_id: AccountId,
+    /// Pool to swap in.
+    pub pool_id: u64,
+    /// Token sold by the order.
+    pub token_in: AccountId,
+    /// Amount of token in, including the bounty.
+    pub amount_in: Balance,
+    /// Token bought by the order.
+    pub token_out: AccountId,
+    /// Price of token in in token out, at or below which the order can be executed.
+    pub trigger_price: Balance,
+    /// Minimum amount of token out of the swap.
+    pub min_amount_out: Balance,
+    /// Part of amount in paid to whoever executes the order.
+    pub bounty: Balance,
+}
+
+/// Stop order as returned by views.
+#[derive(Debug, Serialize, Deserialize, PartialEq)]
+#[serde(crate = "near_sdk::serde")]
+pub struct StopOrderInfo {
+    /// Id of the order.
+    pub order_id: U64,
+    /// Account that placed the order.
+    pub owner_id: AccountId,
+    /// Pool to swap in.
+    pub pool_id: u64,
+    /// Token sold by the order.
+    pub token_in: AccountId,
+    /// Amount of token in, including the bounty.
+    pub amount_in: U128,
+    /// Token bought by the order.
+    pub token_out: AccountId,
+    /// Price of token in in token out, at or below which the order can be executed.
+    pub trigger_price: U128,
+    /// Minimum amount of token out of the swap.
+    pub min_amount_out: U128,
+    /// Part of amount in paid to whoever executes the order.
+    pub bounty: U128,
+}
+
+#[near_bindgen]
+impl Contract {
+    /// Places order to sell `amount_in` of token_in from the deposits of the caller for token_out in the pool once
+    /// its average price over `STOP_ORDER_TWAP_PERIOD` (as in `get_twap`) is at or below `trigger_price`, for at least
+    /// `min_amount_out`. The pool must have TWAP oracle enabled. The caller pays storage of the order.
+    /// Returns id of the order.
+    pub fn add_stop_order(
+        &mut self,
+        pool_id: u64,
+        token_in: AccountId,
+        amount_in: U128,
+        token_out: AccountId,
+        trigger_price: U128,
+        min_amount_out: U128,
+    ) -> U64 {
+        self.assert_running();
+        require(token_in != token_out, PoolError::SameToken);
+        require(
+            amount_in.0 > 0 && trigger_price.0 > 0,
+            PoolError::AmountZero,
+        );
+        // Fails if the pool doesn't have the tokens.
+        self.internal_get_pool(pool_id)
+            .spot_price(&token_in, &token_out);
+        require(
+            self.twap_oracles.contains_key(&pool_id),
+            PoolError::NoTwapOracle,
+        );
+        let sender_id = env::predecessor_account_id();
+        self.internal_withdraw_tokens(&sender_id, &[token_in.clone()], &[amount_in.0]);
+        let order_id = self.next_stop_order_id;
+        self.next_stop_order_id += 1;
+        let bounty = (U256::from(amount_in.0) * U256::from(self.stop_order_bounty)
+            / U256::from(FEE_DIVISOR))
+        .as_u128();
+        let prev_storage = env::storage_usage();
+        self.stop_orders.insert(
+            &order_id,
+            &StopOrder {
+                owner_id: sender_id.clone(),
+                pool_id,
+                token_in,
+                amount_in: amount_in.into(),
+                token_out,
+                trigger_price: trigger_price.into(),
+                min_amount_out: min_amount_out.into(),
+                bounty,
+            },
+        );
+        self.internal_update_storage(&sender_id, prev_storage);
+        self.internal_open_position(&sender_id);
+        log!("Stop order {} placed by {}", order_id, sender_id);
+        order_id.into()
+    }
+
+    /// Cancels order of the caller, returning its token in to the deposits.
+    pub fn cancel_stop_order(&mut self, order_id: U64) {
+        let order = self.internal_get_stop_order(order_id.into());
+        require(
+            order.owner_id == env::predecessor_account_id(),
+            PoolError::NotOwner,
+        );
+        self.internal_remove_stop_order(order_id.into(), &order);
+        let mut deposits = self.internal_get_account(&order.owner_id);
+        deposits.refund(&order.token_in, order.amount_in);
+        self.deposited_amounts.insert(&order.owner_id, &deposits);
+        log!("Stop order {} cancelled", order_id.0);
+    }
+
+    /// Executes the order once the average price of its pool reached the trigger price. The caller receives the bounty
+    /// into the deposits, and must have token in registered if there is any. Returns amount of token out
+    /// credited to the owner of the order.
+    pub fn execute_stop_order(&mut self, order_id: U64) -> U128 {
+        let order = self.internal_get_stop_order(order_id.into());
+        let price = self.internal_get_twap(
+            order.pool_id,
+            &order.token_in,
+            &order.token_out,
+            STOP_ORDER_TWAP_PERIOD,
+        );
+        require(price <= order.trigger_price, PoolError::StopPriceNotReached);
+        self.internal_remove_stop_order(order_id.into(), &order);
+        let amount_out = self.internal_swap(
+            &order.owner_id,
+            order.pool_id,
+            &order.token_in,
+            order.amount_in - order.bounty,
+            &order.token_out,
+            order.min_amount_out,
+            None,
+        );
+        let mut deposits = self.internal_get_account(&order.owner_id);
+        deposits.refund(&order.token_out, amount_out);
+        self.deposited_amounts.insert(&order.owner_id, &deposits);
+        self.internal_deposit_tokens(
+            &env::predecessor_account_id(),
+            &[order.token_in.clone()],
+            &[order.bounty],
+        );
+        log!(
+            "Stop order {} executed at average price {}",
+            order_id.0,
+            price
+        );
+        amount_out.into()
+    }
+
+    /// Sets bounty of stop orders placed from now on, in 1/10000 of their amount in. Only owner and fee setters can call it.
+    #[payable]
+    pub fn set_stop_order_bounty(&mut self, stop_order_bounty: u32) {
+        assert_one_yocto();
+        self.assert_role(Role::FeeSetter);
+        self.assert_no_timelock();
+        self.internal_set_stop_order_bounty(stop_order_bounty);
+    }
+
+    /// Returns bounty of stop orders, in 1/10000 of their amount in.
+    pub fn get_stop_order_bounty(&self) -> u32 {
+        self.stop_order_bounty
+    }
+
+    /// Returns given stop order, or None if it doesn't exist, e.g. because it was executed or cancelled.
+    pub fn get_stop_order(&self, order_id: U64) -> Option<StopOrderInfo> {
+        self.stop_orders
+            .get(&order_id.into())
+            .map(|order| StopOrderInfo::new(order_id.into(), order))
+    }
+
+    /// Returns open stop orders, in no particular order, e.g. for keepers to find the triggered ones.
+    pub fn get_stop_orders(&self, from_index: u64, limit: u64) -> Vec<StopOrderInfo> {
+        let keys = self.stop_orders.keys_as_vector();
+        let values = self.stop_orders.values_as_vector();
+        (from_index..std::cmp::min(from_index + limit, keys.len()))
+            .map(|index| StopOrderInfo::new(keys.get(index).unwrap(), values.get(index).unwrap()))
+            .collect()
+    }
+}
+
+impl StopOrderInfo {
+    fn new(order_id: u64, order: StopOrder) -> Self {
+        Self {
+            order_id: order_id.into(),
+            owner_id: order.owner_id,
+            pool_id: order.pool_id,
+            token_in: order.token_in,
+            amount_in: order.amount_in.into(),
+            token_out: order.token_out,
+            trigger_price: order.trigger_price.into(),
+            min_amount_out: order.min_amount_out.into(),
+            bounty: order.bounty.into(),
+        }
+    }
+}
+
+impl Contract {
+    pub(crate) fn internal_set_stop_order_bounty(&mut self, stop_order_bounty: u32) {
+        require(stop_order_bounty < FEE_DIVISOR, PoolError::FeeTooLarge);
+        self.stop_order_bounty = stop_order_bounty;
+    }
+
+    fn internal_get_stop_order(&self, order_id: u64) -> StopOrder {
+        self.stop_orders
+            .get(&order_id)
+            .unwrap_or_panic(PoolError::NoStopOrder)
+    }
+
+    /// Removes the order, releasing its storage to the owner.
+    fn internal_remove_stop_order(&mut self, order_id: u64, order: &StopOrder) {
+        let prev_storage = env::storage_usage();
+        self.stop_orders.remove(&order_id);
+        self.internal_update_storage(&order.owner_id, prev_storage);
+        self.internal_close_position(&order.owner_id);
+    }
+}
@@ -1,68 +1,132 @@
+//! Storage management of accounts. Promises run interleaved with other calls, so no account or pool is
+//! locked while one is in flight: every transfer out is debited from the deposits or the pools before the promise
+//! is scheduled, and its callback only returns the amount carried in its arguments. Calls interleaving with it
+//! see the balances already without the tokens in flight and can't spend them twice. Deposits measured by balance
+//! credit at most what the balance grew by, and flash loans lock their pool until repaid.
+//! The only state a callback relies on is the account itself, so `storage_unregister` fails while a transfer or
+//! wrapping of NEAR of the account is in flight, or while it has orders, farm stakes or vault shares in escrow.
+
 use crate::*;
 
 /// Implements users storage management for the pool.
-/// TODO: This can be improve by keeping track NEAR balances per user, allowing to keep more than fixed number of tokens.
+/// Registration prepays the deposits record of the account. Anything attached above that
+/// pays for storage the account takes in pools, i.e. liquidity shares and positions.
 #[near_bindgen]
 impl StorageManagement for Contract {
-    #[allow(unused_variables)]
     #[payable]
     fn storage_deposit(
         &mut self,
-        account_id: Option<ValidAccountId>,
+        account_id: Option<AccountId>,
         registration_only: Option<bool>,
     ) -> StorageBalance {
         let amount = env::attached_deposit();
-        let account_id = account_id
-            .map(|a| a.into())
-            .unwrap_or_else(|| env::predecessor_account_id());
-        if self.deposited_amounts.contains_key(&account_id) {
-            log!("The account is already registered, refunding the deposit");
-            if amount > 0 {
-                Promise::new(env::predecessor_account_id()).transfer(amount);
-            }
-        } else {
-            let min_balance = self.storage_balance_bounds().min.0;
-            if amount < min_balance {
-                env::panic(b"The attached deposit is less than the mimimum storage balance");
+        let account_id = account_id.unwrap_or_else(env::predecessor_account_id);
+        let registration_only = registration_only.unwrap_or(false);
+        let min_balance = self.storage_balance_bounds().min.0;
+        match self.deposited_amounts.get(&account_id) {
+            Some(mut deposits) => {
+                if registration_only {
+                    log!("The account is already registered, refunding the deposit");
+                    if amount > 0 {
+                        Promise::new(env::predecessor_account_id()).transfer(amount);
+                    }
+                } else {
+                    deposits.storage_balance += amount;
+                    self.deposited_amounts.insert(&account_id, &deposits);
+                }
             }
-
-            self.internal_register_account(&account_id);
-            let refund = amount - min_balance;
-            if refund > 0 {
-                Promise::new(env::predecessor_account_id()).transfer(refund);
+            None => {
+                near_sdk::require!(
+                    amount >= min_balance,
+                    "The attached deposit is less than the mimimum storage balance"
+                );
+                if registration_only {
+                    self.internal_register_account(&account_id, min_balance);
+                    let refund = amount - min_balance;
+                    if refund > 0 {
+                        Promise::new(env::predecessor_account_id()).transfer(refund);
+                    }
+                } else {
+                    self.internal_register_account(&account_id, amount);
+                }
             }
         }
-        self.storage_balance_of(account_id.try_into().unwrap())
-            .unwrap()
+        self.storage_balance_of(account_id).unwrap()
     }
 
-    #[allow(unused_variables)]
+    /// Withdraws NEAR not needed for storage of the caller, all of it if `amount` is not given.
+    #[payable]
     fn storage_withdraw(&mut self, amount: Option<U128>) -> StorageBalance {
-        // TODO: implement
-        unimplemented!()
+        assert_one_yocto();
+        let account_id = env::predecessor_account_id();
+        let mut deposits = self.internal_get_account(&account_id);
+        let available = deposits.storage_available();
+        let amount = amount.map(|a| a.0).unwrap_or(available);
+        require(amount <= available, PoolError::InsufficientStorage);
+        deposits.storage_balance -= amount;
+        self.deposited_amounts.insert(&account_id, &deposits);
+        if amount > 0 {
+            Promise::new(account_id.clone()).transfer(amount);
+        }
+        self.storage_balance_of(account_id).unwrap()
     }
 
-    #[allow(unused_variables)]
+    /// Unregisters the caller and returns its storage balance.
+    /// Fails if the caller has deposits, unless `force` is set, in which case the deposits are burnt.
+    /// Fails while a transfer of the caller is in flight, or while it has orders, farm stakes or vault shares
+    /// in escrow, which would credit the closed account.
+    /// Storage still taken by the caller's liquidity in pools is not refunded.
+    #[payable]
     fn storage_unregister(&mut self, force: Option<bool>) -> bool {
-        // TODO: implement
-        unimplemented!()
+        assert_one_yocto();
+        let account_id = env::predecessor_account_id();
+        let deposits = match self.deposited_amounts.get(&account_id) {
+            Some(deposits) => deposits,
+            None => return false,
+        };
+        // Tokens returned by a failed transfer would otherwise recreate the account without storage paid.
+        require(
+            !self.transfers_in_flight.contains_key(&account_id),
+            PoolError::TransferInFlight,
+        );
+        require(
+            !self.open_positions.contains_key(&account_id),
+            PoolError::OpenPositions,
+        );
+        if deposits.tokens.values().any(|amount| *amount > 0) {
+            require(force.unwrap_or(false), PoolError::NonZeroDeposits);
+            for (token_id, amount) in deposits.tokens.iter().filter(|(_, amount)| **amount > 0) {
+                log!(
+                    "Burning {} of {} deposited by {}",
+                    amount,
+                    token_id,
+                    account_id
+                );
+            }
+        }
+        self.deposited_amounts.remove(&account_id);
+        let refund = deposits
+            .storage_balance
+            .saturating_sub(deposits.storage_used as Balance * env::storage_byte_cost());
+        if refund > 0 {
+            Promise::new(account_id).transfer(refund);
+        }
+        true
     }
 
     fn storage_balance_bounds(&self) -> StorageBalanceBounds {
         StorageBalanceBounds {
-            min: (BYTES_PER_DEPOSIT_RECORD * env::storage_byte_cost()).into(),
+            min: (BYTES_PER_DEPOSIT_RECORD as u128 * env::storage_byte_cost()).into(),
             max: None,
         }
     }
 
-    fn storage_balance_of(&self, account_id: ValidAccountId) -> Option<StorageBalance> {
-        if self.deposited_amounts.contains_key(account_id.as_ref()) {
-            Some(StorageBalance {
-                total: self.storage_balance_bounds().min,
-                available: 0.into(),
+    fn storage_balance_of(&self, account_id: AccountId) -> Option<StorageBalance> {
+        self.deposited_amounts
+            .get(&account_id)
+            .map(|deposits| StorageBalance {
+                total: deposits.storage_balance.into(),
+                available: deposits.storage_available().into(),
             })
-        } else {
-            None
-        }
     }
 }
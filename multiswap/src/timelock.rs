@@ -0,0 +1,197 @@
+//! Timelocked admin actions. Once owner sets a timelock delay, fee changes, whitelist edits, treasury changes
+//! and reserve floors can't be made directly anymore: owner proposes them, and can execute them only after the delay
+//! passed, so users can see pending actions on-chain and react before they take effect. Changing the delay is
+//! timelocked too.
+
+use crate::dynamic_fee::timestamp_sec;
+use crate::errors::{require, PoolError, UnwrapOrPanic};
+use crate::*;
+
+/// Admin action that can be proposed while the timelock delay is set.
+/// Pending actions are stored, so new actions are added at the end to keep the stored ones readable.
+#[derive(BorshSerialize, BorshDeserialize, Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(crate = "near_sdk::serde")]
+pub enum AdminAction {
+    SetReferralFee {
+        referral_fee: u32,
+    },
+    SetProtocolFee {
+        protocol_fee: u32,
+    },
+    SetFlashLoanFee {
+        flash_loan_fee: u32,
+    },
+    SetLimitOrderFees {
+        fees: LimitOrderFees,
+    },
+    SetStopOrderBounty {
+        stop_order_bounty: u32,
+    },
+    AddWhitelistedToken {
+        token_id: AccountId,
+    },
+    RemoveWhitelistedToken {
+        token_id: AccountId,
+    },
+    SetTreasury {
+        treasury_id: AccountId,
+    },
+    SetTimelockDelay {
+        timelock_delay: u64,
+    },
+    SetVaultProtocolFees {
+        pool_id: u64,
+        enabled: bool,
+    },
+    SetReserveFloor {
+        pool_id: u64,
+        floors: Option<Vec<U128>>,
+    },
+}
+
+/// Proposed admin action waiting for its delay.
+#[derive(BorshSerialize, BorshDeserialize)]
+pub struct PendingAdminAction {
+    /// Action to execute.
+    pub action: AdminAction,
+    /// Timestamp in seconds from which the action can be executed.
+    pub executable_at: u64,
+}
+
+/// Pending admin action as returned by views.
+#[derive(Debug, Serialize, Deserialize, PartialEq)]
+#[serde(crate = "near_sdk::serde")]
+pub struct AdminActionInfo {
+    /// Id of the action.
+    pub action_id: U64,
+    /// Action to execute.
+    pub action: AdminAction,
+    /// Timestamp in seconds from which the action can be executed.
+    pub executable_at: U64,
+}
+
+#[near_bindgen]
+impl Contract {
+    /// Sets delay in seconds between proposing and executing admin actions, enabling the timelock.
+    /// Only owner can call it, and only while there is no delay; afterwards the delay is changed by an admin action.
+    #[payable]
+    pub fn set_timelock_delay(&mut self, timelock_delay: u64) {
+        assert_one_yocto();
+        self.assert_owner();
+        self.assert_no_timelock();
+        self.timelock_delay = timelock_delay;
+    }
+
+    /// Proposes given admin action, to be executed once the timelock delay passed. Only owner can call it.
+    /// Returns id of the action.
+    #[payable]
+    pub fn propose_admin_action(&mut self, action: AdminAction) -> U64 {
+        assert_one_yocto();
+        self.assert_owner();
+        let action_id = self.next_admin_action_id;
+        self.next_admin_action_id += 1;
+        let executable_at = timestamp_sec() + self.timelock_delay;
+        log!(
+            "Admin action {} proposed: {:?}, executable at {}",
+            action_id,
+            action,
+            executable_at
+        );
+        self.admin_actions.insert(
+            &action_id,
+            &PendingAdminAction {
+                action,
+                executable_at,
+            },
+        );
+        action_id.into()
+    }
+
+    /// Executes proposed admin action once its delay passed. Only owner can call it.
+    #[payable]
+    pub fn execute_admin_action(&mut self, action_id: U64) {
+        assert_one_yocto();
+        self.assert_owner();
+        let pending = self
+            .admin_actions
+            .get(&action_id.into())
+            .unwrap_or_panic(PoolError::NoAdminAction);
+        require(
+            timestamp_sec() >= pending.executable_at,
+            PoolError::TimelockNotEnded,
+        );
+        self.admin_actions.remove(&action_id.into());
+        match pending.action {
+            AdminAction::SetReferralFee { referral_fee } => {
+                self.internal_set_referral_fee(referral_fee)
+            }
+            AdminAction::SetProtocolFee { protocol_fee } => {
+                self.internal_set_protocol_fee(protocol_fee)
+            }
+            AdminAction::SetFlashLoanFee { flash_loan_fee } => {
+                self.internal_set_flash_loan_fee(flash_loan_fee)
+            }
+            AdminAction::SetLimitOrderFees { fees } => self.internal_set_limit_order_fees(fees),
+            AdminAction::SetStopOrderBounty { stop_order_bounty } => {
+                self.internal_set_stop_order_bounty(stop_order_bounty)
+            }
+            AdminAction::AddWhitelistedToken { token_id } => {
+                self.internal_add_whitelisted_token(&token_id)
+            }
+            AdminAction::RemoveWhitelistedToken { token_id } => {
+                self.whitelisted_tokens.remove(&token_id);
+            }
+            AdminAction::SetTreasury { treasury_id } => self.treasury_id = Some(treasury_id),
+            AdminAction::SetVaultProtocolFees { pool_id, enabled } => {
+                self.internal_set_vault_protocol_fees(pool_id, enabled)
+            }
+            AdminAction::SetTimelockDelay { timelock_delay } => {
+                self.timelock_delay = timelock_delay
+            }
+            AdminAction::SetReserveFloor { pool_id, floors } => {
+                self.internal_set_reserve_floor(pool_id, floors)
+            }
+        }
+        log!("Admin action {} executed", action_id.0);
+    }
+
+    /// Cancels proposed admin action. Only owner can call it.
+    #[payable]
+    pub fn cancel_admin_action(&mut self, action_id: U64) {
+        assert_one_yocto();
+        self.assert_owner();
+        require(
+            self.admin_actions.remove(&action_id.into()).is_some(),
+            PoolError::NoAdminAction,
+        );
+        log!("Admin action {} cancelled", action_id.0);
+    }
+
+    /// Returns delay in seconds between proposing and executing admin actions.
+    pub fn get_timelock_delay(&self) -> u64 {
+        self.timelock_delay
+    }
+
+    /// Returns proposed admin actions that weren't executed or cancelled yet.
+    pub fn get_admin_actions(&self, from_index: u64, limit: u64) -> Vec<AdminActionInfo> {
+        let keys = self.admin_actions.keys_as_vector();
+        let values = self.admin_actions.values_as_vector();
+        (from_index..std::cmp::min(from_index + limit, keys.len()))
+            .map(|index| {
+                let pending = values.get(index).unwrap();
+                AdminActionInfo {
+                    action_id: keys.get(index).unwrap().into(),
+                    action: pending.action,
+                    executable_at: pending.executable_at.into(),
+                }
+            })
+            .collect()
+    }
+}
+
+impl Contract {
+    /// Fails if the timelock delay is set, so the change must go through `propose_admin_action`.
+    pub(crate) fn assert_no_timelock(&self) {
+        require(self.timelock_delay == 0, PoolError::TimelockActive);
+    }
+}
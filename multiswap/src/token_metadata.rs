@@ -0,0 +1,72 @@
+//! Metadata of tokens cached in the contract state. Whitelisting a token queries its `ft_metadata` and keeps its
+//! symbol and decimals, which pool views return along with the tokens, so UIs can show amounts without calling
+//! every token. Metadata of tokens whitelisted before, or changed since, is fetched again with `refresh_token_metadata`.
+
+use crate::errors::{require, PoolError};
+use crate::*;
+use near_sdk::serde_json;
+
+/// Symbol and decimals of a token, as returned by its `ft_metadata`.
+#[derive(BorshSerialize, BorshDeserialize, Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(crate = "near_sdk::serde")]
+pub struct TokenMetadata {
+    pub symbol: String,
+    pub decimals: u8,
+}
+
+#[near_bindgen]
+impl Contract {
+    /// Returns cached metadata of given token, None until it's fetched.
+    pub fn get_token_metadata(&self, token_id: AccountId) -> Option<TokenMetadata> {
+        self.token_metadata.get(&token_id)
+    }
+
+    /// Fetches metadata of given whitelisted token again. Anyone can call it.
+    pub fn refresh_token_metadata(&mut self, token_id: AccountId) -> Promise {
+        self.assert_whitelisted(&token_id);
+        self.internal_query_token_metadata(&token_id)
+    }
+
+    /// Callback from the token with its metadata. Keeps the previous metadata if the call failed.
+    #[private]
+    pub fn on_token_metadata(&mut self, token_id: AccountId) {
+        require(env::promise_results_count() == 1, PoolError::Invalid);
+        let metadata = match env::promise_result(0) {
+            PromiseResult::Successful(value) => {
+                serde_json::from_slice::<TokenMetadata>(&value).ok()
+            }
+            _ => None,
+        };
+        match metadata {
+            Some(metadata) => {
+                self.token_metadata.insert(&token_id, &metadata);
+            }
+            None => log!("Failed to fetch metadata of {}", token_id),
+        }
+    }
+}
+
+impl Contract {
+    /// Requests metadata of given token, cached once it's returned.
+    pub(crate) fn internal_query_token_metadata(&self, token_id: &AccountId) -> Promise {
+        ext_fungible_token::ext(token_id.clone())
+            .with_static_gas(GAS_FOR_FT_METADATA)
+            .ft_metadata()
+            .then(
+                ext_self::ext(env::current_account_id())
+                    .with_static_gas(GAS_FOR_ON_TOKEN_METADATA)
+                    .on_token_metadata(token_id.clone()),
+            )
+    }
+
+    /// Returns cached metadata of given tokens, in their order.
+    pub(crate) fn internal_tokens_metadata(
+        &self,
+        token_ids: &[AccountId],
+    ) -> Vec<Option<TokenMetadata>> {
+        token_ids
+            .iter()
+            .map(|token_id| self.token_metadata.get(token_id))
+            .collect()
+    }
+}
@@ -1,20 +1,140 @@
+use crate::errors::{require, PoolError, UnwrapOrPanic};
+use crate::events::emit_deposit;
 use crate::*;
+use near_sdk::serde_json;
 use near_sdk::PromiseOrValue;
 
 use near_contract_standards::fungible_token::receiver::FungibleTokenReceiver;
 
+/// Message parameters to receive via token function call.
+#[derive(Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+#[serde(untagged)]
+enum TokenReceiverMessage {
+    /// Alternative to deposit + execute actions call.
+    Execute {
+        /// List of sequential actions.
+        actions: Vec<SwapAction>,
+        /// Sends the outputs back to the sender instead of crediting them to the deposits.
+        /// The swaps then don't touch the deposits, so the sender doesn't need to be registered.
+        #[serde(default)]
+        withdraw: bool,
+        /// Account receiving the referral fee of the swaps.
+        referral_id: Option<AccountId>,
+    },
+}
+
 #[near_bindgen]
 impl FungibleTokenReceiver for Contract {
     /// Callback on receiving tokens by this contract.
+    /// With empty `msg` the tokens are added to the deposits of the sender.
+    /// Otherwise `msg` is JSON of `TokenReceiverMessage`, whose actions are executed right after the deposit,
+    /// and the part of received tokens not used by them is returned to the sender.
+    /// With `withdraw` set, e.g. `{"actions": [...], "withdraw": true}`, the outputs are sent back to the sender as well.
+    /// Tokens with measured deposits only support empty `msg`, and are credited in `on_measured_deposit`.
     fn ft_on_transfer(
         &mut self,
-        sender_id: ValidAccountId,
+        sender_id: AccountId,
         amount: U128,
         msg: String,
     ) -> PromiseOrValue<U128> {
+        self.assert_running();
         let token_in = env::predecessor_account_id();
-        assert!(msg.is_empty(), "ERR_MSG_INCORRECT");
-        self.internal_deposit(sender_id.as_ref(), &token_in, amount.into());
-        PromiseOrValue::Value(U128(0))
+        self.assert_whitelisted(&token_in);
+        if self.measured_balances.contains_key(&token_in) {
+            require(msg.is_empty(), PoolError::MsgIncorrect);
+            self.internal_get_account(&sender_id);
+            return PromiseOrValue::Promise(
+                ext_fungible_token::ext(token_in.clone())
+                    .with_static_gas(GAS_FOR_FT_BALANCE_OF)
+                    .ft_balance_of(env::current_account_id())
+                    .then(
+                        ext_self::ext(env::current_account_id())
+                            .with_static_gas(GAS_FOR_ON_MEASURED_BALANCE)
+                            .on_measured_deposit(sender_id, token_in, amount),
+                    ),
+            );
+        }
+        let amount: Balance = amount.into();
+        if msg.is_empty() {
+            self.internal_deposit_tokens(&sender_id, &[token_in.clone()], &[amount]);
+            self.internal_add_token_balance(&token_in, amount);
+            emit_deposit(&sender_id, &token_in, amount);
+            return PromiseOrValue::Value(U128(0));
+        }
+        let message = serde_json::from_str::<TokenReceiverMessage>(&msg)
+            .unwrap_or_panic(PoolError::MsgIncorrect);
+        match message {
+            TokenReceiverMessage::Execute {
+                actions,
+                withdraw: true,
+                referral_id,
+            } => {
+                require(!actions.is_empty(), PoolError::MsgIncorrect);
+                // Temporary deposits of the sender, with all tokens of the actions registered.
+                let mut deposits = AccountDeposits::default();
+                for action in actions.iter() {
+                    deposits.refund(&action.token_in, 0);
+                    deposits.refund(&action.token_out, 0);
+                }
+                deposits.refund(&token_in, amount);
+                let referral_id = referral_id
+                    .as_ref()
+                    .filter(|referral_id| *referral_id != &sender_id);
+                self.internal_swap_deposits(&sender_id, &mut deposits, actions, referral_id);
+                let unused_amount = std::cmp::min(amount, deposits.get_balance(&token_in));
+                deposits.withdraw(&token_in, unused_amount);
+                self.internal_add_token_balance(&token_in, amount - unused_amount);
+                for (token_id, balance) in deposits.tokens.iter() {
+                    if *balance > 0 {
+                        self.internal_transfer_tokens(&sender_id, token_id, *balance);
+                    }
+                }
+                PromiseOrValue::Value(U128(unused_amount))
+            }
+            TokenReceiverMessage::Execute {
+                actions,
+                withdraw: false,
+                referral_id,
+            } => {
+                require(!actions.is_empty(), PoolError::MsgIncorrect);
+                let prev_deposit = self.internal_get_deposit(&sender_id, &token_in);
+                self.internal_deposit_tokens(&sender_id, &[token_in.clone()], &[amount]);
+                emit_deposit(&sender_id, &token_in, amount);
+                self.internal_execute_actions(&sender_id, actions, referral_id.as_ref());
+                let deposit = self.internal_get_deposit(&sender_id, &token_in);
+                let unused_amount = std::cmp::min(amount, deposit.saturating_sub(prev_deposit));
+                self.internal_withdraw_tokens(&sender_id, &[token_in.clone()], &[unused_amount]);
+                self.internal_add_token_balance(&token_in, amount - unused_amount);
+                PromiseOrValue::Value(U128(unused_amount))
+            }
+        }
+    }
+}
+
+#[near_bindgen]
+impl Contract {
+    /// Callback from the token with the balance of the contract after a deposit of a measured token.
+    /// Credits the sender with the growth of the balance since the last measurement, but not more than
+    /// the transferred amount, so concurrent deposits can't be credited more than the contract received.
+    #[private]
+    pub fn on_measured_deposit(
+        &mut self,
+        sender_id: AccountId,
+        token_id: AccountId,
+        amount: U128,
+        #[callback_unwrap] balance: U128,
+    ) -> U128 {
+        let prev_balance = self
+            .measured_balances
+            .get(&token_id)
+            .unwrap_or_panic(PoolError::MissingToken);
+        let received = std::cmp::min(amount.0, balance.0.saturating_sub(prev_balance));
+        self.measured_balances
+            .insert(&token_id, &(prev_balance + received));
+        self.internal_deposit_tokens(&sender_id, &[token_id.clone()], &[received]);
+        self.internal_add_token_balance(&token_id, received);
+        emit_deposit(&sender_id, &token_id, received);
+        U128(0)
     }
 }
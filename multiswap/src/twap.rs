@@ -0,0 +1,207 @@
+//! Time-weighted average price (TWAP) oracle of a pool, enabled per pool with `enable_twap`.
+//! Before every swap, spot prices of the pool are accumulated over the time since the previous one,
+//! and snapshots of the accumulators are kept at least `OBSERVATION_INTERVAL` apart. Average price over a period is
+//! the difference of the accumulators divided by the elapsed time, which can't be moved by a single block.
+
+use crate::dynamic_fee::timestamp_sec;
+use crate::errors::{require, CheckedAsU128, PoolError, UnwrapOrPanic};
+use crate::utils::{PRICE_PRECISION, U256};
+use crate::*;
+
+/// Minimal number of seconds between stored observations.
+const OBSERVATION_INTERVAL: u64 = 60;
+/// Number of stored observations, so TWAP is available for periods of at least an hour.
+const MAX_OBSERVATIONS: usize = 60;
+
+/// Price accumulators of the pool at given time.
+/// Accumulators wrap on overflow, differences of two of them are correct as long as the period doesn't overflow.
+#[derive(BorshSerialize, BorshDeserialize, Clone)]
+pub struct TwapObservation {
+    /// Block timestamp in seconds.
+    pub timestamp: u64,
+    /// For each token, sum of its spot price in the first token of the pool times seconds.
+    pub to_first: Vec<u128>,
+    /// For each token, sum of the spot price of the first token of the pool in it times seconds.
+    pub from_first: Vec<u128>,
+}
+
+/// TWAP oracle of a single pool.
+#[derive(BorshSerialize, BorshDeserialize)]
+pub struct TwapOracle {
+    /// Accumulators as of the last swap.
+    pub last: TwapObservation,
+    /// Stored observations, preallocated so the storage is paid when enabling the oracle. Empty ones have zero timestamp.
+    pub observations: Vec<TwapObservation>,
+    /// Index in `observations` where the next observation is stored, overwriting the oldest one.
+    pub next_index: usize,
+}
+
+impl TwapObservation {
+    fn new(num_tokens: usize, timestamp: u64) -> Self {
+        Self {
+            timestamp,
+            to_first: vec![0; num_tokens],
+            from_first: vec![0; num_tokens],
+        }
+    }
+
+    /// Accumulates current spot prices of the pool up to given timestamp. Pools without liquidity don't have a price,
+    /// and the time without it is skipped.
+    fn accumulate(&mut self, pool: &Pool, timestamp: u64) {
+        let elapsed = timestamp.saturating_sub(self.timestamp) as u128;
+        if elapsed > 0 && pool.has_liquidity() {
+            let tokens = pool.tokens();
+            for (i, token_id) in tokens.iter().enumerate() {
+                let to_first = spot_price(pool, token_id, &tokens[0]);
+                let from_first = spot_price(pool, &tokens[0], token_id);
+                self.to_first[i] = self.to_first[i].wrapping_add(to_first.wrapping_mul(elapsed));
+                self.from_first[i] =
+                    self.from_first[i].wrapping_add(from_first.wrapping_mul(elapsed));
+            }
+        }
+        self.timestamp = std::cmp::max(self.timestamp, timestamp);
+    }
+}
+
+/// Returns spot price of token_in in token_out, capped to fit into u128. Price of a token in itself is one.
+pub fn spot_price(pool: &Pool, token_in: &AccountId, token_out: &AccountId) -> u128 {
+    if token_in == token_out {
+        return PRICE_PRECISION;
+    }
+    let price = pool.spot_price(token_in, token_out);
+    if price > U256::from(u128::MAX) {
+        u128::MAX
+    } else {
+        price.as_u128()
+    }
+}
+
+impl TwapOracle {
+    pub fn new(num_tokens: usize, timestamp: u64) -> Self {
+        let mut observations = vec![TwapObservation::new(num_tokens, 0); MAX_OBSERVATIONS];
+        observations[0] = TwapObservation::new(num_tokens, timestamp);
+        Self {
+            last: TwapObservation::new(num_tokens, timestamp),
+            observations,
+            next_index: 1 % MAX_OBSERVATIONS,
+        }
+    }
+
+    /// Accumulates prices of the pool before a swap at given timestamp,
+    /// and stores the accumulators if the newest observation is old enough.
+    pub fn update(&mut self, pool: &Pool, timestamp: u64) {
+        self.last.accumulate(pool, timestamp);
+        let newest_index = (self.next_index + MAX_OBSERVATIONS - 1) % MAX_OBSERVATIONS;
+        if self.last.timestamp >= self.observations[newest_index].timestamp + OBSERVATION_INTERVAL {
+            self.observations[self.next_index] = self.last.clone();
+            self.next_index = (self.next_index + 1) % MAX_OBSERVATIONS;
+        }
+    }
+
+    /// Returns average price of token_in in token_out, given by their indexes, from the newest observation
+    /// at least `period` seconds old until given timestamp, with `PRICE_PRECISION` precision.
+    /// Price between two tokens other than the first one is the product of their average prices in the first token.
+    pub fn twap(
+        &self,
+        pool: &Pool,
+        token_in: usize,
+        token_out: usize,
+        period: u64,
+        timestamp: u64,
+    ) -> Balance {
+        let mut current = self.last.clone();
+        current.accumulate(pool, timestamp);
+        let start = self
+            .observations
+            .iter()
+            .filter(|observation| {
+                observation.timestamp > 0
+                    && observation.timestamp.saturating_add(period) <= current.timestamp
+            })
+            .max_by_key(|observation| observation.timestamp)
+            .unwrap_or_panic(PoolError::TwapPeriodTooLong);
+        let elapsed = current.timestamp - start.timestamp;
+        require(elapsed > 0, PoolError::TwapPeriodTooLong);
+        let to_first =
+            current.to_first[token_in].wrapping_sub(start.to_first[token_in]) / elapsed as u128;
+        let from_first = current.from_first[token_out].wrapping_sub(start.from_first[token_out])
+            / elapsed as u128;
+        (U256::from(to_first) * U256::from(from_first) / U256::from(PRICE_PRECISION))
+            .checked_as_u128()
+    }
+}
+
+#[near_bindgen]
+impl Contract {
+    /// Enables TWAP oracle of the pool, which then records its prices on every swap.
+    /// Attached NEAR must cover storage of the oracle, the rest of it is refunded. Anyone can call it.
+    #[payable]
+    pub fn enable_twap(&mut self, pool_id: u64) {
+        let pool = self.internal_get_pool(pool_id);
+        require(
+            !self.twap_oracles.contains_key(&pool_id),
+            PoolError::TwapOracleExists,
+        );
+        let prev_storage = env::storage_usage();
+        self.twap_oracles.insert(
+            &pool_id,
+            &TwapOracle::new(pool.tokens().len(), timestamp_sec()),
+        );
+        self.internal_charge_attached_deposit(prev_storage, 0);
+    }
+
+    /// Returns time-weighted average price of token_in in token_out in the pool over at least the last `period` seconds,
+    /// with 10^24 meaning 1 token_out for 1 token_in. The period starts at the newest stored observation old enough,
+    /// so it may be longer. Fails if the pool doesn't have TWAP oracle or observations that old.
+    pub fn get_twap(
+        &self,
+        pool_id: u64,
+        token_in: AccountId,
+        token_out: AccountId,
+        period: u64,
+    ) -> U128 {
+        require(token_in != token_out, PoolError::SameToken);
+        self.internal_get_twap(pool_id, &token_in, &token_out, period)
+            .into()
+    }
+}
+
+impl Contract {
+    /// Accumulates prices of the pool up to the current block, if its TWAP oracle is enabled.
+    /// Must be called before every swap in the pool.
+    pub(crate) fn internal_update_twap(&mut self, pool_id: u64, pool: &Pool) {
+        if let Some(mut oracle) = self.twap_oracles.get(&pool_id) {
+            oracle.update(pool, timestamp_sec());
+            self.twap_oracles.insert(&pool_id, &oracle);
+        }
+    }
+
+    /// Returns time-weighted average price of token_in in token_out in the pool over at least the last `period` seconds.
+    /// Fails if the pool doesn't have TWAP oracle or observations that old.
+    pub(crate) fn internal_get_twap(
+        &self,
+        pool_id: u64,
+        token_in: &AccountId,
+        token_out: &AccountId,
+        period: u64,
+    ) -> Balance {
+        let oracle = self
+            .twap_oracles
+            .get(&pool_id)
+            .unwrap_or_panic(PoolError::NoTwapOracle);
+        let pool = self.internal_get_pool(pool_id);
+        let token_index = |token_id: &AccountId| {
+            pool.tokens()
+                .iter()
+                .position(|id| id == token_id)
+                .unwrap_or_panic(PoolError::MissingToken)
+        };
+        oracle.twap(
+            &pool,
+            token_index(token_in),
+            token_index(token_out),
+            period,
+            timestamp_sec(),
+        )
+    }
+}
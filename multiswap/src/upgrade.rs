@@ -0,0 +1,69 @@
+//! Self-upgrade. Owner (or the DAO, once it's set) stages new code of this contract, which can be deployed after
+//! the timelock delay, so upgrades go through the same approved and observable path as other admin actions
+//! instead of a full access key. The deployment calls `migrate` right away.
+
+use near_sdk::json_types::Base64VecU8;
+
+use crate::dynamic_fee::timestamp_sec;
+use crate::errors::{require, PoolError, UnwrapOrPanic};
+use crate::utils::GAS_FOR_MIGRATE;
+use crate::*;
+
+/// Storage key of the code staged by `stage_code`.
+const STAGED_CODE_KEY: &[u8] = b"staged_code";
+/// Storage key of the `StagedCode` describing it.
+const STAGED_CODE_INFO_KEY: &[u8] = b"staged_code_info";
+
+/// Code staged for deployment.
+#[derive(BorshSerialize, BorshDeserialize, Serialize, Deserialize, Debug, PartialEq)]
+#[serde(crate = "near_sdk::serde")]
+pub struct StagedCode {
+    /// SHA-256 hash of the code.
+    pub code_hash: Base64VecU8,
+    /// Timestamp in seconds from which the code can be deployed.
+    pub deployable_at: U64,
+}
+
+#[near_bindgen]
+impl Contract {
+    /// Stages new code of this contract, replacing code staged before. It can be deployed
+    /// with `deploy_staged_code` once the timelock delay passed. Only owner can call it.
+    #[payable]
+    pub fn stage_code(&mut self, #[serializer(borsh)] code: Vec<u8>) {
+        assert_one_yocto();
+        self.assert_owner();
+        let staged = StagedCode {
+            code_hash: env::sha256(&code).into(),
+            deployable_at: (timestamp_sec() + self.timelock_delay).into(),
+        };
+        log!("Code staged, deployable at {}", staged.deployable_at.0);
+        env::storage_write(STAGED_CODE_KEY, &code);
+        env::storage_write(STAGED_CODE_INFO_KEY, &staged.try_to_vec().unwrap());
+    }
+
+    /// Deploys the staged code and migrates the state to it. Only owner can call it.
+    #[payable]
+    pub fn deploy_staged_code(&mut self) -> Promise {
+        assert_one_yocto();
+        self.assert_owner();
+        let staged = self
+            .get_staged_code()
+            .unwrap_or_panic(PoolError::NoStagedCode);
+        require(
+            timestamp_sec() >= staged.deployable_at.0,
+            PoolError::TimelockNotEnded,
+        );
+        let code = env::storage_read(STAGED_CODE_KEY).unwrap();
+        env::storage_remove(STAGED_CODE_KEY);
+        env::storage_remove(STAGED_CODE_INFO_KEY);
+        Promise::new(env::current_account_id())
+            .deploy_contract(code)
+            .function_call("migrate".to_string(), b"{}".to_vec(), 0, GAS_FOR_MIGRATE)
+    }
+
+    /// Returns hash of the staged code and when it can be deployed, if there is any.
+    pub fn get_staged_code(&self) -> Option<StagedCode> {
+        env::storage_read(STAGED_CODE_INFO_KEY)
+            .map(|staged| StagedCode::try_from_slice(&staged).unwrap())
+    }
+}
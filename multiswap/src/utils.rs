@@ -1,31 +1,238 @@
 use std::collections::HashSet;
 
 use near_sdk::collections::LookupMap;
-use near_sdk::json_types::{ValidAccountId, U128};
-use near_sdk::{ext_contract, AccountId, Balance, Gas};
-use uint::construct_uint;
+use near_sdk::json_types::U128;
+use near_sdk::{env, ext_contract, AccountId, Balance, Gas};
 
-pub const GAS_FOR_FT_TRANSFER: Gas = 10_000_000_000_000;
+pub use multiswap_math::U256;
 
-construct_uint! {
-    /// 256-bit unsigned integer.
-    pub struct U256(4);
+use crate::errors::{require, PoolError, UnwrapOrPanic};
+use crate::flash_loan::FlashLoan;
+use crate::SwapAction;
+
+pub const GAS_FOR_FT_TRANSFER: Gas = Gas(10_000_000_000_000);
+/// Precision of prices, i.e. price of `PRICE_PRECISION` means 1 token out for 1 token in.
+pub const PRICE_PRECISION: u128 = 1_000_000_000_000_000_000_000_000;
+pub const GAS_FOR_GET_RATE: Gas = Gas(10_000_000_000_000);
+pub const GAS_FOR_ON_TOKEN_RATE: Gas = Gas(10_000_000_000_000);
+pub const GAS_FOR_ON_WITHDRAW: Gas = Gas(10_000_000_000_000);
+pub const GAS_FOR_ON_TRANSFER_CALL: Gas = Gas(10_000_000_000_000);
+/// Gas kept by `swap_and_call` for the swaps and the callback, the rest goes to `ft_transfer_call`.
+pub const GAS_FOR_SWAP_AND_CALL: Gas = Gas(40_000_000_000_000 + GAS_FOR_ON_TRANSFER_CALL.0);
+pub const GAS_FOR_NEAR_DEPOSIT: Gas = Gas(10_000_000_000_000);
+pub const GAS_FOR_NEAR_WITHDRAW: Gas = Gas(10_000_000_000_000);
+pub const GAS_FOR_ON_NEAR_WITHDRAW: Gas = Gas(10_000_000_000_000);
+pub const GAS_FOR_ON_NEAR_DEPOSIT: Gas = Gas(10_000_000_000_000);
+/// Gas kept by `swap_near` with attached NEAR for itself, the fees of its receipts and wrapping,
+/// the rest goes to the swaps after it.
+pub const GAS_FOR_SWAP_NEAR: Gas = Gas(25_000_000_000_000 + GAS_FOR_NEAR_DEPOSIT.0);
+pub const GAS_FOR_FT_BALANCE_OF: Gas = Gas(10_000_000_000_000);
+pub const GAS_FOR_ON_MEASURED_BALANCE: Gas = Gas(20_000_000_000_000);
+pub const GAS_FOR_ON_BALANCE: Gas = Gas(10_000_000_000_000);
+pub const GAS_FOR_ON_EXCESS_TRANSFER: Gas = Gas(10_000_000_000_000);
+pub const GAS_FOR_FT_METADATA: Gas = Gas(10_000_000_000_000);
+pub const GAS_FOR_ON_TOKEN_METADATA: Gas = Gas(10_000_000_000_000);
+/// Gas for the callback that reads the balance and sends the excess out.
+pub const GAS_FOR_SEND_EXCESS: Gas =
+    Gas(GAS_FOR_ON_BALANCE.0 + GAS_FOR_FT_TRANSFER.0 + GAS_FOR_ON_EXCESS_TRANSFER.0);
+pub const GAS_FOR_MFT_RESOLVE_TRANSFER: Gas = Gas(10_000_000_000_000);
+/// Gas kept by `mft_transfer_call` for itself and the resolve callback, the rest goes to the receiver.
+pub const GAS_FOR_MFT_TRANSFER_CALL: Gas = Gas(25_000_000_000_000 + GAS_FOR_MFT_RESOLVE_TRANSFER.0);
+pub const GAS_FOR_NFT_RESOLVE_TRANSFER: Gas = Gas(10_000_000_000_000);
+/// Gas kept by `nft_transfer_call` for itself and the resolve callback, the rest goes to the receiver.
+pub const GAS_FOR_NFT_TRANSFER_CALL: Gas = Gas(25_000_000_000_000 + GAS_FOR_NFT_RESOLVE_TRANSFER.0);
+pub const GAS_FOR_LP_TOKEN_NEW: Gas = Gas(20_000_000_000_000);
+pub const GAS_FOR_ON_LP_TOKEN_DEPLOY: Gas = Gas(10_000_000_000_000);
+pub const GAS_FOR_LP_TOKEN_MINT: Gas = Gas(10_000_000_000_000);
+pub const GAS_FOR_LP_TOKEN_BURN: Gas = Gas(10_000_000_000_000);
+pub const GAS_FOR_ON_LP_TOKEN_MINT: Gas = Gas(10_000_000_000_000);
+pub const GAS_FOR_ON_LP_TOKEN_BURN: Gas = Gas(10_000_000_000_000);
+pub const GAS_FOR_RESOLVE_FLASH_LOAN: Gas = Gas(10_000_000_000_000);
+/// Gas for resolving a flash swap, which may swap the repayment through the pool.
+pub const GAS_FOR_RESOLVE_FLASH_SWAP: Gas = Gas(40_000_000_000_000);
+pub const GAS_FOR_ON_FLASH_LOAN_RESOLVED: Gas = Gas(10_000_000_000_000);
+/// Gas kept by `on_flash_loan_transfer` for itself, the fees of its receipts and the resolve callbacks,
+/// the rest goes to the receiver.
+pub const GAS_FOR_ON_FLASH_LOAN_TRANSFER: Gas =
+    Gas(40_000_000_000_000 + GAS_FOR_RESOLVE_FLASH_SWAP.0 + GAS_FOR_ON_FLASH_LOAN_RESOLVED.0);
+/// Gas kept by `flash_loan` for itself and the transfer, the rest goes to the callback.
+pub const GAS_FOR_FLASH_LOAN: Gas = Gas(20_000_000_000_000 + GAS_FOR_FT_TRANSFER.0);
+/// Gas attached to admin calls in DAO proposals built by `get_dao_proposal`.
+pub const GAS_FOR_DAO_ACTION: Gas = Gas(20_000_000_000_000);
+/// Gas for `migrate` called after deploying staged code.
+pub const GAS_FOR_MIGRATE: Gas = Gas(50_000_000_000_000);
+
+/// Computes `a * b / denominator` rounding down, with full precision of the intermediate product.
+pub fn mul_div(a: U256, b: U256, denominator: U256) -> U256 {
+    multiswap_math::mul_div(a, b, denominator).unwrap_or_panic(PoolError::MulDivOverflow)
+}
+
+/// Computes `a * b / denominator` rounding up, with full precision of the intermediate product.
+pub fn mul_div_round_up(a: U256, b: U256, denominator: U256) -> U256 {
+    multiswap_math::mul_div_round_up(a, b, denominator).unwrap_or_panic(PoolError::MulDivOverflow)
 }
 
 /// TODO: this should be in the near_standard_contracts
 #[ext_contract(ext_fungible_token)]
+#[allow(dead_code)]
 pub trait FungibleToken {
     fn ft_transfer(&mut self, receiver_id: AccountId, amount: U128, memo: Option<String>);
+    fn ft_transfer_call(
+        &mut self,
+        receiver_id: AccountId,
+        amount: U128,
+        memo: Option<String>,
+        msg: String,
+    ) -> U128;
+    fn ft_balance_of(&self, account_id: AccountId) -> U128;
+    fn ft_metadata(
+        &self,
+    ) -> near_contract_standards::fungible_token::metadata::FungibleTokenMetadata;
+}
+
+/// Interface of wrapped NEAR token, converting native NEAR to the fungible token and back.
+#[ext_contract(ext_wrap_near)]
+#[allow(dead_code)]
+pub trait WrapNear {
+    fn near_deposit(&mut self);
+    fn near_withdraw(&mut self, amount: U128);
+}
+
+/// Interface of token rate provider, returning exchange rate of the token to the underlying asset
+/// with `RATE_PRECISION` precision (e.g. staking pool returning NEAR per stNEAR).
+#[ext_contract(ext_rate_provider)]
+#[allow(dead_code)]
+pub trait RateProvider {
+    fn get_rate(&self) -> U128;
+}
+
+/// Interface of contracts receiving shares with `mft_transfer_call`, returning the number of shares they didn't use.
+#[ext_contract(ext_mft_receiver)]
+#[allow(dead_code)]
+pub trait MFTTokenReceiver {
+    fn mft_on_transfer(
+        &mut self,
+        token_id: String,
+        sender_id: AccountId,
+        amount: U128,
+        msg: String,
+    ) -> near_sdk::PromiseOrValue<U128>;
+}
+
+/// Interface of contracts receiving position tokens with `nft_transfer_call`, returning whether to return the token.
+#[ext_contract(ext_nft_receiver)]
+#[allow(dead_code)]
+pub trait NonFungibleTokenReceiver {
+    fn nft_on_transfer(
+        &mut self,
+        sender_id: AccountId,
+        previous_owner_id: AccountId,
+        token_id: String,
+        msg: String,
+    ) -> near_sdk::PromiseOrValue<bool>;
+}
+
+/// Interface of contracts receiving flash loans. The receiver must deposit the loan plus fee back
+/// to its deposits in the contract before the call ends, or the amount in of a flash swap.
+#[ext_contract(ext_flash_loan_receiver)]
+#[allow(dead_code)]
+pub trait FlashLoanReceiver {
+    fn on_flash_loan(
+        &mut self,
+        sender_id: AccountId,
+        token_id: AccountId,
+        amount: U128,
+        fee: U128,
+        msg: String,
+    );
+    fn on_flash_swap(&mut self, sender_id: AccountId, loan: FlashLoan, msg: String);
+}
+
+/// Interface of the token wrapping shares of a pool, minted and burnt by this contract.
+#[ext_contract(ext_lp_token)]
+#[allow(dead_code)]
+pub trait LpToken {
+    fn mint(&mut self, account_id: AccountId, amount: U128);
+    fn burn(&mut self, account_id: AccountId, amount: U128);
+}
+
+#[ext_contract(ext_self)]
+#[allow(dead_code)]
+pub trait MultiSwapSelf {
+    fn on_token_rate(&mut self, pool_id: u64, token_id: AccountId);
+    fn on_withdraw(&mut self, token_id: AccountId, sender_id: AccountId, amount: U128);
+    fn on_transfer_call(&mut self, token_id: AccountId, sender_id: AccountId, amount: U128)
+        -> U128;
+    fn on_near_withdraw(&mut self, sender_id: AccountId, amount: U128);
+    fn on_near_deposit(
+        &mut self,
+        sender_id: AccountId,
+        amount: U128,
+        actions: Vec<SwapAction>,
+        referral_id: Option<AccountId>,
+    );
+    fn swap_near_from_deposits(
+        &mut self,
+        sender_id: AccountId,
+        actions: Vec<SwapAction>,
+        referral_id: Option<AccountId>,
+    );
+    fn on_measured_token_balance(&mut self, token_id: AccountId);
+    fn on_token_metadata(&mut self, token_id: AccountId);
+    fn on_measured_deposit(&mut self, sender_id: AccountId, token_id: AccountId, amount: U128);
+    fn on_sync_balance(&mut self, pool_id: u64, token_id: AccountId);
+    fn on_migrated_token_balance(&mut self, token_id: AccountId);
+    fn on_skim_balance(&mut self, token_id: AccountId, receiver_id: AccountId);
+    fn on_excess_transfer(&mut self, token_id: AccountId, amount: U128);
+    fn on_rescue_balance(&mut self, token_id: AccountId, amount: U128, receiver_id: AccountId);
+    fn mft_resolve_transfer(
+        &mut self,
+        token_id: String,
+        sender_id: AccountId,
+        receiver_id: AccountId,
+        amount: U128,
+    ) -> U128;
+    fn nft_resolve_transfer(
+        &mut self,
+        owner_id: AccountId,
+        receiver_id: AccountId,
+        token_id: String,
+    ) -> bool;
+    fn on_lp_token_deploy(&mut self, pool_id: u64);
+    fn on_lp_token_mint(&mut self, pool_id: u64, sender_id: AccountId, amount: U128);
+    fn on_lp_token_burn(&mut self, pool_id: u64, sender_id: AccountId, amount: U128);
+    fn on_flash_loan_transfer(&mut self, loan: FlashLoan, sender_id: AccountId, msg: String);
+    fn resolve_flash_loan(&mut self, loan: FlashLoan) -> U128;
+    fn on_flash_loan_resolved(&mut self, loan: FlashLoan) -> U128;
 }
 
 /// Adds given value to item stored in the given key in the LookupMap collection.
-pub fn add_to_collection(c: &mut LookupMap<AccountId, Balance>, key: &String, value: Balance) {
+pub fn add_to_collection(c: &mut LookupMap<AccountId, Balance>, key: &AccountId, value: Balance) {
     let prev_value = c.get(key).unwrap_or(0);
     c.insert(key, &(prev_value + value));
 }
 
-/// Checks if there are any duplicates in the given list of tokens.
-pub fn check_token_duplicates(tokens: &[ValidAccountId]) {
-    let token_set: HashSet<_> = tokens.iter().map(|a| AccountId::from(a.clone())).collect();
-    assert_eq!(token_set.len(), tokens.len(), "ERR_TOKEN_DUPLICATES");
+/// Returns id of the token wrapping shares of given pool, deployed as a sub-account of this contract.
+pub fn lp_token_id(pool_id: u64) -> AccountId {
+    AccountId::new_unchecked(format!("lp-{}.{}", pool_id, env::current_account_id()))
+}
+
+/// Checks that the given list of tokens of a new pool has at least two tokens, none of them this contract,
+/// and no duplicates, which would break lookups of tokens by index and invariants of the pool.
+pub fn check_pool_tokens(tokens: &[AccountId]) {
+    require(tokens.len() >= 2, PoolError::NotEnoughTokens);
+    require(
+        tokens
+            .iter()
+            .all(|token_id| token_id != &env::current_account_id()),
+        PoolError::TokenIsContract,
+    );
+    let token_set: HashSet<_> = tokens.iter().collect();
+    require(token_set.len() == tokens.len(), PoolError::TokenDuplicates);
+}
+
+/// Returns id under which shares of given pool are held in the deposits and traded in metapools.
+/// It's a sub-account of this contract, so it can't collide with any token not deployed by the contract owner.
+pub fn shares_token_id(pool_id: u64) -> AccountId {
+    AccountId::new_unchecked(format!("shares-{}.{}", pool_id, env::current_account_id()))
 }
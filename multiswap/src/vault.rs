@@ -0,0 +1,271 @@
+//! Auto-compounding vaults. A vault of a pool holds shares of the pool for its depositors, who get vault shares
+//! in return. Swap fees stay in the pool, so the vault earns them with its shares like any liquidity provider.
+//! Rewards on top of that (tokens of the pool added by anyone, e.g. incentives of a project, and protocol fees
+//! of the pool if owner directs them to the vault) are added back as liquidity by `compound_vault`, which anyone
+//! can call periodically. Imbalanced rewards are charged swap fee, as if they were swapped into the other tokens
+//! before adding, so each vault share is worth more shares of the pool over time. Compounding is only done while
+//! prices of the pool are close to their TWAP, so it can't be sandwiched by moving the price first.
+//! The first deposit into a vault locks `MIN_LIQUIDITY` vault shares, like the first deposit into a pool, so
+//! the value of a vault share can't be inflated by rewarding an almost empty vault.
+
+use crate::dynamic_fee::timestamp_sec;
+use crate::errors::{require, PoolError, UnwrapOrPanic};
+use crate::pool::MIN_LIQUIDITY;
+use crate::twap::spot_price;
+use crate::utils::U256;
+use crate::*;
+
+/// Seconds over which the average prices of the pool are taken as the reference of compounding.
+const VAULT_TWAP_PERIOD: u64 = 5 * 60;
+/// Maximum deviation of the prices of the pool from their average at which rewards are compounded, in basis points.
+const MAX_COMPOUND_PRICE_DEVIATION: u32 = 100;
+
+/// Vault of a pool.
+#[derive(BorshSerialize, BorshDeserialize)]
+pub struct Vault {
+    /// Shares of the pool held by the vault.
+    pub shares: Balance,
+    /// Total supply of vault shares.
+    pub total_supply: Balance,
+    /// Rewards waiting to be compounded, in the order of the pool's tokens.
+    pub rewards: Vec<Balance>,
+    /// Whether protocol fees of the pool are compounded into the vault instead of going to the treasury.
+    pub compounds_protocol_fees: bool,
+    /// Timestamp in seconds of the last compounding.
+    pub last_compound_at: u64,
+}
+
+/// Vault of a pool, returned by `get_vault`.
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+#[serde(crate = "near_sdk::serde")]
+pub struct VaultInfo {
+    pub shares: U128,
+    pub total_supply: U128,
+    pub rewards: Vec<U128>,
+    pub compounds_protocol_fees: bool,
+    pub last_compound_at: U64,
+}
+
+#[near_bindgen]
+impl Contract {
+    /// Creates vault of the pool, enabling TWAP oracle of the pool if it's not enabled yet. Attached NEAR must cover
+    /// storage of both, the rest of it is refunded. Anyone can call it.
+    #[payable]
+    pub fn create_vault(&mut self, pool_id: u64) {
+        let pool = self.internal_get_pool(pool_id);
+        require(
+            matches!(
+                pool,
+                Pool::SimplePool(_)
+                    | Pool::StableSwapPool(_)
+                    | Pool::WeightedPool(_)
+                    | Pool::ConstantSumPool(_)
+            ),
+            PoolError::NoLiquidityShares,
+        );
+        require(!self.vaults.contains_key(&pool_id), PoolError::VaultExists);
+        let prev_storage = env::storage_usage();
+        self.vaults.insert(
+            &pool_id,
+            &Vault {
+                shares: 0,
+                total_supply: 0,
+                rewards: vec![0; pool.tokens().len()],
+                compounds_protocol_fees: false,
+                last_compound_at: timestamp_sec(),
+            },
+        );
+        if !self.twap_oracles.contains_key(&pool_id) {
+            self.twap_oracles.insert(
+                &pool_id,
+                &TwapOracle::new(pool.tokens().len(), timestamp_sec()),
+            );
+        }
+        self.internal_charge_attached_deposit(prev_storage, 0);
+    }
+
+    /// Moves given number of caller's shares of the pool into its vault. The first deposit gets `MIN_LIQUIDITY`
+    /// vault shares less, which stay locked. Returns number of minted vault shares.
+    pub fn vault_deposit(&mut self, pool_id: u64, shares: U128) -> U128 {
+        self.assert_running();
+        let sender_id = env::predecessor_account_id();
+        let mut vault = self.internal_get_vault(pool_id);
+        let vault_shares = if vault.total_supply == 0 {
+            require(shares.0 > MIN_LIQUIDITY, PoolError::SharesBelowMinLiquidity);
+            vault.total_supply = MIN_LIQUIDITY;
+            shares.0 - MIN_LIQUIDITY
+        } else {
+            (U256::from(shares.0) * U256::from(vault.total_supply) / U256::from(vault.shares))
+                .as_u128()
+        };
+        require(vault_shares > 0, PoolError::ZeroShares);
+        let mut pool = self.internal_get_pool(pool_id);
+        let prev_storage = env::storage_usage();
+        self.internal_settle_lp_fees(pool_id, &pool, &sender_id);
+        self.internal_settle_lp_fees(pool_id, &pool, &env::current_account_id());
+        pool.share_transfer(&sender_id, &env::current_account_id(), shares.into());
+        self.internal_prune_lp_fees(pool_id, &pool, &sender_id);
+        self.assert_shares_unlocked(pool_id, &pool, &sender_id);
+        self.internal_set_pool(pool_id, pool);
+        vault.shares += shares.0;
+        vault.total_supply += vault_shares;
+        self.vaults.insert(&pool_id, &vault);
+        let key = (pool_id, sender_id.clone());
+        let balance = self.vault_balances.get(&key).unwrap_or_default();
+        self.vault_balances.insert(&key, &(balance + vault_shares));
+        if balance == 0 {
+            self.internal_open_position(&sender_id);
+        }
+        self.internal_update_storage(&sender_id, prev_storage);
+        vault_shares.into()
+    }
+
+    /// Burns given number of caller's vault shares of the pool and moves their part of the vault's shares
+    /// back to the caller. Returns number of the pool's shares received.
+    pub fn vault_withdraw(&mut self, pool_id: u64, vault_shares: U128) -> U128 {
+        let sender_id = env::predecessor_account_id();
+        let mut vault = self.internal_get_vault(pool_id);
+        let key = (pool_id, sender_id.clone());
+        let balance = self.vault_balances.get(&key).unwrap_or_default();
+        require(balance >= vault_shares.0, PoolError::NotEnoughShares);
+        require(vault_shares.0 > 0, PoolError::ZeroShares);
+        let shares = (U256::from(vault_shares.0) * U256::from(vault.shares)
+            / U256::from(vault.total_supply))
+        .as_u128();
+        require(shares > 0, PoolError::ZeroShares);
+        let mut pool = self.internal_get_pool(pool_id);
+        let prev_storage = env::storage_usage();
+        self.internal_settle_lp_fees(pool_id, &pool, &env::current_account_id());
+        self.internal_settle_lp_fees(pool_id, &pool, &sender_id);
+        pool.share_transfer(&env::current_account_id(), &sender_id, shares);
+        self.internal_prune_lp_fees(pool_id, &pool, &env::current_account_id());
+        self.internal_set_pool(pool_id, pool);
+        vault.shares -= shares;
+        vault.total_supply -= vault_shares.0;
+        self.vaults.insert(&pool_id, &vault);
+        if balance == vault_shares.0 {
+            self.vault_balances.remove(&key);
+            self.internal_close_position(&sender_id);
+        } else {
+            self.vault_balances
+                .insert(&key, &(balance - vault_shares.0));
+        }
+        self.internal_update_storage(&sender_id, prev_storage);
+        shares.into()
+    }
+
+    /// Adds given amount of a token of the pool from caller's deposits to the rewards of its vault,
+    /// to be compounded for the vault's depositors.
+    pub fn add_vault_rewards(&mut self, pool_id: u64, token_id: AccountId, amount: U128) {
+        let sender_id = env::predecessor_account_id();
+        let mut vault = self.internal_get_vault(pool_id);
+        let pool = self.internal_get_pool(pool_id);
+        let index = pool
+            .tokens()
+            .iter()
+            .position(|id| id == &token_id)
+            .unwrap_or_panic(PoolError::MissingToken);
+        self.internal_withdraw_tokens(&sender_id, &[token_id], &[amount.into()]);
+        vault.rewards[index] += amount.0;
+        self.vaults.insert(&pool_id, &vault);
+    }
+
+    /// Claims protocol fees of the pool if its vault compounds them, and adds the rewards of the vault
+    /// to the pool as liquidity of the vault. Fails if prices of the pool deviate from their average over
+    /// `VAULT_TWAP_PERIOD` more than `MAX_COMPOUND_PRICE_DEVIATION`, or if it would add less than `min_shares`.
+    /// Anyone can call it. Returns number of shares added to the vault.
+    pub fn compound_vault(&mut self, pool_id: u64, min_shares: U128) -> U128 {
+        self.assert_pool_running(pool_id);
+        self.assert_no_flash_loan(pool_id);
+        let mut vault = self.internal_get_vault(pool_id);
+        if vault.compounds_protocol_fees {
+            let fees = self.protocol_fees.remove(&pool_id).unwrap_or_default();
+            for (reward, fee) in vault.rewards.iter_mut().zip(fees) {
+                *reward += fee;
+            }
+        }
+        // Rewards wait for the first depositor, so they can't go to nobody.
+        if vault.total_supply == 0 || vault.rewards.iter().all(|reward| *reward == 0) {
+            self.vaults.insert(&pool_id, &vault);
+            return 0.into();
+        }
+        let mut pool = self.internal_get_pool(pool_id);
+        self.assert_prices_near_twap(pool_id, &pool);
+        self.internal_settle_lp_fees(pool_id, &pool, &env::current_account_id());
+        let rewards = std::mem::replace(&mut vault.rewards, vec![0; pool.tokens().len()]);
+        let shares = pool.add_liquidity_imbalanced(&env::current_account_id(), rewards);
+        require(shares >= min_shares.0, PoolError::MinShares);
+        self.internal_set_pool(pool_id, pool);
+        vault.shares += shares;
+        vault.last_compound_at = timestamp_sec();
+        self.vaults.insert(&pool_id, &vault);
+        log!(
+            "Compounded {} shares into vault of pool {}",
+            shares,
+            pool_id
+        );
+        shares.into()
+    }
+
+    /// Directs protocol fees of the pool to its vault instead of the treasury, or back. Only owner can call it.
+    #[payable]
+    pub fn set_vault_protocol_fees(&mut self, pool_id: u64, enabled: bool) {
+        assert_one_yocto();
+        self.assert_owner();
+        self.assert_no_timelock();
+        self.internal_set_vault_protocol_fees(pool_id, enabled);
+    }
+
+    /// Returns vault of the pool, if it exists.
+    pub fn get_vault(&self, pool_id: u64) -> Option<VaultInfo> {
+        self.vaults.get(&pool_id).map(|vault| VaultInfo {
+            shares: vault.shares.into(),
+            total_supply: vault.total_supply.into(),
+            rewards: vault.rewards.into_iter().map(U128).collect(),
+            compounds_protocol_fees: vault.compounds_protocol_fees,
+            last_compound_at: vault.last_compound_at.into(),
+        })
+    }
+
+    /// Returns vault shares of the pool given account holds.
+    pub fn get_vault_balance(&self, pool_id: u64, account_id: AccountId) -> U128 {
+        self.vault_balances
+            .get(&(pool_id, account_id))
+            .unwrap_or_default()
+            .into()
+    }
+}
+
+impl Contract {
+    fn internal_get_vault(&self, pool_id: u64) -> Vault {
+        self.vaults
+            .get(&pool_id)
+            .unwrap_or_panic(PoolError::NoVault)
+    }
+
+    /// Fails if the price of any token of the pool in its first token deviates from the average price over
+    /// `VAULT_TWAP_PERIOD` more than `MAX_COMPOUND_PRICE_DEVIATION`.
+    fn assert_prices_near_twap(&self, pool_id: u64, pool: &Pool) {
+        let tokens = pool.tokens();
+        for token_id in tokens[1..].iter() {
+            let price = spot_price(pool, token_id, &tokens[0]);
+            let average = self.internal_get_twap(pool_id, token_id, &tokens[0], VAULT_TWAP_PERIOD);
+            let deviation = if price > average {
+                price - average
+            } else {
+                average - price
+            };
+            require(
+                U256::from(deviation) * U256::from(FEE_DIVISOR)
+                    <= U256::from(average) * U256::from(MAX_COMPOUND_PRICE_DEVIATION),
+                PoolError::PriceDeviatesFromTwap,
+            );
+        }
+    }
+
+    pub(crate) fn internal_set_vault_protocol_fees(&mut self, pool_id: u64, enabled: bool) {
+        let mut vault = self.internal_get_vault(pool_id);
+        vault.compounds_protocol_fees = enabled;
+        self.vaults.insert(&pool_id, &vault);
+    }
+}
@@ -2,15 +2,27 @@
 
 use std::collections::HashMap;
 
-use near_sdk::json_types::{ValidAccountId, U128};
+use near_sdk::json_types::{U128, U64};
 use near_sdk::serde::{Deserialize, Serialize};
 use near_sdk::{near_bindgen, AccountId};
 
+use crate::errors::{require, CheckedAsU128, PoolError, UnwrapOrPanic};
+use crate::utils::{
+    lp_token_id, GAS_FOR_FLASH_LOAN, GAS_FOR_FT_TRANSFER, GAS_FOR_MFT_TRANSFER_CALL,
+    GAS_FOR_MIGRATE, GAS_FOR_SWAP_AND_CALL, PRICE_PRECISION, U256,
+};
 use crate::*;
 
+/// Divisor of values in basis points.
+const BPS_DIVISOR: u32 = 10_000;
+/// Maximum number of hops of a route searched by `get_best_route`.
+const MAX_ROUTE_HOPS: u8 = 3;
+
 #[derive(Debug, Serialize, Deserialize, PartialEq)]
 #[serde(crate = "near_sdk::serde")]
 pub struct PoolInfo {
+    /// Pool kind.
+    pub pool_kind: String,
     /// List of tokens in the pool.
     pub token_account_ids: Vec<AccountId>,
     /// How much NEAR this contract has.
@@ -19,23 +31,342 @@ pub struct PoolInfo {
     pub fee: u32,
     /// Total number of shares.
     pub shares_total_supply: U128,
+    /// Cached metadata of the tokens, in their order, None for tokens whose metadata isn't fetched.
+    pub token_metadata: Vec<Option<TokenMetadata>>,
 }
 
 impl From<Pool> for PoolInfo {
     fn from(pool: Pool) -> Self {
+        let pool_kind = pool.kind();
         match pool {
             Pool::SimplePool(pool) => Self {
+                pool_kind,
+                token_account_ids: pool.token_account_ids,
+                amounts: pool.amounts.into_iter().map(|a| U128(a)).collect(),
+                fee: pool.fee,
+                shares_total_supply: U128(pool.shares_total_supply),
+                token_metadata: vec![],
+            },
+            Pool::StableSwapPool(pool) => Self {
+                pool_kind,
+                token_account_ids: pool.token_account_ids,
+                amounts: pool.amounts.into_iter().map(|a| U128(a)).collect(),
+                fee: pool.fee,
+                shares_total_supply: U128(pool.shares_total_supply),
+                token_metadata: vec![],
+            },
+            Pool::WeightedPool(pool) => Self {
+                pool_kind,
+                token_account_ids: pool.token_account_ids,
+                amounts: pool.amounts.into_iter().map(|a| U128(a)).collect(),
+                fee: pool.fee,
+                shares_total_supply: U128(pool.shares_total_supply),
+                token_metadata: vec![],
+            },
+            Pool::ConcentratedPool(pool) => Self {
+                pool_kind,
+                token_account_ids: pool.token_account_ids,
+                amounts: pool.amounts.into_iter().map(|a| U128(a)).collect(),
+                fee: pool.fee,
+                shares_total_supply: U128(0),
+                token_metadata: vec![],
+            },
+            Pool::ConstantSumPool(pool) => Self {
+                pool_kind,
                 token_account_ids: pool.token_account_ids,
                 amounts: pool.amounts.into_iter().map(|a| U128(a)).collect(),
                 fee: pool.fee,
                 shares_total_supply: U128(pool.shares_total_supply),
+                token_metadata: vec![],
+            },
+            Pool::BondingCurvePool(pool) => Self {
+                pool_kind,
+                token_account_ids: pool.token_account_ids,
+                amounts: pool.amounts.into_iter().map(|a| U128(a)).collect(),
+                fee: pool.fee,
+                shares_total_supply: U128(0),
+                token_metadata: vec![],
             },
+            Pool::RetiredPool(pool) => Self {
+                pool_kind,
+                amounts: vec![U128(0); pool.token_account_ids.len()],
+                token_account_ids: pool.token_account_ids,
+                fee: pool.fee,
+                shares_total_supply: U128(0),
+                token_metadata: vec![],
+            },
+        }
+    }
+}
+
+/// Information about liquidity position in concentrated pool.
+#[derive(Debug, Serialize, Deserialize, PartialEq)]
+#[serde(crate = "near_sdk::serde")]
+pub struct PositionInfo {
+    /// Owner of the position.
+    pub owner_id: AccountId,
+    /// Lower tick of the price range.
+    pub tick_lower: i32,
+    /// Upper tick of the price range.
+    pub tick_upper: i32,
+    /// Liquidity provided in the range.
+    pub liquidity: U128,
+    /// Fees earned by the position and not withdrawn yet.
+    pub unclaimed_fees: Vec<U128>,
+}
+
+/// Pool and amount of token out that swap via this pool returns.
+#[derive(Debug, Serialize, Deserialize, PartialEq)]
+#[serde(crate = "near_sdk::serde")]
+pub struct PoolReturn {
+    /// Id of the pool.
+    pub pool_id: u64,
+    /// Amount of token out.
+    pub amount_out: U128,
+}
+
+/// Sequence of swaps through the pools and amount of token out it returns.
+#[derive(Debug, Serialize, Deserialize, PartialEq)]
+#[serde(crate = "near_sdk::serde")]
+pub struct RouteInfo {
+    /// Ids of the pools to swap in, in order.
+    pub pool_ids: Vec<u64>,
+    /// Tokens along the route, starting with token in and ending with token out.
+    pub tokens: Vec<AccountId>,
+    /// Amount of token out.
+    pub amount_out: U128,
+}
+
+/// Extends the route ending with `tokens.last()` by up to `hops_left` swaps towards `token_out`,
+/// never reusing a pool or a token, and keeps the route returning the most of `token_out` in `best`.
+fn search_route(
+    pools: &[Pool],
+    amount_in: Balance,
+    token_out: &AccountId,
+    hops_left: u8,
+    pool_ids: &mut Vec<u64>,
+    tokens: &mut Vec<AccountId>,
+    best: &mut Option<RouteInfo>,
+) {
+    let token_in = tokens.last().cloned().unwrap_or_panic(PoolError::Invalid);
+    for (pool_id, pool) in pools.iter().enumerate() {
+        let pool_id = pool_id as u64;
+        if pool_ids.contains(&pool_id) || !pool.tokens().contains(&token_in) {
+            continue;
+        }
+        for token in pool.tokens() {
+            if tokens.contains(token) {
+                continue;
+            }
+            let amount_out = match pool.try_get_return(&token_in, amount_in, token) {
+                Some(amount_out) if amount_out > 0 => amount_out,
+                _ => continue,
+            };
+            pool_ids.push(pool_id);
+            tokens.push(token.clone());
+            if token == token_out {
+                if amount_out > best.as_ref().map(|route| route.amount_out.0).unwrap_or(0) {
+                    *best = Some(RouteInfo {
+                        pool_ids: pool_ids.clone(),
+                        tokens: tokens.clone(),
+                        amount_out: amount_out.into(),
+                    });
+                }
+            } else if hops_left > 1 {
+                search_route(
+                    pools,
+                    amount_out,
+                    token_out,
+                    hops_left - 1,
+                    pool_ids,
+                    tokens,
+                    best,
+                );
+            }
+            pool_ids.pop();
+            tokens.pop();
         }
     }
 }
 
+/// Amount of token out of a route, together with amount out of each of its hops.
+#[derive(Debug, Serialize, Deserialize, PartialEq)]
+#[serde(crate = "near_sdk::serde")]
+pub struct RouteReturn {
+    /// Amount of token out of the last hop.
+    pub amount_out: U128,
+    /// Pool and amount out of each hop, in order.
+    pub hops: Vec<PoolReturn>,
+}
+
+/// Shares minted by adding liquidity, together with amounts of tokens it takes.
+#[derive(Debug, Serialize, Deserialize, PartialEq)]
+#[serde(crate = "near_sdk::serde")]
+pub struct AddLiquidityReturn {
+    /// Number of minted shares.
+    pub shares: U128,
+    /// Amounts of tokens taken from the deposits, in the order of the pool's tokens. The rest stays in the deposits.
+    pub amounts: Vec<U128>,
+}
+
+/// Breakdown of a swap through a pool, returned by `simulate_swap`.
+#[derive(Debug, Serialize, Deserialize, PartialEq)]
+#[serde(crate = "near_sdk::serde")]
+pub struct SwapSimulation {
+    /// Amount of token out.
+    pub amount_out: U128,
+    /// Swap fee charged in token in.
+    pub fee: U128,
+    /// Part of the fee taken by the protocol.
+    pub protocol_fee: U128,
+    /// Balances of the pool after the swap, in the order of its tokens.
+    pub amounts: Vec<U128>,
+    /// Spot price of token in in token out after the swap, with 10^24 meaning 1:1, excluding fee.
+    pub spot_price: U128,
+    /// By how much execution price is worse than the spot price before the swap, in basis points. Includes the fee.
+    pub price_impact: u32,
+}
+
+/// Tunable parameters of the contract, so integrators and monitors can track changes of the configuration.
+#[derive(Debug, Serialize, Deserialize, PartialEq)]
+#[serde(crate = "near_sdk::serde")]
+pub struct ConfigInfo {
+    /// Owner of the contract.
+    pub owner_id: AccountId,
+    /// DAO calling admin methods instead of owner, if it's set.
+    pub dao_id: Option<AccountId>,
+    /// Account receiving protocol fees, if it's set.
+    pub treasury_id: Option<AccountId>,
+    /// Wrapped NEAR contract used to swap native NEAR, if it's set.
+    pub wnear_id: Option<AccountId>,
+    /// Aurora engine receiving outputs of `swap_to_aurora`, if it's set.
+    pub aurora_id: Option<AccountId>,
+    /// Maximum number of tokens in newly created pools.
+    pub max_num_tokens: u32,
+    /// Divisor of swap fees, flash loan fee, limit order fees and stop order bounty.
+    pub fee_divisor: u32,
+    /// Part of the swap fee paid to referrals, divided by `referral_fee_divisor`.
+    pub referral_fee: u32,
+    /// Divisor of the referral fee.
+    pub referral_fee_divisor: u32,
+    /// Part of the swap fee taken by the protocol, divided by `protocol_fee_divisor`.
+    pub protocol_fee: u32,
+    /// Divisor of the protocol fee.
+    pub protocol_fee_divisor: u32,
+    /// Fee of flash loans.
+    pub flash_loan_fee: u32,
+    /// Fees of limit orders.
+    pub limit_order_fees: LimitOrderFees,
+    /// Bounty of stop orders.
+    pub stop_order_bounty: u32,
+    /// Whether anyone can create pools, or only owner and approved creators.
+    pub permissionless_pool_creation: bool,
+    /// Deposit taken on top of storage from newly created pools.
+    pub pool_creation_deposit: U128,
+    /// Whether the exchange is running or paused as a whole.
+    pub running_state: RunningState,
+    /// Delay in seconds between proposing and executing admin actions.
+    pub timelock_delay: u64,
+    /// Version of the layout of the contract state.
+    pub state_version: StateVersion,
+    /// Gas of outgoing token transfers.
+    pub gas_for_ft_transfer: U64,
+    /// Gas kept by `swap_and_call`, the rest goes to the receiver.
+    pub gas_for_swap_and_call: U64,
+    /// Gas kept by `mft_transfer_call`, the rest goes to the receiver.
+    pub gas_for_mft_transfer_call: U64,
+    /// Gas kept by `flash_loan`, the rest goes to the receiver.
+    pub gas_for_flash_loan: U64,
+    /// Gas of `migrate` after deploying staged code.
+    pub gas_for_migrate: U64,
+}
+
 #[near_bindgen]
 impl Contract {
+    /// Returns all tunable parameters of the contract.
+    pub fn get_config(&self) -> ConfigInfo {
+        ConfigInfo {
+            owner_id: self.owner_id.clone(),
+            dao_id: self.dao_id.clone(),
+            treasury_id: self.treasury_id.clone(),
+            wnear_id: self.wnear_id.clone(),
+            aurora_id: self.aurora_id.clone(),
+            max_num_tokens: self.max_num_tokens,
+            fee_divisor: FEE_DIVISOR,
+            referral_fee: self.referral_fee,
+            referral_fee_divisor: REFERRAL_FEE_DIVISOR,
+            protocol_fee: self.protocol_fee,
+            protocol_fee_divisor: PROTOCOL_FEE_DIVISOR,
+            flash_loan_fee: self.flash_loan_fee,
+            limit_order_fees: self.limit_order_fees,
+            stop_order_bounty: self.stop_order_bounty,
+            permissionless_pool_creation: self.permissionless_pool_creation,
+            pool_creation_deposit: self.pool_creation_deposit.into(),
+            running_state: self.running_state,
+            timelock_delay: self.timelock_delay,
+            state_version: self.state_version,
+            gas_for_ft_transfer: GAS_FOR_FT_TRANSFER.0.into(),
+            gas_for_swap_and_call: GAS_FOR_SWAP_AND_CALL.0.into(),
+            gas_for_mft_transfer_call: GAS_FOR_MFT_TRANSFER_CALL.0.into(),
+            gas_for_flash_loan: GAS_FOR_FLASH_LOAN.0.into(),
+            gas_for_migrate: GAS_FOR_MIGRATE.0.into(),
+        }
+    }
+
+    /// Returns owner of the contract.
+    pub fn get_owner(&self) -> AccountId {
+        self.owner_id.clone()
+    }
+
+    /// Returns account proposed to become the new owner, if there is any.
+    pub fn get_pending_owner(&self) -> Option<AccountId> {
+        self.pending_owner_id.clone()
+    }
+
+    /// Returns maximum number of tokens in newly created pools.
+    pub fn get_max_num_tokens(&self) -> u32 {
+        self.max_num_tokens
+    }
+
+    /// Returns part of the swap fee paid to referrals of swaps, divided by 10000.
+    pub fn get_referral_fee(&self) -> u32 {
+        self.referral_fee
+    }
+
+    /// Returns wrapped NEAR contract used to swap native NEAR, if it's set.
+    pub fn get_wnear_id(&self) -> Option<AccountId> {
+        self.wnear_id.clone()
+    }
+
+    /// Returns balance of the contract in given token as of the last measurement, if the token has measured deposits.
+    pub fn get_measured_balance(&self, token_id: AccountId) -> Option<U128> {
+        self.measured_balances
+            .get(&token_id)
+            .map(|balance| balance.into())
+    }
+
+    /// Returns tokens that can be deposited and added to new pools.
+    pub fn get_whitelisted_tokens(&self) -> Vec<AccountId> {
+        self.whitelisted_tokens.to_vec()
+    }
+
+    /// Returns token wrapping shares of given pool or None if it's not deployed.
+    pub fn get_lp_token(&self, pool_id: u64) -> Option<AccountId> {
+        if self.lp_tokens.contains(&pool_id) {
+            Some(lp_token_id(pool_id))
+        } else {
+            None
+        }
+    }
+
+    /// Returns amount of given token held by pools and deposits.
+    pub fn get_token_balance(&self, token_id: AccountId) -> U128 {
+        self.token_balances
+            .get(&token_id)
+            .unwrap_or_default()
+            .into()
+    }
+
     /// Returns number of pools.
     pub fn get_number_of_pools(&self) -> u64 {
         self.pools.len()
@@ -50,30 +381,314 @@ impl Contract {
 
     /// Returns information about specified pool.
     pub fn get_pool(&self, pool_id: u64) -> PoolInfo {
-        self.pools.get(pool_id).expect("ERR_NO_POOL").into()
+        let mut info: PoolInfo = self
+            .pools
+            .get(pool_id)
+            .map(Pool::from)
+            .unwrap_or_panic(PoolError::NoPool)
+            .into();
+        info.token_metadata = self.internal_tokens_metadata(&info.token_account_ids);
+        info
     }
 
     /// Returns number of shares given account has in given pool.
-    pub fn get_pool_shares(&self, pool_id: u64, account_id: ValidAccountId) -> U128 {
+    pub fn get_pool_shares(&self, pool_id: u64, account_id: AccountId) -> U128 {
         self.pools
             .get(pool_id)
-            .expect("ERR_NO_POOL")
-            .share_balances(account_id.as_ref())
+            .map(Pool::from)
+            .unwrap_or_panic(PoolError::NoPool)
+            .share_balances(&account_id)
             .into()
     }
 
+    /// Returns value of a single share of the given stable swap pool in the underlying asset, with 10^24 meaning 1:1.
+    pub fn get_virtual_price(&self, pool_id: u64) -> U128 {
+        match self.internal_get_pool(pool_id) {
+            Pool::StableSwapPool(pool) => pool.virtual_price().into(),
+            _ => PoolError::NotStableSwapPool.panic(),
+        }
+    }
+
     /// Returns total number of shares in the given pool.
     pub fn get_pool_total_shares(&self, pool_id: u64) -> U128 {
         self.pools
             .get(pool_id)
-            .expect("ERR_NO_POOL")
+            .map(Pool::from)
+            .unwrap_or_panic(PoolError::NoPool)
             .share_total_balance()
             .into()
     }
 
-    /// Returns balances of the deposits for given user outside of any pools.
+    /// Returns shares minted and amounts taken by `add_liquidity` of given amounts into the pool at its current state.
+    pub fn predict_add_liquidity(&self, pool_id: u64, amounts: Vec<U128>) -> AddLiquidityReturn {
+        let amounts: Vec<Balance> = amounts.into_iter().map(|amount| amount.into()).collect();
+        let (shares, amounts) = self
+            .internal_get_pool(pool_id)
+            .predict_add_liquidity(&amounts);
+        AddLiquidityReturn {
+            shares: shares.into(),
+            amounts: amounts.into_iter().map(U128).collect(),
+        }
+    }
+
+    /// Returns amounts of tokens received by `remove_liquidity` of given number of shares from the pool
+    /// at its current state.
+    pub fn predict_remove_liquidity(&self, pool_id: u64, shares: U128) -> Vec<U128> {
+        self.internal_get_pool(pool_id)
+            .predict_remove_liquidity(shares.into())
+            .into_iter()
+            .map(U128)
+            .collect()
+    }
+
+    /// Returns information about given position in the concentrated pool or None if position doesn't exist.
+    pub fn get_position(&self, pool_id: u64, position_id: U64) -> Option<PositionInfo> {
+        match self.internal_get_pool(pool_id) {
+            Pool::ConcentratedPool(pool) => pool.get_position(position_id.into()).map(|position| {
+                let unclaimed_fees = pool.position_unclaimed_fees(&position);
+                PositionInfo {
+                    owner_id: position.owner_id,
+                    tick_lower: position.tick_lower,
+                    tick_upper: position.tick_upper,
+                    liquidity: U128(position.liquidity),
+                    unclaimed_fees: unclaimed_fees.iter().map(|a| U128(*a)).collect(),
+                }
+            }),
+            _ => PoolError::NotConcentratedPool.panic(),
+        }
+    }
+
+    /// Given specific pool, returns amounts of token_out received swapping each of amounts_in of token_in.
+    /// Allows to draw price impact curve in one call.
+    pub fn get_returns(
+        &self,
+        pool_id: u64,
+        token_in: AccountId,
+        amounts_in: Vec<U128>,
+        token_out: AccountId,
+    ) -> Vec<U128> {
+        let pool = self.internal_get_pool(pool_id);
+        amounts_in
+            .into_iter()
+            .map(|amount_in| {
+                pool.get_return(&token_in, amount_in.into(), &token_out)
+                    .into()
+            })
+            .collect()
+    }
+
+    /// Given specific pool, returns marginal price of token_in in token_out derived from current reserves,
+    /// with 10^24 meaning 1 token_out for 1 token_in. If `include_fee`, the price is reduced by the current swap fee.
+    pub fn get_spot_price(
+        &self,
+        pool_id: u64,
+        token_in: AccountId,
+        token_out: AccountId,
+        include_fee: bool,
+    ) -> U128 {
+        let pool = self.internal_get_pool(pool_id);
+        let mut price = pool.spot_price(&token_in, &token_out);
+        if include_fee {
+            price = price * U256::from(BPS_DIVISOR - pool.current_fee()) / U256::from(BPS_DIVISOR);
+        }
+        price.checked_as_u128().into()
+    }
+
+    /// Given specific pool, returns by how much execution price of swapping amount_in of token_in into token_out
+    /// is worse than the current spot price, in basis points. Includes the fee.
+    pub fn get_price_impact(
+        &self,
+        pool_id: u64,
+        token_in: AccountId,
+        amount_in: U128,
+        token_out: AccountId,
+    ) -> u32 {
+        let pool = self.internal_get_pool(pool_id);
+        let spot_amount_out = pool.spot_price(&token_in, &token_out) * U256::from(amount_in.0)
+            / U256::from(PRICE_PRECISION);
+        let amount_out = pool.get_return(&token_in, amount_in.into(), &token_out);
+        if spot_amount_out.is_zero() {
+            return 0;
+        }
+        (spot_amount_out.saturating_sub(U256::from(amount_out)) * U256::from(BPS_DIVISOR)
+            / spot_amount_out)
+            .as_u32()
+    }
+
+    /// Given specific pool, returns how swapping amount_in of token_in into token_out would go at the current state:
+    /// amount out, fee and its protocol part, balances and spot price of the pool after the swap, and price impact.
+    /// Swaps without referral, and doesn't check the circuit breaker. Concentrated pools are not supported.
+    pub fn simulate_swap(
+        &self,
+        pool_id: u64,
+        token_in: AccountId,
+        amount_in: U128,
+        token_out: AccountId,
+    ) -> SwapSimulation {
+        let mut pool = self.internal_get_pool(pool_id);
+        // Crossing ticks of concentrated pools writes to the storage, which views can't.
+        require(
+            !matches!(pool, Pool::ConcentratedPool(_)),
+            PoolError::UnsupportedPool,
+        );
+        let spot_amount_out = pool.spot_price(&token_in, &token_out) * U256::from(amount_in.0)
+            / U256::from(PRICE_PRECISION);
+        let fee = pool.current_fee();
+        let amount_out = pool.swap(&token_in, amount_in.into(), &token_out, 0);
+        let protocol_fee = self.internal_protocol_fee_amount(&pool, amount_in.into(), fee);
+        if protocol_fee > 0 {
+            pool.withdraw_fee(&token_in, protocol_fee);
+        }
+        let price_impact = if spot_amount_out.is_zero() {
+            0
+        } else {
+            (spot_amount_out.saturating_sub(U256::from(amount_out)) * U256::from(BPS_DIVISOR)
+                / spot_amount_out)
+                .as_u32()
+        };
+        SwapSimulation {
+            amount_out: amount_out.into(),
+            fee: (U256::from(amount_in.0) * U256::from(fee) / U256::from(FEE_DIVISOR))
+                .as_u128()
+                .into(),
+            protocol_fee: protocol_fee.into(),
+            amounts: pool.amounts().iter().map(|amount| U128(*amount)).collect(),
+            spot_price: pool
+                .spot_price(&token_in, &token_out)
+                .checked_as_u128()
+                .into(),
+            price_impact,
+        }
+    }
+
+    /// Given specific pool, returns amount of token_in needed to receive exactly amount_out of token_out.
+    pub fn get_amount_in(
+        &self,
+        pool_id: u64,
+        token_in: AccountId,
+        amount_out: U128,
+        token_out: AccountId,
+    ) -> U128 {
+        let pool = self.internal_get_pool(pool_id);
+        pool.get_amount_in(&token_in, amount_out.into(), &token_out)
+            .into()
+    }
+
+    /// Returns ids of the pools over exactly given set of tokens, i.e. fee tiers of the same market.
+    pub fn get_fee_tiers(&self, tokens: Vec<AccountId>) -> Vec<u64> {
+        self.fee_tiers.get(&tokens_key(&tokens)).unwrap_or_default()
+    }
+
+    /// Returns ids of the pools containing both given tokens, including pools with more tokens.
+    pub fn get_pools_by_tokens(&self, token_a: AccountId, token_b: AccountId) -> Vec<u64> {
+        require(token_a != token_b, PoolError::SameToken);
+        let pair = tokens_key(&[token_a, token_b]);
+        let mut pool_ids = self.fee_tiers.get(&pair).unwrap_or_default();
+        pool_ids.extend(self.token_pair_pools.get(&pair).unwrap_or_default());
+        pool_ids.sort();
+        pool_ids
+    }
+
+    /// Returns ids of the pools containing given token, of given length from given start index.
+    pub fn get_pools_with_token(
+        &self,
+        token_id: AccountId,
+        from_index: u64,
+        limit: u64,
+    ) -> Vec<u64> {
+        self.token_pools
+            .get(&token_id)
+            .unwrap_or_default()
+            .into_iter()
+            .skip(from_index as usize)
+            .take(limit as usize)
+            .collect()
+    }
+
+    /// Returns fee tier of token_in / token_out market that gives the most of token_out for given amount_in.
+    /// Pools that can't quote the trade are skipped, see `Pool::try_get_return`. Returns None if there are no such pools.
+    pub fn get_best_fee_tier(
+        &self,
+        token_in: AccountId,
+        amount_in: U128,
+        token_out: AccountId,
+    ) -> Option<PoolReturn> {
+        self.get_fee_tiers(vec![token_in.clone(), token_out.clone()])
+            .into_iter()
+            .filter_map(|pool_id| {
+                let amount_out = self.internal_get_pool(pool_id).try_get_return(
+                    &token_in,
+                    amount_in.into(),
+                    &token_out,
+                )?;
+                Some(PoolReturn {
+                    pool_id,
+                    amount_out: amount_out.into(),
+                })
+            })
+            .max_by_key(|pool_return| pool_return.amount_out.0)
+    }
+
+    /// Returns route of at most `max_hops` swaps through the pools that gives the most of token_out for given
+    /// amount_in, or None if there is no such route. Routes never go through the same pool or token twice.
+    /// Only simple, stable swap and weighted pools with liquidity are searched, see `Pool::try_get_return`.
+    pub fn get_best_route(
+        &self,
+        token_in: AccountId,
+        amount_in: U128,
+        token_out: AccountId,
+        max_hops: u8,
+    ) -> Option<RouteInfo> {
+        require(
+            max_hops > 0 && max_hops <= MAX_ROUTE_HOPS,
+            PoolError::TooManyHops,
+        );
+        let mut best = None;
+        search_route(
+            &self.pools.iter().map(Pool::from).collect::<Vec<_>>(),
+            amount_in.into(),
+            &token_out,
+            max_hops,
+            &mut vec![],
+            &mut vec![token_in],
+            &mut best,
+        );
+        best
+    }
+
+    /// Returns amount of token_out of the last hop received swapping amount_in along given hops,
+    /// where each hop swaps the whole output of the previous one, with amount out of each hop.
+    pub fn get_return_by_route(&self, hops: Vec<RouteHop>, amount_in: U128) -> RouteReturn {
+        require(!hops.is_empty(), PoolError::TooManyHops);
+        let mut amount: Balance = amount_in.into();
+        let mut token_id: Option<AccountId> = None;
+        let mut pool_returns = Vec::with_capacity(hops.len());
+        for hop in hops {
+            if let Some(token_id) = token_id {
+                require(token_id == hop.token_in, PoolError::WrongTokenIn);
+            }
+            let pool = self.internal_get_pool(hop.pool_id);
+            amount = pool.get_return(&hop.token_in, amount, &hop.token_out);
+            pool_returns.push(PoolReturn {
+                pool_id: hop.pool_id,
+                amount_out: amount.into(),
+            });
+            token_id = Some(hop.token_out);
+        }
+        RouteReturn {
+            amount_out: amount.into(),
+            hops: pool_returns,
+        }
+    }
+
+    /// Returns balances of the deposits for given user outside of any pools, by token, including registered tokens
+    /// with zero balance. Empty if the user is not registered. Users register at most `MAX_NUMBER_OF_TOKENS` tokens,
+    /// so all of them are returned at once.
     pub fn get_deposits(&self, account_id: &AccountId) -> HashMap<AccountId, U128> {
-        self.internal_get_deposits(account_id)
+        self.deposited_amounts
+            .get(account_id)
+            .map(|deposits| deposits.tokens)
+            .unwrap_or_default()
             .into_iter()
             .map(|(acc, bal)| (acc, U128(bal)))
             .collect()
@@ -88,12 +703,12 @@ impl Contract {
     pub fn get_return(
         &self,
         pool_id: u64,
-        token_in: ValidAccountId,
+        token_in: AccountId,
         amount_in: U128,
-        token_out: ValidAccountId,
+        token_out: AccountId,
     ) -> U128 {
-        let pool = self.pools.get(pool_id).expect("ERR_NO_POOL");
-        pool.get_return(token_in.as_ref(), amount_in.into(), token_out.as_ref())
+        let pool = self.internal_get_pool(pool_id);
+        pool.get_return(&token_in, amount_in.into(), &token_out)
             .into()
     }
 }
@@ -0,0 +1,71 @@
+//! Weight schedule of liquidity bootstrapping pool, that moves token weights linearly over time.
+
+use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
+
+use crate::errors::{require, PoolError};
+use crate::utils::U256;
+use crate::weighted_math::ONE;
+use crate::weighted_pool::assert_weights;
+
+/// Schedule of weights of the weighted pool.
+/// Until `start_time` the pool trades at its own weights, after `end_time` at `end_weights`,
+/// and in between normalized weights move linearly from the former to the latter.
+#[derive(BorshSerialize, BorshDeserialize)]
+pub struct WeightSchedule {
+    /// Relative weights of tokens at the end of the schedule.
+    pub end_weights: Vec<u32>,
+    /// Timestamp in seconds when weights start to change.
+    pub start_time: u64,
+    /// Timestamp in seconds when weights reach `end_weights`.
+    pub end_time: u64,
+}
+
+impl WeightSchedule {
+    pub fn new(num_tokens: usize, end_weights: Vec<u32>, start_time: u64, end_time: u64) -> Self {
+        require(
+            end_weights.len() == num_tokens,
+            PoolError::WrongWeightsCount,
+        );
+        assert_weights(&end_weights);
+        require(end_time > start_time, PoolError::WrongWeightSchedule);
+        Self {
+            end_weights,
+            start_time,
+            end_time,
+        }
+    }
+
+    /// Returns weights at given timestamp as fixed point fractions of the total weight,
+    /// given normalized weights at the start of the schedule.
+    pub fn weights(&self, start_weights: &[U256], timestamp: u64) -> Vec<U256> {
+        let end_weights = normalize(&self.end_weights);
+        if timestamp <= self.start_time {
+            return start_weights.to_vec();
+        }
+        if timestamp >= self.end_time {
+            return end_weights;
+        }
+        let elapsed = U256::from(timestamp - self.start_time);
+        let duration = U256::from(self.end_time - self.start_time);
+        start_weights
+            .iter()
+            .zip(end_weights.iter())
+            .map(|(start, end)| {
+                if end >= start {
+                    *start + (*end - *start) * elapsed / duration
+                } else {
+                    *start - (*start - *end) * elapsed / duration
+                }
+            })
+            .collect()
+    }
+}
+
+/// Returns weights as fixed point fractions of the total weight.
+pub fn normalize(weights: &[u32]) -> Vec<U256> {
+    let total_weight: u64 = weights.iter().map(|w| *w as u64).sum();
+    weights
+        .iter()
+        .map(|w| U256::from(*w) * U256::from(ONE) / U256::from(total_weight))
+        .collect()
+}
@@ -0,0 +1,264 @@
+//! Fixed point math used by weighted pools, following Balancer's `BNum` / `BMath`.
+//! All values are fixed point numbers with `ONE` representing 1.0.
+
+use crate::errors::{require, CheckedAsU128, PoolError};
+use crate::utils::U256;
+
+/// Fixed point 1.0.
+pub const ONE: u128 = 1_000_000_000_000_000_000_000_000;
+/// Precision at which power approximation series stops.
+const BPOW_PRECISION: u128 = ONE / 10_000_000_000;
+/// Base of the power must be in (0, 2) for the approximation series to converge.
+const MAX_BPOW_BASE: u128 = 2 * ONE - 1;
+
+fn one() -> U256 {
+    U256::from(ONE)
+}
+
+/// Multiplies two fixed point numbers, rounding half up.
+pub(crate) fn bmul(a: U256, b: U256) -> U256 {
+    (a * b + one() / 2) / one()
+}
+
+/// Divides two fixed point numbers, rounding half up.
+pub(crate) fn bdiv(a: U256, b: U256) -> U256 {
+    require(!b.is_zero(), PoolError::DivZero);
+    (a * one() + b / 2) / b
+}
+
+/// Returns absolute difference between `a` and `b` and whether it's negative.
+fn bsub_sign(a: U256, b: U256) -> (U256, bool) {
+    if a >= b {
+        (a - b, false)
+    } else {
+        (b - a, true)
+    }
+}
+
+/// Raises fixed point `a` to integer power `n`.
+fn bpowi(a: U256, n: u128) -> U256 {
+    let mut a = a;
+    let mut n = n;
+    let mut z = if n % 2 != 0 { a } else { one() };
+    n /= 2;
+    while n != 0 {
+        a = bmul(a, a);
+        if n % 2 != 0 {
+            z = bmul(z, a);
+        }
+        n /= 2;
+    }
+    z
+}
+
+/// Approximates `base ^ exp` for fractional `exp` in [0, 1) using binomial series.
+fn bpow_approx(base: U256, exp: U256) -> U256 {
+    let (x, xneg) = bsub_sign(base, one());
+    let mut term = one();
+    let mut sum = term;
+    let mut negative = false;
+    let mut i = 1u128;
+    while term >= U256::from(BPOW_PRECISION) {
+        let big_k = U256::from(i) * one();
+        let (c, cneg) = bsub_sign(exp, big_k - one());
+        term = bmul(term, bmul(c, x));
+        term = bdiv(term, big_k);
+        if term.is_zero() {
+            break;
+        }
+        if xneg {
+            negative = !negative;
+        }
+        if cneg {
+            negative = !negative;
+        }
+        if negative {
+            sum -= term;
+        } else {
+            sum += term;
+        }
+        i += 1;
+    }
+    sum
+}
+
+/// Raises fixed point `base` to fixed point power `exp`.
+pub(crate) fn bpow(base: U256, exp: U256) -> U256 {
+    require(
+        !base.is_zero() && base <= U256::from(MAX_BPOW_BASE),
+        PoolError::BpowBase,
+    );
+    let whole = exp / one();
+    let remain = exp - whole * one();
+    let whole_pow = bpowi(base, whole.checked_as_u128());
+    if remain.is_zero() {
+        return whole_pow;
+    }
+    bmul(whole_pow, bpow_approx(base, remain))
+}
+
+/// Returns amount of token out for given amount of token in.
+/// Formula:
+///     amount_out = balance_out * (1 - (balance_in / (balance_in + amount_in * (1 - fee))) ^ (weight_in / weight_out))
+/// Fee is given as fixed point fraction.
+pub(crate) fn calc_out_given_in(
+    balance_in: u128,
+    weight_in: u128,
+    balance_out: u128,
+    weight_out: u128,
+    amount_in: u128,
+    fee: U256,
+) -> u128 {
+    let weight_ratio = bdiv(U256::from(weight_in), U256::from(weight_out));
+    let adjusted_in = bmul(U256::from(amount_in), one() - fee);
+    let y = bdiv(U256::from(balance_in), U256::from(balance_in) + adjusted_in);
+    let pow = bpow(y, weight_ratio);
+    bmul(U256::from(balance_out), one() - pow).checked_as_u128()
+}
+
+/// Returns amount of token in needed to receive given amount of token out.
+/// Formula:
+///     amount_in = balance_in * ((balance_out / (balance_out - amount_out)) ^ (weight_out / weight_in) - 1) / (1 - fee)
+/// Fee is given as fixed point fraction.
+pub(crate) fn calc_in_given_out(
+    balance_in: u128,
+    weight_in: u128,
+    balance_out: u128,
+    weight_out: u128,
+    amount_out: u128,
+    fee: U256,
+) -> u128 {
+    let weight_ratio = bdiv(U256::from(weight_out), U256::from(weight_in));
+    let y = bdiv(
+        U256::from(balance_out),
+        U256::from(balance_out) - U256::from(amount_out),
+    );
+    let pow = bpow(y, weight_ratio);
+    let amount_in = bmul(U256::from(balance_in), pow - one());
+    // Add one to round in favor of the pool.
+    (bdiv(amount_in, one() - fee) + 1).checked_as_u128()
+}
+
+/// Returns number of shares to mint for depositing given amounts in arbitrary proportion.
+/// Part of each amount above the proportional deposit is charged swap fee, as it's equivalent to swapping it into the other tokens.
+/// Weights are fixed point fractions summing up to `ONE`, fee is given as fixed point fraction.
+pub(crate) fn calc_shares_out_given_exact_tokens_in(
+    balances: &[u128],
+    normalized_weights: &[U256],
+    amounts_in: &[u128],
+    total_shares: u128,
+    fee: U256,
+) -> u128 {
+    let mut balance_ratios = vec![];
+    let mut invariant_ratio_with_fees = U256::zero();
+    for i in 0..balances.len() {
+        let ratio = bdiv(
+            U256::from(balances[i]) + U256::from(amounts_in[i]),
+            U256::from(balances[i]),
+        );
+        require(ratio <= U256::from(MAX_BPOW_BASE), PoolError::MaxInRatio);
+        invariant_ratio_with_fees += bmul(ratio, normalized_weights[i]);
+        balance_ratios.push(ratio);
+    }
+    let mut invariant_ratio = one();
+    for i in 0..balances.len() {
+        let amount_in_without_fee = if balance_ratios[i] > invariant_ratio_with_fees {
+            let non_taxable_amount =
+                bmul(U256::from(balances[i]), invariant_ratio_with_fees - one());
+            let taxable_amount = U256::from(amounts_in[i]).saturating_sub(non_taxable_amount);
+            non_taxable_amount + bmul(taxable_amount, one() - fee)
+        } else {
+            U256::from(amounts_in[i])
+        };
+        let balance_ratio = bdiv(
+            U256::from(balances[i]) + amount_in_without_fee,
+            U256::from(balances[i]),
+        );
+        invariant_ratio = bmul(invariant_ratio, bpow(balance_ratio, normalized_weights[i]));
+    }
+    if invariant_ratio <= one() {
+        return 0;
+    }
+    // Round down in favor of the pool.
+    (U256::from(total_shares) * (invariant_ratio - one()) / one()).checked_as_u128()
+}
+
+/// Returns number of shares to burn for withdrawing given amounts in arbitrary proportion, rounded up.
+/// Part of each amount above the proportional withdrawal is charged swap fee, as it's equivalent to swapping the other tokens into it.
+/// Weights are fixed point fractions summing up to `ONE`, fee is given as fixed point fraction.
+pub(crate) fn calc_shares_in_given_exact_tokens_out(
+    balances: &[u128],
+    normalized_weights: &[U256],
+    amounts_out: &[u128],
+    total_shares: u128,
+    fee: U256,
+) -> u128 {
+    let mut balance_ratios = vec![];
+    let mut invariant_ratio_without_fees = U256::zero();
+    for i in 0..balances.len() {
+        require(amounts_out[i] < balances[i], PoolError::NotEnoughLiquidity);
+        let ratio = bdiv(
+            U256::from(balances[i] - amounts_out[i]),
+            U256::from(balances[i]),
+        );
+        invariant_ratio_without_fees += bmul(ratio, normalized_weights[i]);
+        balance_ratios.push(ratio);
+    }
+    let mut invariant_ratio = one();
+    for i in 0..balances.len() {
+        let amount_out_with_fee = if invariant_ratio_without_fees > balance_ratios[i] {
+            let non_taxable_amount = bmul(
+                U256::from(balances[i]),
+                one() - invariant_ratio_without_fees,
+            );
+            let taxable_amount = U256::from(amounts_out[i]).saturating_sub(non_taxable_amount);
+            non_taxable_amount + bdiv(taxable_amount, one() - fee)
+        } else {
+            U256::from(amounts_out[i])
+        };
+        require(
+            amount_out_with_fee < U256::from(balances[i]),
+            PoolError::NotEnoughLiquidity,
+        );
+        let balance_ratio = bdiv(
+            U256::from(balances[i]) - amount_out_with_fee,
+            U256::from(balances[i]),
+        );
+        invariant_ratio = bmul(invariant_ratio, bpow(balance_ratio, normalized_weights[i]));
+    }
+    // Round up in favor of the pool.
+    ((U256::from(total_shares) * (one() - invariant_ratio) + one() - 1) / one()).checked_as_u128()
+}
+
+/// Returns amount of token out for burning given number of shares and withdrawing only this token.
+/// Part of the amount that is converted from the other tokens is charged swap fee.
+/// Weight is fixed point fraction of the total weight, fee is given as fixed point fraction.
+pub(crate) fn calc_single_out_given_shares_in(
+    balance_out: u128,
+    normalized_weight_out: U256,
+    total_shares: u128,
+    shares_in: u128,
+    fee: U256,
+) -> u128 {
+    let shares_ratio = bdiv(
+        U256::from(total_shares - shares_in),
+        U256::from(total_shares),
+    );
+    let balance_ratio = bpow(shares_ratio, bdiv(one(), normalized_weight_out));
+    let amount_out_before_fee = bmul(U256::from(balance_out), one() - balance_ratio);
+    let fee = bmul(one() - normalized_weight_out, fee);
+    bmul(amount_out_before_fee, one() - fee).checked_as_u128()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bpow() {
+        // 0.5 ^ 2.5 = 0.1767766952966369
+        let result = bpow(U256::from(ONE / 2), U256::from(5 * ONE / 2));
+        let expected = U256::from(176_776_695_296_636u128) * U256::from(10u128.pow(9));
+        assert!(bsub_sign(result, expected).0 < U256::from(ONE / 1_000_000_000));
+    }
+}
@@ -0,0 +1,563 @@
+use std::cmp::min;
+
+use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
+use near_sdk::collections::LookupMap;
+use near_sdk::{env, AccountId, Balance};
+
+use crate::dynamic_fee::timestamp_sec;
+use crate::errors::{require, CheckedAsU128, PoolError, UnwrapOrPanic};
+use crate::utils::{add_to_collection, mul_div_round_up, PRICE_PRECISION, U256};
+use crate::weight_schedule::{normalize, WeightSchedule};
+#[cfg(feature = "invariant-checks")]
+use crate::weighted_math::{bdiv, bmul, bpow};
+use crate::weighted_math::{
+    calc_in_given_out, calc_out_given_in, calc_shares_in_given_exact_tokens_out,
+    calc_shares_out_given_exact_tokens_in, calc_single_out_given_shares_in, ONE,
+};
+
+const FEE_DIVISOR: u32 = 10_000;
+const INIT_SHARES_SUPPLY: u128 = 1_000_000_000_000_000_000_000_000;
+/// Minimum weight of a single token as percent of total weight.
+const MIN_WEIGHT_PERCENT: u32 = 1;
+/// Maximum amount in as fraction of balance of token in, so power approximation stays precise.
+const MAX_IN_RATIO_DIVISOR: u128 = 2;
+/// Relative error of the invariant allowed when checking swaps, covers precision of power approximation.
+#[cfg(feature = "invariant-checks")]
+const INVARIANT_TOLERANCE: u128 = ONE / 1_000_000_000;
+
+/// Implementation of weighted pool, that maintains weighted product of balances of all the tokens:
+/// `prod(balance_i ^ weight_i) = const`, similar to "Balancer".
+/// Pool with equal weights behaves as simple pool, while for example 80/20 pool
+/// has lower exposure of liquidity providers to the price of the second token.
+/// Liquidity providers when depositing receive shares, that can be later burnt to withdraw pool's tokens in proportion.
+#[derive(BorshSerialize, BorshDeserialize)]
+pub struct WeightedPool {
+    /// List of tokens in the pool.
+    pub token_account_ids: Vec<AccountId>,
+    /// How much of each token this pool has.
+    pub amounts: Vec<Balance>,
+    /// Relative weights of tokens.
+    pub weights: Vec<u32>,
+    /// Fee charged for swap (gets divided by FEE_DIVISOR).
+    pub fee: u32,
+    /// Shares of the pool by liquidity providers.
+    pub shares: LookupMap<AccountId, Balance>,
+    /// Total number of shares.
+    pub shares_total_supply: Balance,
+    /// Schedule of changing weights for liquidity bootstrapping pool, `None` if weights are fixed.
+    pub weight_schedule: Option<WeightSchedule>,
+}
+
+/// Checks that every weight is at least `MIN_WEIGHT_PERCENT` of the total weight.
+pub fn assert_weights(weights: &[u32]) {
+    let total_weight: u64 = weights.iter().map(|w| *w as u64).sum();
+    for weight in weights.iter() {
+        require(
+            *weight as u64 * 100 >= total_weight * MIN_WEIGHT_PERCENT as u64,
+            PoolError::WeightTooSmall,
+        );
+    }
+}
+
+impl WeightedPool {
+    pub fn new(id: u32, token_account_ids: Vec<AccountId>, weights: Vec<u32>, fee: u32) -> Self {
+        require(fee < FEE_DIVISOR, PoolError::FeeTooLarge);
+        require(
+            weights.len() == token_account_ids.len(),
+            PoolError::WrongWeightsCount,
+        );
+        assert_weights(&weights);
+        Self {
+            token_account_ids: token_account_ids.clone(),
+            amounts: vec![0u128; token_account_ids.len()],
+            weights,
+            fee,
+            shares: LookupMap::new(format!("s{}", id).into_bytes()),
+            shares_total_supply: 0,
+            weight_schedule: None,
+        }
+    }
+
+    /// Returns number of shares given account has.
+    pub fn share_balances(&self, account_id: &AccountId) -> Balance {
+        self.shares.get(account_id).unwrap_or_default()
+    }
+
+    /// Returns total number of shares in this pool.
+    pub fn share_total_balance(&self) -> Balance {
+        self.shares_total_supply
+    }
+
+    /// Returns list of tokens in this pool.
+    pub fn tokens(&self) -> &[AccountId] {
+        &self.token_account_ids
+    }
+
+    /// Checks that every token has nonzero reserve while the pool has shares.
+    #[cfg(feature = "invariant-checks")]
+    pub fn assert_invariants(&self) {
+        require(
+            self.shares_total_supply == 0 || self.amounts.iter().all(|amount| *amount > 0),
+            PoolError::InvariantViolated,
+        );
+    }
+
+    /// Checks that the swap didn't decrease weighted product of balances, given balances before the swap.
+    /// Tolerates error of the power approximation.
+    #[cfg(feature = "invariant-checks")]
+    pub fn assert_swap_invariant(&self, prev_amounts: &[Balance]) {
+        let weights = self.normalized_weights();
+        let mut invariant_ratio = U256::from(ONE);
+        for i in 0..self.amounts.len() {
+            if self.amounts[i] != prev_amounts[i] {
+                let balance_ratio = bdiv(U256::from(self.amounts[i]), U256::from(prev_amounts[i]));
+                invariant_ratio = bmul(invariant_ratio, bpow(balance_ratio, weights[i]));
+            }
+        }
+        require(
+            invariant_ratio + U256::from(INVARIANT_TOLERANCE) >= U256::from(ONE),
+            PoolError::InvariantViolated,
+        );
+    }
+
+    /// Returns current weights as fixed point fractions of the total weight.
+    /// Follows the weight schedule if the pool has one.
+    fn normalized_weights(&self) -> Vec<U256> {
+        let weights = normalize(&self.weights);
+        match &self.weight_schedule {
+            Some(weight_schedule) => weight_schedule.weights(&weights, timestamp_sec()),
+            None => weights,
+        }
+    }
+
+    /// Returns number of shares minted for adding given amounts of tokens, and amounts of them actually used.
+    pub fn predict_add_liquidity(&self, amounts: &[Balance]) -> (Balance, Vec<Balance>) {
+        require(
+            amounts.len() == self.token_account_ids.len(),
+            PoolError::WrongTokenCount,
+        );
+        if self.shares_total_supply > 0 {
+            let mut fair_supply = U256::max_value();
+            for i in 0..self.token_account_ids.len() {
+                require(amounts[i] > 0, PoolError::AmountZero);
+                fair_supply = min(
+                    fair_supply,
+                    U256::from(amounts[i]) * U256::from(self.shares_total_supply) / self.amounts[i],
+                );
+            }
+            // Round amounts taken up and shares minted down in favor of the pool.
+            let used_amounts = self
+                .amounts
+                .iter()
+                .map(|amount| {
+                    mul_div_round_up(
+                        U256::from(*amount),
+                        fair_supply,
+                        U256::from(self.shares_total_supply),
+                    )
+                    .checked_as_u128()
+                })
+                .collect();
+            (fair_supply.checked_as_u128(), used_amounts)
+        } else {
+            for amount in amounts.iter() {
+                require(*amount > 0, PoolError::AmountZero);
+            }
+            (INIT_SHARES_SUPPLY, amounts.to_vec())
+        }
+    }
+
+    /// Adds the amounts of tokens to liquidity pool and returns number of shares that this user receives.
+    /// Updates amounts to amounts actually used, the rest stays with the user.
+    /// First deposit defines the initial prices, following deposits are taken in proportion of the current balances,
+    /// which keeps the weighted invariant per share.
+    pub fn add_liquidity(&mut self, sender_id: &AccountId, amounts: &mut Vec<Balance>) -> Balance {
+        let (shares, used_amounts) = self.predict_add_liquidity(amounts);
+        for i in 0..self.token_account_ids.len() {
+            self.amounts[i] += used_amounts[i];
+        }
+        *amounts = used_amounts;
+        self.shares_total_supply += shares;
+        add_to_collection(&mut self.shares, &sender_id, shares);
+        shares
+    }
+
+    /// Adds the amounts of tokens in arbitrary proportion to liquidity pool and returns number of shares that this user receives.
+    /// Part of the deposit above the pool ratio is charged swap fee, as if it was swapped into the other tokens.
+    pub fn add_liquidity_imbalanced(
+        &mut self,
+        sender_id: &AccountId,
+        amounts: Vec<Balance>,
+    ) -> Balance {
+        let n = self.token_account_ids.len();
+        require(amounts.len() == n, PoolError::WrongTokenCount);
+        if self.shares_total_supply == 0 {
+            let mut amounts = amounts;
+            return self.add_liquidity(sender_id, &mut amounts);
+        }
+        let shares = calc_shares_out_given_exact_tokens_in(
+            &self.amounts,
+            &self.normalized_weights(),
+            &amounts,
+            self.shares_total_supply,
+            U256::from(self.fee) * U256::from(ONE) / U256::from(FEE_DIVISOR),
+        );
+        require(shares > 0, PoolError::ZeroShares);
+        for i in 0..n {
+            self.amounts[i] += amounts[i];
+        }
+        self.shares_total_supply += shares;
+        add_to_collection(&mut self.shares, &sender_id, shares);
+        shares
+    }
+
+    /// Transfers given number of shares from one account to another.
+    pub fn share_transfer(
+        &mut self,
+        sender_id: &AccountId,
+        receiver_id: &AccountId,
+        shares: Balance,
+    ) {
+        self.burn_shares(sender_id, shares);
+        self.shares_total_supply += shares;
+        add_to_collection(&mut self.shares, receiver_id, shares);
+    }
+
+    /// Takes given number of shares from the account, leaving them in the total supply without owner.
+    pub fn lock_shares(&mut self, sender_id: &AccountId, shares: Balance) {
+        self.burn_shares(sender_id, shares);
+        self.shares_total_supply += shares;
+    }
+
+    /// Burns given number of shares of the given account.
+    fn burn_shares(&mut self, sender_id: &AccountId, shares: Balance) {
+        let prev_shares_amount = self
+            .shares
+            .get(&sender_id)
+            .unwrap_or_panic(PoolError::NoShares);
+        require(prev_shares_amount >= shares, PoolError::NotEnoughShares);
+        if prev_shares_amount == shares {
+            self.shares.remove(&sender_id);
+        } else {
+            self.shares
+                .insert(&sender_id, &(prev_shares_amount - shares));
+        }
+        self.shares_total_supply -= shares;
+    }
+
+    /// Removes given number of shares from the pool and returns amounts to the parent.
+    pub fn remove_liquidity(
+        &mut self,
+        sender_id: &AccountId,
+        shares: Balance,
+        min_amounts: Vec<Balance>,
+    ) -> Vec<Balance> {
+        let mut result = vec![];
+        for i in 0..self.token_account_ids.len() {
+            let amount = (U256::from(self.amounts[i]) * U256::from(shares)
+                / U256::from(self.shares_total_supply))
+            .checked_as_u128();
+            require(amount >= min_amounts[i], PoolError::MinAmount);
+            self.amounts[i] -= amount;
+            result.push(amount);
+        }
+        self.burn_shares(sender_id, shares);
+        result
+    }
+
+    /// Burns shares needed to withdraw exactly given amounts of tokens and returns number of burnt shares.
+    /// Part of the withdrawal above the pool ratio is charged swap fee. Fails if it needs more than `max_burn_shares`.
+    pub fn remove_liquidity_by_tokens(
+        &mut self,
+        sender_id: &AccountId,
+        amounts: Vec<Balance>,
+        max_burn_shares: Balance,
+    ) -> Balance {
+        let n = self.token_account_ids.len();
+        require(amounts.len() == n, PoolError::WrongTokenCount);
+        let shares = calc_shares_in_given_exact_tokens_out(
+            &self.amounts,
+            &self.normalized_weights(),
+            &amounts,
+            self.shares_total_supply,
+            U256::from(self.fee) * U256::from(ONE) / U256::from(FEE_DIVISOR),
+        );
+        require(shares <= max_burn_shares, PoolError::MaxBurnShares);
+        self.burn_shares(sender_id, shares);
+        for i in 0..n {
+            self.amounts[i] -= amounts[i];
+        }
+        shares
+    }
+
+    /// Burns given number of shares and returns amount of given token to the parent.
+    /// Part of the amount that is converted from the other tokens is charged swap fee.
+    pub fn remove_liquidity_one_token(
+        &mut self,
+        sender_id: &AccountId,
+        shares: Balance,
+        token_out: &AccountId,
+        min_amount_out: Balance,
+    ) -> Balance {
+        require(shares < self.shares_total_supply, PoolError::SharesTooLarge);
+        let out_idx = self.token_index(token_out);
+        let amount_out = calc_single_out_given_shares_in(
+            self.amounts[out_idx],
+            self.normalized_weights()[out_idx],
+            self.shares_total_supply,
+            shares,
+            U256::from(self.fee) * U256::from(ONE) / U256::from(FEE_DIVISOR),
+        );
+        require(amount_out >= min_amount_out, PoolError::MinAmount);
+        self.burn_shares(sender_id, shares);
+        self.amounts[out_idx] -= amount_out;
+        amount_out
+    }
+
+    /// Returns token index for given pool.
+    fn token_index(&self, token_id: &AccountId) -> usize {
+        self.token_account_ids
+            .iter()
+            .position(|id| id == token_id)
+            .unwrap_or_panic(PoolError::MissingToken)
+    }
+
+    /// Returns number of tokens in outcome, given amount.
+    /// Tokens are provided as indexes into token list for given pool.
+    fn internal_get_return(
+        &self,
+        token_in: usize,
+        amount_in: Balance,
+        token_out: usize,
+    ) -> Balance {
+        require(
+            self.amounts[token_in] > 0
+                && self.amounts[token_out] > 0
+                && token_in != token_out
+                && amount_in > 0,
+            PoolError::Invalid,
+        );
+        require(
+            amount_in <= self.amounts[token_in] / MAX_IN_RATIO_DIVISOR,
+            PoolError::MaxInRatio,
+        );
+        let weights = self.normalized_weights();
+        calc_out_given_in(
+            self.amounts[token_in],
+            weights[token_in].as_u128(),
+            self.amounts[token_out],
+            weights[token_out].as_u128(),
+            amount_in,
+            U256::from(self.fee) * U256::from(ONE) / U256::from(FEE_DIVISOR),
+        )
+    }
+
+    /// Returns how much token you will receive if swap `token_amount_in` of `token_in` for `token_out`.
+    pub fn get_return(
+        &self,
+        token_in: &AccountId,
+        amount_in: Balance,
+        token_out: &AccountId,
+    ) -> Balance {
+        self.internal_get_return(
+            self.token_index(token_in),
+            amount_in,
+            self.token_index(token_out),
+        )
+    }
+
+    /// Returns maximum amount of given token that can be swapped in at once.
+    pub fn max_amount_in(&self, token_in: &AccountId) -> Balance {
+        self.amounts[self.token_index(token_in)] / MAX_IN_RATIO_DIVISOR
+    }
+
+    /// Returns marginal price of `token_in` in `token_out` excluding fee, with `PRICE_PRECISION` precision.
+    pub fn spot_price(&self, token_in: &AccountId, token_out: &AccountId) -> U256 {
+        let in_idx = self.token_index(token_in);
+        let out_idx = self.token_index(token_out);
+        require(
+            self.amounts[in_idx] > 0 && in_idx != out_idx,
+            PoolError::Invalid,
+        );
+        let weights = self.normalized_weights();
+        U256::from(self.amounts[out_idx]) * weights[in_idx] * U256::from(PRICE_PRECISION)
+            / (U256::from(self.amounts[in_idx]) * weights[out_idx])
+    }
+
+    /// Returns amount of token in needed to receive given amount of token out.
+    /// Tokens are provided as indexes into token list for given pool.
+    fn internal_get_amount_in(
+        &self,
+        token_in: usize,
+        amount_out: Balance,
+        token_out: usize,
+    ) -> Balance {
+        require(
+            self.amounts[token_in] > 0 && token_in != token_out && amount_out > 0,
+            PoolError::Invalid,
+        );
+        require(
+            amount_out * MAX_IN_RATIO_DIVISOR < self.amounts[token_out],
+            PoolError::MaxOutRatio,
+        );
+        let weights = self.normalized_weights();
+        calc_in_given_out(
+            self.amounts[token_in],
+            weights[token_in].as_u128(),
+            self.amounts[token_out],
+            weights[token_out].as_u128(),
+            amount_out,
+            U256::from(self.fee) * U256::from(ONE) / U256::from(FEE_DIVISOR),
+        )
+    }
+
+    /// Returns how much of `token_in` is needed to receive exactly `amount_out` of `token_out`.
+    pub fn get_amount_in(
+        &self,
+        token_in: &AccountId,
+        amount_out: Balance,
+        token_out: &AccountId,
+    ) -> Balance {
+        self.internal_get_amount_in(
+            self.token_index(token_in),
+            amount_out,
+            self.token_index(token_out),
+        )
+    }
+
+    /// Swap `token_amount_in` of `token_in` token into `token_out` and return how much was received.
+    /// Assuming that `token_amount_in` was already received from `sender_id`.
+    pub fn swap(
+        &mut self,
+        token_in: &AccountId,
+        amount_in: Balance,
+        token_out: &AccountId,
+        min_amount_out: Balance,
+    ) -> Balance {
+        let in_idx = self.token_index(token_in);
+        let out_idx = self.token_index(token_out);
+        let amount_out = self.internal_get_return(in_idx, amount_in, out_idx);
+        env::log_str(&format!(
+            "Swapped {} {} for {} {}",
+            amount_in, token_in, amount_out, token_out
+        ));
+        require(amount_out >= min_amount_out, PoolError::MinAmount);
+
+        self.amounts[in_idx] += amount_in;
+        self.amounts[out_idx] -= amount_out;
+
+        amount_out
+    }
+
+    /// Swap `token_in` into exactly `amount_out` of `token_out` and return how much of `token_in` it takes.
+    /// Fails if it takes more than `max_amount_in`.
+    pub fn swap_for_exact(
+        &mut self,
+        token_in: &AccountId,
+        max_amount_in: Balance,
+        token_out: &AccountId,
+        amount_out: Balance,
+    ) -> Balance {
+        let in_idx = self.token_index(token_in);
+        let out_idx = self.token_index(token_out);
+        let amount_in = self.internal_get_amount_in(in_idx, amount_out, out_idx);
+        env::log_str(&format!(
+            "Swapped {} {} for {} {}",
+            amount_in, token_in, amount_out, token_out
+        ));
+        require(amount_in <= max_amount_in, PoolError::MaxAmountIn);
+
+        self.amounts[in_idx] += amount_in;
+        self.amounts[out_idx] -= amount_out;
+
+        amount_in
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use near_sdk::test_utils::{accounts, VMContextBuilder};
+    use near_sdk::testing_env;
+
+    use super::*;
+
+    #[test]
+    fn test_weighted_pool_swap() {
+        let one_near = 10u128.pow(24);
+        let mut context = VMContextBuilder::new();
+        context.predecessor_account_id(accounts(0));
+        testing_env!(context.build());
+        let mut pool = WeightedPool::new(0, vec![accounts(1), accounts(2)], vec![80, 20], 30);
+        let num_shares =
+            pool.add_liquidity(&accounts(0), &mut vec![400 * one_near, 100 * one_near]);
+        // Spot price of token 1 is (100 / 20) / (400 / 80) = 1, so small trade returns close to amount in minus fee.
+        let amount_out = pool.swap(&accounts(1), one_near, &accounts(2), 1);
+        assert!(amount_out > 99 * one_near / 100 && amount_out < 997 * one_near / 1000);
+        pool.remove_liquidity(&accounts(0), num_shares, vec![1, 1]);
+        assert_eq!(pool.share_total_balance(), 0);
+    }
+
+    #[test]
+    fn test_equal_weights_match_constant_product() {
+        let one_near = 10u128.pow(24);
+        let mut context = VMContextBuilder::new();
+        context.predecessor_account_id(accounts(0));
+        testing_env!(context.build());
+        let mut pool = WeightedPool::new(0, vec![accounts(1), accounts(2)], vec![50, 50], 30);
+        pool.add_liquidity(&accounts(0), &mut vec![5 * one_near, 10 * one_near]);
+        let amount_out = pool.get_return(&accounts(1), one_near, &accounts(2));
+        // Same as the simple pool returns for the same reserves, within approximation error.
+        let expected = 1662497915624478906119726u128;
+        let diff = if amount_out > expected {
+            amount_out - expected
+        } else {
+            expected - amount_out
+        };
+        assert!(diff < one_near / 1_000_000_000);
+    }
+
+    #[test]
+    fn test_liquidity_bootstrapping_pool() {
+        let one_near = 10u128.pow(24);
+        let hour = 3600;
+        let start_time = 1_000_000;
+        let mut context = VMContextBuilder::new();
+        context.predecessor_account_id(accounts(0));
+        testing_env!(context.build());
+        let mut pool = WeightedPool::new(0, vec![accounts(1), accounts(2)], vec![95, 5], 30);
+        pool.weight_schedule = Some(WeightSchedule::new(
+            2,
+            vec![50, 50],
+            start_time,
+            start_time + 72 * hour,
+        ));
+        pool.add_liquidity(&accounts(0), &mut vec![1900 * one_near, 100 * one_near]);
+        let spot_price_at = |context: &mut VMContextBuilder, timestamp: u64| {
+            testing_env!(context.block_timestamp(timestamp * 1_000_000_000).build());
+            pool.spot_price(&accounts(1), &accounts(2))
+        };
+        // Before the start weights are 95/5: (100 / 5) / (1900 / 95) = 1.
+        assert_eq!(
+            spot_price_at(&mut context, start_time - hour),
+            U256::from(PRICE_PRECISION)
+        );
+        // Halfway weights are 72.5/27.5: (100 / 27.5) / (1900 / 72.5) = 29 / 209.
+        assert_eq!(
+            spot_price_at(&mut context, start_time + 36 * hour),
+            U256::from(PRICE_PRECISION) * U256::from(29) / U256::from(209)
+        );
+        // After the end weights stay 50/50: 100 / 1900 = 1 / 19.
+        assert_eq!(
+            spot_price_at(&mut context, start_time + 100 * hour),
+            U256::from(PRICE_PRECISION) / U256::from(19)
+        );
+        let amount_out = pool.swap(&accounts(1), one_near, &accounts(2), 1);
+        assert!(amount_out < one_near / 19);
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_WRONG_WEIGHT_SCHEDULE")]
+    fn test_deny_weight_schedule_ending_before_start() {
+        WeightSchedule::new(2, vec![50, 50], 100, 100);
+    }
+}
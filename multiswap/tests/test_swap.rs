@@ -1,156 +1,329 @@
-use std::convert::TryFrom;
+use std::collections::HashMap;
 
-use near_sdk::json_types::{ValidAccountId, U128};
+use near_sdk::json_types::U128;
+use near_sdk::serde::de::DeserializeOwned;
+use near_sdk::serde_json::{json, Value};
 use near_sdk::AccountId;
-use near_sdk_sim::{call, deploy, init_simulator, to_yocto, view, ContractAccount, UserAccount};
+use near_sdk_sim::{init_simulator, to_yocto, ExecutionResult, UserAccount, DEFAULT_GAS};
 
-use multiswap::{ContractContract as Multiswap, PoolInfo, SwapAction};
-use std::collections::HashMap;
-use test_token::ContractContract as TestToken;
+use multiswap::{PoolInfo, SwapAction};
 
 near_sdk_sim::lazy_static_include::lazy_static_include_bytes! {
     TEST_TOKEN_WASM_BYTES => "../test-token/res/test_token.wasm",
     MUTLISWAP_WASM_BYTES => "res/multiswap.wasm",
 }
 
-fn test_token(root: &UserAccount, token_id: AccountId) -> ContractAccount<TestToken> {
-    let t = deploy!(
-        contract: TestToken,
-        contract_id: token_id,
-        bytes: &TEST_TOKEN_WASM_BYTES,
-        signer_account: root
-    );
-    call!(root, t.new()).assert_success();
-    call!(
+/// The simulator is still on near-sdk 3, which can't use the bindings of a near-sdk 4 contract,
+/// so contracts are called by method name with JSON arguments.
+fn call(
+    user: &UserAccount,
+    contract_id: &AccountId,
+    method: &str,
+    args: Value,
+    deposit: u128,
+) -> ExecutionResult {
+    user.call(
+        contract_id.to_string(),
+        method,
+        args.to_string().as_bytes(),
+        DEFAULT_GAS,
+        deposit,
+    )
+}
+
+fn view<T: DeserializeOwned>(
+    user: &UserAccount,
+    contract_id: &AccountId,
+    method: &str,
+    args: Value,
+) -> T {
+    user.view(contract_id.to_string(), method, args.to_string().as_bytes())
+        .unwrap_json()
+}
+
+fn test_token(root: &UserAccount, token_id: AccountId) {
+    root.deploy(&TEST_TOKEN_WASM_BYTES, token_id.to_string(), to_yocto("50"));
+    call(root, &token_id, "new", json!({}), 0).assert_success();
+    call(
         root,
-        t.mint(to_va(root.account_id.clone()), to_yocto("1000").into())
+        &token_id,
+        "mint",
+        json!({ "account_id": root.account_id, "amount": U128(to_yocto("1000")) }),
+        0,
     )
     .assert_success();
-    t
 }
 
 fn dai() -> AccountId {
-    "dai".to_string()
+    "dai".parse().unwrap()
 }
 
 fn eth() -> AccountId {
-    "eth".to_string()
+    "eth".parse().unwrap()
 }
 
 fn swap() -> AccountId {
-    "swap".to_string()
-}
-
-fn to_va(a: AccountId) -> ValidAccountId {
-    ValidAccountId::try_from(a).unwrap()
+    "swap".parse().unwrap()
 }
 
-#[test]
-fn test_swap() {
-    let root = init_simulator(None);
-    let token1 = test_token(&root, dai());
-    let token2 = test_token(&root, eth());
-    let pool = deploy!(
-        contract: Multiswap,
-        contract_id: swap(),
-        bytes: &MUTLISWAP_WASM_BYTES,
-        signer_account: root
-    );
-    call!(root, pool.new());
-    call!(
+fn deploy_swap(root: &UserAccount) {
+    root.deploy(&MUTLISWAP_WASM_BYTES, swap().to_string(), to_yocto("50"));
+    call(
         root,
-        pool.add_simple_pool(vec![to_va(dai()), to_va(eth())], 30),
-        deposit = to_yocto("1")
+        &swap(),
+        "new",
+        json!({ "owner_id": root.account_id }),
+        0,
     )
     .assert_success();
+}
 
-    call!(
+fn deposits(root: &UserAccount) -> HashMap<AccountId, U128> {
+    view(
         root,
-        pool.storage_deposit(None, None),
-        deposit = to_yocto("1")
+        &swap(),
+        "get_deposits",
+        json!({ "account_id": root.account_id }),
     )
-    .assert_success();
-    call!(
-        root,
-        token1.storage_deposit(Some(to_va(swap())), None),
-        deposit = to_yocto("1")
+}
+
+/// Gas limit that swaps in the largest pools must fit into with a large margin.
+const MAX_SWAP_GAS: u64 = 100_000_000_000_000;
+
+#[test]
+fn test_swap() {
+    let root = init_simulator(None);
+    test_token(&root, dai());
+    test_token(&root, eth());
+    deploy_swap(&root);
+    for token_id in [dai(), eth()].iter() {
+        call(
+            &root,
+            &swap(),
+            "add_whitelisted_token",
+            json!({ "token_id": token_id }),
+            1,
+        )
+        .assert_success();
+    }
+    call(
+        &root,
+        &swap(),
+        "add_simple_pool",
+        json!({ "tokens": [dai(), eth()], "fee": 30 }),
+        to_yocto("1"),
     )
     .assert_success();
-    call!(
-        root,
-        token2.storage_deposit(Some(to_va(swap())), None),
-        deposit = to_yocto("1")
+
+    call(&root, &swap(), "storage_deposit", json!({}), to_yocto("1")).assert_success();
+    call(
+        &root,
+        &swap(),
+        "register_tokens",
+        json!({ "token_ids": [dai(), eth()] }),
+        0,
     )
     .assert_success();
+    for token_id in [dai(), eth()].iter() {
+        call(
+            &root,
+            token_id,
+            "storage_deposit",
+            json!({ "account_id": swap() }),
+            to_yocto("1"),
+        )
+        .assert_success();
+    }
 
-    call!(
-        root,
-        token1.ft_transfer_call(to_va(swap()), to_yocto("105").into(), None, "".to_string()),
-        deposit = 1
+    call(
+        &root,
+        &dai(),
+        "ft_transfer_call",
+        json!({ "receiver_id": swap(), "amount": U128(to_yocto("105")), "msg": "" }),
+        1,
     )
     .assert_success();
-    call!(
-        root,
-        token2.ft_transfer_call(to_va(swap()), to_yocto("110").into(), None, "".to_string()),
-        deposit = 1
+    call(
+        &root,
+        &eth(),
+        "ft_transfer_call",
+        json!({ "receiver_id": swap(), "amount": U128(to_yocto("110")), "msg": "" }),
+        1,
     )
     .assert_success();
-    call!(
-        root,
-        pool.add_liquidity(0, vec![U128(to_yocto("5")), U128(to_yocto("10"))])
+    call(
+        &root,
+        &swap(),
+        "add_liquidity",
+        json!({
+            "pool_id": 0,
+            "amounts": [U128(to_yocto("5")), U128(to_yocto("10"))],
+            "min_shares": U128(0),
+        }),
+        0,
     )
     .assert_success();
     assert_eq!(
-        view!(pool.get_pool(0)).unwrap_json::<PoolInfo>(),
+        view::<PoolInfo>(&root, &swap(), "get_pool", json!({ "pool_id": 0 })),
         PoolInfo {
+            pool_kind: "SIMPLE_POOL".to_string(),
             token_account_ids: vec![dai(), eth()],
             amounts: vec![to_yocto("5").into(), to_yocto("10").into()],
             fee: 30,
             shares_total_supply: to_yocto("1").into(),
+            token_metadata: vec![None, None],
         }
     );
-    let balances =
-        view!(pool.get_deposits(&root.account_id)).unwrap_json::<HashMap<AccountId, U128>>();
+    let balances = deposits(&root);
     let balances = balances.values().cloned().collect::<Vec<_>>();
     assert_eq!(balances, vec![U128(to_yocto("100")), U128(to_yocto("100"))]);
 
-    call!(
-        root,
-        pool.swap(vec![SwapAction {
-            pool_id: 0,
-            token_in: to_va(dai()),
-            amount_in: Some(U128(to_yocto("1"))),
-            token_out: to_va(eth()),
-            min_amount_out: U128(1)
-        }])
+    call(
+        &root,
+        &swap(),
+        "swap",
+        json!({
+            "actions": [SwapAction {
+                pool_id: 0,
+                token_in: dai(),
+                amount_in: Some(U128(to_yocto("1"))),
+                token_out: eth(),
+                min_amount_out: U128(1)
+            }],
+        }),
+        0,
     )
     .assert_success();
 
-    let balances =
-        view!(pool.get_deposits(&root.account_id)).unwrap_json::<HashMap<AccountId, U128>>();
+    let balances = deposits(&root);
     assert_eq!(
         balances.get(&eth()).unwrap(),
         &U128(to_yocto("100") + 1662497915624478906119726)
     );
     assert_eq!(balances.get(&dai()).unwrap(), &U128(to_yocto("99")));
 
-    call!(
-        root,
-        pool.withdraw(to_va(eth()), U128(to_yocto("101"))),
-        deposit = 1
+    call(
+        &root,
+        &swap(),
+        "withdraw",
+        json!({ "token_id": eth(), "amount": U128(to_yocto("101")) }),
+        1,
     );
-    call!(
-        root,
-        pool.withdraw(to_va(dai()), U128(to_yocto("99"))),
-        deposit = 1
+    call(
+        &root,
+        &swap(),
+        "withdraw",
+        json!({ "token_id": dai(), "amount": U128(to_yocto("99")) }),
+        1,
     );
 
-    let balance1 = view!(token1.ft_balance_of(to_va(root.account_id.clone())))
-        .unwrap_json::<U128>()
-        .0;
+    let balance1 = view::<U128>(
+        &root,
+        &dai(),
+        "ft_balance_of",
+        json!({ "account_id": root.account_id }),
+    )
+    .0;
     assert_eq!(balance1, to_yocto("994"));
-    let balance2 = view!(token2.ft_balance_of(to_va(root.account_id.clone())))
-        .unwrap_json::<U128>()
-        .0;
+    let balance2 = view::<U128>(
+        &root,
+        &eth(),
+        "ft_balance_of",
+        json!({ "account_id": root.account_id }),
+    )
+    .0;
     assert_eq!(balance2, to_yocto("991"));
 }
+
+#[test]
+fn test_swap_gas_in_large_pool() {
+    let root = init_simulator(None);
+    deploy_swap(&root);
+    let max_num_tokens: u32 = view(&root, &swap(), "get_max_num_tokens", json!({}));
+    let token_ids: Vec<AccountId> = (0..max_num_tokens)
+        .map(|i| format!("token{}", i).parse().unwrap())
+        .collect();
+    for token_id in token_ids.iter() {
+        test_token(&root, token_id.clone());
+    }
+    for token_id in token_ids.iter() {
+        call(
+            &root,
+            &swap(),
+            "add_whitelisted_token",
+            json!({ "token_id": token_id }),
+            1,
+        )
+        .assert_success();
+    }
+    call(
+        &root,
+        &swap(),
+        "add_stable_swap_pool",
+        json!({
+            "tokens": token_ids,
+            "decimals": vec![24; token_ids.len()],
+            "fee": 5,
+            "amp_factor": 100,
+        }),
+        to_yocto("1"),
+    )
+    .assert_success();
+    call(&root, &swap(), "storage_deposit", json!({}), to_yocto("1")).assert_success();
+    call(
+        &root,
+        &swap(),
+        "register_tokens",
+        json!({ "token_ids": token_ids }),
+        0,
+    )
+    .assert_success();
+    for token_id in token_ids.iter() {
+        call(
+            &root,
+            token_id,
+            "storage_deposit",
+            json!({ "account_id": swap() }),
+            to_yocto("1"),
+        )
+        .assert_success();
+        call(
+            &root,
+            token_id,
+            "ft_transfer_call",
+            json!({ "receiver_id": swap(), "amount": U128(to_yocto("100")), "msg": "" }),
+            1,
+        )
+        .assert_success();
+    }
+    call(
+        &root,
+        &swap(),
+        "add_liquidity",
+        json!({
+            "pool_id": 0,
+            "amounts": vec![U128(to_yocto("50")); token_ids.len()],
+            "min_shares": U128(0),
+        }),
+        0,
+    )
+    .assert_success();
+
+    let outcome = call(
+        &root,
+        &swap(),
+        "swap",
+        json!({
+            "actions": [SwapAction {
+                pool_id: 0,
+                token_in: token_ids[0].clone(),
+                amount_in: Some(U128(to_yocto("1"))),
+                token_out: token_ids[token_ids.len() - 1].clone(),
+                min_amount_out: U128(1)
+            }],
+        }),
+        0,
+    );
+    outcome.assert_success();
+    assert!(outcome.gas_burnt() < MAX_SWAP_GAS);
+}